@@ -0,0 +1,20 @@
+#![no_main]
+use std::io::Write;
+
+use gruf::grf::GrfArchive;
+use libfuzzer_sys::fuzz_target;
+
+// GRF archives can only be opened from files
+fuzz_target!(|data: &[u8]| {
+    let mut grf_file = tempfile::NamedTempFile::new().unwrap();
+    grf_file.write_all(data).unwrap();
+    if let Ok(mut grf_archive) = GrfArchive::open(grf_file.path()) {
+        let entry_paths: Vec<String> = grf_archive
+            .get_entries()
+            .map(|entry| entry.relative_path.clone())
+            .collect();
+        for entry_path in entry_paths {
+            let _ = grf_archive.read_file_content(entry_path);
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use std::io::Cursor;
+
+use gruf::thor::ThorArchive;
+use libfuzzer_sys::fuzz_target;
+
+// Malformed patches must be rejected, never crash or hang the patcher
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut thor_archive) = ThorArchive::new(Cursor::new(data)) {
+        let entry_paths: Vec<String> = thor_archive
+            .get_entries()
+            .map(|entry| entry.relative_path.clone())
+            .collect();
+        for entry_path in entry_paths {
+            let _ = thor_archive.read_file_content(entry_path);
+        }
+        let _ = thor_archive.is_valid();
+    }
+});
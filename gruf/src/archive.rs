@@ -1,8 +1,12 @@
-use std::io::Write;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
 
 use crate::{GrufError, Result};
 use encoding::label::encoding_from_whatwg_label;
 use encoding::EncoderTrap;
+use flate2::read::ZlibDecoder;
 
 pub struct GenericFileEntry {
     pub offset: u64,
@@ -38,3 +42,306 @@ pub fn serialize_to_win1252(string: &str) -> Result<Vec<u8>> {
         .encode(string, EncoderTrap::Strict)
         .map_err(|_| GrufError::serialization_error("Encoding failed"))
 }
+
+/// Reasons why extracting an entry could write outside of the directory it's
+/// extracted to, or to something other than a regular file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryPathIssue {
+    Empty,               // No file name
+    Absolute,            // Leading separator or drive letter
+    ParentDirectory,     // `..` component
+    DeviceName,          // `CON`, `NUL`, `COM1`, etc.
+    AlternateDataStream, // `file:stream`
+    NulCharacter,
+}
+
+impl fmt::Display for EntryPathIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            EntryPathIssue::Empty => "empty path",
+            EntryPathIssue::Absolute => "absolute path",
+            EntryPathIssue::ParentDirectory => "parent directory component",
+            EntryPathIssue::DeviceName => "device name",
+            EntryPathIssue::AlternateDataStream => "alternate data stream",
+            EntryPathIssue::NulCharacter => "NUL character",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Returns what makes an entry's path unsafe to extract, if anything
+/// (absolute paths, drive letters, `..` components, device names, etc.).
+///
+/// Paths are expected to be Windows style, as in the GRF and THOR file
+/// formats.
+pub fn find_entry_path_issue(entry_path: &str) -> Option<EntryPathIssue> {
+    if entry_path.contains('\0') {
+        return Some(EntryPathIssue::NulCharacter);
+    }
+    if entry_path.starts_with(['\\', '/']) {
+        return Some(EntryPathIssue::Absolute);
+    }
+    let mut component_count = 0;
+    for (component_index, component) in entry_path.split(['\\', '/']).enumerate() {
+        match component {
+            "" | "." => continue,
+            ".." => return Some(EntryPathIssue::ParentDirectory),
+            _ if component.contains(':') => {
+                let is_drive_letter = component_index == 0
+                    && component.find(':') == Some(1)
+                    && component.starts_with(|c: char| c.is_ascii_alphabetic());
+                return Some(if is_drive_letter {
+                    EntryPathIssue::Absolute
+                } else {
+                    EntryPathIssue::AlternateDataStream
+                });
+            }
+            _ if is_device_name(component) => return Some(EntryPathIssue::DeviceName),
+            _ => component_count += 1,
+        }
+    }
+    if component_count == 0 {
+        return Some(EntryPathIssue::Empty);
+    }
+    None
+}
+
+/// Indicates whether Windows would open a device instead of a file named
+/// `file_name` (extensions and trailing spaces are ignored, e.g. `nul .txt`).
+fn is_device_name(file_name: &str) -> bool {
+    const DEVICE_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL"];
+    let stem = file_name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ')
+        .to_ascii_uppercase();
+    if DEVICE_NAMES.contains(&stem.as_str()) {
+        return true;
+    }
+    // COM1-COM9 and LPT1-LPT9
+    let bytes = stem.as_bytes();
+    bytes.len() == 4
+        && (stem.starts_with("COM") || stem.starts_with("LPT"))
+        && (b'1'..=b'9').contains(&bytes[3])
+}
+
+/// Limits enforced when decompressing entries and file tables, so that
+/// archives declaring absurd uncompressed sizes (i.e., decompression bombs)
+/// are rejected instead of exhausting memory or disk space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecompressionLimits {
+    pub max_entry_size: u64,      // In bytes
+    pub max_expansion_ratio: u64, // Uncompressed size / compressed size
+}
+
+impl Default for DecompressionLimits {
+    fn default() -> Self {
+        Self {
+            // Note: u32 limited by the GRF and THOR file formats
+            max_entry_size: u32::MAX as u64,
+            // zlib cannot compress data beyond ~1032:1
+            max_expansion_ratio: 1032,
+        }
+    }
+}
+
+impl DecompressionLimits {
+    /// Returns how many bytes `size_compressed` bytes of compressed data are
+    /// allowed to decompress to.
+    pub fn max_decompressed_size(&self, size_compressed: usize) -> u64 {
+        self.max_entry_size
+            .min((size_compressed as u64).saturating_mul(self.max_expansion_ratio))
+    }
+
+    /// Indicates whether an entry of `size` bytes, compressed into
+    /// `size_compressed` bytes, is within the limits.
+    pub fn allows(&self, size: usize, size_compressed: usize) -> bool {
+        size as u64 <= self.max_decompressed_size(size_compressed)
+    }
+}
+
+/// Decompresses zlib-compressed `data`, stopping after `max_size` + 1 bytes so
+/// that callers can tell outputs larger than `max_size` apart without
+/// decompressing them entirely.
+pub fn zlib_decompress_bounded(data: &[u8], max_size: u64) -> io::Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(data).take(max_size.saturating_add(1));
+    let mut decompressed_data = Vec::new();
+    decoder.read_to_end(&mut decompressed_data)?;
+    Ok(decompressed_data)
+}
+
+/// Buffer that holds data in memory up to `memory_budget` bytes, and moves it
+/// to a temporary file past that.
+pub struct SpillBuffer {
+    memory_budget: usize,
+    temp_directory: Option<PathBuf>, // System's temporary directory if unset
+    memory: Vec<u8>,
+    file: Option<File>, // Set once the budget has been exceeded
+    size: u64,
+}
+
+impl SpillBuffer {
+    pub fn new(memory_budget: usize, temp_directory: Option<PathBuf>) -> Self {
+        Self {
+            memory_budget,
+            temp_directory,
+            memory: Vec::new(),
+            file: None,
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Copies the content of the buffer into `writer`.
+    pub fn copy_into<W: Write + ?Sized>(self, writer: &mut W) -> io::Result<u64> {
+        match self.file {
+            Some(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(&mut file, writer)
+            }
+            None => {
+                writer.write_all(&self.memory)?;
+                Ok(self.size)
+            }
+        }
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.memory.len().saturating_add(buf.len()) > self.memory_budget {
+            let mut file = match &self.temp_directory {
+                Some(temp_directory) => tempfile::tempfile_in(temp_directory)?,
+                None => tempfile::tempfile()?,
+            };
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        let written_size = match &mut self.file {
+            Some(file) => file.write(buf)?,
+            None => {
+                self.memory.extend_from_slice(buf);
+                buf.len()
+            }
+        };
+        self.size += written_size as u64;
+        Ok(written_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_suspicious_entry_paths() {
+        assert!(find_entry_path_issue("data\\texture\\icon.bmp").is_none());
+        assert!(find_entry_path_issue("data/sprite/a..b.spr").is_none());
+        assert!(find_entry_path_issue("").is_some());
+        assert!(find_entry_path_issue("\\Windows\\system32\\a.dll").is_some());
+        assert!(find_entry_path_issue("data\\..\\..\\a.exe").is_some());
+        assert!(find_entry_path_issue("C:\\a.exe").is_some());
+        assert!(find_entry_path_issue("data\\a.txt:stream").is_some());
+    }
+
+    #[test]
+    fn test_find_entry_path_issue() {
+        assert_eq!(find_entry_path_issue("data\\con_data.txt"), None);
+        assert_eq!(find_entry_path_issue("data\\com10.txt"), None);
+        assert_eq!(find_entry_path_issue("data\\.\\a.txt"), None);
+        assert_eq!(find_entry_path_issue("."), Some(EntryPathIssue::Empty));
+        assert_eq!(
+            find_entry_path_issue("/data/a.txt"),
+            Some(EntryPathIssue::Absolute)
+        );
+        assert_eq!(
+            find_entry_path_issue("d:data.grf"),
+            Some(EntryPathIssue::Absolute)
+        );
+        assert_eq!(
+            find_entry_path_issue("data/../a.txt"),
+            Some(EntryPathIssue::ParentDirectory)
+        );
+        assert_eq!(
+            find_entry_path_issue("data\\CON"),
+            Some(EntryPathIssue::DeviceName)
+        );
+        assert_eq!(
+            find_entry_path_issue("nul .txt"),
+            Some(EntryPathIssue::DeviceName)
+        );
+        assert_eq!(
+            find_entry_path_issue("data\\lpt3.log"),
+            Some(EntryPathIssue::DeviceName)
+        );
+        assert_eq!(
+            find_entry_path_issue("data\\a.txt::$DATA"),
+            Some(EntryPathIssue::AlternateDataStream)
+        );
+        assert_eq!(
+            find_entry_path_issue("data\\a\0.txt"),
+            Some(EntryPathIssue::NulCharacter)
+        );
+    }
+
+    #[test]
+    fn test_decompression_limits() {
+        let limits = DecompressionLimits {
+            max_entry_size: 1000,
+            max_expansion_ratio: 10,
+        };
+        assert!(limits.allows(100, 10));
+        assert!(!limits.allows(101, 10));
+        assert!(!limits.allows(1001, 500));
+        assert!(limits.allows(0, 0));
+        assert!(DecompressionLimits::default().allows(u32::MAX as usize, 1 << 22));
+    }
+
+    #[test]
+    fn test_spill_buffer() {
+        let mut buffer = SpillBuffer::new(8, None);
+        buffer.write_all(b"12345").unwrap();
+        assert!(buffer.file.is_none());
+        buffer.write_all(b"6789").unwrap();
+        assert!(buffer.file.is_some());
+        buffer.write_all(b"0").unwrap();
+        assert_eq!(buffer.size(), 10);
+        let mut content = vec![];
+        assert_eq!(buffer.copy_into(&mut content).unwrap(), 10);
+        assert_eq!(content, b"1234567890");
+    }
+
+    #[test]
+    fn test_zlib_decompress_bounded() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 4096]).unwrap();
+        let compressed_data = encoder.finish().unwrap();
+        assert_eq!(
+            zlib_decompress_bounded(&compressed_data, 4096)
+                .unwrap()
+                .len(),
+            4096
+        );
+        // Decompression stops right after the limit
+        assert_eq!(
+            zlib_decompress_bounded(&compressed_data, 100)
+                .unwrap()
+                .len(),
+            101
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Conversions of entry names between the client's code page and UTF-8.
+//!
+//! GRF and THOR archives store file names as raw bytes, encoded with the code
+//! page of the game client (EUC-KR for most clients). Archive readers and
+//! builders represent these names as strings decoded as windows-1252, which
+//! preserves the original bytes. The functions below convert such names from
+//! and to their actual text, e.g. to extract files to disk.
+
+use crate::{GrufError, Result};
+use encoding::label::encoding_from_whatwg_label;
+use encoding::types::EncodingRef;
+use encoding::{DecoderTrap, EncoderTrap};
+
+/// Indicates if `code_page` is a supported code page label (e.g., "euc-kr",
+/// "windows-1252" or "windows-874").
+pub fn is_supported_code_page(code_page: &str) -> bool {
+    encoding_from_whatwg_label(code_page).is_some()
+}
+
+/// Converts an entry name, as returned by archive readers, to UTF-8.
+pub fn decode_entry_name(entry_name: &str, code_page: &str) -> Result<String> {
+    let raw_name = get_encoding("windows-1252")?
+        .encode(entry_name, EncoderTrap::Strict)
+        .map_err(|_| GrufError::invalid_content("Invalid entry name"))?;
+    get_encoding(code_page)?
+        .decode(&raw_name, DecoderTrap::Strict)
+        .map_err(|_| GrufError::invalid_content(format!("Entry name is not valid {}", code_page)))
+}
+
+/// Converts a UTF-8 file name to an entry name, as expected by archive
+/// builders.
+pub fn encode_entry_name(name: &str, code_page: &str) -> Result<String> {
+    let raw_name = get_encoding(code_page)?
+        .encode(name, EncoderTrap::Strict)
+        .map_err(|_| {
+            GrufError::serialization_error(format!("'{}' cannot be encoded as {}", name, code_page))
+        })?;
+    get_encoding("windows-1252")?
+        .decode(&raw_name, DecoderTrap::Strict)
+        .map_err(|_| GrufError::serialization_error("Encoding failed"))
+}
+
+fn get_encoding(code_page: &str) -> Result<EncodingRef> {
+    encoding_from_whatwg_label(code_page)
+        .ok_or_else(|| GrufError::UnknownCodePage(code_page.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_names_round_trip() {
+        let names = [
+            (
+                "data\\texture\\유저인터페이스\\basic_interface.bmp",
+                "euc-kr",
+            ),
+            ("data\\texture\\ภาษาไทย.bmp", "windows-874"),
+            ("data\\texture\\café.bmp", "windows-1252"),
+        ];
+        for (name, code_page) in names.iter() {
+            let entry_name = encode_entry_name(name, code_page).unwrap();
+            assert_eq!(&decode_entry_name(&entry_name, code_page).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn test_windows_1252_preserves_bytes() {
+        // Names must survive being decoded by archive readers, whatever their
+        // code page
+        let encoding = get_encoding("windows-1252").unwrap();
+        let all_bytes: Vec<u8> = (1..=255).collect();
+        let decoded = encoding.decode(&all_bytes, DecoderTrap::Strict).unwrap();
+        assert_eq!(
+            encoding.encode(&decoded, EncoderTrap::Strict).unwrap(),
+            all_bytes
+        );
+    }
+
+    #[test]
+    fn test_decode_entry_name() {
+        // Name as read from an archive
+        let entry_name = "data\\texture\\À¯ÀúÀÎÅÍÆäÀÌ½º";
+        assert_eq!(
+            decode_entry_name(entry_name, "euc-kr").unwrap(),
+            "data\\texture\\유저인터페이스"
+        );
+        assert_eq!(
+            decode_entry_name(entry_name, "windows-1252").unwrap(),
+            entry_name
+        );
+        assert!(matches!(
+            decode_entry_name(entry_name, "unknown"),
+            Err(GrufError::UnknownCodePage(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_entry_name() {
+        assert_eq!(
+            encode_entry_name("data\\유저인터페이스", "euc-kr").unwrap(),
+            "data\\À¯ÀúÀÎÅÍÆäÀÌ½º"
+        );
+        assert!(encode_entry_name("data\\유저인터페이스", "windows-1252").is_err());
+        assert!(is_supported_code_page("windows-874"));
+        assert!(!is_supported_code_page("unknown"));
+    }
+}
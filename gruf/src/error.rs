@@ -15,12 +15,26 @@ pub enum GrufError {
     TryFromIntError(#[from] num::TryFromIntError),
     #[error("failed to parse archive: {0}")]
     ParsingError(String),
+    #[error("not a {0} archive (bad magic)")]
+    BadMagic(&'static str),
+    #[error("truncated {0}")]
+    Truncated(&'static str),
+    #[error("entry '{0}' lies outside of the archive")]
+    OversizedEntry(String),
+    #[error("failed to decompress {0}: {1}")]
+    ZlibError(&'static str, #[source] io::Error),
+    #[error("{0} exceeds the decompression limits")]
+    DecompressionLimitExceeded(String),
+    #[error("archive is being used by another process")]
+    Locked,
     #[error("failed to find file entry")]
     EntryNotFound,
     #[error("failed to read content: {0}")]
     InvalidContent(String),
     #[error("failed to serialize data: {0}")]
     SerializationError(String),
+    #[error("unknown code page: {0}")]
+    UnknownCodePage(String),
     #[error("dyn_alloc error")]
     DynAllocError,
 }
@@ -37,4 +51,30 @@ impl GrufError {
     pub fn serialization_error(msg: impl Into<String>) -> Self {
         Self::SerializationError(msg.into())
     }
+
+    /// Converts the error of an attempt to lock an archive.
+    pub(crate) fn from_lock_error(e: advisory_lock::FileLockError) -> Self {
+        match e {
+            advisory_lock::FileLockError::AlreadyLocked => Self::Locked,
+            advisory_lock::FileLockError::Io(e) => Self::IoError(e),
+        }
+    }
+
+    /// Converts the error of a parser, `structure_name` being the name of
+    /// what was being parsed (e.g., "THOR header").
+    pub(crate) fn from_nom_error<E>(e: nom::Err<E>, structure_name: &'static str) -> Self {
+        match e {
+            nom::Err::Incomplete(_) => Self::Truncated(structure_name),
+            _ => Self::ParsingError(format!("Failed to parse {}", structure_name)),
+        }
+    }
+
+    /// Converts an I/O error that occurred while reading `structure_name`
+    /// (e.g., "GRF header"), reporting unexpected EOFs as truncations.
+    pub(crate) fn from_read_error(e: io::Error, structure_name: &'static str) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated(structure_name),
+            _ => Self::IoError(e),
+        }
+    }
 }
@@ -3,13 +3,14 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::archive::{serialize_as_win1252_cstr_into, GenericFileEntry};
+use crate::archive::{serialize_as_win1252_cstr_into, GenericFileEntry, SpillBuffer};
 use crate::grf::dyn_alloc::{self, AvailableChunkList};
-use crate::grf::{GrfArchive, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
+use crate::grf::{GrfArchive, JournaledFile, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
 use crate::thor::ThorArchive;
 use crate::{GrufError, Result};
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
@@ -24,6 +25,8 @@ pub struct GrfArchiveBuilder<W: Write + Seek> {
     version_minor: u32,
     entries: HashMap<String, GenericFileEntry>,
     chunks: AvailableChunkList,
+    memory_budget: usize, // Bytes of entry data that can be held in memory
+    temp_directory: Option<PathBuf>, // Where entries that exceed the budget are staged
 }
 
 #[derive(Debug, Serialize)]
@@ -59,6 +62,8 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             version_minor,
             entries: HashMap::new(),
             chunks: AvailableChunkList::new(),
+            memory_budget: usize::MAX,
+            temp_directory: None,
         })
     }
 
@@ -71,23 +76,21 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
-        let content = archive.get_entry_raw_data(&relative_path)?;
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    content.len(),
-                )?
-            } else {
-                self.chunks.alloc_chunk(content.len())?
-            }
+        let raw_data_size = match entry.size {
+            0 => 0,
+            _ => entry.size_compressed_aligned,
         };
+        let offset = self.alloc_entry_chunk(&relative_path, raw_data_size)?;
 
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut content_reader = Cursor::new(content);
-        let content_size = io::copy(&mut content_reader, self.obj.by_ref())?;
-        debug_assert_eq!(entry.size_compressed_aligned as u64, content_size);
+        let content_size = if raw_data_size <= self.memory_budget {
+            let content = archive.get_entry_raw_data(&relative_path)?;
+            let mut content_reader = Cursor::new(content);
+            io::copy(&mut content_reader, self.obj.by_ref())?
+        } else {
+            archive.copy_entry_raw_data(&relative_path, &mut self.obj)?
+        };
+        debug_assert_eq!(raw_data_size as u64, content_size);
         self.entries.insert(
             relative_path,
             GenericFileEntry {
@@ -108,22 +111,16 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
-        let content = thor_archive.get_entry_raw_data(&relative_path)?;
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    content.len(),
-                )?
-            } else {
-                self.chunks.alloc_chunk(content.len())?
-            }
-        };
+        let offset = self.alloc_entry_chunk(&relative_path, entry.size_compressed)?;
 
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut content_reader = Cursor::new(content);
-        let _ = io::copy(&mut content_reader, self.obj.by_ref())?;
+        if entry.size_compressed <= self.memory_budget {
+            let content = thor_archive.get_entry_raw_data(&relative_path)?;
+            let mut content_reader = Cursor::new(content);
+            let _ = io::copy(&mut content_reader, self.obj.by_ref())?;
+        } else {
+            let _ = thor_archive.copy_entry_raw_data(&relative_path, &mut self.obj)?;
+        }
         self.entries.insert(
             relative_path,
             GenericFileEntry {
@@ -136,28 +133,21 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
     }
 
     pub fn add_file<R: Read>(&mut self, relative_path: String, mut data: R) -> Result<()> {
-        // Compress it
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        // Compress it, in a temporary file if it doesn't fit in the memory
+        // budget
+        let mut encoder = ZlibEncoder::new(
+            SpillBuffer::new(self.memory_budget, self.temp_directory.clone()),
+            Compression::default(),
+        );
         let data_size = io::copy(data.by_ref(), &mut encoder)?;
         let data_size_u32 = u32::try_from(data_size)?;
         // Write compressed data
         let compressed_data = encoder.finish()?;
-        let compressed_data_size = compressed_data.len();
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    compressed_data_size,
-                )?
-            } else {
-                self.chunks.alloc_chunk(compressed_data_size)?
-            }
-        };
+        let compressed_data_size = usize::try_from(compressed_data.size())?;
+        let offset = self.alloc_entry_chunk(&relative_path, compressed_data_size)?;
 
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut compressed_reader = Cursor::new(compressed_data);
-        let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
+        let _ = compressed_data.copy_into(&mut self.obj)?;
         let compressed_data_size_u32 = u32::try_from(compressed_data_size)?;
         self.entries.insert(
             relative_path,
@@ -170,6 +160,30 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
         Ok(())
     }
 
+    /// Sets how many bytes of entry data can be held in memory at once
+    /// (unlimited by default). Larger entries are copied in chunks, or staged
+    /// in a temporary file when they have to be compressed first.
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+    }
+
+    /// Sets the directory entries are staged in when they exceed the memory
+    /// budget (the system's temporary directory by default).
+    pub fn set_temp_directory(&mut self, temp_directory: impl Into<PathBuf>) {
+        self.temp_directory = Some(temp_directory.into());
+    }
+
+    /// Finds room for `size` bytes of data for the entry at `relative_path`,
+    /// reusing the entry's current chunk if possible.
+    fn alloc_entry_chunk(&mut self, relative_path: &str, size: usize) -> Result<u64> {
+        if let Some(grf_entry) = self.entries.get(relative_path) {
+            self.chunks
+                .realloc_chunk(grf_entry.offset, grf_entry.size_compressed as usize, size)
+        } else {
+            self.chunks.alloc_chunk(size)
+        }
+    }
+
     pub fn remove_file<S: AsRef<str>>(&mut self, relative_path: S) -> Result<bool> {
         if let Some(entry) = self.entries.remove(relative_path.as_ref()) {
             self.chunks
@@ -180,6 +194,38 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
         }
     }
 
+    pub fn get_entry_names(&self) -> impl Iterator<Item = &'_ str> {
+        self.entries
+            .keys()
+            .map(|relative_path| relative_path.as_str())
+    }
+
+    /// Renames the entry at `relative_path` to `new_relative_path`, replacing
+    /// the entry that may already have that name.
+    pub fn rename_file<S: AsRef<str>>(
+        &mut self,
+        relative_path: S,
+        new_relative_path: String,
+    ) -> Result<bool> {
+        let entry = match self.entries.remove(relative_path.as_ref()) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        if let Some(replaced_entry) = self.entries.insert(new_relative_path, entry) {
+            self.chunks.free_chunk(
+                replaced_entry.offset,
+                replaced_entry.size_compressed as usize,
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of bytes left unused between entries (e.g., by
+    /// removed entries), which rebuilding the archive would reclaim.
+    pub fn unused_space(&self) -> u64 {
+        self.chunks.available_size()
+    }
+
     pub fn finish(&mut self) -> Result<()> {
         if self.finished {
             return Ok(());
@@ -204,8 +250,11 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
 
     fn write_grf_table_200(&mut self) -> Result<u64> {
         let mut table: Vec<u8> = Vec::new();
+        // Sort entries so that identical archives have identical tables
+        let mut entries: Vec<(&String, &GenericFileEntry)> = self.entries.iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
         // Generate table and write files' content
-        for (relative_path, entry) in &self.entries {
+        for (relative_path, entry) in entries {
             let grf_file_entry = SerializableGrfFileEntry200 {
                 size_compressed: entry.size_compressed,
                 size_compressed_aligned: entry.size_compressed,
@@ -240,7 +289,42 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
 
 impl GrfArchiveBuilder<File> {
     pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
-        let mut grf_archive = GrfArchive::open(&grf_path)?;
+        Self::open_with(grf_path.as_ref(), Ok)
+    }
+}
+
+impl GrfArchiveBuilder<JournaledFile> {
+    /// Opens the archive at `grf_path` like `GrfArchiveBuilder::open`, saving
+    /// the content that gets overwritten in an undo journal at `journal_path`
+    /// first.
+    ///
+    /// Changes are kept once `commit` has been called. Until then, the
+    /// archive can be put back in its original state with
+    /// `rollback_undo_journal` (e.g., after a crash).
+    pub fn open_journaled<P: AsRef<Path>, J: AsRef<Path>>(
+        grf_path: P,
+        journal_path: J,
+    ) -> Result<Self> {
+        Self::open_with(grf_path.as_ref(), |file| {
+            JournaledFile::new(file, journal_path)
+        })
+    }
+
+    /// Finishes the archive, syncs it to disk and removes the undo journal.
+    pub fn commit(&mut self) -> Result<()> {
+        self.finish()?;
+        self.obj.commit()
+    }
+}
+
+impl<W: Write + Seek> GrfArchiveBuilder<W> {
+    /// Opens the archive at `grf_path` for modification, writing to it
+    /// through what `wrap_file` makes of the opened file.
+    fn open_with<F>(grf_path: &Path, wrap_file: F) -> Result<Self>
+    where
+        F: FnOnce(File) -> Result<W>,
+    {
+        let mut grf_archive = GrfArchive::open(grf_path)?;
         let chunks = dyn_alloc::list_available_chunks(&mut grf_archive)?;
         let mut entries = HashMap::with_capacity(grf_archive.file_count());
         for entry in grf_archive.get_entries() {
@@ -254,15 +338,21 @@ impl GrfArchiveBuilder<File> {
             );
         }
 
-        let file = OpenOptions::new().read(true).write(true).open(&grf_path)?;
+        let file = OpenOptions::new().read(true).write(true).open(grf_path)?;
+        // Readers that opened the archive with `GrfArchive::open_shared` must
+        // be done with it. The lock is released once the builder is dropped
+        AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive)
+            .map_err(GrufError::from_lock_error)?;
         Ok(Self {
-            obj: Box::new(file),
+            obj: Box::new(wrap_file(file)?),
             start_offset: 0,
             finished: false,
             version_major: grf_archive.version_major(),
             version_minor: grf_archive.version_minor(),
             entries,
             chunks,
+            memory_budget: usize::MAX,
+            temp_directory: None,
         })
     }
 }
@@ -298,7 +388,7 @@ mod tests {
     use std::fs::File;
     use std::path::PathBuf;
 
-    use crate::grf::{GrfArchive, GrfArchiveBuilder, GrfFileEntry};
+    use crate::grf::{rollback_undo_journal, GrfArchive, GrfArchiveBuilder, GrfFileEntry};
     use tempfile::tempdir;
 
     #[test]
@@ -350,6 +440,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_memory_budget() {
+        let temp_dir = tempdir().unwrap();
+        let source_path = temp_dir.path().join("source.grf");
+        let output_path = temp_dir.path().join("output.grf");
+        let content: Vec<u8> = (0..4096).map(|i| (i * 7 % 251) as u8).collect();
+        {
+            let output_file = File::create(&source_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+            builder.set_memory_budget(16);
+            builder.set_temp_directory(temp_dir.path());
+            builder
+                .add_file("data\\big.bin".to_string(), content.as_slice())
+                .unwrap();
+            builder
+                .add_file("data\\empty.bin".to_string(), &b""[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        {
+            let mut source_archive = GrfArchive::open(&source_path).unwrap();
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+            builder.set_memory_budget(16);
+            for entry_path in &["data\\big.bin", "data\\empty.bin"] {
+                builder
+                    .import_raw_entry_from_grf(&mut source_archive, entry_path.to_string())
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+        assert_eq!(
+            grf_archive.read_file_content("data\\big.bin").unwrap(),
+            content
+        );
+        assert!(grf_archive
+            .read_file_content("data\\empty.bin")
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn test_import_raw_entry_from_grf() {
         let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
@@ -387,4 +519,123 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_unused_space() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-unused.grf");
+        {
+            let grf_file = File::create(&grf_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            for (name, content) in &[("data\\a.txt", [b'a'; 64]), ("data\\b.txt", [b'b'; 64])] {
+                builder.add_file(name.to_string(), &content[..]).unwrap();
+            }
+            builder
+                .add_file(
+                    "data\\c.txt".to_string(),
+                    &(0..255).collect::<Vec<u8>>()[..],
+                )
+                .unwrap();
+        }
+        let removed_size = {
+            let grf_archive = GrfArchive::open(&grf_path).unwrap();
+            let entry = grf_archive.get_file_entry("data\\b.txt").unwrap();
+            entry.size_compressed_aligned as u64
+        };
+        {
+            let mut builder = GrfArchiveBuilder::open(&grf_path).unwrap();
+            assert_eq!(builder.unused_space(), 0);
+            assert!(builder.remove_file("data\\b.txt").unwrap());
+            builder.finish().unwrap();
+            assert_eq!(builder.unused_space(), removed_size);
+        }
+        // The space is still unused once the archive is opened again
+        let builder = GrfArchiveBuilder::open(&grf_path).unwrap();
+        assert_eq!(builder.unused_space(), removed_size);
+    }
+
+    #[test]
+    fn test_journaled_builder() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-journaled.grf");
+        let journal_path = temp_dir.path().join("200-journaled.grf.undo");
+        {
+            let grf_file = File::create(&grf_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &[b'a'; 64][..])
+                .unwrap();
+        }
+        let original_content = std::fs::read(&grf_path).unwrap();
+        {
+            // Interrupted before being committed
+            let mut builder = GrfArchiveBuilder::open_journaled(&grf_path, &journal_path).unwrap();
+            assert!(builder.remove_file("data\\a.txt").unwrap());
+            builder
+                .add_file("data\\b.txt".to_string(), &[b'b'; 64][..])
+                .unwrap();
+        }
+        assert!(rollback_undo_journal(&grf_path, &journal_path).unwrap());
+        assert_eq!(std::fs::read(&grf_path).unwrap(), original_content);
+        {
+            let mut builder = GrfArchiveBuilder::open_journaled(&grf_path, &journal_path).unwrap();
+            builder
+                .add_file("data\\b.txt".to_string(), &[b'b'; 64][..])
+                .unwrap();
+            builder.commit().unwrap();
+        }
+        assert!(!journal_path.exists());
+        let grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert!(grf_archive.get_file_entry("data\\a.txt").is_some());
+        assert!(grf_archive.get_file_entry("data\\b.txt").is_some());
+    }
+
+    #[test]
+    fn test_rename_file() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-rename.grf");
+        {
+            let grf_file = File::create(&grf_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data/sprite/a.spr".to_string(), &b"new"[..])
+                .unwrap();
+            builder
+                .add_file("data\\Sprite\\A.spr".to_string(), &b"old"[..])
+                .unwrap();
+            assert!(builder
+                .rename_file("data/sprite/a.spr", "data\\Sprite\\A.spr".to_string())
+                .unwrap());
+            assert!(!builder
+                .rename_file("data\\missing.spr", "data\\other.spr".to_string())
+                .unwrap());
+        }
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 1);
+        assert_eq!(
+            grf_archive
+                .read_file_content("data\\Sprite\\A.spr")
+                .unwrap(),
+            b"new"
+        );
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let temp_dir = tempdir().unwrap();
+        let build = |file_name: &str| {
+            let output_path = temp_dir.path().join(file_name);
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+                for i in 0..32 {
+                    builder
+                        .add_file(format!("data\\file{}.gat", i), &[i as u8; 64][..])
+                        .unwrap();
+                }
+            }
+            std::fs::read(output_path).unwrap()
+        };
+        assert_eq!(build("builder1.grf"), build("builder2.grf"));
+    }
 }
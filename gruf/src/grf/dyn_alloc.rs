@@ -146,8 +146,10 @@ impl AvailableChunkList {
         }
         // Check right merge
         if chunk_end_offset == self.end_offset {
-            // "Merge" to the right
+            // "Merge" to the right, the chunk isn't available anymore since
+            // it's now past the end
             self.end_offset = new_chunk_offset;
+            return Ok(());
         } else if self.chunks.contains_key(&chunk_end_offset) {
             // Merge to the right with another chunk
             let chunk = self
@@ -159,6 +161,12 @@ impl AvailableChunkList {
         Ok(())
     }
 
+    /// Returns the total size of the available chunks, that is of the space
+    /// left between used chunks
+    pub fn available_size(&self) -> u64 {
+        self.chunks.values().map(|chunk| chunk.size as u64).sum()
+    }
+
     fn insert_chunk_internal(&mut self, offset: u64, size: usize) {
         self.sizes.insert((size, offset));
         self.chunks.insert(offset, AvailableChunk { size });
@@ -202,6 +210,38 @@ mod tests {
         assert_eq!(START_OFFSET + size1 as u64 + size2 as u64, res);
     }
 
+    #[test]
+    fn test_chunk_list_available_size() {
+        let chunk_size: usize = 64;
+        let mut chunk_list = AvailableChunkList::new();
+        let offset1 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        let offset2 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        let offset3 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        assert_eq!(chunk_list.available_size(), 0);
+
+        chunk_list.free_chunk(offset1, chunk_size).unwrap();
+        chunk_list.free_chunk(offset2, chunk_size).unwrap();
+        assert_eq!(chunk_list.available_size(), 2 * chunk_size as u64);
+        // Space freed at the end isn't between used chunks
+        chunk_list.free_chunk(offset3, chunk_size).unwrap();
+        assert_eq!(chunk_list.available_size(), 0);
+    }
+
+    #[test]
+    fn test_chunk_list_free_last_chunks() {
+        let chunk_size: usize = 64;
+        let mut chunk_list = AvailableChunkList::new();
+        let offset1 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        let offset2 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        chunk_list.free_chunk(offset2, chunk_size).unwrap();
+        chunk_list.free_chunk(offset1, chunk_size).unwrap();
+        // Chunks freed at the end mustn't be handed out twice
+        let res1 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        let res2 = chunk_list.alloc_chunk(chunk_size).unwrap();
+        assert_eq!(res1, START_OFFSET);
+        assert_eq!(res2, START_OFFSET + chunk_size as u64);
+    }
+
     #[test]
     fn test_chunk_list_realloc() {
         let chunk_size: usize = 64;
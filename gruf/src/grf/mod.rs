@@ -3,8 +3,10 @@ pub mod reader;
 
 pub use builder::GrfArchiveBuilder;
 pub use reader::{GrfArchive, GrfFileEntry};
+pub use undo_journal::{rollback_undo_journal, JournaledFile};
 
 mod crypto;
 mod dyn_alloc;
+mod undo_journal;
 
 use reader::{GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
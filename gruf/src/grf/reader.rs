@@ -1,17 +1,18 @@
 use std::boxed::Box;
 use std::collections::HashMap;
-use std::convert::TryInto;
-use std::fs::File;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::str;
 
+use crate::archive::{zlib_decompress_bounded, DecompressionLimits};
 use crate::grf::crypto::{decrypt_file_content, decrypt_file_name};
 use crate::{GrufError, Result};
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
-use flate2::read::ZlibDecoder;
 use nom::error::ErrorKind;
 use nom::number::complete::{le_i32, le_u32, le_u8};
 use nom::*;
@@ -25,31 +26,77 @@ const GRF_TABLE_INFO2_SIZE: usize = 2 * std::mem::size_of::<u32>();
 pub struct GrfArchive {
     obj: Box<File>,
     container: GrfContainer,
+    limits: DecompressionLimits,
 }
 
 impl GrfArchive {
     /// Create a new archive with the underlying object as the reader.
     pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
-        let mut file = File::open(grf_path)?;
-        let mut grf_header_buf = [0; GRF_HEADER_SIZE];
-        file.read_exact(&mut grf_header_buf)?;
+        Self::open_with_limits(grf_path, DecompressionLimits::default())
+    }
+
+    /// Create a new archive with the underlying object as the reader, `limits`
+    /// being enforced when decompressing the file table and entries.
+    pub fn open_with_limits<P: AsRef<Path>>(
+        grf_path: P,
+        limits: DecompressionLimits,
+    ) -> Result<Self> {
+        let file = File::open(grf_path)?;
+        Self::from_file(file, limits)
+    }
+
+    /// Opens an archive in read-only mode, holding a shared lock on it for as
+    /// long as the archive is open.
+    ///
+    /// Any number of processes can read the archive that way (e.g., while the
+    /// game client is running) but `GrfArchiveBuilder` cannot modify it in the
+    /// meantime, and archives that are being modified cannot be opened
+    /// (`GrufError::Locked`).
+    pub fn open_shared<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        let mut open_options = OpenOptions::new();
+        open_options.read(true);
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_SHARE_READ: u32 = 0x1;
+            const FILE_SHARE_WRITE: u32 = 0x2;
+            // Processes that already have the archive open (e.g., the game
+            // client) must not prevent it from being opened
+            open_options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE);
+        }
+        let file = open_options.open(grf_path)?;
+        AdvisoryFileLock::try_lock(&file, FileLockMode::Shared)
+            .map_err(GrufError::from_lock_error)?;
+        Self::from_file(file, DecompressionLimits::default())
+    }
+
+    fn from_file(mut file: File, limits: DecompressionLimits) -> Result<Self> {
+        // Offsets and sizes are checked against the archive's size, since they
+        // cannot be trusted
+        let archive_size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut grf_header_buf = Vec::with_capacity(GRF_HEADER_SIZE);
+        let mut file_chunk = file.by_ref().take(grf_header_buf.capacity() as u64);
+        file_chunk.read_to_end(&mut grf_header_buf)?;
+        if !grf_header_buf.starts_with(GRF_HEADER_MAGIC.as_bytes()) {
+            return Err(GrufError::BadMagic("GRF"));
+        }
         let (parser_output, grf_header) = parse_grf_header(&grf_header_buf)
-            .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
+            .map_err(|e| GrufError::from_nom_error(e, "GRF header"))?;
 
         match grf_header.version_major {
             2 => {
                 let mut table_info_buf = [0; GRF_TABLE_INFO2_SIZE];
-                file.seek(SeekFrom::Start(
-                    GRF_HEADER_SIZE as u64 + grf_header.file_table_offset,
-                ))?;
-                file.read_exact(&mut table_info_buf)?;
+                let table_info_offset = GRF_HEADER_SIZE as u64 + grf_header.file_table_offset;
+                file.seek(SeekFrom::Start(table_info_offset))?;
+                file.read_exact(&mut table_info_buf)
+                    .map_err(|e| GrufError::from_read_error(e, "GRF table info"))?;
                 let (_parser_output, grf_table_info) = parse_grf_table_info_200(&table_info_buf)
-                    .map_err(|_| {
-                        GrufError::parsing_error("Failed to parse archive (table info)")
-                    })?;
+                    .map_err(|e| GrufError::from_nom_error(e, "GRF table info"))?;
                 if grf_table_info.table_size_compressed == 0 || grf_table_info.table_size == 0 {
                     return Ok(Self {
                         obj: Box::new(file),
+                        limits,
                         container: GrfContainer {
                             header: grf_header,
                             table_info: GrfTableInfo::Compressed(grf_table_info),
@@ -57,25 +104,43 @@ impl GrfArchive {
                         },
                     });
                 }
+                if !limits.allows(
+                    grf_table_info.table_size,
+                    grf_table_info.table_size_compressed,
+                ) {
+                    return Err(GrufError::DecompressionLimitExceeded(
+                        "GRF file table".to_string(),
+                    ));
+                }
+                let table_end = table_info_offset
+                    + GRF_TABLE_INFO2_SIZE as u64
+                    + grf_table_info.table_size_compressed as u64;
+                if table_end > archive_size {
+                    return Err(GrufError::Truncated("GRF file table"));
+                }
                 // Decompress the table with zlib
                 let mut compressed_table: Vec<u8> =
                     Vec::with_capacity(grf_table_info.table_size_compressed);
                 let mut file_chunk = file.by_ref().take(compressed_table.capacity() as u64);
                 file_chunk.read_to_end(&mut compressed_table)?;
-                let mut decoder = ZlibDecoder::new(compressed_table.as_slice());
-                let mut decompressed_table = vec![];
-                let _decompressed_size =
-                    decoder.read_to_end(&mut decompressed_table).map_err(|e| {
-                        GrufError::ParsingError(format!("Failed to decompress file table: {}", e))
-                    })?;
+                let decompressed_table =
+                    zlib_decompress_bounded(&compressed_table, grf_table_info.table_size as u64)
+                        .map_err(|e| GrufError::ZlibError("GRF file table", e))?;
                 // Parse entries
                 let (_output, entries) = parse_grf_file_entries_200(
                     decompressed_table.as_slice(),
                     grf_header.file_count,
+                    file_table_capacity(
+                        grf_header.file_count,
+                        decompressed_table.len(),
+                        MIN_FILE_ENTRY_SIZE_200,
+                    ),
                 )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+                .map_err(|e| GrufError::from_nom_error(e, "GRF file table"))?;
+                check_file_entries(&entries, archive_size)?;
                 Ok(Self {
                     obj: Box::new(file),
+                    limits,
                     container: GrfContainer {
                         header: grf_header,
                         table_info: GrfTableInfo::Compressed(grf_table_info),
@@ -92,6 +157,7 @@ impl GrfArchive {
                 if table_size == 0 {
                     return Ok(Self {
                         obj: Box::new(file),
+                        limits,
                         container: GrfContainer {
                             header: grf_header,
                             table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
@@ -100,14 +166,23 @@ impl GrfArchive {
                     });
                 }
                 // Parse entries
-                let (_parser_output, entries) = parse_grf_file_entries_101(
-                    &parser_output[grf_header.file_table_offset as usize..],
+                let file_table = usize::try_from(grf_header.file_table_offset)
+                    .ok()
+                    .and_then(|file_table_offset| parser_output.get(file_table_offset..))
+                    .ok_or(GrufError::Truncated("GRF file table"))?;
+                let capacity = file_table_capacity(
                     grf_header.file_count,
-                )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+                    file_table.len(),
+                    MIN_FILE_ENTRY_SIZE_101,
+                );
+                let (_parser_output, entries) =
+                    parse_grf_file_entries_101(file_table, grf_header.file_count, capacity)
+                        .map_err(|e| GrufError::from_nom_error(e, "GRF file table"))?;
+                check_file_entries(&entries, archive_size)?;
 
                 Ok(Self {
                     obj: Box::new(file),
+                    limits,
                     container: GrfContainer {
                         header: grf_header,
                         table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
@@ -147,6 +222,31 @@ impl GrfArchive {
         Ok(content)
     }
 
+    /// Copies an entry's raw (compressed and possibly encrypted) data into
+    /// `writer` in chunks, instead of reading it into memory at once.
+    ///
+    /// Returns the number of bytes copied.
+    pub fn copy_entry_raw_data<S: AsRef<str> + Hash, W: io::Write + ?Sized>(
+        &mut self,
+        file_path: S,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?
+            .clone();
+        if file_entry.size == 0 {
+            return Ok(0);
+        }
+
+        self.obj.seek(SeekFrom::Start(file_entry.offset))?;
+        let mut file_chunk = self
+            .obj
+            .by_ref()
+            .take(file_entry.size_compressed_aligned as u64);
+        Ok(io::copy(&mut file_chunk, writer)?)
+    }
+
     pub fn read_file_content<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
         let file_entry = self
             .get_file_entry(file_path)
@@ -155,6 +255,15 @@ impl GrfArchive {
         if file_entry.size == 0 {
             return Ok(vec![]);
         }
+        if !self
+            .limits
+            .allows(file_entry.size, file_entry.size_compressed)
+        {
+            return Err(GrufError::DecompressionLimitExceeded(format!(
+                "entry '{}'",
+                file_entry.relative_path
+            )));
+        }
 
         self.obj.seek(SeekFrom::Start(file_entry.offset))?;
         let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed_aligned);
@@ -166,11 +275,10 @@ impl GrfArchive {
                 decrypt_file_content(&mut content, cycle);
             }
         }
-        // Decompress the content with zlib
-        let mut decoder = ZlibDecoder::new(content.as_slice());
-        let mut decompressed_content = Vec::new();
-        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
-        if decompressed_size != file_entry.size {
+        // Decompress the content with zlib, never beyond the declared size
+        let decompressed_content = zlib_decompress_bounded(&content, file_entry.size as u64)
+            .map_err(|e| GrufError::ZlibError("file entry", e))?;
+        if decompressed_content.len() != file_entry.size {
             return Err(GrufError::parsing_error(
                 "Decompressed content is not as expected",
             ));
@@ -257,22 +365,48 @@ pub enum GrfFileEncryption {
 named!(parse_grf_header<&[u8], GrfHeader>,
     do_parse!(
         tag!(GRF_HEADER_MAGIC)
-            >> key: take!(14)
+            >> key: map_res!(take!(14), |key: &[u8]| key.try_into())
             >> file_table_offset: le_u32
             >> seed: le_i32
-            >> v_files_count: le_i32
+            >> file_count: map_opt!(le_i32, |v_files_count| grf_file_count(v_files_count, seed))
             >> version: le_u32
             >> (GrfHeader {
-                key: key.try_into().unwrap(),
+                key,
                 file_table_offset: file_table_offset as u64,
                 seed,
-                file_count: (v_files_count - seed - 7) as usize,
+                file_count,
                 version_major: (version >> 8) & 0xFF,
                 version_minor: version & 0xFF
             }
     )
 ));
 
+/// Computes the number of files from the header's fields.
+///
+/// Returns `None` if the header is invalid.
+fn grf_file_count(v_files_count: i32, seed: i32) -> Option<usize> {
+    usize::try_from(i64::from(v_files_count) - i64::from(seed) - 7).ok()
+}
+
+/// Makes sure that entries can be read safely. Whether their paths are safe to
+/// extract to is up to the caller (see `find_entry_path_issue`).
+fn check_file_entries(entries: &HashMap<String, GrfFileEntry>, archive_size: u64) -> Result<()> {
+    for entry in entries.values() {
+        // The content of empty entries isn't read
+        if entry.size == 0 {
+            continue;
+        }
+        match entry
+            .offset
+            .checked_add(entry.size_compressed_aligned as u64)
+        {
+            Some(entry_end) if entry_end <= archive_size => {}
+            _ => return Err(GrufError::OversizedEntry(entry.relative_path.clone())),
+        }
+    }
+    Ok(())
+}
+
 named!(parse_grf_table_info_200<&[u8], GrfTableInfo2>,
     do_parse!(
         table_size_compressed: le_u32
@@ -307,14 +441,16 @@ macro_rules! take_obfuscated_name_101 (
 
 fn determine_file_encryption_101(file_name: &str, size_compressed: usize) -> GrfFileEncryption {
     const SPECIAL_EXTENSIONS: [&str; 4] = [".gnd", ".gat", ".act", ".str"];
-    let file_name_len = file_name.len();
-    if file_name_len < 4 {
+    if file_name.len() < 4 {
         return GrfFileEncryption::Encrypted(0);
     }
-    let file_extension = &file_name[file_name_len - 4..];
-    match SPECIAL_EXTENSIONS.iter().position(|&r| r == file_extension) {
-        Some(_) => GrfFileEncryption::Encrypted(0),
-        None => GrfFileEncryption::Encrypted(digit_count(size_compressed)),
+    if SPECIAL_EXTENSIONS
+        .iter()
+        .any(|extension| file_name.ends_with(extension))
+    {
+        GrfFileEncryption::Encrypted(0)
+    } else {
+        GrfFileEncryption::Encrypted(digit_count(size_compressed))
     }
 }
 
@@ -333,25 +469,39 @@ fn digit_count(n: usize) -> usize {
     result
 }
 
+/// Returns the number of entries a file table of `table_size` bytes can hold,
+/// at most, so that big tables are allocated once without trusting the
+/// header's file count blindly.
+fn file_table_capacity(file_count: usize, table_size: usize, min_entry_size: usize) -> usize {
+    file_count.min(table_size / min_entry_size)
+}
+
+// Sizes of entries with an empty path
+const MIN_FILE_ENTRY_SIZE_101: usize = 27;
+const MIN_FILE_ENTRY_SIZE_200: usize = 18;
+
 // Parses file table entries for GRF 1.1, 1.2 and 1.3
 named!(parse_grf_file_entry_101<&[u8], GrfFileEntry>,
     do_parse!(
-        path_size_padded: le_u32
+        path_size_padded: verify!(le_u32, |path_size_padded: &u32| *path_size_padded >= 6)
             >> take!(2) // Null chars
             >> relative_path: take_obfuscated_name_101!(path_size_padded - 6)
             >> take!(4) // Null chars
             >> size_tot_enc: le_u32
             >> size_compressed_aligned_enc: le_u32
             >> size: le_u32
+            // Sizes are obfuscated, invalid entries must not underflow
+            >> size_compressed: map_opt!(value!(()), |_| size_tot_enc.checked_sub(size)?.checked_sub(0x02CB))
+            >> size_compressed_aligned: map_opt!(value!(()), |_| size_compressed_aligned_enc.checked_sub(0x92CB))
             >> entry_type: le_u8
             >> offset: le_u32
             >> (GrfFileEntry {
-                size_compressed: (size_tot_enc - size - 0x02CB) as usize,
-                size_compressed_aligned: (size_compressed_aligned_enc - 0x92CB) as usize,
+                size_compressed: size_compressed as usize,
+                size_compressed_aligned: size_compressed_aligned as usize,
                 size: size as usize,
                 entry_type,
                 offset: GRF_HEADER_SIZE as u64 + offset as u64,
-                encryption: determine_file_encryption_101(&relative_path, (size_tot_enc - size - 0x02CB) as usize),
+                encryption: determine_file_encryption_101(&relative_path, size_compressed as usize),
                 relative_path,
             }
         )
@@ -381,15 +531,15 @@ named!(parse_grf_file_entry_200<&[u8], GrfFileEntry>,
     )
 );
 
-named_args!(parse_grf_file_entries_101(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
-fold_many_m_n!(1, files_count - 1, parse_grf_file_entry_101, HashMap::new(), |mut acc: HashMap<_, _>, item| {
+named_args!(parse_grf_file_entries_101(files_count: usize, capacity: usize)<&[u8], HashMap<String, GrfFileEntry>>,
+fold_many_m_n!(1, files_count.saturating_sub(1), parse_grf_file_entry_101, HashMap::with_capacity(capacity), |mut acc: HashMap<_, _>, item| {
         acc.insert(item.relative_path.clone(), item);
         acc
     })
 );
 
-named_args!(parse_grf_file_entries_200(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
-fold_many_m_n!(1, files_count, parse_grf_file_entry_200, HashMap::new(), |mut acc: HashMap<_, _>, item| {
+named_args!(parse_grf_file_entries_200(files_count: usize, capacity: usize)<&[u8], HashMap<String, GrfFileEntry>>,
+fold_many_m_n!(1, files_count, parse_grf_file_entry_200, HashMap::with_capacity(capacity), |mut acc: HashMap<_, _>, item| {
         acc.insert(item.relative_path.clone(), item);
         acc
     })
@@ -398,8 +548,11 @@ fold_many_m_n!(1, files_count, parse_grf_file_entry_200, HashMap::new(), |mut ac
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grf::GrfArchiveBuilder;
     use hex_literal::hex;
+    use std::io::{Cursor, Write};
     use std::path::PathBuf;
+    use tempfile::tempdir;
     use twox_hash::XxHash64;
 
     #[test]
@@ -510,6 +663,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_file_table_capacity() {
+        assert_eq!(
+            file_table_capacity(500_000, 20_000_000, MIN_FILE_ENTRY_SIZE_200),
+            500_000
+        );
+        // Bogus file counts don't get to allocate more than the table holds
+        assert_eq!(
+            file_table_capacity(usize::MAX, 180, MIN_FILE_ENTRY_SIZE_200),
+            10
+        );
+    }
+
     #[test]
     fn test_digit_count() {
         assert_eq!(1, digit_count(0));
@@ -519,4 +685,86 @@ mod tests {
         assert_eq!(3, digit_count(100));
         assert_eq!(8, digit_count(87654321));
     }
+
+    #[test]
+    fn test_open_malformed_container() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("malformed.grf");
+        let open_grf_with_content = |content: &[u8]| {
+            File::create(&grf_path).unwrap().write_all(content).unwrap();
+            GrfArchive::open(&grf_path)
+        };
+        assert!(matches!(
+            open_grf_with_content(b"PK\x03\x04").unwrap_err(),
+            GrufError::BadMagic(_)
+        ));
+        // Truncated archives must be rejected, without panicking
+        let mut grf_data = vec![];
+        {
+            let mut builder = GrfArchiveBuilder::create(Cursor::new(&mut grf_data), 2, 0).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &b"content"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        assert!(open_grf_with_content(&grf_data).is_ok());
+        for size in 0..grf_data.len() {
+            assert!(open_grf_with_content(&grf_data[..size]).is_err());
+        }
+        // Invalid file count
+        let file_count_offset = GRF_HEADER_MAGIC.len() + 14 + 8;
+        grf_data[file_count_offset..file_count_offset + 4].copy_from_slice(&0_i32.to_le_bytes());
+        assert!(open_grf_with_content(&grf_data).is_err());
+    }
+
+    #[test]
+    fn test_open_shared_container() {
+        let temp_dir = tempdir().unwrap();
+        let grf_file_path = temp_dir.path().join("data.grf");
+        {
+            let grf_file = File::create(&grf_file_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &b"content"[..])
+                .unwrap();
+        }
+        {
+            let mut grf_archive = GrfArchive::open_shared(&grf_file_path).unwrap();
+            // Several readers are allowed, but no writer
+            let _other_grf_archive = GrfArchive::open_shared(&grf_file_path).unwrap();
+            assert!(matches!(
+                GrfArchiveBuilder::open(&grf_file_path).err().unwrap(),
+                GrufError::Locked
+            ));
+            assert_eq!(
+                grf_archive.read_file_content("data\\a.txt").unwrap(),
+                b"content"
+            );
+        }
+        {
+            let _builder = GrfArchiveBuilder::open(&grf_file_path).unwrap();
+            assert!(matches!(
+                GrfArchive::open_shared(&grf_file_path).unwrap_err(),
+                GrufError::Locked
+            ));
+        }
+        assert!(GrfArchive::open_shared(&grf_file_path).is_ok());
+    }
+
+    #[test]
+    fn test_determine_file_encryption_101() {
+        assert_eq!(
+            determine_file_encryption_101("data\\prontera.gat", 1234),
+            GrfFileEncryption::Encrypted(0)
+        );
+        assert_eq!(
+            determine_file_encryption_101("data\\icon.bmp", 1234),
+            GrfFileEncryption::Encrypted(4)
+        );
+        // Extensions mustn't be sliced in the middle of a character
+        assert_eq!(
+            determine_file_encryption_101("data\\\u{B8}\u{F3}", 12),
+            GrfFileEncryption::Encrypted(2)
+        );
+    }
 }
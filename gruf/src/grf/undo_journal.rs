@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{GrufError, Result};
+
+const UNDO_JOURNAL_MAGIC: &[u8; 8] = b"GRFUNDO1";
+const UNDO_JOURNAL_HEADER_SIZE: usize = UNDO_JOURNAL_MAGIC.len() + 8;
+// Offset and size of a saved block
+const UNDO_RECORD_HEADER_SIZE: usize = 8 + 4;
+// The original content is saved one block at a time, the journal being
+// synced once per block
+const UNDO_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// File whose original content is saved to an undo journal before being
+/// overwritten, so that the file can be put back in its original state with
+/// `rollback_undo_journal` if the changes can't be completed (e.g., because
+/// the process has been killed).
+///
+/// The journal is synced to disk before the content it saves is overwritten.
+/// Data written past the original end of the file isn't saved, the file is
+/// truncated instead when rolling back.
+pub struct JournaledFile {
+    file: File,
+    journal: File,
+    journal_path: PathBuf,
+    original_size: u64,
+    position: u64,
+    saved_blocks: HashSet<u64>,
+}
+
+impl JournaledFile {
+    /// Starts journaling the changes made to `file`, in a new undo journal at
+    /// `journal_path`. Fails if there's a journal there already, which must be
+    /// rolled back first.
+    pub fn new(mut file: File, journal_path: impl AsRef<Path>) -> Result<JournaledFile> {
+        let journal_path = journal_path.as_ref().to_path_buf();
+        let original_size = file.metadata()?.len();
+        let position = file.stream_position()?;
+        let mut journal = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&journal_path)?;
+        journal.write_all(UNDO_JOURNAL_MAGIC)?;
+        journal.write_all(&original_size.to_le_bytes())?;
+        journal.sync_data()?;
+        Ok(JournaledFile {
+            file,
+            journal,
+            journal_path,
+            original_size,
+            position,
+            saved_blocks: HashSet::new(),
+        })
+    }
+
+    /// Syncs the changes made to the file to disk and removes the undo
+    /// journal, after which they can't be rolled back anymore.
+    pub fn commit(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        fs::remove_file(&self.journal_path)?;
+        Ok(())
+    }
+
+    /// Saves the original content of the blocks that writing `size` bytes at
+    /// the current position would overwrite, if it hasn't been saved yet.
+    fn save_original_content(&mut self, size: u64) -> io::Result<()> {
+        let end = self.position.saturating_add(size).min(self.original_size);
+        if self.position >= end {
+            return Ok(());
+        }
+        let mut records = vec![];
+        for block in self.position / UNDO_BLOCK_SIZE..=(end - 1) / UNDO_BLOCK_SIZE {
+            if !self.saved_blocks.insert(block) {
+                continue;
+            }
+            let block_offset = block * UNDO_BLOCK_SIZE;
+            let block_size = UNDO_BLOCK_SIZE.min(self.original_size - block_offset);
+            records.extend_from_slice(&block_offset.to_le_bytes());
+            records.extend_from_slice(&(block_size as u32).to_le_bytes());
+            let content_offset = records.len();
+            records.resize(content_offset + block_size as usize, 0);
+            self.file.seek(SeekFrom::Start(block_offset))?;
+            self.file.read_exact(&mut records[content_offset..])?;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(self.position))?;
+        self.journal.write_all(&records)?;
+        self.journal.sync_data()
+    }
+}
+
+impl Write for JournaledFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.save_original_content(buf.len() as u64)?;
+        let written_size = self.file.write(buf)?;
+        self.position += written_size as u64;
+        Ok(written_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for JournaledFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Puts the file at `file_path` back in the state it was in when the undo
+/// journal at `journal_path` was created, then removes the journal.
+///
+/// Returns `false` if there's no journal. A record truncated by a crash is
+/// ignored, since the content it saves hadn't been overwritten yet.
+pub fn rollback_undo_journal(
+    file_path: impl AsRef<Path>,
+    journal_path: impl AsRef<Path>,
+) -> Result<bool> {
+    let journal_path = journal_path.as_ref();
+    let mut journal = match File::open(journal_path) {
+        Ok(v) => BufReader::new(v),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = [0; UNDO_JOURNAL_HEADER_SIZE];
+    match journal.read_exact(&mut header) {
+        Ok(()) => {}
+        // Nothing had been written yet
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            drop(journal);
+            fs::remove_file(journal_path)?;
+            return Ok(true);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if &header[..UNDO_JOURNAL_MAGIC.len()] != UNDO_JOURNAL_MAGIC {
+        return Err(GrufError::BadMagic("undo journal"));
+    }
+    let original_size = u64::from_le_bytes(read_array(&header[UNDO_JOURNAL_MAGIC.len()..]));
+
+    let mut file = OpenOptions::new().write(true).open(file_path)?;
+    let mut record_header = [0; UNDO_RECORD_HEADER_SIZE];
+    let mut content = vec![];
+    loop {
+        match journal.read_exact(&mut record_header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let block_offset = u64::from_le_bytes(read_array(&record_header[..8]));
+        let block_size = u32::from_le_bytes(read_array(&record_header[8..]));
+        content.resize(usize::try_from(block_size)?, 0);
+        match journal.read_exact(&mut content) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        file.seek(SeekFrom::Start(block_offset))?;
+        file.write_all(&content)?;
+    }
+    file.set_len(original_size)?;
+    file.sync_all()?;
+    drop(journal);
+    fs::remove_file(journal_path)?;
+    Ok(true)
+}
+
+fn read_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(&bytes[..N]);
+    array
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_undo_journal() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("data.grf");
+        let journal_path = temp_dir.path().join("data.grf.undo");
+        let original_content: Vec<u8> = (0..3 * UNDO_BLOCK_SIZE / 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&file_path, &original_content).unwrap();
+        assert!(!rollback_undo_journal(&file_path, &journal_path).unwrap());
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        let mut journaled_file = JournaledFile::new(file, &journal_path).unwrap();
+        // Overwrite both blocks, twice, and append data
+        for _ in 0..2 {
+            journaled_file.seek(SeekFrom::Start(10)).unwrap();
+            journaled_file.write_all(&[0xFF; 20]).unwrap();
+            journaled_file
+                .seek(SeekFrom::Start(UNDO_BLOCK_SIZE - 5))
+                .unwrap();
+            journaled_file.write_all(&[0xFE; 10]).unwrap();
+        }
+        journaled_file.seek(SeekFrom::End(0)).unwrap();
+        journaled_file.write_all(b"appended").unwrap();
+        drop(journaled_file);
+        assert_ne!(fs::read(&file_path).unwrap(), original_content);
+
+        assert!(rollback_undo_journal(&file_path, &journal_path).unwrap());
+        assert_eq!(fs::read(&file_path).unwrap(), original_content);
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn test_commit_undo_journal() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("data.grf");
+        let journal_path = temp_dir.path().join("data.grf.undo");
+        fs::write(&file_path, b"original").unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        let mut journaled_file = JournaledFile::new(file, &journal_path).unwrap();
+        journaled_file.write_all(b"modified").unwrap();
+        // Journals left behind must be rolled back first
+        let other_file = File::open(&file_path).unwrap();
+        assert!(JournaledFile::new(other_file, &journal_path).is_err());
+        journaled_file.commit().unwrap();
+        assert!(!journal_path.exists());
+        assert!(!rollback_undo_journal(&file_path, &journal_path).unwrap());
+        assert_eq!(fs::read(&file_path).unwrap(), b"modified");
+    }
+}
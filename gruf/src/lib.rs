@@ -1,6 +1,8 @@
 mod archive;
+pub mod charset;
 mod error;
 pub mod grf;
 pub mod thor;
 
+pub use archive::{find_entry_path_issue, DecompressionLimits, EntryPathIssue};
 pub use error::{GrufError, Result};
@@ -0,0 +1,308 @@
+const OUT_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+// Mixes a column or a diagonal of the state
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(
+    chaining_value: &[u32; 8],
+    block_words: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        chaining_value[0],
+        chaining_value[1],
+        chaining_value[2],
+        chaining_value[3],
+        chaining_value[4],
+        chaining_value[5],
+        chaining_value[6],
+        chaining_value[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut block = *block_words;
+    for round_number in 0..7 {
+        round(&mut state, &block);
+        if round_number < 6 {
+            let mut permuted = [0; 16];
+            for (i, &source) in MSG_PERMUTATION.iter().enumerate() {
+                permuted[i] = block[source];
+            }
+            block = permuted;
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn first_8_words(compression_output: [u32; 16]) -> [u32; 8] {
+    let mut words = [0; 8];
+    words.copy_from_slice(&compression_output[..8]);
+    words
+}
+
+fn words_from_le_bytes(bytes: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, word_bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([word_bytes[0], word_bytes[1], word_bytes[2], word_bytes[3]]);
+    }
+    words
+}
+
+/// Compression input of a node of the tree, which either gives the node's
+/// chaining value or the root's output.
+struct Output {
+    input_chaining_value: [u32; 8],
+    block_words: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8_words(compress(
+            &self.input_chaining_value,
+            &self.block_words,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root_hash(&self) -> [u8; OUT_LEN] {
+        let words = compress(
+            &self.input_chaining_value,
+            &self.block_words,
+            0,
+            self.block_len,
+            self.flags | ROOT,
+        );
+        let mut hash = [0; OUT_LEN];
+        for (hash_bytes, word) in hash.chunks_exact_mut(4).zip(words.iter()) {
+            hash_bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        hash
+    }
+}
+
+fn parent_output(left_child_cv: [u32; 8], right_child_cv: [u32; 8]) -> Output {
+    let mut block_words = [0; 16];
+    block_words[..8].copy_from_slice(&left_child_cv);
+    block_words[8..].copy_from_slice(&right_child_cv);
+    Output {
+        input_chaining_value: IV,
+        block_words,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+/// Input of the chunk (1 KiB of input) being hashed.
+struct ChunkState {
+    chaining_value: [u32; 8],
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> ChunkState {
+        ChunkState {
+            chaining_value: IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // The last block is compressed by `output`, with the CHUNK_END flag
+            if self.block_len == BLOCK_LEN {
+                self.chaining_value = first_8_words(compress(
+                    &self.chaining_value,
+                    &words_from_le_bytes(&self.block),
+                    self.chunk_counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            input_chaining_value: self.chaining_value,
+            block_words: words_from_le_bytes(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// Computes the BLAKE3 hash (default mode, 32 bytes) of the data it's given,
+/// in any number of writes. Follows the specification's reference
+/// implementation.
+pub(crate) struct Blake3Hasher {
+    chunk_state: ChunkState,
+    chaining_value_stack: Vec<[u32; 8]>, // Subtrees whose sibling isn't complete yet
+}
+
+impl Blake3Hasher {
+    pub fn new() -> Blake3Hasher {
+        Blake3Hasher {
+            chunk_state: ChunkState::new(0),
+            chaining_value_stack: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // Only finish the current chunk once there's more input, since the
+            // last one is the root if it's the only one
+            if self.chunk_state.len() == CHUNK_LEN {
+                let chunk_cv = self.chunk_state.output().chaining_value();
+                let total_chunks = self.chunk_state.chunk_counter + 1;
+                self.add_chunk_chaining_value(chunk_cv, total_chunks);
+                self.chunk_state = ChunkState::new(total_chunks);
+            }
+            let take = (CHUNK_LEN - self.chunk_state.len()).min(input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; OUT_LEN] {
+        let mut output = self.chunk_state.output();
+        for &left_child_cv in self.chaining_value_stack.iter().rev() {
+            output = parent_output(left_child_cv, output.chaining_value());
+        }
+        output.root_hash()
+    }
+
+    // Merges the completed subtrees, as many as the number of trailing zero
+    // bits in the total number of chunks
+    fn add_chunk_chaining_value(&mut self, mut new_cv: [u32; 8], mut total_chunks: u64) {
+        while total_chunks & 1 == 0 {
+            let left_child_cv = match self.chaining_value_stack.pop() {
+                Some(v) => v,
+                None => break,
+            };
+            new_cv = parent_output(left_child_cv, new_cv).chaining_value();
+            total_chunks >>= 1;
+        }
+        self.chaining_value_stack.push(new_cv);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    fn blake3(data: &[u8]) -> [u8; OUT_LEN] {
+        let mut hasher = Blake3Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    #[test]
+    fn test_blake3() {
+        assert_eq!(
+            blake3(b""),
+            hex!("af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262")
+        );
+        assert_eq!(
+            blake3(b"abc"),
+            hex!("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85")
+        );
+        // Inputs of the official test vectors, spanning several chunks
+        let data: Vec<u8> = (0..5 * CHUNK_LEN + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(
+            blake3(&data[..1024]),
+            hex!("42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7")
+        );
+        assert_eq!(
+            blake3(&data[..1025]),
+            hex!("d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444")
+        );
+        assert_eq!(
+            blake3(&data[..2048]),
+            hex!("e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a")
+        );
+        // Hashing in several writes mustn't make a difference, whatever the
+        // number of chunks and blocks
+        let mut hasher = Blake3Hasher::new();
+        for piece in data.chunks(BLOCK_LEN + 7) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finalize(), blake3(&data));
+    }
+}
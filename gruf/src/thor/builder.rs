@@ -1,32 +1,40 @@
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 use crate::archive::{serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry};
+use crate::thor::checksum::{Checksum, ChecksumAlgorithm, ChecksumHasher};
 use crate::thor::{
     ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
 use crate::Result;
-use crc::crc32::{self, Hasher32};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
 
 const THOR_HEADER_FIXED_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8;
 
+/// Builds THOR archives.
+///
+/// The output only depends on the entries appended (and their order): the
+/// file table and 'data.integrity' are sorted by path and the compression
+/// level is fixed, so that identical inputs produce identical archives.
 pub struct ThorArchiveBuilder<W: Write + Seek> {
     obj: Box<W>,
-    entries: HashMap<String, Option<BuilderFileEntry>>,
+    entries: BTreeMap<String, Option<BuilderFileEntry>>, // Ordered by path
     finished: bool,
     use_grf_merging: bool,
     target_grf_name: String,
     include_checksums: bool,
+    checksum_algorithm: ChecksumAlgorithm,
+    file_table_size: u64,     // Uncompressed, without 'data.integrity'
+    data_integrity_size: u64, // Uncompressed
 }
 
 struct BuilderFileEntry {
     generic: GenericFileEntry,
-    checksum: u32,
+    checksum: Option<Checksum>, // None if checksums aren't included
 }
 
 #[derive(Debug, Serialize)]
@@ -74,14 +82,24 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         obj.write_all(place_holder.as_slice())?;
         Ok(Self {
             obj: Box::new(obj),
-            entries: HashMap::new(),
+            entries: BTreeMap::new(),
             finished: false,
             use_grf_merging,
             target_grf_name,
             include_checksums,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            file_table_size: 0,
+            data_integrity_size: 0,
         })
     }
 
+    /// Sets the algorithm used to hash the entries listed in
+    /// 'data.integrity', when checksums are included. Only affects the entries
+    /// appended afterwards.
+    pub fn set_checksum_algorithm(&mut self, checksum_algorithm: ChecksumAlgorithm) {
+        self.checksum_algorithm = checksum_algorithm;
+    }
+
     pub fn append_file_update<R>(&mut self, entry_path: String, mut data: R) -> Result<()>
     where
         R: Read,
@@ -89,9 +107,11 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         // Compress it
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
         let (data_size, data_checksum) = if self.include_checksums {
-            copy_and_measure_crc32(data.by_ref(), &mut encoder)?
+            let (data_size, data_checksum) =
+                copy_and_measure_checksum(data.by_ref(), &mut encoder, self.checksum_algorithm)?;
+            (data_size, Some(data_checksum))
         } else {
-            (io::copy(data.by_ref(), &mut encoder)?, 0)
+            (io::copy(data.by_ref(), &mut encoder)?, None)
         };
         // Write compressed data
         let compressed_data = encoder.finish()?;
@@ -100,6 +120,11 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         let offset = self.obj.seek(SeekFrom::Current(0))?;
         let mut compressed_reader = Cursor::new(compressed_data);
         let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
+        self.file_table_size += file_table_entry_size(&entry_path, false);
+        if data_checksum.is_some() {
+            self.data_integrity_size +=
+                data_integrity_line_size(&entry_path, self.checksum_algorithm);
+        }
         self.entries.insert(
             entry_path,
             Some(BuilderFileEntry {
@@ -114,7 +139,37 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         Ok(())
     }
 
+    /// Returns the number of bytes written so far: the header and the
+    /// entries' compressed data, but not the file table (written by `finish`).
+    pub fn written_size(&mut self) -> Result<u64> {
+        Ok(self.obj.stream_position()?)
+    }
+
+    /// Returns the maximum size of the archive, were it finished right after
+    /// appending an entry named `entry_path` of `size` bytes (`None` for a
+    /// removal): its data, file table entry and 'data.integrity' line are
+    /// accounted for, as if nothing compressed.
+    pub fn size_bound_after(&mut self, entry_path: &str, size: Option<u64>) -> Result<u64> {
+        let mut size_bound = self.written_size()?;
+        let mut file_table_size =
+            self.file_table_size + file_table_entry_size(entry_path, size.is_none());
+        if let Some(size) = size {
+            size_bound += compressed_size_bound(size);
+        }
+        if self.include_checksums {
+            let mut data_integrity_size = self.data_integrity_size;
+            if size.is_some() {
+                data_integrity_size +=
+                    data_integrity_line_size(entry_path, self.checksum_algorithm);
+            }
+            size_bound += compressed_size_bound(data_integrity_size);
+            file_table_size += file_table_entry_size(INTEGRITY_FILE_NAME, false);
+        }
+        Ok(size_bound + compressed_size_bound(file_table_size))
+    }
+
     pub fn append_file_removal(&mut self, entry_path: String) {
+        self.file_table_size += file_table_entry_size(&entry_path, true);
         self.entries.insert(entry_path, None);
     }
 
@@ -190,10 +245,9 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
 
     fn generate_data_integrity(&self) -> Result<Vec<u8>> {
         let content = self.entries.iter().fold(String::new(), |acc, v| {
-            if let Some(entry) = v.1 {
-                acc + format!("{}=0x{:08x}\r\n", v.0, entry.checksum).as_str()
-            } else {
-                acc
+            match v.1.as_ref().and_then(|entry| entry.checksum) {
+                Some(checksum) => acc + format!("{}={}\r\n", v.0, checksum).as_str(),
+                None => acc,
             }
         });
         serialize_to_win1252(content.as_str())
@@ -262,27 +316,57 @@ fn serialize_thor_slice_into<W: Write>(mut writer: W, slice: &[u8]) -> Result<()
     Ok(())
 }
 
-/// Computes a CRC32 checksum from a reader.
-fn copy_and_measure_crc32<R: ?Sized, W: ?Sized>(
+/// Returns the size of an entry in the (uncompressed) file table: its path's
+/// size, its path and either a removal flag or a `SerializableThorFileEntryAdd`.
+///
+/// Paths are assumed to take as many bytes in Windows-1252 as in UTF-8, which
+/// is the most they can take.
+fn file_table_entry_size(entry_path: &str, is_removal: bool) -> u64 {
+    const REMOVAL_SIZE: usize = std::mem::size_of::<u8>();
+    const ADDITION_SIZE: usize = std::mem::size_of::<u8>() + 3 * std::mem::size_of::<u32>();
+    let entry_size = if is_removal {
+        REMOVAL_SIZE
+    } else {
+        ADDITION_SIZE
+    };
+    (std::mem::size_of::<u8>() + entry_path.len() + entry_size) as u64
+}
+
+/// Returns the size of an entry's `<path>=<checksum>\r\n` line in
+/// 'data.integrity'. Checksums of a given algorithm all have the same length.
+fn data_integrity_line_size(entry_path: &str, algorithm: ChecksumAlgorithm) -> u64 {
+    let checksum = Checksum::of(algorithm, &[]);
+    format!("{}={}\r\n", entry_path, checksum).len() as u64
+}
+
+/// Returns the maximum size of `size` bytes of data compressed with zlib, like
+/// zlib's `compressBound`.
+fn compressed_size_bound(size: u64) -> u64 {
+    size + (size >> 12) + (size >> 14) + (size >> 25) + 13
+}
+
+/// Computes a checksum from a reader.
+fn copy_and_measure_checksum<R, W>(
     reader: &mut R,
     writer: &mut W,
-) -> Result<(u64, u32)>
+    algorithm: ChecksumAlgorithm,
+) -> Result<(u64, Checksum)>
 where
-    R: Read,
-    W: Write,
+    R: Read + ?Sized,
+    W: Write + ?Sized,
 {
     // Use an 8KiB buffer
     let mut buf = [0_u8; 8 * 1024];
-    let mut digest = crc32::Digest::new(crc::crc32::IEEE);
+    let mut hasher = ChecksumHasher::new(algorithm);
     let mut written = 0;
     loop {
         let len = match reader.read(&mut buf) {
-            Ok(0) => return Ok((written, digest.sum32())),
+            Ok(0) => return Ok((written, hasher.finish())),
             Ok(len) => len,
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => return Err(e.into()),
         };
-        digest.write(&buf[..len]);
+        hasher.write(&buf[..len]);
         writer.write_all(&buf[..len])?;
         written += len as u64;
     }
@@ -292,6 +376,7 @@ where
 mod tests {
     use super::*;
     use crate::thor::{ThorArchive, ThorFileEntry};
+    use std::collections::HashMap;
     use std::fs::File;
     use tempfile::tempdir;
 
@@ -398,18 +483,91 @@ mod tests {
                 .iter()
                 .cloned()
                 .collect();
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Xxh64,
+            ChecksumAlgorithm::Blake3,
+        ]
+        .iter()
+        {
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+                builder.set_checksum_algorithm(*algorithm);
+                for entry in &expected_content {
+                    builder
+                        .append_file_update(entry.0.to_string(), entry.1.as_slice())
+                        .unwrap();
+                }
+            }
+            {
+                let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+                assert!(thor_archive.is_valid().unwrap());
+                let integrity_data = thor_archive.read_file_content(INTEGRITY_FILE_NAME).unwrap();
+                let integrity_data = String::from_utf8(integrity_data).unwrap();
+                assert_eq!(
+                    integrity_data.contains("=xxh64:0x"),
+                    *algorithm == ChecksumAlgorithm::Xxh64
+                );
+                assert_eq!(
+                    integrity_data.contains("=blake3:"),
+                    *algorithm == ChecksumAlgorithm::Blake3
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_bound_after() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Xxh64,
+            ChecksumAlgorithm::Blake3,
+        ]
+        .iter()
         {
             let output_file = File::create(&output_path).unwrap();
             let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
-            for entry in &expected_content {
+            builder.set_checksum_algorithm(*algorithm);
+            // Long paths and small entries, so that metadata dominates
+            for i in 0..500 {
+                let entry_path = format!("data\\{}\\{}", "x".repeat(200), i);
                 builder
-                    .append_file_update(entry.0.to_string(), entry.1.as_slice())
+                    .append_file_update(entry_path, &[i as u8; 4][..])
                     .unwrap();
+                builder.append_file_removal(format!("data\\removed{}", i));
             }
+            let size_bound = builder.size_bound_after("data\\last", Some(4)).unwrap();
+            builder
+                .append_file_update("data\\last".to_string(), &[0_u8; 4][..])
+                .unwrap();
+            let data_integrity_size = builder.generate_data_integrity().unwrap().len();
+            assert_eq!(builder.data_integrity_size, data_integrity_size as u64);
+            builder.finish().unwrap();
+            drop(builder);
+            assert!(std::fs::metadata(&output_path).unwrap().len() <= size_bound);
         }
-        {
-            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
-            assert!(thor_archive.is_valid().unwrap());
-        }
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let temp_dir = tempdir().unwrap();
+        let build = |file_name: &str| {
+            let output_path = temp_dir.path().join(file_name);
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new(output_file, true, None, true).unwrap();
+                for i in 0..32 {
+                    builder
+                        .append_file_update(format!("data\\test{}", i), &[i as u8; 64][..])
+                        .unwrap();
+                    builder.append_file_removal(format!("data\\removed{}", i));
+                }
+            }
+            std::fs::read(output_path).unwrap()
+        };
+        assert_eq!(build("builder1.thor"), build("builder2.thor"));
     }
 }
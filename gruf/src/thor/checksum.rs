@@ -0,0 +1,157 @@
+use std::fmt;
+use std::hash::Hasher;
+
+use crc::crc32::{self, Hasher32};
+use serde::Deserialize;
+use twox_hash::XxHash64;
+
+use super::blake3::Blake3Hasher;
+
+const XXH64_PREFIX: &str = "xxh64:";
+const BLAKE3_PREFIX: &str = "blake3:";
+
+/// Algorithms that can be used to hash the entries listed in
+/// 'data.integrity'.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Crc32, // Understood by every patcher
+    Xxh64,  // Much faster on big entries, written with a `xxh64:` prefix
+    Blake3, // Cryptographic, written with a `blake3:` prefix
+}
+
+/// Checksum of an entry, as found in 'data.integrity'.
+///
+/// The algorithm is given by the value itself (e.g., `0x1a2b3c4d` for CRC32,
+/// `xxh64:0x0123456789abcdef` for XXH64, `blake3:` followed by 64 hexadecimal
+/// digits for BLAKE3), so that patches can pick theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Checksum {
+    Crc32(u32),
+    Xxh64(u64),
+    Blake3([u8; 32]),
+}
+
+impl Checksum {
+    pub fn parse(value: &str) -> Option<Checksum> {
+        if let Some(hash_str) = value.strip_prefix(BLAKE3_PREFIX) {
+            return parse_hash_bytes(hash_str.trim_start_matches("0x")).map(Checksum::Blake3);
+        }
+        match value.strip_prefix(XXH64_PREFIX) {
+            Some(hash_str) => u64::from_str_radix(hash_str.trim_start_matches("0x"), 16)
+                .ok()
+                .map(Checksum::Xxh64),
+            None => u32::from_str_radix(value.trim_start_matches("0x"), 16)
+                .ok()
+                .map(Checksum::Crc32),
+        }
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Checksum::Crc32(_) => ChecksumAlgorithm::Crc32,
+            Checksum::Xxh64(_) => ChecksumAlgorithm::Xxh64,
+            Checksum::Blake3(_) => ChecksumAlgorithm::Blake3,
+        }
+    }
+
+    pub fn of(algorithm: ChecksumAlgorithm, data: &[u8]) -> Checksum {
+        let mut hasher = ChecksumHasher::new(algorithm);
+        hasher.write(data);
+        hasher.finish()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Crc32(hash) => write!(f, "0x{:08x}", hash),
+            Checksum::Xxh64(hash) => write!(f, "{}0x{:016x}", XXH64_PREFIX, hash),
+            Checksum::Blake3(hash) => {
+                write!(f, "{}", BLAKE3_PREFIX)?;
+                hash.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+fn parse_hash_bytes<const N: usize>(hash_str: &str) -> Option<[u8; N]> {
+    if hash_str.len() != 2 * N || !hash_str.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; N];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash_str[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Computes a `Checksum` incrementally.
+pub(crate) enum ChecksumHasher {
+    Crc32(Box<crc32::Digest>), // Keeps a 1 KiB table
+    Xxh64(XxHash64),
+    Blake3(Box<Blake3Hasher>),
+}
+
+impl ChecksumHasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> ChecksumHasher {
+        match algorithm {
+            ChecksumAlgorithm::Crc32 => {
+                ChecksumHasher::Crc32(Box::new(crc32::Digest::new(crc32::IEEE)))
+            }
+            ChecksumAlgorithm::Xxh64 => ChecksumHasher::Xxh64(XxHash64::with_seed(0)),
+            ChecksumAlgorithm::Blake3 => ChecksumHasher::Blake3(Box::new(Blake3Hasher::new())),
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Crc32(digest) => Hasher32::write(digest.as_mut(), data),
+            ChecksumHasher::Xxh64(hasher) => Hasher::write(hasher, data),
+            ChecksumHasher::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(&self) -> Checksum {
+        match self {
+            ChecksumHasher::Crc32(digest) => Checksum::Crc32(digest.sum32()),
+            ChecksumHasher::Xxh64(hasher) => Checksum::Xxh64(hasher.finish()),
+            ChecksumHasher::Blake3(hasher) => Checksum::Blake3(hasher.finalize()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_format() {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Xxh64,
+            ChecksumAlgorithm::Blake3,
+        ]
+        .iter()
+        {
+            let checksum = Checksum::of(*algorithm, b"content");
+            assert_eq!(checksum.algorithm(), *algorithm);
+            assert_eq!(Checksum::parse(&checksum.to_string()), Some(checksum));
+        }
+        assert_eq!(
+            Checksum::parse("0x1a2b3c4d"),
+            Some(Checksum::Crc32(0x1a2b3c4d))
+        );
+        assert_eq!(
+            Checksum::parse("xxh64:0x0123456789abcdef"),
+            Some(Checksum::Xxh64(0x0123456789abcdef))
+        );
+        assert_eq!(
+            Checksum::of(ChecksumAlgorithm::Blake3, b"abc").to_string(),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(Checksum::parse("blake3:6437b3ac"), None);
+        assert_eq!(Checksum::parse("sha1:0x1234"), None);
+    }
+}
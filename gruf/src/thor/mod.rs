@@ -1,7 +1,10 @@
+mod blake3;
 pub mod builder;
+mod checksum;
 pub mod reader;
 
 pub use builder::ThorArchiveBuilder;
+pub use checksum::ChecksumAlgorithm;
 pub use reader::{
     patch_list_from_string, ThorArchive, ThorFileEntry, ThorPatchInfo, ThorPatchList,
 };
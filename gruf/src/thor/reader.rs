@@ -2,17 +2,17 @@ use std::boxed::Box;
 use std::collections::HashMap;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
+use crate::archive::{zlib_decompress_bounded, DecompressionLimits};
+use crate::thor::checksum::Checksum;
 use crate::thor::{
     ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
 use crate::{GrufError, Result};
-use crc::crc32;
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
-use flate2::read::ZlibDecoder;
 use nom::number::complete::{le_i16, le_i32, le_u32, le_u8};
 use nom::*;
 
@@ -24,45 +24,89 @@ const SINGLE_FILE_ENTRY_MAX_SIZE: usize = 9 + MAX_FILE_NAME_SIZE;
 pub type ThorPatchList = Vec<ThorPatchInfo>;
 
 /// Parses Thor's plist.txt file
-pub fn patch_list_from_string(content: &str) -> ThorPatchList {
-    let vec_lines: Vec<&str> = content.lines().collect();
-    let mut sorted_patch_list: ThorPatchList = vec_lines
-        .into_iter()
-        .filter_map(|elem| ThorPatchInfo::from_string(&elem))
-        .collect();
+///
+/// Lines that don't start with a patch index (e.g., comments) are ignored,
+/// but malformed constraints are reported as errors rather than skipping the
+/// patches they apply to.
+pub fn patch_list_from_string(content: &str) -> Result<ThorPatchList> {
+    let mut sorted_patch_list = ThorPatchList::new();
+    for (line_index, line) in content.lines().enumerate() {
+        let patch_info = ThorPatchInfo::from_string(line).map_err(|e| {
+            GrufError::parsing_error(format!("Line {} of the patch list: {}", line_index + 1, e))
+        })?;
+        sorted_patch_list.extend(patch_info);
+    }
     // Sort patch list by index
     sorted_patch_list.sort_by(|a, b| a.index.cmp(&b.index));
-    sorted_patch_list
+    Ok(sorted_patch_list)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ThorPatchInfo {
     pub index: usize,
     pub file_name: String,
+    pub requires_index: Option<usize>, // Patch that must have been applied before this one
+    pub requires_patcher: Option<String>, // Minimum version of the patcher
+    pub magnet_link: Option<String>,   // Magnet link of a torrent distributing the patch
 }
 
 impl ThorPatchInfo {
     /// Parses a line to extract patch index and patch file name.
+    ///
+    /// Patch file names can be followed by `requires_index>=N` and
+    /// `requires_patcher>=x.y` constraints, as well as by a magnet link (or an
+    /// `infohash=<hash>`) for peer-to-peer downloads.
+    ///
     /// Returns a PatchInfo struct in case of success.
-    /// Returns None in case of failure
-    fn from_string(line: &str) -> Option<ThorPatchInfo> {
+    /// Returns None if the line doesn't describe a patch, and an error
+    /// message if one of its constraints is malformed.
+    fn from_string(line: &str) -> std::result::Result<Option<ThorPatchInfo>, String> {
         let words: Vec<_> = line.trim().split_whitespace().collect();
-        let index_str = words.get(0)?;
-        let index = match str::parse(index_str) {
-            Ok(v) => v,
-            Err(_) => {
-                return None;
+        let index = match words.first().map(|index_str| str::parse(index_str)) {
+            Some(Ok(v)) => v,
+            _ => {
+                return Ok(None);
+            }
+        };
+        let file_name = match words.get(1) {
+            Some(v) => v,
+            None => {
+                return Ok(None);
             }
         };
-        let file_name = words.get(1)?;
-        Some(ThorPatchInfo {
+        let mut requires_index = None;
+        let mut requires_patcher = None;
+        let mut magnet_link = None;
+        for constraint in words.iter().skip(2) {
+            if let Some(value) = constraint.strip_prefix("requires_index>=") {
+                requires_index = Some(
+                    str::parse(value)
+                        .map_err(|_| format!("invalid constraint '{}'", constraint))?,
+                );
+            } else if let Some(value) = constraint.strip_prefix("requires_patcher>=") {
+                if value.is_empty() {
+                    return Err(format!("invalid constraint '{}'", constraint));
+                }
+                requires_patcher = Some(value.to_string());
+            } else if constraint.starts_with("requires_") {
+                return Err(format!("unknown constraint '{}'", constraint));
+            } else if constraint.starts_with("magnet:?") {
+                magnet_link = Some(constraint.to_string());
+            } else if let Some(value) = constraint.strip_prefix("infohash=") {
+                magnet_link = Some(format!("magnet:?xt=urn:btih:{}", value));
+            }
+        }
+        Ok(Some(ThorPatchInfo {
             index,
             file_name: (*file_name).to_string(),
-        })
+            requires_index,
+            requires_patcher,
+            magnet_link,
+        }))
     }
 }
 
-fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
+fn parse_data_integrity_info(data: &str) -> HashMap<&str, Checksum> {
     let vec_lines: Vec<_> = data.lines().collect();
     vec_lines
         .into_iter()
@@ -70,11 +114,7 @@ fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
             let words: Vec<&str> = line.trim().split('=').collect();
             let file_name = words.get(0)?;
             let hash_str = words.get(1)?;
-            let hash = match u32::from_str_radix(hash_str.trim_start_matches("0x"), 16) {
-                Ok(v) => v,
-                Err(_) => return None,
-            };
-            Some((*file_name, hash))
+            Some((*file_name, Checksum::parse(hash_str)?))
         })
         .collect()
 }
@@ -83,22 +123,37 @@ fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
 pub struct ThorArchive<R: ?Sized> {
     obj: Box<R>,
     container: ThorContainer,
+    limits: DecompressionLimits,
 }
 
 impl ThorArchive<File> {
     pub fn open(thor_archive_path: &Path) -> Result<ThorArchive<File>> {
+        Self::open_with_limits(thor_archive_path, DecompressionLimits::default())
+    }
+
+    pub fn open_with_limits(
+        thor_archive_path: &Path,
+        limits: DecompressionLimits,
+    ) -> Result<ThorArchive<File>> {
         let file = File::open(thor_archive_path)?;
-        ThorArchive::new(file)
+        ThorArchive::new_with_limits(file, limits)
     }
 }
 
 impl<R: Read + Seek> ThorArchive<R> {
     /// Create a new archive with the underlying object as the reader.
-    pub fn new(mut obj: R) -> Result<ThorArchive<R>> {
-        let thor_patch = parse_thor_patch(&mut obj)?;
+    pub fn new(obj: R) -> Result<ThorArchive<R>> {
+        Self::new_with_limits(obj, DecompressionLimits::default())
+    }
+
+    /// Create a new archive with the underlying object as the reader, `limits`
+    /// being enforced when decompressing the file table and entries.
+    pub fn new_with_limits(mut obj: R, limits: DecompressionLimits) -> Result<ThorArchive<R>> {
+        let thor_patch = parse_thor_patch_with_limits(&mut obj, &limits)?;
         Ok(ThorArchive {
             obj: Box::new(obj),
             container: thor_patch,
+            limits,
         })
     }
 
@@ -130,6 +185,28 @@ impl<R: Read + Seek> ThorArchive<R> {
         Ok(content)
     }
 
+    /// Copies an entry's raw (compressed) data into `writer` in chunks,
+    /// instead of reading it into memory at once.
+    ///
+    /// Returns the number of bytes copied.
+    pub fn copy_entry_raw_data<S: AsRef<str> + Hash, W: Write + ?Sized>(
+        &mut self,
+        file_path: S,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?
+            .clone();
+        if file_entry.size_compressed == 0 {
+            return Ok(0);
+        }
+
+        self.obj.seek(SeekFrom::Start(file_entry.offset))?;
+        let mut file_chunk = self.obj.by_ref().take(file_entry.size_compressed as u64);
+        Ok(io::copy(&mut file_chunk, writer)?)
+    }
+
     pub fn read_file_content<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
         let file_entry = self
             .get_file_entry(file_path)
@@ -138,16 +215,24 @@ impl<R: Read + Seek> ThorArchive<R> {
         if file_entry.size_compressed == 0 {
             return Ok(vec![]);
         }
+        if !self
+            .limits
+            .allows(file_entry.size, file_entry.size_compressed)
+        {
+            return Err(GrufError::DecompressionLimitExceeded(format!(
+                "entry '{}'",
+                file_entry.relative_path
+            )));
+        }
 
         self.obj.seek(SeekFrom::Start(file_entry.offset))?;
         let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed);
         let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
         file_chunk.read_to_end(&mut content)?;
-        // Decompress the content with zlib
-        let mut decoder = ZlibDecoder::new(content.as_slice());
-        let mut decompressed_content = Vec::new();
-        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
-        if decompressed_size != file_entry.size {
+        // Decompress the content with zlib, never beyond the declared size
+        let decompressed_content = zlib_decompress_bounded(&content, file_entry.size as u64)
+            .map_err(|e| GrufError::ZlibError("file entry", e))?;
+        if decompressed_content.len() != file_entry.size {
             return Err(GrufError::parsing_error(
                 "Decompressed content is not as expected",
             ));
@@ -178,12 +263,12 @@ impl<R: Read + Seek> ThorArchive<R> {
         let integrity_data = self.read_file_content(INTEGRITY_FILE_NAME)?;
         let integrity_data_as_str = string_from_win_1252(integrity_data.as_slice())?;
         let integrity_info = parse_data_integrity_info(integrity_data_as_str.as_str());
-        for (file_path, hash) in integrity_info {
+        for (file_path, checksum) in integrity_info {
             let file_content = match self.read_file_content(file_path) {
                 Ok(v) => v,
                 Err(_) => return Ok(false),
             };
-            if crc32::checksum_ieee(file_content.as_slice()) != hash {
+            if Checksum::of(checksum.algorithm(), file_content.as_slice()) != checksum {
                 return Ok(false);
             }
         }
@@ -258,6 +343,15 @@ fn i16_to_thor_mode(i: i16) -> ThorMode {
     }
 }
 
+/// Makes sure that an entry can be read safely. Whether its path is safe to
+/// extract to is up to the caller (see `find_entry_path_issue`).
+fn check_file_entry(entry: &ThorFileEntry, archive_size: u64) -> Result<()> {
+    match entry.offset.checked_add(entry.size_compressed as u64) {
+        Some(entry_end) if entry_end <= archive_size => Ok(()),
+        _ => Err(GrufError::OversizedEntry(entry.relative_path.clone())),
+    }
+}
+
 /// Checks entries' flags
 /// If LSB is 1, the entry indicates a file deletion
 fn is_file_removed(flags: u8) -> bool {
@@ -375,23 +469,38 @@ named!(parse_multiple_files_entries<&[u8], HashMap<String, ThorFileEntry>>,
 );
 
 pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer> {
+    parse_thor_patch_with_limits(reader, &DecompressionLimits::default())
+}
+
+pub fn parse_thor_patch_with_limits<R: Seek + Read>(
+    reader: &mut R,
+    limits: &DecompressionLimits,
+) -> Result<ThorContainer> {
     const HEADER_EXTENDED_MAX_SIZE: usize =
         HEADER_MAX_SIZE + MULTIPLE_FILES_TABLE_DESC_SIZE + SINGLE_FILE_ENTRY_MAX_SIZE;
+    // Offsets and sizes are checked against the archive's size, since they
+    // cannot be trusted
+    let archive_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
     let mut thor_header_buf = Vec::with_capacity(HEADER_EXTENDED_MAX_SIZE);
     let mut reader_chunk = reader.take(thor_header_buf.capacity() as u64);
     reader_chunk.read_to_end(&mut thor_header_buf)?;
+    if !thor_header_buf.starts_with(THOR_HEADER_MAGIC) {
+        return Err(GrufError::BadMagic("THOR"));
+    }
     let (output, header) = parse_thor_header(&thor_header_buf)
-        .map_err(|_| GrufError::parsing_error("Failed to parse THOR header"))?;
+        .map_err(|e| GrufError::from_nom_error(e, "THOR header"))?;
     match header.mode {
         ThorMode::Invalid => Err(GrufError::parsing_error("Invalid THOR header mode")),
         ThorMode::SingleFile => {
             // Parse table
             let (output, table) = parse_single_file_table(output)
-                .map_err(|_| GrufError::parsing_error("Failed to parse THOR file table"))?;
+                .map_err(|e| GrufError::from_nom_error(e, "THOR file table"))?;
             // Parse the single entry
             let (output, mut entry) = parse_single_file_entry(output)
-                .map_err(|_| GrufError::parsing_error("Failed to parse THOR file entry"))?;
+                .map_err(|e| GrufError::from_nom_error(e, "THOR file entry"))?;
             entry.offset = output.as_ptr() as u64 - thor_header_buf.as_ptr() as u64;
+            check_file_entry(&entry, archive_size)?;
             Ok(ThorContainer {
                 header,
                 table: ThorTable::SingleFile(table),
@@ -403,31 +512,44 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
         }
         ThorMode::MultipleFiles => {
             let (output, table) = parse_multiple_files_table(output)
-                .map_err(|_| GrufError::parsing_error("Failed to parse THOR file table"))?;
+                .map_err(|e| GrufError::from_nom_error(e, "THOR file table"))?;
             let consumed_bytes = output.as_ptr() as u64 - thor_header_buf.as_ptr() as u64;
             if table.file_table_offset < consumed_bytes {
                 return Err(GrufError::parsing_error("Invalid THOR file table offset"));
             }
+            match table
+                .file_table_offset
+                .checked_add(table.file_table_compressed_size as u64)
+            {
+                Some(table_end) if table_end <= archive_size => {}
+                _ => return Err(GrufError::Truncated("THOR file table")),
+            }
             // Decompress the table with zlib
             reader.seek(SeekFrom::Start(table.file_table_offset))?;
             let mut compressed_table: Vec<u8> =
                 Vec::with_capacity(table.file_table_compressed_size);
             let mut file_chunk = reader.take(compressed_table.capacity() as u64);
             file_chunk.read_to_end(&mut compressed_table)?;
-            let mut decoder = ZlibDecoder::new(compressed_table.as_slice());
-            let mut decompressed_table = vec![];
-            let decompressed_size = decoder.read_to_end(&mut decompressed_table)?;
+            let max_table_size = limits.max_decompressed_size(compressed_table.len());
+            let decompressed_table = zlib_decompress_bounded(&compressed_table, max_table_size)
+                .map_err(|e| GrufError::ZlibError("THOR file table", e))?;
+            if decompressed_table.len() as u64 > max_table_size {
+                return Err(GrufError::DecompressionLimitExceeded(
+                    "THOR file table".to_string(),
+                ));
+            }
             // Parse multiple entries
-            let entries = match decompressed_size {
+            let entries = match decompressed_table.len() {
                 0 => HashMap::new(), // No entries
                 _ => {
                     let (_, entries) = parse_multiple_files_entries(decompressed_table.as_slice())
-                        .map_err(|_| {
-                            GrufError::parsing_error("Failed to parse THOR file entries")
-                        })?;
+                        .map_err(|e| GrufError::from_nom_error(e, "THOR file table"))?;
                     entries
                 }
             };
+            for entry in entries.values() {
+                check_file_entry(entry, archive_size)?;
+            }
             Ok(ThorContainer {
                 header,
                 table: ThorTable::MultipleFiles(table),
@@ -440,8 +562,35 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::find_entry_path_issue;
+    use crate::thor::ThorArchiveBuilder;
+    use std::io::Cursor;
     use std::path::PathBuf;
 
+    fn build_thor_archive(entry_paths: &[&str]) -> Vec<u8> {
+        let entries: Vec<(&str, &[u8])> = entry_paths
+            .iter()
+            .map(|entry_path| (*entry_path, &b"content"[..]))
+            .collect();
+        build_thor_archive_with_content(&entries)
+    }
+
+    fn build_thor_archive_with_content(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut thor_archive_data = vec![];
+        {
+            let mut builder =
+                ThorArchiveBuilder::new(Cursor::new(&mut thor_archive_data), true, None, false)
+                    .unwrap();
+            for (entry_path, content) in entries {
+                builder
+                    .append_file_update(entry_path.to_string(), *content)
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        thor_archive_data
+    }
+
     #[test]
     fn test_patch_list_from_string() {
         let plist_content = "//869 iteminfo_20170423.thor
@@ -463,11 +612,11 @@ mod tests {
         .cloned()
         .collect();
         //Empty patch list
-        let empty_thor_patch_list = patch_list_from_string("");
+        let empty_thor_patch_list = patch_list_from_string("").unwrap();
         assert_eq!(empty_thor_patch_list.len(), 0);
         // TODO(LinkZ): Ensure patch list is ordered by patch index
         // Regular patch list
-        let thor_patch_list = patch_list_from_string(plist_content);
+        let thor_patch_list = patch_list_from_string(plist_content).unwrap();
         assert_eq!(thor_patch_list.len(), expected_content.len());
         for patch_info in thor_patch_list {
             assert!(expected_content.contains_key(&patch_info.index));
@@ -475,6 +624,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_patch_list_constraints() {
+        let plist_content = "876 sprites_20170503.thor requires_index>=875
+877 client_20170504.thor requires_patcher>=0.4 requires_index>=876";
+        let thor_patch_list = patch_list_from_string(plist_content).unwrap();
+        assert_eq!(thor_patch_list.len(), 2);
+        assert_eq!(thor_patch_list[0].requires_index, Some(875));
+        assert_eq!(thor_patch_list[0].requires_patcher, None);
+        assert_eq!(thor_patch_list[1].requires_index, Some(876));
+        assert_eq!(thor_patch_list[1].requires_patcher.as_deref(), Some("0.4"));
+    }
+
+    #[test]
+    fn test_patch_list_malformed_constraints() {
+        for plist_content in [
+            "876 sprites.thor\n878 invalid.thor requires_index>=abc",
+            "878 invalid.thor requires_patcher>=",
+            "878 invalid.thor requires_index=876",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                patch_list_from_string(plist_content),
+                Err(GrufError::ParsingError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_patch_list_magnet_links() {
+        let plist_content = "876 sprites_20170503.thor magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=sprites_20170503.thor
+877 client_20170504.thor infohash=d2474e86c95b19b8bcfdb92bc12c9d44667cfa36
+878 data_20170505.thor";
+        let thor_patch_list = patch_list_from_string(plist_content).unwrap();
+        assert_eq!(
+            thor_patch_list[0].magnet_link.as_deref(),
+            Some("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=sprites_20170503.thor")
+        );
+        assert_eq!(
+            thor_patch_list[1].magnet_link.as_deref(),
+            Some("magnet:?xt=urn:btih:d2474e86c95b19b8bcfdb92bc12c9d44667cfa36")
+        );
+        assert_eq!(thor_patch_list[2].magnet_link, None);
+    }
+
     #[test]
     fn test_open_empty_container() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
@@ -575,4 +769,79 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_open_malformed_container() {
+        assert!(matches!(
+            ThorArchive::new(Cursor::new(b"PK\x03\x04".to_vec())).unwrap_err(),
+            GrufError::BadMagic(_)
+        ));
+        assert!(matches!(
+            ThorArchive::new(Cursor::new(vec![])).unwrap_err(),
+            GrufError::BadMagic(_)
+        ));
+        // Truncated archives must be rejected, without panicking
+        let thor_archive_data = build_thor_archive(&["data\\a.txt", "data\\b.txt"]);
+        assert!(ThorArchive::new(Cursor::new(thor_archive_data.clone())).is_ok());
+        for size in 0..thor_archive_data.len() {
+            let truncated_data = thor_archive_data[..size].to_vec();
+            assert!(ThorArchive::new(Cursor::new(truncated_data)).is_err());
+        }
+        // The file table's size is a signed integer
+        let mut thor_archive_data = thor_archive_data;
+        let table_desc_offset = THOR_HEADER_MAGIC.len() + 8;
+        thor_archive_data[table_desc_offset..table_desc_offset + 4]
+            .copy_from_slice(&(-1_i32).to_le_bytes());
+        assert!(matches!(
+            ThorArchive::new(Cursor::new(thor_archive_data)).unwrap_err(),
+            GrufError::Truncated(_)
+        ));
+    }
+
+    #[test]
+    fn test_open_container_with_suspicious_path() {
+        // Suspicious paths are reported by `find_entry_path_issue`, so that
+        // callers can decide what to do with them
+        let thor_archive_data = build_thor_archive(&["data\\a.txt", "..\\..\\client.exe"]);
+        let thor_archive = ThorArchive::new(Cursor::new(thor_archive_data)).unwrap();
+        let suspicious_entry_paths: Vec<&str> = thor_archive
+            .get_entries()
+            .map(|entry| entry.relative_path.as_str())
+            .filter(|entry_path| find_entry_path_issue(entry_path).is_some())
+            .collect();
+        assert_eq!(suspicious_entry_paths, vec!["..\\..\\client.exe"]);
+    }
+
+    #[test]
+    fn test_decompression_limits() {
+        let thor_archive_data = build_thor_archive_with_content(&[
+            ("data\\a.txt", &b"content"[..]),
+            ("data\\zeros.bin", &[0; 65536][..]),
+        ]);
+        let limits = DecompressionLimits {
+            max_entry_size: 1024,
+            max_expansion_ratio: 50,
+        };
+        let mut thor_archive =
+            ThorArchive::new_with_limits(Cursor::new(thor_archive_data.clone()), limits).unwrap();
+        assert_eq!(
+            thor_archive.read_file_content("data\\a.txt").unwrap(),
+            b"content"
+        );
+        assert!(matches!(
+            thor_archive
+                .read_file_content("data\\zeros.bin")
+                .unwrap_err(),
+            GrufError::DecompressionLimitExceeded(_)
+        ));
+        // The file table is subject to the limits as well
+        let limits = DecompressionLimits {
+            max_entry_size: 8,
+            max_expansion_ratio: 50,
+        };
+        assert!(matches!(
+            ThorArchive::new_with_limits(Cursor::new(thor_archive_data), limits).unwrap_err(),
+            GrufError::DecompressionLimitExceeded(name) if name == "THOR file table"
+        ));
+    }
 }
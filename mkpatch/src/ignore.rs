@@ -0,0 +1,252 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use walkdir::WalkDir;
+
+pub const IGNORE_FILE_NAME: &str = ".mkpatchignore";
+
+/// Glob rules that exclude files from the directories included in a patch,
+/// read from `.mkpatchignore` files.
+///
+/// The syntax is a subset of `.gitignore`'s: one pattern per line, `#` starts
+/// a comment, `*` and `?` match anything but `/`, `**` matches any number of
+/// directories, a trailing `/` only matches directories and a leading `!`
+/// includes files again. Patterns that don't contain a `/` match files at any
+/// depth, others are relative to the directory that contains the
+/// `.mkpatchignore` file. Rules only apply to the files below the directory
+/// that contains them, and the last matching pattern wins, the ones of nested
+/// directories' files coming last.
+#[derive(Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+struct IgnoreRule {
+    base: Vec<char>, // Directory of the `.mkpatchignore` file, with a trailing `/`
+    pattern: Vec<char>,
+    is_negated: bool,
+    directory_only: bool,
+}
+
+impl IgnoreRules {
+    /// Reads the `.mkpatchignore` files of `directory` and of its
+    /// subdirectories. There are no rules if there are no such files.
+    pub fn load(directory: &Path) -> Result<Self> {
+        let mut ignore_files = vec![];
+        for entry in WalkDir::new(directory)
+            .follow_links(false)
+            .sort_by_file_name()
+        {
+            let entry = entry?;
+            if entry.file_type().is_file() && entry.file_name() == IGNORE_FILE_NAME {
+                ignore_files.push((entry.depth(), entry.into_path()));
+            }
+        }
+        // Parents' rules come first, so that nested directories' rules win
+        ignore_files.sort_by_key(|(depth, _)| *depth);
+        let mut ignore_rules = Self::default();
+        for (_, file_path) in ignore_files {
+            let content = fs::read_to_string(&file_path)
+                .with_context(|| format!("Failed to read '{}'", file_path.to_string_lossy()))?;
+            let base = file_path
+                .parent()
+                .and_then(|parent| parent.strip_prefix(directory).ok())
+                .map(|base| base.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_default();
+            ignore_rules.add_rules(&base, &content);
+        }
+        Ok(ignore_rules)
+    }
+
+    #[cfg(test)]
+    fn parse(content: &str) -> Self {
+        let mut ignore_rules = Self::default();
+        ignore_rules.add_rules("", content);
+        ignore_rules
+    }
+
+    /// Adds the rules of the `.mkpatchignore` file of the `base` directory
+    /// (relative, with `/` separators).
+    fn add_rules(&mut self, base: &str, content: &str) {
+        let base: Vec<char> = match base {
+            "" => vec![],
+            base => format!("{}/", base).chars().collect(),
+        };
+        let rules = content.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (is_negated, line) = match line.strip_prefix('!') {
+                Some(v) => (true, v),
+                None => (false, line),
+            };
+            // Windows users might use backslashes as separators
+            let line = line.replace('\\', "/");
+            let directory_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let pattern = if line.contains('/') {
+                line.trim_start_matches('/').to_string()
+            } else {
+                format!("**/{}", line)
+            };
+            Some(IgnoreRule {
+                base: base.clone(),
+                pattern: pattern.chars().collect(),
+                is_negated,
+                directory_only,
+            })
+        });
+        self.rules.extend(rules);
+    }
+
+    /// Indicates whether the file (or directory) at `relative_path` (with `/`
+    /// separators) is excluded.
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        let path: Vec<char> = relative_path.chars().collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.directory_only)
+                    && path.starts_with(&rule.base)
+                    && glob_match(&rule.pattern, &path[rule.base.len()..])
+            })
+            .is_some_and(|rule| !rule.is_negated)
+    }
+}
+
+/// Indicates whether `text` matches `pattern`.
+///
+/// Results are memoized by position in the pattern and the text, so that
+/// patterns with several wildcards (e.g., `**/*a*a*a*b`) don't backtrack
+/// exponentially.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let mut memo = vec![None; (pattern.len() + 1) * (text.len() + 1)];
+    glob_match_from(pattern, text, 0, 0, &mut memo)
+}
+
+fn glob_match_from(
+    pattern: &[char],
+    text: &[char],
+    pattern_idx: usize,
+    text_idx: usize,
+    memo: &mut [Option<bool>],
+) -> bool {
+    let memo_idx = pattern_idx * (text.len() + 1) + text_idx;
+    if let Some(is_match) = memo[memo_idx] {
+        return is_match;
+    }
+    let next_char = text.get(text_idx).copied();
+    let is_match = match &pattern[pattern_idx..] {
+        [] => next_char.is_none(),
+        ['*', '*', '/', ..] => {
+            glob_match_from(pattern, text, pattern_idx + 3, text_idx, memo)
+                || (text_idx..text.len()).any(|i| {
+                    text[i] == '/' && glob_match_from(pattern, text, pattern_idx + 3, i + 1, memo)
+                })
+        }
+        ['*', '*', ..] => (text_idx..=text.len())
+            .any(|i| glob_match_from(pattern, text, pattern_idx + 2, i, memo)),
+        ['*', ..] => (text_idx..=text.len())
+            .take_while(|&i| i == text_idx || text[i - 1] != '/')
+            .any(|i| glob_match_from(pattern, text, pattern_idx + 1, i, memo)),
+        ['?', ..] => {
+            next_char.is_some_and(|c| c != '/')
+                && glob_match_from(pattern, text, pattern_idx + 1, text_idx + 1, memo)
+        }
+        [p, ..] => {
+            next_char == Some(*p)
+                && glob_match_from(pattern, text, pattern_idx + 1, text_idx + 1, memo)
+        }
+    };
+    memo[memo_idx] = Some(is_match);
+    is_match
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_star() {
+        let rules = IgnoreRules::parse("data/**/*.bak\n**/tmp/");
+        assert!(rules.is_ignored("data/a.bak", false));
+        assert!(rules.is_ignored("data/sprite/a.bak", false));
+        assert!(rules.is_ignored("data/sprite/npc/a.bak", false));
+        assert!(!rules.is_ignored("other/a.bak", false));
+        assert!(rules.is_ignored("tmp", true));
+        assert!(rules.is_ignored("data/tmp", true));
+    }
+
+    #[test]
+    fn test_star_stops_at_separator() {
+        let rules = IgnoreRules::parse("data/*.txt");
+        assert!(rules.is_ignored("data/a.txt", false));
+        assert!(!rules.is_ignored("data/sub/a.txt", false));
+        // Unanchored patterns match at any depth, but not across directories
+        let rules = IgnoreRules::parse("*.log");
+        assert!(rules.is_ignored("a.log", false));
+        assert!(rules.is_ignored("data/a.log", false));
+        assert!(!rules.is_ignored("a.log/b", false));
+    }
+
+    #[test]
+    fn test_question_mark() {
+        let rules = IgnoreRules::parse("file?.bin");
+        assert!(rules.is_ignored("file1.bin", false));
+        assert!(rules.is_ignored("data/file2.bin", false));
+        assert!(!rules.is_ignored("file.bin", false));
+        assert!(!rules.is_ignored("file10.bin", false));
+        let rules = IgnoreRules::parse("data?a");
+        assert!(!rules.is_ignored("data/a", false));
+    }
+
+    #[test]
+    fn test_negation() {
+        let rules = IgnoreRules::parse("# Comment\n*.txt\n!keep.txt\n");
+        assert!(rules.is_ignored("a.txt", false));
+        assert!(!rules.is_ignored("keep.txt", false));
+        assert!(!rules.is_ignored("data/keep.txt", false));
+        // The last matching rule wins
+        let rules = IgnoreRules::parse("!keep.txt\n*.txt");
+        assert!(rules.is_ignored("keep.txt", false));
+    }
+
+    #[test]
+    fn test_directory_only() {
+        let rules = IgnoreRules::parse("cache/\ndata\\raw\\");
+        assert!(rules.is_ignored("cache", true));
+        assert!(!rules.is_ignored("cache", false));
+        assert!(rules.is_ignored("data/raw", true));
+        assert!(!rules.is_ignored("other/data/raw", true));
+    }
+
+    #[test]
+    fn test_pathological_pattern() {
+        let rules = IgnoreRules::parse("**/*a*a*a*a*a*a*a*a*b");
+        let path = format!("{}/{}", "a".repeat(50), "a".repeat(50));
+        assert!(!rules.is_ignored(&path, false));
+        assert!(rules.is_ignored(&format!("{}b", path), false));
+    }
+
+    #[test]
+    fn test_nested_ignore_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        fs::create_dir(&data_dir).unwrap();
+        fs::write(temp_dir.path().join(IGNORE_FILE_NAME), "*.txt\n").unwrap();
+        fs::write(data_dir.join(IGNORE_FILE_NAME), "!keep.txt\n/local.bin\n").unwrap();
+        let rules = IgnoreRules::load(temp_dir.path()).unwrap();
+        assert!(rules.is_ignored("a.txt", false));
+        assert!(rules.is_ignored("data/a.txt", false));
+        // Nested rules win, and only apply to their directory
+        assert!(!rules.is_ignored("data/keep.txt", false));
+        assert!(!rules.is_ignored("data/sub/keep.txt", false));
+        assert!(rules.is_ignored("keep.txt", false));
+        assert!(rules.is_ignored("data/local.bin", false));
+        assert!(!rules.is_ignored("data/sub/local.bin", false));
+        assert!(!rules.is_ignored("local.bin", false));
+    }
+}
@@ -1,16 +1,23 @@
+mod ignore;
+mod manifest;
 mod patch_definition;
 
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
-use std::{env, process};
+use std::time::{Duration, SystemTime};
+use std::{env, process, thread};
 
 use anyhow::{anyhow, Context, Result};
+use gruf::charset;
 use gruf::thor::ThorArchiveBuilder;
+use ignore::{IgnoreRules, IGNORE_FILE_NAME};
 use log::LevelFilter;
+use manifest::{parse_patch_manifest, PatchManifest};
 use patch_definition::{parse_patch_definition, PatchDefinition};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -37,6 +44,25 @@ struct Opt {
         help = "Path to the output archive (default: <patch_definition_file_name>.thor)"
     )]
     output_file: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        short,
+        long,
+        help = "Path to a manifest that lists files to include (source path and target path) and files to remove, in addition to the patch definition's entries"
+    )]
+    manifest: Option<PathBuf>,
+    #[structopt(
+        short,
+        long,
+        help = "Watch the patch data directory and rebuild the archive whenever something changes"
+    )]
+    watch: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_size),
+        help = "Split the patch into sequential archives (<output_file_name>-1.thor, -2, ...) of at most this size (e.g., 100MB)"
+    )]
+    max_size: Option<u64>,
 }
 
 fn run(cli_args: Opt) -> Result<()> {
@@ -51,73 +77,452 @@ fn run(cli_args: Opt) -> Result<()> {
             .ok_or_else(|| anyhow!("Invalid patch definition file name"))?,
     ));
 
+    let output_paths = build_patch(
+        &cli_args.patch_definition_file,
+        cli_args.manifest.as_deref(),
+        &patch_data_directory,
+        &output_file_path,
+        cli_args.max_size,
+    )?;
+    if cli_args.watch {
+        watch_and_rebuild(
+            &cli_args.patch_definition_file,
+            cli_args.manifest.as_deref(),
+            &patch_data_directory,
+            &output_file_path,
+            cli_args.max_size,
+            output_paths,
+        )?;
+    }
+    Ok(())
+}
+
+/// Builds the patch, split in several archives if `max_size` is set, and
+/// returns the paths of the archives written.
+fn build_patch(
+    patch_definition_file: &Path,
+    manifest_file: Option<&Path>,
+    patch_data_directory: &Path,
+    output_file_path: &Path,
+    max_size: Option<u64>,
+) -> Result<Vec<PathBuf>> {
     // Parse the YAML definition file
-    log::info!(
-        "Processing '{}'",
-        cli_args.patch_definition_file.to_string_lossy()
-    );
-    let patch_definition = parse_patch_definition(&cli_args.patch_definition_file)
+    log::info!("Processing '{}'", patch_definition_file.to_string_lossy());
+    let patch_definition = parse_patch_definition(patch_definition_file)
         .context("Failed to parse the patch definition")?;
+    let patch_manifest = match manifest_file {
+        Some(manifest_file) => {
+            log::info!("Manifest: '{}'", manifest_file.to_string_lossy());
+            Some(parse_patch_manifest(manifest_file).context("Failed to parse the manifest")?)
+        }
+        None => None,
+    };
 
     // Display patch info
     log::info!("GRF merging: {}", patch_definition.use_grf_merging);
     log::info!("Checksums included: {}", patch_definition.include_checksums);
+    if patch_definition.include_checksums {
+        log::info!(
+            "Checksum algorithm: {:?}",
+            patch_definition.checksum_algorithm
+        );
+    }
     if let Some(target_grf_name) = &patch_definition.target_grf_name {
         log::info!("Target GRF: '{}'", target_grf_name);
     } else {
         log::info!("Target: Game directory");
     }
 
-    // Generate THOR archive
-    generate_patch_from_definition(patch_definition, patch_data_directory, &output_file_path)
-        .context("Failed to generate patch from definition")?;
+    // Generate THOR archive(s)
+    let output_paths = generate_patch_from_definition(
+        patch_definition,
+        patch_manifest.as_ref(),
+        patch_data_directory,
+        output_file_path,
+        max_size,
+    )
+    .context("Failed to generate patch from definition")?;
+    for output_path in &output_paths {
+        log::info!("Patch generated at '{}'", output_path.to_string_lossy());
+    }
+    Ok(output_paths)
+}
+
+/// Polls the patch definition file, the manifest (and the files it lists) and
+/// the patch data directory and rebuilds the output archive each time one of
+/// them changes.
+///
+/// This never returns unless the watched paths cannot be read initially.
+fn watch_and_rebuild(
+    patch_definition_file: &Path,
+    manifest_file: Option<&Path>,
+    patch_data_directory: &Path,
+    output_file_path: &Path,
+    max_size: Option<u64>,
+    mut output_paths: Vec<PathBuf>,
+) -> Result<()> {
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+    // The output archives might be located in the watched directory, make
+    // sure we don't rebuild them in a loop
+    let mut canonical_output_paths = BTreeSet::new();
+    for output_path in &output_paths {
+        canonical_output_paths.insert(fs::canonicalize(output_path)?);
+    }
+
     log::info!(
-        "Patch generated at '{}'",
-        output_file_path.to_string_lossy()
+        "Watching '{}' for changes (press Ctrl+C to stop)",
+        patch_data_directory.to_string_lossy()
     );
+    let mut last_snapshot = snapshot_watched_files(
+        patch_definition_file,
+        manifest_file,
+        patch_data_directory,
+        &canonical_output_paths,
+    )?;
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        // Files can disappear while we're walking the directory (e.g. editors
+        // saving through a temporary file), simply try again later
+        let snapshot = match snapshot_watched_files(
+            patch_definition_file,
+            manifest_file,
+            patch_data_directory,
+            &canonical_output_paths,
+        ) {
+            Ok(v) => v,
+            Err(err) => {
+                log::warn!("Failed to scan watched files: {:#}", err);
+                continue;
+            }
+        };
+        if snapshot == last_snapshot {
+            continue;
+        }
+        last_snapshot = snapshot;
+        log::info!("Change detected, rebuilding");
+        // Keep watching on failure, the user is probably in the middle of an edit
+        match build_patch(
+            patch_definition_file,
+            manifest_file,
+            patch_data_directory,
+            output_file_path,
+            max_size,
+        ) {
+            Err(err) => log::error!("{:#}", err),
+            Ok(new_output_paths) => {
+                // Splitting can produce fewer archives than before, don't
+                // leave outdated parts behind
+                for stale_path in output_paths
+                    .iter()
+                    .filter(|path| !new_output_paths.contains(path))
+                {
+                    log::info!("Removing '{}'", stale_path.to_string_lossy());
+                    if let Err(err) = fs::remove_file(stale_path) {
+                        log::warn!(
+                            "Failed to remove '{}': {}",
+                            stale_path.to_string_lossy(),
+                            err
+                        );
+                    }
+                }
+                // Or more
+                for output_path in &new_output_paths {
+                    match fs::canonicalize(output_path) {
+                        Ok(canonical_path) => {
+                            canonical_output_paths.insert(canonical_path);
+                        }
+                        Err(err) => log::warn!(
+                            "Failed to resolve '{}': {}",
+                            output_path.to_string_lossy(),
+                            err
+                        ),
+                    }
+                }
+                output_paths = new_output_paths;
+            }
+        }
+    }
+}
+
+type WatchSnapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Lists watched files with their modification time and size, except the
+/// output archives and ignored files.
+fn snapshot_watched_files(
+    patch_definition_file: &Path,
+    manifest_file: Option<&Path>,
+    patch_data_directory: &Path,
+    output_paths: &BTreeSet<PathBuf>,
+) -> Result<WatchSnapshot> {
+    let mut snapshot = WatchSnapshot::new();
+    let ignore_rules = IgnoreRules::load(patch_data_directory)?;
+    let walker = WalkDir::new(patch_data_directory)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| !is_ignored_entry(&ignore_rules, patch_data_directory, entry));
+    add_to_snapshot(&mut snapshot, walker, output_paths)?;
+    add_to_snapshot(
+        &mut snapshot,
+        WalkDir::new(patch_definition_file),
+        output_paths,
+    )?;
+    if let Some(manifest_file) = manifest_file {
+        add_to_snapshot(&mut snapshot, WalkDir::new(manifest_file), output_paths)?;
+        // Parsing errors are reported when rebuilding
+        if let Ok(patch_manifest) = parse_patch_manifest(manifest_file) {
+            for file in patch_manifest.files {
+                let walker = WalkDir::new(file.source).follow_links(false);
+                add_to_snapshot(&mut snapshot, walker, output_paths)?;
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+fn add_to_snapshot<I>(
+    snapshot: &mut WatchSnapshot,
+    entries: I,
+    output_paths: &BTreeSet<PathBuf>,
+) -> Result<()>
+where
+    I: IntoIterator<Item = walkdir::Result<DirEntry>>,
+{
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let is_output = output_paths
+            .iter()
+            .any(|output_path| output_path.file_name() == Some(entry.file_name()));
+        if is_output && output_paths.contains(&fs::canonicalize(entry.path())?) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        snapshot.insert(
+            entry.path().to_path_buf(),
+            (metadata.modified().ok(), metadata.len()),
+        );
+    }
     Ok(())
 }
 
+/// Indicates whether `entry`, found while walking `base_directory`, is
+/// excluded by `ignore_rules`.
+fn is_ignored_entry(ignore_rules: &IgnoreRules, base_directory: &Path, entry: &DirEntry) -> bool {
+    match entry.path().strip_prefix(base_directory) {
+        Ok(relative_path) if !relative_path.as_os_str().is_empty() => ignore_rules.is_ignored(
+            &posix_path(relative_path.to_string_lossy()),
+            entry.file_type().is_dir(),
+        ),
+        _ => false,
+    }
+}
+
+/// Change made by a patch.
+enum PatchOperation {
+    Update {
+        native_path: PathBuf,
+        win32_relative_path: String, // Encoded with the patch's code page
+        size: u64,
+    },
+    Removal {
+        win32_relative_path: String,
+    },
+}
+
+impl PatchOperation {
+    fn win32_relative_path(&self) -> &str {
+        match self {
+            PatchOperation::Update {
+                win32_relative_path,
+                ..
+            }
+            | PatchOperation::Removal {
+                win32_relative_path,
+            } => win32_relative_path,
+        }
+    }
+
+    /// Returns the size of the entry's data, `None` for a removal.
+    fn size(&self) -> Option<u64> {
+        match self {
+            PatchOperation::Update { size, .. } => Some(*size),
+            PatchOperation::Removal { .. } => None,
+        }
+    }
+
+    /// Returns the maximum size of the archive being built by
+    /// `archive_builder`, were it finished right after this entry.
+    fn archive_size_bound(&self, archive_builder: &mut ThorArchiveBuilder<File>) -> Result<u64> {
+        Ok(archive_builder.size_bound_after(self.win32_relative_path(), self.size())?)
+    }
+}
+
+/// Generates the patch described by `patch_definition` (and `patch_manifest`)
+/// at `output_path`, or
+/// as sequential archives of at most `max_size` bytes next to it
+/// (`<output_file_name>-1.thor`, `-2`, ...) if `max_size` is set.
+///
+/// Entries are never split between archives, an entry bigger than `max_size`
+/// gets an archive of its own. Archives are filled in the order of the
+/// definition, so that applying them in sequence has the same effect as
+/// applying a single archive.
+///
+/// Returns the paths of the archives written.
 fn generate_patch_from_definition<P1, P2>(
     patch_definition: PatchDefinition,
+    patch_manifest: Option<&PatchManifest>,
     patch_data_directory: P1,
     output_path: P2,
-) -> Result<()>
+    max_size: Option<u64>,
+) -> Result<Vec<PathBuf>>
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
-    let output_file = File::create(output_path)?;
-    let mut archive_builder = ThorArchiveBuilder::new(
-        output_file,
-        patch_definition.use_grf_merging,
-        patch_definition.target_grf_name,
-        patch_definition.include_checksums,
+    let operations = collect_patch_operations(
+        &patch_definition,
+        patch_manifest,
+        patch_data_directory.as_ref(),
     )?;
-    for entry in patch_definition.entries {
-        let win32_relative_path = win32_path(&entry.relative_path);
-        let target_win32_relative_path = entry.in_grf_path.unwrap_or(win32_relative_path.clone());
+    let create_archive = |output_path: &Path| -> Result<ThorArchiveBuilder<File>> {
+        let output_file = File::create(output_path)?;
+        let mut archive_builder = ThorArchiveBuilder::new(
+            output_file,
+            patch_definition.use_grf_merging,
+            patch_definition.target_grf_name.clone(),
+            patch_definition.include_checksums,
+        )?;
+        archive_builder.set_checksum_algorithm(patch_definition.checksum_algorithm);
+        Ok(archive_builder)
+    };
+    let max_size = match max_size {
+        Some(v) => v,
+        None => {
+            let mut archive_builder = create_archive(output_path.as_ref())?;
+            for operation in operations {
+                append_patch_operation(&mut archive_builder, operation)?;
+            }
+            archive_builder.finish()?;
+            return Ok(vec![output_path.as_ref().to_path_buf()]);
+        }
+    };
 
-        if entry.is_removed {
+    let mut output_paths = vec![split_archive_path(output_path.as_ref(), 1)?];
+    let mut archive_builder = create_archive(&output_paths[0])?;
+    let mut entry_count = 0;
+    for operation in operations {
+        // Compressed sizes are only known once entries have been appended,
+        // assume the worst for the next one
+        if entry_count > 0 && operation.archive_size_bound(&mut archive_builder)? > max_size {
+            archive_builder.finish()?;
+            let part_path = split_archive_path(output_path.as_ref(), output_paths.len() + 1)?;
+            archive_builder = create_archive(&part_path)?;
+            output_paths.push(part_path);
+            entry_count = 0;
+        }
+        if entry_count == 0 && operation.archive_size_bound(&mut archive_builder)? > max_size {
+            log::warn!(
+                "'{}' is bigger than the maximum size, its archive will be too",
+                operation.win32_relative_path()
+            );
+        }
+        entry_count += 1;
+        append_patch_operation(&mut archive_builder, operation)?;
+    }
+    archive_builder.finish()?;
+    Ok(output_paths)
+}
+
+/// Returns the path of the `part_number`th archive of a split patch, e.g.
+/// `patch-2.thor` for `patch.thor`.
+fn split_archive_path(output_path: &Path, part_number: usize) -> Result<PathBuf> {
+    let file_stem = output_path
+        .file_stem()
+        .ok_or_else(|| anyhow!("Invalid output file name"))?
+        .to_string_lossy();
+    let file_name = match output_path.extension() {
+        Some(extension) => format!(
+            "{}-{}.{}",
+            file_stem,
+            part_number,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}-{}", file_stem, part_number),
+    };
+    Ok(output_path.with_file_name(file_name))
+}
+
+fn append_patch_operation(
+    archive_builder: &mut ThorArchiveBuilder<File>,
+    operation: PatchOperation,
+) -> Result<()> {
+    match operation {
+        PatchOperation::Update {
+            native_path,
+            win32_relative_path,
+            ..
+        } => {
+            log::trace!("'{}' will be UPDATED", &win32_relative_path);
+            let file = File::open(native_path)?;
+            archive_builder.append_file_update(win32_relative_path, file)?;
+        }
+        PatchOperation::Removal {
+            win32_relative_path,
+        } => {
             log::trace!("'{}' will be REMOVED", &win32_relative_path);
             archive_builder.append_file_removal(win32_relative_path);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the changes described by `patch_definition`, followed by the ones
+/// described by `patch_manifest`, in order. Directories are expanded to the
+/// files they contain, except the ones excluded by `.mkpatchignore` rules.
+///
+/// Fails if a path is both included in the patch and removed by it.
+fn collect_patch_operations(
+    patch_definition: &PatchDefinition,
+    patch_manifest: Option<&PatchManifest>,
+    patch_data_directory: &Path,
+) -> Result<Vec<PatchOperation>> {
+    let code_page = patch_definition.code_page.as_deref();
+    let ignore_rules = IgnoreRules::load(patch_data_directory)?;
+    let mut operations = vec![];
+    for entry in &patch_definition.entries {
+        let win32_relative_path = encode_entry_name(&win32_path(&entry.relative_path), code_page)?;
+        let target_win32_relative_path = match &entry.in_grf_path {
+            Some(in_grf_path) => encode_entry_name(in_grf_path, code_page)?,
+            None => win32_relative_path.clone(),
+        };
+
+        if entry.is_removed {
+            operations.push(PatchOperation::Removal {
+                win32_relative_path,
+            });
             continue;
         }
 
-        let native_path = patch_data_directory
-            .as_ref()
-            .join(posix_path(entry.relative_path));
+        let native_path = patch_data_directory.join(posix_path(&entry.relative_path));
         if native_path.is_file() {
-            // Path points to a single file
-            log::trace!("'{}' will be UPDATED", &target_win32_relative_path);
-            let file = File::open(native_path)?;
-            archive_builder.append_file_update(target_win32_relative_path, file)?;
+            // Path points to a single file, explicitly listed files aren't ignored
+            let size = native_path.metadata()?.len();
+            operations.push(PatchOperation::Update {
+                native_path,
+                win32_relative_path: target_win32_relative_path,
+                size,
+            });
         } else if native_path.is_dir() {
             // Path points to a directory
-            append_directory_update(
-                &mut archive_builder,
-                patch_data_directory.as_ref(),
-                native_path,
+            collect_directory_updates(
+                &mut operations,
+                patch_data_directory,
+                &native_path,
+                None,
+                &ignore_rules,
+                code_page,
             )?;
         } else {
             return Err(anyhow!(
@@ -126,35 +531,147 @@ where
             ));
         }
     }
+
+    if let Some(patch_manifest) = patch_manifest {
+        for path in &patch_manifest.delete {
+            operations.push(PatchOperation::Removal {
+                win32_relative_path: encode_entry_name(&win32_path(path), code_page)?,
+            });
+        }
+        for file in &patch_manifest.files {
+            let target = win32_path(&file.target);
+            if file.source.is_file() {
+                operations.push(PatchOperation::Update {
+                    native_path: file.source.clone(),
+                    win32_relative_path: encode_entry_name(&target, code_page)?,
+                    size: file.source.metadata()?.len(),
+                });
+            } else if file.source.is_dir() {
+                // Rules are relative to the included directory
+                collect_directory_updates(
+                    &mut operations,
+                    &file.source,
+                    &file.source,
+                    Some(target.trim_end_matches('\\')),
+                    &IgnoreRules::load(&file.source)?,
+                    code_page,
+                )?;
+            } else {
+                return Err(anyhow!(
+                    "Path '{}' is invalid or does not exist",
+                    file.source.to_string_lossy()
+                ));
+            }
+        }
+    }
+    check_conflicting_operations(&operations)?;
+    Ok(operations)
+}
+
+/// Fails if one of `operations`' paths is both updated and removed, since
+/// the patch would only keep one of the two. Paths are compared like on
+/// Windows, ignoring case.
+fn check_conflicting_operations(operations: &[PatchOperation]) -> Result<()> {
+    let removed_paths: BTreeSet<String> = operations
+        .iter()
+        .filter_map(|operation| match operation {
+            PatchOperation::Removal {
+                win32_relative_path,
+            } => Some(win32_relative_path.to_ascii_lowercase()),
+            PatchOperation::Update { .. } => None,
+        })
+        .collect();
+    for operation in operations {
+        if let PatchOperation::Update {
+            win32_relative_path,
+            ..
+        } = operation
+        {
+            if removed_paths.contains(&win32_relative_path.to_ascii_lowercase()) {
+                return Err(anyhow!(
+                    "'{}' is both included in the patch and removed by it",
+                    win32_relative_path
+                ));
+            }
+        }
+    }
     Ok(())
 }
 
-fn append_directory_update<P1, P2>(
-    archive_builder: &mut ThorArchiveBuilder<File>,
-    patch_data_directory: P1,
-    directory_path: P2,
-) -> Result<()>
-where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    let walker = WalkDir::new(directory_path).follow_links(false).into_iter();
+/// Adds the files of `directory_path` to `operations`, with their path
+/// relative to `base_directory`, prefixed with `target_directory` if set.
+fn collect_directory_updates(
+    operations: &mut Vec<PatchOperation>,
+    base_directory: &Path,
+    directory_path: &Path,
+    target_directory: Option<&str>,
+    ignore_rules: &IgnoreRules,
+    code_page: Option<&str>,
+) -> Result<()> {
+    // Sorted so that the output doesn't depend on the file system's order
+    let walker = WalkDir::new(directory_path)
+        .follow_links(false)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_ignored_entry(ignore_rules, base_directory, entry));
     for entry in walker {
         let entry = entry?;
-        if entry.file_type().is_file() {
-            let rel_path = entry.path().strip_prefix(patch_data_directory.as_ref())?;
+        if entry.file_type().is_file() && entry.file_name() != IGNORE_FILE_NAME {
+            let rel_path = entry.path().strip_prefix(base_directory)?;
             let rel_path_str = rel_path
                 .to_str()
                 .ok_or_else(|| anyhow!("Invalid file path encountered"))?;
-            let win32_relative_path = win32_path(rel_path_str);
-            log::trace!("'{}' will be UPDATED", &win32_relative_path);
-            let file = File::open(entry.path())?;
-            archive_builder.append_file_update(win32_relative_path, file)?;
+            let win32_relative_path = match target_directory {
+                Some(target_directory) if !target_directory.is_empty() => {
+                    format!("{}\\{}", target_directory, win32_path(rel_path_str))
+                }
+                _ => win32_path(rel_path_str),
+            };
+            operations.push(PatchOperation::Update {
+                native_path: entry.path().to_path_buf(),
+                win32_relative_path: encode_entry_name(&win32_relative_path, code_page)?,
+                size: entry.metadata()?.len(),
+            });
         }
     }
     Ok(())
 }
 
+/// Parses a size such as `100MB`, `1.5GB` or `4096`. Units are binary
+/// multiples (1KB = 1024 bytes).
+fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let unit_index = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_index);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(anyhow!("Unknown size unit '{}'", unit)),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size '{}'", value))?;
+    if number <= 0.0 {
+        return Err(anyhow!("Size must be positive"));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Converts a file name to the representation used in archives, given the
+/// code page used by the game client.
+fn encode_entry_name(name: &str, code_page: Option<&str>) -> Result<String> {
+    match code_page {
+        Some(code_page) => charset::encode_entry_name(name, code_page)
+            .with_context(|| format!("Failed to encode '{}'", name)),
+        None => Ok(name.to_string()),
+    }
+}
+
 fn main() {
     const SUCCESS_EXIT_CODE: i32 = 0;
     const FAILURE_EXIT_CODE: i32 = 1;
@@ -199,3 +716,211 @@ fn posix_path<S: AsRef<str>>(path: S) -> String {
 fn win32_path<S: AsRef<str>>(path: S) -> String {
     path.as_ref().replace("/", "\\")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::ThorArchive;
+    use patch_definition::PatchEntry;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("10B").unwrap(), 10);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("2kb").unwrap(), 2048);
+        assert_eq!(parse_size("2KiB").unwrap(), 2048);
+        assert_eq!(parse_size("100MB").unwrap(), 100 << 20);
+        assert_eq!(parse_size(" 1.5 GB ").unwrap(), 3 << 29);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1XB").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn test_split_archive_path() {
+        assert_eq!(
+            split_archive_path(Path::new("out/patch.thor"), 1).unwrap(),
+            Path::new("out/patch-1.thor")
+        );
+        assert_eq!(
+            split_archive_path(Path::new("patch.2021.thor"), 12).unwrap(),
+            Path::new("patch.2021-12.thor")
+        );
+        assert_eq!(
+            split_archive_path(Path::new("patch"), 2).unwrap(),
+            Path::new("patch-2")
+        );
+        assert!(split_archive_path(Path::new(""), 1).is_err());
+    }
+
+    #[test]
+    fn test_manifest_operations() {
+        let temp_dir = tempdir().unwrap();
+        let texture_dir = temp_dir.path().join("textures");
+        fs::create_dir_all(texture_dir.join("ui")).unwrap();
+        fs::write(texture_dir.join("ui").join("button.bmp"), [1]).unwrap();
+        fs::write(texture_dir.join("ui").join("button.psd"), [2]).unwrap();
+        fs::write(texture_dir.join(IGNORE_FILE_NAME), "*.psd\n").unwrap();
+        fs::write(temp_dir.path().join("a.spr"), [3, 4]).unwrap();
+        let manifest_path = temp_dir.path().join("manifest.yml");
+        fs::write(
+            &manifest_path,
+            "files:\n  - source: a.spr\n    target: data/sprite/b.spr\n  - source: textures\n    target: data\\texture\\\ndelete:\n  - data/old.spr\n",
+        )
+        .unwrap();
+        let patch_manifest = parse_patch_manifest(&manifest_path).unwrap();
+        let patch_definition = PatchDefinition {
+            include_checksums: false,
+            checksum_algorithm: Default::default(),
+            use_grf_merging: true,
+            target_grf_name: None,
+            code_page: None,
+            entries: vec![],
+        };
+
+        let operations =
+            collect_patch_operations(&patch_definition, Some(&patch_manifest), temp_dir.path())
+                .unwrap();
+        let paths: Vec<(&str, u64)> = operations
+            .iter()
+            .map(|operation| match operation {
+                PatchOperation::Update {
+                    win32_relative_path,
+                    size,
+                    ..
+                } => (win32_relative_path.as_str(), *size),
+                PatchOperation::Removal {
+                    win32_relative_path,
+                } => (win32_relative_path.as_str(), 0),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("data\\old.spr", 0),
+                ("data\\sprite\\b.spr", 2),
+                ("data\\texture\\ui\\button.bmp", 1),
+            ]
+        );
+
+        // Paths can't be both included and removed
+        fs::write(
+            &manifest_path,
+            "files:\n  - source: a.spr\n    target: data/sprite/b.spr\ndelete:\n  - data/Sprite/B.spr\n",
+        )
+        .unwrap();
+        let patch_manifest = parse_patch_manifest(&manifest_path).unwrap();
+        assert!(collect_patch_operations(
+            &patch_definition,
+            Some(&patch_manifest),
+            temp_dir.path()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_split_parts_fit_max_size() {
+        const MAX_SIZE: u64 = 1 << 20;
+        // The second file would just fit if compression didn't make it bigger
+        let file_sizes = [
+            1000,
+            MAX_SIZE - 1320,
+            3000,
+            3000,
+            MAX_SIZE / 2,
+            MAX_SIZE / 2,
+        ];
+        let temp_dir = tempdir().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        fs::create_dir(&data_dir).unwrap();
+        // Pseudo-random data doesn't compress, which is the worst case
+        let mut state: u32 = 0x1234_5678;
+        for (i, &file_size) in file_sizes.iter().enumerate() {
+            let content: Vec<u8> = (0..file_size)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            fs::write(data_dir.join(format!("file{}.bin", i)), content).unwrap();
+        }
+        let patch_definition = PatchDefinition {
+            include_checksums: true,
+            checksum_algorithm: Default::default(),
+            use_grf_merging: false,
+            target_grf_name: None,
+            code_page: None,
+            entries: vec![PatchEntry {
+                relative_path: "data".to_string(),
+                is_removed: false,
+                in_grf_path: None,
+            }],
+        };
+
+        let output_paths = generate_patch_from_definition(
+            patch_definition,
+            None,
+            temp_dir.path(),
+            temp_dir.path().join("patch.thor"),
+            Some(MAX_SIZE),
+        )
+        .unwrap();
+        assert!(output_paths.len() > 1);
+        let mut entry_count = 0;
+        for output_path in &output_paths {
+            assert!(fs::metadata(output_path).unwrap().len() <= MAX_SIZE);
+            let thor_archive = ThorArchive::open(output_path).unwrap();
+            // Each part has its own 'data.integrity'
+            entry_count += thor_archive.file_count() - 1;
+        }
+        assert_eq!(entry_count, file_sizes.len());
+    }
+
+    #[test]
+    fn test_split_parts_fit_max_size_with_many_paths() {
+        const MAX_SIZE: u64 = 16 * 1024;
+        // Small files with long paths, whose BLAKE3 'data.integrity' lines
+        // take more room than their data
+        let temp_dir = tempdir().unwrap();
+        let data_dir = temp_dir.path().join("data").join("a".repeat(100));
+        fs::create_dir_all(&data_dir).unwrap();
+        for i in 0..400 {
+            fs::write(
+                data_dir.join(format!("{}{}.txt", "b".repeat(100), i)),
+                [i as u8],
+            )
+            .unwrap();
+        }
+        let patch_definition = PatchDefinition {
+            include_checksums: true,
+            checksum_algorithm: gruf::thor::ChecksumAlgorithm::Blake3,
+            use_grf_merging: false,
+            target_grf_name: None,
+            code_page: None,
+            entries: vec![PatchEntry {
+                relative_path: "data".to_string(),
+                is_removed: false,
+                in_grf_path: None,
+            }],
+        };
+
+        let output_paths = generate_patch_from_definition(
+            patch_definition,
+            None,
+            temp_dir.path(),
+            temp_dir.path().join("patch.thor"),
+            Some(MAX_SIZE),
+        )
+        .unwrap();
+        assert!(output_paths.len() > 1);
+        for output_path in &output_paths {
+            assert!(fs::metadata(output_path).unwrap().len() <= MAX_SIZE);
+        }
+    }
+}
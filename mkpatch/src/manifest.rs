@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Explicit list of a patch's content, usually generated by build pipelines,
+/// so that patch data doesn't have to be staged in a mirror of the game's
+/// directory tree.
+#[derive(Deserialize, Clone, Default)]
+pub struct PatchManifest {
+    #[serde(default)]
+    pub files: Vec<ManifestFile>,
+    #[serde(default)]
+    pub delete: Vec<String>, // Paths inside the GRF (or the game's directory)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ManifestFile {
+    // File or directory, relative to the manifest. Directories are included
+    // recursively, under `target`
+    pub source: PathBuf,
+    pub target: String, // Path inside the GRF (or the game's directory)
+}
+
+/// Parses the manifest at `file_path`. Source paths are returned relative to
+/// the current working directory.
+pub fn parse_patch_manifest(file_path: impl AsRef<Path>) -> Result<PatchManifest> {
+    let file_path = file_path.as_ref();
+    let file = File::open(file_path)?;
+    let file_reader = BufReader::new(file);
+    let mut manifest: PatchManifest =
+        serde_yaml::from_reader(file_reader).context("Invalid manifest")?;
+    let manifest_directory = file_path.parent().unwrap_or_else(|| Path::new(""));
+    for file in &mut manifest.files {
+        file.source = manifest_directory.join(&file.source);
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_patch_manifest() {
+        let temp_dir = tempdir().unwrap();
+        let manifest_path = temp_dir.path().join("build").join("manifest.yml");
+        fs::create_dir(temp_dir.path().join("build")).unwrap();
+        fs::write(
+            &manifest_path,
+            "files:\n  - source: out/sprite.spr\n    target: data\\sprite\\a.spr\n  - source: ../textures\n    target: data\\texture\ndelete:\n  - data\\old.spr\n",
+        )
+        .unwrap();
+
+        let manifest = parse_patch_manifest(&manifest_path).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(
+            manifest.files[0].source,
+            temp_dir.path().join("build").join("out/sprite.spr")
+        );
+        assert_eq!(manifest.files[0].target, "data\\sprite\\a.spr");
+        assert_eq!(
+            manifest.files[1].source,
+            temp_dir.path().join("build").join("../textures")
+        );
+        assert_eq!(manifest.files[1].target, "data\\texture");
+        assert_eq!(manifest.delete, vec!["data\\old.spr".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_empty_patch_manifest() {
+        let temp_dir = tempdir().unwrap();
+        let manifest_path = temp_dir.path().join("manifest.yml");
+        fs::write(&manifest_path, "delete: [data\\old.spr]\n").unwrap();
+
+        let manifest = parse_patch_manifest(&manifest_path).unwrap();
+        assert!(manifest.files.is_empty());
+        assert_eq!(manifest.delete.len(), 1);
+    }
+}
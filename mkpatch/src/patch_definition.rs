@@ -3,14 +3,19 @@ use std::io::BufReader;
 use std::path::Path;
 
 use anyhow::{Context, Result};
+use gruf::thor::ChecksumAlgorithm;
 use serde::Deserialize;
 
 #[derive(Deserialize, Clone)]
 pub struct PatchDefinition {
     #[serde(default)] // Defaults to false
     pub include_checksums: bool,
+    #[serde(default)] // Defaults to crc32
+    pub checksum_algorithm: ChecksumAlgorithm,
     pub use_grf_merging: bool,
     pub target_grf_name: Option<String>,
+    pub code_page: Option<String>, // File names are used as is when not set
+    #[serde(default)] // Entries can also come from a manifest
     pub entries: Vec<PatchEntry>,
 }
 
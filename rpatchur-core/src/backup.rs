@@ -0,0 +1,234 @@
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use gruf::thor::ThorArchive;
+
+use super::file_attributes::with_writable_file;
+use super::patching::resolve_disk_entries;
+
+/// Directory backups are stored in, relative to the client's directory.
+pub const BACKUP_DIRECTORY_NAME: &str = "backup";
+pub const DEFAULT_BACKUP_MAX_SIZE_MB: u64 = 512;
+
+/// Copies the files of `root_directory` that a THOR patch is about to replace
+/// or remove into a timestamped subdirectory of `backup_directory` (e.g.,
+/// `backup/20210507-183012/data.ini`).
+///
+/// Backups are kept under `max_size` bytes in total: the oldest ones are
+/// removed to make room for new ones, and files that still don't fit aren't
+/// backed up. Within the same subdirectory, the first copy of a file wins.
+pub fn backup_replaced_files<R: Read + Seek>(
+    backup_directory: &Path,
+    root_directory: &Path,
+    code_page: Option<&str>,
+    thor_archive: &ThorArchive<R>,
+    max_size: u64,
+) -> Result<()> {
+    let replaced_files: Vec<(PathBuf, u64)> =
+        resolve_disk_entries(root_directory, code_page, thor_archive)?
+            .into_iter()
+            .filter_map(|(_, dest_path)| {
+                let file_size = fs::metadata(&dest_path).ok().filter(|m| m.is_file())?.len();
+                Some((dest_path, file_size))
+            })
+            .collect();
+    if replaced_files.is_empty() {
+        return Ok(());
+    }
+    let backup_set_name = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let backup_set_directory = backup_directory.join(&backup_set_name);
+    // Make room for the new files, oldest backups first
+    let required_size: u64 = replaced_files.iter().map(|(_, file_size)| file_size).sum();
+    let mut backup_size = directory_size(backup_directory)?;
+    for old_backup_set_directory in list_backup_sets(backup_directory)? {
+        if backup_size.saturating_add(required_size) <= max_size {
+            break;
+        }
+        if old_backup_set_directory == backup_set_directory {
+            continue;
+        }
+        let old_backup_set_size = directory_size(&old_backup_set_directory)?;
+        log::info!("Removing backup '{}'", old_backup_set_directory.display());
+        fs::remove_dir_all(&old_backup_set_directory)
+            .with_context(|| "Failed to remove old backup")?;
+        backup_size = backup_size.saturating_sub(old_backup_set_size);
+    }
+
+    for (file_path, file_size) in replaced_files {
+        let relative_path = match file_path.strip_prefix(root_directory) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let backup_file_path = backup_set_directory.join(relative_path);
+        if backup_file_path.exists() {
+            continue;
+        }
+        if backup_size.saturating_add(file_size) > max_size {
+            log::warn!(
+                "Not backing up '{}', backups would exceed their size limit",
+                relative_path.display()
+            );
+            continue;
+        }
+        if let Some(parent_dir) = backup_file_path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+        fs::copy(&file_path, &backup_file_path)
+            .with_context(|| format!("Failed to back up '{}'", relative_path.display()))?;
+        backup_size += file_size;
+    }
+    Ok(())
+}
+
+/// Copies the files backed up in `backup_directory` back into
+/// `root_directory` and removes the backups.
+///
+/// When a file has been backed up several times, its oldest copy (i.e., the
+/// file as it was before the first patch replaced it) is restored.
+///
+/// Returns the number of files restored.
+pub fn restore_backups(backup_directory: &Path, root_directory: &Path) -> Result<usize> {
+    if !backup_directory.exists() {
+        return Ok(0);
+    }
+    let mut restored_files = Vec::new();
+    // Newest backups first, so that older copies overwrite newer ones
+    for backup_set_directory in list_backup_sets(backup_directory)?.into_iter().rev() {
+        for backup_file_path in list_files(&backup_set_directory)? {
+            let relative_path = match backup_file_path.strip_prefix(&backup_set_directory) {
+                Ok(v) => v.to_path_buf(),
+                Err(_) => continue,
+            };
+            let file_path = root_directory.join(&relative_path);
+            if let Some(parent_dir) = file_path.parent() {
+                fs::create_dir_all(parent_dir)?;
+            }
+            with_writable_file(&file_path, || {
+                fs::copy(&backup_file_path, &file_path)?;
+                Ok(())
+            })
+            .with_context(|| format!("Failed to restore '{}'", relative_path.display()))?;
+            if !restored_files.contains(&relative_path) {
+                restored_files.push(relative_path);
+            }
+        }
+    }
+    fs::remove_dir_all(backup_directory).with_context(|| "Failed to remove backups")?;
+    Ok(restored_files.len())
+}
+
+/// Lists the subdirectories of `backup_directory`, oldest first.
+fn list_backup_sets(backup_directory: &Path) -> Result<Vec<PathBuf>> {
+    if !backup_directory.exists() {
+        return Ok(vec![]);
+    }
+    let mut backup_sets: Vec<PathBuf> = fs::read_dir(backup_directory)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    // Names are timestamps
+    backup_sets.sort();
+    Ok(backup_sets)
+}
+
+/// Lists the files located in `directory` and its subdirectories.
+fn list_files(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(list_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn directory_size(directory: &Path) -> Result<u64> {
+    if !directory.exists() {
+        return Ok(0);
+    }
+    list_files(directory)?
+        .iter()
+        .try_fold(0, |size, file_path| {
+            Ok(size + fs::metadata(file_path)?.len())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patching::apply_patch_to_disk;
+    use crate::test_fixtures::{build_disk_patch, PatchEntries};
+    use tempfile::tempdir;
+
+    fn apply_disk_patch(root_dir: &Path, backup_dir: &Path, entries: &PatchEntries) {
+        let thor_archive_path = root_dir.with_extension("thor");
+        build_disk_patch(&thor_archive_path, entries);
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        backup_replaced_files(backup_dir, root_dir, None, &thor_archive, 1024).unwrap();
+        apply_patch_to_disk(root_dir, None, &mut thor_archive, |_, _, _| {}).unwrap();
+    }
+
+    #[test]
+    fn test_restore_backups() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let backup_dir = root_dir.join("backup");
+        fs::create_dir_all(root_dir.join("skin")).unwrap();
+        fs::write(root_dir.join("data.ini"), "custom").unwrap();
+        fs::write(root_dir.join("skin").join("a.bmp"), "custom skin").unwrap();
+
+        apply_disk_patch(
+            &root_dir,
+            &backup_dir,
+            &[
+                ("data.ini", Some(b"patched")),
+                ("skin\\a.bmp", None),
+                ("new.txt", Some(b"new")),
+            ],
+        );
+        assert_eq!(fs::read(root_dir.join("data.ini")).unwrap(), b"patched");
+        assert!(!root_dir.join("skin").join("a.bmp").exists());
+
+        assert_eq!(restore_backups(&backup_dir, &root_dir).unwrap(), 2);
+        assert_eq!(fs::read(root_dir.join("data.ini")).unwrap(), b"custom");
+        assert_eq!(
+            fs::read(root_dir.join("skin").join("a.bmp")).unwrap(),
+            b"custom skin"
+        );
+        // Files added by patches are left alone
+        assert!(root_dir.join("new.txt").exists());
+        assert!(!backup_dir.exists());
+        assert_eq!(restore_backups(&backup_dir, &root_dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_backup_size_limit() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let backup_dir = root_dir.join("backup");
+        fs::create_dir_all(&root_dir).unwrap();
+        fs::write(root_dir.join("small.txt"), "small").unwrap();
+        fs::write(root_dir.join("big.bin"), vec![0; 2048]).unwrap();
+        // Older backups make room for new ones
+        let old_backup_set_dir = backup_dir.join("20000101-000000");
+        fs::create_dir_all(&old_backup_set_dir).unwrap();
+        fs::write(old_backup_set_dir.join("old.txt"), vec![0; 1000]).unwrap();
+
+        apply_disk_patch(
+            &root_dir,
+            &backup_dir,
+            &[("small.txt", Some(b"patched")), ("big.bin", None)],
+        );
+        assert!(!old_backup_set_dir.exists());
+        let backup_files = list_files(&backup_dir).unwrap();
+        assert_eq!(backup_files.len(), 1);
+        assert!(backup_files[0].ends_with("small.txt"));
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::{ThorPatchInfo, ThorPatchList};
+use serde::{Deserialize, Serialize};
+
+use super::elevation::share_with_unelevated_user;
+
+const CHECKSUM_PREFIX: &str = "crc32:";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct PatcherCache {
+    pub last_patch_index: Option<usize>,
+    #[serde(default)]
+    pub skipped_patch_indices: Vec<usize>, // Skipped on purpose and not applied since
+    #[serde(default)]
+    pub skip_requests: Vec<usize>, // Requested through `PatcherCommand::SkipPatch`
+    #[serde(default)]
+    pub grf_unused_space: BTreeMap<String, u64>, // GRF name -> Bytes defragmenting would reclaim
+}
+
+impl PatcherCache {
+    /// Forgets about applied and skipped patches (e.g., when the cached index
+    /// isn't part of the patch list anymore). Skip requests are kept.
+    pub fn forget_applied_patches(&mut self) {
+        self.last_patch_index = None;
+        self.skipped_patch_indices.clear();
+    }
+
+    /// Forgets about the patches applied from `index` on, so that the next
+    /// update applies them again (along with the patches skipped since).
+    pub fn forget_patches_from(&mut self, index: usize) {
+        if matches!(self.last_patch_index, Some(last_patch_index) if last_patch_index >= index) {
+            self.last_patch_index = index.checked_sub(1);
+        }
+        self.skipped_patch_indices.retain(|&x| x < index);
+    }
+
+    /// Records that the patch at `index` has been applied.
+    pub fn mark_patch_applied(&mut self, index: usize) {
+        self.skipped_patch_indices.retain(|&x| x != index);
+        self.advance_last_patch_index(index);
+    }
+
+    /// Records that the patches at `indices` have been skipped on purpose.
+    pub fn mark_patches_skipped(&mut self, indices: &[usize]) {
+        self.record_skipped_patches(indices);
+        for &index in indices {
+            self.advance_last_patch_index(index);
+        }
+    }
+
+    /// Records that the patches at `indices` are skipped on purpose, before
+    /// the patches that come before them have been applied (i.e., without
+    /// advancing the last patch index).
+    pub fn record_skipped_patches(&mut self, indices: &[usize]) {
+        for &index in indices {
+            if !self.skipped_patch_indices.contains(&index) {
+                self.skipped_patch_indices.push(index);
+            }
+        }
+    }
+
+    /// Removes the patches that must be skipped from `new_patches`, which are
+    /// the patches of `patch_list` that come after the last applied patch.
+    ///
+    /// Patches are skipped if their index is part of `configured_skip_indices`
+    /// or of the cached skip requests. Returns the patches skipped during
+    /// previous updates that mustn't be skipped anymore (and thus have to be
+    /// applied first), along with the indices of the new patches that have
+    /// been left out.
+    pub fn reconcile_skipped_patches(
+        &mut self,
+        patch_list: &[ThorPatchInfo],
+        new_patches: &mut ThorPatchList,
+        configured_skip_indices: &[usize],
+    ) -> (ThorPatchList, Vec<usize>) {
+        let available_indices: HashSet<usize> = patch_list.iter().map(|x| x.index).collect();
+        // Patches removed from the patch list cannot be applied anymore
+        self.skipped_patch_indices
+            .retain(|x| available_indices.contains(x));
+        self.skip_requests.retain(|x| available_indices.contains(x));
+
+        let skip_indices: HashSet<usize> = configured_skip_indices
+            .iter()
+            .chain(self.skip_requests.iter())
+            .copied()
+            .collect();
+        let last_patch_index = self.last_patch_index;
+        let previously_skipped_patches = patch_list
+            .iter()
+            .filter(|x| {
+                let applied_before = match last_patch_index {
+                    Some(last_patch_index) => x.index <= last_patch_index,
+                    None => false,
+                };
+                applied_before
+                    && self.skipped_patch_indices.contains(&x.index)
+                    && !skip_indices.contains(&x.index)
+            })
+            .cloned()
+            .collect();
+        let mut skipped_indices = vec![];
+        new_patches.retain(|x| {
+            let skip = skip_indices.contains(&x.index);
+            if skip {
+                skipped_indices.push(x.index);
+            }
+            !skip
+        });
+        (previously_skipped_patches, skipped_indices)
+    }
+
+    /// Records the number of bytes left unused in the GRF named `grf_name`
+    /// (e.g., by removed entries).
+    pub fn record_grf_unused_space(&mut self, grf_name: &str, unused_space: u64) {
+        if unused_space == 0 {
+            self.grf_unused_space.remove(grf_name);
+        } else {
+            self.grf_unused_space
+                .insert(grf_name.to_string(), unused_space);
+        }
+    }
+
+    fn advance_last_patch_index(&mut self, index: usize) {
+        // Previously skipped patches are applied after more recent ones
+        match self.last_patch_index {
+            Some(last_patch_index) if last_patch_index >= index => {}
+            _ => self.last_patch_index = Some(index),
+        }
+    }
+}
+
+/// Patcher cache, along with the path of the file it's persisted to.
+pub struct PatcherCacheFile {
+    path: PathBuf,
+    pub cache: PatcherCache,
+}
+
+impl PatcherCacheFile {
+    /// Reads the cache file at `path`. Starts from an empty cache if there's
+    /// none.
+    ///
+    /// If both the cache file and its backup are corrupted, this also starts
+    /// from an empty cache, with which all the patches are applied again.
+    /// Fails if the cache file can't be read.
+    pub async fn open(path: PathBuf) -> Result<PatcherCacheFile> {
+        let cache = match read_cache_file(&path).await {
+            Ok(v) => v,
+            Err(e) if is_not_found(&e) && !backup_file_path(&path).exists() => {
+                PatcherCache::default()
+            }
+            Err(e) if is_read_error(&e) => return Err(e),
+            Err(e) => {
+                log::error!(
+                    "{:#}, the patcher cache and its backup are corrupted, all patches will be applied again",
+                    e
+                );
+                PatcherCache::default()
+            }
+        };
+        Ok(PatcherCacheFile { path, cache })
+    }
+
+    /// Starts from an empty cache, which replaces the cache file at `path` once
+    /// saved.
+    pub fn empty(path: PathBuf) -> PatcherCacheFile {
+        PatcherCacheFile {
+            path,
+            cache: PatcherCache::default(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        write_cache_file(&self.path, &self.cache).await
+    }
+}
+
+/// Reads the cache file at `cache_file_path`. The backup of the previous
+/// version of the cache is read instead if the cache file is corrupted (e.g.,
+/// because the patcher crashed while writing it).
+pub async fn read_cache_file(cache_file_path: impl AsRef<Path>) -> Result<PatcherCache> {
+    let cache_file_path = cache_file_path.as_ref();
+    let err = match read_and_verify_cache_file(cache_file_path) {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+    let backup_file_path = backup_file_path(cache_file_path);
+    if !backup_file_path.exists() {
+        return Err(err);
+    }
+    log::warn!("{:#}, using the backup of the patcher cache", err);
+    read_and_verify_cache_file(&backup_file_path)
+}
+
+/// Writes the cache file at `cache_file_path`.
+///
+/// The cache is written to a temporary file first, which then replaces the
+/// cache file. The previous version is kept as a backup.
+pub async fn write_cache_file(
+    cache_file_path: impl AsRef<Path>,
+    new_cache: &PatcherCache,
+) -> Result<()> {
+    let cache_file_path = cache_file_path.as_ref();
+    let tmp_file_path = with_suffix(cache_file_path, "tmp");
+    {
+        let mut tmp_file = File::create(&tmp_file_path)?;
+        tmp_file.write_all(serialize_cache(new_cache)?.as_bytes())?;
+        tmp_file.sync_all()?;
+    }
+    // Only keep backups that can be trusted
+    if read_and_verify_cache_file(cache_file_path).is_ok() {
+        let backup_file_path = backup_file_path(cache_file_path);
+        fs::copy(cache_file_path, &backup_file_path)
+            .with_context(|| "Failed to back up the patcher cache")?;
+        share_with_unelevated_user(backup_file_path);
+    }
+    fs::rename(&tmp_file_path, cache_file_path)
+        .with_context(|| "Failed to replace the patcher cache")?;
+    share_with_unelevated_user(cache_file_path);
+    Ok(())
+}
+
+/// Removes the cache file at `cache_file_path`, along with its backup. Files
+/// that don't exist are ignored.
+pub fn remove_cache_file(cache_file_path: impl AsRef<Path>) -> Result<()> {
+    let cache_file_path = cache_file_path.as_ref();
+    // The backup would be used instead otherwise
+    match fs::remove_file(backup_file_path(cache_file_path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    match fs::remove_file(cache_file_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the backup of the cache file at `cache_file_path`.
+fn backup_file_path(cache_file_path: impl AsRef<Path>) -> PathBuf {
+    with_suffix(cache_file_path.as_ref(), "bak")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn read_and_verify_cache_file(cache_file_path: &Path) -> Result<PatcherCache> {
+    let content = fs::read_to_string(cache_file_path)?;
+    deserialize_cache(&content)
+        .with_context(|| format!("Invalid patcher cache '{}'", cache_file_path.display()))
+}
+
+/// Serializes the cache as JSON, followed by a line containing the checksum of
+/// the JSON document.
+fn serialize_cache(cache: &PatcherCache) -> Result<String> {
+    let content = serde_json::to_string(cache).context("Failed to serialize patcher cache")?;
+    let checksum = checksum(&content);
+    Ok(format!(
+        "{}\n{}{:08x}\n",
+        content, CHECKSUM_PREFIX, checksum
+    ))
+}
+
+/// Deserializes a cache serialized with `serialize_cache`, or a legacy cache
+/// (plain JSON, without checksum).
+fn deserialize_cache(content: &str) -> Result<PatcherCache> {
+    let content = content.trim_end();
+    let content = match content.rfind('\n') {
+        Some(pos) if content[pos + 1..].starts_with(CHECKSUM_PREFIX) => {
+            let (content, checksum_line) = content.split_at(pos);
+            let expected_checksum =
+                u32::from_str_radix(&checksum_line[1 + CHECKSUM_PREFIX.len()..], 16)
+                    .context("Invalid checksum")?;
+            if checksum(content) != expected_checksum {
+                return Err(anyhow!("Checksum mismatch"));
+            }
+            content
+        }
+        _ => content,
+    };
+    serde_json::from_str(content).context("Failed to deserialize patcher cache")
+}
+
+fn checksum(content: &str) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(content.as_bytes());
+    hasher.finalize()
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == ErrorKind::NotFound,
+        None => false,
+    }
+}
+
+/// Returns `true` if `err` tells that a file couldn't be read, rather than
+/// that its content is corrupted.
+fn is_read_error(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() != ErrorKind::InvalidData,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::patch_list_from_string;
+
+    fn indices(patch_list: &[ThorPatchInfo]) -> Vec<usize> {
+        patch_list.iter().map(|x| x.index).collect()
+    }
+
+    #[test]
+    fn test_deserialize_legacy_cache() {
+        let cache: PatcherCache = serde_json::from_str(r#"{"last_patch_index":5}"#).unwrap();
+        assert_eq!(
+            cache,
+            PatcherCache {
+                last_patch_index: Some(5),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_forget_patches_from() {
+        let mut cache = PatcherCache {
+            last_patch_index: Some(12),
+            skipped_patch_indices: vec![3, 9],
+            ..Default::default()
+        };
+        cache.forget_patches_from(15);
+        assert_eq!(cache.last_patch_index, Some(12));
+        cache.forget_patches_from(5);
+        assert_eq!(cache.last_patch_index, Some(4));
+        assert_eq!(cache.skipped_patch_indices, vec![3]);
+        cache.forget_patches_from(0);
+        assert_eq!(cache.last_patch_index, None);
+        assert!(cache.skipped_patch_indices.is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_cache() {
+        let cache = PatcherCache {
+            last_patch_index: Some(12),
+            skipped_patch_indices: vec![3],
+            ..Default::default()
+        };
+        let content = serialize_cache(&cache).unwrap();
+        assert_eq!(deserialize_cache(&content).unwrap(), cache);
+        // Legacy caches don't have a checksum
+        assert!(deserialize_cache(r#"{"last_patch_index":5}"#).is_ok());
+        // Truncated and altered caches
+        assert!(deserialize_cache(&content[..content.len() / 2]).is_err());
+        let altered_content = content.replace("12", "13");
+        assert!(deserialize_cache(&altered_content).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_cache_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cache_file_path = tmp_dir.path().join("rpatchur.dat");
+        let cache_file = PatcherCacheFile::open(cache_file_path.clone())
+            .await
+            .unwrap();
+        assert_eq!(cache_file.cache, PatcherCache::default());
+        for last_patch_index in 1..=2 {
+            let cache = PatcherCache {
+                last_patch_index: Some(last_patch_index),
+                ..Default::default()
+            };
+            write_cache_file(&cache_file_path, &cache).await.unwrap();
+        }
+
+        // The backup is used when the cache is corrupted
+        fs::write(&cache_file_path, r#"{"last_patch_"#).unwrap();
+        let cache_file = PatcherCacheFile::open(cache_file_path.clone())
+            .await
+            .unwrap();
+        assert_eq!(cache_file.cache.last_patch_index, Some(1));
+        // Corrupted caches aren't backed up
+        cache_file.save().await.unwrap();
+        let cache = read_cache_file(&cache_file_path).await.unwrap();
+        assert_eq!(cache.last_patch_index, Some(1));
+
+        fs::write(&cache_file_path, "").unwrap();
+        fs::write(backup_file_path(&cache_file_path), "").unwrap();
+        let cache_file = PatcherCacheFile::open(cache_file_path).await.unwrap();
+        assert_eq!(cache_file.cache, PatcherCache::default());
+    }
+
+    #[test]
+    fn test_reconcile_skipped_patches() {
+        let patch_list =
+            patch_list_from_string("1 a.thor\n2 b.thor\n3 c.thor\n4 d.thor\n").unwrap();
+        let mut cache = PatcherCache {
+            last_patch_index: Some(1),
+            skip_requests: vec![4, 7],
+            ..Default::default()
+        };
+        let mut new_patches: ThorPatchList =
+            patch_list.iter().filter(|x| x.index > 1).cloned().collect();
+        let (previously_skipped_patches, skipped_indices) =
+            cache.reconcile_skipped_patches(&patch_list, &mut new_patches, &[2]);
+        assert!(previously_skipped_patches.is_empty());
+        assert_eq!(indices(&new_patches), vec![3]);
+        assert_eq!(skipped_indices, vec![2, 4]);
+        // Unknown patches are forgotten
+        assert_eq!(cache.skip_requests, vec![4]);
+        cache.record_skipped_patches(&skipped_indices);
+        assert_eq!(cache.last_patch_index, Some(1));
+        assert_eq!(cache.skipped_patch_indices, vec![2, 4]);
+        cache.mark_patch_applied(3);
+        cache.mark_patches_skipped(&skipped_indices);
+        assert_eq!(cache.last_patch_index, Some(4));
+        assert_eq!(cache.skipped_patch_indices, vec![2, 4]);
+
+        // Patch 2 has been fixed, it must be applied on the next update
+        let mut new_patches = ThorPatchList::new();
+        let (previously_skipped_patches, skipped_indices) =
+            cache.reconcile_skipped_patches(&patch_list, &mut new_patches, &[]);
+        assert_eq!(indices(&previously_skipped_patches), vec![2]);
+        assert!(skipped_indices.is_empty());
+        cache.mark_patch_applied(2);
+        assert_eq!(cache.last_patch_index, Some(4));
+        assert_eq!(cache.skipped_patch_indices, vec![4]);
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{PatchFailureAction, PatcherCommand};
+
+pub type InterruptibleFnResult<T> = std::result::Result<T, InterruptibleFnError>;
+
+pub enum InterruptibleFnError {
+    Err(String), // An actual error
+    Interrupted, // An interruption
+}
+
+/// Handle used to cancel an update started with `Patcher::update`, and to
+/// answer the prompts of its progress sink, e.g., from another thread.
+///
+/// Clones share their state. Once canceled, a token stays canceled: updates
+/// started with it are canceled right away.
+#[derive(Clone)]
+pub struct CancellationToken {
+    is_canceled: Arc<AtomicBool>,
+    // Wakes the update up, it also keeps the channel open
+    commands_tx: flume::Sender<PatcherCommand>,
+    commands_rx: flume::Receiver<PatcherCommand>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        let (commands_tx, commands_rx) = flume::unbounded();
+        CancellationToken {
+            is_canceled: Arc::new(AtomicBool::new(false)),
+            commands_tx,
+            commands_rx,
+        }
+    }
+
+    /// Cancels the update that uses this token, if any, and the ones started
+    /// with it later.
+    pub fn cancel(&self) {
+        if !self.is_canceled.swap(true, Ordering::SeqCst) {
+            let _ = self.commands_tx.send(PatcherCommand::CancelUpdate);
+        }
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.is_canceled.load(Ordering::SeqCst)
+    }
+
+    /// Answers the `ProgressSink::prompt_close_processes` prompt of the update
+    /// that uses this token.
+    pub fn reply_close_processes(&self, close: bool) {
+        let _ = self
+            .commands_tx
+            .send(PatcherCommand::CloseProcessesReply(close));
+    }
+
+    /// Answers the `ProgressSink::prompt_patch_failure` prompt of the update
+    /// that uses this token.
+    pub fn reply_patch_failure(&self, action: PatchFailureAction) {
+        let _ = self
+            .commands_tx
+            .send(PatcherCommand::PatchFailureReply(action));
+    }
+
+    /// Returns the channel the patching routines listen to for interruptions
+    /// and replies.
+    pub(crate) fn commands(&self) -> CommandReceiver {
+        CommandReceiver::new(self.commands_rx.clone())
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Commands received by the patching routines of one patcher.
+///
+/// It remembers if a `Quit` command has been received, so that the patching
+/// thread stops even if the command has been consumed by an interrupted task.
+pub struct CommandReceiver {
+    rx: flume::Receiver<PatcherCommand>,
+    quit_requested: bool,
+}
+
+impl CommandReceiver {
+    pub fn new(rx: flume::Receiver<PatcherCommand>) -> CommandReceiver {
+        CommandReceiver {
+            rx,
+            quit_requested: false,
+        }
+    }
+
+    pub async fn recv_async(&mut self) -> Result<PatcherCommand, flume::RecvError> {
+        self.rx.recv_async().await
+    }
+
+    /// Indicates whether the patching thread has been asked to stop.
+    pub fn is_quit_requested(&self) -> bool {
+        self.quit_requested
+    }
+
+    /// Indicates whether `cmd` interrupts the current task.
+    fn is_interruption(&mut self, cmd: &PatcherCommand) -> bool {
+        match cmd {
+            PatcherCommand::Quit => {
+                self.quit_requested = true;
+                true
+            }
+            PatcherCommand::CancelUpdate => true,
+            _ => false,
+        }
+    }
+}
+
+pub async fn wait_for_cancellation(
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnError {
+    if let Ok(cmd) = patching_thread_rx.recv_async().await {
+        if patching_thread_rx.is_interruption(&cmd) {
+            InterruptibleFnError::Interrupted
+        } else {
+            InterruptibleFnError::Err("Unexpected command received".to_string())
+        }
+    } else {
+        InterruptibleFnError::Err("Channel was closed".to_string())
+    }
+}
+
+/// Waits for the UI to tell what to do with a patch that failed to apply.
+pub async fn wait_for_patch_failure_action(
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnResult<PatchFailureAction> {
+    loop {
+        match patching_thread_rx.recv_async().await {
+            Err(_) => return Err(InterruptibleFnError::Err("Channel was closed".to_string())),
+            Ok(PatcherCommand::PatchFailureReply(action)) => return Ok(action),
+            Ok(cmd) if patching_thread_rx.is_interruption(&cmd) => {
+                return Err(InterruptibleFnError::Interrupted)
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Waits for the UI to tell whether running processes can be closed.
+///
+/// Processes are left running if the UI doesn't answer within `timeout`.
+pub async fn wait_for_close_processes_reply(
+    patching_thread_rx: &mut CommandReceiver,
+    timeout: Duration,
+) -> InterruptibleFnResult<bool> {
+    let wait_for_reply = async {
+        loop {
+            match patching_thread_rx.recv_async().await {
+                Err(_) => return Err(InterruptibleFnError::Err("Channel was closed".to_string())),
+                Ok(PatcherCommand::CloseProcessesReply(close)) => return Ok(close),
+                Ok(cmd) if patching_thread_rx.is_interruption(&cmd) => {
+                    return Err(InterruptibleFnError::Interrupted)
+                }
+                Ok(_) => {}
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, wait_for_reply).await {
+        Ok(res) => res,
+        Err(_) => {
+            log::warn!("No answer to the prompt, leaving the processes running");
+            Ok(false)
+        }
+    }
+}
+
+pub fn process_incoming_commands(
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnResult<()> {
+    match patching_thread_rx.rx.try_recv() {
+        Ok(cmd) if patching_thread_rx.is_interruption(&cmd) => {
+            Err(InterruptibleFnError::Interrupted)
+        }
+        Ok(_) => Ok(()),
+        Err(e) => match e {
+            flume::TryRecvError::Disconnected => {
+                Err(InterruptibleFnError::Err("Channel was closed".to_string()))
+            }
+            flume::TryRecvError::Empty => Ok(()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_quit_requests_are_per_receiver() {
+        let (tx1, rx1) = flume::unbounded();
+        let (_tx2, rx2) = flume::unbounded();
+        let mut rx1 = CommandReceiver::new(rx1);
+        let rx2 = CommandReceiver::new(rx2);
+        tx1.send(PatcherCommand::Quit).unwrap();
+        assert!(matches!(
+            wait_for_cancellation(&mut rx1).await,
+            InterruptibleFnError::Interrupted
+        ));
+        assert!(rx1.is_quit_requested());
+        assert!(!rx2.is_quit_requested());
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_token_replies() {
+        let cancel_token = CancellationToken::new();
+        let mut commands = cancel_token.commands();
+        cancel_token.reply_close_processes(true);
+        let close = wait_for_close_processes_reply(&mut commands, Duration::from_secs(1)).await;
+        assert!(matches!(close, Ok(true)));
+        cancel_token.reply_patch_failure(PatchFailureAction::Skip);
+        let action = wait_for_patch_failure_action(&mut commands).await;
+        assert!(matches!(action, Ok(PatchFailureAction::Skip)));
+        // Unanswered prompts leave the processes running
+        let close = wait_for_close_processes_reply(&mut commands, Duration::from_millis(10)).await;
+        assert!(matches!(close, Ok(false)));
+    }
+}
@@ -0,0 +1,157 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+use anyhow::{anyhow, Context, Result};
+use flate2::write::GzDecoder;
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+
+/// Content codings accepted when fetching patch lists.
+pub const ACCEPT_ENCODING: &str = "gzip";
+
+/// Compression applied to a patch file, as indicated by its extension (e.g.,
+/// `patch.thor.gz`). Only gzip is supported.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PatchCompression {
+    None,
+    Gzip,
+}
+
+impl PatchCompression {
+    pub fn from_file_name(file_name: &str) -> PatchCompression {
+        let file_name = file_name.to_ascii_lowercase();
+        if file_name.ends_with(".gz") {
+            PatchCompression::Gzip
+        } else {
+            PatchCompression::None
+        }
+    }
+}
+
+/// Decompresses patch files chunk by chunk, while they're being downloaded.
+pub enum StreamDecoder {
+    Identity,
+    Gzip(Box<GzDecoder<Vec<u8>>>),
+}
+
+impl StreamDecoder {
+    pub fn new(compression: PatchCompression) -> StreamDecoder {
+        match compression {
+            PatchCompression::None => StreamDecoder::Identity,
+            PatchCompression::Gzip => StreamDecoder::Gzip(Box::new(GzDecoder::new(Vec::new()))),
+        }
+    }
+
+    /// Returns the decompressed data made available by `chunk`.
+    pub fn decode<'a>(&mut self, chunk: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        match self {
+            StreamDecoder::Identity => Ok(Cow::Borrowed(chunk)),
+            StreamDecoder::Gzip(decoder) => {
+                decoder
+                    .write_all(chunk)
+                    .context("Failed to decompress data")?;
+                Ok(Cow::Owned(std::mem::take(decoder.get_mut())))
+            }
+        }
+    }
+
+    /// Returns the remaining decompressed data, once all chunks have been
+    /// decoded.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        match self {
+            StreamDecoder::Identity => Ok(Vec::new()),
+            StreamDecoder::Gzip(decoder) => decoder.finish().context("Truncated compressed data"),
+        }
+    }
+}
+
+/// Decodes a response's body according to its `Content-Encoding` header.
+pub fn decode_response_body(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>> {
+    let content_encoding = match headers.get(CONTENT_ENCODING) {
+        Some(value) => value
+            .to_str()
+            .context("Invalid Content-Encoding header")?
+            .trim()
+            .to_ascii_lowercase(),
+        None => return Ok(body.to_vec()),
+    };
+    let compression = match content_encoding.as_str() {
+        "" | "identity" => PatchCompression::None,
+        "gzip" | "x-gzip" => PatchCompression::Gzip,
+        _ => {
+            return Err(anyhow!(
+                "Unsupported content encoding '{}'",
+                content_encoding
+            ))
+        }
+    };
+    let mut decoder = StreamDecoder::new(compression);
+    let mut decoded_body = decoder.decode(body)?.into_owned();
+    decoded_body.extend(decoder.finish()?);
+    Ok(decoded_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use reqwest::header::HeaderValue;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_patch_compression_from_file_name() {
+        assert_eq!(
+            PatchCompression::from_file_name("patch.thor"),
+            PatchCompression::None
+        );
+        assert_eq!(
+            PatchCompression::from_file_name("patch.thor.gz"),
+            PatchCompression::Gzip
+        );
+        assert_eq!(
+            PatchCompression::from_file_name("PATCH.THOR.GZ"),
+            PatchCompression::Gzip
+        );
+    }
+
+    #[test]
+    fn test_stream_decoder_gzip() {
+        let data = b"ASSF (C) 2007 Aeomin DEV".repeat(1000);
+        let compressed_data = gzip(&data);
+        let mut decoder = StreamDecoder::new(PatchCompression::Gzip);
+        let mut decoded_data = Vec::new();
+        for chunk in compressed_data.chunks(100) {
+            decoded_data.extend_from_slice(&decoder.decode(chunk).unwrap());
+        }
+        decoded_data.extend(decoder.finish().unwrap());
+        assert_eq!(decoded_data, data);
+
+        // Truncated data
+        let mut decoder = StreamDecoder::new(PatchCompression::Gzip);
+        decoder
+            .decode(&compressed_data[..compressed_data.len() / 2])
+            .unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_decode_response_body() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            decode_response_body(&headers, b"1 a.thor").unwrap(),
+            b"1 a.thor"
+        );
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(
+            decode_response_body(&headers, &gzip(b"1 a.thor")).unwrap(),
+            b"1 a.thor"
+        );
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(decode_response_body(&headers, b"1 a.thor").is_err());
+    }
+}
@@ -0,0 +1,3034 @@
+use std::convert::TryFrom;
+use std::env;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use advisory_lock::FileLockMode;
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use futures::executor::block_on;
+use futures::stream::{StreamExt, TryStreamExt};
+use gruf::thor::{self, ThorArchive, ThorPatchInfo, ThorPatchList};
+use gruf::{DecompressionLimits, GrufError};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_LENGTH};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use url::Url;
+
+use super::backup::{
+    backup_replaced_files, restore_backups, BACKUP_DIRECTORY_NAME, DEFAULT_BACKUP_MAX_SIZE_MB,
+};
+use super::cache::{read_cache_file, remove_cache_file, PatcherCacheFile};
+use super::cancellation::{
+    process_incoming_commands, wait_for_cancellation, wait_for_close_processes_reply,
+    wait_for_patch_failure_action, CommandReceiver, InterruptibleFnError, InterruptibleFnResult,
+};
+use super::compression::{self, PatchCompression, StreamDecoder};
+use super::config::{
+    DownloadOrder, DuplicateEntryPolicy, ErrorPolicy, GrfVerification, PatchServerInfo, PluginEvent,
+};
+use super::elevation::share_with_unelevated_user;
+use super::hooks::run_hooks;
+use super::http::{build_http_client, stall_timeout, PatchServerClient, PatchServerHeaders};
+use super::journal::{remove_journal, InstallationJournal};
+use super::lan_source::LanSource;
+use super::manifest::{read_manifest, write_manifest, InstallManifest};
+use super::patching::{
+    apply_patch_to_disk, apply_patch_to_disk_resumable, apply_patch_to_grf, check_entry_paths,
+    defragment_grf, recover_interrupted_grf_patch, register_grf_in_data_ini, FileLocked,
+    GrfPatchingMethod, GrfWriteOptions,
+};
+use super::plugins::dispatch_plugin_event_async;
+use super::preview::{preview_patch_file, PatchPreview};
+use super::process::{close_processes, find_processes};
+use super::progress::{PatchingStatus, ProgressSink};
+use super::quiet_hours::QuietHours;
+use super::recheck::{count_new_patches, CachedPatchList, PatchListWatcher};
+use super::requirements::{check_patch_requirements, PATCHER_VERSION};
+use super::restore_point;
+use super::stats::{SessionStage, SessionStats};
+use super::torrent::download_with_torrent;
+use super::{
+    get_executable_name, get_patcher_name, PatchFailureAction, PatcherCommand, PatcherConfiguration,
+};
+
+/// Name of the patch list's cache, in the installation journal's download
+/// directory.
+const PATCH_LIST_CACHE_FILE_NAME: &str = "plist.json";
+
+/// Representation of a pending patch (a patch that's been downloaded but has
+/// not been applied yet).
+#[derive(Debug)]
+struct PendingPatch {
+    info: thor::ThorPatchInfo,
+    patch_url: Url, // Where the patch has been downloaded from
+    local_file_path: PathBuf,
+}
+
+/// Entry point of the patching task.
+///
+/// This waits for a `PatcherCommand::Start` command before starting an
+/// interruptible patching task. Updates started during quiet hours are
+/// deferred until the end of the quiet period.
+pub async fn patcher_thread_routine(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: flume::Receiver<PatcherCommand>,
+) {
+    log::trace!("Patching thread started. Waiting for commands ...");
+    let rx = &mut CommandReceiver::new(patcher_thread_rx);
+    let recheck_interval = config
+        .patching
+        .recheck_interval_minutes
+        .filter(|&minutes| minutes > 0)
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    let quiet_hours =
+        match QuietHours::parse(config.patching.quiet_hours.as_deref().unwrap_or_default()) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Ignoring quiet hours: {:#}", e);
+                QuietHours::default()
+            }
+        };
+    let mut patch_list_watcher = PatchListWatcher::default();
+    let mut update_deferred = false;
+    let mut prefetch_interruption = None;
+    loop {
+        // Wake up when the quiet period ends to start the deferred update
+        let deferral_delay = if update_deferred {
+            Some(
+                quiet_hours
+                    .time_until_end(Local::now().time())
+                    .unwrap_or_default(),
+            )
+        } else {
+            None
+        };
+        let timeout = recheck_interval.into_iter().chain(deferral_delay).min();
+        let cmd = match (prefetch_interruption.take(), timeout) {
+            // Commands that interrupted a prefetch are handled first
+            (Some(cmd), _) => Ok(cmd),
+            (None, None) => rx.recv_async().await,
+            (None, Some(timeout)) => match tokio::time::timeout(timeout, rx.recv_async()).await {
+                Ok(cmd) => cmd,
+                Err(_) => {
+                    if quiet_hours
+                        .end_of_quiet_period(Local::now().time())
+                        .is_some()
+                    {
+                        log::trace!("Quiet hours, deferring check for new patches");
+                    } else if update_deferred {
+                        update_deferred = false;
+                        let _ = update_game(progress_sink, config, rx).await;
+                        if rx.is_quit_requested() {
+                            break;
+                        }
+                    } else {
+                        check_for_new_patches(progress_sink, config, &mut patch_list_watcher).await;
+                    }
+                    continue;
+                }
+            },
+        };
+        // Commands sent while idle (e.g., by the control endpoint) mark the
+        // patcher as busy until they've been handled, even if they fail
+        // before patching actually starts
+        let _idle_guard = scopeguard::guard((), |_| progress_sink.set_patch_in_progress(false));
+        match cmd {
+            Err(e) => {
+                log::error!("Failed to read from channel: {}", e);
+                return;
+            }
+            Ok(cmd) => match cmd {
+                PatcherCommand::Quit => break,
+                PatcherCommand::StartUpdate => {
+                    if let Some(end_time) = quiet_hours.end_of_quiet_period(Local::now().time()) {
+                        let resume_time = end_time.format("%H:%M").to_string();
+                        log::info!("Quiet hours, deferring update until {}", resume_time);
+                        update_deferred = true;
+                        if let Err(e) = progress_sink.dispatch_update_deferred(&resume_time) {
+                            log::warn!("Failed to dispatch update deferral: {}", e);
+                        }
+                        continue;
+                    }
+                    let _ = update_game(progress_sink, config, rx).await;
+                    // The update might have been interrupted by a `Quit` command
+                    if rx.is_quit_requested() {
+                        break;
+                    }
+                }
+                PatcherCommand::ForceUpdate => {
+                    update_deferred = false;
+                    let _ = update_game(progress_sink, config, rx).await;
+                    // The update might have been interrupted by a `Quit` command
+                    if rx.is_quit_requested() {
+                        break;
+                    }
+                }
+                PatcherCommand::ApplyPatch(patch_file_path) => {
+                    apply_single_patch(patch_file_path, progress_sink, config, rx).await;
+                }
+                PatcherCommand::ApplyRemotePatch(patch_url) => {
+                    apply_remote_patch(patch_url, progress_sink, config, rx).await;
+                }
+                PatcherCommand::CreateRestorePoint => {
+                    create_restore_point(progress_sink, config).await;
+                }
+                PatcherCommand::RestoreFromPoint => {
+                    restore_game_from_point(progress_sink, config).await;
+                }
+                PatcherCommand::RestoreBackups => {
+                    restore_replaced_files(progress_sink, config);
+                }
+                PatcherCommand::SkipPatch(patch_index) => {
+                    skip_patch(patch_index, config).await;
+                }
+                PatcherCommand::DefragmentGrfs => {
+                    defragment_grfs(progress_sink, config).await;
+                }
+                PatcherCommand::PrefetchPatches => {
+                    prefetch_interruption = prefetch_patches(progress_sink, config, rx).await;
+                }
+                PatcherCommand::RepairGameFiles => {
+                    repair_game_files(progress_sink, config, rx).await;
+                    if rx.is_quit_requested() {
+                        break;
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+    log::trace!("Patching thread stopped");
+}
+
+/// Checks whether new patches have been published since the last check and
+/// notifies the UI if so.
+async fn check_for_new_patches(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patch_list_watcher: &mut PatchListWatcher,
+) {
+    let patch_list = match patch_list_watcher.fetch_if_changed(&config.web).await {
+        Err(err) => {
+            log::warn!("Failed to check for new patches: {:#}", err);
+            return;
+        }
+        Ok(None) => return,
+        Ok(Some(v)) => v,
+    };
+    let last_patch_index = match get_cache_file_path(config) {
+        Err(_) => None,
+        Ok(cache_file_path) => read_cache_file(cache_file_path)
+            .await
+            .ok()
+            .and_then(|cache| cache.last_patch_index),
+    };
+    let new_patch_count = count_new_patches(&patch_list, last_patch_index);
+    log::info!("{} new patch(es) available", new_patch_count);
+    if let Err(e) = progress_sink.dispatch_updates_available(new_patch_count) {
+        log::warn!("Failed to dispatch available updates: {}", e);
+    }
+}
+
+/// Downloads the pending patches that are at most
+/// `patching.prefetch_max_patch_size_mb`, so that the next update has less to
+/// download.
+///
+/// Prefetched patches are kept in the installation journal. The UI is only
+/// told about the downloaded bytes. Any command received in the meantime
+/// abandons the prefetch and is returned, to be handled by the patching thread.
+async fn prefetch_patches(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) -> Option<PatcherCommand> {
+    tokio::select! {
+        cmd = patcher_thread_rx.recv_async() => {
+            log::debug!("Prefetch interrupted");
+            cmd.ok()
+        }
+        res = prefetch_patches_inner(progress_sink, config) => {
+            match res {
+                Err(err) => log::warn!("Failed to prefetch patches: {:#}", err),
+                Ok(PendingDownloads { downloaded: 0, .. }) => log::debug!("No patches to prefetch"),
+                Ok(pending_downloads) => {
+                    log::info!("Prefetched {} patch(es)", pending_downloads.downloaded)
+                }
+            }
+            None
+        }
+    }
+}
+
+async fn prefetch_patches_inner(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+) -> Result<PendingDownloads> {
+    let max_patch_size = match config.patching.prefetch_max_patch_size_mb {
+        Some(v) => v.saturating_mul(1024 * 1024),
+        None => return Ok(PendingDownloads::default()),
+    };
+    // Without the journal, prefetched patches couldn't be reused
+    if !config.patching.resumable_updates.unwrap_or(true) {
+        return Ok(PendingDownloads::default());
+    }
+    download_pending_patches(progress_sink, config, Some(max_patch_size)).await
+}
+
+/// Downloads the patches that the next update would install (at most
+/// `max_patch_size` bytes each, if given) into the installation journal's
+/// download directory, without installing them. `progress_sink` is only told
+/// about the downloaded bytes.
+///
+/// Fails without `patching.resumable_updates`, since the next update couldn't
+/// use the downloaded patches.
+pub async fn download_pending_patches(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    max_patch_size: Option<u64>,
+) -> Result<PendingDownloads> {
+    if !config.patching.resumable_updates.unwrap_or(true) {
+        return Err(anyhow!(
+            "Patches can only be downloaded ahead of time with 'resumable_updates'"
+        ));
+    }
+    // Updates started by other instances take precedence
+    let lock_file = take_update_lock().with_context(|| "Failed to take the update lock")?;
+    let _guard = scopeguard::guard((), |_| {
+        let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+    });
+
+    let client = PatchServerClient::new(&config.web)?;
+    let _bandwidth_guard = report_downloaded_bytes(&client, progress_sink);
+    // Interruptions are handled by the caller
+    let (_idle_tx, idle_rx) = flume::unbounded();
+    let mut idle_rx = CommandReceiver::new(idle_rx);
+    let (mut patch_list, patch_url) = find_available_patch_server(
+        &client,
+        config.web.patch_servers.as_slice(),
+        &config.web.preferred_patch_server,
+        &mut idle_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => anyhow!(msg),
+        InterruptibleFnError::Interrupted => anyhow!("Download interrupted"),
+    })?;
+    let last_patch_index = read_cache_file(get_cache_file_path(config)?)
+        .await
+        .ok()
+        .and_then(|cache| cache.last_patch_index);
+    if let Some(last_patch_index) = last_patch_index {
+        if patch_list.iter().any(|x| x.index == last_patch_index) {
+            patch_list.retain(|x| x.index > last_patch_index);
+        }
+    }
+    let skip_indices = config.patching.skip_indices.as_deref().unwrap_or_default();
+    let journal = open_installation_journal()?;
+    patch_list.retain(|x| !skip_indices.contains(&x.index));
+    let patch_count = patch_list.len();
+    patch_list.retain(|x| journal.downloaded_patch(x).is_none());
+    let previously_downloaded = patch_count - patch_list.len();
+
+    if let Some(max_patch_size) = max_patch_size {
+        // Patches whose size is unknown are left out
+        let mut small_patch_list = vec![];
+        for patch_info in patch_list {
+            let patch_file_url = patch_url.join(patch_info.file_name.as_str())?;
+            match fetch_patch_size(&client, patch_file_url).await {
+                Some(size) if size <= max_patch_size => small_patch_list.push(patch_info),
+                _ => {}
+            }
+        }
+        patch_list = small_patch_list;
+    }
+    let downloaded_patches = download_patches_concurrent_inner(
+        &client,
+        patch_url,
+        patch_list,
+        config.patching.check_integrity,
+        &journal,
+        &SilentProgressSink,
+    )
+    .await?;
+    Ok(PendingDownloads {
+        downloaded: downloaded_patches.len(),
+        previously_downloaded,
+    })
+}
+
+/// Indicates whether one of the game's executables (`play.path`,
+/// `play.clients` and `patching.close_client_processes`) is running.
+pub fn is_game_client_running(config: &PatcherConfiguration) -> Result<bool> {
+    let executable_names: Vec<String> = std::iter::once(config.play.path.as_str())
+        .chain(
+            config
+                .play
+                .clients
+                .iter()
+                .flatten()
+                .map(|client| client.path.as_str()),
+        )
+        .filter_map(|path| Path::new(path).file_name())
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .chain(
+            config
+                .patching
+                .close_client_processes
+                .iter()
+                .flatten()
+                .cloned(),
+        )
+        .collect();
+    let processes =
+        find_processes(&executable_names).with_context(|| "Failed to list running processes")?;
+    Ok(!processes.is_empty())
+}
+
+/// `ProgressSink` that dispatches nothing, for background tasks.
+struct SilentProgressSink;
+
+impl ProgressSink for SilentProgressSink {
+    fn dispatch_patching_status(&self, _status: PatchingStatus) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Patches that the next update would install, as left in the download cache
+/// by `download_pending_patches`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingDownloads {
+    pub downloaded: usize,            // Downloaded by this call
+    pub previously_downloaded: usize, // Downloaded earlier, still waiting to be installed
+}
+
+/// Result of an update that went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    UpToDate, // There was nothing to patch
+    Patched,  // At least one patch has been applied
+}
+
+/// Error that made an update fail, classified by the stage it occurred at.
+#[derive(Debug)]
+pub enum UpdateError {
+    Canceled,                    // Canceled by the user
+    Network(anyhow::Error),      // Patch servers unreachable or download failed
+    Installation(anyhow::Error), // Patches could not be applied
+    Other(anyhow::Error),
+}
+
+impl std::error::Error for UpdateError {}
+
+impl std::fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateError::Canceled => write!(f, "Patching was canceled"),
+            UpdateError::Network(e) | UpdateError::Installation(e) | UpdateError::Other(e) => {
+                write!(f, "{:#}", e)
+            }
+        }
+    }
+}
+
+/// Starts the automatic update process (download + patching)
+pub async fn update_game(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) -> std::result::Result<UpdateOutcome, UpdateError> {
+    // Try taking the update lock
+    match take_update_lock().with_context(|| "Failed to take the update lock") {
+        Err(err) => {
+            log::error!("{:#}", err);
+            if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err))) {
+                log::warn!("Failed to update error status: {}", e);
+            }
+            Err(UpdateError::Other(err))
+        }
+        Ok(lock_file) => {
+            // Tell the UI and other processes that we're currently working
+            progress_sink.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+                progress_sink.set_patch_in_progress(false);
+            });
+
+            let res = interruptible_update_routine(progress_sink, config, patcher_thread_rx).await;
+            match &res {
+                Err(err) => {
+                    log::error!("{}", err);
+                    if let Err(e) = progress_sink
+                        .dispatch_patching_status(PatchingStatus::Error(err.to_string())) {
+                        log::warn!("Failed to update error status: {}", e);
+                    }
+                }
+                Ok(_) => {
+                    if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::Ready) {
+                        log::warn!("Failed to update ready status: {}", e);
+                    }
+                    log::info!("Patching finished!");
+                }
+            }
+            progress_sink.patching_finished(&res);
+            res
+        }
+    }
+}
+
+/// Applies a manual patch given by the user
+async fn apply_single_patch(
+    patch_file_path: impl AsRef<Path>,
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) {
+    // Try taking the update lock
+    match take_update_lock().with_context(|| "Failed to take the update lock") {
+        Err(err) => {
+            log::error!("{:#}", err);
+            if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err))) {
+                log::warn!("Failed to update error status: {}", e);
+            }
+        }
+        Ok(lock_file) => {
+            // Tell the UI and other processes that we're currently working
+            progress_sink.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+                progress_sink.set_patch_in_progress(false);
+            });
+
+            let current_working_dir =
+                env::current_dir().with_context(|| "Failed to resolve current working directory");
+            match current_working_dir {
+                Err(err) => {
+                    log::error!("{:#}", err);
+                    if let Err(e) = progress_sink
+                        .dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err))) {
+                        log::warn!("Failed to update error status: {}", e);
+                    }
+                }
+                Ok(current_working_dir) => {
+                    let patch_file_name = patch_file_path
+                        .as_ref()
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_str()
+                        .unwrap_or_default()
+                        .to_string();
+                    log::info!("Applying patch '{}'", patch_file_name);
+                    if let Err(e) = progress_sink
+                        .dispatch_patching_status(PatchingStatus::InstallationInProgress(0, 1))
+                    {
+                        log::warn!("Failed to update patching status: {}", e);
+                    }
+                    let res = close_client_processes(config, progress_sink, patcher_thread_rx)
+                        .await
+                        .and_then(|_| {
+                            apply_patch(
+                                patch_file_path,
+                                config,
+                                current_working_dir,
+                                None,
+                                None,
+                                progress_sink,
+                            )
+                            .map_err(UpdateError::Installation)
+                        })
+                        .map(|_| UpdateOutcome::Patched);
+                    match &res {
+                        Err(err) => {
+                            log::error!("{}", err);
+                            if let Err(e) = progress_sink
+                                .dispatch_patching_status(PatchingStatus::Error(err.to_string())) {
+                                log::warn!("Failed to update error status: {}", e);
+                            }
+                        }
+                        Ok(_) => {
+                            log::info!("Done");
+                            if let Err(e) = progress_sink.dispatch_patching_status(
+                                PatchingStatus::ManualPatchApplied(patch_file_name),
+                            ) {
+                                log::warn!("Failed to update patch status: {}", e);
+                            }
+                        }
+                    }
+                    progress_sink.patching_finished(&res);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the client used to download remote patches, which are only
+/// fetched from their URL (i.e., without URL signing, peers or LAN sources).
+fn remote_patch_client(config: &PatcherConfiguration) -> Result<PatchServerClient> {
+    Ok(PatchServerClient {
+        http_client: build_http_client(&config.web)?,
+        server_headers: PatchServerHeaders::from_config(&config.web)?,
+        url_signer: None,
+        torrent_config: None,
+        lan_source: None,
+        stats: SessionStats::new(),
+        stall_timeout: stall_timeout(&config.web),
+    })
+}
+
+/// Downloads a single patch from a patch server and applies it like a manual
+/// patch.
+async fn apply_remote_patch(
+    patch_url: Url,
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) {
+    let tmp_dir = match create_temp_directory(config) {
+        Err(err) => {
+            log::error!("{:#}", err);
+            return;
+        }
+        Ok(v) => v,
+    };
+    let download_res = match remote_patch_client(config) {
+        Err(err) => Err(err),
+        Ok(client) => {
+            let _bandwidth_guard = report_downloaded_bytes(&client, progress_sink);
+            download_remote_patch(&client, &patch_url, tmp_dir.path()).await
+        }
+    };
+    match download_res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            if let Err(e) =
+                progress_sink.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)))
+            {
+                log::warn!("Failed to update error status: {}", e);
+            }
+        }
+        Ok(patch_file_path) => {
+            apply_single_patch(patch_file_path, progress_sink, config, patcher_thread_rx).await
+        }
+    }
+}
+
+async fn download_remote_patch(
+    client: &PatchServerClient,
+    patch_url: &Url,
+    download_dir: &Path,
+) -> Result<PathBuf> {
+    let file_name = patch_url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|file_name| !file_name.is_empty())
+        .with_context(|| format!("Invalid patch URL '{}'", patch_url))?;
+    let patch_info = ThorPatchInfo {
+        index: 0,
+        file_name: file_name.to_string(),
+        requires_index: None,
+        requires_patcher: None,
+        magnet_link: None,
+    };
+    let local_file_path = download_dir.join(file_name);
+    let mut patch_file = File::create(&local_file_path)
+        .await
+        .with_context(|| format!("Failed to create file '{}'", file_name))?;
+    log::info!("Downloading patch '{}'", patch_url);
+    download_patch_to_file(
+        client,
+        patch_url,
+        &patch_info,
+        &mut patch_file,
+        downloaded_bytes_counter(client),
+    )
+    .await?;
+    Ok(local_file_path)
+}
+
+/// Creates a restore point, from which GRFs can be restored if an update goes
+/// wrong.
+async fn create_restore_point(progress_sink: &dyn ProgressSink, config: &PatcherConfiguration) {
+    let res = async {
+        let last_patch_index = read_cache_file(get_cache_file_path(config)?)
+            .await
+            .ok()
+            .and_then(|cache| cache.last_patch_index);
+        restore_point::create_restore_point(&get_restore_point_directory_path()?, last_patch_index)
+    }
+    .await
+    .with_context(|| "Failed to create restore point");
+    match res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            if let Err(e) =
+                progress_sink.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)))
+            {
+                log::warn!("Failed to update error status: {}", e);
+            }
+        }
+        Ok(()) => log::info!("Restore point created"),
+    }
+}
+
+/// Makes the next updates skip the patch at `patch_index`, until the patch is
+/// removed from the patch list.
+async fn skip_patch(patch_index: usize, config: &PatcherConfiguration) {
+    let res = async {
+        let mut patcher_cache = PatcherCacheFile::open(get_cache_file_path(config)?).await?;
+        if !patcher_cache.cache.skip_requests.contains(&patch_index) {
+            patcher_cache.cache.skip_requests.push(patch_index);
+        }
+        patcher_cache.save().await
+    }
+    .await;
+    match res {
+        Err(e) => log::error!("Failed to skip patch {}: {:#}", patch_index, e),
+        Ok(()) => log::info!("Patch {} will be skipped", patch_index),
+    }
+}
+
+/// Restores GRFs to the state they were in when the restore point was
+/// created.
+async fn restore_game_from_point(progress_sink: &dyn ProgressSink, config: &PatcherConfiguration) {
+    let res = restore_game_from_point_inner(progress_sink, config)
+        .await
+        .with_context(|| "Failed to restore from restore point");
+    let status = match res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            PatchingStatus::Error(format!("{:#}", err))
+        }
+        Ok(()) => {
+            log::info!("Game restored from restore point");
+            PatchingStatus::Ready
+        }
+    };
+    if let Err(e) = progress_sink.dispatch_patching_status(status) {
+        log::warn!("Failed to update patching status: {}", e);
+    }
+}
+
+async fn restore_game_from_point_inner(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+) -> Result<()> {
+    let lock_file = take_update_lock().with_context(|| "Failed to take the update lock")?;
+    progress_sink.set_patch_in_progress(true);
+    let _guard = scopeguard::guard((), |_| {
+        let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+        progress_sink.set_patch_in_progress(false);
+    });
+
+    let last_patch_index = restore_point::restore_from_point(
+        &get_restore_point_directory_path()?,
+        &env::current_dir()?,
+    )?;
+    // Patches applied since then must be applied again
+    let cache_file_path = get_cache_file_path(config)?;
+    let mut patcher_cache = match PatcherCacheFile::open(cache_file_path.clone()).await {
+        Ok(v) => v,
+        // The restore point tells which patches have been applied
+        Err(_) => PatcherCacheFile::empty(cache_file_path),
+    };
+    match last_patch_index {
+        Some(last_patch_index) => {
+            patcher_cache.cache.last_patch_index = Some(last_patch_index);
+            patcher_cache
+                .cache
+                .skipped_patch_indices
+                .retain(|&x| x <= last_patch_index);
+        }
+        None => patcher_cache.cache.forget_applied_patches(),
+    }
+    patcher_cache.save().await
+}
+
+/// Puts back the files that patches replaced or removed on disk, from the
+/// backups made with `patching.backup_replaced_files`.
+fn restore_replaced_files(progress_sink: &dyn ProgressSink, config: &PatcherConfiguration) {
+    let res = restore_replaced_files_inner(progress_sink, config)
+        .with_context(|| "Failed to restore backups");
+    let status = match res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            PatchingStatus::Error(format!("{:#}", err))
+        }
+        Ok(restored_file_count) => {
+            log::info!("Restored {} file(s) from backups", restored_file_count);
+            PatchingStatus::Ready
+        }
+    };
+    if let Err(e) = progress_sink.dispatch_patching_status(status) {
+        log::warn!("Failed to update patching status: {}", e);
+    }
+}
+
+fn restore_replaced_files_inner(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+) -> Result<usize> {
+    let lock_file = take_update_lock().with_context(|| "Failed to take the update lock")?;
+    progress_sink.set_patch_in_progress(true);
+    let _guard = scopeguard::guard((), |_| {
+        let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+        progress_sink.set_patch_in_progress(false);
+    });
+
+    let current_working_dir = env::current_dir()?;
+    restore_backups(
+        &current_working_dir.join(BACKUP_DIRECTORY_NAME),
+        &get_disk_root_directory(config, &current_working_dir),
+    )
+}
+
+/// Rebuilds the GRFs that removed entries left unused space in, as recorded in
+/// the patcher cache.
+async fn defragment_grfs(progress_sink: &dyn ProgressSink, config: &PatcherConfiguration) {
+    let res = defragment_grfs_inner(progress_sink, config)
+        .await
+        .with_context(|| "Failed to defragment GRFs");
+    let status = match res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            PatchingStatus::Error(format!("{:#}", err))
+        }
+        Ok(reclaimed_space) => {
+            log::info!("Reclaimed {} byte(s)", reclaimed_space);
+            PatchingStatus::Ready
+        }
+    };
+    if let Err(e) = progress_sink.dispatch_patching_status(status) {
+        log::warn!("Failed to update patching status: {}", e);
+    }
+}
+
+async fn defragment_grfs_inner(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+) -> Result<u64> {
+    let lock_file = take_update_lock().with_context(|| "Failed to take the update lock")?;
+    progress_sink.set_patch_in_progress(true);
+    let _guard = scopeguard::guard((), |_| {
+        let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+        progress_sink.set_patch_in_progress(false);
+    });
+
+    let current_working_dir = env::current_dir()?;
+    let mut patcher_cache = PatcherCacheFile::open(get_cache_file_path(config)?).await?;
+    let grf_names: Vec<String> = patcher_cache
+        .cache
+        .grf_unused_space
+        .keys()
+        .cloned()
+        .collect();
+    let temp_directory = get_temp_directory_path(config);
+    let mut reclaimed_space = 0;
+    for grf_name in grf_names {
+        let grf_file_path = current_working_dir.join(&grf_name);
+        // GRFs that don't exist anymore are forgotten
+        if let Ok(metadata) = std::fs::metadata(&grf_file_path) {
+            log::info!("Defragmenting '{}'", grf_name);
+            defragment_grf(
+                &grf_write_options(config, temp_directory.as_deref()),
+                &grf_file_path,
+                grf_verification(config),
+                entry_progress_reporter(progress_sink, None),
+            )
+            .with_context(|| format!("Failed to defragment '{}'", grf_name))?;
+            let defragmented_size = std::fs::metadata(&grf_file_path)?.len();
+            reclaimed_space += metadata.len().saturating_sub(defragmented_size);
+        }
+        patcher_cache.cache.record_grf_unused_space(&grf_name, 0);
+        patcher_cache.save().await?;
+    }
+    Ok(reclaimed_space)
+}
+
+/// Verifies the files installed by patches against the install manifest, then
+/// installs the damaged ones again by applying the patches from the oldest one
+/// that installed one of them on.
+///
+/// All patches are applied again for games patched without a manifest (i.e.,
+/// by older versions).
+async fn repair_game_files(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) {
+    let res = prepare_game_files_repair(progress_sink, config)
+        .await
+        .with_context(|| "Failed to verify the game's files");
+    match res {
+        Err(err) => {
+            log::error!("{:#}", err);
+            if let Err(e) =
+                progress_sink.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)))
+            {
+                log::warn!("Failed to update patching status: {}", e);
+            }
+        }
+        Ok(false) => {
+            log::info!("No damaged files");
+            if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::Ready) {
+                log::warn!("Failed to update patching status: {}", e);
+            }
+        }
+        Ok(true) => {
+            let _ = update_game(progress_sink, config, patcher_thread_rx).await;
+        }
+    }
+}
+
+/// Looks for damaged files and rewinds the patcher cache so that the next
+/// update installs them again. Returns `false` if there's nothing to repair.
+async fn prepare_game_files_repair(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+) -> Result<bool> {
+    let lock_file = take_update_lock().with_context(|| "Failed to take the update lock")?;
+    progress_sink.set_patch_in_progress(true);
+    let _guard = scopeguard::guard((), |_| {
+        let _ = advisory_lock::AdvisoryFileLock::unlock(&lock_file);
+        progress_sink.set_patch_in_progress(false);
+    });
+
+    let current_working_dir = env::current_dir()?;
+    let manifest = read_manifest(&get_manifest_file_path()?)?;
+    let mut patcher_cache = PatcherCacheFile::open(get_cache_file_path(config)?).await?;
+    if manifest.is_empty() {
+        log::info!("No install manifest, applying all patches again");
+        patcher_cache.cache.forget_applied_patches();
+        patcher_cache.save().await?;
+        return Ok(true);
+    }
+    let mut report_progress = entry_progress_reporter(progress_sink, None);
+    let damaged_files = manifest.find_damaged_files(
+        &current_working_dir,
+        &get_disk_root_directory(config, &current_working_dir),
+        |nb_checked, nb_total| report_progress(nb_checked, nb_total, 0),
+    )?;
+    for damaged_file in &damaged_files {
+        let location = damaged_file.grf_name.as_deref().unwrap_or("disk");
+        match damaged_file.patch_index {
+            // Manual patches can't be applied again
+            None => log::warn!(
+                "'{}' ({}) is damaged, but was installed by a manual patch",
+                damaged_file.path,
+                location
+            ),
+            Some(_) => log::warn!("'{}' ({}) is damaged", damaged_file.path, location),
+        }
+    }
+    let oldest_patch_index = match damaged_files.iter().filter_map(|f| f.patch_index).min() {
+        None if damaged_files.is_empty() => return Ok(false),
+        None => {
+            return Err(anyhow!(
+                "{} file(s) installed by manual patches are damaged",
+                damaged_files.len()
+            ))
+        }
+        Some(v) => v,
+    };
+    log::info!(
+        "{} damaged file(s), applying patches again from index {}",
+        damaged_files.len(),
+        oldest_patch_index
+    );
+    patcher_cache.cache.forget_patches_from(oldest_patch_index);
+    patcher_cache.save().await?;
+    Ok(true)
+}
+
+/// Closes the processes listed in `patching.close_client_processes` that are
+/// currently running, after asking the user for confirmation.
+///
+/// Patches are installed anyway if the user refuses.
+async fn close_client_processes(
+    config: &PatcherConfiguration,
+    progress_sink: &dyn ProgressSink,
+    patcher_thread_rx: &mut CommandReceiver,
+) -> std::result::Result<(), UpdateError> {
+    const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+    const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+    let process_names = match &config.patching.close_client_processes {
+        Some(v) if !v.is_empty() => v,
+        _ => return Ok(()),
+    };
+    let processes = find_processes(process_names)
+        .with_context(|| "Failed to list running processes")
+        .map_err(UpdateError::Installation)?;
+    if processes.is_empty() {
+        return Ok(());
+    }
+    let running_process_names: Vec<String> = processes.iter().map(|p| p.name.clone()).collect();
+    let close = if progress_sink.prompt_close_processes(&running_process_names) {
+        wait_for_close_processes_reply(patcher_thread_rx, PROMPT_TIMEOUT)
+            .await
+            .map_err(|e| match e {
+                InterruptibleFnError::Err(msg) => UpdateError::Other(anyhow!(msg)),
+                InterruptibleFnError::Interrupted => UpdateError::Canceled,
+            })?
+    } else {
+        progress_sink.confirm_close_processes(&running_process_names)
+    };
+    if !close {
+        log::warn!("Installing patches while the game client is running");
+        return Ok(());
+    }
+    close_processes(&processes, CLOSE_TIMEOUT)
+        .with_context(|| "Failed to close the game client")
+        .map_err(UpdateError::Installation)
+}
+
+/// Takes an advisory lock that prevents multiple instances of the patcher to
+/// update the game at the same time
+fn take_update_lock() -> Result<std::fs::File> {
+    let lock_file_name = get_update_lock_file_path()?;
+    let lock_file = std::fs::File::create(&lock_file_name)?;
+    share_with_unelevated_user(lock_file_name);
+    advisory_lock::AdvisoryFileLock::try_lock(&lock_file, FileLockMode::Exclusive)?;
+
+    Ok(lock_file)
+}
+
+/// Main routine of the patching task.
+///
+/// This routine is written in a way that makes it interuptible (or cancellable)
+/// with a relatively low latency.
+async fn interruptible_update_routine(
+    progress_sink: &dyn ProgressSink,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut CommandReceiver,
+) -> std::result::Result<UpdateOutcome, UpdateError> {
+    log::info!("Start patching");
+    // Shared by all requests made during the update
+    let client = PatchServerClient::new(&config.web).map_err(UpdateError::Other)?;
+    let _bandwidth_guard = report_downloaded_bytes(&client, progress_sink);
+
+    // Find a patch server that we can connect to
+    log::info!("Looking for an available patch server ...");
+    client.stats.start_stage(SessionStage::Lookup);
+    let (mut patch_list, patch_data_url) = find_available_patch_server(
+        &client,
+        config.web.patch_servers.as_slice(),
+        &config.web.preferred_patch_server,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => UpdateError::Network(anyhow!(msg)),
+        InterruptibleFnError::Interrupted => UpdateError::Canceled,
+    })?;
+    log::debug!("Successfully fetched patch list: {:?}", patch_list);
+
+    // Try to read cache
+    let cache_file_path = get_cache_file_path(config)
+        .with_context(|| "Failed to resolve patcher name")
+        .map_err(UpdateError::Other)?;
+    let mut patcher_cache = PatcherCacheFile::open(cache_file_path)
+        .await
+        .map_err(UpdateError::Other)?;
+    let available_patches = patch_list.clone();
+    let last_patch_index = patcher_cache.cache.last_patch_index;
+    // Ignore already applied patches if needed
+    // First we verify that our cached index looks relevant
+    match last_patch_index {
+        Some(last_patch_index) if patch_list.iter().any(|x| x.index == last_patch_index) => {
+            patch_list.retain(|x| x.index > last_patch_index);
+        }
+        _ => patcher_cache.cache.forget_applied_patches(),
+    }
+    let last_patch_index = patcher_cache.cache.last_patch_index;
+    // Leave out known-bad patches, and apply the ones that have been fixed since
+    let (previously_skipped_patches, mut skipped_indices) =
+        patcher_cache.cache.reconcile_skipped_patches(
+            &available_patches,
+            &mut patch_list,
+            config.patching.skip_indices.as_deref().unwrap_or_default(),
+        );
+    // Make sure patches won't be applied out of order or by an outdated patcher
+    check_patch_requirements(&patch_list, last_patch_index, PATCHER_VERSION)
+        .map_err(UpdateError::Other)?;
+    check_patch_requirements(&previously_skipped_patches, None, PATCHER_VERSION)
+        .map_err(UpdateError::Other)?;
+    let mut patch_list: ThorPatchList = previously_skipped_patches
+        .into_iter()
+        .chain(patch_list)
+        .collect();
+    // Patches vetoed by plugins are handled like skipped patches
+    if !patch_list.is_empty() {
+        let actions = dispatch_plugin_event_async(
+            config,
+            PluginEvent::PreDownload,
+            json!({ "patches": patch_list_to_json(&patch_list) }),
+        )
+        .await;
+        patch_list.retain(|x| {
+            let vetoed = actions.vetoed_patches.contains(&x.index);
+            if vetoed {
+                skipped_indices.push(x.index);
+            }
+            !vetoed
+        });
+    }
+    if !skipped_indices.is_empty() {
+        log::warn!("Skipping patches {:?}", skipped_indices);
+        // Remembered right away, even if the update doesn't complete. The
+        // last patch index is only advanced once the other patches are applied
+        patcher_cache.cache.record_skipped_patches(&skipped_indices);
+        if let Err(e) = patcher_cache.save().await {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+    }
+    if patch_list.is_empty() {
+        patcher_cache.cache.mark_patches_skipped(&skipped_indices);
+        if let Err(e) = patcher_cache.save().await {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+        // Nothing left to resume
+        let res = get_journal_file_path().and_then(|journal_file_path| {
+            remove_journal(&journal_file_path, &get_download_directory_path()?)
+        });
+        if let Err(e) = res {
+            log::warn!("Failed to remove the installation journal: {:#}", e);
+        }
+        log::info!("Game is already up to date");
+        return Ok(UpdateOutcome::UpToDate);
+    }
+    let applied_patches = patch_list_to_json(&patch_list);
+
+    // Try fetching patch files
+    log::info!("Downloading patches ...");
+    client.stats.start_stage(SessionStage::Download);
+    let patch_url = Url::parse(patch_data_url.as_str())
+        .with_context(|| "Failed to parse 'patch_url'")
+        .map_err(UpdateError::Other)?;
+    let tmp_dir = create_temp_directory(config).map_err(UpdateError::Other)?;
+    // Downloads and installation progress survive crashes with the journal
+    let journal = match config.patching.resumable_updates.unwrap_or(true) {
+        true => open_installation_journal().map_err(UpdateError::Other)?,
+        false => InstallationJournal::disabled(tmp_dir.path().to_path_buf()),
+    };
+    let download_order = config
+        .patching
+        .download_order
+        .unwrap_or(DownloadOrder::List);
+    let patch_list = order_patch_downloads(&client, &patch_url, patch_list, download_order).await;
+    let pending_patch_queue = download_patches_concurrent(
+        &client,
+        patch_url,
+        patch_list,
+        config.patching.check_integrity,
+        &journal,
+        progress_sink,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => {
+            UpdateError::Network(anyhow!("Failed to download patches: {}", msg))
+        }
+        InterruptibleFnError::Interrupted => UpdateError::Canceled,
+    })?;
+    log::info!("Patches have been downloaded");
+
+    // Make sure the game client doesn't keep GRFs open
+    client.stats.start_stage(SessionStage::Installation);
+    close_client_processes(config, progress_sink, patcher_thread_rx).await?;
+
+    // Let operators prepare the client (e.g., back up configuration files)
+    run_update_hooks(config, UpdateHookStage::PreUpdate, patcher_thread_rx).await?;
+
+    // Proceed with actual patching
+    log::info!("Applying patches ...");
+    apply_patches(
+        &client,
+        pending_patch_queue,
+        config,
+        &mut patcher_cache,
+        &journal,
+        progress_sink,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => {
+            UpdateError::Installation(anyhow!("Failed to apply patches: {}", msg))
+        }
+        InterruptibleFnError::Interrupted => UpdateError::Canceled,
+    })?;
+    log::info!("Patches have been applied");
+    patcher_cache.cache.mark_patches_skipped(&skipped_indices);
+    if let Err(e) = patcher_cache.save().await {
+        log::warn!("Failed to write cache file: {}.", e);
+    }
+    if let Err(e) = journal.remove() {
+        log::warn!("Failed to remove the installation journal: {:#}", e);
+    }
+    run_update_hooks(config, UpdateHookStage::PostUpdate, patcher_thread_rx).await?;
+    dispatch_plugin_event_async(
+        config,
+        PluginEvent::PostInstall,
+        json!({ "patches": applied_patches }),
+    )
+    .await;
+
+    let summary = client.stats.summary();
+    log::info!("{}", summary.describe());
+    if let Err(e) = progress_sink.dispatch_patching_summary(&summary) {
+        log::warn!("Failed to dispatch patching summary: {}", e);
+    }
+    Ok(UpdateOutcome::Patched)
+}
+
+fn patch_list_to_json(patch_list: &[ThorPatchInfo]) -> Value {
+    patch_list
+        .iter()
+        .map(|x| json!({ "index": x.index, "file_name": x.file_name }))
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum UpdateHookStage {
+    PreUpdate,  // Before patches are installed
+    PostUpdate, // Once patches have been installed
+}
+
+/// Runs the commands configured in `hooks` for `stage`.
+///
+/// This function is interruptible.
+async fn run_update_hooks(
+    config: &PatcherConfiguration,
+    stage: UpdateHookStage,
+    patcher_thread_rx: &mut CommandReceiver,
+) -> std::result::Result<(), UpdateError> {
+    let hooks_config = match &config.hooks {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let (commands, stage_name) = match stage {
+        UpdateHookStage::PreUpdate => (&hooks_config.pre_update, "pre-update"),
+        UpdateHookStage::PostUpdate => (&hooks_config.post_update, "post-update"),
+    };
+    let commands = match commands {
+        Some(v) if !v.is_empty() => v,
+        _ => return Ok(()),
+    };
+    log::info!("Running {} hooks ...", stage_name);
+    tokio::select! {
+        cancel_res = wait_for_cancellation(patcher_thread_rx) => match cancel_res {
+            InterruptibleFnError::Err(msg) => Err(UpdateError::Other(anyhow!(msg))),
+            InterruptibleFnError::Interrupted => Err(UpdateError::Canceled),
+        },
+        res = run_hooks(hooks_config, commands) => res
+            .with_context(|| format!("Failed to run {} hooks", stage_name))
+            .map_err(UpdateError::Installation),
+    }
+}
+
+/// Iterates through `server_list` and returns the first available server's info.
+/// `preferred_server_name` is checked first if present.
+async fn find_available_patch_server(
+    client: &PatchServerClient,
+    server_list: &[PatchServerInfo],
+    preferred_server_name: &Option<String>,
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnResult<(ThorPatchList, Url)> {
+    // Probe the preferred server first if it's specified and valid
+    if let Some(preferred_server_name) = preferred_server_name {
+        let preferred_server = server_list
+            .iter()
+            .find(|s| &s.name == preferred_server_name);
+        if let Some(preferred_server) = preferred_server {
+            if let Ok((patch_list, patch_url)) = probe_patch_server(client, preferred_server).await
+            {
+                return Ok((patch_list, patch_url));
+            } else {
+                log::warn!("'{}' is unavailable", preferred_server_name);
+                client.stats.add_retry();
+            }
+        } else {
+            log::warn!(
+                "'{}' isn't in the list of patch servers",
+                preferred_server_name
+            );
+        }
+    }
+
+    // Probe other servers, if any
+    for server in server_list {
+        // Cancel the patching process if we've been asked to or if the other
+        // end of the channel has been disconnected
+        process_incoming_commands(patching_thread_rx)?;
+        if let Ok((patch_list, patch_url)) = probe_patch_server(client, server).await {
+            return Ok((patch_list, patch_url));
+        } else {
+            log::warn!("'{}' is unavailable", server.name);
+            client.stats.add_retry();
+        }
+    }
+
+    Err(InterruptibleFnError::Err(
+        "None of the patch servers are available at the moment".to_string(),
+    ))
+}
+
+/// Checks whether a patch server is up or not.
+/// Returns the list of patches served by the server as well as the URL to
+/// download them from.
+async fn probe_patch_server(
+    client: &PatchServerClient,
+    server_info: &PatchServerInfo,
+) -> Result<(ThorPatchList, Url)> {
+    // Parse URLs
+    let patch_list_url = Url::parse(server_info.plist_url.as_str())
+        .with_context(|| "Failed to parse 'plist_url'")?;
+    let patch_url = Url::parse(server_info.patch_url.as_str())
+        .with_context(|| "Failed to parse 'patch_url'")?;
+
+    // Fetch plist
+    let patch_list = fetch_patch_list(client, patch_list_url)
+        .await
+        .with_context(|| "Failed to retrieve the patch list")?;
+
+    // Ensure that the server serves the patches (check the first patch of the list)
+    if let Some(patch_info) = patch_list.first() {
+        let patch_file_url = patch_url.join(patch_info.file_name.as_str())?;
+        let patch_resp = client
+            .head_patch_file(patch_file_url)
+            .await?
+            .send()
+            .await
+            .with_context(|| "Failed to HEAD URL")?;
+        // Return on error
+        patch_resp.error_for_status()?;
+    }
+
+    Ok((patch_list, patch_url))
+}
+
+/// Downloads and parses a 'plist.txt' file located as the URL contained in the
+/// `patch_list_url` argument.
+///
+/// The patch list is cached in the installation journal's download directory,
+/// along with the prefetched patches, and only downloaded again if it changed.
+///
+/// Returns a vector of `ThorPatchInfo` in case of success.
+async fn fetch_patch_list(
+    client: &PatchServerClient,
+    patch_list_url: Url,
+) -> Result<ThorPatchList> {
+    let cache_file_path = get_download_directory_path()?.join(PATCH_LIST_CACHE_FILE_NAME);
+    fetch_patch_list_cached(client, patch_list_url, &cache_file_path).await
+}
+
+/// Fetches the patch list like `fetch_patch_list`, with the cached patch list
+/// at `cache_file_path`.
+async fn fetch_patch_list_cached(
+    client: &PatchServerClient,
+    patch_list_url: Url,
+    cache_file_path: &Path,
+) -> Result<ThorPatchList> {
+    let cached_patch_list = CachedPatchList::read(cache_file_path, &patch_list_url);
+    let mut request = client
+        .get(patch_list_url.clone())
+        .header(ACCEPT_ENCODING, compression::ACCEPT_ENCODING);
+    if let Some(cached_patch_list) = &cached_patch_list {
+        request = cached_patch_list.apply_validators(request);
+    }
+    let resp = request.send().await.with_context(|| "Failed to GET URL")?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached_patch_list) = &cached_patch_list {
+            log::info!("Patch list unchanged, using the cached one");
+            return cached_patch_list.patch_list();
+        }
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!("Patch list file not found on the remote server"));
+    }
+    let resp_headers = resp.headers().clone();
+    let resp_body = resp
+        .bytes()
+        .await
+        .with_context(|| "Invalid responde body")?;
+    let resp_body = compression::decode_response_body(&resp_headers, &resp_body)
+        .with_context(|| "Invalid responde body")?;
+    let patch_index_content = String::from_utf8_lossy(&resp_body);
+    log::info!("Parsing patch index...");
+
+    let patch_list =
+        thor::patch_list_from_string(&patch_index_content).with_context(|| "Invalid patch list")?;
+    if let Some(cached_patch_list) =
+        CachedPatchList::from_response(&patch_list_url, &resp_headers, &patch_index_content)
+    {
+        if let Err(e) = cached_patch_list.write(cache_file_path) {
+            log::warn!("Failed to cache the patch list: {:#}", e);
+        }
+    }
+    Ok(patch_list)
+}
+
+/// Returns the patcher cache file's name as a `PathBuf` on success.
+///
+/// This is `patching.cache_file` if set, `<patcher name>.dat` otherwise.
+fn get_cache_file_path(config: &PatcherConfiguration) -> Result<PathBuf> {
+    match &config.patching.cache_file {
+        Some(cache_file) => Ok(PathBuf::from(cache_file)),
+        None => get_instance_asset_file_name("dat"),
+    }
+}
+
+/// Removes the patcher cache, so that all patches are applied again during
+/// the next update.
+pub fn reset_patcher_cache(config: &PatcherConfiguration) -> Result<()> {
+    remove_cache_file(get_cache_file_path(config)?)
+}
+
+/// Removes the files the patching engine stores in the game's directory (the
+/// patcher cache, the installation journal, the install manifest, the restore
+/// point and the update lock).
+///
+/// Mustn't be called while patching. Everything is removed even if some
+/// removals fail, failed removals are then reported in the returned error.
+pub fn remove_patcher_data(config: &PatcherConfiguration) -> Result<()> {
+    let mut errors = vec![];
+    let mut run_step = |description: &str, result: Result<()>| {
+        if let Err(e) = result {
+            errors.push(format!("{} ({:#})", description, e));
+        }
+    };
+    run_step("the patcher cache", reset_patcher_cache(config));
+    run_step(
+        "the installation journal",
+        get_journal_file_path().and_then(|journal_file_path| {
+            remove_journal(&journal_file_path, &get_download_directory_path()?)
+        }),
+    );
+    run_step(
+        "the install manifest",
+        get_manifest_file_path().and_then(|manifest_file_path| {
+            match std::fs::remove_file(manifest_file_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }),
+    );
+    run_step(
+        "the restore point",
+        get_restore_point_directory_path().and_then(|restore_point_directory| {
+            match std::fs::remove_dir_all(restore_point_directory) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }),
+    );
+    run_step(
+        "the update lock",
+        get_update_lock_file_path().and_then(|update_lock_file_path| {
+            match std::fs::remove_file(update_lock_file_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }),
+    );
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to remove {}", errors.join(", ")))
+    }
+}
+
+/// Returns the files that hold the patching engine's state, along with their
+/// role (`cache`, `journal` and `restore_point`).
+///
+/// Files may not exist (e.g., if nothing has been patched yet).
+pub fn get_patcher_state_files(
+    config: &PatcherConfiguration,
+) -> Result<Vec<(&'static str, PathBuf)>> {
+    Ok(vec![
+        ("cache", get_cache_file_path(config)?),
+        ("journal", get_journal_file_path()?),
+        (
+            "restore_point",
+            get_restore_point_directory_path()?.join(restore_point::MANIFEST_FILE_NAME),
+        ),
+    ])
+}
+
+/// Downloads the patch published with index `patch_index`, without applying
+/// it, and lists its entries. `progress_sink` is only told about the
+/// downloaded bytes.
+///
+/// The preferred patch server is tried first, if any.
+pub async fn preview_published_patch(
+    patch_index: usize,
+    config: &PatcherConfiguration,
+    progress_sink: &dyn ProgressSink,
+) -> Result<PatchPreview> {
+    let client = PatchServerClient::new(&config.web)?;
+    let _bandwidth_guard = report_downloaded_bytes(&client, progress_sink);
+    let mut server_list: Vec<&PatchServerInfo> = config.web.patch_servers.iter().collect();
+    if let Some(preferred_server_name) = &config.web.preferred_patch_server {
+        server_list.sort_by_key(|s| &s.name != preferred_server_name);
+    }
+    let mut available_server = None;
+    for server in server_list {
+        match probe_patch_server(&client, server).await {
+            Ok(v) => {
+                available_server = Some(v);
+                break;
+            }
+            Err(e) => log::warn!("'{}' is unavailable: {:#}", server.name, e),
+        }
+    }
+    let (patch_list, patch_url) =
+        available_server.context("None of the patch servers are available at the moment")?;
+    let patch_info = patch_list
+        .into_iter()
+        .find(|patch_info| patch_info.index == patch_index)
+        .with_context(|| format!("Patch {} isn't in the patch list", patch_index))?;
+    let patch_file_url = patch_url
+        .join(patch_info.file_name.as_str())
+        .with_context(|| format!("Invalid file name '{}'", patch_info.file_name))?;
+    let tmp_dir = create_temp_directory(config)?;
+    let patch_file_path = download_remote_patch(&client, &patch_file_url, tmp_dir.path()).await?;
+    preview_patch_file(patch_file_path, &config.client.default_grf_name)
+}
+
+/// Returns the directory patches that don't target a GRF are extracted to.
+fn get_disk_root_directory(config: &PatcherConfiguration, current_working_dir: &Path) -> PathBuf {
+    match &config.patching.disk_root {
+        Some(disk_root) => current_working_dir.join(disk_root),
+        None => current_working_dir.to_path_buf(),
+    }
+}
+
+/// Returns `patching.temp_dir`, relative to the client's directory, if set.
+fn get_temp_directory_path(config: &PatcherConfiguration) -> Option<PathBuf> {
+    config.patching.temp_dir.as_ref().map(PathBuf::from)
+}
+
+/// Creates a temporary directory in `patching.temp_dir`, or in the system's
+/// temporary directory if it isn't set.
+fn create_temp_directory(config: &PatcherConfiguration) -> Result<tempfile::TempDir> {
+    let tmp_dir = match get_temp_directory_path(config) {
+        Some(temp_directory) => std::fs::create_dir_all(&temp_directory)
+            .and_then(|_| tempfile::tempdir_in(temp_directory)),
+        None => tempfile::tempdir(),
+    };
+    tmp_dir.with_context(|| "Failed to create temporary directory")
+}
+
+/// Returns the restore point directory's name as a `PathBuf` on success.
+fn get_restore_point_directory_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("restore")
+}
+
+/// Opens the installation journal, which lets updates resume where an
+/// interrupted one stopped.
+fn open_installation_journal() -> Result<InstallationJournal> {
+    InstallationJournal::open(get_journal_file_path()?, get_download_directory_path()?)
+        .with_context(|| "Failed to open the installation journal")
+}
+
+/// Returns the install manifest's name as a `PathBuf` on success.
+fn get_manifest_file_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("manifest")
+}
+
+/// Returns the installation journal's name as a `PathBuf` on success.
+fn get_journal_file_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("journal")
+}
+
+/// Returns the name of the directory patches are downloaded to as a `PathBuf`
+/// on success.
+fn get_download_directory_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("downloads")
+}
+
+/// Returns the patcher update lock file's name as a `PathBuf` on success.
+///
+/// The lock is shared by all profiles, since they patch the same game files.
+fn get_update_lock_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_executable_name()?).with_extension("lock"))
+}
+
+/// Generates asset file names which are associated with the current 'instance'
+/// of the patcher.
+fn get_instance_asset_file_name(extension: impl AsRef<std::ffi::OsStr>) -> Result<PathBuf> {
+    let patcher_name = get_patcher_name()?;
+
+    Ok(PathBuf::from(patcher_name).with_extension(extension))
+}
+
+/// Downloads a list of patches (described with a `ThorPatchList`).
+///
+/// Files are downloaded from the remote directory located at the URL
+/// contained in the 'patch_url' argument.
+///
+/// This function is interruptible.
+async fn download_patches_concurrent(
+    client: &PatchServerClient,
+    patch_url: Url,
+    patch_list: ThorPatchList,
+    ensure_integrity: bool,
+    journal: &InstallationJournal,
+    progress_sink: &dyn ProgressSink,
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnResult<Vec<PendingPatch>> {
+    let patch_count = patch_list.len();
+    if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::DownloadInProgress(0, patch_count, 0)) {
+        log::warn!("Failed to update download status: {}", e);
+    }
+    client.stats.expect_downloads(patch_count);
+    // Download files in a cancelable manner
+    let mut vec = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
+        download_res = download_patches_concurrent_inner(client, patch_url, patch_list, ensure_integrity, journal, progress_sink) => {
+            download_res.map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))
+        },
+        _ = report_stalls(client, progress_sink) => unreachable!(),
+    }?;
+    // Sort patches by index before returning
+    vec.sort_unstable_by_key(|l| l.info.index);
+    Ok(vec)
+}
+
+/// Sorts `patch_list` in the order patches should be downloaded in.
+///
+/// Patch sizes are queried from the patch server, patches whose size is
+/// unknown come last. Patches are installed in the order of the patch list
+/// regardless.
+async fn order_patch_downloads(
+    client: &PatchServerClient,
+    patch_url: &Url,
+    patch_list: ThorPatchList,
+    download_order: DownloadOrder,
+) -> ThorPatchList {
+    const CONCURRENT_REQUESTS: usize = 16;
+    if download_order == DownloadOrder::List {
+        return patch_list;
+    }
+    let sized_patch_list = futures::stream::iter(patch_list.into_iter().map(|patch_info| async {
+        let size = match patch_url.join(patch_info.file_name.as_str()) {
+            Ok(patch_file_url) => fetch_patch_size(client, patch_file_url).await,
+            Err(_) => None,
+        };
+        (patch_info, size)
+    }))
+    .buffered(CONCURRENT_REQUESTS)
+    .collect()
+    .await;
+    sort_by_download_order(sized_patch_list, download_order)
+}
+
+/// Returns the size of the patch file at `patch_file_url` (unsigned), if the
+/// patch server gives it.
+async fn fetch_patch_size(client: &PatchServerClient, patch_file_url: Url) -> Option<u64> {
+    let request = client.head_patch_file(patch_file_url).await.ok()?.send();
+    let resp = with_stall_timeout(client.stall_timeout, request)
+        .await
+        .ok()?
+        .ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    // `content_length` gives the size of the (empty) body of HEAD responses
+    resp.headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn sort_by_download_order(
+    mut sized_patch_list: Vec<(ThorPatchInfo, Option<u64>)>,
+    download_order: DownloadOrder,
+) -> ThorPatchList {
+    // Sorts are stable, patches of the same size stay in the list's order
+    match download_order {
+        DownloadOrder::List => {}
+        DownloadOrder::SmallestFirst => {
+            sized_patch_list.sort_by_key(|(_, size)| size.unwrap_or(u64::MAX))
+        }
+        DownloadOrder::LargestFirst => {
+            sized_patch_list.sort_by_key(|(_, size)| std::cmp::Reverse(size.unwrap_or(0)))
+        }
+    }
+    sized_patch_list
+        .into_iter()
+        .map(|(patch_info, _)| patch_info)
+        .collect()
+}
+
+/// Actual implementation of the concurrent file download
+///
+/// Returns an unordered vector of `PendingPatch`.
+async fn download_patches_concurrent_inner(
+    client: &PatchServerClient,
+    patch_url: Url,
+    patch_list: ThorPatchList,
+    ensure_integrity: bool,
+    journal: &InstallationJournal,
+    progress_sink: &dyn ProgressSink,
+) -> Result<Vec<PendingPatch>> {
+    const CONCURRENT_DOWNLOADS: usize = 32;
+    const ONE_SECOND: Duration = Duration::from_secs(1);
+    // Shared value that contains the number of downloaded patches
+    let shared_patch_number = AtomicUsize::new(0_usize);
+    // Shared tuple that's used to compute the download speed
+    let shared_progress_state = Arc::new(std::sync::Mutex::new((Instant::now(), 0_u64)));
+
+    // Collect stream of "PendingPatch" concurrently with an unordered_buffer
+    let patch_count = patch_list.len();
+    futures::stream::iter(patch_list.into_iter().map(|patch_info| async {
+        let patch_file_url = patch_url
+            .join(patch_info.file_name.as_str())
+            .with_context(|| "Failed to generate URL for patch file")?;
+        let local_file_path = journal
+            .download_directory()
+            .join(patch_info.file_name.as_str());
+        let shared_patch_number_ref = &shared_patch_number;
+
+        // Patches downloaded before an update got interrupted are reused
+        if let Some(local_file_path) = journal.downloaded_patch(&patch_info) {
+            if matches!(is_archive_valid(&local_file_path), Ok(true)) {
+                log::info!("Reusing '{}', downloaded previously", patch_info.file_name);
+                client.stats.skip_download();
+                shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+                return Ok(PendingPatch {
+                    info: patch_info,
+                    patch_url: patch_url.clone(),
+                    local_file_path,
+                });
+            }
+        }
+
+        // Setup a progress callback that'll send the current download speed to the UI
+        let shared_state = shared_progress_state.clone();
+        let mut last_downloaded_bytes: u64 = 0;
+        let mut download_started = false;
+        let mut progress_callback = move |dl_now, dl_total| {
+            if !download_started && dl_total > 0 {
+                download_started = true;
+                client.stats.start_download(dl_total);
+            }
+            let dl_delta = dl_now - last_downloaded_bytes;
+            client.stats.add_downloaded_bytes(dl_delta);
+            // Return download speed if the required time has elapsed (1s)
+            let downloaded_bytes_per_sec = {
+                if let Ok(mut shared_state) = shared_state.lock() {
+                    shared_state.1 += dl_delta;
+                    if shared_state.0.elapsed() >= ONE_SECOND {
+                        let downloaded_bytes_per_sec = (shared_state.1 as f32
+                            / shared_state.0.elapsed().as_secs_f32())
+                        .round() as u64;
+                        shared_state.0 = Instant::now();
+                        shared_state.1 = 0;
+                        Some(downloaded_bytes_per_sec)
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            };
+            // If speed is "available", update UI
+            if let Some(downloaded_bytes_per_sec) = downloaded_bytes_per_sec {
+                client.stats.record_download_speed(downloaded_bytes_per_sec);
+                block_on(async {
+                    if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::DownloadInProgress(
+                        shared_patch_number_ref.load(Ordering::SeqCst),
+                        patch_count,
+                        downloaded_bytes_per_sec,
+                    )) {
+                        log::warn!("Failed to update download status: {}", e);
+                    }
+                    dispatch_overall_progress(&client.stats, progress_sink);
+                });
+            }
+            last_downloaded_bytes = dl_now;
+        };
+
+        // Try the LAN source first
+        let lan_file_size = match &client.lan_source {
+            Some(lan_source) => {
+                fetch_from_lan_source(lan_source, &patch_info, &local_file_path).await
+            }
+            None => None,
+        };
+        // Then peers, the patch server is used as a web seed
+        let torrent_file_path = match (
+            lan_file_size,
+            &client.torrent_config,
+            &patch_info.magnet_link,
+        ) {
+            (None, Some(torrent_config), Some(magnet_link)) => match download_with_torrent(
+                torrent_config,
+                magnet_link,
+                &patch_info.file_name,
+                journal.download_directory(),
+            )
+            .await
+            {
+                Ok(file_path) => Some(file_path),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to download '{}' from peers, falling back to HTTP: {:#}",
+                        patch_info.file_name,
+                        e
+                    );
+                    client.stats.add_retry();
+                    None
+                }
+            },
+            _ => None,
+        };
+        match (lan_file_size, torrent_file_path) {
+            (Some(file_size), _) => progress_callback(file_size, file_size),
+            (None, Some(torrent_file_path)) => {
+                tokio::fs::rename(&torrent_file_path, &local_file_path)
+                    .await
+                    .with_context(|| "Failed to move downloaded file")?;
+                let file_size = tokio::fs::metadata(&local_file_path).await?.len();
+                progress_callback(file_size, file_size);
+            }
+            (None, None) => {
+                let mut tmp_file = File::create(&local_file_path)
+                    .await
+                    .with_context(|| "Failed to create temporary file")?;
+                download_patch_to_file(
+                    client,
+                    &patch_file_url,
+                    &patch_info,
+                    &mut tmp_file,
+                    &mut progress_callback,
+                )
+                .await?;
+            }
+        }
+
+        // Check the archive's integrity if required
+        let context = || {
+            format!(
+                "Failed to check archive's integrity: '{}'",
+                patch_info.file_name
+            )
+        };
+        if ensure_integrity && !is_archive_valid(&local_file_path).with_context(context)? {
+            return Err(anyhow!("Archive '{}' is corrupt", patch_info.file_name));
+        }
+
+        journal
+            .record_download(&patch_info, &local_file_path)
+            .with_context(|| "Failed to update the installation journal")?;
+
+        // Update status
+        shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+
+        // File's been downloaded, add it to the queue
+        Ok(PendingPatch {
+            info: patch_info,
+            patch_url: patch_url.clone(),
+            local_file_path,
+        }) as Result<PendingPatch>
+    }))
+    .buffer_unordered(CONCURRENT_DOWNLOADS)
+    .try_collect()
+    .await
+}
+
+/// Checks whether a downloaded archive is corrupt: it cannot be parsed, it
+/// doesn't match its integrity file or one of its entries cannot be
+/// decompressed.
+///
+/// Archives exceeding `limits` aren't considered corrupt, since downloading
+/// them again wouldn't help.
+fn is_archive_corrupt(archive_path: impl AsRef<Path>, limits: &DecompressionLimits) -> bool {
+    if !matches!(is_archive_valid(archive_path.as_ref()), Ok(true)) {
+        return true;
+    }
+    let mut archive = match ThorArchive::open_with_limits(archive_path.as_ref(), *limits) {
+        Ok(v) => v,
+        Err(GrufError::DecompressionLimitExceeded(_)) => return false,
+        Err(_) => return true,
+    };
+    let entry_paths: Vec<String> = archive
+        .get_entries()
+        .filter(|e| !e.is_removed)
+        .map(|e| e.relative_path.clone())
+        .collect();
+    entry_paths
+        .iter()
+        .any(|entry_path| match archive.read_file_content(entry_path) {
+            Ok(_) | Err(GrufError::DecompressionLimitExceeded(_)) => false,
+            Err(_) => true,
+        })
+}
+
+/// Returns the limits enforced when decompressing patches, from
+/// `patching.max_entry_size_mb` and `patching.max_expansion_ratio`.
+fn decompression_limits(config: &PatcherConfiguration) -> DecompressionLimits {
+    const BYTES_PER_MB: u64 = 1024 * 1024;
+    let default_limits = DecompressionLimits::default();
+    DecompressionLimits {
+        max_entry_size: config
+            .patching
+            .max_entry_size_mb
+            .map(|size_mb| size_mb.saturating_mul(BYTES_PER_MB))
+            .unwrap_or(default_limits.max_entry_size),
+        max_expansion_ratio: config
+            .patching
+            .max_expansion_ratio
+            .unwrap_or(default_limits.max_expansion_ratio),
+    }
+}
+
+/// Returns how many bytes of entry data can be held in memory when patching
+/// GRFs, from `patching.max_memory_mb` (unlimited by default).
+fn memory_budget(config: &PatcherConfiguration) -> usize {
+    const BYTES_PER_MB: u64 = 1024 * 1024;
+    match config.patching.max_memory_mb {
+        Some(size_mb) => {
+            usize::try_from(size_mb.saturating_mul(BYTES_PER_MB)).unwrap_or(usize::MAX)
+        }
+        None => usize::MAX,
+    }
+}
+
+/// Returns how GRFs rebuilt out-of-place are checked, as set by
+/// `patching.verify_rebuilt_grf`.
+fn grf_verification(config: &PatcherConfiguration) -> GrfVerification {
+    config
+        .patching
+        .verify_rebuilt_grf
+        .unwrap_or(GrfVerification::Sampled)
+}
+
+/// Returns how entries are written to GRFs, staging big ones in
+/// `temp_directory`.
+fn grf_write_options<'a>(
+    config: &PatcherConfiguration,
+    temp_directory: Option<&'a Path>,
+) -> GrfWriteOptions<'a> {
+    GrfWriteOptions {
+        memory_budget: memory_budget(config),
+        temp_directory,
+        duplicate_entries: config
+            .patching
+            .duplicate_entries
+            .unwrap_or(DuplicateEntryPolicy::UseGrfName),
+    }
+}
+
+/// Replaces a pending patch's local file with a fresh copy from the patch
+/// server.
+async fn redownload_patch(client: &PatchServerClient, pending_patch: &PendingPatch) -> Result<()> {
+    tokio::fs::remove_file(&pending_patch.local_file_path)
+        .await
+        .with_context(|| "Failed to remove corrupt file")?;
+    let mut tmp_file = File::create(&pending_patch.local_file_path)
+        .await
+        .with_context(|| "Failed to create temporary file")?;
+    download_patch_to_file(
+        client,
+        &pending_patch.patch_url,
+        &pending_patch.info,
+        &mut tmp_file,
+        downloaded_bytes_counter(client),
+    )
+    .await
+}
+
+/// Returns a progress callback for `download_patch_to_file` that only adds the
+/// downloaded bytes to the statistics of `client`.
+fn downloaded_bytes_counter(client: &PatchServerClient) -> impl FnMut(u64, u64) + '_ {
+    let mut last_downloaded_bytes = 0;
+    move |dl_now, _| {
+        client
+            .stats
+            .add_downloaded_bytes(dl_now.saturating_sub(last_downloaded_bytes));
+        last_downloaded_bytes = dl_now;
+    }
+}
+
+/// Lets `progress_sink` know how many bytes have been downloaded with `client`
+/// once the returned guard is dropped, i.e. when the operation `client` is
+/// used for ends, whatever its outcome.
+fn report_downloaded_bytes<'a>(
+    client: &'a PatchServerClient,
+    progress_sink: &'a dyn ProgressSink,
+) -> scopeguard::ScopeGuard<(), impl FnOnce(()) + 'a> {
+    scopeguard::guard((), move |_| {
+        let downloaded_bytes = client.stats.downloaded_bytes();
+        if downloaded_bytes > 0 {
+            progress_sink.record_downloaded_bytes(downloaded_bytes);
+        }
+    })
+}
+
+fn is_archive_valid(archive_path: impl AsRef<Path>) -> Result<bool> {
+    let mut archive =
+        ThorArchive::open(archive_path.as_ref()).with_context(|| "Failed to open archive")?;
+    match archive.is_valid() {
+        Err(e) => {
+            if let GrufError::EntryNotFound = e {
+                // No integrity file present, consider the archive valid
+                Ok(true)
+            } else {
+                // Only consider this an error if the integrity file was found
+                Err(anyhow!(
+                    "Archive's integrity file is invalid: {}",
+                    e.to_string(),
+                ))
+            }
+        }
+        Ok(v) => Ok(v),
+    }
+}
+
+/// Downloads a single patch described with a `ThorPatchInfo`.
+///
+/// The patch's URL is signed right before the download starts, if required.
+/// Compressed patches (e.g., `patch.thor.gz`) are decompressed while they're
+/// written to `tmp_file`.
+///
+/// Transfers that receive no data for `web.stall_timeout_secs` are aborted and
+/// started over. `progress_callback` is given the bytes received by all
+/// attempts, so that they add up.
+async fn download_patch_to_file<CB: FnMut(u64, u64)>(
+    client: &PatchServerClient,
+    patch_url: &Url,
+    patch: &ThorPatchInfo,
+    tmp_file: &mut File,
+    mut progress_callback: CB,
+) -> Result<()> {
+    const STALL_RETRIES: usize = 3;
+    // Bytes received by aborted transfers, so that reported progress never
+    // goes backwards
+    let mut aborted_bytes: u64 = 0;
+    let mut retries = 0;
+    loop {
+        let mut downloaded_bytes: u64 = 0;
+        let res =
+            download_patch_to_file_once(client, patch_url, patch, tmp_file, |dl_now, dl_total| {
+                downloaded_bytes = dl_now;
+                progress_callback(aborted_bytes + dl_now, dl_total);
+            })
+            .await;
+        match res {
+            Err(e) if retries < STALL_RETRIES && e.downcast_ref::<TransferStalled>().is_some() => {
+                log::warn!("Retrying download of '{}': {:#}", patch.file_name, e);
+                client.stats.add_retry();
+                retries += 1;
+                aborted_bytes += downloaded_bytes;
+                tmp_file.set_len(0).await?;
+                tmp_file.seek(SeekFrom::Start(0)).await?;
+            }
+            res => {
+                return res
+                    .with_context(|| format!("Failed to download file '{}'", patch.file_name))
+            }
+        }
+    }
+}
+
+/// Fetches a patch from `web.lan_source` to `local_file_path` and returns its
+/// size, or None if it must be downloaded from elsewhere.
+///
+/// Invalid archives (e.g., patches that another install is still downloading
+/// to the share) are ignored.
+async fn fetch_from_lan_source(
+    lan_source: &LanSource,
+    patch_info: &ThorPatchInfo,
+    local_file_path: &Path,
+) -> Option<u64> {
+    let res = match lan_source.fetch_patch(patch_info, local_file_path).await {
+        Ok(file_size) => match is_archive_valid(local_file_path) {
+            Ok(true) => Ok(file_size),
+            Ok(false) => Err(anyhow!("Archive is corrupt")),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(file_size) => {
+            log::info!("Fetched '{}' from the LAN source", patch_info.file_name);
+            Some(file_size)
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to fetch '{}' from the LAN source, falling back to the patch server: {:#}",
+                patch_info.file_name,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Error returned when a transfer receives no data for `web.stall_timeout_secs`.
+#[derive(Debug)]
+struct TransferStalled(Duration);
+
+impl std::error::Error for TransferStalled {}
+
+impl std::fmt::Display for TransferStalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No data received for {}s", self.0.as_secs())
+    }
+}
+
+/// Awaits `future`, failing with `TransferStalled` if it takes longer than
+/// `stall_timeout`.
+async fn with_stall_timeout<F: Future>(
+    stall_timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output> {
+    match stall_timeout {
+        None => Ok(future.await),
+        Some(stall_timeout) => tokio::time::timeout(stall_timeout, future)
+            .await
+            .map_err(|_| TransferStalled(stall_timeout).into()),
+    }
+}
+
+/// How often `StallReporter` should check the progress of the update.
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lets the UI know when the update makes no progress for
+/// `web.stall_timeout_secs`, and again each time it's been stalled for as long
+/// once more.
+struct StallReporter {
+    stall_timeout_secs: Option<u64>,
+    reported_stalls: u64,
+}
+
+impl StallReporter {
+    fn new(stall_timeout: Option<Duration>) -> StallReporter {
+        StallReporter {
+            stall_timeout_secs: stall_timeout.map(|v| v.as_secs().max(1)),
+            reported_stalls: 0,
+        }
+    }
+
+    fn check(&mut self, stats: &SessionStats, progress_sink: &dyn ProgressSink) {
+        let stall_timeout_secs = match self.stall_timeout_secs {
+            Some(v) => v,
+            None => return,
+        };
+        let (stage, stalled_secs) = match stats.time_since_progress() {
+            Some((stage, elapsed)) => (stage, elapsed.as_secs()),
+            None => return,
+        };
+        let stalls = stalled_secs / stall_timeout_secs;
+        if stalls > self.reported_stalls {
+            log::warn!("No progress for {}s ({})", stalled_secs, stage.name());
+            if let Err(e) = progress_sink.dispatch_patching_stalled(stage.name(), stalled_secs) {
+                log::warn!("Failed to dispatch stalled status: {}", e);
+            }
+        }
+        self.reported_stalls = stalls;
+    }
+}
+
+/// Reports the stalls of the update with a `StallReporter`.
+///
+/// Never returns.
+async fn report_stalls(client: &PatchServerClient, progress_sink: &dyn ProgressSink) {
+    if client.stall_timeout.is_none() {
+        return std::future::pending().await;
+    }
+    let mut stall_reporter = StallReporter::new(client.stall_timeout);
+    loop {
+        tokio::time::sleep(STALL_CHECK_INTERVAL).await;
+        stall_reporter.check(&client.stats, progress_sink);
+    }
+}
+
+async fn download_patch_to_file_once<CB: FnMut(u64, u64)>(
+    client: &PatchServerClient,
+    patch_url: &Url,
+    patch: &ThorPatchInfo,
+    tmp_file: &mut File,
+    mut progress_callback: CB,
+) -> Result<()> {
+    let patch_file_url = patch_url.join(patch.file_name.as_str()).with_context(|| {
+        format!(
+            "Invalid file name '{}' given in patch list file",
+            patch.file_name
+        )
+    })?;
+    let request = client
+        .get_patch_file(patch_file_url)
+        .await
+        .with_context(|| format!("Failed to sign URL of file '{}'", patch.file_name))?;
+    let mut resp = with_stall_timeout(client.stall_timeout, request.send()).await??;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Patch file '{}' not found on the remote server",
+            patch.file_name
+        ));
+    }
+    let mut decoder = StreamDecoder::new(PatchCompression::from_file_name(&patch.file_name));
+    let bytes_to_download = resp.content_length().unwrap_or(0);
+    let mut downloaded_bytes: u64 = 0;
+    while let Some(chunk) = with_stall_timeout(client.stall_timeout, resp.chunk()).await?? {
+        let decoded_chunk = decoder
+            .decode(&chunk[..])
+            .with_context(|| format!("Failed to decompress file '{}'", patch.file_name))?;
+        tmp_file.write_all(&decoded_chunk).await?;
+        downloaded_bytes += chunk.len() as u64;
+        progress_callback(downloaded_bytes, bytes_to_download);
+    }
+    let decoded_chunk = decoder
+        .finish()
+        .with_context(|| format!("Failed to decompress file '{}'", patch.file_name))?;
+    tmp_file.write_all(&decoded_chunk).await?;
+    tmp_file
+        .sync_all()
+        .await
+        .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name,))?;
+    Ok(())
+}
+
+/// Parses and applies a list of patches to GRFs and/or to the game client's
+/// files.
+///
+/// Patches that turn out to be corrupt are downloaded again from the patch
+/// server (up to `patching.corrupt_patch_retries` times) before giving up on them.
+///
+/// This function is interruptible.
+async fn apply_patches(
+    client: &PatchServerClient,
+    pending_patch_queue: Vec<PendingPatch>,
+    config: &PatcherConfiguration,
+    patcher_cache: &mut PatcherCacheFile,
+    journal: &InstallationJournal,
+    progress_sink: &dyn ProgressSink,
+    patching_thread_rx: &mut CommandReceiver,
+) -> InterruptibleFnResult<()> {
+    const DEFAULT_CORRUPT_PATCH_RETRIES: usize = 2;
+    let current_working_dir = env::current_dir().map_err(|e| {
+        InterruptibleFnError::Err(format!(
+            "Failed to resolve current working directory: {}.",
+            e
+        ))
+    })?;
+    let patch_count = pending_patch_queue.len();
+    if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::InstallationInProgress(0, patch_count)) {
+        log::warn!("Failed to update patching status: {}", e);
+    }
+    let patch_sizes: Vec<u64> = pending_patch_queue
+        .iter()
+        .map(|pending_patch| {
+            std::fs::metadata(&pending_patch.local_file_path)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0)
+        })
+        .collect();
+    client.stats.expect_installation(patch_sizes.iter().sum());
+    let error_policy = config.patching.error_policy.unwrap_or(ErrorPolicy::Abort);
+    let corrupt_patch_retries = config
+        .patching
+        .corrupt_patch_retries
+        .unwrap_or(DEFAULT_CORRUPT_PATCH_RETRIES);
+    let limits = decompression_limits(config);
+    let mut skipped_patches = vec![];
+    for (patch_number, pending_patch) in pending_patch_queue.into_iter().enumerate() {
+        // Cancel the patching process if we've been asked to or if the other
+        // end of the channel has been disconnected
+        process_incoming_commands(patching_thread_rx)?;
+
+        let patch_name = pending_patch.info.file_name.clone();
+        client
+            .stats
+            .start_patch_installation(patch_sizes[patch_number]);
+        let mut redownload_count = 0;
+        let mut skipped = false;
+        loop {
+            log::info!("Processing {}", patch_name);
+            let err = match apply_patch_monitored(
+                &pending_patch.local_file_path,
+                config,
+                &current_working_dir,
+                (journal, pending_patch.info.index),
+                client,
+                progress_sink,
+            ) {
+                Ok(grf_unused_space) => {
+                    if let Some((grf_name, unused_space)) = grf_unused_space {
+                        patcher_cache
+                            .cache
+                            .record_grf_unused_space(&grf_name, unused_space);
+                    }
+                    client.stats.add_applied_patch();
+                    break;
+                }
+                Err(e) => e,
+            };
+            // Corrupt downloads are common with some CDNs, get the patch again
+            if redownload_count < corrupt_patch_retries
+                && is_archive_corrupt(&pending_patch.local_file_path, &limits)
+            {
+                redownload_count += 1;
+                client.stats.add_retry();
+                log::warn!(
+                    "Patch '{}' is corrupt ({:#}), downloading it again ({}/{})",
+                    patch_name,
+                    err,
+                    redownload_count,
+                    corrupt_patch_retries
+                );
+                let redownload_res = tokio::select! {
+                    cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
+                    res = redownload_patch(client, &pending_patch) => res,
+                };
+                match redownload_res {
+                    Ok(()) => {
+                        let res = journal
+                            .record_download(&pending_patch.info, &pending_patch.local_file_path);
+                        if let Err(e) = res {
+                            log::warn!("Failed to update the installation journal: {:#}", e);
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to download patch '{}' again: {:#}", patch_name, e)
+                    }
+                }
+            }
+            let action = match error_policy {
+                ErrorPolicy::Abort => PatchFailureAction::Abort,
+                ErrorPolicy::Skip => PatchFailureAction::Skip,
+                ErrorPolicy::Ask => {
+                    if progress_sink.prompt_patch_failure(&patch_name, &format!("{:#}", err)) {
+                        wait_for_patch_failure_action(patching_thread_rx).await?
+                    } else {
+                        PatchFailureAction::Abort
+                    }
+                }
+            };
+            match action {
+                PatchFailureAction::Retry => {
+                    client.stats.add_retry();
+                    continue;
+                }
+                PatchFailureAction::Skip => {
+                    log::warn!("Skipping patch '{}': {:#}", patch_name, err);
+                    skipped_patches.push(patch_name.clone());
+                    skipped = true;
+                    break;
+                }
+                PatchFailureAction::Abort => {
+                    return Err(InterruptibleFnError::Err(format!(
+                        "Failed to apply patch '{}': {}.",
+                        patch_name, err
+                    )));
+                }
+            }
+        }
+        // Update the cache file with the last successful patch's index.
+        // Skipped patches are applied again by the next update
+        if skipped {
+            patcher_cache
+                .cache
+                .mark_patches_skipped(&[pending_patch.info.index]);
+        } else {
+            patcher_cache
+                .cache
+                .mark_patch_applied(pending_patch.info.index);
+        }
+        if let Err(e) = patcher_cache.save().await {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+        if !skipped {
+            let res = journal
+                .record_patch_applied(pending_patch.info.index, &pending_patch.local_file_path);
+            if let Err(e) = res {
+                log::warn!("Failed to update the installation journal: {:#}", e);
+            }
+        }
+        // Update status
+        client.stats.finish_patch_installation();
+        if let Err(e) = progress_sink.dispatch_patching_status(PatchingStatus::InstallationInProgress(
+            1 + patch_number,
+            patch_count,
+        )) {
+            log::warn!("Failed to update patching status: {}", e);
+        }
+        dispatch_overall_progress(&client.stats, progress_sink);
+    }
+    if !skipped_patches.is_empty() {
+        if let Err(e) =
+            progress_sink.dispatch_patching_status(PatchingStatus::PatchesSkipped(skipped_patches))
+        {
+            log::warn!("Failed to update patching status: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the THOR patch at `thor_archive_path`.
+///
+/// With `journal` (along with the patch's index), the entries applied to disk
+/// are journaled, and the ones journaled by an interrupted update are skipped.
+/// With `stats`, the installation's progress is recorded to estimate the
+/// overall progress of the update.
+///
+/// For GRF patches, returns the name of the patched GRF along with the number
+/// of bytes left unused in it.
+fn apply_patch(
+    thor_archive_path: impl AsRef<Path>,
+    config: &PatcherConfiguration,
+    current_working_dir: impl AsRef<Path>,
+    journal: Option<(&InstallationJournal, usize)>,
+    stats: Option<&SessionStats>,
+    progress_sink: &dyn ProgressSink,
+) -> Result<Option<(String, u64)>> {
+    let patch_index = journal.map(|(_, patch_index)| patch_index);
+    let mut thor_archive =
+        ThorArchive::open_with_limits(thor_archive_path.as_ref(), decompression_limits(config))?;
+    // Names are used as is unless a code page is configured
+    let code_page = config.client.code_page.as_deref();
+    if config.patching.strict_entry_paths.unwrap_or(true) {
+        check_entry_paths(&thor_archive, code_page)?;
+    }
+    if thor_archive.use_grf_merging() {
+        // Patch GRF file
+        let target_grf_name = {
+            if thor_archive.target_grf_name().is_empty() {
+                config.client.default_grf_name.clone()
+            } else {
+                thor_archive.target_grf_name()
+            }
+        };
+        log::trace!("Target GRF: {:?}", target_grf_name);
+        let grf_patching_method = match config.patching.in_place {
+            true => GrfPatchingMethod::InPlace,
+            false => GrfPatchingMethod::OutOfPlace(grf_verification(config)),
+        };
+        let target_grf_path = current_working_dir.as_ref().join(&target_grf_name);
+        recover_interrupted_grf_patch(&target_grf_path)
+            .with_context(|| format!("Failed to restore '{}'", target_grf_name))?;
+        // The default GRF can be created on its own, for fresh installations
+        let is_default_grf = target_grf_name == config.client.default_grf_name;
+        let create_grf = config.patching.create_grf
+            || (is_default_grf && config.client.create_grf_if_missing.unwrap_or(false));
+        let grf_created = create_grf && !target_grf_path.exists();
+        restore_point::save_grf_entries(
+            &get_restore_point_directory_path()?,
+            &target_grf_name,
+            &target_grf_path,
+            &thor_archive,
+        )
+        .with_context(|| "Failed to update the restore point")?;
+        let temp_directory = get_temp_directory_path(config);
+        let res = apply_patch_to_grf(
+            grf_patching_method,
+            create_grf,
+            &grf_write_options(config, temp_directory.as_deref()),
+            target_grf_path,
+            &mut thor_archive,
+            entry_progress_reporter(progress_sink, stats),
+        );
+        if matches!(&res, Err(e) if e.downcast_ref::<FileLocked>().is_some()) {
+            if let Err(e) = progress_sink.dispatch_antivirus_interference_suspected() {
+                log::warn!("Failed to dispatch antivirus hint: {}", e);
+            }
+        }
+        let unused_space = res?;
+        update_install_manifest(|manifest| {
+            manifest.record_grf_patch(&target_grf_name, &mut thor_archive, patch_index)
+        });
+        if grf_created && is_default_grf {
+            if let Err(e) = register_grf_in_data_ini(current_working_dir, &target_grf_name) {
+                log::warn!("Failed to add '{}' to DATA.INI: {}", target_grf_name, e);
+            }
+        }
+        Ok(Some((target_grf_name, unused_space)))
+    } else {
+        // Patch root directory
+        let root_directory = get_disk_root_directory(config, current_working_dir.as_ref());
+        if config.patching.backup_replaced_files.unwrap_or(false) {
+            backup_replaced_files(
+                &current_working_dir.as_ref().join(BACKUP_DIRECTORY_NAME),
+                &root_directory,
+                code_page,
+                &thor_archive,
+                config
+                    .patching
+                    .backup_max_size_mb
+                    .unwrap_or(DEFAULT_BACKUP_MAX_SIZE_MB)
+                    .saturating_mul(1024 * 1024),
+            )
+            .with_context(|| "Failed to back up replaced files")?;
+        }
+        match journal {
+            Some((journal, patch_index)) => apply_patch_to_disk_resumable(
+                &root_directory,
+                code_page,
+                &mut thor_archive,
+                &journal.applied_entries(patch_index),
+                |entry| {
+                    if let Err(e) = journal.record_entry_applied(patch_index, entry) {
+                        log::warn!("Failed to update the installation journal: {:#}", e);
+                    }
+                },
+                entry_progress_reporter(progress_sink, stats),
+            ),
+            None => apply_patch_to_disk(
+                &root_directory,
+                code_page,
+                &mut thor_archive,
+                entry_progress_reporter(progress_sink, stats),
+            ),
+        }?;
+        update_install_manifest(|manifest| {
+            manifest.record_disk_patch(&root_directory, code_page, &mut thor_archive, patch_index)
+        });
+        Ok(None)
+    }
+}
+
+/// Records the files a patch installed in the install manifest (with `update`).
+/// Failures are only logged, since they merely affect later repairs.
+fn update_install_manifest(update: impl FnOnce(&mut InstallManifest) -> Result<()>) {
+    let res = get_manifest_file_path().and_then(|manifest_file_path| {
+        let mut manifest = read_manifest(&manifest_file_path)?;
+        update(&mut manifest)?;
+        write_manifest(&manifest_file_path, &manifest)
+    });
+    if let Err(e) = res {
+        log::warn!("Failed to update the install manifest: {:#}", e);
+    }
+}
+
+/// Applies the THOR patch at `thor_archive_path` like `apply_patch`, on a
+/// separate thread. In the meantime, the patching thread forwards what the
+/// installation reports to `progress_sink`, and reports the installation's
+/// stalls (e.g., because of an unresponsive disk).
+fn apply_patch_monitored(
+    thor_archive_path: &Path,
+    config: &PatcherConfiguration,
+    current_working_dir: &Path,
+    journal: (&InstallationJournal, usize),
+    client: &PatchServerClient,
+    progress_sink: &dyn ProgressSink,
+) -> Result<Option<(String, u64)>> {
+    let stats = &client.stats;
+    let mut stall_reporter = StallReporter::new(client.stall_timeout);
+    let (event_tx, event_rx) = flume::unbounded();
+    std::thread::scope(|scope| {
+        let installation = scope.spawn(move || {
+            let forwarding_sink = ForwardingProgressSink(event_tx);
+            apply_patch(
+                thor_archive_path,
+                config,
+                current_working_dir,
+                Some(journal),
+                Some(stats),
+                &forwarding_sink,
+            )
+        });
+        loop {
+            match event_rx.recv_timeout(STALL_CHECK_INTERVAL) {
+                Ok(event) => event.dispatch(progress_sink),
+                Err(flume::RecvTimeoutError::Timeout) => stall_reporter.check(stats, progress_sink),
+                Err(flume::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        installation
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("The installation thread panicked")))
+    })
+}
+
+/// What an installation running on another thread reports.
+enum InstallationEvent {
+    Status(PatchingStatus),
+    AntivirusInterferenceSuspected,
+}
+
+impl InstallationEvent {
+    fn dispatch(self, progress_sink: &dyn ProgressSink) {
+        let res = match self {
+            InstallationEvent::Status(status) => progress_sink.dispatch_patching_status(status),
+            InstallationEvent::AntivirusInterferenceSuspected => {
+                progress_sink.dispatch_antivirus_interference_suspected()
+            }
+        };
+        if let Err(e) = res {
+            log::warn!("Failed to update patching status: {}", e);
+        }
+    }
+}
+
+/// Sends what an installation running on another thread reports to the
+/// patching thread, since `ProgressSink`s are only used from there.
+struct ForwardingProgressSink(flume::Sender<InstallationEvent>);
+
+impl ProgressSink for ForwardingProgressSink {
+    fn dispatch_patching_status(&self, status: PatchingStatus) -> Result<()> {
+        self.0
+            .send(InstallationEvent::Status(status))
+            .map_err(|_| anyhow!("The patching thread is gone"))
+    }
+
+    fn dispatch_antivirus_interference_suspected(&self) -> Result<()> {
+        self.0
+            .send(InstallationEvent::AntivirusInterferenceSuspected)
+            .map_err(|_| anyhow!("The patching thread is gone"))
+    }
+}
+
+/// Returns a callback that sends the progress of the installation of a patch
+/// (entries processed, total number of entries, bytes written) to the UI.
+///
+/// Updates are throttled, since patches can contain a lot of entries.
+fn entry_progress_reporter<'a>(
+    progress_sink: &'a dyn ProgressSink,
+    stats: Option<&'a SessionStats>,
+) -> impl FnMut(usize, usize, u64) + 'a {
+    const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+    let mut last_update: Option<Instant> = None;
+    move |nb_processed, nb_total, written_bytes| {
+        if let Some(stats) = stats {
+            stats.record_entry_progress(nb_processed, nb_total);
+        }
+        let should_update = match last_update {
+            Some(instant) => nb_processed == nb_total || instant.elapsed() >= UPDATE_INTERVAL,
+            None => true,
+        };
+        if should_update {
+            last_update = Some(Instant::now());
+            if let Err(e) = progress_sink.dispatch_patching_status(
+                PatchingStatus::EntryInstallationInProgress(nb_processed, nb_total, written_bytes),
+            ) {
+                log::warn!("Failed to update patching status: {}", e);
+            }
+            if let Some(stats) = stats {
+                dispatch_overall_progress(stats, progress_sink);
+            }
+        }
+    }
+}
+
+/// Sends the progress of the whole update to the UI, once it can be estimated.
+fn dispatch_overall_progress(stats: &SessionStats, progress_sink: &dyn ProgressSink) {
+    if let Some((percent, eta_secs)) = stats.overall_progress() {
+        if let Err(e) = progress_sink
+            .dispatch_patching_status(PatchingStatus::OverallProgress(percent, eta_secs))
+        {
+            log::warn!("Failed to update patching status: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{FixtureServer, FixtureServerOptions};
+    use crate::url_signer::UrlSigner;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_download_path_to_file() {
+        // Generate 200MiB of data
+        let data_size: usize = 200 * 1024 * 1024;
+        let body_content: Vec<u8> = (0..data_size).map(|x| x as u8).collect();
+
+        let patch_name = "patch_archive";
+        let patch_path = format!("/{}", patch_name);
+        // Setup a local web server
+        let server = Server::run();
+        // Configure the server to expect a single GET request and respond
+        // with a 200 status code.
+        server.expect(
+            Expectation::matching(request::method_path("GET", patch_path.clone()))
+                .respond_with(status_code(200).body(body_content.clone())),
+        );
+
+        // "Download" the file
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+            requires_index: None,
+            requires_patcher: None,
+            magnet_link: None,
+        };
+        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+        download_patch_to_file(
+            &PatchServerClient {
+                http_client: reqwest::Client::new(),
+                server_headers: PatchServerHeaders::default(),
+                url_signer: None,
+                torrent_config: None,
+                lan_source: None,
+                stats: SessionStats::new(),
+                stall_timeout: None,
+            },
+            &from_url,
+            &patch_info,
+            &mut tmp_file,
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut file_content = Vec::with_capacity(data_size);
+        tmp_file.read_to_end(&mut file_content).await.unwrap();
+        // Size check
+        assert_eq!(data_size as u64, tmp_file.metadata().await.unwrap().len());
+        assert_eq!(data_size, file_content.len());
+        // Content check
+        assert_eq!(body_content, file_content);
+    }
+
+    #[tokio::test]
+    async fn test_skipped_patch_is_applied_again() {
+        struct NullSink;
+        impl ProgressSink for NullSink {
+            fn dispatch_patching_status(&self, _status: PatchingStatus) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config: PatcherConfiguration = serde_yaml::from_str(
+            r#"
+window: { title: test, width: 1, height: 1, resizable: false }
+play: { path: game.exe, arguments: [] }
+setup: { path: setup.exe, arguments: [] }
+web: { index_url: "http://localhost/", patch_servers: [] }
+client: { default_grf_name: data.grf }
+patching:
+  in_place: true
+  check_integrity: false
+  create_grf: false
+  error_policy: skip
+  corrupt_patch_retries: 0
+"#,
+        )
+        .unwrap();
+        let patch_list = thor::patch_list_from_string("1 a.thor\n2 b.thor\n").unwrap();
+        let local_file_path = temp_dir.path().join("b.thor");
+        std::fs::write(&local_file_path, b"not a THOR archive").unwrap();
+        let pending_patch_queue = vec![PendingPatch {
+            info: patch_list[1].clone(),
+            patch_url: Url::parse("http://localhost/").unwrap(),
+            local_file_path,
+        }];
+        let mut patcher_cache = PatcherCacheFile::open(temp_dir.path().join("cache"))
+            .await
+            .unwrap();
+        patcher_cache.cache.mark_patch_applied(1);
+        let client = PatchServerClient {
+            http_client: reqwest::Client::new(),
+            server_headers: PatchServerHeaders::default(),
+            url_signer: None,
+            torrent_config: None,
+            lan_source: None,
+            stats: SessionStats::new(),
+            stall_timeout: None,
+        };
+        let (_tx, rx) = flume::bounded(1);
+        let res = apply_patches(
+            &client,
+            pending_patch_queue,
+            &config,
+            &mut patcher_cache,
+            &InstallationJournal::disabled(temp_dir.path().to_path_buf()),
+            &NullSink,
+            &mut CommandReceiver::new(rx),
+        )
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(patcher_cache.cache.last_patch_index, Some(2));
+        assert_eq!(patcher_cache.cache.skipped_patch_indices, vec![2]);
+
+        // The next update tries to apply the skipped patch again
+        let mut new_patches = ThorPatchList::new();
+        let (previously_skipped_patches, _) =
+            patcher_cache
+                .cache
+                .reconcile_skipped_patches(&patch_list, &mut new_patches, &[]);
+        let indices: Vec<usize> = previously_skipped_patches.iter().map(|x| x.index).collect();
+        assert_eq!(indices, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_update_game_from_fixture_server() {
+        struct NullSink;
+        impl ProgressSink for NullSink {
+            fn dispatch_patching_status(&self, _status: PatchingStatus) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let fixtures_dir = tempfile::tempdir().unwrap();
+        crate::test_fixtures::build_disk_patch(
+            &fixtures_dir.path().join("2021-01.thor"),
+            &[
+                ("data\\hello.txt", Some(b"hello")),
+                ("data\\removed.txt", None),
+            ],
+        );
+        crate::test_fixtures::build_disk_patch(
+            &fixtures_dir.path().join("2021-02.thor"),
+            &[("data\\hello.txt", Some(b"hello again"))],
+        );
+        let server =
+            FixtureServer::bind(fixtures_dir.path(), 0, FixtureServerOptions::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+
+        // The game's files and the patcher's state are in the working directory
+        let game_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(game_dir.path().join("data")).unwrap();
+        std::fs::write(game_dir.path().join("data").join("removed.txt"), b"").unwrap();
+        let previous_working_dir = env::current_dir().unwrap();
+        env::set_current_dir(game_dir.path()).unwrap();
+        let _guard = scopeguard::guard((), |_| {
+            let _ = env::set_current_dir(&previous_working_dir);
+        });
+        let config: PatcherConfiguration = serde_yaml::from_str(&format!(
+            r#"
+window: {{ title: test, width: 1, height: 1, resizable: false }}
+play: {{ path: game.exe, arguments: [] }}
+setup: {{ path: setup.exe, arguments: [] }}
+web:
+  index_url: "http://localhost/"
+  patch_servers:
+    - name: fixtures
+      plist_url: "http://{0}/plist.txt"
+      patch_url: "http://{0}/"
+client: {{ default_grf_name: data.grf }}
+patching:
+  in_place: true
+  check_integrity: false
+  create_grf: false
+"#,
+            address
+        ))
+        .unwrap();
+
+        let (_tx, rx) = flume::bounded(1);
+        let mut rx = CommandReceiver::new(rx);
+        let res = update_game(&NullSink, &config, &mut rx).await;
+        assert_eq!(res.unwrap(), UpdateOutcome::Patched);
+        let hello_path = game_dir.path().join("data").join("hello.txt");
+        assert_eq!(std::fs::read(&hello_path).unwrap(), b"hello again");
+        assert!(!game_dir.path().join("data").join("removed.txt").exists());
+        // Both patches are remembered as applied
+        let res = update_game(&NullSink, &config, &mut rx).await;
+        assert_eq!(res.unwrap(), UpdateOutcome::UpToDate);
+    }
+
+    #[test]
+    fn test_sort_by_download_order() {
+        let patch_list =
+            thor::patch_list_from_string("1 a.thor\n2 b.thor\n3 c.thor\n4 d.thor\n").unwrap();
+        let sizes = [Some(30), None, Some(10), Some(30)];
+        let sorted_indices = |download_order| -> Vec<usize> {
+            let sized_patch_list = patch_list.iter().cloned().zip(sizes.iter().cloned());
+            sort_by_download_order(sized_patch_list.collect(), download_order)
+                .iter()
+                .map(|patch_info| patch_info.index)
+                .collect()
+        };
+        assert_eq!(sorted_indices(DownloadOrder::List), vec![1, 2, 3, 4]);
+        assert_eq!(
+            sorted_indices(DownloadOrder::SmallestFirst),
+            vec![3, 1, 4, 2]
+        );
+        assert_eq!(
+            sorted_indices(DownloadOrder::LargestFirst),
+            vec![1, 4, 3, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_patch_size_signs_url_once() {
+        let server = Server::run();
+        let patch_file_url = Url::parse(server.url("/1.thor").to_string().as_str()).unwrap();
+        let signed_url = format!("{}?sig=abc", patch_file_url);
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/sign"),
+                request::query(url_decoded(contains(("url", patch_file_url.to_string())))),
+            ])
+            .times(1)
+            .respond_with(json_encoded(serde_json::json!({ "url": signed_url }))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("HEAD", "/1.thor"),
+                request::query(url_decoded(contains(("sig", "abc")))),
+            ])
+            .respond_with(status_code(200).body(vec![0; 42])),
+        );
+
+        let http_client = reqwest::Client::new();
+        let url_signer = UrlSigner::new(
+            http_client.clone(),
+            server.url("/sign").to_string().as_str(),
+        )
+        .unwrap();
+        let client = PatchServerClient {
+            http_client,
+            server_headers: PatchServerHeaders::default(),
+            url_signer: Some(url_signer),
+            torrent_config: None,
+            lan_source: None,
+            stats: SessionStats::new(),
+            stall_timeout: None,
+        };
+        assert_eq!(fetch_patch_size(&client, patch_file_url).await, Some(42));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_patch_list_cached() {
+        let server = Server::run();
+        let patch_list_url = Url::parse(server.url("/plist.txt").to_string().as_str()).unwrap();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/plist.txt"),
+                not(request::headers(contains(key("if-none-match")))),
+            ])
+            .times(1)
+            .respond_with(
+                status_code(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .body("1 a.thor\n2 b.thor\n"),
+            ),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/plist.txt"),
+                request::headers(contains(("if-none-match", "\"v1\""))),
+            ])
+            .times(1)
+            .respond_with(status_code(304)),
+        );
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_file_path = temp_dir.path().join("downloads").join("plist.json");
+        let client = PatchServerClient {
+            http_client: reqwest::Client::new(),
+            server_headers: PatchServerHeaders::default(),
+            url_signer: None,
+            torrent_config: None,
+            lan_source: None,
+            stats: SessionStats::new(),
+            stall_timeout: None,
+        };
+        for _ in 0..2 {
+            let patch_list =
+                fetch_patch_list_cached(&client, patch_list_url.clone(), &cache_file_path)
+                    .await
+                    .unwrap();
+            assert_eq!(
+                patch_list.iter().map(|x| x.index).collect::<Vec<_>>(),
+                vec![1, 2]
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_archive_corrupt() {
+        use crate::test_fixtures::build_thor_archive;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_thor_archive(
+            &thor_archive_path,
+            false,
+            true,
+            &[("data\\a.txt", Some(b"content"))],
+        );
+        let limits = DecompressionLimits::default();
+        assert!(!is_archive_corrupt(&thor_archive_path, &limits));
+        // Patches exceeding the limits must not be downloaded again
+        let limits = DecompressionLimits {
+            max_entry_size: 1,
+            max_expansion_ratio: 1,
+        };
+        assert!(!is_archive_corrupt(&thor_archive_path, &limits));
+
+        // Truncated download
+        let limits = DecompressionLimits::default();
+        let content = std::fs::read(&thor_archive_path).unwrap();
+        std::fs::write(&thor_archive_path, &content[..content.len() / 2]).unwrap();
+        assert!(is_archive_corrupt(&thor_archive_path, &limits));
+        assert!(is_archive_corrupt(
+            temp_dir.path().join("missing.thor"),
+            &limits
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_compressed_patch_to_file() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let body_content: Vec<u8> = (0..1024 * 1024).map(|x| (x % 7) as u8).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body_content).unwrap();
+        let compressed_content = encoder.finish().unwrap();
+
+        let patch_name = "patch_archive.thor.gz";
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", format!("/{}", patch_name)))
+                .respond_with(status_code(200).body(compressed_content)),
+        );
+
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+            requires_index: None,
+            requires_patcher: None,
+            magnet_link: None,
+        };
+        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+        download_patch_to_file(
+            &PatchServerClient {
+                http_client: reqwest::Client::new(),
+                server_headers: PatchServerHeaders::default(),
+                url_signer: None,
+                torrent_config: None,
+                lan_source: None,
+                stats: SessionStats::new(),
+                stall_timeout: None,
+            },
+            &from_url,
+            &patch_info,
+            &mut tmp_file,
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut file_content = Vec::new();
+        tmp_file.read_to_end(&mut file_content).await.unwrap();
+        assert_eq!(body_content, file_content);
+    }
+}
@@ -0,0 +1,214 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Makes a file created by the patcher usable by the non-elevated user, when
+/// the patcher runs elevated.
+///
+/// Otherwise, state files (e.g., the cache) written by an elevated run would
+/// look foreign to the following non-elevated runs, which then fail to update
+/// them and start over. This does nothing when the patcher isn't elevated,
+/// failures are logged and ignored.
+pub fn share_with_unelevated_user(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    if !is_elevated() {
+        return;
+    }
+    if let Err(e) = grant_unelevated_user_access(path) {
+        log::warn!(
+            "Failed to share '{}' with the non-elevated user: {:#}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Indicates whether the patcher runs with administrator privileges.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn is_elevated() -> bool {
+    windows::win32_is_elevated()
+}
+
+/// Indicates whether the patcher runs with administrator privileges (e.g.,
+/// through `sudo`).
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn is_elevated() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Grants the members of the Users group read and write access to the file.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn grant_unelevated_user_access(path: &Path) -> Result<()> {
+    windows::win32_grant_users_access(path)
+}
+
+/// Gives the file back to the user that invoked `sudo`, if any.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn grant_unelevated_user_access(path: &Path) -> Result<()> {
+    use anyhow::anyhow;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (uid, gid) = match sudo_user_ids(
+        std::env::var("SUDO_UID").ok().as_deref(),
+        std::env::var("SUDO_GID").ok().as_deref(),
+    ) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(anyhow!("{}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Parses the IDs of the user that invoked `sudo`.
+#[cfg(not(windows))]
+fn sudo_user_ids(uid: Option<&str>, gid: Option<&str>) -> Option<(u32, u32)> {
+    Some((uid?.parse().ok()?, gid?.parse().ok()?))
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    use anyhow::{anyhow, Result};
+
+    pub fn win32_is_elevated() -> bool {
+        use winapi::shared::minwindef::DWORD;
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+        use winapi::um::securitybaseapi::GetTokenInformation;
+        use winapi::um::winnt::{TokenElevation, HANDLE, TOKEN_ELEVATION, TOKEN_QUERY};
+
+        let mut token: HANDLE = ptr::null_mut();
+        if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+            return false;
+        }
+        let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+        let mut returned_size: DWORD = 0;
+        let result = unsafe {
+            GetTokenInformation(
+                token,
+                TokenElevation,
+                &mut elevation as *mut TOKEN_ELEVATION as _,
+                std::mem::size_of::<TOKEN_ELEVATION>() as DWORD,
+                &mut returned_size,
+            )
+        };
+        unsafe { CloseHandle(token) };
+        result != 0 && elevation.TokenIsElevated != 0
+    }
+
+    pub fn win32_grant_users_access(path: &Path) -> Result<()> {
+        use winapi::shared::minwindef::DWORD;
+        use winapi::shared::winerror::ERROR_SUCCESS;
+        use winapi::um::accctrl::{
+            EXPLICIT_ACCESS_W, GRANT_ACCESS, NO_MULTIPLE_TRUSTEE, SE_FILE_OBJECT, TRUSTEE_IS_SID,
+            TRUSTEE_IS_WELL_KNOWN_GROUP, TRUSTEE_W,
+        };
+        use winapi::um::aclapi::{GetNamedSecurityInfoW, SetEntriesInAclW, SetNamedSecurityInfoW};
+        use winapi::um::securitybaseapi::CreateWellKnownSid;
+        use winapi::um::winbase::LocalFree;
+        use winapi::um::winnt::{
+            WinBuiltinUsersSid, DACL_SECURITY_INFORMATION, DELETE, FILE_GENERIC_READ,
+            FILE_GENERIC_WRITE, PACL, PSECURITY_DESCRIPTOR, SECURITY_MAX_SID_SIZE,
+        };
+        const NO_INHERITANCE: DWORD = 0;
+
+        let mut wide_path: Vec<u16> = OsStr::new(path).encode_wide().chain(Some(0)).collect();
+        let mut users_sid = [0_u8; SECURITY_MAX_SID_SIZE];
+        let mut users_sid_size = SECURITY_MAX_SID_SIZE as DWORD;
+        let result = unsafe {
+            CreateWellKnownSid(
+                WinBuiltinUsersSid,
+                ptr::null_mut(),
+                users_sid.as_mut_ptr() as _,
+                &mut users_sid_size,
+            )
+        };
+        if result == 0 {
+            return Err(anyhow!("Failed to get the Users group's SID"));
+        }
+
+        let mut old_dacl: PACL = ptr::null_mut();
+        let mut security_descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+        let result = unsafe {
+            GetNamedSecurityInfoW(
+                wide_path.as_ptr(),
+                SE_FILE_OBJECT,
+                DACL_SECURITY_INFORMATION,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut old_dacl,
+                ptr::null_mut(),
+                &mut security_descriptor,
+            )
+        };
+        if result != ERROR_SUCCESS {
+            return Err(anyhow!("Failed to read the file's ACL ({})", result));
+        }
+
+        let mut explicit_access = EXPLICIT_ACCESS_W {
+            grfAccessPermissions: FILE_GENERIC_READ | FILE_GENERIC_WRITE | DELETE,
+            grfAccessMode: GRANT_ACCESS,
+            grfInheritance: NO_INHERITANCE,
+            Trustee: TRUSTEE_W {
+                pMultipleTrustee: ptr::null_mut(),
+                MultipleTrusteeOperation: NO_MULTIPLE_TRUSTEE,
+                TrusteeForm: TRUSTEE_IS_SID,
+                TrusteeType: TRUSTEE_IS_WELL_KNOWN_GROUP,
+                ptstrName: users_sid.as_mut_ptr() as _,
+            },
+        };
+        let mut new_dacl: PACL = ptr::null_mut();
+        let result = unsafe { SetEntriesInAclW(1, &mut explicit_access, old_dacl, &mut new_dacl) };
+        if result != ERROR_SUCCESS {
+            unsafe { LocalFree(security_descriptor) };
+            return Err(anyhow!("Failed to build the file's new ACL ({})", result));
+        }
+        let result = unsafe {
+            SetNamedSecurityInfoW(
+                wide_path.as_mut_ptr(),
+                SE_FILE_OBJECT,
+                DACL_SECURITY_INFORMATION,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                new_dacl,
+                ptr::null_mut(),
+            )
+        };
+        unsafe {
+            LocalFree(new_dacl as _);
+            LocalFree(security_descriptor);
+        }
+        if result != ERROR_SUCCESS {
+            return Err(anyhow!("Failed to update the file's ACL ({})", result));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sudo_user_ids() {
+        assert_eq!(sudo_user_ids(Some("1000"), Some("100")), Some((1000, 100)));
+        assert_eq!(sudo_user_ids(None, Some("100")), None);
+        assert_eq!(sudo_user_ids(Some("root"), Some("100")), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Runs `f` after making the file at `path` writable (if it exists), and
+/// restores the file's attributes afterwards.
+///
+/// Files marked as read-only (or hidden/system on Windows) cannot be replaced
+/// directly, which happens with clients unpacked from old installers.
+/// Attributes aren't restored if the file doesn't exist anymore once `f`
+/// returns.
+pub fn with_writable_file<T>(path: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let saved_attributes = match clear_attributes(path) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Failed to clear attributes of '{}': {}", path.display(), e);
+            None
+        }
+    };
+    let result = f();
+    if let Some(attributes) = saved_attributes {
+        if path.exists() {
+            if let Err(e) = restore_attributes(path, attributes) {
+                log::warn!(
+                    "Failed to restore attributes of '{}': {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    result
+}
+
+/// Clears the attributes that prevent a file from being replaced. Returns the
+/// original attributes if some had to be cleared.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn clear_attributes(path: &Path) -> Result<Option<u32>> {
+    use winapi::um::winnt::{
+        FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NORMAL, FILE_ATTRIBUTE_READONLY,
+        FILE_ATTRIBUTE_SYSTEM,
+    };
+    const BLOCKING_ATTRIBUTES: u32 =
+        FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM;
+
+    let attributes = match windows::get_file_attributes(path)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if attributes & BLOCKING_ATTRIBUTES == 0 {
+        return Ok(None);
+    }
+    let mut new_attributes = attributes & !BLOCKING_ATTRIBUTES;
+    if new_attributes == 0 {
+        new_attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    windows::set_file_attributes(path, new_attributes)?;
+    Ok(Some(attributes))
+}
+
+/// This is the Windows version.
+#[cfg(windows)]
+fn restore_attributes(path: &Path, attributes: u32) -> Result<()> {
+    windows::set_file_attributes(path, attributes)
+}
+
+/// Clears the attributes that prevent a file from being replaced. Returns the
+/// original attributes if some had to be cleared.
+///
+/// This is the non-Windows version, only the owner's write permission is
+/// taken into account.
+#[cfg(not(windows))]
+fn clear_attributes(path: &Path) -> Result<Option<u32>> {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => return Ok(None),
+    };
+    let mode = permissions.mode();
+    if mode & 0o200 != 0 {
+        return Ok(None);
+    }
+    permissions.set_mode(mode | 0o200);
+    fs::set_permissions(path, permissions)?;
+    Ok(Some(mode))
+}
+
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn restore_attributes(path: &Path, mode: u32) -> Result<()> {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::{anyhow, Result};
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use winapi::um::fileapi::{GetFileAttributesW, SetFileAttributesW, INVALID_FILE_ATTRIBUTES};
+
+    fn to_u16s<S: AsRef<OsStr>>(s: S) -> Result<Vec<u16>> {
+        let mut result: Vec<u16> = s.as_ref().encode_wide().collect();
+        if result.iter().any(|&u| u == 0) {
+            return Err(anyhow!("strings passed to WinAPI cannot contain NULs"));
+        }
+        result.push(0);
+        Ok(result)
+    }
+
+    /// Returns `None` if the file doesn't exist.
+    pub fn get_file_attributes(path: &Path) -> Result<Option<u32>> {
+        let path = to_u16s(path)?;
+        let attributes = unsafe { GetFileAttributesW(path.as_ptr()) };
+        if attributes == INVALID_FILE_ATTRIBUTES {
+            return Ok(None);
+        }
+        Ok(Some(attributes))
+    }
+
+    pub fn set_file_attributes(path: &Path, attributes: u32) -> Result<()> {
+        let path = to_u16s(path)?;
+        if unsafe { SetFileAttributesW(path.as_ptr(), attributes) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_with_writable_file() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("readonly.txt");
+        fs::write(&file_path, "original").unwrap();
+        let mut permissions = fs::metadata(&file_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&file_path, permissions).unwrap();
+
+        with_writable_file(&file_path, || {
+            assert!(!fs::metadata(&file_path).unwrap().permissions().readonly());
+            Ok(fs::write(&file_path, "updated")?)
+        })
+        .unwrap();
+        assert!(fs::metadata(&file_path).unwrap().permissions().readonly());
+
+        // Errors are forwarded and attributes are restored anyway
+        let result: Result<()> = with_writable_file(&file_path, || Err(anyhow!("failure")));
+        assert!(result.is_err());
+        assert!(fs::metadata(&file_path).unwrap().permissions().readonly());
+
+        // Missing files are left alone
+        let missing_file_path = temp_dir.path().join("missing.txt");
+        with_writable_file(&missing_file_path, || Ok(())).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+const PATCH_LIST_FILE_NAME: &str = "plist.txt";
+
+/// Behavior of the fixture server, to reproduce the conditions of a real
+/// patch server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixtureServerOptions {
+    pub latency: Duration,       // Waited before answering each request
+    pub fail_every: Option<u64>, // Every Nth request is answered with a 503
+}
+
+/// Minimal patch server that serves a patch list and patches from a local
+/// directory, for testing.
+///
+/// The directory's `plist.txt` is served if there's one, otherwise the patch
+/// list contains the directory's THOR files, ordered by name.
+pub struct FixtureServer {
+    listener: TcpListener,
+    directory: PathBuf,
+    options: FixtureServerOptions,
+    request_count: Arc<AtomicU64>,
+}
+
+impl FixtureServer {
+    /// Listens on the loopback interface. Port 0 picks any available port.
+    pub fn bind(directory: &Path, port: u16, options: FixtureServerOptions) -> Result<Self> {
+        if !directory.is_dir() {
+            return Err(anyhow!("'{}' isn't a directory", directory.display()));
+        }
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .with_context(|| format!("Failed to listen on port {}", port))?;
+        Ok(FixtureServer {
+            listener,
+            directory: directory.to_path_buf(),
+            options,
+            request_count: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves requests until the process exits, each connection in a
+    /// separate thread.
+    pub fn serve(self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Err(e) => {
+                    log::warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+            let directory = self.directory.clone();
+            let options = self.options;
+            let request_number = self.request_count.fetch_add(1, Ordering::Relaxed) + 1;
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &directory, options, request_number) {
+                    log::warn!("Failed to handle request: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Serves `directory` until the process exits, and prints the URLs to use in
+/// the patcher's configuration.
+pub fn serve_fixtures(directory: &Path, port: u16, options: FixtureServerOptions) -> Result<()> {
+    let server = FixtureServer::bind(directory, port, options)?;
+    let address = server.local_addr()?;
+    println!("Serving '{}'", directory.display());
+    println!("  plist_url: http://{}/{}", address, PATCH_LIST_FILE_NAME);
+    println!("  patch_url: http://{}/", address);
+    server.serve();
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    directory: &Path,
+    options: FixtureServerOptions,
+    request_number: u64,
+) -> Result<()> {
+    let (method, path) = read_request_line(BufReader::new(&mut stream))?;
+    log::info!("{} {}", method, path);
+    thread::sleep(options.latency);
+    if let Some(fail_every) = options.fail_every {
+        if request_number.checked_rem(fail_every) == Some(0) {
+            return write_head(&mut stream, 503, 0);
+        }
+    }
+    let send_body = match method.as_str() {
+        "GET" => true,
+        "HEAD" => false,
+        _ => return write_head(&mut stream, 405, 0),
+    };
+    // Query strings (e.g., signed URLs) are ignored
+    let file_name = path.split('?').next().unwrap_or_default();
+    let file_name = file_name.trim_start_matches('/');
+    if file_name == PATCH_LIST_FILE_NAME && !directory.join(file_name).is_file() {
+        let patch_list = generate_patch_list(directory)?;
+        write_head(&mut stream, 200, patch_list.len() as u64)?;
+        if send_body {
+            stream.write_all(patch_list.as_bytes())?;
+        }
+        return Ok(());
+    }
+    let file_path = match fixture_path(directory, file_name) {
+        Some(v) => v,
+        None => return write_head(&mut stream, 404, 0),
+    };
+    let mut file = File::open(&file_path)?;
+    write_head(&mut stream, 200, file.metadata()?.len())?;
+    if send_body {
+        std::io::copy(&mut file, &mut stream)?;
+    }
+    Ok(())
+}
+
+/// Reads the method and path of an HTTP/1.x request, and skips its headers.
+/// Requests served by the fixture server have no body.
+fn read_request_line<R: BufRead>(mut reader: R) -> Result<(String, String)> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut words = request_line.split_whitespace();
+    let method = words.next().context("Missing HTTP method")?.to_string();
+    let path = words.next().context("Missing HTTP path")?.to_string();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    Ok((method, path))
+}
+
+/// Returns the path of the file named `file_name` in `directory`, if it
+/// exists. Subdirectories aren't served.
+fn fixture_path(directory: &Path, file_name: &str) -> Option<PathBuf> {
+    if file_name.is_empty() || file_name.contains(&['/', '\\'][..]) || file_name == ".." {
+        return None;
+    }
+    let file_path = directory.join(file_name);
+    if file_path.is_file() {
+        Some(file_path)
+    } else {
+        None
+    }
+}
+
+/// Lists the THOR files of `directory`, ordered by name, in the format of
+/// `plist.txt`.
+fn generate_patch_list(directory: &Path) -> Result<String> {
+    let mut file_names: Vec<String> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|file_name| file_name.to_ascii_lowercase().ends_with(".thor"))
+        .collect();
+    file_names.sort();
+    Ok(file_names
+        .iter()
+        .enumerate()
+        .map(|(i, file_name)| format!("{} {}\n", i + 1, file_name))
+        .collect())
+}
+
+fn write_head<W: Write>(mut writer: W, status_code: u16, content_length: u64) -> Result<()> {
+    let reason = match status_code {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status_code, reason, content_length
+    )?;
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(address: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_fixture_server() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("2021-02.thor"), b"second").unwrap();
+        fs::write(temp_dir.path().join("2021-01.thor"), b"first").unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), b"").unwrap();
+        let options = FixtureServerOptions {
+            latency: Duration::from_millis(0),
+            fail_every: Some(4),
+        };
+        let server = FixtureServer::bind(temp_dir.path(), 0, options).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+
+        let response = get(address, "GET", "/plist.txt");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n1 2021-01.thor\n2 2021-02.thor\n"));
+        let response = get(address, "GET", "/2021-02.thor?signature=abc");
+        assert!(response.ends_with("Content-Length: 6\r\nConnection: close\r\n\r\nsecond"));
+        let response = get(address, "HEAD", "/2021-01.thor");
+        assert!(response.ends_with("Content-Length: 5\r\nConnection: close\r\n\r\n"));
+        // Every 4th request fails
+        let response = get(address, "GET", "/2021-01.thor");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        let response = get(address, "GET", "/../2021-01.thor");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,124 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::process::Command;
+
+use super::config::{HookFailurePolicy, HooksConfiguration};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Runs `commands` (`hooks.pre_update` or `hooks.post_update`) one after the
+/// other, through the system's shell.
+///
+/// Failing commands make this function fail if `hooks.failure_policy` is
+/// `abort`, following commands aren't run in this case.
+pub async fn run_hooks(hooks_config: &HooksConfiguration, commands: &[String]) -> Result<()> {
+    let timeout = Duration::from_secs(hooks_config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let failure_policy = hooks_config
+        .failure_policy
+        .unwrap_or(HookFailurePolicy::Abort);
+    for command in commands {
+        log::info!("Running hook '{}'", command);
+        if let Err(e) = run_hook(command, timeout).await {
+            match failure_policy {
+                HookFailurePolicy::Abort => return Err(e),
+                HookFailurePolicy::Ignore => log::warn!("{:#}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_hook(command: &str, timeout: Duration) -> Result<()> {
+    let mut shell_command = shell_command(command);
+    let output = shell_command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Commands that time out are killed
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, output)
+        .await
+        .map_err(|_| anyhow!("Hook '{}' timed out", command))?
+        .with_context(|| format!("Failed to run hook '{}'", command))?;
+    log::debug!(
+        "Hook '{}' output: {}",
+        command,
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Hook '{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    let mut shell_command = Command::new("cmd");
+    shell_command
+        .arg("/C")
+        .arg(command)
+        .creation_flags(CREATE_NO_WINDOW);
+    shell_command
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut shell_command = Command::new("sh");
+    shell_command.arg("-c").arg(command);
+    shell_command
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+
+    fn hooks_config(failure_policy: HookFailurePolicy) -> HooksConfiguration {
+        HooksConfiguration {
+            pre_update: None,
+            post_update: None,
+            timeout_secs: Some(1),
+            failure_policy: Some(failure_policy),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_hooks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let marker_path = tmp_dir.path().join("marker");
+        let commands = vec![
+            "exit 1".to_string(),
+            format!("touch '{}'", marker_path.display()),
+        ];
+        assert!(
+            run_hooks(&hooks_config(HookFailurePolicy::Abort), &commands)
+                .await
+                .is_err()
+        );
+        assert!(!marker_path.exists());
+        assert!(
+            run_hooks(&hooks_config(HookFailurePolicy::Ignore), &commands)
+                .await
+                .is_ok()
+        );
+        assert!(marker_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_hooks_timeout() {
+        let commands = vec!["sleep 5".to_string()];
+        assert!(
+            run_hooks(&hooks_config(HookFailurePolicy::Abort), &commands)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, USER_AGENT};
+use reqwest::RequestBuilder;
+use url::{Origin, Url};
+
+use super::config::{AuthConfiguration, TorrentConfiguration, WebConfiguration};
+use super::keyring::read_keyring_secret;
+use super::lan_source::LanSource;
+use super::requirements::PATCHER_VERSION;
+use super::resolver::{resolving_proxy, HostResolver};
+use super::stats::SessionStats;
+use super::url_signer::UrlSigner;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_STALL_TIMEOUT_SECS: u64 = 60;
+
+/// Client used to reach patch servers during an update.
+pub struct PatchServerClient {
+    pub http_client: reqwest::Client,
+    pub server_headers: PatchServerHeaders, // Sent along with the requests made to patch servers
+    pub url_signer: Option<UrlSigner>,      // Set if patch files' URLs must be signed
+    pub torrent_config: Option<TorrentConfiguration>, // Set if patches can be downloaded from peers
+    pub lan_source: Option<LanSource>, // Set if patches can be fetched from the local network first
+    pub stats: SessionStats,           // Statistics of the session the client is used for
+    pub stall_timeout: Option<Duration>, // Downloads that receive no data for this long are retried
+}
+
+impl PatchServerClient {
+    pub fn new(web_config: &WebConfiguration) -> Result<PatchServerClient> {
+        let http_client = build_http_client(web_config)?;
+        let url_signer = match &web_config.url_signer_endpoint {
+            None => None,
+            Some(endpoint) => Some(UrlSigner::new(http_client.clone(), endpoint)?),
+        };
+        Ok(PatchServerClient {
+            http_client,
+            server_headers: PatchServerHeaders::from_config(web_config)?,
+            url_signer,
+            torrent_config: web_config.torrent.clone(),
+            lan_source: web_config.lan_source.as_deref().map(LanSource::parse),
+            stats: SessionStats::new(),
+            stall_timeout: stall_timeout(web_config),
+        })
+    }
+
+    /// Starts building a GET request, with the patch servers' headers if
+    /// `url` points to one of them.
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        let request = self.http_client.get(url.clone());
+        self.server_headers.apply(request, &url)
+    }
+
+    /// Starts building a HEAD request, with the patch servers' headers if
+    /// `url` points to one of them.
+    pub fn head(&self, url: Url) -> RequestBuilder {
+        let request = self.http_client.head(url.clone());
+        self.server_headers.apply(request, &url)
+    }
+
+    /// Starts building a GET request for the patch file at `url`, signing the
+    /// URL if required. `url` must not be signed already.
+    pub async fn get_patch_file(&self, url: Url) -> Result<RequestBuilder> {
+        Ok(self.get(self.sign_patch_file_url(url).await?))
+    }
+
+    /// Starts building a HEAD request for the patch file at `url`, signing the
+    /// URL if required. `url` must not be signed already.
+    pub async fn head_patch_file(&self, url: Url) -> Result<RequestBuilder> {
+        Ok(self.head(self.sign_patch_file_url(url).await?))
+    }
+
+    /// Returns the URL to download a patch file from, signing it if required.
+    ///
+    /// Only used right before sending requests, so that URLs are signed
+    /// exactly once.
+    async fn sign_patch_file_url(&self, url: Url) -> Result<Url> {
+        match &self.url_signer {
+            None => Ok(url),
+            Some(url_signer) => url_signer.sign(&url).await,
+        }
+    }
+}
+
+/// Returns `web.stall_timeout_secs`, or None if stall detection is disabled.
+pub fn stall_timeout(web_config: &WebConfiguration) -> Option<Duration> {
+    match web_config
+        .stall_timeout_secs
+        .unwrap_or(DEFAULT_STALL_TIMEOUT_SECS)
+    {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Builds the HTTP client used to reach patch servers, as configured in the
+/// `web` section.
+///
+/// When a host has both IPv6 and IPv4 addresses, connection attempts are raced
+/// ("happy eyeballs"), so a broken IPv6 route only delays the connection
+/// slightly. `force_ipv4` makes the patcher ignore IPv6 addresses altogether.
+///
+/// Hosts listed in `host_overrides` (or all hosts, if `dns_over_https_url` is
+/// set) are reached through a local proxy that resolves their names itself.
+pub fn build_http_client(web_config: &WebConfiguration) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .default_headers(build_default_headers(web_config)?);
+    if web_config.force_ipv4.unwrap_or(false) {
+        // Binding to an IPv4 address filters out the hosts' IPv6 addresses
+        builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+    if let Some(resolver) = HostResolver::from_config(web_config)? {
+        builder = builder.proxy(resolving_proxy(&resolver)?);
+    }
+    builder.build().context("Failed to build the HTTP client")
+}
+
+/// Headers that are only sent to the patch servers (i.e., to the origins of
+/// their `plist_url` and `patch_url`): `extra_headers` and the
+/// `Authorization` header if `auth` is set.
+///
+/// They're attached to each request rather than to the HTTP client, so that
+/// remote patches, LAN sources or URL signers don't receive them.
+#[derive(Clone, Default)]
+pub struct PatchServerHeaders {
+    origins: Vec<Origin>,
+    headers: HeaderMap,
+}
+
+impl PatchServerHeaders {
+    pub fn from_config(web_config: &WebConfiguration) -> Result<PatchServerHeaders> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in web_config.extra_headers.iter().flatten() {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name '{}'", name))?;
+            let header_value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header '{}'", name))?;
+            headers.insert(header_name, header_value);
+        }
+        if let Some(auth_config) = &web_config.auth {
+            headers.insert(AUTHORIZATION, build_authorization_header(auth_config)?);
+        }
+        // Invalid URLs are reported when the patch servers are probed
+        let origins = web_config
+            .patch_servers
+            .iter()
+            .flat_map(|server| vec![&server.plist_url, &server.patch_url])
+            .filter_map(|url| Url::parse(url).ok())
+            .map(|url| url.origin())
+            .collect();
+        Ok(PatchServerHeaders { origins, headers })
+    }
+
+    /// Adds the headers to `request` if it's sent to one of the patch servers.
+    ///
+    /// Note: the HTTP client drops the `Authorization` header itself when
+    /// following a redirection to another host.
+    pub fn apply(&self, request: RequestBuilder, url: &Url) -> RequestBuilder {
+        if self.headers.is_empty() || !self.origins.contains(&url.origin()) {
+            return request;
+        }
+        request.headers(self.headers.clone())
+    }
+}
+
+/// Returns the headers sent with every request, which is only the
+/// `User-Agent` (which defaults to "rpatchur/<version>").
+fn build_default_headers(web_config: &WebConfiguration) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let user_agent = web_config
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("rpatchur/{}", PATCHER_VERSION));
+    headers.insert(
+        USER_AGENT,
+        HeaderValue::from_str(&user_agent)
+            .with_context(|| format!("Invalid user agent '{}'", user_agent))?,
+    );
+    Ok(headers)
+}
+
+fn build_authorization_header(auth_config: &AuthConfiguration) -> Result<HeaderValue> {
+    let secret = match &auth_config.keyring_entry {
+        Some(entry) => Some(
+            read_keyring_secret(entry)
+                .with_context(|| format!("Failed to read '{}' from the keyring", entry))?,
+        ),
+        None => None,
+    };
+    let authorization = match &auth_config.username {
+        Some(username) => {
+            let password = secret
+                .as_ref()
+                .or(auth_config.password.as_ref())
+                .map(String::as_str)
+                .unwrap_or_default();
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )
+        }
+        None => {
+            let token = secret
+                .as_ref()
+                .or(auth_config.token.as_ref())
+                .context("A username or a token is required for authentication")?;
+            format!("Bearer {}", token)
+        }
+    };
+    let mut header_value =
+        HeaderValue::from_str(&authorization).context("Invalid authentication token")?;
+    // Keeps the credentials out of debug output
+    header_value.set_sensitive(true);
+    Ok(header_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn web_config(yaml: &str) -> WebConfiguration {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_build_default_headers() {
+        let headers =
+            build_default_headers(&web_config("{index_url: '', patch_servers: []}")).unwrap();
+        assert_eq!(
+            headers[USER_AGENT],
+            format!("rpatchur/{}", PATCHER_VERSION).as_str()
+        );
+
+        let headers = build_default_headers(&web_config(
+            "{index_url: '', patch_servers: [], user_agent: MyServerPatcher/1.0, \
+             extra_headers: {X-Patcher-Key: abc}}",
+        ))
+        .unwrap();
+        assert_eq!(headers[USER_AGENT], "MyServerPatcher/1.0");
+        assert!(!headers.contains_key("x-patcher-key"));
+    }
+
+    #[test]
+    fn test_patch_server_headers() {
+        let server_headers = PatchServerHeaders::from_config(&web_config(
+            "{index_url: '', patch_servers: [{name: main, \
+             plist_url: 'https://patch.example.com/plist.txt', \
+             patch_url: 'https://cdn.example.com:8443/patches/'}], \
+             extra_headers: {X-Patcher-Key: abc}, auth: {token: abc123}}",
+        ))
+        .unwrap();
+        let client = reqwest::Client::new();
+        let headers = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            server_headers
+                .apply(client.get(url.clone()), &url)
+                .build()
+                .unwrap()
+                .headers()
+                .clone()
+        };
+        let headers_for_server = headers("https://patch.example.com/plist.txt");
+        assert_eq!(headers_for_server["x-patcher-key"], "abc");
+        assert_eq!(headers_for_server[AUTHORIZATION], "Bearer abc123");
+        assert!(headers("https://cdn.example.com:8443/patches/1.thor").contains_key(AUTHORIZATION));
+        // Other origins, such as remote patches' or another port of the server
+        assert!(headers("https://example.org/hotfix.thor").is_empty());
+        assert!(headers("https://cdn.example.com/patches/1.thor").is_empty());
+        assert!(headers("http://patch.example.com/plist.txt").is_empty());
+
+        assert!(PatchServerHeaders::from_config(&web_config(
+            "{index_url: '', patch_servers: [], extra_headers: {'Bad Header': abc}}"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_authorization_header() {
+        let auth_config = |yaml: &str| -> AuthConfiguration { serde_yaml::from_str(yaml).unwrap() };
+        assert_eq!(
+            build_authorization_header(&auth_config("{username: beta, password: secret}")).unwrap(),
+            "Basic YmV0YTpzZWNyZXQ="
+        );
+        assert_eq!(
+            build_authorization_header(&auth_config("{token: abc123}")).unwrap(),
+            "Bearer abc123"
+        );
+        assert!(build_authorization_header(&auth_config("{password: secret}")).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::ThorPatchInfo;
+use serde::{Deserialize, Serialize};
+
+use super::elevation::share_with_unelevated_user;
+
+/// Journal of an update, which lets the next update resume where an
+/// interrupted one stopped (e.g., if the patcher has been killed).
+///
+/// Patches are downloaded to a directory that outlives the update, and the
+/// journal records which patches have been downloaded and which entries of the
+/// patch being installed have been applied. The journal is an append-only
+/// file with one JSON record per line, so that a crash can only lose the last
+/// record.
+pub struct InstallationJournal {
+    path: PathBuf,
+    download_directory: PathBuf,
+    file: Option<Mutex<File>>, // None if updates aren't resumable
+    state: JournalState,
+}
+
+/// What's left to resume, built from the journal's records.
+#[derive(Default)]
+struct JournalState {
+    downloaded_patches: HashMap<usize, (String, u64)>, // Index -> File name, Size
+    applied_entries: HashMap<usize, HashSet<String>>, // Index -> Entries of patches not fully applied
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum JournalRecord {
+    Downloaded {
+        index: usize,
+        file_name: String,
+        size: u64,
+    },
+    EntryApplied {
+        index: usize,
+        entry: String,
+    },
+    PatchApplied {
+        index: usize,
+    },
+}
+
+impl InstallationJournal {
+    /// Opens the journal at `path`, left by a previous update if any, and
+    /// creates `download_directory` if needed.
+    pub fn open(path: PathBuf, download_directory: PathBuf) -> Result<InstallationJournal> {
+        let records = match fs::read_to_string(&path) {
+            Ok(content) => parse_records(&content),
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| "Failed to read the installation journal"),
+        };
+        let mut state = JournalState::default();
+        for record in records {
+            state.replay(record);
+        }
+        if !state.downloaded_patches.is_empty() {
+            log::info!("Resuming an interrupted update");
+        }
+        // Rewrite the records that still matter, which also gets rid of a
+        // record truncated by a crash
+        let file = compact_journal_file(&path, &state.live_records())?;
+        fs::create_dir_all(&download_directory)
+            .with_context(|| "Failed to create the download directory")?;
+        share_with_unelevated_user(&download_directory);
+        Ok(InstallationJournal {
+            path,
+            download_directory,
+            file: Some(Mutex::new(file)),
+            state,
+        })
+    }
+
+    /// Returns a journal that doesn't record anything, for updates that can't
+    /// be resumed. Patches are downloaded to `download_directory`, which is
+    /// left alone.
+    pub fn disabled(download_directory: PathBuf) -> InstallationJournal {
+        InstallationJournal {
+            path: PathBuf::new(),
+            download_directory,
+            file: None,
+            state: JournalState::default(),
+        }
+    }
+
+    pub fn download_directory(&self) -> &Path {
+        &self.download_directory
+    }
+
+    /// Returns the path of the patch described by `patch_info` if it's been
+    /// downloaded during an interrupted update and is still there, untouched.
+    pub fn downloaded_patch(&self, patch_info: &ThorPatchInfo) -> Option<PathBuf> {
+        let (file_name, size) = self.state.downloaded_patches.get(&patch_info.index)?;
+        if file_name != &patch_info.file_name {
+            return None;
+        }
+        let local_file_path = self.download_directory.join(file_name);
+        match fs::metadata(&local_file_path) {
+            Ok(metadata) if metadata.len() == *size => Some(local_file_path),
+            _ => None,
+        }
+    }
+
+    /// Returns the entries of the patch at `index` that have been applied
+    /// during an interrupted update.
+    pub fn applied_entries(&self, index: usize) -> HashSet<String> {
+        self.state
+            .applied_entries
+            .get(&index)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn record_download(
+        &self,
+        patch_info: &ThorPatchInfo,
+        local_file_path: &Path,
+    ) -> Result<()> {
+        let size = fs::metadata(local_file_path)?.len();
+        self.append(
+            JournalRecord::Downloaded {
+                index: patch_info.index,
+                file_name: patch_info.file_name.clone(),
+                size,
+            },
+            true,
+        )
+    }
+
+    pub fn record_entry_applied(&self, index: usize, entry: &str) -> Result<()> {
+        self.append(
+            JournalRecord::EntryApplied {
+                index,
+                entry: entry.to_string(),
+            },
+            false,
+        )
+    }
+
+    /// Records that the patch at `index` has been applied (and thus saved in
+    /// the patcher cache), and removes its local file.
+    pub fn record_patch_applied(&self, index: usize, local_file_path: &Path) -> Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        self.append(JournalRecord::PatchApplied { index }, true)?;
+        match fs::remove_file(local_file_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the journal and the downloaded patches, once the update is
+    /// complete.
+    pub fn remove(self) -> Result<()> {
+        match self.file {
+            Some(_) => remove_journal(&self.path, &self.download_directory),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends `record` to the journal. Records that can't be lost without
+    /// downloading or applying a whole patch again are synced to disk.
+    fn append(&self, record: JournalRecord, sync: bool) -> Result<()> {
+        let file = match &self.file {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let file = file
+            .lock()
+            .map_err(|_| anyhow!("Installation journal is poisoned"))?;
+        (&*file).write_all(line.as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl JournalState {
+    fn replay(&mut self, record: JournalRecord) {
+        match record {
+            JournalRecord::Downloaded {
+                index,
+                file_name,
+                size,
+            } => {
+                self.downloaded_patches.insert(index, (file_name, size));
+            }
+            JournalRecord::EntryApplied { index, entry } => {
+                self.applied_entries.entry(index).or_default().insert(entry);
+            }
+            JournalRecord::PatchApplied { index } => {
+                self.downloaded_patches.remove(&index);
+                self.applied_entries.remove(&index);
+            }
+        }
+    }
+
+    fn live_records(&self) -> Vec<JournalRecord> {
+        let mut records: Vec<JournalRecord> = self
+            .downloaded_patches
+            .iter()
+            .map(|(&index, (file_name, size))| JournalRecord::Downloaded {
+                index,
+                file_name: file_name.clone(),
+                size: *size,
+            })
+            .collect();
+        for (&index, entries) in &self.applied_entries {
+            records.extend(entries.iter().map(|entry| JournalRecord::EntryApplied {
+                index,
+                entry: entry.clone(),
+            }));
+        }
+        records
+    }
+}
+
+/// Removes the journal at `journal_path` and the patches downloaded to
+/// `download_directory`. Files that don't exist are ignored.
+pub fn remove_journal(journal_path: &Path, download_directory: &Path) -> Result<()> {
+    match fs::remove_dir_all(download_directory) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    match fs::remove_file(journal_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Parses the records of a journal, up to the first invalid line (e.g., a
+/// record that was being written when the patcher crashed).
+fn parse_records(content: &str) -> Vec<JournalRecord> {
+    content
+        .lines()
+        .map(serde_json::from_str)
+        .take_while(|record| record.is_ok())
+        .filter_map(|record| record.ok())
+        .collect()
+}
+
+/// Replaces the journal file at `path` with `records` and returns it, opened
+/// for appending.
+fn compact_journal_file(path: &Path, records: &[JournalRecord]) -> Result<File> {
+    let mut content = String::new();
+    for record in records {
+        content.push_str(&serde_json::to_string(record)?);
+        content.push('\n');
+    }
+    fs::write(path, content).with_context(|| "Failed to write the installation journal")?;
+    share_with_unelevated_user(path);
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::patch_list_from_string;
+
+    #[test]
+    fn test_parse_records() {
+        let content = concat!(
+            r#"{"downloaded":{"index":1,"file_name":"a.thor","size":12}}"#,
+            "\n",
+            r#"{"entry_applied":{"index":1,"entry":"data.ini"}}"#,
+            "\n",
+            r#"{"entry_applied":{"ind"#,
+        );
+        assert_eq!(
+            parse_records(content),
+            vec![
+                JournalRecord::Downloaded {
+                    index: 1,
+                    file_name: "a.thor".to_string(),
+                    size: 12
+                },
+                JournalRecord::EntryApplied {
+                    index: 1,
+                    entry: "data.ini".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resume_from_journal() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let journal_path = tmp_dir.path().join("rpatchur.journal");
+        let download_dir = tmp_dir.path().join("rpatchur.downloads");
+        let patch_list = patch_list_from_string("1 a.thor\n2 b.thor\n").unwrap();
+        {
+            let journal =
+                InstallationJournal::open(journal_path.clone(), download_dir.clone()).unwrap();
+            for patch_info in &patch_list {
+                let local_file_path = download_dir.join(&patch_info.file_name);
+                fs::write(&local_file_path, &patch_info.file_name).unwrap();
+                journal
+                    .record_download(patch_info, &local_file_path)
+                    .unwrap();
+            }
+            journal.record_entry_applied(1, "data.ini").unwrap();
+            journal
+                .record_patch_applied(1, &download_dir.join("a.thor"))
+                .unwrap();
+            journal.record_entry_applied(2, "data\\a.txt").unwrap();
+            // Killed while writing a record
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&journal_path)
+                .unwrap()
+                .write_all(br#"{"entry_applied":"#)
+                .unwrap();
+        }
+
+        let journal =
+            InstallationJournal::open(journal_path.clone(), download_dir.clone()).unwrap();
+        assert!(journal.downloaded_patch(&patch_list[0]).is_none());
+        assert_eq!(
+            journal.downloaded_patch(&patch_list[1]),
+            Some(download_dir.join("b.thor"))
+        );
+        assert!(journal.applied_entries(1).is_empty());
+        assert!(journal.applied_entries(2).contains("data\\a.txt"));
+        // Altered downloads aren't reused
+        fs::write(download_dir.join("b.thor"), "altered").unwrap();
+        assert!(journal.downloaded_patch(&patch_list[1]).is_none());
+        // Records appended after a truncated record aren't lost
+        journal.record_entry_applied(2, "data\\b.txt").unwrap();
+        drop(journal);
+        let journal =
+            InstallationJournal::open(journal_path.clone(), download_dir.clone()).unwrap();
+        assert_eq!(journal.applied_entries(2).len(), 2);
+
+        journal.remove().unwrap();
+        assert!(!journal_path.exists());
+        assert!(!download_dir.exists());
+    }
+
+    #[test]
+    fn test_disabled_journal() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let patch_list = patch_list_from_string("1 a.thor\n").unwrap();
+        let local_file_path = tmp_dir.path().join("a.thor");
+        fs::write(&local_file_path, "a").unwrap();
+        let journal = InstallationJournal::disabled(tmp_dir.path().to_path_buf());
+        journal
+            .record_download(&patch_list[0], &local_file_path)
+            .unwrap();
+        journal.record_patch_applied(1, &local_file_path).unwrap();
+        assert!(journal.downloaded_patch(&patch_list[0]).is_none());
+        journal.remove().unwrap();
+        // Downloads are left to their owner
+        assert!(local_file_path.exists());
+    }
+}
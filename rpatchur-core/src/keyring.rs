@@ -0,0 +1,91 @@
+use anyhow::Result;
+
+/// Reads a secret from the system's credential store.
+///
+/// On Windows, `entry` is the name of a generic credential of the Credential
+/// Manager (e.g., created with `cmdkey /generic:<entry> /user:<user>
+/// /pass:<secret>`).
+#[cfg(windows)]
+pub fn read_keyring_secret(entry: &str) -> Result<String> {
+    windows::read_generic_credential(entry)
+}
+
+/// Reads a secret from the system's credential store.
+///
+/// On macOS, `entry` is the service name of a generic password of the login
+/// keychain (e.g., stored with `security add-generic-password -s <entry> -a
+/// <user> -w <secret>`).
+#[cfg(target_os = "macos")]
+pub fn read_keyring_secret(entry: &str) -> Result<String> {
+    run_credential_tool("security", &["find-generic-password", "-s", entry, "-w"])
+}
+
+/// Reads a secret from the system's credential store.
+///
+/// On other systems, `entry` is the `service` attribute of a secret of the
+/// Secret Service (e.g., stored with `secret-tool store --label=<label>
+/// service <entry>`).
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn read_keyring_secret(entry: &str) -> Result<String> {
+    run_credential_tool("secret-tool", &["lookup", "service", entry])
+}
+
+#[cfg(unix)]
+fn run_credential_tool(program: &str, args: &[&str]) -> Result<String> {
+    use anyhow::{anyhow, Context};
+    use std::process::Command;
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run '{}'", program))?;
+    if !output.status.success() {
+        return Err(anyhow!("Secret not found in the keyring"));
+    }
+    let secret = String::from_utf8(output.stdout).context("Secret is not valid UTF-8")?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::{anyhow, Result};
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use winapi::um::wincred::{CredFree, CredReadW, CRED_TYPE_GENERIC, PCREDENTIALW};
+
+    fn to_u16s<S: AsRef<OsStr>>(s: S) -> Result<Vec<u16>> {
+        let mut result: Vec<u16> = s.as_ref().encode_wide().collect();
+        if result.iter().any(|&u| u == 0) {
+            return Err(anyhow!("strings passed to WinAPI cannot contain NULs"));
+        }
+        result.push(0);
+        Ok(result)
+    }
+
+    pub fn read_generic_credential(target_name: &str) -> Result<String> {
+        let target_name = to_u16s(target_name)?;
+        let mut credential: PCREDENTIALW = ptr::null_mut();
+        if unsafe { CredReadW(target_name.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+            return Err(anyhow!(
+                "Secret not found in the keyring: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let blob = unsafe {
+            let blob_size = (*credential).CredentialBlobSize as usize;
+            if blob_size == 0 {
+                vec![]
+            } else {
+                std::slice::from_raw_parts((*credential).CredentialBlob, blob_size).to_vec()
+            }
+        };
+        unsafe { CredFree(credential as *mut _) };
+        // Passwords stored through the Credential Manager are UTF-16 strings
+        let wide_chars: Vec<u16> = blob
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        String::from_utf16(&wide_chars).map_err(|_| anyhow!("Secret is not valid UTF-16"))
+    }
+}
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::ThorPatchInfo;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use url::Url;
+
+use super::compression::{PatchCompression, StreamDecoder};
+
+/// LAN sources are expected to answer quickly, they're given up on in favor of
+/// the patch server as soon as they stall.
+const LAN_SOURCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Location patches are fetched from before reaching the patch server
+/// (`web.lan_source`), e.g. a share on the local network that another install
+/// keeps up to date.
+pub enum LanSource {
+    Directory(PathBuf), // Local directory or network share
+    Url {
+        url: Url,
+        http_client: reqwest::Client, // Doesn't send the patch server's credentials
+    },
+}
+
+impl LanSource {
+    /// HTTP(S) URLs are fetched from a web server, anything else is a
+    /// directory (e.g., `\\server\patches`).
+    pub fn parse(value: &str) -> LanSource {
+        match Url::parse(value) {
+            Ok(mut url) if url.scheme() == "http" || url.scheme() == "https" => {
+                // Patch file names are joined to the URL
+                if !url.path().ends_with('/') {
+                    let path = format!("{}/", url.path());
+                    url.set_path(&path);
+                }
+                LanSource::Url {
+                    url,
+                    http_client: reqwest::Client::new(),
+                }
+            }
+            _ => LanSource::Directory(PathBuf::from(value)),
+        }
+    }
+
+    /// Fetches the patch described by `patch` to `local_file_path`,
+    /// decompressing it if needed.
+    ///
+    /// Returns the size of the fetched patch.
+    pub async fn fetch_patch(&self, patch: &ThorPatchInfo, local_file_path: &Path) -> Result<u64> {
+        let mut decoder = StreamDecoder::new(PatchCompression::from_file_name(&patch.file_name));
+        let mut local_file = File::create(local_file_path)
+            .await
+            .with_context(|| "Failed to create temporary file")?;
+        match self {
+            LanSource::Directory(directory) => {
+                let mut file = File::open(directory.join(&patch.file_name)).await?;
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    let read_size = timeout(LAN_SOURCE_TIMEOUT, file.read(&mut buffer)).await??;
+                    if read_size == 0 {
+                        break;
+                    }
+                    local_file
+                        .write_all(&decoder.decode(&buffer[..read_size])?)
+                        .await?;
+                }
+            }
+            LanSource::Url { url, http_client } => {
+                let patch_file_url = url.join(&patch.file_name)?;
+                let mut resp =
+                    timeout(LAN_SOURCE_TIMEOUT, http_client.get(patch_file_url).send()).await??;
+                if !resp.status().is_success() {
+                    return Err(anyhow!("{}", resp.status()));
+                }
+                while let Some(chunk) = timeout(LAN_SOURCE_TIMEOUT, resp.chunk()).await?? {
+                    local_file.write_all(&decoder.decode(&chunk[..])?).await?;
+                }
+            }
+        }
+        local_file.write_all(&decoder.finish()?).await?;
+        local_file.sync_all().await?;
+        Ok(local_file.metadata().await?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::patch_list_from_string;
+
+    #[test]
+    fn test_parse() {
+        match LanSource::parse("http://192.168.1.10/patches") {
+            LanSource::Url { url, .. } => assert_eq!(url.as_str(), "http://192.168.1.10/patches/"),
+            LanSource::Directory(_) => panic!("Expected a URL"),
+        }
+        for directory in &["\\\\server\\patches", "C:\\patches", "/mnt/patches"] {
+            assert!(matches!(
+                LanSource::parse(directory),
+                LanSource::Directory(path) if path == Path::new(directory)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_patch_from_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let share_dir = temp_dir.path().join("share");
+        std::fs::create_dir(&share_dir).unwrap();
+        std::fs::write(share_dir.join("1.thor"), b"patch").unwrap();
+        let lan_source = LanSource::parse(share_dir.to_str().unwrap());
+        let patch_list = patch_list_from_string("1 1.thor\n2 2.thor\n").unwrap();
+        let local_file_path = temp_dir.path().join("1.thor");
+        let size = lan_source
+            .fetch_patch(&patch_list[0], &local_file_path)
+            .await
+            .unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(std::fs::read(&local_file_path).unwrap(), b"patch");
+        assert!(lan_source
+            .fetch_patch(&patch_list[1], &temp_dir.path().join("2.thor"))
+            .await
+            .is_err());
+    }
+}
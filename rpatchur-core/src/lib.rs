@@ -0,0 +1,264 @@
+//! Patching engine of rpatchur.
+//!
+//! This crate downloads THOR patches from the configured patch servers and
+//! applies them to the game's files, independently of any UI. Front-ends
+//! create a [`Patcher`] from a [`PatcherConfiguration`] and follow its
+//! progress through their own [`ProgressSink`]:
+//!
+//! ```no_run
+//! use rpatchur_core::{
+//!     retrieve_patcher_configuration, CancellationToken, Patcher, PatchingStatus, ProgressSink,
+//! };
+//!
+//! struct StdoutSink;
+//!
+//! impl ProgressSink for StdoutSink {
+//!     fn dispatch_patching_status(&self, status: PatchingStatus) -> anyhow::Result<()> {
+//!         println!("{}", status.to_json());
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let patcher = Patcher::new(retrieve_patcher_configuration(None)?);
+//! // Calling `cancel_token.cancel()` (e.g., from another thread) cancels the update
+//! let cancel_token = CancellationToken::new();
+//! patcher.update(&StdoutSink, &cancel_token).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Long-running front-ends can instead hand the command channel to
+//! [`Patcher::run`], which waits for [`PatcherCommand`]s (updates, manual
+//! patches, etc.) until it's told to quit.
+
+mod backup;
+mod cache;
+mod cancellation;
+mod compression;
+mod config;
+mod core;
+mod elevation;
+mod file_attributes;
+mod fixtures;
+mod hooks;
+mod http;
+mod journal;
+mod keyring;
+mod lan_source;
+mod manifest;
+mod patching;
+mod plugins;
+mod preview;
+mod process;
+mod progress;
+mod quiet_hours;
+mod recheck;
+mod requirements;
+mod resolver;
+mod restore_point;
+mod stats;
+#[cfg(test)]
+mod test_fixtures;
+mod torrent;
+mod url_signer;
+
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub use self::cancellation::CancellationToken;
+pub use self::config::{
+    retrieve_patcher_configuration, retrieve_sanitized_configuration, ClientTargetConfiguration,
+    CrashWatchdogConfiguration, InstallDetectionConfiguration, NewsConfiguration, PatchServerInfo,
+    PatcherConfiguration, PlayConfiguration, PluginEvent, ServiceInfo, SessionTokenConfiguration,
+    ShortcutsConfiguration, SoundsConfiguration, WindowConfiguration,
+};
+pub use self::core::{
+    get_patcher_state_files, preview_published_patch, remove_patcher_data, reset_patcher_cache,
+    PendingDownloads, UpdateError, UpdateOutcome,
+};
+pub use self::elevation::{is_elevated, share_with_unelevated_user};
+pub use self::fixtures::{serve_fixtures, FixtureServer, FixtureServerOptions};
+pub use self::plugins::{dispatch_plugin_event, PluginActions};
+pub use self::preview::{preview_patch_file, PatchPreview, PatchPreviewEntry};
+pub use self::progress::{PatchingStatus, ProgressSink};
+pub use self::stats::PatchingSummary;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use url::Url;
+
+/// Patching engine, shared by front-ends.
+pub struct Patcher {
+    config: PatcherConfiguration,
+}
+
+impl Patcher {
+    pub fn new(config: PatcherConfiguration) -> Patcher {
+        Patcher { config }
+    }
+
+    pub fn config(&self) -> &PatcherConfiguration {
+        &self.config
+    }
+
+    /// Updates the game (download + patching).
+    ///
+    /// The update is canceled when `cancel_token` is, which is meant to be
+    /// used by one update at a time. Prompts of `progress_sink` are answered
+    /// through `cancel_token` as well.
+    pub async fn update(
+        &self,
+        progress_sink: &dyn ProgressSink,
+        cancel_token: &CancellationToken,
+    ) -> std::result::Result<UpdateOutcome, UpdateError> {
+        if cancel_token.is_canceled() {
+            return Err(UpdateError::Canceled);
+        }
+        let mut commands = cancel_token.commands();
+        self::core::update_game(progress_sink, &self.config, &mut commands).await
+    }
+
+    /// Downloads the patches that the next update would install, without
+    /// installing them. The next update installs them from the download cache.
+    /// `progress_sink` is only told about the downloaded bytes.
+    ///
+    /// Fails without `patching.resumable_updates`.
+    pub async fn download_pending_patches(
+        &self,
+        progress_sink: &dyn ProgressSink,
+    ) -> Result<PendingDownloads> {
+        self::core::download_pending_patches(progress_sink, &self.config, None).await
+    }
+
+    /// Indicates whether the game client is running, in which case patches
+    /// shouldn't be installed.
+    pub fn is_game_client_running(&self) -> Result<bool> {
+        self::core::is_game_client_running(&self.config)
+    }
+
+    /// Executes the commands received through `commands`, until a `Quit`
+    /// command is received or all its senders are dropped.
+    pub async fn run(
+        &self,
+        progress_sink: &dyn ProgressSink,
+        commands: flume::Receiver<PatcherCommand>,
+    ) {
+        self::core::patcher_thread_routine(progress_sink, &self.config, commands).await
+    }
+}
+
+/// Commands that drive the patcher.
+pub enum PatcherCommand {
+    StartUpdate,
+    ForceUpdate,                           // Start an update, even during quiet hours
+    CancelUpdate,                          // Canceled by the user
+    ApplyPatch(PathBuf),                   // Manual patch submitted by the user
+    ApplyRemotePatch(Url),                 // Patch requested through a link
+    PatchFailureReply(PatchFailureAction), // Answer to a `patchFailedPrompt` event
+    CloseProcessesReply(bool),             // Answer to a `closeProcessesPrompt` event
+    CreateRestorePoint,                    // Snapshot GRFs before updating
+    RestoreFromPoint,                      // Undo the updates applied since the restore point
+    RestoreBackups,                        // Put back the files replaced on disk by patches
+    SkipPatch(usize),                      // Skip a known-bad patch during the next updates
+    DefragmentGrfs,                        // Reclaim the space removed GRF entries left unused
+    PrefetchPatches,                       // Download small pending patches in the background
+    RepairGameFiles, // Verify the installed files and install the damaged ones again
+    Quit,            // Exit requested
+}
+
+/// What to do when a patch fails to apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchFailureAction {
+    Retry,
+    Skip,
+    Abort,
+}
+
+/// Environment variable holding the name of the active profile, if any.
+const PROFILE_ENV_VAR: &str = "RPATCHUR_PROFILE";
+
+/// Selects the profile the patcher works with.
+///
+/// Profiles let several servers share the same patcher directory: each profile
+/// has its own configuration (`<patcher>-<profile>.yml`) and keeps its own
+/// cache, downloaded patches, settings, etc. The profile is inherited by the
+/// processes the patcher starts.
+pub fn set_active_profile(profile_name: &str) -> Result<()> {
+    if !is_valid_profile_name(profile_name) {
+        return Err(anyhow!(
+            "Invalid profile name '{}', only letters, digits, '-' and '_' are allowed",
+            profile_name
+        ));
+    }
+    env::set_var(PROFILE_ENV_VAR, profile_name);
+    Ok(())
+}
+
+/// Profile names end up in file names, and in the command lines of shortcuts.
+fn is_valid_profile_name(profile_name: &str) -> bool {
+    !profile_name.is_empty()
+        && profile_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the name of the active profile, if any.
+pub fn get_active_profile() -> Option<String> {
+    env::var(PROFILE_ENV_VAR)
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// Returns the name files associated with the current 'instance' of the
+/// patcher are named after: the executable's name, followed by the active
+/// profile's name if any (e.g., `rpatchur-myserver`).
+pub fn get_patcher_name() -> Result<OsString> {
+    Ok(patcher_name_for_profile(
+        get_executable_name()?,
+        get_active_profile().as_deref(),
+    ))
+}
+
+fn patcher_name_for_profile(executable_name: OsString, profile_name: Option<&str>) -> OsString {
+    let mut patcher_name = executable_name;
+    if let Some(profile_name) = profile_name {
+        patcher_name.push("-");
+        patcher_name.push(profile_name);
+    }
+    patcher_name
+}
+
+/// Returns the patcher executable's name, without its extension, regardless
+/// of the active profile.
+pub fn get_executable_name() -> Result<OsString> {
+    let current_exe_path = env::current_exe()?;
+    Ok(current_exe_path
+        .file_stem()
+        .context("Current executable path is invalid")?
+        .to_os_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_names() {
+        assert!(is_valid_profile_name("my_server-2"));
+        assert!(!is_valid_profile_name(""));
+        assert!(!is_valid_profile_name("../server"));
+        assert!(!is_valid_profile_name("my server"));
+        assert!(set_active_profile("my server").is_err());
+
+        assert_eq!(
+            patcher_name_for_profile("rpatchur".into(), None),
+            OsString::from("rpatchur")
+        );
+        assert_eq!(
+            patcher_name_for_profile("rpatchur".into(), Some("my_server")),
+            OsString::from("rpatchur-my_server")
+        );
+    }
+}
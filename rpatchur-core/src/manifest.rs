@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::GrfArchive;
+use gruf::thor::{ThorArchive, ThorFileEntry};
+use serde::{Deserialize, Serialize};
+
+use super::elevation::share_with_unelevated_user;
+use super::patching::{normalize_entry_name, resolve_disk_entries};
+
+/// Target of the files patched outside of GRFs.
+const DISK_TARGET: &str = "";
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Files installed by patches along with their checksum, with which the game's
+/// files can be verified and the damaged ones installed again.
+#[derive(Serialize, Deserialize, Default)]
+pub struct InstallManifest {
+    // GRF name (or `DISK_TARGET`) -> File path -> Installed file
+    targets: BTreeMap<String, BTreeMap<String, InstalledFile>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct InstalledFile {
+    crc32: u32,
+    patch_index: Option<usize>, // None for manual and remote patches
+}
+
+/// File whose content doesn't match the manifest anymore (or that can't be
+/// read at all).
+#[derive(Debug, PartialEq)]
+pub struct DamagedFile {
+    pub grf_name: Option<String>, // None for files patched outside of GRFs
+    pub path: String,
+    pub patch_index: Option<usize>, // Patch that installed the file
+}
+
+impl InstallManifest {
+    pub fn is_empty(&self) -> bool {
+        self.targets.values().all(|files| files.is_empty())
+    }
+
+    /// Records the entries a THOR patch merged into the GRF named `grf_name`.
+    pub fn record_grf_patch<R: Read + Seek>(
+        &mut self,
+        grf_name: &str,
+        thor_archive: &mut ThorArchive<R>,
+        patch_index: Option<usize>,
+    ) -> Result<()> {
+        let entries: Vec<(ThorFileEntry, String)> = thor_archive
+            .get_entries()
+            .filter(|e| !e.is_internal())
+            .map(|e| (e.clone(), normalize_entry_name(&e.relative_path)))
+            .collect();
+        self.record_entries(grf_name, thor_archive, entries, patch_index)
+    }
+
+    /// Records the entries a THOR patch extracted to `root_directory` (see
+    /// `apply_patch_to_disk`).
+    pub fn record_disk_patch<R: Read + Seek>(
+        &mut self,
+        root_directory: &Path,
+        code_page: Option<&str>,
+        thor_archive: &mut ThorArchive<R>,
+        patch_index: Option<usize>,
+    ) -> Result<()> {
+        let entries = resolve_disk_entries(root_directory, code_page, thor_archive)?
+            .into_iter()
+            .map(|(entry, dest_path)| {
+                let relative_path = dest_path.strip_prefix(root_directory)?;
+                Ok((entry, relative_path.to_string_lossy().into_owned()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.record_entries(DISK_TARGET, thor_archive, entries, patch_index)
+    }
+
+    fn record_entries<R: Read + Seek>(
+        &mut self,
+        target: &str,
+        thor_archive: &mut ThorArchive<R>,
+        entries: Vec<(ThorFileEntry, String)>,
+        patch_index: Option<usize>,
+    ) -> Result<()> {
+        let files = self.targets.entry(target.to_string()).or_default();
+        for (entry, path) in entries {
+            if entry.is_removed {
+                files.remove(&path);
+                continue;
+            }
+            let content = thor_archive.read_file_content(&entry.relative_path)?;
+            files.insert(
+                path,
+                InstalledFile {
+                    crc32: checksum(&content),
+                    patch_index,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks the recorded files against their checksum, on a pool of worker
+    /// threads (one per core). GRFs are looked up in `grf_directory`, and the
+    /// other files in `disk_root_directory`.
+    ///
+    /// `progress_callback` is called with the number of files checked so far
+    /// and the total number of files.
+    pub fn find_damaged_files<CB: FnMut(usize, usize)>(
+        &self,
+        grf_directory: &Path,
+        disk_root_directory: &Path,
+        mut progress_callback: CB,
+    ) -> Result<Vec<DamagedFile>> {
+        let files: Vec<(&str, &str, InstalledFile)> = self
+            .targets
+            .iter()
+            .flat_map(|(target, files)| {
+                files
+                    .iter()
+                    .map(move |(path, file)| (target.as_str(), path.as_str(), *file))
+            })
+            .collect();
+        let file_count = files.len();
+        // Workers take the next file to check as they go, since file sizes vary
+        let next_file = AtomicUsize::new(0);
+        let checked_count = AtomicUsize::new(0);
+        let damaged_files = Mutex::new(vec![]);
+        let worker_count = thread::available_parallelism().map_or(1, |n| n.get());
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..worker_count.min(file_count))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut grf_archives = HashMap::new();
+                        loop {
+                            let file_number = next_file.fetch_add(1, Ordering::Relaxed);
+                            let (target, path, file) = match files.get(file_number) {
+                                None => break,
+                                Some(v) => *v,
+                            };
+                            let res = if target == DISK_TARGET {
+                                disk_file_checksum(&disk_root_directory.join(path))
+                            } else {
+                                let grf_archive = grf_archives
+                                    .entry(target)
+                                    .or_insert_with(|| open_grf(&grf_directory.join(target)));
+                                grf_entry_checksum(grf_archive, path)
+                            };
+                            let is_damaged = match res {
+                                Ok(crc32) => crc32 != file.crc32,
+                                Err(e) => {
+                                    log::debug!("Failed to check '{}': {:#}", path, e);
+                                    true
+                                }
+                            };
+                            if is_damaged {
+                                if let Ok(mut damaged_files) = damaged_files.lock() {
+                                    damaged_files.push(DamagedFile {
+                                        grf_name: Some(target.to_string())
+                                            .filter(|t| t != DISK_TARGET),
+                                        path: path.to_string(),
+                                        patch_index: file.patch_index,
+                                    });
+                                }
+                            }
+                            checked_count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    })
+                })
+                .collect();
+            while !workers.iter().all(|worker| worker.is_finished()) {
+                progress_callback(checked_count.load(Ordering::Relaxed), file_count);
+                thread::sleep(PROGRESS_INTERVAL);
+            }
+            for worker in workers {
+                worker
+                    .join()
+                    .map_err(|_| anyhow!("File verification thread panicked"))?;
+            }
+            Ok::<(), anyhow::Error>(())
+        })?;
+        progress_callback(file_count, file_count);
+        let mut damaged_files = damaged_files
+            .into_inner()
+            .map_err(|_| anyhow!("File verification thread panicked"))?;
+        damaged_files.sort_unstable_by(|a, b| (&a.grf_name, &a.path).cmp(&(&b.grf_name, &b.path)));
+        Ok(damaged_files)
+    }
+}
+
+/// GRF opened by a verification worker, along with its entry names indexed by
+/// their normalized name, since patch entries can be merged under the name of
+/// the GRF entry they duplicate.
+type OpenedGrf = Result<(GrfArchive, HashMap<String, String>), String>;
+
+fn open_grf(grf_file_path: &Path) -> OpenedGrf {
+    let grf_archive = GrfArchive::open_shared(grf_file_path)
+        .map_err(|e| format!("Failed to open '{}': {}", grf_file_path.display(), e))?;
+    let entry_names = grf_archive
+        .get_entries()
+        .map(|e| {
+            (
+                normalize_entry_name(&e.relative_path),
+                e.relative_path.clone(),
+            )
+        })
+        .collect();
+    Ok((grf_archive, entry_names))
+}
+
+fn grf_entry_checksum(grf_archive: &mut OpenedGrf, path: &str) -> Result<u32> {
+    let (grf_archive, entry_names) = grf_archive.as_mut().map_err(|e| anyhow!(e.clone()))?;
+    let entry_name = entry_names
+        .get(path)
+        .ok_or_else(|| anyhow!("Entry is missing"))?;
+    let content = grf_archive.read_file_content(entry_name)?;
+    Ok(checksum(&content))
+}
+
+fn checksum(content: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(content);
+    hasher.finalize()
+}
+
+fn disk_file_checksum(file_path: &Path) -> Result<u32> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read_size => hasher.update(&buffer[..read_size]),
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// Reads the install manifest at `manifest_file_path`. Starts from an empty
+/// manifest if there's none (e.g., for games patched by older versions).
+pub fn read_manifest(manifest_file_path: &Path) -> Result<InstallManifest> {
+    let content = match fs::read(manifest_file_path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(InstallManifest::default()),
+        res => res?,
+    };
+    serde_json::from_slice(&content).with_context(|| {
+        format!(
+            "Invalid install manifest '{}'",
+            manifest_file_path.display()
+        )
+    })
+}
+
+/// Writes the install manifest at `manifest_file_path`, through a temporary
+/// file that then replaces it.
+pub fn write_manifest(manifest_file_path: &Path, manifest: &InstallManifest) -> Result<()> {
+    let mut tmp_file_path = manifest_file_path.as_os_str().to_os_string();
+    tmp_file_path.push(".tmp");
+    let tmp_file_path = PathBuf::from(tmp_file_path);
+    {
+        let mut tmp_file = File::create(&tmp_file_path)?;
+        tmp_file.write_all(&serde_json::to_vec(manifest)?)?;
+        tmp_file.sync_all()?;
+    }
+    fs::rename(&tmp_file_path, manifest_file_path)
+        .with_context(|| "Failed to replace the install manifest")?;
+    share_with_unelevated_user(manifest_file_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateEntryPolicy;
+    use crate::patching::{
+        apply_patch_to_disk, apply_patch_to_grf, GrfPatchingMethod, GrfWriteOptions,
+    };
+    use crate::test_fixtures::{build_disk_patch, build_grf_patch, PatchEntries};
+    use gruf::grf::GrfArchiveBuilder;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_find_damaged_files() {
+        let temp_dir = tempdir().unwrap();
+        let grf_file_path = temp_dir.path().join("data.grf");
+        {
+            let grf_file = File::create(&grf_file_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("DATA\\Sprite.spr".to_string(), &b"original"[..])
+                .unwrap();
+        }
+        let apply_grf_patch = |thor_archive_name: &str, entries: &PatchEntries| {
+            let thor_archive_path = temp_dir.path().join(thor_archive_name);
+            build_grf_patch(&thor_archive_path, entries);
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            apply_patch_to_grf(
+                GrfPatchingMethod::InPlace,
+                false,
+                &GrfWriteOptions {
+                    memory_budget: usize::MAX,
+                    temp_directory: None,
+                    duplicate_entries: DuplicateEntryPolicy::UseGrfName,
+                },
+                &grf_file_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+            thor_archive
+        };
+        let mut manifest = InstallManifest::default();
+        assert!(manifest.is_empty());
+
+        // Merged under the name of the GRF entry it duplicates
+        let mut thor_archive = apply_grf_patch(
+            "grf.thor",
+            &[
+                ("data\\sprite.spr", Some(b"patched")),
+                ("data\\intact.txt", Some(b"intact")),
+                ("data\\damaged.txt", Some(b"damaged")),
+            ],
+        );
+        manifest
+            .record_grf_patch("data.grf", &mut thor_archive, Some(3))
+            .unwrap();
+        let disk_patch_path = temp_dir.path().join("disk.thor");
+        build_disk_patch(
+            &disk_patch_path,
+            &[
+                ("BGM\\01.mp3", Some(b"music")),
+                ("BGM\\02.mp3", Some(b"music")),
+                ("removed.txt", None),
+            ],
+        );
+        let mut thor_archive = ThorArchive::open(&disk_patch_path).unwrap();
+        apply_patch_to_disk(temp_dir.path(), None, &mut thor_archive, |_, _, _| {}).unwrap();
+        manifest
+            .record_disk_patch(temp_dir.path(), None, &mut thor_archive, None)
+            .unwrap();
+        assert!(!manifest.is_empty());
+
+        let manifest_path = temp_dir.path().join("rpatchur.manifest");
+        write_manifest(&manifest_path, &manifest).unwrap();
+        let manifest = read_manifest(&manifest_path).unwrap();
+        let mut last_progress = None;
+        let damaged_files = manifest
+            .find_damaged_files(temp_dir.path(), temp_dir.path(), |checked, total| {
+                last_progress = Some((checked, total))
+            })
+            .unwrap();
+        assert_eq!(damaged_files, vec![]);
+        assert_eq!(last_progress, Some((5, 5)));
+
+        // Damage files behind the manifest's back
+        apply_grf_patch("tamper.thor", &[("data\\damaged.txt", Some(b"tampered"))]);
+        let removed_file_path = PathBuf::from("BGM").join("02.mp3");
+        fs::remove_file(temp_dir.path().join(&removed_file_path)).unwrap();
+        let damaged_files = manifest
+            .find_damaged_files(temp_dir.path(), temp_dir.path(), |_, _| {})
+            .unwrap();
+        assert_eq!(
+            damaged_files,
+            vec![
+                DamagedFile {
+                    grf_name: None,
+                    path: removed_file_path.to_string_lossy().into_owned(),
+                    patch_index: None,
+                },
+                DamagedFile {
+                    grf_name: Some("data.grf".to_string()),
+                    path: "data\\damaged.txt".to_string(),
+                    patch_index: Some(3),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,1771 @@
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::charset;
+use gruf::grf::{rollback_undo_journal, GrfArchive, GrfArchiveBuilder, JournaledFile};
+use gruf::thor::{ThorArchive, ThorArchiveBuilder, ThorFileEntry};
+use gruf::{find_entry_path_issue, EntryPathIssue};
+
+use super::config::{DuplicateEntryPolicy, GrfVerification};
+use super::file_attributes::with_writable_file;
+
+const LOCKED_FILE_RETRIES: usize = 10;
+const LOCKED_FILE_RETRY_DELAY: Duration = Duration::from_millis(500);
+// One entry out of this many is checked with `GrfVerification::Sampled`
+const VERIFICATION_SAMPLE_INTERVAL: usize = 16;
+
+/// Error returned when a file stays in use by another process, typically an
+/// antivirus scanning a file that's just been written.
+#[derive(Debug)]
+pub struct FileLocked {
+    path: PathBuf,
+    source: io::Error,
+}
+
+impl std::error::Error for FileLocked {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl std::fmt::Display for FileLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' is in use by another process: {}",
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+/// Indicates the method that should be used when patching GRF files.
+pub enum GrfPatchingMethod {
+    OutOfPlace(GrfVerification), // Entries of the rebuilt GRF to check before replacing the original
+    InPlace,
+}
+
+/// How entries are written to GRFs.
+pub struct GrfWriteOptions<'a> {
+    pub memory_budget: usize, // Bytes of entry data held in memory, bigger entries are copied in chunks
+    pub temp_directory: Option<&'a Path>, // Where big entries and rebuilt GRFs are staged (the system's temporary directory if None)
+    pub duplicate_entries: DuplicateEntryPolicy, // Patch entries named like GRF entries but for case or path separators
+}
+
+/// Indicates the type of archive a "file" comes from.
+enum MergeEntrySource {
+    GrfArchive,
+    ThorArchive,
+}
+
+struct MergeEntry {
+    pub source: MergeEntrySource,
+    pub size: u64, // Size of the entry's (raw) data
+}
+
+/// Patches a GRF file with a THOR archive/patch.
+///
+/// Entries bigger than `options.memory_budget` bytes are copied in chunks
+/// instead of being read into memory at once, or staged in
+/// `options.temp_directory`.
+///
+/// Patch entries whose name only differs from the name of a GRF entry (or of
+/// another patch entry) by case or path separators are reported, and handled
+/// as told by `options.duplicate_entries`: the client would load either of
+/// them.
+///
+/// Patches that only remove entries are applied in-place whatever the
+/// patching method, since only the GRF's file table has to be updated.
+///
+/// `progress_callback` is called after each entry with the number of entries
+/// processed, the total number of entries and the number of bytes written so
+/// far.
+///
+/// Returns the number of bytes left unused in the GRF (e.g., by removed
+/// entries), which `defragment_grf` reclaims.
+pub fn apply_patch_to_grf<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    patching_method: GrfPatchingMethod,
+    create_if_needed: bool,
+    options: &GrfWriteOptions,
+    grf_file_path: impl AsRef<Path>,
+    thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
+) -> Result<u64> {
+    if !grf_file_path.as_ref().exists() {
+        if !create_if_needed {
+            return Err(anyhow!(
+                "GRF '{}' does not exist",
+                grf_file_path.as_ref().display()
+            ));
+        }
+        // Create a new GRF file if needed
+        let new_grf = fs::File::create(&grf_file_path)?;
+        GrfArchiveBuilder::create(new_grf, 2, 0)?;
+    }
+    let patching_method = match patching_method {
+        GrfPatchingMethod::OutOfPlace(_) if is_removal_only(thor_archive) => {
+            log::info!("Patch only removes entries, patching GRF in-place");
+            GrfPatchingMethod::InPlace
+        }
+        patching_method => patching_method,
+    };
+    with_writable_file(grf_file_path.as_ref(), || match patching_method {
+        GrfPatchingMethod::InPlace => apply_patch_to_grf_ip(
+            &grf_file_path,
+            options,
+            thor_archive,
+            &mut progress_callback,
+        ),
+        GrfPatchingMethod::OutOfPlace(verification) => apply_patch_to_grf_oop(
+            &grf_file_path,
+            options,
+            verification,
+            thor_archive,
+            &mut progress_callback,
+        )
+        // Rebuilt GRFs don't have unused space
+        .map(|_| 0),
+    })
+}
+
+/// Rebuilds the GRF at `grf_file_path` out-of-place, which reclaims the space
+/// left unused by removed entries. The rebuilt GRF is checked as set by
+/// `verification` before replacing the original.
+pub fn defragment_grf<CB: FnMut(usize, usize, u64)>(
+    options: &GrfWriteOptions,
+    grf_file_path: impl AsRef<Path>,
+    verification: GrfVerification,
+    progress_callback: CB,
+) -> Result<()> {
+    // Merging an empty patch only copies the GRF's entries
+    let mut empty_patch = io::Cursor::new(vec![]);
+    ThorArchiveBuilder::new(&mut empty_patch, true, None, false)?.finish()?;
+    empty_patch.set_position(0);
+    let mut thor_archive = ThorArchive::new(empty_patch)?;
+    with_writable_file(grf_file_path.as_ref(), || {
+        apply_patch_to_grf_oop(
+            &grf_file_path,
+            options,
+            verification,
+            &mut thor_archive,
+            progress_callback,
+        )
+    })
+}
+
+/// Returns true if all the entries of `thor_archive` are removals.
+fn is_removal_only<R: Read + Seek>(thor_archive: &ThorArchive<R>) -> bool {
+    let mut entries = thor_archive.get_entries().filter(|e| !e.is_internal());
+    // Empty patches don't remove anything either
+    entries.next().map(|e| e.is_removed) == Some(true) && entries.all(|e| e.is_removed)
+}
+
+/// Finds the entries of `thor_archive` whose name only differs from the name
+/// of a GRF entry (among `grf_entry_names`), or from the name of another entry
+/// of the patch, by case or path separators.
+///
+/// Fails if the patch has such entries itself, since there's no telling which
+/// one the client should load, unless `policy` is `KeepBoth`. With `Reject`,
+/// entries duplicating GRF entries make it fail as well.
+///
+/// Returns the names of the entries duplicating GRF entries, mapped to the
+/// names of the GRF entries they duplicate, unless `policy` is `KeepBoth`.
+fn resolve_duplicate_entries<'a, R: Read + Seek>(
+    policy: DuplicateEntryPolicy,
+    grf_entry_names: impl Iterator<Item = &'a str>,
+    thor_archive: &ThorArchive<R>,
+) -> Result<HashMap<String, String>> {
+    let mut patch_entry_names: HashMap<String, &str> = HashMap::new();
+    let mut patch_duplicates = vec![];
+    for entry in thor_archive
+        .get_entries()
+        .filter(|e| !e.is_internal() && !e.is_removed)
+    {
+        match patch_entry_names.entry(normalize_entry_name(&entry.relative_path)) {
+            hash_map::Entry::Occupied(o) => {
+                patch_duplicates.push(format!("'{}' and '{}'", o.get(), entry.relative_path));
+            }
+            hash_map::Entry::Vacant(v) => {
+                v.insert(&entry.relative_path);
+            }
+        }
+    }
+    if !patch_duplicates.is_empty() {
+        let message = format!(
+            "Patch entries have the same name but for case or path separators: {}",
+            patch_duplicates.join(", ")
+        );
+        if policy != DuplicateEntryPolicy::KeepBoth {
+            return Err(anyhow!(message));
+        }
+        log::warn!("{}", message);
+    }
+
+    let grf_entry_names: HashMap<String, &str> = grf_entry_names
+        .map(|entry_name| (normalize_entry_name(entry_name), entry_name))
+        .collect();
+    let mut duplicates = HashMap::new();
+    for entry in thor_archive.get_entries().filter(|e| !e.is_internal()) {
+        match grf_entry_names.get(&normalize_entry_name(&entry.relative_path)) {
+            Some(&grf_entry_name) if grf_entry_name != entry.relative_path => {
+                log::warn!(
+                    "Patch entry '{}' duplicates GRF entry '{}'",
+                    entry.relative_path,
+                    grf_entry_name
+                );
+                duplicates.insert(entry.relative_path.clone(), grf_entry_name.to_string());
+            }
+            _ => {}
+        }
+    }
+    match policy {
+        DuplicateEntryPolicy::KeepBoth => Ok(HashMap::new()),
+        DuplicateEntryPolicy::Reject if !duplicates.is_empty() => {
+            let mut duplicates: Vec<String> = duplicates
+                .iter()
+                .map(|(patch_entry_name, grf_entry_name)| {
+                    format!("'{}' ('{}' in the GRF)", patch_entry_name, grf_entry_name)
+                })
+                .collect();
+            duplicates.sort_unstable();
+            Err(anyhow!(
+                "Patch entries duplicate GRF entries but for case or path separators: {}",
+                duplicates.join(", ")
+            ))
+        }
+        _ => Ok(duplicates),
+    }
+}
+
+pub(crate) fn normalize_entry_name(entry_name: &str) -> String {
+    entry_name.replace('/', "\\").to_ascii_lowercase()
+}
+
+/// Patches a GRF in an in-place manner.
+///
+/// This is faster but produces output of bigger size. The content that gets
+/// overwritten is saved in an undo journal next to the GRF first, so that the
+/// GRF can be put back in its original state in case of error, or by
+/// `recover_interrupted_grf_patch` in case of crash.
+fn apply_patch_to_grf_ip<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    grf_file_path: impl AsRef<Path>,
+    options: &GrfWriteOptions,
+    thor_archive: &mut ThorArchive<R>,
+    progress_callback: CB,
+) -> Result<u64> {
+    let grf_file_path = grf_file_path.as_ref();
+    let journal_path = grf_undo_journal_path(grf_file_path);
+    let mut builder = GrfArchiveBuilder::open_journaled(grf_file_path, &journal_path)?;
+    let res = patch_grf_entries(&mut builder, options, thor_archive, progress_callback)
+        .and_then(|_| Ok(builder.commit()?));
+    if let Err(e) = res {
+        drop(builder);
+        rollback_undo_journal(grf_file_path, &journal_path)
+            .with_context(|| format!("Failed to restore '{}'", grf_file_path.display()))?;
+        return Err(e);
+    }
+    Ok(builder.unused_space())
+}
+
+/// Applies the entries of `thor_archive` to the GRF being patched in-place.
+fn patch_grf_entries<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    builder: &mut GrfArchiveBuilder<JournaledFile>,
+    options: &GrfWriteOptions,
+    thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
+) -> Result<()> {
+    builder.set_memory_budget(options.memory_budget);
+    if let Some(temp_directory) = options.temp_directory {
+        builder.set_temp_directory(temp_directory);
+    }
+    let duplicates = resolve_duplicate_entries(
+        options.duplicate_entries,
+        builder.get_entry_names(),
+        thor_archive,
+    )?;
+    let mut thor_entries: Vec<ThorFileEntry> = thor_archive
+        .get_entries()
+        .filter(|e| !e.is_internal())
+        .cloned()
+        .collect();
+    thor_entries.sort_unstable_by_key(|a| a.offset);
+    let entry_count = thor_entries.len();
+    let mut written_bytes: u64 = 0;
+    for (entry_number, entry) in thor_entries.into_iter().enumerate() {
+        let duplicated_entry_name = duplicates.get(&entry.relative_path);
+        if let Some(duplicated_entry_name) = duplicated_entry_name {
+            let _ = builder.remove_file(duplicated_entry_name);
+        }
+        if entry.is_removed {
+            let _ = builder.remove_file(&entry.relative_path);
+        } else {
+            builder.import_raw_entry_from_thor(thor_archive, entry.relative_path.clone())?;
+            written_bytes += entry.size_compressed as u64;
+            match duplicated_entry_name {
+                Some(duplicated_entry_name)
+                    if options.duplicate_entries == DuplicateEntryPolicy::UseGrfName =>
+                {
+                    builder.rename_file(&entry.relative_path, duplicated_entry_name.clone())?;
+                }
+                _ => {}
+            }
+        }
+        progress_callback(entry_number + 1, entry_count, written_bytes);
+    }
+    Ok(())
+}
+
+/// Patches a GRF in an out-of-place manner.
+///
+/// This is safer and produces output of smaller size but slower. The patched
+/// GRF is built in `options.temp_directory` if set, checked as told by
+/// `verification`, and then moved in place of the original.
+fn apply_patch_to_grf_oop<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    grf_file_path: impl AsRef<Path>,
+    options: &GrfWriteOptions,
+    verification: GrfVerification,
+    thor_archive: &mut ThorArchive<R>,
+    progress_callback: CB,
+) -> Result<()> {
+    let grf_file_path = grf_file_path.as_ref();
+    let merged_grf_file_path = match options.temp_directory {
+        Some(temp_directory) => {
+            fs::create_dir_all(temp_directory)?;
+            let mut file_name = grf_file_path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid GRF path '{}'", grf_file_path.display()))?
+                .to_os_string();
+            file_name.push(".tmp");
+            temp_directory.join(file_name)
+        }
+        None => grf_file_path.to_path_buf(),
+    };
+    // Rename file to back it up
+    let backup_file_path = grf_backup_file_path(grf_file_path);
+    retry_while_locked(grf_file_path, || {
+        fs::rename(grf_file_path, &backup_file_path)
+    })?;
+    let res = build_merged_grf(
+        &merged_grf_file_path,
+        &backup_file_path,
+        options,
+        thor_archive,
+        progress_callback,
+    )
+    .and_then(|renamed_entries| {
+        verify_merged_grf(
+            &merged_grf_file_path,
+            &backup_file_path,
+            thor_archive,
+            &renamed_entries,
+            verification,
+        )
+    })
+    .and_then(|_| match merged_grf_file_path == grf_file_path {
+        true => Ok(()),
+        false => replace_file(&merged_grf_file_path, grf_file_path),
+    });
+    if let Err(e) = res {
+        // Put the original GRF back, so that the patch can be applied again
+        let _ = fs::remove_file(&merged_grf_file_path);
+        let _ = fs::remove_file(grf_file_path);
+        retry_while_locked(grf_file_path, || {
+            fs::rename(&backup_file_path, grf_file_path)
+        })?;
+        return Err(e);
+    }
+    // Remove backup file once the patched GRF has been built
+    Ok(fs::remove_file(backup_file_path)?)
+}
+
+/// Moves the file at `src` to `dest`, replacing it.
+///
+/// Files can't be renamed across volumes (e.g., when `patching.temp_dir` is on
+/// another drive than the game), in which case `src` is copied next to `dest`
+/// first, so that `dest` is still replaced in one go.
+fn replace_file(src: &Path, dest: &Path) -> Result<()> {
+    match retry_while_locked(dest, || fs::rename(src, dest)) {
+        Err(e) if matches!(e.downcast_ref(), Some(e) if is_cross_device_error(e)) => {
+            log::info!(
+                "'{}' is on another volume, copying it to '{}'",
+                src.display(),
+                dest.display()
+            );
+            copy_and_replace_file(src, dest)
+        }
+        res => res,
+    }
+}
+
+/// Copies `src` to a file next to `dest` and then renames the copy to `dest`.
+/// `src` is removed on success.
+fn copy_and_replace_file(src: &Path, dest: &Path) -> Result<()> {
+    let mut part_file_path = dest.as_os_str().to_os_string();
+    part_file_path.push(".part");
+    let part_file_path = PathBuf::from(part_file_path);
+    if let Err(e) = copy_file_verified(src, &part_file_path)
+        .and_then(|_| retry_while_locked(dest, || fs::rename(&part_file_path, dest)))
+    {
+        let _ = fs::remove_file(&part_file_path);
+        return Err(e);
+    }
+    Ok(fs::remove_file(src)?)
+}
+
+/// Copies `src` to `dest` and checks that the copy is identical once it's
+/// been synced to disk.
+fn copy_file_verified(src: &Path, dest: &Path) -> Result<()> {
+    {
+        let mut src_file = fs::File::open(src)?;
+        let mut dest_file = fs::File::create(dest)?;
+        io::copy(&mut src_file, &mut dest_file)?;
+        dest_file.sync_all()?;
+    }
+    if file_crc32(src)? != file_crc32(dest)? {
+        return Err(anyhow!("Copy of '{}' is corrupt", src.display()));
+    }
+    Ok(())
+}
+
+fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize()),
+            read_size => hasher.update(&buffer[..read_size]),
+        }
+    }
+}
+
+/// Calls `op` again while it fails because `path` is in use by another
+/// process. Antivirus scanners commonly open files that have just been
+/// written, which prevents replacing them for a moment.
+///
+/// Fails with `FileLocked` if `path` is still in use after
+/// `LOCKED_FILE_RETRIES` retries.
+fn retry_while_locked<T>(path: &Path, mut op: impl FnMut() -> io::Result<T>) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(e) if is_sharing_violation(&e) => {
+                if retries == LOCKED_FILE_RETRIES {
+                    return Err(FileLocked {
+                        path: path.to_path_buf(),
+                        source: e,
+                    }
+                    .into());
+                }
+                log::warn!(
+                    "'{}' is in use by another process, retrying",
+                    path.display()
+                );
+                retries += 1;
+                thread::sleep(LOCKED_FILE_RETRY_DELAY);
+            }
+            res => return Ok(res?),
+        }
+    }
+}
+
+/// Returns true if `e` has been caused by another process using the file.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn is_sharing_violation(e: &io::Error) -> bool {
+    use winapi::shared::winerror::{ERROR_LOCK_VIOLATION, ERROR_SHARING_VIOLATION};
+    // ERROR_ACCESS_DENIED isn't retried, permission issues wouldn't go away
+    [ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION]
+        .iter()
+        .any(|&code| e.raw_os_error() == Some(code as i32))
+}
+
+/// Returns true if `e` has been caused by another process using the file.
+///
+/// Files in use can be replaced on other platforms.
+#[cfg(not(windows))]
+fn is_sharing_violation(_e: &io::Error) -> bool {
+    false
+}
+
+/// Returns true if `e` has been caused by a rename across volumes.
+fn is_cross_device_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    let cross_device_error = libc::EXDEV;
+    #[cfg(windows)]
+    let cross_device_error = winapi::shared::winerror::ERROR_NOT_SAME_DEVICE as i32;
+    e.raw_os_error() == Some(cross_device_error)
+}
+
+/// Builds a GRF at `grf_file_path` from the entries of the GRF at
+/// `original_grf_file_path` and the entries of a THOR archive.
+///
+/// Returns the names of the patch entries that have been renamed like the
+/// GRF entries they duplicate, indexed by their new name.
+fn build_merged_grf<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    grf_file_path: &Path,
+    original_grf_file_path: &Path,
+    options: &GrfWriteOptions,
+    thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
+) -> Result<HashMap<String, String>> {
+    // Prepare file entries that'll be used to make the patched GRF
+    let mut merge_entries: HashMap<String, MergeEntry> = HashMap::new();
+    let mut grf_archive = GrfArchive::open_shared(original_grf_file_path)?;
+    let duplicates = resolve_duplicate_entries(
+        options.duplicate_entries,
+        grf_archive.get_entries().map(|e| e.relative_path.as_str()),
+        thor_archive,
+    )?;
+    let duplicated_entry_names: HashSet<&String> = duplicates.values().collect();
+    // Add files from the original archive while discarding files remove in the patch
+    for entry in grf_archive.get_entries() {
+        if let Some(e) = thor_archive.get_file_entry(&entry.relative_path) {
+            if e.is_removed {
+                continue;
+            }
+        }
+        // Replaced by the patch entry that duplicates it
+        if duplicated_entry_names.contains(&entry.relative_path) {
+            continue;
+        }
+        merge_entries.insert(
+            entry.relative_path.clone(),
+            MergeEntry {
+                source: MergeEntrySource::GrfArchive,
+                size: entry.size_compressed as u64,
+            },
+        );
+    }
+    // Add files from the patch
+    for entry in thor_archive.get_entries() {
+        if entry.is_removed || entry.is_internal() {
+            continue;
+        }
+        merge_entries.insert(
+            entry.relative_path.clone(),
+            MergeEntry {
+                source: MergeEntrySource::ThorArchive,
+                size: entry.size_compressed as u64,
+            },
+        );
+    }
+
+    let grf_file = fs::File::create(grf_file_path)?;
+    let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0)?;
+    builder.set_memory_budget(options.memory_budget);
+    if let Some(temp_directory) = options.temp_directory {
+        builder.set_temp_directory(temp_directory);
+    }
+    let entry_count = merge_entries.len();
+    let mut written_bytes: u64 = 0;
+    for (entry_number, (relative_path, entry)) in merge_entries.into_iter().enumerate() {
+        match entry.source {
+            MergeEntrySource::GrfArchive => {
+                builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+            }
+            MergeEntrySource::ThorArchive => {
+                builder.import_raw_entry_from_thor(thor_archive, relative_path)?;
+            }
+        }
+        written_bytes += entry.size;
+        progress_callback(entry_number + 1, entry_count, written_bytes);
+    }
+    let mut renamed_entries = HashMap::new();
+    if options.duplicate_entries == DuplicateEntryPolicy::UseGrfName {
+        for (patch_entry_name, grf_entry_name) in duplicates {
+            // Removed entries haven't been imported
+            if builder.rename_file(&patch_entry_name, grf_entry_name.clone())? {
+                renamed_entries.insert(grf_entry_name, patch_entry_name);
+            }
+        }
+    }
+    Ok(renamed_entries)
+}
+
+/// Checks that the entries of the GRF built at `grf_file_path` decompress to
+/// the content they've been copied from (in the THOR archive or in the GRF at
+/// `original_grf_file_path`), to catch disk corruption before the original
+/// GRF is replaced. `renamed_entries` are the patch entries that have been
+/// renamed while building the GRF, indexed by their new name.
+///
+/// Entries copied from the original GRF are checked on a pool of worker
+/// threads (one per core), while the ones copied from the THOR archive are
+/// checked on the calling thread.
+///
+/// Entries whose source can't be read are skipped, since the rebuild isn't at
+/// fault.
+fn verify_merged_grf<R: Read + Seek>(
+    grf_file_path: &Path,
+    original_grf_file_path: &Path,
+    thor_archive: &mut ThorArchive<R>,
+    renamed_entries: &HashMap<String, String>,
+    verification: GrfVerification,
+) -> Result<()> {
+    let sample_interval = match verification {
+        GrfVerification::Never => return Ok(()),
+        GrfVerification::Sampled => VERIFICATION_SAMPLE_INTERVAL,
+        GrfVerification::Always => 1,
+    };
+    // Make sure the data is read back from the disk rather than from buffers
+    // that haven't been flushed
+    fs::OpenOptions::new()
+        .write(true)
+        .open(grf_file_path)?
+        .sync_all()?;
+    let mut merged_grf = GrfArchive::open_shared(grf_file_path)?;
+    let mut entry_paths: Vec<String> = merged_grf
+        .get_entries()
+        .map(|e| e.relative_path.clone())
+        .collect();
+    entry_paths.sort_unstable();
+    let (patch_entry_paths, grf_entry_paths): (Vec<&String>, Vec<&String>) = entry_paths
+        .iter()
+        .step_by(sample_interval)
+        .partition(|entry_path| {
+            let patch_entry_path = renamed_entries.get(*entry_path).unwrap_or(entry_path);
+            matches!(thor_archive.get_file_entry(patch_entry_path), Some(e) if !e.is_removed)
+        });
+    let worker_count = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = grf_entry_paths.len().div_ceil(worker_count).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = grf_entry_paths
+            .chunks(chunk_size)
+            .map(|entry_paths| {
+                scope.spawn(move || -> Result<()> {
+                    let mut merged_grf = GrfArchive::open_shared(grf_file_path)?;
+                    let mut original_grf = GrfArchive::open_shared(original_grf_file_path)?;
+                    for entry_path in entry_paths {
+                        let expected_content = original_grf.read_file_content(entry_path);
+                        verify_merged_grf_entry(&mut merged_grf, entry_path, expected_content)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for entry_path in patch_entry_paths {
+            let patch_entry_path = renamed_entries.get(entry_path).unwrap_or(entry_path);
+            let expected_content = thor_archive.read_file_content(patch_entry_path);
+            verify_merged_grf_entry(&mut merged_grf, entry_path, expected_content)?;
+        }
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| anyhow!("GRF verification thread panicked"))??;
+        }
+        Ok(())
+    })
+}
+
+fn verify_merged_grf_entry(
+    merged_grf: &mut GrfArchive,
+    entry_path: &str,
+    expected_content: gruf::Result<Vec<u8>>,
+) -> Result<()> {
+    let expected_content = match expected_content {
+        Ok(v) => v,
+        Err(e) => {
+            log::debug!("Not verifying '{}': {}", entry_path, e);
+            return Ok(());
+        }
+    };
+    match merged_grf.read_file_content(entry_path) {
+        Ok(content) if content == expected_content => Ok(()),
+        Ok(_) => Err(anyhow!(
+            "Entry '{}' is corrupt in the rebuilt GRF",
+            entry_path
+        )),
+        Err(e) => Err(anyhow!(
+            "Failed to read entry '{}' back from the rebuilt GRF: {}",
+            entry_path,
+            e
+        )),
+    }
+}
+
+/// Patches files located in the game client's directory with a THOR
+/// archive/patch.
+///
+/// Entries are extracted relative to `root_directory`. The whole patch is
+/// rejected (before anything is written) if one of its entries points outside
+/// of it. Entry names are converted from `code_page` to UTF-8, if set.
+///
+/// `progress_callback` is called after each entry, like with
+/// `apply_patch_to_grf`.
+pub fn apply_patch_to_disk<R: Read + Seek, CB: FnMut(usize, usize, u64)>(
+    root_directory: impl AsRef<Path>,
+    code_page: Option<&str>,
+    thor_archive: &mut ThorArchive<R>,
+    progress_callback: CB,
+) -> Result<()> {
+    apply_patch_to_disk_resumable(
+        root_directory,
+        code_page,
+        thor_archive,
+        &HashSet::new(),
+        |_| {},
+        progress_callback,
+    )
+}
+
+/// Patches files located in the game client's directory like
+/// `apply_patch_to_disk`, resuming an interrupted installation of the same
+/// patch.
+///
+/// Entries part of `applied_entries` are skipped, unless the files on disk
+/// show that they haven't actually been applied. `entry_callback` is called
+/// with the name of each entry once it's been applied.
+pub fn apply_patch_to_disk_resumable<
+    R: Read + Seek,
+    ECB: FnMut(&str),
+    CB: FnMut(usize, usize, u64),
+>(
+    root_directory: impl AsRef<Path>,
+    code_page: Option<&str>,
+    thor_archive: &mut ThorArchive<R>,
+    applied_entries: &HashSet<String>,
+    mut entry_callback: ECB,
+    mut progress_callback: CB,
+) -> Result<()> {
+    if let Some(code_page) = code_page.filter(|c| !charset::is_supported_code_page(c)) {
+        return Err(anyhow!("Unknown code page '{}'", code_page));
+    }
+    // TODO(LinkZ): Make async?
+    let disk_entries = resolve_disk_entries(root_directory.as_ref(), code_page, thor_archive)?;
+    let entry_count = disk_entries.len();
+    let mut written_bytes: u64 = 0;
+    for (entry_number, (entry, dest_path)) in disk_entries.into_iter().enumerate() {
+        if applied_entries.contains(&entry.relative_path) && is_entry_on_disk(&entry, &dest_path) {
+            log::debug!("Skipping '{}', already applied", entry.relative_path);
+        } else if entry.is_removed {
+            // Try to remove file and ignore errors (file might not exist)
+            let _ignore = with_writable_file(&dest_path, || Ok(fs::remove_file(&dest_path)?));
+        } else {
+            // Create parent directory if needed
+            if let Some(parent_dir) = dest_path.parent() {
+                fs::create_dir_all(parent_dir)?
+            }
+            // Extract file
+            with_writable_file(&dest_path, || {
+                Ok(thor_archive.extract_file(&entry.relative_path, &dest_path)?)
+            })?;
+            written_bytes += entry.size as u64;
+        }
+        entry_callback(&entry.relative_path);
+        progress_callback(entry_number + 1, entry_count, written_bytes);
+    }
+    Ok(())
+}
+
+/// Quickly checks whether `dest_path` looks like `entry` has been applied:
+/// removed files mustn't exist, and updated files must have the entry's size.
+fn is_entry_on_disk(entry: &ThorFileEntry, dest_path: &Path) -> bool {
+    match fs::metadata(dest_path) {
+        Ok(metadata) => !entry.is_removed && metadata.len() == entry.size as u64,
+        Err(_) => entry.is_removed,
+    }
+}
+
+/// Puts the original GRF back if the patching of `grf_file_path` has been
+/// interrupted (e.g., by a crash). Out-of-place patching leaves the GRF being
+/// built and the original's backup behind, and in-place patching leaves its
+/// undo journal behind.
+///
+/// Returns `true` if the GRF has been recovered.
+pub fn recover_interrupted_grf_patch(grf_file_path: impl AsRef<Path>) -> Result<bool> {
+    let grf_file_path = grf_file_path.as_ref();
+    if rollback_undo_journal(grf_file_path, grf_undo_journal_path(grf_file_path))? {
+        log::warn!(
+            "Restored '{}', its patching has been interrupted",
+            grf_file_path.display()
+        );
+        return Ok(true);
+    }
+    let backup_file_path = grf_backup_file_path(grf_file_path);
+    if !backup_file_path.exists() {
+        return Ok(false);
+    }
+    log::warn!(
+        "Restoring '{}', its patching has been interrupted",
+        grf_file_path.display()
+    );
+    match fs::remove_file(grf_file_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    fs::rename(&backup_file_path, grf_file_path)?;
+    Ok(true)
+}
+
+fn grf_backup_file_path(grf_file_path: &Path) -> PathBuf {
+    let mut backup_file_path = grf_file_path.to_path_buf();
+    backup_file_path.set_extension("grf.bak");
+    backup_file_path
+}
+
+fn grf_undo_journal_path(grf_file_path: &Path) -> PathBuf {
+    let mut journal_path = grf_file_path.to_path_buf();
+    journal_path.set_extension("grf.undo");
+    journal_path
+}
+
+/// Lists the entries of a THOR archive/patch (sorted by offset) along with the
+/// paths they're extracted to, relative to `root_directory`. Entry names are
+/// converted from `code_page` to UTF-8, if set.
+///
+/// Fails if one of the entries points outside of `root_directory`.
+pub fn resolve_disk_entries<R: Read + Seek>(
+    root_directory: &Path,
+    code_page: Option<&str>,
+    thor_archive: &ThorArchive<R>,
+) -> Result<Vec<(ThorFileEntry, PathBuf)>> {
+    let mut file_entries: Vec<ThorFileEntry> = thor_archive
+        .get_entries()
+        .filter(|e| !e.is_internal())
+        .cloned()
+        .collect();
+    file_entries.sort_unstable_by_key(|a| a.offset);
+    file_entries
+        .into_iter()
+        .map(|entry| {
+            let file_path =
+                decode_entry_name(&entry.relative_path, code_page).unwrap_or_else(|e| {
+                    log::warn!("Failed to decode '{}': {}", entry.relative_path, e);
+                    entry.relative_path.clone()
+                });
+            let dest_path = resolve_disk_entry_path(root_directory, &file_path)?;
+            Ok((entry, dest_path))
+        })
+        .collect()
+}
+
+/// Converts an entry name to the name of the file it's extracted to, given the
+/// code page used by the game client. Names are used as is without one.
+fn decode_entry_name(entry_name: &str, code_page: Option<&str>) -> gruf::Result<String> {
+    match code_page {
+        Some(code_page) => charset::decode_entry_name(entry_name, code_page),
+        None => Ok(entry_name.to_string()),
+    }
+}
+
+/// Makes sure that a THOR archive/patch doesn't contain entries whose paths are
+/// unsafe (absolute paths, `..` components, device names, alternate data
+/// streams), for GRF entries as well as for files extracted to disk.
+///
+/// The error lists every rejected entry along with the reason. Names of
+/// entries extracted to disk are converted from `code_page` first, like in
+/// `apply_patch_to_disk`.
+pub fn check_entry_paths<R: Read + Seek>(
+    thor_archive: &ThorArchive<R>,
+    code_page: Option<&str>,
+) -> Result<()> {
+    let mut rejected_entries: Vec<(String, EntryPathIssue)> = thor_archive
+        .get_entries()
+        .filter(|e| !e.is_internal())
+        .filter_map(|entry| {
+            let entry_path = if thor_archive.use_grf_merging() {
+                entry.relative_path.clone()
+            } else {
+                decode_entry_name(&entry.relative_path, code_page)
+                    .unwrap_or_else(|_| entry.relative_path.clone())
+            };
+            find_entry_path_issue(&entry_path).map(|issue| (entry_path, issue))
+        })
+        .collect();
+    if rejected_entries.is_empty() {
+        return Ok(());
+    }
+    rejected_entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let rejected_entries_desc: Vec<String> = rejected_entries
+        .iter()
+        .map(|(entry_path, issue)| format!("'{}' ({})", entry_path, issue))
+        .collect();
+    Err(anyhow!(
+        "Rejected {} unsafe entr{}: {}",
+        rejected_entries.len(),
+        if rejected_entries.len() == 1 {
+            "y"
+        } else {
+            "ies"
+        },
+        rejected_entries_desc.join(", ")
+    ))
+}
+
+/// Adds a GRF to the client's DATA.INI file (with the highest priority), so
+/// that the client actually loads it.
+///
+/// Nothing is done if there's no DATA.INI file in `root_directory`.
+pub fn register_grf_in_data_ini(root_directory: impl AsRef<Path>, grf_name: &str) -> Result<()> {
+    let data_ini_path = match fs::read_dir(root_directory.as_ref())?
+        .filter_map(|e| e.ok())
+        .find(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case("data.ini")
+        }) {
+        Some(entry) => entry.path(),
+        None => return Ok(()),
+    };
+    let content = fs::read_to_string(&data_ini_path)?;
+    if let Some(new_content) = add_grf_to_data_ini(&content, grf_name) {
+        log::info!("Adding '{}' to {}", grf_name, data_ini_path.display());
+        fs::write(&data_ini_path, new_content)?;
+    }
+    Ok(())
+}
+
+/// Inserts `grf_name` at the top of the `[Data]` section of a DATA.INI file's
+/// content, and shifts the other entries.
+///
+/// Returns `None` if the GRF is already listed.
+fn add_grf_to_data_ini(content: &str, grf_name: &str) -> Option<String> {
+    let line_ending = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut lines: Vec<String> = vec![];
+    let mut in_data_section = false;
+    let mut found_data_section = false;
+    for line in content.lines() {
+        let trimmed_line = line.trim();
+        if trimmed_line.starts_with('[') {
+            in_data_section = trimmed_line.eq_ignore_ascii_case("[data]");
+            lines.push(line.to_string());
+            if in_data_section {
+                found_data_section = true;
+                lines.push(format!("0={}", grf_name));
+            }
+            continue;
+        }
+        if in_data_section {
+            if let Some((key, value)) = trimmed_line.split_once('=') {
+                if value.trim().eq_ignore_ascii_case(grf_name) {
+                    return None;
+                }
+                if let Ok(index) = key.trim().parse::<usize>() {
+                    lines.push(format!("{}={}", index + 1, value.trim()));
+                    continue;
+                }
+            }
+        }
+        lines.push(line.to_string());
+    }
+    if !found_data_section {
+        lines.push("[Data]".to_string());
+        lines.push(format!("0={}", grf_name));
+    }
+    let mut new_content = lines.join(line_ending);
+    new_content.push_str(line_ending);
+    Some(new_content)
+}
+
+/// Returns the name of the entry of `directory` that matches `name`
+/// case-insensitively, or `name` if there's no such entry.
+#[cfg(not(windows))]
+fn find_existing_entry_name(directory: &Path, name: &str) -> String {
+    if directory.join(name).exists() {
+        return name.to_string();
+    }
+    let lowercase_name = name.to_lowercase();
+    fs::read_dir(directory)
+        .ok()
+        .and_then(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .find(|entry_name| entry_name.to_lowercase() == lowercase_name)
+        })
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Utility function used to join an entry's path (Windows style, as in the GRF
+/// file format) to `root_directory`.
+///
+/// Fails if the path is unsafe to extract (see `find_entry_path_issue`), even
+/// without `patching.strict_entry_paths`. Existing files and directories are
+/// matched case-insensitively, like on Windows.
+fn resolve_disk_entry_path(root_directory: &Path, entry_path: &str) -> Result<PathBuf> {
+    if let Some(issue) = find_entry_path_issue(entry_path) {
+        return Err(anyhow!("Invalid entry path '{}' ({})", entry_path, issue));
+    }
+    let mut result = PathBuf::from(root_directory);
+    for component in entry_path.split(['\\', '/']) {
+        match component {
+            "" | "." => continue,
+            _ => {
+                #[cfg(not(windows))]
+                let component = find_existing_entry_name(&result, component);
+                result.push(component);
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{build_disk_patch, build_grf_patch};
+    use tempfile::tempdir;
+    use walkdir::WalkDir;
+
+    fn write_options() -> GrfWriteOptions<'static> {
+        GrfWriteOptions {
+            memory_budget: usize::MAX,
+            temp_directory: None,
+            duplicate_entries: DuplicateEntryPolicy::UseGrfName,
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        {
+            let count_files = |dir_path| {
+                WalkDir::new(dir_path)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+                    .filter_map(|entry| entry.metadata().ok())
+                    .filter(|metadata| metadata.is_file())
+                    .count()
+            };
+            let expected_file_path = temp_dir
+                .path()
+                .join("data/wav/se_subterranean_rustyengine.wav");
+            let thor_archive_path = thor_dir_path.join("small.thor");
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+
+            // Before patching
+            assert!(!expected_file_path.exists());
+            assert_eq!(0, count_files(temp_dir.path()));
+
+            apply_patch_to_disk(temp_dir.path(), None, &mut thor_archive, |_, _, _| {}).unwrap();
+
+            // After patching
+            assert!(expected_file_path.exists());
+            assert_eq!(nb_of_added_files, count_files(temp_dir.path()));
+            // TODO(LinkZ): Check content
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_resumable() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_disk_patch(
+            &thor_archive_path,
+            &[
+                ("applied.txt", Some(b"applied")),
+                ("interrupted.txt", Some(b"interrupted")),
+                ("removed.txt", None),
+                ("pending.txt", Some(b"pending")),
+            ],
+        );
+        fs::create_dir_all(&root_dir).unwrap();
+        // State left by an interrupted installation
+        fs::write(root_dir.join("applied.txt"), "APPLIED").unwrap();
+        fs::write(root_dir.join("interrupted.txt"), "inter").unwrap();
+        let applied_entries: HashSet<String> = ["applied.txt", "interrupted.txt", "removed.txt"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let mut journaled_entries = vec![];
+        apply_patch_to_disk_resumable(
+            &root_dir,
+            None,
+            &mut thor_archive,
+            &applied_entries,
+            |entry| journaled_entries.push(entry.to_string()),
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert_eq!(journaled_entries.len(), 4);
+        // Entries that look applied are skipped
+        assert_eq!(fs::read(root_dir.join("applied.txt")).unwrap(), b"APPLIED");
+        assert_eq!(
+            fs::read(root_dir.join("interrupted.txt")).unwrap(),
+            b"interrupted"
+        );
+        assert_eq!(fs::read(root_dir.join("pending.txt")).unwrap(), b"pending");
+    }
+
+    #[test]
+    fn test_recover_interrupted_grf_patch() {
+        let temp_dir = tempdir().unwrap();
+        let grf_file_path = temp_dir.path().join("data.grf");
+        assert!(!recover_interrupted_grf_patch(&grf_file_path).unwrap());
+        fs::write(temp_dir.path().join("data.grf.bak"), "original").unwrap();
+        fs::write(&grf_file_path, "partial").unwrap();
+        assert!(recover_interrupted_grf_patch(&grf_file_path).unwrap());
+        assert_eq!(fs::read(&grf_file_path).unwrap(), b"original");
+        assert!(!temp_dir.path().join("data.grf.bak").exists());
+
+        // Interrupted in-place patching
+        GrfArchiveBuilder::create(fs::File::create(&grf_file_path).unwrap(), 2, 0).unwrap();
+        let original_content = fs::read(&grf_file_path).unwrap();
+        {
+            let journal_path = grf_undo_journal_path(&grf_file_path);
+            let mut builder =
+                GrfArchiveBuilder::open_journaled(&grf_file_path, journal_path).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &b"a"[..])
+                .unwrap();
+        }
+        assert_ne!(fs::read(&grf_file_path).unwrap(), original_content);
+        assert!(recover_interrupted_grf_patch(&grf_file_path).unwrap());
+        assert_eq!(fs::read(&grf_file_path).unwrap(), original_content);
+        assert!(!temp_dir.path().join("data.grf.undo").exists());
+    }
+
+    #[test]
+    fn test_verify_merged_grf() {
+        use std::io::{SeekFrom, Write};
+
+        let temp_dir = tempdir().unwrap();
+        let original_grf_path = temp_dir.path().join("data.grf.bak");
+        let merged_grf_path = temp_dir.path().join("data.grf");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        {
+            let grf_file = fs::File::create(&original_grf_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\original.txt".to_string(), &[b'o'; 64][..])
+                .unwrap();
+            builder
+                .add_file("data\\removed.txt".to_string(), &b"removed"[..])
+                .unwrap();
+        }
+        build_grf_patch(
+            &thor_archive_path,
+            &[
+                ("data\\added.txt", Some(&[b'a'; 64])),
+                ("data\\removed.txt", None),
+            ],
+        );
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        // Entries copied from the patch and from the original GRF are checked
+        // on different threads
+        for corrupt_entry_path in ["data\\added.txt", "data\\original.txt"].iter() {
+            build_merged_grf(
+                &merged_grf_path,
+                &original_grf_path,
+                &write_options(),
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+            verify_merged_grf(
+                &merged_grf_path,
+                &original_grf_path,
+                &mut thor_archive,
+                &HashMap::new(),
+                GrfVerification::Always,
+            )
+            .unwrap();
+
+            // Flip a byte of an entry's compressed data
+            let offset = GrfArchive::open(&merged_grf_path)
+                .unwrap()
+                .get_file_entry(corrupt_entry_path)
+                .unwrap()
+                .offset;
+            let mut grf_file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&merged_grf_path)
+                .unwrap();
+            let mut byte = [0];
+            grf_file.seek(SeekFrom::Start(offset + 4)).unwrap();
+            grf_file.read_exact(&mut byte).unwrap();
+            grf_file.seek(SeekFrom::Start(offset + 4)).unwrap();
+            grf_file.write_all(&[!byte[0]]).unwrap();
+            drop(grf_file);
+            assert!(verify_merged_grf(
+                &merged_grf_path,
+                &original_grf_path,
+                &mut thor_archive,
+                &HashMap::new(),
+                GrfVerification::Always,
+            )
+            .is_err());
+        }
+        verify_merged_grf(
+            &merged_grf_path,
+            &original_grf_path,
+            &mut thor_archive,
+            &HashMap::new(),
+            GrfVerification::Never,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_removal_patch_and_defragment_grf() {
+        let temp_dir = tempdir().unwrap();
+        let grf_file_path = temp_dir.path().join("data.grf");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        {
+            let grf_file = fs::File::create(&grf_file_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\removed.txt".to_string(), &[b'r'; 64][..])
+                .unwrap();
+            builder
+                .add_file("data\\kept.txt".to_string(), &[b'k'; 64][..])
+                .unwrap();
+        }
+        build_grf_patch(&thor_archive_path, &[("data\\removed.txt", None)]);
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        assert!(is_removal_only(&thor_archive));
+        let unused_space = apply_patch_to_grf(
+            GrfPatchingMethod::OutOfPlace(GrfVerification::Always),
+            false,
+            &write_options(),
+            &grf_file_path,
+            &mut thor_archive,
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert!(unused_space > 0);
+        let grf_size = fs::metadata(&grf_file_path).unwrap().len();
+
+        defragment_grf(
+            &write_options(),
+            &grf_file_path,
+            GrfVerification::Always,
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert!(fs::metadata(&grf_file_path).unwrap().len() <= grf_size - unused_space);
+        let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+        assert!(!grf_archive.contains_file("data\\removed.txt"));
+        assert_eq!(
+            grf_archive.read_file_content("data\\kept.txt").unwrap(),
+            vec![b'k'; 64]
+        );
+        let builder = GrfArchiveBuilder::open(&grf_file_path).unwrap();
+        assert_eq!(builder.unused_space(), 0);
+    }
+
+    #[test]
+    fn test_apply_patch_with_duplicate_entries() {
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_grf_patch(
+            &thor_archive_path,
+            &[
+                ("data/sprite/a.spr", Some(b"patched")),
+                ("DATA\\REMOVED.TXT", None),
+            ],
+        );
+        let methods = [
+            GrfPatchingMethod::InPlace,
+            GrfPatchingMethod::OutOfPlace(GrfVerification::Always),
+        ];
+        let policies = [
+            (DuplicateEntryPolicy::UseGrfName, 1),
+            (DuplicateEntryPolicy::UsePatchName, 1),
+            (DuplicateEntryPolicy::KeepBoth, 3),
+        ];
+        for patching_method in methods.iter() {
+            for &(duplicate_entries, expected_file_count) in policies.iter() {
+                let grf_file_path = temp_dir.path().join("data.grf");
+                {
+                    let grf_file = fs::File::create(&grf_file_path).unwrap();
+                    let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+                    builder
+                        .add_file("data\\Sprite\\A.spr".to_string(), &b"original"[..])
+                        .unwrap();
+                    builder
+                        .add_file("data\\removed.txt".to_string(), &b"removed"[..])
+                        .unwrap();
+                }
+                let patching_method = match patching_method {
+                    GrfPatchingMethod::InPlace => GrfPatchingMethod::InPlace,
+                    GrfPatchingMethod::OutOfPlace(v) => GrfPatchingMethod::OutOfPlace(*v),
+                };
+                let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+                let options = GrfWriteOptions {
+                    duplicate_entries,
+                    ..write_options()
+                };
+                apply_patch_to_grf(
+                    patching_method,
+                    false,
+                    &options,
+                    &grf_file_path,
+                    &mut thor_archive,
+                    |_, _, _| {},
+                )
+                .unwrap();
+
+                let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+                assert_eq!(grf_archive.file_count(), expected_file_count);
+                let patched_entry_name = match duplicate_entries {
+                    DuplicateEntryPolicy::UseGrfName => "data\\Sprite\\A.spr",
+                    _ => "data/sprite/a.spr",
+                };
+                assert_eq!(
+                    grf_archive.read_file_content(patched_entry_name).unwrap(),
+                    b"patched"
+                );
+            }
+        }
+
+        // Duplicates are rejected when told so, and always within a patch
+        let grf_file_path = temp_dir.path().join("data.grf");
+        {
+            let grf_file = fs::File::create(&grf_file_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\Sprite\\A.spr".to_string(), &b"original"[..])
+                .unwrap();
+        }
+        let duplicate_thor_archive_path = temp_dir.path().join("duplicates.thor");
+        build_grf_patch(
+            &duplicate_thor_archive_path,
+            &[("data/b.txt", Some(b"b")), ("DATA\\B.TXT", Some(b"B"))],
+        );
+        let rejections = [
+            (&thor_archive_path, DuplicateEntryPolicy::Reject),
+            (
+                &duplicate_thor_archive_path,
+                DuplicateEntryPolicy::UseGrfName,
+            ),
+        ];
+        for &(thor_archive_path, duplicate_entries) in rejections.iter() {
+            let options = GrfWriteOptions {
+                duplicate_entries,
+                ..write_options()
+            };
+            for &in_place in [true, false].iter() {
+                let patching_method = match in_place {
+                    true => GrfPatchingMethod::InPlace,
+                    false => GrfPatchingMethod::OutOfPlace(GrfVerification::Always),
+                };
+                let mut thor_archive = ThorArchive::open(thor_archive_path).unwrap();
+                assert!(apply_patch_to_grf(
+                    patching_method,
+                    false,
+                    &options,
+                    &grf_file_path,
+                    &mut thor_archive,
+                    |_, _, _| {},
+                )
+                .is_err());
+                let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+                assert_eq!(grf_archive.file_count(), 1);
+                assert_eq!(
+                    grf_archive
+                        .read_file_content("data\\Sprite\\A.spr")
+                        .unwrap(),
+                    b"original"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_and_replace_file() {
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("data.grf.tmp");
+        let dest_path = temp_dir.path().join("data.grf");
+        fs::write(&src_path, "patched").unwrap();
+        fs::write(&dest_path, "original").unwrap();
+        copy_and_replace_file(&src_path, &dest_path).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), b"patched");
+        assert!(!src_path.exists());
+        assert!(!temp_dir.path().join("data.grf.part").exists());
+        // The destination is left alone if the source can't be copied
+        assert!(copy_and_replace_file(&src_path, &dest_path).is_err());
+        assert_eq!(fs::read(&dest_path).unwrap(), b"patched");
+        assert!(!temp_dir.path().join("data.grf.part").exists());
+        assert!(!is_cross_device_error(&io::Error::from(
+            io::ErrorKind::NotFound
+        )));
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_creates_directories() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_disk_patch(
+            &thor_archive_path,
+            &[
+                ("data\\sprite\\new.spr", Some(b"new")),
+                ("readonly.txt", Some(b"updated")),
+                ("removed.txt", None),
+            ],
+        );
+        fs::create_dir_all(&root_dir).unwrap();
+        let readonly_file_path = root_dir.join("readonly.txt");
+        fs::write(&readonly_file_path, "original").unwrap();
+        let mut permissions = fs::metadata(&readonly_file_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&readonly_file_path, permissions).unwrap();
+        fs::write(root_dir.join("removed.txt"), "removed").unwrap();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(&root_dir, None, &mut thor_archive, |_, _, _| {}).unwrap();
+
+        assert_eq!(
+            fs::read(root_dir.join("data/sprite/new.spr")).unwrap(),
+            b"new"
+        );
+        assert_eq!(fs::read(&readonly_file_path).unwrap(), b"updated");
+        assert!(!root_dir.join("removed.txt").exists());
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_reports_progress() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_disk_patch(
+            &thor_archive_path,
+            &[
+                ("a.txt", Some(b"a")),
+                ("b.txt", Some(b"bb")),
+                ("removed.txt", None),
+            ],
+        );
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let mut progress = vec![];
+        apply_patch_to_disk(
+            &root_dir,
+            None,
+            &mut thor_archive,
+            |nb_processed, nb_total, written_bytes| {
+                progress.push((nb_processed, nb_total, written_bytes))
+            },
+        )
+        .unwrap();
+
+        assert_eq!(progress.len(), 3);
+        assert_eq!(progress.last(), Some(&(3, 3, 3)));
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_converts_entry_names() {
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        let entry_name =
+            charset::encode_entry_name("data\\유저인터페이스\\a.bmp", "euc-kr").unwrap();
+        build_disk_patch(&thor_archive_path, &[(&entry_name, Some(b"a"))]);
+
+        let korean_root_dir = temp_dir.path().join("korean");
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(
+            &korean_root_dir,
+            Some("euc-kr"),
+            &mut thor_archive,
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert!(korean_root_dir.join("data/유저인터페이스/a.bmp").exists());
+
+        // Names are kept as is without a code page (or with windows-1252)
+        for code_page in [None, Some("windows-1252")] {
+            let raw_root_dir = temp_dir.path().join("raw");
+            apply_patch_to_disk(&raw_root_dir, code_page, &mut thor_archive, |_, _, _| {}).unwrap();
+            assert!(raw_root_dir.join("data/À¯ÀúÀÎÅÍÆäÀÌ½º/a.bmp").exists());
+            fs::remove_dir_all(&raw_root_dir).unwrap();
+        }
+
+        let raw_root_dir = temp_dir.path().join("raw");
+        assert!(apply_patch_to_disk(
+            &raw_root_dir,
+            Some("unknown"),
+            &mut thor_archive,
+            |_, _, _| {}
+        )
+        .is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_apply_patch_to_disk_matches_existing_files_case_insensitively() {
+        let temp_dir = tempdir().unwrap();
+        let root_dir = temp_dir.path().join("client");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_disk_patch(
+            &thor_archive_path,
+            &[
+                ("DATA\\texture\\a.bmp", Some(b"updated")),
+                ("DATA\\texture\\new.bmp", Some(b"new")),
+                ("data\\TEXTURE\\REMOVED.BMP", None),
+            ],
+        );
+        fs::create_dir_all(root_dir.join("data/Texture")).unwrap();
+        fs::write(root_dir.join("data/Texture/A.bmp"), "original").unwrap();
+        fs::write(root_dir.join("data/Texture/removed.bmp"), "removed").unwrap();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(&root_dir, None, &mut thor_archive, |_, _, _| {}).unwrap();
+
+        assert_eq!(
+            fs::read(root_dir.join("data/Texture/A.bmp")).unwrap(),
+            b"updated"
+        );
+        assert!(root_dir.join("data/Texture/new.bmp").exists());
+        assert!(!root_dir.join("data/Texture/removed.bmp").exists());
+        // No duplicates
+        assert!(!root_dir.join("DATA").exists());
+        assert_eq!(
+            fs::read_dir(root_dir.join("data/Texture")).unwrap().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_rejects_hostile_entries() {
+        let hostile_entry_paths = [
+            "..\\evil.txt",
+            "data\\..\\..\\evil.txt",
+            "data/../../evil.txt",
+            "\\evil.txt",
+            "/evil.txt",
+            "C:\\evil.txt",
+            "data\\file.txt:stream",
+        ];
+        for hostile_entry_path in hostile_entry_paths.iter() {
+            let temp_dir = tempdir().unwrap();
+            let root_dir = temp_dir.path().join("client");
+            fs::create_dir_all(&root_dir).unwrap();
+            let thor_archive_path = temp_dir.path().join("patch.thor");
+            build_disk_patch(
+                &thor_archive_path,
+                &[
+                    ("data\\harmless.txt", Some(b"harmless")),
+                    (hostile_entry_path, Some(b"evil")),
+                ],
+            );
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            assert!(apply_patch_to_disk(&root_dir, None, &mut thor_archive, |_, _, _| {}).is_err());
+            // Nothing should have been written
+            assert!(!temp_dir.path().join("evil.txt").exists());
+            assert!(!root_dir.join("data").exists());
+        }
+    }
+
+    #[test]
+    fn test_check_entry_paths() {
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_disk_patch(
+            &thor_archive_path,
+            &[
+                ("data\\harmless.txt", Some(b"harmless")),
+                ("data\\..\\..\\evil.exe", Some(b"evil")),
+                ("data\\nul.txt", None),
+            ],
+        );
+        let thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let err = check_entry_paths(&thor_archive, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Rejected 2 unsafe entries: 'data\\..\\..\\evil.exe' (parent directory component), \
+             'data\\nul.txt' (device name)"
+        );
+
+        build_disk_patch(
+            &thor_archive_path,
+            &[("data\\harmless.txt", Some(b"harmless"))],
+        );
+        let thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        assert!(check_entry_paths(&thor_archive, None).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_disk_entry_path() {
+        let root_dir = Path::new("client");
+        assert_eq!(
+            resolve_disk_entry_path(root_dir, "data\\texture\\a.bmp").unwrap(),
+            root_dir.join("data").join("texture").join("a.bmp")
+        );
+        assert_eq!(
+            resolve_disk_entry_path(root_dir, "data\\.\\a.bmp").unwrap(),
+            root_dir.join("data").join("a.bmp")
+        );
+        assert!(resolve_disk_entry_path(root_dir, "").is_err());
+        assert!(resolve_disk_entry_path(root_dir, "data\\..").is_err());
+        assert!(resolve_disk_entry_path(root_dir, "D:data.grf").is_err());
+        assert!(resolve_disk_entry_path(root_dir, "data\\nul.txt").is_err());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_ip_empty() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+
+            // Before patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(0, grf_archive.file_count());
+            let grf_version_major = grf_archive.version_major();
+            let grf_version_minor = grf_archive.version_minor();
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                GrfPatchingMethod::InPlace,
+                false,
+                &write_options(),
+                &grf_archive_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), grf_version_major);
+            assert_eq!(grf_archive.version_minor(), grf_version_minor);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_ip_empty_create() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        {
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                GrfPatchingMethod::InPlace,
+                true,
+                &write_options(),
+                &grf_archive_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), 2);
+            assert_eq!(grf_archive.version_minor(), 0);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_oop_empty() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+
+            // Before patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(0, grf_archive.file_count());
+            let grf_version_major = grf_archive.version_major();
+            let grf_version_minor = grf_archive.version_minor();
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                GrfPatchingMethod::OutOfPlace(GrfVerification::Always),
+                false,
+                &write_options(),
+                &grf_archive_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), grf_version_major);
+            assert_eq!(grf_archive.version_minor(), grf_version_minor);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_oop_empty_create() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("empty.grf");
+        {
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                GrfPatchingMethod::OutOfPlace(GrfVerification::Always),
+                true,
+                &write_options(),
+                &grf_archive_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_added_files, grf_archive.file_count());
+            assert_eq!(grf_archive.version_major(), 2);
+            assert_eq!(grf_archive.version_minor(), 0);
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_add_grf_to_data_ini() {
+        let content = "[Data]\r\n0=rdata.grf\r\n1=data.grf\r\n";
+        assert_eq!(
+            add_grf_to_data_ini(content, "myserver.grf").unwrap(),
+            "[Data]\r\n0=myserver.grf\r\n1=rdata.grf\r\n2=data.grf\r\n"
+        );
+        assert!(add_grf_to_data_ini(content, "DATA.GRF").is_none());
+        assert_eq!(
+            add_grf_to_data_ini("[Other]\nkey=value\n", "myserver.grf").unwrap(),
+            "[Other]\nkey=value\n[Data]\n0=myserver.grf\n"
+        );
+    }
+
+    fn patch_maintained_integrity(thor_file_path: &Path, grf_file_path: &Path) -> Result<bool> {
+        let mut thor_archive = ThorArchive::open(thor_file_path)?;
+        let mut grf_archive = GrfArchive::open(grf_file_path)?;
+        let thor_entries: Vec<ThorFileEntry> = thor_archive.get_entries().cloned().collect();
+        for file_entry in thor_entries {
+            if file_entry.is_internal() || file_entry.is_removed {
+                continue;
+            }
+            let expected_content = thor_archive.read_file_content(&file_entry.relative_path)?;
+            let file_content = grf_archive.read_file_content(&file_entry.relative_path)?;
+            if expected_content != file_content {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
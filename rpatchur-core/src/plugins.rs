@@ -0,0 +1,270 @@
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::config::{PatcherConfiguration, PluginConfiguration, PluginEvent};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+// Time given to the output readers once the plugin has exited
+const OUTPUT_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// Actions requested by plugins in response to an event.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PluginActions {
+    pub extra_arguments: Vec<String>, // Arguments to pass to the game client (`pre_play`)
+    pub vetoed_patches: Vec<usize>,   // Indices of patches not to apply (`pre_download`)
+}
+
+/// What a plugin writes to its standard output. Plugins that have nothing to
+/// request can exit without writing anything.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct PluginResponse {
+    #[serde(default)]
+    add_arguments: Vec<String>,
+    #[serde(default)]
+    veto_patches: Vec<usize>,
+}
+
+/// Notifies the plugins subscribed to `event` and gathers their requests.
+///
+/// Plugins are programs that receive the event as a JSON object on their
+/// standard input, such as
+/// `{"event": "pre_play", "config": {...}, "arguments": [...]}` (`payload`'s
+/// fields are added to the object), and answer with a JSON object on their
+/// standard output, such as `{"add_arguments": ["-1rag1"]}` or
+/// `{"veto_patches": [12]}`. Requests that don't apply to the event are
+/// ignored. Plugins that fail are logged and ignored. What plugins write to
+/// their standard error is logged.
+pub fn dispatch_plugin_event(
+    config: &PatcherConfiguration,
+    event: PluginEvent,
+    payload: Value,
+) -> PluginActions {
+    let mut actions = PluginActions::default();
+    let plugins = subscribed_plugins(config, event);
+    if plugins.is_empty() {
+        return actions;
+    }
+    let message = plugin_message(config, event, payload).to_string();
+    for plugin in plugins {
+        log::info!("Notifying plugin '{}' of {:?}", plugin.name, event);
+        let response = match run_plugin(plugin, &message) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Plugin '{}' failed: {:#}", plugin.name, e);
+                continue;
+            }
+        };
+        match event {
+            PluginEvent::PrePlay => actions.extra_arguments.extend(response.add_arguments),
+            PluginEvent::PreDownload => actions.vetoed_patches.extend(response.veto_patches),
+            PluginEvent::PostInstall => {}
+        }
+    }
+    actions
+}
+
+/// Same as `dispatch_plugin_event`, without blocking the async runtime.
+pub async fn dispatch_plugin_event_async(
+    config: &PatcherConfiguration,
+    event: PluginEvent,
+    payload: Value,
+) -> PluginActions {
+    if subscribed_plugins(config, event).is_empty() {
+        return PluginActions::default();
+    }
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || dispatch_plugin_event(&config, event, payload))
+        .await
+        .unwrap_or_default()
+}
+
+fn subscribed_plugins(
+    config: &PatcherConfiguration,
+    event: PluginEvent,
+) -> Vec<&PluginConfiguration> {
+    match &config.plugins {
+        Some(plugins) => plugins
+            .iter()
+            .filter(|plugin| plugin.events.contains(&event))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Builds the message sent to plugins. Only a subset of the configuration is
+/// exposed to plugins.
+fn plugin_message(config: &PatcherConfiguration, event: PluginEvent, payload: Value) -> Value {
+    let mut message = json!({
+        "event": event,
+        "config": {
+            "window_title": config.window.title,
+            "play": {
+                "path": config.play.path,
+                "arguments": config.play.arguments,
+            },
+            "client": {
+                "default_grf_name": config.client.default_grf_name,
+            },
+            "patch_servers": config
+                .web
+                .patch_servers
+                .iter()
+                .map(|server| server.name.as_str())
+                .collect::<Vec<_>>(),
+        },
+    });
+    if let (Some(message), Value::Object(fields)) = (message.as_object_mut(), payload) {
+        message.extend(fields);
+    }
+    message
+}
+
+fn run_plugin(plugin: &PluginConfiguration, message: &str) -> Result<PluginResponse> {
+    let mut command = Command::new(&plugin.command);
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    let mut child = command
+        .args(plugin.arguments.as_deref().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", plugin.command))?;
+    // The event is written and the output is read on separate threads, so
+    // that neither the patcher nor the plugin block on a full pipe
+    let mut stdin = child.stdin.take().context("Failed to send event")?;
+    let plugin_name = plugin.name.clone();
+    let message = message.to_string();
+    std::thread::spawn(move || {
+        // Plugins aren't required to read the event
+        if let Err(e) = stdin.write_all(message.as_bytes()) {
+            log::debug!("Failed to send event to plugin '{}': {}", plugin_name, e);
+        }
+    });
+    let stdout = spawn_output_reader(child.stdout.take().context("Failed to read output")?);
+    let stderr = spawn_output_reader(child.stderr.take().context("Failed to read output")?);
+    let timeout = Duration::from_secs(plugin.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let exit_result = wait_with_timeout(&mut child, timeout);
+    // Processes started by the plugin can keep the pipes open after it has
+    // exited, so the output isn't waited for indefinitely
+    if let Ok(Ok(errors)) = stderr.recv_timeout(OUTPUT_GRACE_PERIOD) {
+        if !errors.trim().is_empty() {
+            log::warn!("Plugin '{}': {}", plugin.name, errors.trim());
+        }
+    }
+    exit_result?;
+    let output = stdout
+        .recv_timeout(OUTPUT_GRACE_PERIOD)
+        .map_err(|_| anyhow!("Plugin didn't close its output"))?
+        .context("Failed to read output")?;
+    parse_plugin_response(&output)
+}
+
+/// Reads `pipe` until it's closed, on a separate thread.
+fn spawn_output_reader<R: Read + Send + 'static>(
+    mut pipe: R,
+) -> flume::Receiver<std::io::Result<String>> {
+    let (output_tx, output_rx) = flume::bounded(1);
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = output_tx.send(pipe.read_to_string(&mut output).map(|_| output));
+    });
+    output_rx
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<()> {
+    let started_at = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                return Err(anyhow!("Plugin exited with {}", status));
+            }
+            return Ok(());
+        }
+        if started_at.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("Plugin timed out"));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn parse_plugin_response(output: &str) -> Result<PluginResponse> {
+    if output.trim().is_empty() {
+        return Ok(PluginResponse::default());
+    }
+    serde_json::from_str(output).context("Invalid plugin response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plugin_response() {
+        let response = parse_plugin_response("").unwrap();
+        assert!(response.add_arguments.is_empty());
+        assert!(response.veto_patches.is_empty());
+        let response =
+            parse_plugin_response(r#"{"add_arguments": ["-1rag1"], "veto_patches": [12]}"#)
+                .unwrap();
+        assert_eq!(response.add_arguments, vec!["-1rag1"]);
+        assert_eq!(response.veto_patches, vec![12]);
+        // Only the restricted API is available
+        assert!(parse_plugin_response(r#"{"run": "format c:"}"#).is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_run_plugin() {
+        let plugin = PluginConfiguration {
+            name: "test".to_string(),
+            command: "sh".to_string(),
+            arguments: Some(vec![
+                "-c".to_string(),
+                r#"grep -q '"event":"pre_play"' && echo '{"add_arguments": ["-1rag1"]}'"#
+                    .to_string(),
+            ]),
+            events: vec![PluginEvent::PrePlay],
+            timeout_secs: Some(1),
+        };
+        let response = run_plugin(&plugin, r#"{"event":"pre_play"}"#).unwrap();
+        assert_eq!(response.add_arguments, vec!["-1rag1"]);
+        assert!(run_plugin(&plugin, r#"{"event":"pre_download"}"#).is_err());
+
+        // Plugins that write their output before reading the event
+        let message = format!(
+            r#"{{"event":"pre_play","padding":"{}"}}"#,
+            " ".repeat(1 << 20)
+        );
+        let chatty_plugin = PluginConfiguration {
+            arguments: Some(vec![
+                "-c".to_string(),
+                r#"head -c 200000 /dev/zero | tr '\0' ' '; cat >/dev/null; echo '{}'"#.to_string(),
+            ]),
+            timeout_secs: Some(5),
+            ..plugin.clone()
+        };
+        assert!(run_plugin(&chatty_plugin, &message).is_ok());
+
+        // The plugin's own processes keep its output open after it's killed
+        let plugin = PluginConfiguration {
+            arguments: Some(vec!["-c".to_string(), "sleep 5".to_string()]),
+            ..plugin
+        };
+        let started_at = Instant::now();
+        assert!(run_plugin(&plugin, "{}").is_err());
+        assert!(started_at.elapsed() < Duration::from_secs(3));
+    }
+}
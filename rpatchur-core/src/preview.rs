@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use gruf::thor::ThorArchive;
+use serde::Serialize;
+
+/// Content of a THOR patch, as it would be applied by the patcher.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatchPreview {
+    pub file_name: String,
+    pub target_grf: Option<String>, // None if the patch targets the client directory
+    pub entries: Vec<PatchPreviewEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatchPreviewEntry {
+    pub path: String,
+    pub size: usize,
+    pub size_compressed: usize,
+    pub is_removed: bool, // The entry is deleted by the patch
+}
+
+/// Parses the THOR patch located at `patch_file_path` and lists its entries,
+/// sorted by path. Patches that don't specify a GRF target `default_grf_name`.
+pub fn preview_patch_file(
+    patch_file_path: impl AsRef<Path>,
+    default_grf_name: &str,
+) -> Result<PatchPreview> {
+    let patch_file_path = patch_file_path.as_ref();
+    let thor_archive = ThorArchive::open(patch_file_path)
+        .with_context(|| format!("Failed to open '{}'", patch_file_path.display()))?;
+    let target_grf = if thor_archive.use_grf_merging() {
+        let target_grf_name = thor_archive.target_grf_name();
+        if target_grf_name.is_empty() {
+            Some(default_grf_name.to_string())
+        } else {
+            Some(target_grf_name)
+        }
+    } else {
+        None
+    };
+    let mut entries: Vec<PatchPreviewEntry> = thor_archive
+        .get_entries()
+        .filter(|entry| !entry.is_internal())
+        .map(|entry| PatchPreviewEntry {
+            path: entry.relative_path.clone(),
+            size: entry.size,
+            size_compressed: entry.size_compressed,
+            is_removed: entry.is_removed,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(PatchPreview {
+        file_name: patch_file_path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        target_grf,
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::build_grf_patch;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_preview_patch_file() {
+        let temp_dir = tempdir().unwrap();
+        let patch_file_path = temp_dir.path().join("hotfix.thor");
+        build_grf_patch(
+            &patch_file_path,
+            &[("data\\b.txt", Some(b"content")), ("data\\a.txt", None)],
+        );
+        let preview = preview_patch_file(&patch_file_path, "data.grf").unwrap();
+        assert_eq!(preview.file_name, "hotfix.thor");
+        assert_eq!(preview.target_grf.as_deref(), Some("data.grf"));
+        assert_eq!(preview.entries.len(), 2);
+        assert_eq!(preview.entries[0].path, "data\\a.txt");
+        assert!(preview.entries[0].is_removed);
+        assert_eq!(preview.entries[1].path, "data\\b.txt");
+        assert_eq!(preview.entries[1].size, 7);
+        assert!(!preview.entries[1].is_removed);
+    }
+}
@@ -0,0 +1,224 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// Process running on the system.
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+}
+
+/// Returns the running processes whose executable's name is one of `names`
+/// (case-insensitive).
+pub fn find_processes(names: &[String]) -> Result<Vec<ProcessInfo>> {
+    Ok(list_processes()?
+        .into_iter()
+        .filter(|process| {
+            names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&process.name))
+        })
+        .collect())
+}
+
+/// Politely asks the given processes to exit and waits for them to do so.
+///
+/// Processes that are still running after `timeout` are killed. Processes
+/// that exit on their own in the meantime aren't treated as errors.
+pub fn close_processes(processes: &[ProcessInfo], timeout: Duration) -> Result<()> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+    for process in processes {
+        log::info!("Closing process '{}' ({})", process.name, process.pid);
+        request_process_exit(process.pid)?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let running_pids: Vec<u32> = list_processes()?.into_iter().map(|p| p.pid).collect();
+        let remaining: Vec<&ProcessInfo> = processes
+            .iter()
+            .filter(|process| running_pids.contains(&process.pid))
+            .collect();
+        if remaining.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            for process in remaining {
+                log::warn!("Killing process '{}' ({})", process.name, process.pid);
+                kill_process(process.pid)?;
+            }
+            return Ok(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Lists running processes.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn list_processes() -> Result<Vec<ProcessInfo>> {
+    windows::win32_list_processes()
+}
+
+/// Lists running processes.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn list_processes() -> Result<Vec<ProcessInfo>> {
+    let mut processes = vec![];
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(v) => v,
+            None => continue,
+        };
+        // Processes can exit while we're iterating
+        if let Ok(exe_path) = std::fs::read_link(entry.path().join("exe")) {
+            if let Some(name) = exe_path.file_name().and_then(|s| s.to_str()) {
+                processes.push(ProcessInfo {
+                    pid,
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+    Ok(processes)
+}
+
+/// Asks a process to exit, by closing its windows.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn request_process_exit(pid: u32) -> Result<()> {
+    windows::win32_close_process_windows(pid);
+    Ok(())
+}
+
+/// Asks a process to exit, by sending it SIGTERM.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn request_process_exit(pid: u32) -> Result<()> {
+    send_signal(pid, "TERM")
+}
+
+/// Forcefully terminates a process.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn kill_process(pid: u32) -> Result<()> {
+    windows::win32_terminate_process(pid)
+}
+
+/// Forcefully terminates a process.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn kill_process(pid: u32) -> Result<()> {
+    send_signal(pid, "KILL")
+}
+
+/// Sends `signal` to a process, which is fine if the process has already
+/// exited.
+#[cfg(not(windows))]
+fn send_signal(pid: u32, signal: &str) -> Result<()> {
+    use anyhow::anyhow;
+    use std::process::Command;
+
+    let status = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .status()?;
+    if status.success() || !std::path::Path::new("/proc").join(pid.to_string()).exists() {
+        return Ok(());
+    }
+    Err(anyhow!("Failed to send SIG{} to process {}", signal, pid))
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::{anyhow, Result};
+
+    pub fn win32_list_processes() -> Result<Vec<super::ProcessInfo>> {
+        use std::ffi::OsString;
+        use std::os::windows::ffi::OsStringExt;
+        use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+        use winapi::um::tlhelp32::{
+            CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+            TH32CS_SNAPPROCESS,
+        };
+
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(anyhow!("Failed to list processes"));
+        }
+        let mut processes = vec![];
+        let mut entry: PROCESSENTRY32W = unsafe { std::mem::zeroed() };
+        entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+        let mut has_entry = unsafe { Process32FirstW(snapshot, &mut entry) } != 0;
+        while has_entry {
+            let name_len = entry
+                .szExeFile
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or_else(|| entry.szExeFile.len());
+            let name = OsString::from_wide(&entry.szExeFile[..name_len]);
+            processes.push(super::ProcessInfo {
+                pid: entry.th32ProcessID,
+                name: name.to_string_lossy().into_owned(),
+            });
+            has_entry = unsafe { Process32NextW(snapshot, &mut entry) } != 0;
+        }
+        unsafe { CloseHandle(snapshot) };
+        Ok(processes)
+    }
+
+    /// Sends `WM_CLOSE` to all the top-level windows owned by a process.
+    pub fn win32_close_process_windows(pid: u32) {
+        use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, TRUE};
+        use winapi::shared::windef::HWND;
+        use winapi::um::winuser::{EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE};
+
+        unsafe extern "system" fn close_window(hwnd: HWND, lparam: LPARAM) -> BOOL {
+            let mut window_pid: DWORD = 0;
+            GetWindowThreadProcessId(hwnd, &mut window_pid);
+            if window_pid == lparam as DWORD {
+                PostMessageW(hwnd, WM_CLOSE, 0, 0);
+            }
+            TRUE
+        }
+        unsafe { EnumWindows(Some(close_window), pid as LPARAM) };
+    }
+
+    /// Terminates a process, which is fine if the process has already
+    /// exited.
+    pub fn win32_terminate_process(pid: u32) -> Result<()> {
+        use winapi::shared::minwindef::FALSE;
+        use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+        use winapi::um::errhandlingapi::GetLastError;
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+        use winapi::um::synchapi::WaitForSingleObject;
+        use winapi::um::winbase::WAIT_OBJECT_0;
+        use winapi::um::winnt::{PROCESS_TERMINATE, SYNCHRONIZE};
+
+        let process = unsafe { OpenProcess(PROCESS_TERMINATE | SYNCHRONIZE, FALSE, pid) };
+        if process.is_null() {
+            // There's no process with this PID anymore
+            if unsafe { GetLastError() } == ERROR_INVALID_PARAMETER {
+                return Ok(());
+            }
+            return Err(anyhow!("Failed to open process {}", pid));
+        }
+        let result = unsafe { TerminateProcess(process, 1) };
+        // Terminating a process that is exiting fails
+        let has_exited = unsafe { WaitForSingleObject(process, 0) } == WAIT_OBJECT_0;
+        unsafe { CloseHandle(process) };
+        if result == 0 && !has_exited {
+            return Err(anyhow!("Failed to terminate process {}", pid));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use super::core::{UpdateError, UpdateOutcome};
+use super::stats::PatchingSummary;
+
+/// Receives the progress of the patching process, so that a front-end can
+/// display it.
+///
+/// Methods are called from the patching thread.
+pub trait ProgressSink {
+    /// Indicates the current status of the patching process.
+    fn dispatch_patching_status(&self, status: PatchingStatus) -> Result<()>;
+
+    /// Indicates how many patches are available, after a background check.
+    fn dispatch_updates_available(&self, _patch_count: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indicates that an update has been deferred because of quiet hours. It
+    /// starts automatically at `resume_time` ("HH:MM", local time).
+    fn dispatch_update_deferred(&self, _resume_time: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gives the statistics of an update, once its patches have been applied.
+    fn dispatch_patching_summary(&self, _summary: &PatchingSummary) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indicates that `byte_count` bytes have been downloaded for an operation
+    /// (e.g., an update or a prefetch) that just ended, whether it succeeded or
+    /// not.
+    fn record_downloaded_bytes(&self, _byte_count: u64) {}
+
+    /// Indicates that the patching process hasn't made any progress for
+    /// `seconds` during the given stage ("download", ...), e.g., because of a
+    /// stalled connection.
+    fn dispatch_patching_stalled(&self, _stage: &str, _seconds: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indicates that a GRF couldn't be replaced because another process kept
+    /// using it, which is usually caused by an antivirus.
+    fn dispatch_antivirus_interference_suspected(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indicates whether an update is in progress.
+    fn set_patch_in_progress(&self, _value: bool) {}
+
+    /// Asks the user whether the given running processes can be closed. The
+    /// answer is expected through a `CloseProcessesReply` command (or
+    /// `CancellationToken::reply_close_processes` with `Patcher::update`).
+    ///
+    /// Returns false if the user cannot be asked, in which case
+    /// `confirm_close_processes` decides.
+    fn prompt_close_processes(&self, _process_names: &[String]) -> bool {
+        false
+    }
+
+    /// Indicates whether the given running processes can be closed, when the
+    /// user cannot be asked through `prompt_close_processes`. Processes are
+    /// left alone by default.
+    fn confirm_close_processes(&self, _process_names: &[String]) -> bool {
+        false
+    }
+
+    /// Lets the user decide what to do with a patch that failed to apply. The
+    /// answer is expected through a `PatchFailureReply` command (or
+    /// `CancellationToken::reply_patch_failure` with `Patcher::update`).
+    ///
+    /// Returns false if the user cannot be asked, in which case patching is
+    /// aborted.
+    fn prompt_patch_failure(&self, _patch_name: &str, _error: &str) -> bool {
+        false
+    }
+
+    /// Called once an update or a manual patch is over, whether it went
+    /// through or not.
+    fn patching_finished(&self, _result: &std::result::Result<UpdateOutcome, UpdateError>) {}
+}
+
+/// Used to indicate the current status of the patching process.
+pub enum PatchingStatus {
+    Ready,
+    Error(String),                                  // Error message
+    DownloadInProgress(usize, usize, u64), // Downloaded files, Total number, Bytes per second
+    InstallationInProgress(usize, usize),  // Installed patches, Total number
+    EntryInstallationInProgress(usize, usize, u64), // Processed entries, Total number, Bytes written
+    ManualPatchApplied(String),                     // Patch file name
+    PatchesSkipped(Vec<String>),                    // Names of the patches that failed to apply
+    OverallProgress(f32, Option<u64>), // Percentage of the whole update, Estimated seconds left
+}
+
+impl PatchingStatus {
+    /// Returns a JSON representation of the status (e.g., for machine-readable
+    /// output).
+    pub fn to_json(&self) -> Value {
+        match self {
+            PatchingStatus::Ready => json!({ "status": "ready" }),
+            PatchingStatus::Error(msg) => json!({ "status": "error", "message": msg }),
+            PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => json!({
+                "status": "download_in_progress",
+                "downloaded": nb_downloaded,
+                "total": nb_total,
+                "bytes_per_sec": bytes_per_sec,
+            }),
+            PatchingStatus::InstallationInProgress(nb_installed, nb_total) => json!({
+                "status": "installation_in_progress",
+                "installed": nb_installed,
+                "total": nb_total,
+            }),
+            PatchingStatus::EntryInstallationInProgress(nb_processed, nb_total, written_bytes) => {
+                json!({
+                    "status": "entry_installation_in_progress",
+                    "processed": nb_processed,
+                    "total": nb_total,
+                    "bytes_written": written_bytes,
+                })
+            }
+            PatchingStatus::ManualPatchApplied(name) => {
+                json!({ "status": "manual_patch_applied", "patch_name": name })
+            }
+            PatchingStatus::PatchesSkipped(names) => {
+                json!({ "status": "patches_skipped", "patch_names": names })
+            }
+            PatchingStatus::OverallProgress(percent, eta_secs) => json!({
+                "status": "overall_progress",
+                "percent": percent,
+                "eta_secs": eta_secs,
+            }),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::NaiveTime;
+
+/// Periods of the day during which automatic updates are deferred.
+///
+/// Periods are given in local time, as "HH:MM-HH:MM" strings, and can span
+/// midnight (e.g., "23:00-01:00").
+#[derive(Debug, Default)]
+pub struct QuietHours {
+    periods: Vec<(NaiveTime, NaiveTime)>, // Start (inclusive), End (exclusive)
+}
+
+impl QuietHours {
+    pub fn parse(periods: &[String]) -> Result<QuietHours> {
+        let periods = periods
+            .iter()
+            .map(|period| parse_period(period))
+            .collect::<Result<_>>()?;
+        Ok(QuietHours { periods })
+    }
+
+    /// Returns the time at which the quiet period `time` falls into ends, if
+    /// any.
+    pub fn end_of_quiet_period(&self, time: NaiveTime) -> Option<NaiveTime> {
+        self.periods
+            .iter()
+            .find(|(start, end)| {
+                if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    *start <= time || time < *end
+                }
+            })
+            .map(|(_, end)| *end)
+    }
+
+    /// Returns how long it takes for the quiet period `time` falls into to end.
+    /// Returns `None` outside of quiet hours.
+    pub fn time_until_end(&self, time: NaiveTime) -> Option<Duration> {
+        let end = self.end_of_quiet_period(time)?;
+        let mut remaining = end.signed_duration_since(time);
+        if remaining <= chrono::Duration::zero() {
+            remaining = remaining + chrono::Duration::days(1);
+        }
+        remaining.to_std().ok()
+    }
+}
+
+fn parse_period(period: &str) -> Result<(NaiveTime, NaiveTime)> {
+    let invalid_period = || anyhow!("Invalid quiet period '{}'", period);
+    let mut bounds = period.splitn(2, '-').map(|bound| {
+        NaiveTime::parse_from_str(bound.trim(), "%H:%M").map_err(|_| invalid_period())
+    });
+    let start = bounds.next().ok_or_else(invalid_period)??;
+    let end = bounds.next().ok_or_else(invalid_period)??;
+    if start == end {
+        return Err(invalid_period());
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(QuietHours::parse(&["18:00-20:30".to_string()]).is_ok());
+        assert!(QuietHours::parse(&["18:00 - 20:30".to_string()]).is_ok());
+        assert!(QuietHours::parse(&["18:00".to_string()]).is_err());
+        assert!(QuietHours::parse(&["18:00-25:00".to_string()]).is_err());
+        assert!(QuietHours::parse(&["18:00-18:00".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_end_of_quiet_period() {
+        let quiet_hours =
+            QuietHours::parse(&["18:00-20:30".to_string(), "23:00-01:00".to_string()]).unwrap();
+        assert_eq!(quiet_hours.end_of_quiet_period(time(17, 59)), None);
+        assert_eq!(
+            quiet_hours.end_of_quiet_period(time(18, 0)),
+            Some(time(20, 30))
+        );
+        assert_eq!(quiet_hours.end_of_quiet_period(time(20, 30)), None);
+        assert_eq!(
+            quiet_hours.end_of_quiet_period(time(23, 30)),
+            Some(time(1, 0))
+        );
+        assert_eq!(
+            quiet_hours.end_of_quiet_period(time(0, 30)),
+            Some(time(1, 0))
+        );
+        assert_eq!(quiet_hours.end_of_quiet_period(time(1, 0)), None);
+    }
+
+    #[test]
+    fn test_time_until_end() {
+        let quiet_hours = QuietHours::parse(&["23:00-01:00".to_string()]).unwrap();
+        assert_eq!(quiet_hours.time_until_end(time(12, 0)), None);
+        assert_eq!(
+            quiet_hours.time_until_end(time(23, 30)),
+            Some(Duration::from_secs(90 * 60))
+        );
+        assert_eq!(
+            quiet_hours.time_until_end(time(0, 45)),
+            Some(Duration::from_secs(15 * 60))
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::{self, ThorPatchList};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::compression::{self, decode_response_body};
+use super::config::WebConfiguration;
+use super::http::{build_http_client, PatchServerHeaders};
+
+/// Keeps track of the patch list last fetched from the patch server, so that
+/// it is only downloaded again when it changes.
+#[derive(Default)]
+pub struct PatchListWatcher {
+    patch_list_url: Option<Url>,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl PatchListWatcher {
+    /// Fetches the patch list of the preferred patch server (or of the first
+    /// one), if it changed since the last call.
+    pub async fn fetch_if_changed(
+        &mut self,
+        web_config: &WebConfiguration,
+    ) -> Result<Option<ThorPatchList>> {
+        let server = web_config
+            .preferred_patch_server
+            .as_ref()
+            .and_then(|name| web_config.patch_servers.iter().find(|s| &s.name == name))
+            .or_else(|| web_config.patch_servers.first())
+            .context("No patch server configured")?;
+        let patch_list_url =
+            Url::parse(server.plist_url.as_str()).with_context(|| "Failed to parse 'plist_url'")?;
+        if self.patch_list_url.as_ref() != Some(&patch_list_url) {
+            *self = PatchListWatcher {
+                patch_list_url: Some(patch_list_url.clone()),
+                ..Default::default()
+            };
+        }
+
+        let request = build_http_client(web_config)?.get(patch_list_url.clone());
+        let mut request = PatchServerHeaders::from_config(web_config)?
+            .apply(request, &patch_list_url)
+            .header(ACCEPT_ENCODING, compression::ACCEPT_ENCODING);
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        let resp = request.send().await.with_context(|| "Failed to GET URL")?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(anyhow!("Patch list file not found on the remote server"));
+        }
+        self.etag = resp.headers().get(ETAG).cloned();
+        self.last_modified = resp.headers().get(LAST_MODIFIED).cloned();
+        let resp_headers = resp.headers().clone();
+        let resp_body = resp
+            .bytes()
+            .await
+            .with_context(|| "Invalid response body")?;
+        let resp_body = decode_response_body(&resp_headers, &resp_body)
+            .with_context(|| "Invalid response body")?;
+
+        let patch_list = thor::patch_list_from_string(&String::from_utf8_lossy(&resp_body))
+            .with_context(|| "Invalid patch list")?;
+        Ok(Some(patch_list))
+    }
+}
+
+/// Patch list saved along with its validators (`ETag`, `Last-Modified`), so
+/// that the next fetches of the same URL (e.g., by the update following a
+/// prefetch) only download it again if it changed.
+#[derive(Serialize, Deserialize)]
+pub struct CachedPatchList {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    content: String,
+}
+
+impl CachedPatchList {
+    /// Reads the patch list cached at `path`, if it's the one served at
+    /// `patch_list_url`.
+    pub fn read(path: &Path, patch_list_url: &Url) -> Option<CachedPatchList> {
+        let cached_patch_list: CachedPatchList =
+            serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        Some(cached_patch_list).filter(|c| c.url == patch_list_url.as_str())
+    }
+
+    /// Keeps the patch list served at `patch_list_url`, unless the response's
+    /// `headers` don't give any way to tell whether it changed.
+    pub fn from_response(
+        patch_list_url: &Url,
+        headers: &HeaderMap,
+        content: &str,
+    ) -> Option<CachedPatchList> {
+        let header_value = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header_value(ETAG);
+        let last_modified = header_value(LAST_MODIFIED);
+        if etag.is_none() && last_modified.is_none() {
+            return None;
+        }
+        Some(CachedPatchList {
+            url: patch_list_url.to_string(),
+            etag,
+            last_modified,
+            content: content.to_string(),
+        })
+    }
+
+    /// Makes `request` conditional, the server answering `304 Not Modified` if
+    /// the cached patch list is still up to date.
+    pub fn apply_validators(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+        request
+    }
+
+    pub fn patch_list(&self) -> Result<ThorPatchList> {
+        thor::patch_list_from_string(&self.content).with_context(|| "Invalid patch list")
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// Returns the number of patches of `patch_list` that haven't been applied
+/// yet, given the index of the last applied patch.
+///
+/// Follows the same rules as the update: the cached index is ignored if it
+/// isn't part of the patch list.
+pub fn count_new_patches(
+    patch_list: &[thor::ThorPatchInfo],
+    last_patch_index: Option<usize>,
+) -> usize {
+    match last_patch_index {
+        Some(last_patch_index) if patch_list.iter().any(|x| x.index == last_patch_index) => {
+            patch_list
+                .iter()
+                .filter(|x| x.index > last_patch_index)
+                .count()
+        }
+        _ => patch_list.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_new_patches() {
+        let patch_list = thor::patch_list_from_string("1 a.thor\n2 b.thor\n5 c.thor\n").unwrap();
+        assert_eq!(count_new_patches(&patch_list, None), 3);
+        assert_eq!(count_new_patches(&patch_list, Some(2)), 1);
+        assert_eq!(count_new_patches(&patch_list, Some(5)), 0);
+        // Unknown index
+        assert_eq!(count_new_patches(&patch_list, Some(3)), 3);
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use gruf::thor::ThorPatchInfo;
+
+/// Version of the patching engine, which patches' `requires_patcher`
+/// constraints are checked against.
+pub const PATCHER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Makes sure that the patches of `patch_list` can be applied in order.
+///
+/// `last_patch_index` is the index of the last patch known to have been
+/// applied, if any. When it's unknown, the first patch's `requires_index`
+/// constraint cannot be checked.
+pub fn check_patch_requirements(
+    patch_list: &[ThorPatchInfo],
+    last_patch_index: Option<usize>,
+    patcher_version: &str,
+) -> Result<()> {
+    let mut last_patch_index = last_patch_index;
+    for patch_info in patch_list {
+        if let Some(required_version) = &patch_info.requires_patcher {
+            if !is_version_at_least(patcher_version, required_version)? {
+                return Err(anyhow!(
+                    "Patch '{}' requires version {} of the patcher (this is version {}), please update the patcher",
+                    patch_info.file_name,
+                    required_version,
+                    patcher_version
+                ));
+            }
+        }
+        match (patch_info.requires_index, last_patch_index) {
+            (Some(required_index), Some(index)) if index < required_index => {
+                return Err(anyhow!(
+                    "Patch '{}' requires patch {} to be applied first, the game client must be reinstalled",
+                    patch_info.file_name,
+                    required_index
+                ));
+            }
+            _ => {}
+        }
+        last_patch_index = Some(patch_info.index);
+    }
+    Ok(())
+}
+
+/// Compares dotted version numbers (e.g., "0.3.1" and "0.4"). Missing
+/// components count as zeros.
+fn is_version_at_least(version: &str, minimum_version: &str) -> Result<bool> {
+    let parse_version = |version: &str| -> Result<Vec<u64>> {
+        // Pre-release and build metadata are ignored
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|component| {
+                component
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid version '{}'", version))
+            })
+            .collect()
+    };
+    let mut version = parse_version(version)?;
+    let mut minimum_version = parse_version(minimum_version)?;
+    let len = version.len().max(minimum_version.len());
+    version.resize(len, 0);
+    minimum_version.resize(len, 0);
+    Ok(version >= minimum_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_info(
+        index: usize,
+        requires_index: Option<usize>,
+        requires_patcher: Option<&str>,
+    ) -> ThorPatchInfo {
+        ThorPatchInfo {
+            index,
+            file_name: format!("{}.thor", index),
+            requires_index,
+            requires_patcher: requires_patcher.map(|v| v.to_string()),
+            magnet_link: None,
+        }
+    }
+
+    #[test]
+    fn test_check_patch_requirements() {
+        let patch_list = vec![
+            patch_info(10, Some(9), None),
+            patch_info(11, Some(10), None),
+        ];
+        assert!(check_patch_requirements(&patch_list, Some(9), "0.3.0").is_ok());
+        // The state of the client is unknown
+        assert!(check_patch_requirements(&patch_list, None, "0.3.0").is_ok());
+        // Patch 9 is missing
+        assert!(check_patch_requirements(&patch_list, Some(8), "0.3.0").is_err());
+
+        let patch_list = vec![patch_info(10, None, None), patch_info(12, Some(11), None)];
+        assert!(check_patch_requirements(&patch_list, None, "0.3.0").is_err());
+
+        let patch_list = vec![patch_info(10, None, Some("0.4"))];
+        assert!(check_patch_requirements(&patch_list, None, "0.3.0").is_err());
+        assert!(check_patch_requirements(&patch_list, None, "0.4.0").is_ok());
+    }
+
+    #[test]
+    fn test_is_version_at_least() {
+        assert!(is_version_at_least("0.3.0", "0.3").unwrap());
+        assert!(is_version_at_least("0.10.0", "0.9.1").unwrap());
+        assert!(is_version_at_least("1.0.0-beta", "1.0").unwrap());
+        assert!(!is_version_at_least("0.3.0", "0.3.1").unwrap());
+        assert!(is_version_at_least("0.3.0", "latest").is_err());
+    }
+}
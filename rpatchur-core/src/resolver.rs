@@ -0,0 +1,499 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use super::config::WebConfiguration;
+
+const MAX_REQUEST_HEAD_SIZE: usize = 16 * 1024;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+
+const PROXY_USER_NAME: &str = "rpatchur";
+
+/// Resolving proxies started so far, one per resolver configuration.
+static RESOLVING_PROXIES: Mutex<Vec<(HostResolver, ResolvingProxy)>> = Mutex::new(Vec::new());
+
+/// Resolves patch servers' host names, using the addresses given in
+/// `web.host_overrides` or a DNS-over-HTTPS resolver instead of the system's
+/// resolver.
+#[derive(Clone, PartialEq)]
+pub struct HostResolver {
+    host_overrides: HashMap<String, IpAddr>, // Lowercase host name -> Address
+    dns_over_https_url: Option<Url>,
+    ipv4_only: bool,
+}
+
+impl HostResolver {
+    /// Returns `None` if host names should be resolved by the system.
+    pub fn from_config(web_config: &WebConfiguration) -> Result<Option<HostResolver>> {
+        let host_overrides = web_config
+            .host_overrides
+            .iter()
+            .flatten()
+            .map(|(host, address)| {
+                let address: IpAddr = address.parse().with_context(|| {
+                    format!("Invalid IP address '{}' for host '{}'", address, host)
+                })?;
+                Ok((host.to_ascii_lowercase(), address))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let dns_over_https_url = match &web_config.dns_over_https_url {
+            None => None,
+            Some(url) => {
+                Some(Url::parse(url).with_context(|| "Failed to parse 'dns_over_https_url'")?)
+            }
+        };
+        if host_overrides.is_empty() && dns_over_https_url.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(HostResolver {
+            host_overrides,
+            dns_over_https_url,
+            ipv4_only: web_config.force_ipv4.unwrap_or(false),
+        }))
+    }
+
+    /// Indicates whether the system's resolver should be bypassed for `host`.
+    pub fn handles_host(&self, host: &str) -> bool {
+        self.dns_over_https_url.is_some()
+            || self.host_overrides.contains_key(&host.to_ascii_lowercase())
+    }
+
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addresses = if let Ok(address) = host.parse::<IpAddr>() {
+            vec![address]
+        } else if let Some(address) = self.host_overrides.get(&host.to_ascii_lowercase()) {
+            vec![*address]
+        } else if let Some(dns_over_https_url) = &self.dns_over_https_url {
+            resolve_with_dns_over_https(dns_over_https_url, host, self.ipv4_only).await?
+        } else {
+            tokio::net::lookup_host((host, port))
+                .await?
+                .map(|address| address.ip())
+                .collect()
+        };
+        let addresses: Vec<SocketAddr> = addresses
+            .into_iter()
+            .filter(|address| !self.ipv4_only || address.is_ipv4())
+            .map(|address| SocketAddr::new(address, port))
+            .collect();
+        if addresses.is_empty() {
+            return Err(anyhow!("Failed to resolve '{}'", host));
+        }
+        Ok(addresses)
+    }
+}
+
+/// Local HTTP proxy that connects to the hosts resolved by a `HostResolver`.
+///
+/// This is how `HostResolver` is plugged into the HTTP client, which cannot
+/// be given a custom resolver (reqwest 0.11.3 has neither `resolve` nor
+/// `dns_resolver`): HTTPS requests go through `CONNECT` tunnels (so TLS is
+/// still end-to-end) and plain HTTP requests are forwarded as-is.
+///
+/// Other local programs cannot use it, requests must be authenticated with a
+/// random password and only target the hosts handled by the resolver.
+#[derive(Clone)]
+struct ResolvingProxy {
+    address: SocketAddr,
+    password: String,
+}
+
+impl ResolvingProxy {
+    fn start(resolver: HostResolver) -> Result<ResolvingProxy> {
+        let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .with_context(|| "Failed to start the resolving proxy")?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let password = random_password();
+        let authorization = proxy_authorization(&password);
+        let tokio_rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .with_context(|| "Failed to build a tokio runtime")?;
+        thread::spawn(move || {
+            tokio_rt.block_on(serve_resolving_proxy(listener, resolver, authorization))
+        });
+        log::debug!("Resolving proxy listening on {}", address);
+        Ok(ResolvingProxy { address, password })
+    }
+
+    /// URL of the proxy, including its credentials.
+    fn url(&self) -> String {
+        format!(
+            "http://{}:{}@{}",
+            PROXY_USER_NAME, self.password, self.address
+        )
+    }
+}
+
+/// Returns a proxy for the HTTP client, which resolves the hosts handled by
+/// `resolver` (and only them) itself. The underlying local proxy is started
+/// if needed.
+pub fn resolving_proxy(resolver: &HostResolver) -> Result<reqwest::Proxy> {
+    let mut proxies = RESOLVING_PROXIES
+        .lock()
+        .map_err(|_| anyhow!("Resolving proxy state is poisoned"))?;
+    let proxy = match proxies.iter().find(|(r, _)| r == resolver) {
+        Some((_, proxy)) => proxy.clone(),
+        None => {
+            let proxy = ResolvingProxy::start(resolver.clone())?;
+            proxies.push((resolver.clone(), proxy.clone()));
+            proxy
+        }
+    };
+    let proxy_url = proxy.url();
+    let resolver = resolver.clone();
+    Ok(reqwest::Proxy::custom(move |url| {
+        url.host_str()
+            .filter(|host| resolver.handles_host(host))
+            .map(|_| proxy_url.clone())
+    }))
+}
+
+/// 128 bits taken from the randomly seeded keys of the standard hasher.
+fn random_password() -> String {
+    (0..2)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
+
+/// Value of the `Proxy-Authorization` header expected by the proxy.
+fn proxy_authorization(password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", PROXY_USER_NAME, password))
+    )
+}
+
+async fn serve_resolving_proxy(
+    listener: StdTcpListener,
+    resolver: HostResolver,
+    authorization: String,
+) {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to start the resolving proxy: {}", e);
+            return;
+        }
+    };
+    let resolver = Arc::new(resolver);
+    let authorization = Arc::new(authorization);
+    loop {
+        match listener.accept().await {
+            Err(e) => log::warn!("Failed to accept proxy connection: {}", e),
+            Ok((stream, _)) => {
+                let resolver = resolver.clone();
+                let authorization = authorization.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_proxy_connection(stream, &resolver, &authorization).await
+                    {
+                        log::warn!("Proxied connection failed: {:#}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+async fn handle_proxy_connection(
+    mut stream: TcpStream,
+    resolver: &HostResolver,
+    authorization: &str,
+) -> Result<()> {
+    let request_head = read_request_head(&mut stream).await?;
+    let (is_tunnel, host, port) = parse_proxy_request(&request_head)?;
+    if !is_request_authorized(&request_head, authorization) {
+        stream
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+            .await?;
+        return Err(anyhow!("Unauthenticated proxy request"));
+    }
+    if !resolver.handles_host(&host) {
+        stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(anyhow!("Proxy request to unexpected host '{}'", host));
+    }
+    let addresses = resolver.resolve(&host, port).await?;
+    let mut upstream = TcpStream::connect(&addresses[..])
+        .await
+        .with_context(|| format!("Failed to connect to '{}'", host))?;
+    if is_tunnel {
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+    } else {
+        // Servers must accept absolute URIs, the request can be sent unchanged
+        upstream.write_all(&request_head).await?;
+    }
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+/// Reads the request line and headers of a proxy request (and whatever
+/// follows them in the last read).
+async fn read_request_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request_head = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while !request_head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request_head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(anyhow!("Proxy request is too large"));
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(anyhow!("Connection closed before the end of the request"));
+        }
+        request_head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(request_head)
+}
+
+/// Extracts the target of a proxy request: whether it's a `CONNECT` tunnel,
+/// the host and the port.
+fn parse_proxy_request(request_head: &[u8]) -> Result<(bool, String, u16)> {
+    let request_line = request_head
+        .split(|&c| c == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .context("Invalid proxy request")?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().context("Invalid proxy request")?;
+    if method == "CONNECT" {
+        let url = Url::parse(&format!("https://{}", target))
+            .with_context(|| format!("Invalid tunnel target '{}'", target))?;
+        let host = url.host_str().context("Invalid tunnel target")?;
+        return Ok((
+            true,
+            trim_brackets(host),
+            url.port_or_known_default().unwrap_or(443),
+        ));
+    }
+    let url = Url::parse(target).with_context(|| format!("Invalid request target '{}'", target))?;
+    let host = url.host_str().context("Invalid request target")?;
+    let port = url
+        .port_or_known_default()
+        .context("Invalid request target")?;
+    Ok((false, trim_brackets(host), port))
+}
+
+/// Indicates whether the `Proxy-Authorization` header of a proxy request is
+/// `authorization`.
+fn is_request_authorized(request_head: &[u8], authorization: &str) -> bool {
+    String::from_utf8_lossy(request_head)
+        .split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("proxy-authorization") && value.trim() == authorization
+        })
+}
+
+/// IPv6 addresses are enclosed in brackets in URLs.
+fn trim_brackets(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string()
+}
+
+/// Subset of the JSON format used by DNS-over-HTTPS resolvers (e.g.,
+/// Cloudflare's or Google's) that's needed to get addresses.
+#[derive(Deserialize)]
+struct DnsJsonResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsJsonAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsJsonAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+async fn resolve_with_dns_over_https(
+    dns_over_https_url: &Url,
+    host: &str,
+    ipv4_only: bool,
+) -> Result<Vec<IpAddr>> {
+    // The resolver itself is reached through the system's resolver
+    let client = reqwest::Client::builder().no_proxy().build()?;
+    let record_types: &[(u16, &str)] = if ipv4_only {
+        &[(DNS_TYPE_A, "A")]
+    } else {
+        &[(DNS_TYPE_A, "A"), (DNS_TYPE_AAAA, "AAAA")]
+    };
+    let mut addresses = Vec::new();
+    for (record_type, record_type_name) in record_types {
+        let response_content = client
+            .get(dns_over_https_url.clone())
+            .query(&[("name", host), ("type", record_type_name)])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await
+            .with_context(|| "Failed to reach the DNS-over-HTTPS resolver")?
+            .error_for_status()?
+            .text()
+            .await?;
+        let response: DnsJsonResponse = serde_json::from_str(&response_content)
+            .with_context(|| "Invalid DNS-over-HTTPS response")?;
+        addresses.extend(parse_dns_json_answers(&response, *record_type));
+    }
+    Ok(addresses)
+}
+
+fn parse_dns_json_answers(response: &DnsJsonResponse, record_type: u16) -> Vec<IpAddr> {
+    // Non-zero statuses are DNS errors (e.g., NXDOMAIN)
+    if response.status != 0 {
+        return vec![];
+    }
+    // Answers can also contain CNAME records, which are followed by the resolver
+    response
+        .answer
+        .iter()
+        .filter(|answer| answer.record_type == record_type)
+        .filter_map(|answer| answer.data.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    #[test]
+    fn test_parse_proxy_request() {
+        assert_eq!(
+            parse_proxy_request(b"CONNECT patch.example.com:443 HTTP/1.1\r\n\r\n").unwrap(),
+            (true, "patch.example.com".to_string(), 443)
+        );
+        assert_eq!(
+            parse_proxy_request(
+                b"GET http://patch.example.com:8080/plist.txt HTTP/1.1\r\nhost: patch.example.com:8080\r\n\r\n"
+            )
+            .unwrap(),
+            (false, "patch.example.com".to_string(), 8080)
+        );
+        assert_eq!(
+            parse_proxy_request(b"CONNECT [::1]:8443 HTTP/1.1\r\n\r\n").unwrap(),
+            (true, "::1".to_string(), 8443)
+        );
+        assert!(parse_proxy_request(b"GET /plist.txt HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_is_request_authorized() {
+        let authorization = proxy_authorization("secret");
+        let request_head = format!(
+            "CONNECT patch.example.com:443 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+            authorization
+        );
+        assert!(is_request_authorized(
+            request_head.as_bytes(),
+            &authorization
+        ));
+        assert!(!is_request_authorized(
+            b"CONNECT patch.example.com:443 HTTP/1.1\r\n\r\n",
+            &authorization
+        ));
+        assert!(!is_request_authorized(
+            request_head.as_bytes(),
+            &proxy_authorization("other")
+        ));
+    }
+
+    #[test]
+    fn test_parse_dns_json_answers() {
+        let response: DnsJsonResponse = serde_json::from_str(
+            r#"{"Status": 0, "Answer": [
+                {"name": "patch.example.com", "type": 5, "TTL": 300, "data": "cdn.example.com."},
+                {"name": "cdn.example.com", "type": 1, "TTL": 300, "data": "203.0.113.7"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parse_dns_json_answers(&response, DNS_TYPE_A),
+            vec!["203.0.113.7".parse::<IpAddr>().unwrap()]
+        );
+        let response: DnsJsonResponse = serde_json::from_str(r#"{"Status": 3}"#).unwrap();
+        assert!(parse_dns_json_answers(&response, DNS_TYPE_A).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_host_override() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/plist.txt"))
+                .respond_with(status_code(200).body("1 patch.thor")),
+        );
+        let resolver = HostResolver {
+            host_overrides: vec![("patch.example.com".to_string(), server.addr().ip())]
+                .into_iter()
+                .collect(),
+            dns_over_https_url: None,
+            ipv4_only: false,
+        };
+        assert!(resolver.handles_host("PATCH.example.com"));
+        assert!(!resolver.handles_host("www.example.com"));
+        let client = reqwest::Client::builder()
+            .proxy(resolving_proxy(&resolver).unwrap())
+            .build()
+            .unwrap();
+        let body = client
+            .get(format!(
+                "http://patch.example.com:{}/plist.txt",
+                server.addr().port()
+            ))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "1 patch.thor");
+
+        // The proxy cannot be used without credentials, or for other hosts
+        let proxy = RESOLVING_PROXIES
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(r, _)| *r == resolver)
+            .map(|(_, proxy)| proxy.clone())
+            .unwrap();
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(format!("http://{}", proxy.address)).unwrap())
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!(
+                "http://patch.example.com:{}/plist.txt",
+                server.addr().port()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 407);
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(proxy.url()).unwrap())
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("http://{}/plist.txt", server.addr()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::{GrfArchive, GrfArchiveBuilder};
+use gruf::thor::ThorArchive;
+use serde::{Deserialize, Serialize};
+
+use super::file_attributes::with_writable_file;
+
+pub(crate) const MANIFEST_FILE_NAME: &str = "restore_point.json";
+
+/// State of the game's GRFs at the time a restore point was created.
+///
+/// A GRF's file table is captured the first time it's patched after the
+/// restore point's creation, along with the original content of the entries
+/// that patches modify (copy-on-write). Files patched outside of GRFs are not
+/// covered.
+#[derive(Serialize, Deserialize, Default)]
+struct RestorePoint {
+    last_patch_index: Option<usize>, // Patcher cache at the time of creation
+    grfs: HashMap<String, GrfSnapshot>, // Indexed by GRF name
+}
+
+#[derive(Serialize, Deserialize)]
+struct GrfSnapshot {
+    existed: bool,
+    entries: HashSet<String>, // File table at the time of the snapshot
+    saved_entries: HashMap<String, String>, // Entry name -> Name of the file storing its content
+}
+
+/// Creates a restore point in `directory`, replacing the previous one (if
+/// any).
+pub fn create_restore_point(directory: &Path, last_patch_index: Option<usize>) -> Result<()> {
+    if directory.exists() {
+        fs::remove_dir_all(directory).with_context(|| "Failed to remove previous restore point")?;
+    }
+    fs::create_dir_all(directory)?;
+    write_manifest(
+        directory,
+        &RestorePoint {
+            last_patch_index,
+            ..Default::default()
+        },
+    )
+}
+
+/// Saves the original content of the entries of a GRF that a THOR patch is
+/// about to modify, if there's a restore point in `directory`.
+pub fn save_grf_entries<R: Read + Seek>(
+    directory: &Path,
+    grf_name: &str,
+    grf_file_path: &Path,
+    thor_archive: &ThorArchive<R>,
+) -> Result<()> {
+    let mut restore_point = match read_manifest(directory) {
+        Err(_) => return Ok(()), // No restore point
+        Ok(v) => v,
+    };
+    let mut grf_archive = if grf_file_path.exists() {
+        Some(GrfArchive::open_shared(grf_file_path)?)
+    } else {
+        None
+    };
+    // Files storing entries' content are numbered
+    let mut next_file_index: usize = restore_point
+        .grfs
+        .values()
+        .map(|snapshot| snapshot.saved_entries.len())
+        .sum();
+    let snapshot = restore_point
+        .grfs
+        .entry(grf_name.to_string())
+        .or_insert_with(|| GrfSnapshot {
+            existed: grf_archive.is_some(),
+            entries: grf_archive
+                .iter()
+                .flat_map(|archive| archive.get_entries())
+                .map(|entry| entry.relative_path.clone())
+                .collect(),
+            saved_entries: HashMap::new(),
+        });
+    let grf_archive = match &mut grf_archive {
+        Some(v) => v,
+        None => return write_manifest(directory, &restore_point),
+    };
+    for entry in thor_archive.get_entries().filter(|e| !e.is_internal()) {
+        let entry_name = &entry.relative_path;
+        // Entries that didn't exist are simply removed on restoration
+        if !snapshot.entries.contains(entry_name)
+            || snapshot.saved_entries.contains_key(entry_name)
+            || !grf_archive.contains_file(entry_name)
+        {
+            continue;
+        }
+        let content = grf_archive.read_file_content(entry_name)?;
+        let file_name = format!("{}.bin", next_file_index);
+        fs::write(directory.join(&file_name), content)?;
+        next_file_index += 1;
+        snapshot.saved_entries.insert(entry_name.clone(), file_name);
+    }
+    write_manifest(directory, &restore_point)
+}
+
+/// Restores the GRFs located in `root_directory` to the state they were in
+/// when the restore point in `directory` was created.
+///
+/// Returns the index of the last patch that had been applied at that time,
+/// if any.
+pub fn restore_from_point(directory: &Path, root_directory: &Path) -> Result<Option<usize>> {
+    let restore_point =
+        read_manifest(directory).with_context(|| "Failed to read the restore point")?;
+    for (grf_name, snapshot) in &restore_point.grfs {
+        log::info!("Restoring '{}'", grf_name);
+        let grf_file_path = root_directory.join(grf_name);
+        if !snapshot.existed {
+            if grf_file_path.exists() {
+                fs::remove_file(&grf_file_path)?;
+            }
+            continue;
+        }
+        if !grf_file_path.exists() {
+            return Err(anyhow!("GRF '{}' does not exist", grf_file_path.display()));
+        }
+        with_writable_file(&grf_file_path, || {
+            let added_entries: Vec<String> = GrfArchive::open_shared(&grf_file_path)?
+                .get_entries()
+                .map(|entry| entry.relative_path.clone())
+                .filter(|entry_name| !snapshot.entries.contains(entry_name))
+                .collect();
+            let mut builder = GrfArchiveBuilder::open(&grf_file_path)?;
+            for entry_name in added_entries {
+                builder.remove_file(&entry_name)?;
+            }
+            for (entry_name, file_name) in &snapshot.saved_entries {
+                let content = File::open(directory.join(file_name))?;
+                builder.add_file(entry_name.clone(), content)?;
+            }
+            Ok(builder.finish()?)
+        })?;
+    }
+    Ok(restore_point.last_patch_index)
+}
+
+fn read_manifest(directory: &Path) -> Result<RestorePoint> {
+    let file = File::open(directory.join(MANIFEST_FILE_NAME))?;
+    serde_json::from_reader(file).context("Failed to deserialize restore point")
+}
+
+fn write_manifest(directory: &Path, restore_point: &RestorePoint) -> Result<()> {
+    let file = File::create(directory.join(MANIFEST_FILE_NAME))?;
+    serde_json::to_writer(file, restore_point).context("Failed to serialize restore point")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateEntryPolicy;
+    use crate::patching::{apply_patch_to_grf, GrfPatchingMethod, GrfWriteOptions};
+    use crate::test_fixtures::build_grf_patch;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_restore_from_point() {
+        let temp_dir = tempdir().unwrap();
+        let restore_point_dir = temp_dir.path().join("rpatchur.restore");
+        let grf_file_path = temp_dir.path().join("data.grf");
+        {
+            let grf_file = File::create(&grf_file_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\modified.txt".to_string(), &b"original"[..])
+                .unwrap();
+            builder
+                .add_file("data\\removed.txt".to_string(), &b"removed"[..])
+                .unwrap();
+            builder
+                .add_file("data\\untouched.txt".to_string(), &b"untouched"[..])
+                .unwrap();
+        }
+        create_restore_point(&restore_point_dir, Some(42)).unwrap();
+
+        let apply_patch = |thor_archive_name: &str, entries: &[(&str, Option<&[u8]>)]| {
+            let thor_archive_path = temp_dir.path().join(thor_archive_name);
+            build_grf_patch(&thor_archive_path, entries);
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            save_grf_entries(
+                &restore_point_dir,
+                "data.grf",
+                &grf_file_path,
+                &thor_archive,
+            )
+            .unwrap();
+            apply_patch_to_grf(
+                GrfPatchingMethod::InPlace,
+                false,
+                &GrfWriteOptions {
+                    memory_budget: usize::MAX,
+                    temp_directory: None,
+                    duplicate_entries: DuplicateEntryPolicy::UseGrfName,
+                },
+                &grf_file_path,
+                &mut thor_archive,
+                |_, _, _| {},
+            )
+            .unwrap();
+        };
+        // Both patches modify the same entry
+        apply_patch(
+            "1.thor",
+            &[
+                ("data\\modified.txt", Some(b"first patch")),
+                ("data\\removed.txt", None),
+            ],
+        );
+        apply_patch(
+            "2.thor",
+            &[
+                ("data\\modified.txt", Some(b"second patch")),
+                ("data\\added.txt", Some(b"added")),
+            ],
+        );
+
+        assert_eq!(
+            restore_from_point(&restore_point_dir, temp_dir.path()).unwrap(),
+            Some(42)
+        );
+        let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 3);
+        assert!(!grf_archive.contains_file("data\\added.txt"));
+        assert_eq!(
+            grf_archive.read_file_content("data\\modified.txt").unwrap(),
+            b"original"
+        );
+        assert_eq!(
+            grf_archive.read_file_content("data\\removed.txt").unwrap(),
+            b"removed"
+        );
+        assert_eq!(
+            grf_archive
+                .read_file_content("data\\untouched.txt")
+                .unwrap(),
+            b"untouched"
+        );
+    }
+
+    #[test]
+    fn test_save_grf_entries_without_restore_point() {
+        let temp_dir = tempdir().unwrap();
+        let restore_point_dir = temp_dir.path().join("rpatchur.restore");
+        let thor_archive_path = temp_dir.path().join("patch.thor");
+        build_grf_patch(&thor_archive_path, &[("data\\new.txt", Some(b"new"))]);
+        let thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        save_grf_entries(
+            &restore_point_dir,
+            "data.grf",
+            &temp_dir.path().join("data.grf"),
+            &thor_archive,
+        )
+        .unwrap();
+        assert!(!restore_point_dir.exists());
+    }
+}
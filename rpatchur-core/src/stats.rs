@@ -0,0 +1,456 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Throughputs used to weigh the download and installation stages until actual
+/// ones have been measured.
+const DEFAULT_NETWORK_BYTES_PER_SEC: f64 = 2.0 * 1024.0 * 1024.0;
+const DEFAULT_DISK_BYTES_PER_SEC: f64 = 32.0 * 1024.0 * 1024.0;
+
+/// Statistics gathered during an update session, shared by concurrent
+/// downloads.
+pub struct SessionStats {
+    started_at: Instant,
+    state: Mutex<SessionStatsState>,
+}
+
+#[derive(Default)]
+struct SessionStatsState {
+    downloaded_bytes: u64,
+    peak_bytes_per_sec: u64,
+    retries: usize,
+    patch_count: usize,
+    stage: Option<(SessionStage, Instant)>, // Current stage and when it started
+    last_progress: Option<Instant>,         // Heartbeat, updated whenever progress is made
+    lookup_duration: Duration,
+    download_duration: Duration,
+    installation_duration: Duration,
+    download_count: Option<usize>, // Patches to download, once known
+    started_downloads: usize,
+    started_download_bytes: u64, // Expected sizes of the downloads started so far
+    download_bytes: Option<u64>, // Size of all the downloads, once they're over
+    installation_bytes: Option<u64>, // Size of the patches to install, once known
+    installed_bytes: u64,        // Size of the patches installed
+    current_patch: (u64, f64), // Size of the patch being installed, Fraction of its entries processed
+    reported_progress: f32,    // So that the overall progress never goes backwards
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionStage {
+    Lookup,       // Looking for an available patch server
+    Download,     // Downloading patches
+    Installation, // Applying patches
+}
+
+impl SessionStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionStage::Lookup => "lookup",
+            SessionStage::Download => "download",
+            SessionStage::Installation => "installation",
+        }
+    }
+}
+
+/// Summary of an update session, dispatched once patches have been applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatchingSummary {
+    pub patch_count: usize, // Patches applied
+    pub downloaded_bytes: u64,
+    pub average_bytes_per_sec: u64, // Over the download stage
+    pub peak_bytes_per_sec: u64,
+    pub retries: usize, // Unavailable patch servers, failed peer downloads and retried patches
+    pub lookup_secs: f64,
+    pub download_secs: f64,
+    pub installation_secs: f64,
+    pub total_secs: f64,
+}
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats {
+            started_at: Instant::now(),
+            state: Mutex::new(SessionStatsState::default()),
+        }
+    }
+
+    /// Ends the current stage, if any, and starts timing `stage`.
+    pub fn start_stage(&self, stage: SessionStage) {
+        if let Ok(mut state) = self.state.lock() {
+            state.end_stage();
+            state.stage = Some((stage, Instant::now()));
+            state.last_progress = Some(Instant::now());
+        }
+    }
+
+    pub fn add_downloaded_bytes(&self, byte_count: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.downloaded_bytes += byte_count;
+            if byte_count > 0 {
+                state.last_progress = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Returns the bytes downloaded so far.
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.state.lock().map_or(0, |state| state.downloaded_bytes)
+    }
+
+    pub fn record_download_speed(&self, bytes_per_sec: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.peak_bytes_per_sec = state.peak_bytes_per_sec.max(bytes_per_sec);
+        }
+    }
+
+    pub fn add_retry(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.retries += 1;
+        }
+    }
+
+    pub fn add_applied_patch(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.patch_count += 1;
+            state.last_progress = Some(Instant::now());
+        }
+    }
+
+    /// Sets the number of patches about to be downloaded.
+    pub fn expect_downloads(&self, patch_count: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.download_count = Some(patch_count);
+        }
+    }
+
+    /// Records that a download of `expected_bytes` has started.
+    pub fn start_download(&self, expected_bytes: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.started_downloads += 1;
+            state.started_download_bytes += expected_bytes;
+        }
+    }
+
+    /// Records that a patch doesn't have to be downloaded after all (e.g., it's
+    /// been downloaded by an interrupted update).
+    pub fn skip_download(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.download_count = state.download_count.map(|v| v.saturating_sub(1));
+        }
+    }
+
+    /// Sets the total size of the patches about to be installed, which ends
+    /// the download estimates.
+    pub fn expect_installation(&self, patch_bytes: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.download_bytes = Some(state.downloaded_bytes);
+            state.installation_bytes = Some(patch_bytes);
+        }
+    }
+
+    pub fn start_patch_installation(&self, patch_bytes: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.current_patch = (patch_bytes, 0.0);
+            state.last_progress = Some(Instant::now());
+        }
+    }
+
+    pub fn record_entry_progress(&self, nb_processed: usize, nb_total: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            if nb_total > 0 {
+                state.current_patch.1 = nb_processed.min(nb_total) as f64 / nb_total as f64;
+            }
+            state.last_progress = Some(Instant::now());
+        }
+    }
+
+    /// Records that the patch being installed is done with, whether it's been
+    /// applied or skipped.
+    pub fn finish_patch_installation(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.installed_bytes += state.current_patch.0;
+            state.current_patch = (0, 0.0);
+        }
+    }
+
+    /// Returns the progress of the whole update, download and installation
+    /// included, as a percentage along with the estimated number of seconds
+    /// left.
+    ///
+    /// Stages are weighed by how long they're expected to take, given the
+    /// network and disk throughputs measured so far. The remaining time isn't
+    /// estimated until one of them has been measured. Returns `None` until the
+    /// size of the update can be estimated.
+    pub fn overall_progress(&self) -> Option<(f32, Option<u64>)> {
+        let mut state = self.state.lock().ok()?;
+        let download_bytes = state.estimated_download_bytes()?;
+        let installation_bytes = state.installation_bytes.unwrap_or(download_bytes);
+        let downloaded_bytes = state.downloaded_bytes.min(download_bytes);
+        let installed_bytes = state.installed_progress_bytes().min(installation_bytes);
+        let network_rate = measured_rate(
+            downloaded_bytes,
+            state.stage_duration(SessionStage::Download),
+        );
+        let disk_rate = measured_rate(
+            installed_bytes,
+            state.stage_duration(SessionStage::Installation),
+        );
+        let secs = |download_bytes: u64, installation_bytes: u64| {
+            download_bytes as f64 / network_rate.unwrap_or(DEFAULT_NETWORK_BYTES_PER_SEC)
+                + installation_bytes as f64 / disk_rate.unwrap_or(DEFAULT_DISK_BYTES_PER_SEC)
+        };
+        let total_secs = secs(download_bytes, installation_bytes);
+        let remaining_secs = secs(
+            download_bytes - downloaded_bytes,
+            installation_bytes - installed_bytes,
+        );
+        let percent = if total_secs > 0.0 {
+            (100.0 * (1.0 - remaining_secs / total_secs)) as f32
+        } else {
+            100.0
+        };
+        state.reported_progress = state.reported_progress.max(percent);
+        let eta_secs = match (network_rate, disk_rate) {
+            (None, None) => None,
+            _ => Some(remaining_secs.round() as u64),
+        };
+        Some((state.reported_progress, eta_secs))
+    }
+
+    /// Returns the current stage and how long it's been since progress was
+    /// last made, if a stage is in progress.
+    pub fn time_since_progress(&self) -> Option<(SessionStage, Duration)> {
+        let state = self.state.lock().ok()?;
+        let (stage, _) = state.stage?;
+        Some((stage, state.last_progress?.elapsed()))
+    }
+
+    /// Ends the current stage and returns the summary of the session.
+    pub fn summary(&self) -> PatchingSummary {
+        let mut state = match self.state.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+        state.end_stage();
+        let download_secs = state.download_duration.as_secs_f64();
+        let average_bytes_per_sec = if download_secs > 0.0 {
+            (state.downloaded_bytes as f64 / download_secs).round() as u64
+        } else {
+            0
+        };
+        PatchingSummary {
+            patch_count: state.patch_count,
+            downloaded_bytes: state.downloaded_bytes,
+            average_bytes_per_sec,
+            peak_bytes_per_sec: state.peak_bytes_per_sec.max(average_bytes_per_sec),
+            retries: state.retries,
+            lookup_secs: state.lookup_duration.as_secs_f64(),
+            download_secs,
+            installation_secs: state.installation_duration.as_secs_f64(),
+            total_secs: self.started_at.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> SessionStats {
+        SessionStats::new()
+    }
+}
+
+impl SessionStatsState {
+    fn end_stage(&mut self) {
+        if let Some((stage, started_at)) = self.stage.take() {
+            let elapsed = started_at.elapsed();
+            match stage {
+                SessionStage::Lookup => self.lookup_duration += elapsed,
+                SessionStage::Download => self.download_duration += elapsed,
+                SessionStage::Installation => self.installation_duration += elapsed,
+            }
+        }
+    }
+
+    /// Returns the size of all the downloads, extrapolated from the ones that
+    /// have started if downloads are still in progress.
+    fn estimated_download_bytes(&self) -> Option<u64> {
+        if let Some(download_bytes) = self.download_bytes {
+            return Some(download_bytes);
+        }
+        let download_count = self.download_count?;
+        if download_count == 0 {
+            return Some(0);
+        }
+        if self.started_downloads == 0 {
+            return None;
+        }
+        let estimate = self.started_download_bytes as f64 * download_count as f64
+            / self.started_downloads as f64;
+        Some((estimate.round() as u64).max(self.downloaded_bytes))
+    }
+
+    fn installed_progress_bytes(&self) -> u64 {
+        let (patch_bytes, fraction) = self.current_patch;
+        self.installed_bytes + (patch_bytes as f64 * fraction).round() as u64
+    }
+
+    /// Returns the time spent in `stage` so far.
+    fn stage_duration(&self, stage: SessionStage) -> Duration {
+        let ended = match stage {
+            SessionStage::Lookup => self.lookup_duration,
+            SessionStage::Download => self.download_duration,
+            SessionStage::Installation => self.installation_duration,
+        };
+        match self.stage {
+            Some((current_stage, started_at)) if current_stage == stage => {
+                ended + started_at.elapsed()
+            }
+            _ => ended,
+        }
+    }
+}
+
+/// Returns the throughput for `byte_count` bytes processed in `duration`, if
+/// there's enough data for it to mean anything.
+fn measured_rate(byte_count: u64, duration: Duration) -> Option<f64> {
+    const MIN_DURATION: Duration = Duration::from_millis(500);
+    if byte_count == 0 || duration < MIN_DURATION {
+        return None;
+    }
+    Some(byte_count as f64 / duration.as_secs_f64())
+}
+
+impl PatchingSummary {
+    /// Returns a JSON representation of the summary (e.g., for machine-readable
+    /// output).
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({ "status": "summary" });
+        if let (Some(object), Value::Object(fields)) = (value.as_object_mut(), json!(self)) {
+            object.extend(fields);
+        }
+        value
+    }
+
+    /// Returns a short, human-readable description of the session (e.g.,
+    /// "Updated 12 files, 840.0 MiB in 3m12s").
+    pub fn describe(&self) -> String {
+        let total_secs = self.total_secs.round() as u64;
+        let duration = if total_secs >= 60 {
+            format!("{}m{:02}s", total_secs / 60, total_secs % 60)
+        } else {
+            format!("{}s", total_secs)
+        };
+        format!(
+            "Updated {} file{}, {} in {}",
+            self.patch_count,
+            if self.patch_count == 1 { "" } else { "s" },
+            format_size(self.downloaded_bytes),
+            duration
+        )
+    }
+}
+
+fn format_size(byte_count: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    const GIB: u64 = 1024 * MIB;
+    if byte_count >= GIB {
+        format!("{:.1} GiB", byte_count as f64 / GIB as f64)
+    } else if byte_count >= MIB {
+        format!("{:.1} MiB", byte_count as f64 / MIB as f64)
+    } else if byte_count >= KIB {
+        format!("{:.1} KiB", byte_count as f64 / KIB as f64)
+    } else {
+        format!("{} B", byte_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let stats = SessionStats::new();
+        stats.start_stage(SessionStage::Download);
+        stats.add_downloaded_bytes(1024);
+        stats.add_downloaded_bytes(2048);
+        stats.record_download_speed(4096);
+        stats.record_download_speed(512);
+        stats.start_stage(SessionStage::Installation);
+        stats.add_applied_patch();
+        stats.add_retry();
+        let summary = stats.summary();
+        assert_eq!(summary.patch_count, 1);
+        assert_eq!(summary.downloaded_bytes, 3072);
+        assert!(summary.peak_bytes_per_sec >= 4096);
+        assert_eq!(summary.retries, 1);
+        assert_eq!(summary.lookup_secs, 0.0);
+        assert!(summary.total_secs >= summary.download_secs + summary.installation_secs);
+    }
+
+    #[test]
+    fn test_time_since_progress() {
+        let stats = SessionStats::new();
+        assert!(stats.time_since_progress().is_none());
+        stats.start_stage(SessionStage::Download);
+        std::thread::sleep(Duration::from_millis(20));
+        let (stage, elapsed) = stats.time_since_progress().unwrap();
+        assert_eq!(stage, SessionStage::Download);
+        assert!(elapsed >= Duration::from_millis(20));
+        stats.add_downloaded_bytes(1);
+        assert!(stats.time_since_progress().unwrap().1 < elapsed);
+    }
+
+    #[test]
+    fn test_overall_progress() {
+        let stats = SessionStats::new();
+        assert!(stats.overall_progress().is_none());
+        stats.start_stage(SessionStage::Download);
+        stats.expect_downloads(4);
+        assert!(stats.overall_progress().is_none());
+        // The size of the update is extrapolated from the first download
+        stats.start_download(1000);
+        stats.add_downloaded_bytes(1000);
+        let (percent, eta_secs) = stats.overall_progress().unwrap();
+        assert!(percent > 0.0 && percent < 25.0);
+        assert!(eta_secs.is_none());
+        stats.skip_download();
+        stats.start_download(1000);
+        stats.start_download(1000);
+        stats.add_downloaded_bytes(2000);
+        let (download_percent, _) = stats.overall_progress().unwrap();
+        assert!(download_percent > percent && download_percent < 100.0);
+
+        stats.start_stage(SessionStage::Installation);
+        stats.expect_installation(3000);
+        stats.start_patch_installation(1000);
+        stats.record_entry_progress(1, 2);
+        assert!(stats.overall_progress().unwrap().0 >= download_percent);
+        stats.finish_patch_installation();
+        for _ in 0..2 {
+            stats.start_patch_installation(1000);
+            stats.finish_patch_installation();
+        }
+        assert_eq!(stats.overall_progress().unwrap().0, 100.0);
+    }
+
+    #[test]
+    fn test_describe() {
+        let summary = PatchingSummary {
+            patch_count: 12,
+            downloaded_bytes: 840 * 1024 * 1024,
+            average_bytes_per_sec: 0,
+            peak_bytes_per_sec: 0,
+            retries: 0,
+            lookup_secs: 0.0,
+            download_secs: 0.0,
+            installation_secs: 0.0,
+            total_secs: 192.0,
+        };
+        assert_eq!(summary.describe(), "Updated 12 files, 840.0 MiB in 3m12s");
+        assert_eq!(summary.to_json()["status"], "summary");
+        assert_eq!(summary.to_json()["patch_count"], 12);
+    }
+}
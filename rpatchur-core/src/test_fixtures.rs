@@ -0,0 +1,39 @@
+//! Fixtures shared by the tests of the different modules.
+use std::fs::File;
+use std::path::Path;
+
+use gruf::thor::ThorArchiveBuilder;
+
+/// Entries of a patch, removed when their content is `None`.
+pub type PatchEntries<'a> = [(&'a str, Option<&'a [u8]>)];
+
+/// Builds a THOR archive meant to be merged into a GRF.
+pub fn build_grf_patch(thor_archive_path: &Path, entries: &PatchEntries) {
+    build_thor_archive(thor_archive_path, true, false, entries)
+}
+
+/// Builds a THOR archive meant to be extracted to disk.
+pub fn build_disk_patch(thor_archive_path: &Path, entries: &PatchEntries) {
+    build_thor_archive(thor_archive_path, false, false, entries)
+}
+
+/// Builds a THOR archive containing the given entries.
+pub fn build_thor_archive(
+    thor_archive_path: &Path,
+    use_grf_merging: bool,
+    include_checksums: bool,
+    entries: &PatchEntries,
+) {
+    let thor_file = File::create(thor_archive_path).unwrap();
+    let mut builder =
+        ThorArchiveBuilder::new(thor_file, use_grf_merging, None, include_checksums).unwrap();
+    for (entry_path, content) in entries {
+        match content {
+            Some(content) => builder
+                .append_file_update(entry_path.to_string(), *content)
+                .unwrap(),
+            None => builder.append_file_removal(entry_path.to_string()),
+        }
+    }
+    builder.finish().unwrap();
+}
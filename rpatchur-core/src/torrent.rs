@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::process::Command;
+
+use super::config::TorrentConfiguration;
+
+/// Time after which a stalled download is given up on, in favor of HTTP.
+const DEFAULT_STALL_TIMEOUT_SECS: u64 = 60;
+
+/// Downloads a patch from the BitTorrent swarm of `magnet_link`, with aria2
+/// (https://aria2.github.io).
+///
+/// Returns the path of the downloaded file, which is located somewhere in
+/// `download_directory`. The download fails if it stalls, in which case the
+/// patch is expected to be downloaded from the patch server instead (which
+/// acts as a web seed).
+pub async fn download_with_torrent(
+    torrent_config: &TorrentConfiguration,
+    magnet_link: &str,
+    file_name: &str,
+    download_directory: &Path,
+) -> Result<PathBuf> {
+    // Each torrent gets its own directory, so that partial downloads don't
+    // get mixed up
+    let torrent_directory = download_directory.join(format!("{}.torrent", file_name));
+    tokio::fs::create_dir_all(&torrent_directory).await?;
+    let mut command = Command::new(&torrent_config.aria2c_path);
+    #[cfg(windows)]
+    {
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    let output = command
+        .args(aria2c_arguments(
+            torrent_config,
+            magnet_link,
+            &torrent_directory,
+        ))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        // Canceling the update stops the download
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run '{}'", torrent_config.aria2c_path))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "aria2c failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let file_path = torrent_directory.join(file_name);
+    if !file_path.exists() {
+        return Err(anyhow!("Torrent doesn't contain '{}'", file_name));
+    }
+    Ok(file_path)
+}
+
+fn aria2c_arguments(
+    torrent_config: &TorrentConfiguration,
+    magnet_link: &str,
+    torrent_directory: &Path,
+) -> Vec<String> {
+    vec![
+        format!("--dir={}", torrent_directory.display()),
+        // The update waits for the download to end
+        "--seed-time=0".to_string(),
+        format!(
+            "--bt-stop-timeout={}",
+            torrent_config
+                .stall_timeout_secs
+                .unwrap_or(DEFAULT_STALL_TIMEOUT_SECS)
+        ),
+        "--bt-save-metadata=false".to_string(),
+        "--summary-interval=0".to_string(),
+        "--console-log-level=error".to_string(),
+        magnet_link.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aria2c_arguments() {
+        let torrent_config: TorrentConfiguration =
+            serde_yaml::from_str("{aria2c_path: aria2c}").unwrap();
+        let arguments = aria2c_arguments(
+            &torrent_config,
+            "magnet:?xt=urn:btih:d2474e86c95b19b8bcfdb92bc12c9d44667cfa36",
+            Path::new("tmp"),
+        );
+        assert_eq!(
+            arguments,
+            vec![
+                format!("--dir={}", Path::new("tmp").display()),
+                "--seed-time=0".to_string(),
+                "--bt-stop-timeout=60".to_string(),
+                "--bt-save-metadata=false".to_string(),
+                "--summary-interval=0".to_string(),
+                "--console-log-level=error".to_string(),
+                "magnet:?xt=urn:btih:d2474e86c95b19b8bcfdb92bc12c9d44667cfa36".to_string(),
+            ]
+        );
+    }
+}
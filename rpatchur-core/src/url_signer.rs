@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use url::Url;
+
+/// Signed URLs are renewed a bit before they expire, so that they're still
+/// valid when the server receives the request.
+const EXPIRATION_MARGIN: Duration = Duration::from_secs(30);
+
+/// Gets signed URLs for patch files from `web.url_signer_endpoint`, for CDNs
+/// that require signatures which expire.
+///
+/// The endpoint is queried with the URL to sign (`GET <endpoint>?url=<url>`)
+/// and responds with a JSON object such as
+/// `{"url": "https://...", "expires_in": 3600}`. Signed URLs are cached until
+/// they expire, URLs without `expires_in` aren't cached.
+pub struct UrlSigner {
+    client: reqwest::Client,
+    endpoint: Url,
+    cache: Mutex<HashMap<Url, (Url, Instant)>>, // URL -> Signed URL, Expiration
+}
+
+#[derive(Deserialize)]
+struct SignedUrlResponse {
+    url: String,
+    expires_in: Option<u64>, // Seconds
+}
+
+impl UrlSigner {
+    pub fn new(client: reqwest::Client, endpoint: &str) -> Result<UrlSigner> {
+        Ok(UrlSigner {
+            client,
+            endpoint: Url::parse(endpoint)
+                .with_context(|| "Failed to parse 'url_signer_endpoint'")?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn sign(&self, url: &Url) -> Result<Url> {
+        if let Some(signed_url) = self.cached_signed_url(url) {
+            return Ok(signed_url);
+        }
+        let resp = self
+            .client
+            .get(self.endpoint.clone())
+            .query(&[("url", url.as_str())])
+            .send()
+            .await
+            .with_context(|| "Failed to reach the URL signer")?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "URL signer failed to sign '{}': {}",
+                url,
+                resp.status()
+            ));
+        }
+        let resp_content = resp.text().await.with_context(|| "Invalid response body")?;
+        let (signed_url, expires_in) = parse_signer_response(&resp_content)?;
+        if let Some(expires_in) = expires_in {
+            let expiration = Instant::now() + expires_in.saturating_sub(EXPIRATION_MARGIN);
+            if let Ok(mut cache) = self.cache.lock() {
+                cache.insert(url.clone(), (signed_url.clone(), expiration));
+            }
+        }
+        Ok(signed_url)
+    }
+
+    fn cached_signed_url(&self, url: &Url) -> Option<Url> {
+        let mut cache = self.cache.lock().ok()?;
+        match cache.get(url) {
+            Some((signed_url, expiration)) if Instant::now() < *expiration => {
+                Some(signed_url.clone())
+            }
+            Some(_) => {
+                cache.remove(url);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+fn parse_signer_response(resp_content: &str) -> Result<(Url, Option<Duration>)> {
+    let resp: SignedUrlResponse =
+        serde_json::from_str(resp_content).with_context(|| "Invalid URL signer response")?;
+    let signed_url = Url::parse(&resp.url)
+        .with_context(|| format!("URL signer returned an invalid URL '{}'", resp.url))?;
+    Ok((signed_url, resp.expires_in.map(Duration::from_secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    #[test]
+    fn test_parse_signer_response() {
+        let (signed_url, expires_in) = parse_signer_response(
+            r#"{"url": "https://cdn.example.com/1.thor?sig=abc", "expires_in": 60}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            signed_url.as_str(),
+            "https://cdn.example.com/1.thor?sig=abc"
+        );
+        assert_eq!(expires_in, Some(Duration::from_secs(60)));
+        assert!(parse_signer_response(r#"{"url": "not a url"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sign_is_cached() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/sign"),
+                request::query(url_decoded(contains((
+                    "url",
+                    "https://cdn.example.com/1.thor"
+                )))),
+            ])
+            // A second request would fail the test
+            .times(1)
+            .respond_with(json_encoded(serde_json::json!({
+                "url": "https://cdn.example.com/1.thor?sig=abc",
+                "expires_in": 3600,
+            }))),
+        );
+        let url_signer = UrlSigner::new(
+            reqwest::Client::new(),
+            server.url("/sign").to_string().as_str(),
+        )
+        .unwrap();
+        let url = Url::parse("https://cdn.example.com/1.thor").unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                url_signer.sign(&url).await.unwrap().as_str(),
+                "https://cdn.example.com/1.thor?sig=abc"
+            );
+        }
+    }
+}
@@ -1,9 +1,54 @@
-#[cfg(windows)]
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
-    let mut res = winres::WindowsResource::new();
-    res.set_icon("resources/rpatchur.ico");
-    res.compile().unwrap();
+    // Build information, exposed through `get_version_info`
+    println!("cargo:rustc-env=RPATCHUR_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=RPATCHUR_BUILD_DATE={}", build_date());
+    #[cfg(windows)]
+    {
+        let mut res = winres::WindowsResource::new();
+        res.set_icon("resources/rpatchur.ico");
+        res.compile().unwrap();
+    }
+}
+
+fn git_hash() -> String {
+    Command::new("git")
+        .arg("rev-parse")
+        .arg("--short")
+        .arg("HEAD")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
 }
 
-#[cfg(unix)]
-fn main() {}
+/// Returns the (UTC) date of the build, as "YYYY-MM-DD". `SOURCE_DATE_EPOCH`
+/// is honored for reproducible builds.
+fn build_date() -> String {
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    // Converts days since the epoch to a civil date
+    // (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let days = timestamp / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
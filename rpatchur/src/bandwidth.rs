@@ -0,0 +1,149 @@
+use anyhow::Result;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{load_settings, update_settings};
+
+/// Players are warned once this fraction of their monthly cap has been used.
+const CAP_WARNING_RATIO: f64 = 0.9;
+
+/// Bytes downloaded by the patcher, persisted in the settings store.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthUsage {
+    pub month: String, // "YYYY-MM", local time
+    pub month_bytes: u64,
+    pub total_bytes: u64,
+    #[serde(default)]
+    pub warned_month: Option<String>, // Month the player has been warned about their cap in
+}
+
+/// Bandwidth usage, as given to the UI's `bandwidthStats` function.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BandwidthStats {
+    pub session_bytes: u64, // Since the patcher has been started
+    pub month: String,
+    pub month_bytes: u64,
+    pub total_bytes: u64,
+    pub monthly_cap_bytes: Option<u64>, // Set by the player
+}
+
+impl BandwidthUsage {
+    fn add(&mut self, month: &str, byte_count: u64) {
+        if self.month != month {
+            self.month = month.to_string();
+            self.month_bytes = 0;
+        }
+        self.month_bytes += byte_count;
+        self.total_bytes += byte_count;
+    }
+
+    /// Returns the bytes downloaded during `month`.
+    fn bytes_in_month(&self, month: &str) -> u64 {
+        if self.month == month {
+            self.month_bytes
+        } else {
+            0
+        }
+    }
+
+    /// Indicates whether the player should be warned that their monthly cap
+    /// (in bytes) is about to be reached, which they are once during `month`.
+    fn take_cap_warning(&mut self, month: &str, monthly_cap_bytes: Option<u64>) -> bool {
+        let already_warned = self.warned_month.as_deref() == Some(month);
+        if already_warned || !is_cap_approached(self.bytes_in_month(month), monthly_cap_bytes) {
+            return false;
+        }
+        self.warned_month = Some(month.to_string());
+        true
+    }
+}
+
+/// Adds `byte_count` to the bandwidth usage recorded in the settings store.
+///
+/// Returns true if the player should be warned that their monthly cap is about
+/// to be reached. This is only the case once a month.
+pub fn record_downloaded_bytes(byte_count: u64) -> Result<bool> {
+    let month = current_month();
+    update_settings(|settings| {
+        settings.bandwidth.add(&month, byte_count);
+        let monthly_cap_bytes = monthly_cap_bytes(settings.monthly_bandwidth_cap_mb);
+        settings
+            .bandwidth
+            .take_cap_warning(&month, monthly_cap_bytes)
+    })
+}
+
+/// Returns the bandwidth usage recorded in the settings store.
+pub fn get_bandwidth_stats(session_bytes: u64) -> BandwidthStats {
+    let settings = load_settings();
+    let month = current_month();
+    BandwidthStats {
+        session_bytes,
+        month_bytes: settings.bandwidth.bytes_in_month(&month),
+        month,
+        total_bytes: settings.bandwidth.total_bytes,
+        monthly_cap_bytes: monthly_cap_bytes(settings.monthly_bandwidth_cap_mb),
+    }
+}
+
+/// Sets the monthly cap the player is warned about, `None` disables the
+/// warning.
+pub fn set_monthly_bandwidth_cap(cap_mb: Option<u64>) -> Result<()> {
+    update_settings(|settings| settings.monthly_bandwidth_cap_mb = cap_mb)
+}
+
+fn monthly_cap_bytes(cap_mb: Option<u64>) -> Option<u64> {
+    cap_mb.map(|cap_mb| cap_mb.saturating_mul(1024 * 1024))
+}
+
+/// Indicates whether `month_bytes` is close enough to the monthly cap for the
+/// player to be warned.
+fn is_cap_approached(month_bytes: u64, monthly_cap_bytes: Option<u64>) -> bool {
+    match monthly_cap_bytes {
+        Some(cap_bytes) => month_bytes as f64 >= cap_bytes as f64 * CAP_WARNING_RATIO,
+        None => false,
+    }
+}
+
+fn current_month() -> String {
+    Local::now().format("%Y-%m").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_usage() {
+        let mut usage = BandwidthUsage::default();
+        usage.add("2021-05", 100);
+        usage.add("2021-05", 50);
+        assert_eq!(usage.bytes_in_month("2021-05"), 150);
+        // Monthly usage starts over every month
+        usage.add("2021-06", 10);
+        assert_eq!(usage.bytes_in_month("2021-06"), 10);
+        assert_eq!(usage.bytes_in_month("2021-07"), 0);
+        assert_eq!(usage.total_bytes, 160);
+    }
+
+    #[test]
+    fn test_is_cap_approached() {
+        assert!(!is_cap_approached(899, None));
+        assert!(!is_cap_approached(899, Some(1000)));
+        assert!(is_cap_approached(900, Some(1000)));
+    }
+
+    #[test]
+    fn test_cap_warning_once_a_month() {
+        let mut usage = BandwidthUsage::default();
+        usage.add("2021-05", 899);
+        assert!(!usage.take_cap_warning("2021-05", Some(1000)));
+        usage.add("2021-05", 1);
+        assert!(usage.take_cap_warning("2021-05", Some(1000)));
+        usage.add("2021-05", 100);
+        assert!(!usage.take_cap_warning("2021-05", Some(1000)));
+        // Players are warned again the next month
+        usage.add("2021-06", 950);
+        assert!(usage.take_cap_warning("2021-06", Some(1000)));
+    }
+}
@@ -0,0 +1,701 @@
+use anyhow::Result;
+use web_view::WebView;
+
+use crate::navigation::{decide_navigation, open_in_browser, NavigationDecision};
+
+/// Called with the URL of each page loaded in the main frame.
+pub type PageLoadListener = Box<dyn Fn(&str)>;
+
+/// Keeps the web view within `allowed_url_prefixes`, frames included: other
+/// navigations are cancelled before they start, whether they come from the
+/// user, scripts or redirections. See `decide_navigation`.
+///
+/// `on_page_loaded` is called as soon as a page replaces the previous one,
+/// with the URL the web view actually loaded.
+///
+/// This is the Windows version. MSHTML doesn't tell whether navigations come
+/// from the user, so the ones that closely follow the user's input are taken
+/// as such.
+#[cfg(windows)]
+pub fn guard_navigation<T>(
+    webview: &WebView<'_, T>,
+    allowed_url_prefixes: Vec<String>,
+    on_page_loaded: PageLoadListener,
+) -> Result<()> {
+    mshtml::advise_browser_events(
+        webview.window_handle() as _,
+        allowed_url_prefixes,
+        on_page_loaded,
+    )
+}
+
+/// Keeps the web view within `allowed_url_prefixes`, frames included: other
+/// navigations are cancelled before they start, whether they come from the
+/// user, scripts or redirections. See `decide_navigation`.
+///
+/// `on_page_loaded` is called as soon as a page replaces the previous one,
+/// with the URL the web view actually loaded.
+///
+/// This is the WebKitGTK version.
+#[cfg(not(windows))]
+pub fn guard_navigation<T>(
+    webview: &WebView<'_, T>,
+    allowed_url_prefixes: Vec<String>,
+    on_page_loaded: PageLoadListener,
+) -> Result<()> {
+    use std::cell::Cell;
+
+    let web_view = gtk::find_web_view(webview)?;
+    let guard = Box::into_raw(Box::new(gtk::NavigationGuard {
+        allowed_url_prefixes,
+        initial_navigation: Cell::new(true),
+        on_page_loaded,
+    }));
+    unsafe {
+        // Both handlers are disconnected when the web view is destroyed
+        gtk::connect(
+            web_view,
+            "load-changed",
+            std::mem::transmute::<gtk::LoadChangedHandler, unsafe extern "C" fn()>(
+                gtk::on_load_changed,
+            ),
+            guard as _,
+            None,
+        );
+        gtk::connect(
+            web_view,
+            "decide-policy",
+            std::mem::transmute::<gtk::DecidePolicyHandler, unsafe extern "C" fn()>(
+                gtk::on_decide_policy,
+            ),
+            guard as _,
+            Some(gtk::free_navigation_guard),
+        );
+    }
+    Ok(())
+}
+
+/// Handles a navigation as `decide_navigation` says, and indicates whether
+/// it can go on.
+fn enforce_navigation(
+    url: &str,
+    new_window: bool,
+    user_initiated: bool,
+    allowed_url_prefixes: &[String],
+) -> bool {
+    match decide_navigation(url, new_window, user_initiated, allowed_url_prefixes) {
+        NavigationDecision::Allow => return true,
+        NavigationDecision::OpenInBrowser => open_in_browser(url),
+        NavigationDecision::Block => log::warn!("Blocked navigation to '{}'", url),
+    }
+    false
+}
+
+/// Shows or hides the web view's native context menu, whether the developer
+/// tools are enabled or not.
+///
+/// This is the Windows version. MSHTML never shows the menu.
+#[cfg(windows)]
+pub fn set_context_menu_enabled<T>(_webview: &WebView<'_, T>, enabled: bool) -> Result<()> {
+    if enabled {
+        return Err(anyhow::anyhow!(
+            "The context menu isn't supported on this platform"
+        ));
+    }
+    Ok(())
+}
+
+/// Shows or hides the web view's native context menu, whether the developer
+/// tools are enabled or not.
+///
+/// This is the WebKitGTK version.
+#[cfg(not(windows))]
+pub fn set_context_menu_enabled<T>(webview: &WebView<'_, T>, enabled: bool) -> Result<()> {
+    use gobject_sys::{g_signal_handlers_disconnect_matched, g_signal_lookup, G_SIGNAL_MATCH_ID};
+    use std::ffi::CString;
+    use webkit2gtk_sys::webkit_web_view_get_type;
+
+    let web_view = gtk::find_web_view(webview)?;
+    let signal = CString::new("context-menu")?;
+    unsafe {
+        // web-view hides the menu unless the developer tools are enabled
+        let signal_id = g_signal_lookup(signal.as_ptr(), webkit_web_view_get_type());
+        g_signal_handlers_disconnect_matched(
+            web_view,
+            G_SIGNAL_MATCH_ID,
+            signal_id,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        if !enabled {
+            gtk::connect(
+                web_view,
+                "context-menu",
+                std::mem::transmute::<gtk::ContextMenuHandler, unsafe extern "C" fn()>(
+                    gtk::on_context_menu,
+                ),
+                std::ptr::null_mut(),
+                None,
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+mod gtk {
+    use std::cell::Cell;
+    use std::ffi::{CStr, CString};
+
+    use anyhow::{anyhow, Result};
+    use glib_sys::{gboolean, gpointer, GFALSE, GTRUE};
+    use gobject_sys::{
+        g_signal_connect_data, g_type_check_instance_is_a, GCallback, GClosure, GClosureNotify,
+        GObject,
+    };
+    use gtk_sys::{gtk_bin_get_child, GtkBin};
+    use web_view::WebView;
+    use webkit2gtk_sys::{
+        webkit_navigation_action_get_navigation_type, webkit_navigation_action_get_request,
+        webkit_navigation_action_is_user_gesture,
+        webkit_navigation_policy_decision_get_navigation_action, webkit_policy_decision_ignore,
+        webkit_uri_request_get_uri, webkit_web_view_get_type, webkit_web_view_get_uri,
+        WebKitContextMenu, WebKitHitTestResult, WebKitLoadEvent, WebKitNavigationPolicyDecision,
+        WebKitPolicyDecision, WebKitPolicyDecisionType, WebKitWebView, WEBKIT_LOAD_COMMITTED,
+        WEBKIT_NAVIGATION_TYPE_LINK_CLICKED, WEBKIT_POLICY_DECISION_TYPE_NAVIGATION_ACTION,
+        WEBKIT_POLICY_DECISION_TYPE_NEW_WINDOW_ACTION,
+    };
+
+    use super::{enforce_navigation, PageLoadListener};
+
+    /// What the `decide-policy` handler is given, freed along with the web
+    /// view.
+    pub struct NavigationGuard {
+        pub allowed_url_prefixes: Vec<String>,
+        pub initial_navigation: Cell<bool>, // Until web-view's own page is loaded
+        pub on_page_loaded: PageLoadListener,
+    }
+
+    pub type LoadChangedHandler =
+        unsafe extern "C" fn(*mut WebKitWebView, WebKitLoadEvent, gpointer);
+
+    pub type DecidePolicyHandler = unsafe extern "C" fn(
+        *mut WebKitWebView,
+        *mut WebKitPolicyDecision,
+        WebKitPolicyDecisionType,
+        gpointer,
+    ) -> gboolean;
+
+    pub type ContextMenuHandler = unsafe extern "C" fn(
+        *mut WebKitWebView,
+        *mut WebKitContextMenu,
+        gpointer,
+        *mut WebKitHitTestResult,
+        gpointer,
+    ) -> gboolean;
+
+    /// Returns the `WebKitWebView` of `webview`'s window, which web-view puts
+    /// in a scrolled window.
+    pub fn find_web_view<T>(webview: &WebView<'_, T>) -> Result<*mut GObject> {
+        unsafe {
+            let window = webview.window_handle() as *mut GtkBin;
+            let scroller = gtk_bin_get_child(window) as *mut GtkBin;
+            if scroller.is_null() {
+                return Err(anyhow!("Failed to find the web view"));
+            }
+            let web_view = gtk_bin_get_child(scroller);
+            if web_view.is_null()
+                || g_type_check_instance_is_a(web_view as *mut _, webkit_web_view_get_type()) == 0
+            {
+                return Err(anyhow!("Failed to find the web view"));
+            }
+            Ok(web_view as *mut GObject)
+        }
+    }
+
+    pub unsafe fn connect(
+        instance: *mut GObject,
+        signal: &str,
+        handler: unsafe extern "C" fn(),
+        data: gpointer,
+        destroy_data: GClosureNotify,
+    ) {
+        let signal = CString::new(signal).unwrap();
+        let handler: GCallback = Some(handler);
+        g_signal_connect_data(instance, signal.as_ptr(), handler, data, destroy_data, 0);
+    }
+
+    pub unsafe extern "C" fn on_decide_policy(
+        _web_view: *mut WebKitWebView,
+        decision: *mut WebKitPolicyDecision,
+        decision_type: WebKitPolicyDecisionType,
+        data: gpointer,
+    ) -> gboolean {
+        let new_window = match decision_type {
+            WEBKIT_POLICY_DECISION_TYPE_NAVIGATION_ACTION => false,
+            WEBKIT_POLICY_DECISION_TYPE_NEW_WINDOW_ACTION => true,
+            // Responses are only received for allowed navigations
+            _ => return GFALSE,
+        };
+        let guard = &*(data as *const NavigationGuard);
+        // The offline page is loaded from a `data:` URL
+        if guard.initial_navigation.replace(false) && !new_window {
+            return GFALSE;
+        }
+        let action = webkit_navigation_policy_decision_get_navigation_action(
+            decision as *mut WebKitNavigationPolicyDecision,
+        );
+        let uri = webkit_uri_request_get_uri(webkit_navigation_action_get_request(action));
+        let url = if uri.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(uri).to_string_lossy().into_owned()
+        };
+        // Scripts can open new windows when the user clicks, but only links
+        // can navigate to other sites
+        let user_initiated = webkit_navigation_action_is_user_gesture(action) != 0
+            && (new_window
+                || webkit_navigation_action_get_navigation_type(action)
+                    == WEBKIT_NAVIGATION_TYPE_LINK_CLICKED);
+        if enforce_navigation(
+            &url,
+            new_window,
+            user_initiated,
+            &guard.allowed_url_prefixes,
+        ) {
+            return GFALSE;
+        }
+        webkit_policy_decision_ignore(decision);
+        GTRUE
+    }
+
+    pub unsafe extern "C" fn on_load_changed(
+        web_view: *mut WebKitWebView,
+        load_event: WebKitLoadEvent,
+        data: gpointer,
+    ) {
+        // The previous page is gone once the new one is committed
+        if load_event != WEBKIT_LOAD_COMMITTED {
+            return;
+        }
+        let guard = &*(data as *const NavigationGuard);
+        let uri = webkit_web_view_get_uri(web_view);
+        if !uri.is_null() {
+            (guard.on_page_loaded)(&CStr::from_ptr(uri).to_string_lossy());
+        }
+    }
+
+    pub unsafe extern "C" fn free_navigation_guard(data: gpointer, _closure: *mut GClosure) {
+        drop(Box::from_raw(data as *mut NavigationGuard));
+    }
+
+    // Handled, so that the menu isn't shown
+    pub unsafe extern "C" fn on_context_menu(
+        _web_view: *mut WebKitWebView,
+        _context_menu: *mut WebKitContextMenu,
+        _event: gpointer,
+        _hit_test_result: *mut WebKitHitTestResult,
+        _data: gpointer,
+    ) -> gboolean {
+        GTRUE
+    }
+}
+
+#[cfg(windows)]
+mod mshtml {
+    use std::ffi::c_void;
+    use std::os::raw::{c_char, c_int};
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::{anyhow, Result};
+    use winapi::shared::guiddef::{GUID, REFIID};
+    use winapi::shared::minwindef::{DWORD, UINT, WORD};
+    use winapi::shared::ntdef::HRESULT;
+    use winapi::shared::windef::HWND;
+    use winapi::shared::winerror::{E_NOINTERFACE, E_NOTIMPL, S_OK};
+    use winapi::um::oaidl::{IDispatch, DISPPARAMS, VARIANT};
+    use winapi::um::sysinfoapi::GetTickCount;
+    use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+    use winapi::um::winuser::{GetLastInputInfo, GetWindowLongPtrW, GWLP_USERDATA, LASTINPUTINFO};
+    use winapi::Interface;
+
+    use super::{enforce_navigation, PageLoadListener};
+
+    const IID_ICONNECTION_POINT_CONTAINER: GUID = GUID {
+        Data1: 0xB196_B284,
+        Data2: 0xBAB4,
+        Data3: 0x101A,
+        Data4: [0xB6, 0x9C, 0x00, 0xAA, 0x00, 0x34, 0x1D, 0x07],
+    };
+    const DIID_DWEB_BROWSER_EVENTS2: GUID = GUID {
+        Data1: 0x34A7_15A0,
+        Data2: 0x6587,
+        Data3: 0x11D0,
+        Data4: [0x92, 0x4A, 0x00, 0x20, 0xAF, 0xC7, 0xAC, 0x4D],
+    };
+    const DISPID_BEFORENAVIGATE2: i32 = 250;
+    const DISPID_NAVIGATECOMPLETE2: i32 = 252;
+    const DISPID_NEWWINDOW3: i32 = 273;
+    const NWMF_USERINITED: u32 = 0x2;
+    const VT_I4: u16 = 3;
+    const VT_BSTR: u16 = 8;
+    const VT_DISPATCH: u16 = 9;
+    const VT_BOOL: u16 = 11;
+    const VT_VARIANT: u16 = 12;
+    const VT_UI4: u16 = 19;
+    const VT_BYREF: u16 = 0x4000;
+    const VARIANT_TRUE: i16 = -1;
+    // Input received this long before a navigation is taken as its cause
+    const USER_INPUT_DELAY_MS: DWORD = 1000;
+
+    /// Beginning of webview-sys's `struct mshtml_webview`, which web-view
+    /// stores in the window's user data.
+    #[repr(C)]
+    struct MshtmlWebView {
+        url: *const c_char,
+        width: c_int,
+        height: c_int,
+        resizable: c_int,
+        debug: c_int,
+        frameless: c_int,
+        min_width: c_int,
+        min_height: c_int,
+        external_invoke_cb: *mut c_void,
+        userdata: *mut c_void,
+        hwnd: HWND,
+        browser: *mut *mut IUnknown, // The WebBrowser control's `IOleObject`
+    }
+
+    #[repr(C)]
+    struct IConnectionPointContainer {
+        vtbl: *const IConnectionPointContainerVtbl,
+    }
+
+    #[repr(C)]
+    struct IConnectionPointContainerVtbl {
+        parent: IUnknownVtbl,
+        enum_connection_points: usize,
+        find_connection_point: unsafe extern "system" fn(
+            *mut IConnectionPointContainer,
+            REFIID,
+            *mut *mut IConnectionPoint,
+        ) -> HRESULT,
+    }
+
+    #[repr(C)]
+    struct IConnectionPoint {
+        vtbl: *const IConnectionPointVtbl,
+    }
+
+    #[repr(C)]
+    struct IConnectionPointVtbl {
+        parent: IUnknownVtbl,
+        get_connection_interface: usize,
+        get_connection_point_container: usize,
+        advise:
+            unsafe extern "system" fn(*mut IConnectionPoint, *mut IUnknown, *mut DWORD) -> HRESULT,
+        unadvise: usize,
+        enum_connections: usize,
+    }
+
+    /// `DWebBrowserEvents2` implementation, freed once the browser releases
+    /// it.
+    #[repr(C)]
+    struct EventSink {
+        vtbl: *const EventSinkVtbl,
+        ref_count: AtomicU32,
+        allowed_url_prefixes: Vec<String>,
+        on_page_loaded: PageLoadListener,
+        browser: *mut IUnknown, // Only compared with, to tell the main frame apart
+    }
+
+    #[repr(C)]
+    struct EventSinkVtbl {
+        query_interface:
+            unsafe extern "system" fn(*mut EventSink, REFIID, *mut *mut c_void) -> HRESULT,
+        add_ref: unsafe extern "system" fn(*mut EventSink) -> u32,
+        release: unsafe extern "system" fn(*mut EventSink) -> u32,
+        get_type_info_count: unsafe extern "system" fn(*mut EventSink, *mut UINT) -> HRESULT,
+        get_type_info:
+            unsafe extern "system" fn(*mut EventSink, UINT, DWORD, *mut *mut c_void) -> HRESULT,
+        get_ids_of_names: unsafe extern "system" fn(
+            *mut EventSink,
+            REFIID,
+            *mut *mut u16,
+            UINT,
+            DWORD,
+            *mut i32,
+        ) -> HRESULT,
+        invoke: unsafe extern "system" fn(
+            *mut EventSink,
+            i32,
+            REFIID,
+            DWORD,
+            WORD,
+            *mut DISPPARAMS,
+            *mut VARIANT,
+            *mut c_void,
+            *mut UINT,
+        ) -> HRESULT,
+    }
+
+    static EVENT_SINK_VTBL: EventSinkVtbl = EventSinkVtbl {
+        query_interface,
+        add_ref,
+        release,
+        get_type_info_count,
+        get_type_info,
+        get_ids_of_names,
+        invoke,
+    };
+
+    /// Subscribes to the navigation events of the WebBrowser control of the
+    /// web view's window.
+    pub fn advise_browser_events(
+        hwnd: HWND,
+        allowed_url_prefixes: Vec<String>,
+        on_page_loaded: PageLoadListener,
+    ) -> Result<()> {
+        unsafe {
+            let webview = GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *const MshtmlWebView;
+            if webview.is_null() || (*webview).browser.is_null() || (*(*webview).browser).is_null()
+            {
+                return Err(anyhow!("Failed to find the web browser control"));
+            }
+            let browser = identity(*(*webview).browser);
+            if browser.is_null() {
+                return Err(anyhow!("Failed to find the web browser control"));
+            }
+            let mut container: *mut IConnectionPointContainer = ptr::null_mut();
+            if (*browser).QueryInterface(
+                &IID_ICONNECTION_POINT_CONTAINER,
+                &mut container as *mut _ as *mut *mut c_void,
+            ) != S_OK
+            {
+                return Err(anyhow!("The web browser control doesn't have events"));
+            }
+            let mut point: *mut IConnectionPoint = ptr::null_mut();
+            let res = ((*(*container).vtbl).find_connection_point)(
+                container,
+                &DIID_DWEB_BROWSER_EVENTS2,
+                &mut point,
+            );
+            ((*(*container).vtbl).parent.Release)(container as *mut IUnknown);
+            if res != S_OK {
+                return Err(anyhow!(
+                    "The web browser control doesn't have navigation events"
+                ));
+            }
+            let sink = Box::into_raw(Box::new(EventSink {
+                vtbl: &EVENT_SINK_VTBL,
+                ref_count: AtomicU32::new(1),
+                allowed_url_prefixes,
+                on_page_loaded,
+                browser,
+            }));
+            let mut cookie = 0;
+            let res = ((*(*point).vtbl).advise)(point, sink as *mut IUnknown, &mut cookie);
+            ((*(*point).vtbl).parent.Release)(point as *mut IUnknown);
+            // The browser holds its own reference if the sink was accepted
+            release(sink);
+            if res != S_OK {
+                return Err(anyhow!("Failed to subscribe to navigation events"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the `IUnknown` interface of `object`, which identifies it.
+    /// The reference is released at once.
+    unsafe fn identity(object: *mut IUnknown) -> *mut IUnknown {
+        let mut unknown: *mut IUnknown = ptr::null_mut();
+        if (*object).QueryInterface(
+            &IUnknown::uuidof(),
+            &mut unknown as *mut _ as *mut *mut c_void,
+        ) != S_OK
+        {
+            return ptr::null_mut();
+        }
+        (*unknown).Release();
+        unknown
+    }
+
+    fn is_same_guid(a: &GUID, b: &GUID) -> bool {
+        a.Data1 == b.Data1 && a.Data2 == b.Data2 && a.Data3 == b.Data3 && a.Data4 == b.Data4
+    }
+
+    unsafe extern "system" fn query_interface(
+        this: *mut EventSink,
+        riid: REFIID,
+        object: *mut *mut c_void,
+    ) -> HRESULT {
+        let riid = &*riid;
+        if is_same_guid(riid, &IUnknown::uuidof())
+            || is_same_guid(riid, &IDispatch::uuidof())
+            || is_same_guid(riid, &DIID_DWEB_BROWSER_EVENTS2)
+        {
+            add_ref(this);
+            *object = this as *mut c_void;
+            return S_OK;
+        }
+        *object = ptr::null_mut();
+        E_NOINTERFACE
+    }
+
+    unsafe extern "system" fn add_ref(this: *mut EventSink) -> u32 {
+        (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    unsafe extern "system" fn release(this: *mut EventSink) -> u32 {
+        let ref_count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+        if ref_count == 0 {
+            drop(Box::from_raw(this));
+        }
+        ref_count
+    }
+
+    unsafe extern "system" fn get_type_info_count(
+        _this: *mut EventSink,
+        count: *mut UINT,
+    ) -> HRESULT {
+        *count = 0;
+        S_OK
+    }
+
+    unsafe extern "system" fn get_type_info(
+        _this: *mut EventSink,
+        _index: UINT,
+        _lcid: DWORD,
+        _type_info: *mut *mut c_void,
+    ) -> HRESULT {
+        E_NOTIMPL
+    }
+
+    unsafe extern "system" fn get_ids_of_names(
+        _this: *mut EventSink,
+        _riid: REFIID,
+        _names: *mut *mut u16,
+        _count: UINT,
+        _lcid: DWORD,
+        _disp_ids: *mut i32,
+    ) -> HRESULT {
+        E_NOTIMPL
+    }
+
+    unsafe extern "system" fn invoke(
+        this: *mut EventSink,
+        disp_id: i32,
+        _riid: REFIID,
+        _lcid: DWORD,
+        _flags: WORD,
+        params: *mut DISPPARAMS,
+        _result: *mut VARIANT,
+        _exception_info: *mut c_void,
+        _arg_error: *mut UINT,
+    ) -> HRESULT {
+        if params.is_null() {
+            return S_OK;
+        }
+        let sink = &*this;
+        let params = &*params;
+        match disp_id {
+            // (pDisp, URL, Flags, TargetFrameName, PostData, Headers, Cancel)
+            DISPID_BEFORENAVIGATE2 => {
+                if let (Some(url), Some(cancel)) = (argument(params, 7, 1), argument(params, 7, 6))
+                {
+                    let url = string_value(url).unwrap_or_default();
+                    let user_initiated = has_recent_user_input();
+                    if !enforce_navigation(&url, false, user_initiated, &sink.allowed_url_prefixes)
+                    {
+                        set_true(cancel);
+                    }
+                }
+            }
+            // (pDisp, URL), for every frame
+            DISPID_NAVIGATECOMPLETE2 => {
+                if let (Some(frame), Some(url)) = (argument(params, 2, 0), argument(params, 2, 1)) {
+                    if is_main_frame(sink, frame) {
+                        (sink.on_page_loaded)(&string_value(url).unwrap_or_default());
+                    }
+                }
+            }
+            // (ppDisp, Cancel, dwFlags, bstrUrlContext, bstrUrl)
+            DISPID_NEWWINDOW3 => {
+                if let (Some(cancel), Some(flags), Some(url)) = (
+                    argument(params, 5, 1),
+                    argument(params, 5, 2),
+                    argument(params, 5, 4),
+                ) {
+                    let url = string_value(url).unwrap_or_default();
+                    let user_initiated = flags_value(flags) & NWMF_USERINITED != 0;
+                    enforce_navigation(&url, true, user_initiated, &sink.allowed_url_prefixes);
+                    set_true(cancel);
+                }
+            }
+            _ => {}
+        }
+        S_OK
+    }
+
+    /// Returns the argument at `index` of an event that takes `count`
+    /// arguments, which are passed in reverse order.
+    unsafe fn argument(params: &DISPPARAMS, count: usize, index: usize) -> Option<&VARIANT> {
+        if params.cArgs as usize != count || params.rgvarg.is_null() {
+            return None;
+        }
+        Some(&*params.rgvarg.add(count - 1 - index))
+    }
+
+    unsafe fn string_value(variant: &VARIANT) -> Option<String> {
+        let mut variant = variant;
+        if variant.n1.n2().vt == VT_BYREF | VT_VARIANT {
+            variant = &**variant.n1.n2().n3.pvarVal();
+        }
+        if variant.n1.n2().vt != VT_BSTR {
+            return None;
+        }
+        let bstr = *variant.n1.n2().n3.bstrVal();
+        if bstr.is_null() {
+            return Some(String::new());
+        }
+        let len = (0..).take_while(|&i| *bstr.add(i) != 0).count();
+        Some(String::from_utf16_lossy(std::slice::from_raw_parts(
+            bstr, len,
+        )))
+    }
+
+    unsafe fn is_main_frame(sink: &EventSink, variant: &VARIANT) -> bool {
+        if variant.n1.n2().vt != VT_DISPATCH {
+            return false;
+        }
+        let frame = *variant.n1.n2().n3.pdispVal();
+        !frame.is_null() && identity(frame as *mut IUnknown) == sink.browser
+    }
+
+    unsafe fn flags_value(variant: &VARIANT) -> u32 {
+        match variant.n1.n2().vt {
+            VT_I4 | VT_UI4 => *variant.n1.n2().n3.lVal() as u32,
+            _ => 0,
+        }
+    }
+
+    unsafe fn set_true(variant: &VARIANT) {
+        if variant.n1.n2().vt == VT_BYREF | VT_BOOL {
+            **variant.n1.n2().n3.pboolVal() = VARIANT_TRUE;
+        }
+    }
+
+    fn has_recent_user_input() -> bool {
+        let mut last_input_info = LASTINPUTINFO {
+            cbSize: std::mem::size_of::<LASTINPUTINFO>() as UINT,
+            dwTime: 0,
+        };
+        unsafe {
+            GetLastInputInfo(&mut last_input_info) != 0
+                && GetTickCount().wrapping_sub(last_input_info.dwTime) <= USER_INPUT_DELAY_MS
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use rpatchur_core::{ClientTargetConfiguration, PlayConfiguration};
+use serde::Serialize;
+
+use crate::settings::load_settings;
+
+/// Game client, as listed to the UI by `list_clients`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ClientInfo {
+    pub name: String,
+    pub label: String,
+    pub active: bool,
+}
+
+/// Executable started by `play` and `login`, along with its arguments.
+#[derive(Debug, PartialEq)]
+pub struct PlayTarget<'a> {
+    pub path: &'a str,
+    pub arguments: &'a [String],
+}
+
+/// Returns the game client currently selected through `set_active_client`.
+pub fn active_play_target(play_config: &PlayConfiguration) -> PlayTarget<'_> {
+    select_play_target(play_config, load_settings().active_client.as_deref())
+}
+
+/// Returns the `play.clients` entry named `active_client` (the first entry if
+/// there's no such entry), or the `play` section's executable if there are no
+/// entries.
+pub fn select_play_target<'a>(
+    play_config: &'a PlayConfiguration,
+    active_client: Option<&str>,
+) -> PlayTarget<'a> {
+    match find_active_client(play_config, active_client) {
+        Some(client) => PlayTarget {
+            path: &client.path,
+            arguments: client
+                .arguments
+                .as_deref()
+                .unwrap_or(&play_config.arguments),
+        },
+        None => PlayTarget {
+            path: &play_config.path,
+            arguments: &play_config.arguments,
+        },
+    }
+}
+
+pub fn list_clients(
+    play_config: &PlayConfiguration,
+    active_client: Option<&str>,
+) -> Vec<ClientInfo> {
+    let active_client = find_active_client(play_config, active_client).map(|client| &client.name);
+    play_config
+        .clients
+        .iter()
+        .flatten()
+        .map(|client| ClientInfo {
+            name: client.name.clone(),
+            label: client.label.clone().unwrap_or_else(|| client.name.clone()),
+            active: Some(&client.name) == active_client,
+        })
+        .collect()
+}
+
+pub fn has_client(play_config: &PlayConfiguration, name: &str) -> bool {
+    play_config
+        .clients
+        .iter()
+        .flatten()
+        .any(|client| client.name == name)
+}
+
+fn find_active_client<'a>(
+    play_config: &'a PlayConfiguration,
+    active_client: Option<&str>,
+) -> Option<&'a ClientTargetConfiguration> {
+    let clients = play_config.clients.as_deref().unwrap_or_default();
+    active_client
+        .and_then(|active_client| clients.iter().find(|client| client.name == active_client))
+        .or_else(|| clients.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play_config() -> PlayConfiguration {
+        PlayConfiguration {
+            path: "ragexe.exe".to_string(),
+            arguments: vec!["1sak1".to_string()],
+            exit_on_success: None,
+            env: None,
+            working_directory: None,
+            profiles: None,
+            session_token: None,
+            require_login: None,
+            crash_watchdog: None,
+            stay_open_while_running: None,
+            clients: Some(vec![
+                ClientTargetConfiguration {
+                    name: "dx9".to_string(),
+                    label: None,
+                    path: "ragexe_dx9.exe".to_string(),
+                    arguments: None,
+                },
+                ClientTargetConfiguration {
+                    name: "english".to_string(),
+                    label: Some("English".to_string()),
+                    path: "ragexe_en.exe".to_string(),
+                    arguments: Some(vec!["1sak1".to_string(), "-lang:en".to_string()]),
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_select_play_target() {
+        let play_config = play_config();
+        let target = select_play_target(&play_config, Some("english"));
+        assert_eq!(target.path, "ragexe_en.exe");
+        assert_eq!(target.arguments, ["1sak1", "-lang:en"]);
+        // Defaults to the first client
+        for active_client in [None, Some("unknown")].iter() {
+            let target = select_play_target(&play_config, *active_client);
+            assert_eq!(target.path, "ragexe_dx9.exe");
+            assert_eq!(target.arguments, ["1sak1"]);
+        }
+    }
+
+    #[test]
+    fn test_list_clients() {
+        let play_config = play_config();
+        let clients = list_clients(&play_config, Some("english"));
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].label, "dx9");
+        assert!(!clients[0].active);
+        assert!(clients[1].active);
+        assert!(list_clients(&play_config, None)[0].active);
+    }
+}
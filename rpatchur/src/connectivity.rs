@@ -0,0 +1,71 @@
+use std::env;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use url::Url;
+
+/// Converts the location of a page to an URL that can be loaded by the web
+/// view.
+///
+/// Locations can be URLs (`https://`, `file://`) or paths relative to the
+/// working directory.
+pub fn resolve_page_url(location: &str) -> Result<String> {
+    if let Ok(url) = Url::parse(location) {
+        // Single letters are drive letters on Windows
+        if url.scheme().len() > 1 {
+            return Ok(url.to_string());
+        }
+    }
+    let absolute_path = env::current_dir()?.join(location);
+    Url::from_file_path(&absolute_path)
+        .map(|url| url.to_string())
+        .map_err(|_| anyhow!("Invalid page path '{}'", absolute_path.display()))
+}
+
+/// Returns true if the host serving `url` accepts connections (or if the file
+/// exists, for local URLs).
+pub fn is_url_reachable(url: &str, timeout: Duration) -> bool {
+    let url = match Url::parse(url) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    if url.scheme() == "file" {
+        return url
+            .to_file_path()
+            .map(|path| path.is_file())
+            .unwrap_or(false);
+    }
+    let socket_addrs = match url.socket_addrs(|| None).ok() {
+        Some(v) => v,
+        None => return false,
+    };
+    socket_addrs
+        .iter()
+        .any(|address| TcpStream::connect_timeout(address, timeout).is_ok())
+}
+
+/// Blocks until the host serving `url` accepts connections.
+pub fn wait_until_reachable(url: &str, timeout: Duration, retry_interval: Duration) {
+    while !is_url_reachable(url, timeout) {
+        thread::sleep(retry_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_page_url() {
+        assert_eq!(
+            resolve_page_url("https://myserver.com/index.html").unwrap(),
+            "https://myserver.com/index.html"
+        );
+        let local_url = resolve_page_url("ui/index.html").unwrap();
+        assert!(local_url.starts_with("file://"));
+        assert!(local_url.ends_with("/ui/index.html"));
+        assert!(!is_url_reachable(&local_url, Duration::from_secs(1)));
+    }
+}
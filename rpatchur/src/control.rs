@@ -0,0 +1,453 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::{get_patcher_name, share_with_unelevated_user, PatcherCommand};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Status of the patcher as seen by external tools.
+#[derive(Default)]
+pub struct StatusSnapshot {
+    pub patching_in_progress: bool,
+    pub last_status: Option<Value>,
+}
+
+pub type SharedStatusSnapshot = Arc<Mutex<StatusSnapshot>>;
+
+/// Starts a thread that serves the local control endpoint.
+///
+/// The endpoint only listens on the loopback interface. Requests must carry
+/// the token written (along with the port) to `<patcher name>.control` in an
+/// `Authorization: Bearer <token>` header, so that web pages can't drive the
+/// patcher through the player's browser. It exposes the following routes:
+/// - `GET /status`
+/// - `POST /start_update`
+/// - `POST /force_update` (ignores quiet hours)
+/// - `POST /cancel_update`
+/// - `POST /manual_patch` (with a `{"path": "..."}` JSON body)
+pub fn spawn_control_server(
+    port: u16,
+    patching_thread_tx: flume::Sender<PatcherCommand>,
+    status: SharedStatusSnapshot,
+) -> Result<()> {
+    // Clients that don't send (or read) anything mustn't block the endpoint
+    const IO_TIMEOUT: Duration = Duration::from_secs(5);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to listen on port {}", port))?;
+    let port = listener.local_addr()?.port();
+    let token = generate_token();
+    write_control_file(&get_control_file_path()?, port, &token)
+        .with_context(|| "Failed to write the control file")?;
+    log::info!("Control endpoint listening on {}", listener.local_addr()?);
+    let allowed_hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Err(e) => {
+                    log::warn!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+            let res = stream
+                .set_read_timeout(Some(IO_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    handle_connection(stream, &token, &allowed_hosts, &patching_thread_tx, &status)
+                });
+            if let Err(e) = res {
+                log::warn!("Failed to handle control request: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+pub struct ControlRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>, // Names are lowercase
+    pub body: Vec<u8>,
+}
+
+impl ControlRequest {
+    /// Returns the value of the first header named `name` (in lowercase).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parameters expected for the manual_patch route
+#[derive(Deserialize)]
+struct ManualPatchParameters {
+    path: PathBuf,
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    token: &str,
+    allowed_hosts: &[String],
+    patching_thread_tx: &flume::Sender<PatcherCommand>,
+    status: &SharedStatusSnapshot,
+) -> Result<()> {
+    let request = read_request(BufReader::new(&mut stream))?;
+    if let Err((status_code, response)) = authorize_request(&request, token, allowed_hosts) {
+        log::warn!(
+            "Rejected control request {} {}",
+            request.method,
+            request.path
+        );
+        return write_response(&mut stream, status_code, &response);
+    }
+    let (status_code, response) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => {
+            let status = status
+                .lock()
+                .map_err(|_| anyhow!("Status snapshot is poisoned"))?;
+            (
+                200,
+                json!({
+                    "patching_in_progress": status.patching_in_progress,
+                    "last_status": status.last_status,
+                }),
+            )
+        }
+        ("POST", "/start_update") => {
+            send_command_when_idle(patching_thread_tx, status, PatcherCommand::StartUpdate)
+        }
+        ("POST", "/force_update") => {
+            send_command_when_idle(patching_thread_tx, status, PatcherCommand::ForceUpdate)
+        }
+        ("POST", "/cancel_update") => {
+            send_command(patching_thread_tx, PatcherCommand::CancelUpdate)
+        }
+        ("POST", "/manual_patch") => {
+            match serde_json::from_slice::<ManualPatchParameters>(&request.body) {
+                Err(e) => (
+                    400,
+                    json!({ "error": format!("Invalid parameters: {}", e) }),
+                ),
+                Ok(params) => send_command_when_idle(
+                    patching_thread_tx,
+                    status,
+                    PatcherCommand::ApplyPatch(params.path),
+                ),
+            }
+        }
+        _ => (404, json!({ "error": "Unknown route" })),
+    };
+    write_response(&mut stream, status_code, &response)
+}
+
+/// Checks that `request` comes from a local program that knows `token`, and
+/// not from a web page (through CSRF or DNS rebinding).
+fn authorize_request(
+    request: &ControlRequest,
+    token: &str,
+    allowed_hosts: &[String],
+) -> std::result::Result<(), (u16, Value)> {
+    // Browsers always send an `Origin` header with cross-origin requests
+    if request.header("origin").is_some() {
+        return Err((
+            403,
+            json!({ "error": "Requests from web pages are forbidden" }),
+        ));
+    }
+    let host = request.header("host").unwrap_or_default();
+    if !allowed_hosts
+        .iter()
+        .any(|allowed_host| allowed_host.eq_ignore_ascii_case(host))
+    {
+        return Err((403, json!({ "error": "Invalid Host header" })));
+    }
+    let request_token = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !constant_time_eq(request_token.as_bytes(), token.as_bytes()) {
+        return Err((401, json!({ "error": "Invalid or missing token" })));
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Sends a command that starts patching, unless patching is in progress: the
+/// patching thread would take the command for an error in the middle of an
+/// update (or drop it).
+fn send_command_when_idle(
+    patching_thread_tx: &flume::Sender<PatcherCommand>,
+    status: &SharedStatusSnapshot,
+    command: PatcherCommand,
+) -> (u16, Value) {
+    let mut status = match status.lock() {
+        Ok(v) => v,
+        Err(_) => return (500, json!({ "error": "Status snapshot is poisoned" })),
+    };
+    if status.patching_in_progress {
+        return (409, json!({ "error": "Patching is in progress" }));
+    }
+    let response = send_command(patching_thread_tx, command);
+    // Until the patching thread is done with the command (whether it
+    // succeeds or not), so that concurrent requests are rejected as well
+    status.patching_in_progress = response.0 == 200;
+    response
+}
+
+fn send_command(
+    patching_thread_tx: &flume::Sender<PatcherCommand>,
+    command: PatcherCommand,
+) -> (u16, Value) {
+    match patching_thread_tx.send(command) {
+        Ok(()) => (200, json!({ "ok": true })),
+        Err(_) => (503, json!({ "error": "Patching thread is unavailable" })),
+    }
+}
+
+/// Reads a (minimal) HTTP/1.x request.
+fn read_request<R: BufRead>(mut reader: R) -> Result<ControlRequest> {
+    const MAX_BODY_SIZE: usize = 64 * 1024;
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut words = request_line.split_whitespace();
+    let method = words.next().context("Missing HTTP method")?.to_string();
+    let path = words.next().context("Missing HTTP path")?.to_string();
+
+    const MAX_HEADER_COUNT: usize = 64;
+    let mut headers = vec![];
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADER_COUNT {
+            return Err(anyhow!("Too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim().to_string();
+            if name == "content-length" {
+                content_length = value.parse().context("Invalid Content-Length")?;
+            }
+            headers.push((name, value));
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(anyhow!("Request body is too big"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(ControlRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Generates a random token, as a hexadecimal string.
+fn generate_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn get_control_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("control"))
+}
+
+/// Writes the endpoint's port and token to `file_path`, as a JSON object
+/// that only the current user can read.
+fn write_control_file(file_path: &Path, port: u16, token: &str) -> Result<()> {
+    // Permissions are only set on creation
+    match fs::remove_file(file_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(file_path)?;
+    #[cfg(windows)]
+    restrict_to_current_user(file_path)?;
+    file.write_all(
+        json!({ "port": port, "token": token })
+            .to_string()
+            .as_bytes(),
+    )?;
+    share_with_unelevated_user(file_path);
+    Ok(())
+}
+
+/// Replaces the file's ACL with one that only grants access to the current
+/// user.
+#[cfg(windows)]
+fn restrict_to_current_user(file_path: &Path) -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::accctrl::{
+        EXPLICIT_ACCESS_W, NO_MULTIPLE_TRUSTEE, SET_ACCESS, SE_FILE_OBJECT, TRUSTEE_IS_NAME,
+        TRUSTEE_IS_USER, TRUSTEE_W,
+    };
+    use winapi::um::aclapi::{SetEntriesInAclW, SetNamedSecurityInfoW};
+    use winapi::um::winbase::LocalFree;
+    use winapi::um::winnt::{
+        DACL_SECURITY_INFORMATION, GENERIC_ALL, PACL, PROTECTED_DACL_SECURITY_INFORMATION,
+    };
+    const NO_INHERITANCE: DWORD = 0;
+
+    let mut wide_path: Vec<u16> = file_path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut trustee_name: Vec<u16> = OsStr::new("CURRENT_USER")
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+    let mut explicit_access = EXPLICIT_ACCESS_W {
+        grfAccessPermissions: GENERIC_ALL,
+        grfAccessMode: SET_ACCESS,
+        grfInheritance: NO_INHERITANCE,
+        Trustee: TRUSTEE_W {
+            pMultipleTrustee: ptr::null_mut(),
+            MultipleTrusteeOperation: NO_MULTIPLE_TRUSTEE,
+            TrusteeForm: TRUSTEE_IS_NAME,
+            TrusteeType: TRUSTEE_IS_USER,
+            ptstrName: trustee_name.as_mut_ptr(),
+        },
+    };
+    let mut new_dacl: PACL = ptr::null_mut();
+    let result =
+        unsafe { SetEntriesInAclW(1, &mut explicit_access, ptr::null_mut(), &mut new_dacl) };
+    if result != ERROR_SUCCESS {
+        return Err(anyhow!("Failed to build the file's ACL ({})", result));
+    }
+    // Protected, so that the directory's permissions aren't inherited
+    let result = unsafe {
+        SetNamedSecurityInfoW(
+            wide_path.as_mut_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            new_dacl,
+            ptr::null_mut(),
+        )
+    };
+    unsafe { LocalFree(new_dacl as _) };
+    if result != ERROR_SUCCESS {
+        return Err(anyhow!("Failed to update the file's ACL ({})", result));
+    }
+    Ok(())
+}
+
+fn write_response<W: Write>(mut writer: W, status_code: u16, body: &Value) -> Result<()> {
+    let reason = match status_code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        _ => "Service Unavailable",
+    };
+    let body = body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_code,
+        reason,
+        body.len(),
+        body
+    )?;
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw_request =
+            "POST /manual_patch HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n{\"path\":\"a.thor\"}";
+        let request = read_request(raw_request.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/manual_patch");
+        let params: ManualPatchParameters = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(params.path, PathBuf::from("a.thor"));
+
+        assert_eq!(request.header("host"), Some("localhost"));
+
+        let request = read_request("GET /status HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/status");
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn test_authorize_request() {
+        let allowed_hosts = ["127.0.0.1:7777".to_string(), "localhost:7777".to_string()];
+        let authorize = |raw_request: &str| {
+            let request = read_request(raw_request.as_bytes()).unwrap();
+            authorize_request(&request, "secret", &allowed_hosts).map_err(|(code, _)| code)
+        };
+        assert_eq!(
+            authorize("GET /status HTTP/1.1\r\nHost: 127.0.0.1:7777\r\nAuthorization: Bearer secret\r\n\r\n"),
+            Ok(())
+        );
+        assert_eq!(
+            authorize("GET /status HTTP/1.1\r\nHost: localhost:7777\r\nAuthorization: Bearer wrong\r\n\r\n"),
+            Err(401)
+        );
+        assert_eq!(
+            authorize("GET /status HTTP/1.1\r\nHost: localhost:7777\r\n\r\n"),
+            Err(401)
+        );
+        // DNS rebinding
+        assert_eq!(
+            authorize("GET /status HTTP/1.1\r\nHost: evil.com:7777\r\nAuthorization: Bearer secret\r\n\r\n"),
+            Err(403)
+        );
+        assert_eq!(
+            authorize("POST /start_update HTTP/1.1\r\nHost: localhost:7777\r\nOrigin: https://evil.com\r\nAuthorization: Bearer secret\r\n\r\n"),
+            Err(403)
+        );
+    }
+
+    #[test]
+    fn test_send_command_when_idle() {
+        let (tx, rx) = flume::unbounded();
+        let status = SharedStatusSnapshot::default();
+        assert_eq!(
+            send_command_when_idle(&tx, &status, PatcherCommand::StartUpdate).0,
+            200
+        );
+        assert!(matches!(rx.try_recv(), Ok(PatcherCommand::StartUpdate)));
+        assert_eq!(
+            send_command_when_idle(&tx, &status, PatcherCommand::ForceUpdate).0,
+            409
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
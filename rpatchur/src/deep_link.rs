@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::PatchServerInfo;
+use url::Url;
+
+/// Action requested through a custom URL (e.g., `rpatchur://play`).
+#[derive(Debug, PartialEq)]
+pub enum DeepLink {
+    Play,            // Start the game client
+    ApplyPatch(Url), // Download and apply a patch from one of the patch servers
+}
+
+/// Parses a link of the form `<scheme>://<action>[?<parameters>]`.
+///
+/// Supported actions are `play` and `apply?patch=<url>`.
+pub fn parse_deep_link(link: &str, scheme: &str) -> Result<DeepLink> {
+    let link = Url::parse(link).with_context(|| format!("Invalid link '{}'", link))?;
+    if !link.scheme().eq_ignore_ascii_case(scheme) {
+        return Err(anyhow!("Unexpected scheme '{}'", link.scheme()));
+    }
+    match link.host_str() {
+        Some("play") => Ok(DeepLink::Play),
+        Some("apply") => {
+            let patch_url = link
+                .query_pairs()
+                .find(|(key, _)| key == "patch")
+                .map(|(_, value)| value.into_owned())
+                .context("Missing 'patch' parameter")?;
+            let patch_url = Url::parse(&patch_url)
+                .with_context(|| format!("Invalid patch URL '{}'", patch_url))?;
+            Ok(DeepLink::ApplyPatch(patch_url))
+        }
+        _ => Err(anyhow!("Unknown action in link '{}'", link)),
+    }
+}
+
+/// Returns true if the given patch URL points to one of the configured patch
+/// servers.
+///
+/// Links can come from any web page, this prevents them from making the
+/// patcher install arbitrary files.
+pub fn is_patch_url_allowed(patch_url: &Url, patch_servers: &[PatchServerInfo]) -> bool {
+    patch_servers.iter().any(|server| {
+        Url::parse(&server.patch_url)
+            .map(|server_url| {
+                server_url.origin() == patch_url.origin()
+                    && is_in_directory(patch_url.path(), server_url.path())
+            })
+            .unwrap_or(false)
+    })
+}
+
+/// Indicates whether the URL path `path` is `directory` or is located under
+/// it, `directory` being a path prefix that ends on a segment boundary
+/// (e.g., `/patches` doesn't contain `/patches_old/a.thor`).
+fn is_in_directory(path: &str, directory: &str) -> bool {
+    match path.strip_prefix(directory) {
+        Some(rest) => directory.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Registers the custom URL scheme for the current user, so that links using
+/// it start the patcher.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn register_url_protocol(scheme: &str) -> Result<()> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    let exe_path = std::env::current_exe()?;
+    let working_dir = std::env::current_dir()?;
+    let command = format!(
+        "\"{}\" --working-directory \"{}\" \"%1\"",
+        exe_path.display(),
+        working_dir.display()
+    );
+
+    let classes = RegKey::predef(HKEY_CURRENT_USER).open_subkey("Software\\Classes")?;
+    let (scheme_key, _) = classes.create_subkey(scheme)?;
+    scheme_key.set_value("", &format!("URL:{} Protocol", scheme))?;
+    scheme_key.set_value("URL Protocol", &"")?;
+    let (command_key, _) = scheme_key.create_subkey("shell\\open\\command")?;
+    command_key.set_value("", &command)?;
+    Ok(())
+}
+
+/// Registers the custom URL scheme for the current user, so that links using
+/// it start the patcher.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn register_url_protocol(_scheme: &str) -> Result<()> {
+    Err(anyhow!(
+        "URL protocol registration is only supported on Windows"
+    ))
+}
+
+/// Removes the registration of the custom URL scheme, if any.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn unregister_url_protocol(scheme: &str) -> Result<()> {
+    use winreg::enums::{HKEY_CURRENT_USER, KEY_ALL_ACCESS};
+    use winreg::RegKey;
+
+    let classes = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey_with_flags("Software\\Classes", KEY_ALL_ACCESS)?;
+    match classes.delete_subkey_all(scheme) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// There's nothing to unregister on other platforms.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn unregister_url_protocol(_scheme: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_deep_link() {
+        assert_eq!(
+            parse_deep_link("rpatchur://play", "rpatchur").unwrap(),
+            DeepLink::Play
+        );
+        assert_eq!(
+            parse_deep_link("RPatchur://play/", "rpatchur").unwrap(),
+            DeepLink::Play
+        );
+        assert_eq!(
+            parse_deep_link(
+                "rpatchur://apply?patch=https%3A%2F%2Fmyserver.com%2Fdata%2Fa.thor",
+                "rpatchur"
+            )
+            .unwrap(),
+            DeepLink::ApplyPatch(Url::parse("https://myserver.com/data/a.thor").unwrap())
+        );
+        assert!(parse_deep_link("rpatchur://apply", "rpatchur").is_err());
+        assert!(parse_deep_link("rpatchur://setup", "rpatchur").is_err());
+        assert!(parse_deep_link("other://play", "rpatchur").is_err());
+    }
+
+    #[test]
+    fn test_is_patch_url_allowed() {
+        let patch_servers = vec![PatchServerInfo {
+            name: "Main".to_string(),
+            plist_url: "https://myserver.com/plist.txt".to_string(),
+            patch_url: "https://myserver.com/data/".to_string(),
+        }];
+        let allowed = Url::parse("https://myserver.com/data/a.thor").unwrap();
+        assert!(is_patch_url_allowed(&allowed, &patch_servers));
+        let other_path = Url::parse("https://myserver.com/other/a.thor").unwrap();
+        assert!(!is_patch_url_allowed(&other_path, &patch_servers));
+        let other_host = Url::parse("https://evil.com/data/a.thor").unwrap();
+        assert!(!is_patch_url_allowed(&other_host, &patch_servers));
+        let parent_path = Url::parse("https://myserver.com/data/../a.thor").unwrap();
+        assert!(!is_patch_url_allowed(&parent_path, &patch_servers));
+    }
+
+    #[test]
+    fn test_is_patch_url_allowed_without_trailing_slash() {
+        let patch_servers = vec![PatchServerInfo {
+            name: "Main".to_string(),
+            plist_url: "https://myserver.com/plist.txt".to_string(),
+            patch_url: "https://myserver.com/patches".to_string(),
+        }];
+        let allowed = Url::parse("https://myserver.com/patches/a.thor").unwrap();
+        assert!(is_patch_url_allowed(&allowed, &patch_servers));
+        let sibling = Url::parse("https://myserver.com/patches_evil/x.thor").unwrap();
+        assert!(!is_patch_url_allowed(&sibling, &patch_servers));
+        let same_prefix = Url::parse("https://myserver.com/patchesx.thor").unwrap();
+        assert!(!is_patch_url_allowed(&same_prefix, &patch_servers));
+    }
+}
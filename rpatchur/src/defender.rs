@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+/// Adds `directory` to Windows Defender's exclusions, so that real-time
+/// scanning doesn't slow down the patching of multi-GB GRFs.
+///
+/// This requires administrator privileges.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn add_defender_exclusion(directory: &Path) -> Result<()> {
+    use std::os::windows::process::CommandExt;
+    use std::process::Command;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    let script = format!(
+        "Add-MpPreference -ExclusionPath '{}'",
+        directory.to_string_lossy().replace('\'', "''")
+    );
+    let status = Command::new("powershell")
+        .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("PowerShell exited with {}", status));
+    }
+    Ok(())
+}
+
+/// Windows Defender only exists on Windows.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn add_defender_exclusion(_directory: &Path) -> Result<()> {
+    Err(anyhow!("Windows Defender isn't available on this platform"))
+}
@@ -0,0 +1,335 @@
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::GrfArchive;
+use rpatchur_core::{
+    get_active_profile, get_patcher_name, get_patcher_state_files,
+    retrieve_sanitized_configuration, PatcherConfiguration,
+};
+use serde::Serialize;
+
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// State files that can be imported, the others (e.g., the restore point's
+/// manifest) are useless without the files they refer to.
+const IMPORTABLE_STATE_FILES: &[&str] = &["cache", "journal"];
+
+#[derive(Serialize)]
+struct SystemInfo {
+    patcher_version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    profile: Option<String>,
+    game_directory: PathBuf,
+}
+
+#[derive(Serialize)]
+struct GrfHeaderSummary {
+    file_name: String,
+    size: Option<u64>,
+    version: Option<String>,
+    file_count: Option<usize>,
+    error: Option<String>, // Set if the GRF couldn't be opened
+}
+
+/// Writes a zip archive to `destination`, containing what support needs to
+/// investigate patching issues: the configuration (with credentials
+/// redacted), the patcher's state (cache, installation journal and restore
+/// point), the headers of the game's GRFs and a few details about the system.
+///
+/// `patcher_directory` is the directory the patcher has been started from.
+pub fn export_diagnostics(
+    config: &PatcherConfiguration,
+    patcher_directory: &Path,
+    destination: &Path,
+) -> Result<()> {
+    let file = File::create(destination)
+        .with_context(|| format!("Failed to create '{}'", destination.display()))?;
+    let mut zip_writer = ZipWriter::new(BufWriter::new(file));
+
+    let config_file_path =
+        patcher_directory.join(PathBuf::from(get_patcher_name()?).with_extension("yml"));
+    let sanitized_config = retrieve_sanitized_configuration(config_file_path)
+        .with_context(|| "Failed to read the configuration")?;
+    zip_writer.add_file("config.yml", sanitized_config.as_bytes())?;
+    for (role, path) in get_patcher_state_files(config)? {
+        match fs::read(&path) {
+            Ok(content) => zip_writer.add_file(&state_entry_name(role), &content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read '{}'", path.display()))
+            }
+        }
+    }
+    let game_directory = std::env::current_dir()?;
+    let grf_headers = summarize_grf_headers(&game_directory)?;
+    zip_writer.add_file(
+        "grf_headers.json",
+        &serde_json::to_vec_pretty(&grf_headers)?,
+    )?;
+    let system_info = SystemInfo {
+        patcher_version: PKG_VERSION,
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        profile: get_active_profile(),
+        game_directory,
+    };
+    zip_writer.add_file("system.json", &serde_json::to_vec_pretty(&system_info)?)?;
+    zip_writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Restores the patcher's state (cache and installation journal) from an
+/// archive written by `export_diagnostics`, e.g. to reproduce an issue.
+///
+/// Files are written to the paths of the local configuration, not to paths
+/// taken from the archive. Mustn't be called while patching.
+pub fn import_diagnostics(config: &PatcherConfiguration, archive_path: &Path) -> Result<()> {
+    let file = File::open(archive_path)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let entries = read_zip_entries(BufReader::new(file))
+        .with_context(|| format!("Invalid diagnostics archive '{}'", archive_path.display()))?;
+    for (role, path) in get_patcher_state_files(config)? {
+        if !IMPORTABLE_STATE_FILES.contains(&role) {
+            continue;
+        }
+        let entry_name = state_entry_name(role);
+        match entries.iter().find(|(name, _)| *name == entry_name) {
+            Some((_, content)) => {
+                fs::write(&path, content)
+                    .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                log::info!("Imported '{}'", path.display());
+            }
+            None => log::info!("The archive doesn't contain a {}", role),
+        }
+    }
+    Ok(())
+}
+
+fn state_entry_name(role: &str) -> String {
+    format!("state/{}", role)
+}
+
+/// Summarizes the headers of the GRFs found in `game_directory`.
+fn summarize_grf_headers(game_directory: &Path) -> Result<Vec<GrfHeaderSummary>> {
+    let mut summaries = vec![];
+    for dir_entry in fs::read_dir(game_directory)? {
+        let path = dir_entry?.path();
+        let is_grf = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("grf") || ext.eq_ignore_ascii_case("gpf"));
+        if is_grf != Some(true) {
+            continue;
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).ok();
+        let summary = match GrfArchive::open_shared(&path) {
+            Ok(grf_archive) => GrfHeaderSummary {
+                file_name: file_name.into_owned(),
+                size,
+                version: Some(format!(
+                    "0x{:x}{:02x}",
+                    grf_archive.version_major(),
+                    grf_archive.version_minor()
+                )),
+                file_count: Some(grf_archive.file_count()),
+                error: None,
+            },
+            Err(e) => GrfHeaderSummary {
+                file_name: file_name.into_owned(),
+                size,
+                version: None,
+                file_count: None,
+                error: Some(e.to_string()),
+            },
+        };
+        summaries.push(summary);
+    }
+    summaries.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(summaries)
+}
+
+const ZIP_LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP_VERSION: u16 = 20;
+const ZIP_UTF8_FLAG: u16 = 0x0800;
+/// 1980-01-01, modification dates aren't relevant for diagnostics.
+const ZIP_DOS_DATE: u16 = 0x21;
+
+/// Minimal zip writer, entries are stored (i.e., not compressed).
+struct ZipWriter<W: Write> {
+    writer: W,
+    offset: u32,
+    central_directory: Vec<u8>,
+    entry_count: u16,
+}
+
+impl<W: Write> ZipWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            central_directory: vec![],
+            entry_count: 0,
+        }
+    }
+
+    fn add_file(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        let size = u32::try_from(content.len()).map_err(|_| anyhow!("'{}' is too big", name))?;
+        let name_len = u16::try_from(name.len())?;
+        let crc = crc32(content);
+        // Fields shared by the local and central headers
+        let mut common_fields = vec![];
+        common_fields.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // Version needed
+        common_fields.extend_from_slice(&ZIP_UTF8_FLAG.to_le_bytes());
+        common_fields.extend_from_slice(&0_u16.to_le_bytes()); // Stored
+        common_fields.extend_from_slice(&0_u16.to_le_bytes()); // Time
+        common_fields.extend_from_slice(&ZIP_DOS_DATE.to_le_bytes());
+        common_fields.extend_from_slice(&crc.to_le_bytes());
+        common_fields.extend_from_slice(&size.to_le_bytes()); // Compressed
+        common_fields.extend_from_slice(&size.to_le_bytes()); // Uncompressed
+        common_fields.extend_from_slice(&name_len.to_le_bytes());
+        common_fields.extend_from_slice(&0_u16.to_le_bytes()); // Extra field
+
+        let mut local_header = ZIP_LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes().to_vec();
+        local_header.extend_from_slice(&common_fields);
+        local_header.extend_from_slice(name.as_bytes());
+        self.writer.write_all(&local_header)?;
+        self.writer.write_all(content)?;
+
+        let central_directory = &mut self.central_directory;
+        central_directory.extend_from_slice(&ZIP_CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes());
+        central_directory.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // Version made by
+        central_directory.extend_from_slice(&common_fields);
+        central_directory.extend_from_slice(&0_u16.to_le_bytes()); // Comment
+        central_directory.extend_from_slice(&0_u16.to_le_bytes()); // Disk number
+        central_directory.extend_from_slice(&0_u16.to_le_bytes()); // Internal attributes
+        central_directory.extend_from_slice(&0_u32.to_le_bytes()); // External attributes
+        central_directory.extend_from_slice(&self.offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+
+        let entry_size = u32::try_from(local_header.len())? + size;
+        self.offset = self
+            .offset
+            .checked_add(entry_size)
+            .ok_or_else(|| anyhow!("Archive is too big"))?;
+        self.entry_count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        let central_directory_size = u32::try_from(self.central_directory.len())?;
+        self.writer.write_all(&self.central_directory)?;
+        let mut end_record = ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE
+            .to_le_bytes()
+            .to_vec();
+        end_record.extend_from_slice(&0_u16.to_le_bytes()); // Disk number
+        end_record.extend_from_slice(&0_u16.to_le_bytes()); // Central directory's disk
+        end_record.extend_from_slice(&self.entry_count.to_le_bytes());
+        end_record.extend_from_slice(&self.entry_count.to_le_bytes());
+        end_record.extend_from_slice(&central_directory_size.to_le_bytes());
+        end_record.extend_from_slice(&self.offset.to_le_bytes());
+        end_record.extend_from_slice(&0_u16.to_le_bytes()); // Comment
+        self.writer.write_all(&end_record)?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the entries of a zip archive written by `ZipWriter`, by walking its
+/// local headers.
+fn read_zip_entries<R: Read>(mut reader: R) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut entries = vec![];
+    loop {
+        let mut signature = [0; 4];
+        reader.read_exact(&mut signature)?;
+        if u32::from_le_bytes(signature) != ZIP_LOCAL_FILE_HEADER_SIGNATURE {
+            // Reached the central directory
+            break;
+        }
+        let mut header = [0; 26];
+        reader.read_exact(&mut header)?;
+        let read_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        if read_u16(4) != 0 {
+            return Err(anyhow!("Compressed entries aren't supported"));
+        }
+        let crc = read_u32(10);
+        let size = read_u32(14);
+        let mut name = vec![0; read_u16(22) as usize];
+        reader.read_exact(&mut name)?;
+        let mut extra_field = vec![0; read_u16(24) as usize];
+        reader.read_exact(&mut extra_field)?;
+        // The size comes from the archive, the buffer only grows with the
+        // data that's actually there
+        let mut content = vec![];
+        reader
+            .by_ref()
+            .take(u64::from(size))
+            .read_to_end(&mut content)?;
+        if content.len() as u64 != u64::from(size) {
+            return Err(anyhow!("The archive is truncated"));
+        }
+        let name = String::from_utf8(name)?;
+        if crc32(&content) != crc {
+            return Err(anyhow!("'{}' is corrupted", name));
+        }
+        entries.push((name, content));
+    }
+    Ok(entries)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_zip_roundtrip() {
+        let mut zip_writer = ZipWriter::new(Cursor::new(vec![]));
+        zip_writer.add_file("config.yml", b"web: {}").unwrap();
+        zip_writer.add_file("state/cache", &[0, 1, 2, 3]).unwrap();
+        zip_writer.add_file("empty", &[]).unwrap();
+        let mut archive = zip_writer.finish().unwrap().into_inner();
+        let entries = read_zip_entries(Cursor::new(&archive)).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("config.yml".to_string(), b"web: {}".to_vec()),
+                ("state/cache".to_string(), vec![0, 1, 2, 3]),
+                ("empty".to_string(), vec![]),
+            ]
+        );
+        // End of central directory record
+        let end_record = &archive[archive.len() - 22..];
+        assert_eq!(&end_record[..4], &[0x50, 0x4b, 0x05, 0x06]);
+        assert_eq!(&end_record[10..12], &3_u16.to_le_bytes());
+
+        // Corrupt the content of the first entry
+        archive[30 + "config.yml".len()] ^= 0xFF;
+        assert!(read_zip_entries(Cursor::new(&archive)).is_err());
+
+        // Sizes that the archive doesn't have data for
+        let mut zip_writer = ZipWriter::new(Cursor::new(vec![]));
+        zip_writer.add_file("config.yml", b"web: {}").unwrap();
+        let mut archive = zip_writer.finish().unwrap().into_inner();
+        archive[18..22].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_zip_entries(Cursor::new(&archive)).is_err());
+    }
+}
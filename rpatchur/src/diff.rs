@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::GrfArchive;
+use gruf::thor::ThorArchive;
+use serde::Serialize;
+
+use crate::diagnostics::crc32;
+
+/// Entries that differ between two archives.
+#[derive(Debug, Serialize)]
+pub struct ArchiveDiff {
+    entries: Vec<EntryDiff>, // Ordered by path
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryDiff {
+    path: String,
+    change: EntryChange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_crc32: Option<String>, // Added entries have none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_crc32: Option<String>, // Removed entries have none
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+enum Archive {
+    Grf(GrfArchive),
+    Thor(ThorArchive<File>),
+}
+
+/// Prints the entries that have been added, removed or changed between the
+/// archives located at `old_archive_path` and `new_archive_path`, as JSON if
+/// `json_output` is set.
+pub fn print_archive_diff(
+    old_archive_path: &Path,
+    new_archive_path: &Path,
+    json_output: bool,
+) -> Result<()> {
+    let archive_diff = diff_archives(old_archive_path, new_archive_path)?;
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(&archive_diff)?);
+    } else {
+        print!(
+            "{}",
+            archive_diff.describe(old_archive_path, new_archive_path)
+        );
+    }
+    Ok(())
+}
+
+/// Compares the content of two archives. Entries are compared by name (as
+/// the client does, regardless of case and path separators) and by the CRC32
+/// of their decompressed content.
+///
+/// A THOR patch can be compared with a GRF archive, in which case the
+/// differences are the changes that the patch would make to the GRF.
+pub fn diff_archives(old_archive_path: &Path, new_archive_path: &Path) -> Result<ArchiveDiff> {
+    let old_archive = open_archive(old_archive_path)?;
+    let new_archive = open_archive(new_archive_path)?;
+    let entries = match (old_archive, new_archive) {
+        (Archive::Grf(mut old_grf), Archive::Grf(mut new_grf)) => {
+            diff_grf_archives(&mut old_grf, &mut new_grf)?
+        }
+        (Archive::Grf(mut grf_archive), Archive::Thor(mut thor_archive))
+        | (Archive::Thor(mut thor_archive), Archive::Grf(mut grf_archive)) => {
+            diff_thor_with_grf(&mut thor_archive, &mut grf_archive)?
+        }
+        (Archive::Thor(_), Archive::Thor(_)) => {
+            return Err(anyhow!(
+                "THOR patches can only be compared with GRF archives"
+            ));
+        }
+    };
+    Ok(ArchiveDiff { entries })
+}
+
+fn open_archive(archive_path: &Path) -> Result<Archive> {
+    let extension = archive_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("thor") => Ok(Archive::Thor(
+            ThorArchive::open(archive_path)
+                .with_context(|| format!("Failed to open '{}'", archive_path.display()))?,
+        )),
+        Some("grf") | Some("gpf") => Ok(Archive::Grf(
+            GrfArchive::open_shared(archive_path)
+                .with_context(|| format!("Failed to open '{}'", archive_path.display()))?,
+        )),
+        _ => Err(anyhow!(
+            "Unsupported archive '{}', expected a THOR or GRF file",
+            archive_path.display()
+        )),
+    }
+}
+
+fn diff_grf_archives(old_grf: &mut GrfArchive, new_grf: &mut GrfArchive) -> Result<Vec<EntryDiff>> {
+    let old_entries = grf_entry_names(old_grf);
+    let new_entries = grf_entry_names(new_grf);
+    let mut entries = vec![];
+    for (key, old_path) in &old_entries {
+        if !new_entries.contains_key(key) {
+            entries.push(EntryDiff {
+                path: old_path.clone(),
+                change: EntryChange::Removed,
+                old_crc32: Some(grf_entry_crc32(old_grf, old_path)?),
+                new_crc32: None,
+            });
+        }
+    }
+    for (key, new_path) in &new_entries {
+        let new_crc32 = grf_entry_crc32(new_grf, new_path)?;
+        match old_entries.get(key) {
+            None => entries.push(EntryDiff {
+                path: new_path.clone(),
+                change: EntryChange::Added,
+                old_crc32: None,
+                new_crc32: Some(new_crc32),
+            }),
+            Some(old_path) => {
+                let old_crc32 = grf_entry_crc32(old_grf, old_path)?;
+                if old_crc32 != new_crc32 {
+                    entries.push(EntryDiff {
+                        path: new_path.clone(),
+                        change: EntryChange::Changed,
+                        old_crc32: Some(old_crc32),
+                        new_crc32: Some(new_crc32),
+                    });
+                }
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn diff_thor_with_grf(
+    thor_archive: &mut ThorArchive<File>,
+    grf_archive: &mut GrfArchive,
+) -> Result<Vec<EntryDiff>> {
+    let grf_entries = grf_entry_names(grf_archive);
+    let thor_entries: Vec<(String, bool)> = thor_archive
+        .get_entries()
+        .filter(|entry| !entry.is_internal())
+        .map(|entry| (entry.relative_path.clone(), entry.is_removed))
+        .collect();
+    let mut entries = vec![];
+    for (thor_path, is_removed) in thor_entries {
+        let grf_path = grf_entries.get(&entry_key(&thor_path));
+        let old_crc32 = match grf_path {
+            Some(grf_path) => Some(grf_entry_crc32(grf_archive, grf_path)?),
+            None => None,
+        };
+        if is_removed {
+            // Removing entries that don't exist changes nothing
+            if let (Some(grf_path), Some(old_crc32)) = (grf_path, old_crc32) {
+                entries.push(EntryDiff {
+                    path: grf_path.clone(),
+                    change: EntryChange::Removed,
+                    old_crc32: Some(old_crc32),
+                    new_crc32: None,
+                });
+            }
+            continue;
+        }
+        let content = thor_archive
+            .read_file_content(&thor_path)
+            .with_context(|| format!("Failed to read '{}'", thor_path))?;
+        let new_crc32 = format_crc32(crc32(&content));
+        let change = match &old_crc32 {
+            None => EntryChange::Added,
+            Some(old_crc32) if *old_crc32 != new_crc32 => EntryChange::Changed,
+            Some(_) => continue,
+        };
+        entries.push(EntryDiff {
+            path: thor_path,
+            change,
+            old_crc32,
+            new_crc32: Some(new_crc32),
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Returns the names of the entries of `grf_archive`, indexed by
+/// `entry_key`.
+fn grf_entry_names(grf_archive: &GrfArchive) -> BTreeMap<String, String> {
+    grf_archive
+        .get_entries()
+        .map(|entry| (entry_key(&entry.relative_path), entry.relative_path.clone()))
+        .collect()
+}
+
+fn grf_entry_crc32(grf_archive: &mut GrfArchive, relative_path: &str) -> Result<String> {
+    let content = grf_archive
+        .read_file_content(relative_path)
+        .with_context(|| format!("Failed to read '{}'", relative_path))?;
+    Ok(format_crc32(crc32(&content)))
+}
+
+fn entry_key(relative_path: &str) -> String {
+    relative_path.replace('/', "\\").to_ascii_lowercase()
+}
+
+fn format_crc32(crc32: u32) -> String {
+    format!("{:08x}", crc32)
+}
+
+impl ArchiveDiff {
+    /// Returns a human-readable description of the differences, with one line
+    /// per entry.
+    fn describe(&self, old_archive_path: &Path, new_archive_path: &Path) -> String {
+        let count = |change: EntryChange| {
+            self.entries
+                .iter()
+                .filter(|entry| entry.change == change)
+                .count()
+        };
+        let mut description = format!(
+            "'{}' -> '{}'\nAdded: {}, Removed: {}, Changed: {}\n",
+            old_archive_path.display(),
+            new_archive_path.display(),
+            count(EntryChange::Added),
+            count(EntryChange::Removed),
+            count(EntryChange::Changed)
+        );
+        if self.entries.is_empty() {
+            return description;
+        }
+        description.push_str(&format!("  {:>8} {:>8}  Path\n", "Old", "New"));
+        for entry in &self.entries {
+            let status = match entry.change {
+                EntryChange::Added => '+',
+                EntryChange::Removed => '-',
+                EntryChange::Changed => '~',
+            };
+            description.push_str(&format!(
+                "{} {:>8} {:>8}  {}\n",
+                status,
+                entry.old_crc32.as_deref().unwrap_or("-"),
+                entry.new_crc32.as_deref().unwrap_or("-"),
+                entry.path
+            ));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::grf::GrfArchiveBuilder;
+    use gruf::thor::ThorArchiveBuilder;
+
+    #[test]
+    fn test_diff_archives() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let old_grf_path = temp_dir.path().join("old.grf");
+        let new_grf_path = temp_dir.path().join("new.grf");
+        let thor_path = temp_dir.path().join("patch.thor");
+        {
+            let mut builder =
+                GrfArchiveBuilder::create(File::create(&old_grf_path).unwrap(), 2, 0).unwrap();
+            for (path, content) in [
+                ("data\\removed.txt", "removed"),
+                ("data\\Changed.txt", "old"),
+                ("data\\same.txt", "same"),
+            ]
+            .iter()
+            {
+                builder
+                    .add_file(path.to_string(), content.as_bytes())
+                    .unwrap();
+            }
+        }
+        {
+            let mut builder =
+                GrfArchiveBuilder::create(File::create(&new_grf_path).unwrap(), 2, 0).unwrap();
+            for (path, content) in [
+                ("data\\added.txt", "added"),
+                ("data/changed.txt", "new"),
+                ("data\\same.txt", "same"),
+            ]
+            .iter()
+            {
+                builder
+                    .add_file(path.to_string(), content.as_bytes())
+                    .unwrap();
+            }
+        }
+        {
+            let mut builder =
+                ThorArchiveBuilder::new(File::create(&thor_path).unwrap(), true, None, false)
+                    .unwrap();
+            builder
+                .append_file_update("data\\added.txt".to_string(), &b"added"[..])
+                .unwrap();
+            builder
+                .append_file_update("data/changed.txt".to_string(), &b"new"[..])
+                .unwrap();
+            builder
+                .append_file_update("data\\same.txt".to_string(), &b"same"[..])
+                .unwrap();
+            builder.append_file_removal("data\\removed.txt".to_string());
+            builder.append_file_removal("data\\missing.txt".to_string());
+            builder.finish().unwrap();
+        }
+
+        let expected_changes = [
+            ("data/changed.txt", EntryChange::Changed),
+            ("data\\added.txt", EntryChange::Added),
+            ("data\\removed.txt", EntryChange::Removed),
+        ];
+        for new_archive_path in [&new_grf_path, &thor_path].iter() {
+            let archive_diff = diff_archives(&old_grf_path, new_archive_path).unwrap();
+            let changes: Vec<(&str, EntryChange)> = archive_diff
+                .entries
+                .iter()
+                .map(|entry| (entry.path.as_str(), entry.change))
+                .collect();
+            assert_eq!(changes, expected_changes);
+            let changed_entry = &archive_diff.entries[0];
+            assert_eq!(
+                changed_entry.new_crc32.as_deref(),
+                Some(format_crc32(crc32(b"new")).as_str())
+            );
+            assert_eq!(
+                changed_entry.old_crc32.as_deref(),
+                Some(format_crc32(crc32(b"old")).as_str())
+            );
+
+            let description = archive_diff.describe(&old_grf_path, new_archive_path);
+            assert!(description.contains("Added: 1, Removed: 1, Changed: 1\n"));
+        }
+        assert!(diff_archives(&thor_path, &thor_path).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+/// Window rectangle, in physical pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// Declares the process per-monitor DPI aware (V2 if available), so that
+/// the patcher's windows and dialogs are rendered at the DPI of the monitor
+/// they're on instead of being stretched. Must be called before any window is
+/// created.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn enable_per_monitor_dpi_awareness() {
+    use std::ffi::CString;
+    use winapi::shared::minwindef::BOOL;
+    use winapi::shared::windef::{
+        DPI_AWARENESS_CONTEXT, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE,
+        DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+    };
+    use winapi::um::libloaderapi::{GetModuleHandleA, GetProcAddress};
+
+    type SetProcessDpiAwarenessContextFn = unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> BOOL;
+
+    // Only available since Windows 10 (1703 for V2)
+    let module_name = CString::new("user32.dll").unwrap();
+    let function_name = CString::new("SetProcessDpiAwarenessContext").unwrap();
+    let set_process_dpi_awareness_context = unsafe {
+        let user32 = GetModuleHandleA(module_name.as_ptr());
+        if user32.is_null() {
+            return;
+        }
+        let function = GetProcAddress(user32, function_name.as_ptr());
+        if function.is_null() {
+            log::debug!("Per-monitor DPI awareness isn't supported");
+            return;
+        }
+        std::mem::transmute::<_, SetProcessDpiAwarenessContextFn>(function)
+    };
+    let enabled = [
+        DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+        DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE,
+    ]
+    .iter()
+    .any(|&context| unsafe { set_process_dpi_awareness_context(context) } != 0);
+    if !enabled {
+        log::warn!("Failed to enable per-monitor DPI awareness");
+    }
+}
+
+/// GTK applies the monitor's scale factor by itself.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn enable_per_monitor_dpi_awareness() {}
+
+/// Resizes `hwnd` according to the DPI of its monitor, since windows are
+/// created with their configured size in physical pixels while it's given at
+/// 100% scale (96 DPI).
+#[cfg(windows)]
+pub fn scale_window_to_dpi(hwnd: winapi::shared::windef::HWND) {
+    use std::ffi::CString;
+    use winapi::shared::minwindef::UINT;
+    use winapi::shared::windef::{HWND, RECT};
+    use winapi::um::libloaderapi::{GetModuleHandleA, GetProcAddress};
+    use winapi::um::winuser::GetWindowRect;
+
+    type GetDpiForWindowFn = unsafe extern "system" fn(HWND) -> UINT;
+
+    // Only available since Windows 10 (1607), which per-monitor DPI awareness
+    // requires anyway
+    let module_name = CString::new("user32.dll").unwrap();
+    let function_name = CString::new("GetDpiForWindow").unwrap();
+    let dpi = unsafe {
+        let user32 = GetModuleHandleA(module_name.as_ptr());
+        if user32.is_null() {
+            return;
+        }
+        let function = GetProcAddress(user32, function_name.as_ptr());
+        if function.is_null() {
+            return;
+        }
+        std::mem::transmute::<_, GetDpiForWindowFn>(function)(hwnd)
+    };
+    let mut window_rect: RECT = unsafe { std::mem::zeroed() };
+    if dpi == 0 || unsafe { GetWindowRect(hwnd, &mut window_rect) } == 0 {
+        log::warn!("Failed to retrieve the window's DPI");
+        return;
+    }
+    let window_rect = Rect {
+        left: window_rect.left,
+        top: window_rect.top,
+        right: window_rect.right,
+        bottom: window_rect.bottom,
+    };
+    let scaled_rect = scale_rect(window_rect, dpi);
+    if scaled_rect != window_rect {
+        log::debug!("Scaling window to {:?} ({} DPI)", scaled_rect, dpi);
+        move_window(hwnd, scaled_rect);
+    }
+}
+
+/// Moves and resizes `hwnd` so that it fits in the work area of the monitor
+/// it's (mostly) on.
+#[cfg(windows)]
+pub fn fit_window_to_monitor(hwnd: winapi::shared::windef::HWND) {
+    use winapi::shared::windef::RECT;
+    use winapi::um::winuser::{
+        GetMonitorInfoW, GetWindowRect, MonitorFromWindow, MONITORINFO, MONITOR_DEFAULTTONEAREST,
+    };
+
+    let mut window_rect: RECT = unsafe { std::mem::zeroed() };
+    let mut monitor_info: MONITORINFO = unsafe { std::mem::zeroed() };
+    monitor_info.cbSize = std::mem::size_of::<MONITORINFO>() as u32;
+    let res = unsafe {
+        let monitor = MonitorFromWindow(hwnd, MONITOR_DEFAULTTONEAREST);
+        GetWindowRect(hwnd, &mut window_rect) != 0
+            && GetMonitorInfoW(monitor, &mut monitor_info) != 0
+    };
+    if !res {
+        log::warn!("Failed to retrieve the window's monitor");
+        return;
+    }
+    let to_rect = |rect: RECT| Rect {
+        left: rect.left,
+        top: rect.top,
+        right: rect.right,
+        bottom: rect.bottom,
+    };
+    let window_rect = to_rect(window_rect);
+    let fitted_rect = fit_rect(window_rect, to_rect(monitor_info.rcWork));
+    if fitted_rect != window_rect {
+        log::debug!("Moving window to {:?}", fitted_rect);
+        move_window(hwnd, fitted_rect);
+    }
+}
+
+/// Moves and resizes `hwnd` to `rect`.
+#[cfg(windows)]
+pub fn move_window(hwnd: winapi::shared::windef::HWND, rect: Rect) {
+    use winapi::um::winuser::{SetWindowPos, SWP_NOACTIVATE, SWP_NOZORDER};
+
+    unsafe {
+        SetWindowPos(
+            hwnd,
+            std::ptr::null_mut(),
+            rect.left,
+            rect.top,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+            SWP_NOZORDER | SWP_NOACTIVATE,
+        );
+    }
+}
+
+/// Returns `window` with its size scaled from 96 DPI to `dpi`, keeping its
+/// top-left corner in place.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn scale_rect(window: Rect, dpi: u32) -> Rect {
+    const DEFAULT_DPI: i64 = 96;
+    let scale = |size: i32| (i64::from(size) * i64::from(dpi) / DEFAULT_DPI) as i32;
+    Rect {
+        left: window.left,
+        top: window.top,
+        right: window.left + scale(window.right - window.left),
+        bottom: window.top + scale(window.bottom - window.top),
+    }
+}
+
+/// Returns `window` shrunk to the size of `work_area` if it's bigger, and
+/// moved inside of it.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn fit_rect(window: Rect, work_area: Rect) -> Rect {
+    let width = (window.right - window.left).min(work_area.right - work_area.left);
+    let height = (window.bottom - window.top).min(work_area.bottom - work_area.top);
+    let left = window.left.clamp(work_area.left, work_area.right - width);
+    let top = window.top.clamp(work_area.top, work_area.bottom - height);
+    Rect {
+        left,
+        top,
+        right: left + width,
+        bottom: top + height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_rect() {
+        let window = Rect {
+            left: 100,
+            top: 50,
+            right: 880,
+            bottom: 630,
+        };
+        assert_eq!(scale_rect(window, 96), window);
+        // 150%
+        assert_eq!(
+            scale_rect(window, 144),
+            Rect {
+                left: 100,
+                top: 50,
+                right: 1270,
+                bottom: 920,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fit_rect() {
+        let work_area = Rect {
+            left: 0,
+            top: 0,
+            right: 1920,
+            bottom: 1040,
+        };
+        let window = Rect {
+            left: 100,
+            top: 100,
+            right: 880,
+            bottom: 680,
+        };
+        assert_eq!(fit_rect(window, work_area), window);
+        // Half off-screen
+        let window = Rect {
+            left: 1500,
+            top: -200,
+            right: 2670,
+            bottom: 670,
+        };
+        assert_eq!(
+            fit_rect(window, work_area),
+            Rect {
+                left: 750,
+                top: 0,
+                right: 1920,
+                bottom: 870,
+            }
+        );
+        // Bigger than the monitor
+        let window = Rect {
+            left: -10,
+            top: 0,
+            right: 2330,
+            bottom: 1740,
+        };
+        assert_eq!(fit_rect(window, work_area), work_area);
+    }
+}
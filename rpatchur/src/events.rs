@@ -0,0 +1,397 @@
+#[cfg(feature = "staff")]
+use rpatchur_core::PatchPreview;
+use rpatchur_core::{PatchingStatus, PatchingSummary};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::bandwidth::BandwidthStats;
+use crate::clients::ClientInfo;
+use crate::news::NewsItem;
+use crate::ui::ResetScope;
+use crate::version::VersionInfo;
+
+/// Event sent to the UI, through its `rpatchurEvent` function.
+///
+/// Events are serialized as JSON objects with a `type` field, e.g.
+/// `{"type": "installing", "installed": 1, "total": 3}`. UIs that don't define
+/// `rpatchurEvent` receive events through the functions used by previous
+/// versions instead (e.g., `patchingStatusInstalling(1, 3)`).
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UiEvent {
+    Ready,
+    Error {
+        message: String,
+    },
+    Downloading {
+        downloaded: usize,
+        total: usize,
+        bytes_per_sec: u64,
+    },
+    Installing {
+        installed: usize,
+        total: usize,
+    },
+    InstallingEntries {
+        processed: usize,
+        total: usize,
+        bytes_written: u64,
+    },
+    Progress {
+        percent: f32,          // Of the whole update, download and installation included
+        eta_secs: Option<u64>, // Unknown until a throughput has been measured
+    },
+    PatchApplied {
+        patch_name: String,
+    },
+    PatchesSkipped {
+        patch_names: Vec<String>,
+    },
+    PatchFailed {
+        patch_name: String,
+        error: String,
+    },
+    CloseProcesses {
+        process_names: Vec<String>,
+    },
+    UpdatesAvailable {
+        patch_count: usize,
+    },
+    UpdateDeferred {
+        resume_time: String, // "HH:MM", local time
+    },
+    PatchingSummary {
+        summary: PatchingSummary,
+    },
+    PatchingStalled {
+        stage: String, // "download", ...
+        seconds: u64,  // Time since progress was last made
+    },
+    PatchingInProgress, // An action was refused because patching is in progress
+    ConfirmExitWhilePatching,
+    AntivirusInterferenceSuspected, // A GRF stayed locked by another process
+    ServerStatus {
+        services: Vec<Value>,
+    },
+    NewsFeed {
+        news: Vec<NewsItem>,
+    },
+    VersionInfo {
+        info: VersionInfo,
+    },
+    ClientList {
+        clients: Vec<ClientInfo>,
+    },
+    LoginFailed {
+        error: String,
+    },
+    LoginRequired, // `play` was refused because no login has been performed
+    RequestRejected {
+        function: Option<String>, // Unknown if the request couldn't be parsed
+        error: String,
+    },
+    ClientCrashed {
+        exit_code: i32,
+        troubleshooting_url: Option<String>,
+    },
+    ClientExited {
+        exit_code: Option<i32>,
+    },
+    CacheResetDone {
+        scope: ResetScope,
+    },
+    BandwidthStats {
+        stats: BandwidthStats,
+    },
+    BandwidthCapApproached {
+        stats: BandwidthStats,
+    },
+    #[cfg(feature = "staff")]
+    PatchPreview {
+        preview: PatchPreview,
+    },
+    #[cfg(feature = "staff")]
+    PatchPreviewFailed {
+        error: String,
+    },
+}
+
+impl UiEvent {
+    /// Returns the JavaScript code that dispatches the event to the UI.
+    pub fn to_js_code(&self) -> String {
+        format!(
+            "if (typeof rpatchurEvent === 'function') {{ {} }} else {{ {} }}",
+            format_js_call("rpatchurEvent", &[json!(self)]),
+            self.legacy_js_code()
+        )
+    }
+
+    /// Returns the JavaScript code that handles the event in UIs that rely on
+    /// the functions used by previous versions.
+    fn legacy_js_code(&self) -> String {
+        match self {
+            UiEvent::Ready => LEGACY_READY_JS_CODE.to_string(),
+            UiEvent::Error { message } => format_js_call("patchingStatusError", &[json!(message)]),
+            UiEvent::Downloading {
+                downloaded,
+                total,
+                bytes_per_sec,
+            } => format_js_call(
+                "patchingStatusDownloading",
+                &[json!(downloaded), json!(total), json!(bytes_per_sec)],
+            ),
+            UiEvent::Installing { installed, total } => format_js_call(
+                "patchingStatusInstalling",
+                &[json!(installed), json!(total)],
+            ),
+            UiEvent::InstallingEntries {
+                processed,
+                total,
+                bytes_written,
+            } => format_js_call(
+                "patchingStatusInstallingEntries",
+                &[json!(processed), json!(total), json!(bytes_written)],
+            ),
+            UiEvent::Progress { percent, eta_secs } => {
+                format_js_call("patchingStatusProgress", &[json!(percent), json!(eta_secs)])
+            }
+            UiEvent::PatchApplied { patch_name } => {
+                format_js_call("patchingStatusPatchApplied", &[json!(patch_name)])
+            }
+            UiEvent::PatchesSkipped { patch_names } => {
+                format_js_call("patchingStatusPatchesSkipped", &[json!(patch_names)])
+            }
+            UiEvent::PatchFailed { patch_name, error } => {
+                format_js_call("patchFailedPrompt", &[json!(patch_name), json!(error)])
+            }
+            UiEvent::CloseProcesses { process_names } => {
+                format_js_call("closeProcessesPrompt", &[json!(process_names)])
+            }
+            UiEvent::UpdatesAvailable { patch_count } => {
+                format_js_call("updatesAvailable", &[json!(patch_count)])
+            }
+            UiEvent::UpdateDeferred { resume_time } => {
+                format_js_call("updateDeferred", &[json!(resume_time)])
+            }
+            UiEvent::PatchingSummary { summary } => {
+                format_js_call("patchingSummary", &[json!(summary)])
+            }
+            UiEvent::PatchingStalled { stage, seconds } => {
+                format_js_call("patchingStalled", &[json!(stage), json!(seconds)])
+            }
+            UiEvent::AntivirusInterferenceSuspected => {
+                format_js_call("antivirusInterferenceSuspected", &[])
+            }
+            UiEvent::PatchingInProgress => format_js_call("notificationInProgress", &[]),
+            UiEvent::ConfirmExitWhilePatching => format_js_call("confirmExitWhilePatching", &[]),
+            UiEvent::ServerStatus { services } => {
+                format_js_call("serverStatus", &[json!(services)])
+            }
+            UiEvent::NewsFeed { news } => format_js_call("newsFeed", &[json!(news)]),
+            UiEvent::VersionInfo { info } => format_js_call("versionInfo", &[json!(info)]),
+            UiEvent::ClientList { clients } => format_js_call("clientList", &[json!(clients)]),
+            UiEvent::LoginFailed { error } => format_js_call("loginFailed", &[json!(error)]),
+            UiEvent::LoginRequired => format_js_call("loginRequired", &[]),
+            UiEvent::RequestRejected { function, error } => {
+                format_js_call("requestRejected", &[json!(function), json!(error)])
+            }
+            UiEvent::ClientCrashed {
+                exit_code,
+                troubleshooting_url,
+            } => format_js_call(
+                "clientCrashed",
+                &[json!(exit_code), json!(troubleshooting_url)],
+            ),
+            UiEvent::ClientExited { exit_code } => {
+                format_js_call("clientExited", &[json!(exit_code)])
+            }
+            UiEvent::CacheResetDone { scope } => format_js_call("cacheResetDone", &[json!(scope)]),
+            UiEvent::BandwidthStats { stats } => format_js_call("bandwidthStats", &[json!(stats)]),
+            UiEvent::BandwidthCapApproached { stats } => {
+                format_js_call("bandwidthCapApproached", &[json!(stats)])
+            }
+            #[cfg(feature = "staff")]
+            UiEvent::PatchPreview { preview } => format_js_call("patchPreview", &[json!(preview)]),
+            #[cfg(feature = "staff")]
+            UiEvent::PatchPreviewFailed { error } => {
+                format_js_call("patchPreviewFailed", &[json!(error)])
+            }
+        }
+    }
+}
+
+/// Returns the JavaScript code that answers the request identified by
+/// `request_id`, through the UI's `rpatchurResponse` function.
+///
+/// Responses are JSON objects with either a `result` or an `error` field, e.g.
+/// `rpatchurResponse(3, {"result": {"news": [...]}})`. Results are the fields of
+/// the event that would have been sent otherwise, or null.
+pub fn response_js_code(request_id: u64, outcome: Result<Option<&UiEvent>, &str>) -> String {
+    let response = match outcome {
+        Ok(event) => {
+            let mut result = json!(event);
+            if let Some(fields) = result.as_object_mut() {
+                fields.remove("type");
+            }
+            json!({ "result": result })
+        }
+        Err(error) => json!({ "error": error }),
+    };
+    format_js_call("rpatchurResponse", &[json!(request_id), response])
+}
+
+impl From<&PatchingStatus> for UiEvent {
+    fn from(status: &PatchingStatus) -> UiEvent {
+        match status {
+            PatchingStatus::Ready => UiEvent::Ready,
+            PatchingStatus::Error(msg) => UiEvent::Error {
+                message: msg.clone(),
+            },
+            PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => {
+                UiEvent::Downloading {
+                    downloaded: *nb_downloaded,
+                    total: *nb_total,
+                    bytes_per_sec: *bytes_per_sec,
+                }
+            }
+            PatchingStatus::InstallationInProgress(nb_installed, nb_total) => UiEvent::Installing {
+                installed: *nb_installed,
+                total: *nb_total,
+            },
+            PatchingStatus::EntryInstallationInProgress(nb_processed, nb_total, written_bytes) => {
+                UiEvent::InstallingEntries {
+                    processed: *nb_processed,
+                    total: *nb_total,
+                    bytes_written: *written_bytes,
+                }
+            }
+            PatchingStatus::ManualPatchApplied(name) => UiEvent::PatchApplied {
+                patch_name: name.clone(),
+            },
+            PatchingStatus::PatchesSkipped(names) => UiEvent::PatchesSkipped {
+                patch_names: names.clone(),
+            },
+            PatchingStatus::OverallProgress(percent, eta_secs) => UiEvent::Progress {
+                percent: *percent,
+                eta_secs: *eta_secs,
+            },
+        }
+    }
+}
+
+/// Updates the default page's progress bar, there's no function to call for
+/// this event.
+const LEGACY_READY_JS_CODE: &str = r#"
+    const progressBar = document.getElementById('download-progress-bar');
+    const progressText = document.getElementById('download-progress-text');
+    const playButton = document.getElementById('button-play');
+
+    progressBar.style.width = '100%';
+    progressBar.setAttribute('aria-valuenow', '100');
+    progressBar.classList.remove('bg-warning', 'bg-danger');
+    progressBar.classList.add('bg-primary');
+    progressText.textContent = 'Ready';
+    playButton.disabled = false;
+"#;
+
+/// Formats a call to a JavaScript function.
+///
+/// Arguments are serialized as JSON, which makes any string safe to pass.
+pub fn format_js_call(function_name: &str, args: &[Value]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            // These are valid in JSON strings but not in (older) JavaScript ones
+            arg.to_string()
+                .replace('\u{2028}', "\\u2028")
+                .replace('\u{2029}', "\\u2029")
+        })
+        .collect();
+    format!("{}({})", function_name, args.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_js_call() {
+        assert_eq!(
+            format_js_call("notificationInProgress", &[]),
+            "notificationInProgress()"
+        );
+        assert_eq!(
+            format_js_call(
+                "patchingStatusError",
+                &[json!(
+                    "Failed to open 'C:\\Games\\data.grf': \"Access denied\"\n"
+                )]
+            ),
+            r#"patchingStatusError("Failed to open 'C:\\Games\\data.grf': \"Access denied\"\n")"#
+        );
+        assert_eq!(
+            format_js_call("patchingStatusInstalling", &[json!(1), json!(3)]),
+            "patchingStatusInstalling(1, 3)"
+        );
+        assert_eq!(
+            format_js_call("newsFeed", &[json!(["\u{2028}"])]),
+            r#"newsFeed(["\u2028"])"#
+        );
+    }
+
+    #[test]
+    fn test_event_js_code() {
+        let event = UiEvent::Installing {
+            installed: 1,
+            total: 3,
+        };
+        assert_eq!(
+            json!(event),
+            json!({"type": "installing", "installed": 1, "total": 3})
+        );
+        assert_eq!(
+            event.to_js_code(),
+            format!(
+                "if (typeof rpatchurEvent === 'function') {{ rpatchurEvent({}) }} \
+                 else {{ patchingStatusInstalling(1, 3) }}",
+                json!(event)
+            )
+        );
+        assert_eq!(
+            json!(UiEvent::ConfirmExitWhilePatching),
+            json!({"type": "confirm_exit_while_patching"})
+        );
+        let event = UiEvent::CacheResetDone {
+            scope: ResetScope::All,
+        };
+        assert_eq!(
+            json!(event),
+            json!({"type": "cache_reset_done", "scope": "all"})
+        );
+        assert_eq!(
+            event.to_js_code(),
+            format!(
+                "if (typeof rpatchurEvent === 'function') {{ rpatchurEvent({}) }} \
+                 else {{ cacheResetDone(\"all\") }}",
+                json!(event)
+            )
+        );
+    }
+
+    #[test]
+    fn test_response_js_code() {
+        let event = UiEvent::ClientExited { exit_code: Some(0) };
+        assert_eq!(
+            response_js_code(1, Ok(Some(&event))),
+            r#"rpatchurResponse(1, {"result":{"exit_code":0}})"#
+        );
+        assert_eq!(
+            response_js_code(2, Ok(None)),
+            r#"rpatchurResponse(2, {"result":null})"#
+        );
+        assert_eq!(
+            response_js_code(3, Err("Unknown function")),
+            r#"rpatchurResponse(3, {"error":"Unknown function"})"#
+        );
+    }
+}
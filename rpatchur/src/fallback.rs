@@ -0,0 +1,425 @@
+use anyhow::{Context, Result};
+use rpatchur_core::{
+    dispatch_plugin_event, CancellationToken, Patcher, PatcherConfiguration, PatchingStatus,
+    PluginEvent, ProgressSink, UpdateError,
+};
+use serde_json::json;
+use tinyfiledialogs as tfd;
+use tokio::runtime;
+
+use crate::bandwidth::record_downloaded_bytes;
+use crate::clients::active_play_target;
+use crate::process::start_executable;
+
+/// Minimal UI, made of native dialogs, used when the web view cannot be
+/// created (e.g., the WebView2 runtime or WebKitGTK is missing).
+///
+/// It lets the user update the game and start it. Progress is reported in a
+/// small native window on Windows (there's no console), which also lets the
+/// user cancel the update, and on stdout as text otherwise.
+pub fn run_fallback_ui(config: PatcherConfiguration, webview_error: &anyhow::Error) -> Result<()> {
+    #[cfg(windows)]
+    crate::attach_parent_console();
+
+    log::error!("{:#}", webview_error);
+    let title = config.window.title.clone();
+    let patcher = Patcher::new(config);
+    let update_requested = tfd::message_box_yes_no(
+        &title,
+        format!(
+            "The patcher's window could not be opened: {:#}.\n\nUpdate the game now?",
+            webview_error
+        )
+        .as_str(),
+        tfd::MessageBoxIcon::Warning,
+        tfd::YesNo::Yes,
+    ) == tfd::YesNo::Yes;
+    if update_requested {
+        let tokio_rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .with_context(|| "Failed to build a tokio runtime")?;
+        let cancel_token = CancellationToken::new();
+        let result = {
+            // The progress window is closed before showing the outcome
+            let progress_sink = FallbackProgressSink::new(&title, &cancel_token);
+            tokio_rt.block_on(patcher.update(&progress_sink, &cancel_token))
+        };
+        if let Err(UpdateError::Canceled) = result {
+            tfd::message_box_ok(
+                &title,
+                "The update was canceled.",
+                tfd::MessageBoxIcon::Info,
+            );
+            return Ok(());
+        }
+        if let Err(e) = result {
+            tfd::message_box_ok(
+                &title,
+                format!("Failed to update the game: {}.", e).as_str(),
+                tfd::MessageBoxIcon::Error,
+            );
+            return Ok(());
+        }
+    }
+
+    // There's no way to log in without the UI
+    if patcher.config().play.require_login.unwrap_or(false) {
+        tfd::message_box_ok(
+            &title,
+            "The game cannot be started from here, since logging in requires the patcher's window.",
+            tfd::MessageBoxIcon::Info,
+        );
+        return Ok(());
+    }
+    let play_requested = tfd::message_box_yes_no(
+        &title,
+        "Start the game now?",
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::Yes,
+    ) == tfd::YesNo::Yes;
+    if play_requested {
+        let play_config = &patcher.config().play;
+        let play_target = active_play_target(play_config);
+        // Plugins can add arguments
+        let actions = dispatch_plugin_event(
+            patcher.config(),
+            PluginEvent::PrePlay,
+            json!({ "arguments": play_target.arguments }),
+        );
+        let client_arguments: Vec<String> = play_target
+            .arguments
+            .iter()
+            .cloned()
+            .chain(actions.extra_arguments)
+            .collect();
+        start_executable(
+            play_target.path,
+            &client_arguments,
+            &play_config.env.clone().unwrap_or_default(),
+            play_config.working_directory.as_deref(),
+            None,
+        )
+        .with_context(|| "Failed to start client")?;
+    }
+    Ok(())
+}
+
+/// Reports the patching status in a progress window on Windows, or on stdout
+/// as human-readable lines otherwise.
+struct FallbackProgressSink {
+    #[cfg(windows)]
+    window: Option<progress_window::ProgressWindow>,
+}
+
+impl FallbackProgressSink {
+    #[cfg(windows)]
+    fn new(title: &str, cancel_token: &CancellationToken) -> FallbackProgressSink {
+        let window = match progress_window::ProgressWindow::open(title, cancel_token.clone()) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                None
+            }
+        };
+        FallbackProgressSink { window }
+    }
+
+    /// The update can be interrupted with Ctrl+C instead, on this platform.
+    #[cfg(not(windows))]
+    fn new(_title: &str, _cancel_token: &CancellationToken) -> FallbackProgressSink {
+        FallbackProgressSink {}
+    }
+}
+
+impl ProgressSink for FallbackProgressSink {
+    /// This is the Windows version.
+    #[cfg(windows)]
+    fn dispatch_patching_status(&self, status: PatchingStatus) -> Result<()> {
+        if let Some(window) = &self.window {
+            match status {
+                PatchingStatus::OverallProgress(percent, _) => window.set_progress(percent),
+                status => window.set_text(&describe_status(&status)),
+            }
+        }
+        Ok(())
+    }
+
+    /// This is the non-Windows version.
+    #[cfg(not(windows))]
+    fn dispatch_patching_status(&self, status: PatchingStatus) -> Result<()> {
+        println!("{}", describe_status(&status));
+        Ok(())
+    }
+
+    fn confirm_close_processes(&self, process_names: &[String]) -> bool {
+        let answer = tfd::message_box_yes_no(
+            "Game client running",
+            format!(
+                "The following programs must be closed before installing patches: {}. Close them now?",
+                process_names.join(", ")
+            )
+            .as_str(),
+            tfd::MessageBoxIcon::Question,
+            tfd::YesNo::Yes,
+        );
+        answer == tfd::YesNo::Yes
+    }
+
+    /// There's no UI to warn about the monthly cap, the bytes are only added
+    /// to the bandwidth usage.
+    fn record_downloaded_bytes(&self, byte_count: u64) {
+        if let Err(e) = record_downloaded_bytes(byte_count) {
+            log::warn!("Failed to record bandwidth usage: {:#}", e);
+        }
+    }
+}
+
+fn describe_status(status: &PatchingStatus) -> String {
+    match status {
+        PatchingStatus::Ready => "Ready".to_string(),
+        PatchingStatus::Error(msg) => format!("Error: {}", msg),
+        PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => format!(
+            "Downloading patches: {}/{} ({} KiB/s)",
+            nb_downloaded,
+            nb_total,
+            bytes_per_sec / 1024
+        ),
+        PatchingStatus::InstallationInProgress(nb_installed, nb_total) => {
+            format!("Installing patches: {}/{}", nb_installed, nb_total)
+        }
+        PatchingStatus::EntryInstallationInProgress(nb_processed, nb_total, _) => {
+            format!("Installing files: {}/{}", nb_processed, nb_total)
+        }
+        PatchingStatus::ManualPatchApplied(name) => format!("Patch '{}' applied", name),
+        PatchingStatus::PatchesSkipped(names) => {
+            format!("Skipped patches: {}", names.join(", "))
+        }
+        PatchingStatus::OverallProgress(percent, _) => format!("Overall progress: {:.0}%", percent),
+    }
+}
+
+/// Native window made of a label, a progress bar and a cancel button.
+#[cfg(windows)]
+mod progress_window {
+    use std::cell::RefCell;
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::thread::JoinHandle;
+
+    use anyhow::{anyhow, Result};
+    use rpatchur_core::CancellationToken;
+    use winapi::shared::minwindef::{FALSE, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::{HBRUSH, HMENU, HWND};
+    use winapi::um::commctrl::{PBM_SETPOS, PBM_SETRANGE32};
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::winuser::{
+        CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, EnableWindow,
+        GetMessageW, LoadCursorW, PostMessageW, PostQuitMessage, RegisterClassW, SendMessageW,
+        SetWindowTextW, TranslateMessage, BS_PUSHBUTTON, COLOR_BTNFACE, CW_USEDEFAULT, IDC_ARROW,
+        MSG, WM_APP, WM_CLOSE, WM_COMMAND, WM_DESTROY, WNDCLASSW, WS_CAPTION, WS_CHILD,
+        WS_MINIMIZEBOX, WS_OVERLAPPED, WS_TABSTOP, WS_VISIBLE,
+    };
+
+    // Sent by the owner of the window to close it
+    const WM_CLOSE_PROGRESS_WINDOW: UINT = WM_APP + 1;
+    const CANCEL_BUTTON_ID: u16 = 1;
+    const PROGRESS_RANGE: f32 = 1000.0;
+
+    thread_local! {
+        // Canceled by the cancel button, the window procedure runs on the
+        // window's thread
+        static CANCEL_TOKEN: RefCell<Option<CancellationToken>> = RefCell::new(None);
+    }
+
+    pub struct ProgressWindow {
+        // Handles are passed around as integers, since pointers aren't `Send`
+        window: usize,
+        label: usize,
+        progress_bar: usize,
+        ui_thread: Option<JoinHandle<()>>,
+    }
+
+    impl ProgressWindow {
+        /// Opens the window, which runs its message loop on its own thread.
+        /// `cancel_token` is canceled when the user clicks the cancel button.
+        pub fn open(title: &str, cancel_token: CancellationToken) -> Result<ProgressWindow> {
+            let title = to_wide(title);
+            let (handles_tx, handles_rx) = flume::bounded(1);
+            let ui_thread = std::thread::spawn(move || unsafe {
+                CANCEL_TOKEN.with(|token| *token.borrow_mut() = Some(cancel_token));
+                let instance = GetModuleHandleW(ptr::null());
+                let class_name = to_wide("RPatchurProgressWindow");
+                let mut window_class: WNDCLASSW = std::mem::zeroed();
+                window_class.lpfnWndProc = Some(window_procedure);
+                window_class.hInstance = instance;
+                window_class.hCursor = LoadCursorW(ptr::null_mut(), IDC_ARROW);
+                window_class.hbrBackground = (COLOR_BTNFACE + 1) as usize as HBRUSH;
+                window_class.lpszClassName = class_name.as_ptr();
+                RegisterClassW(&window_class);
+                // No close button, the window is closed once the update is over
+                let window = CreateWindowExW(
+                    0,
+                    class_name.as_ptr(),
+                    title.as_ptr(),
+                    WS_OVERLAPPED | WS_CAPTION | WS_MINIMIZEBOX | WS_VISIBLE,
+                    CW_USEDEFAULT,
+                    CW_USEDEFAULT,
+                    420,
+                    150,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    instance,
+                    ptr::null_mut(),
+                );
+                if window.is_null() {
+                    let _ = handles_tx.send(None);
+                    return;
+                }
+                let label = CreateWindowExW(
+                    0,
+                    to_wide("STATIC").as_ptr(),
+                    to_wide("").as_ptr(),
+                    WS_CHILD | WS_VISIBLE,
+                    12,
+                    12,
+                    380,
+                    20,
+                    window,
+                    ptr::null_mut(),
+                    instance,
+                    ptr::null_mut(),
+                );
+                let progress_bar = CreateWindowExW(
+                    0,
+                    to_wide("msctls_progress32").as_ptr(),
+                    ptr::null(),
+                    WS_CHILD | WS_VISIBLE,
+                    12,
+                    40,
+                    380,
+                    20,
+                    window,
+                    ptr::null_mut(),
+                    instance,
+                    ptr::null_mut(),
+                );
+                CreateWindowExW(
+                    0,
+                    to_wide("BUTTON").as_ptr(),
+                    to_wide("Cancel").as_ptr(),
+                    WS_CHILD | WS_VISIBLE | WS_TABSTOP | BS_PUSHBUTTON,
+                    312,
+                    72,
+                    80,
+                    24,
+                    window,
+                    CANCEL_BUTTON_ID as usize as HMENU,
+                    instance,
+                    ptr::null_mut(),
+                );
+                SendMessageW(progress_bar, PBM_SETRANGE32, 0, PROGRESS_RANGE as LPARAM);
+                let handles = (window as usize, label as usize, progress_bar as usize);
+                let _ = handles_tx.send(Some(handles));
+
+                let mut msg: MSG = std::mem::zeroed();
+                while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            });
+            match handles_rx.recv() {
+                Ok(Some((window, label, progress_bar))) => Ok(ProgressWindow {
+                    window,
+                    label,
+                    progress_bar,
+                    ui_thread: Some(ui_thread),
+                }),
+                _ => {
+                    let _ = ui_thread.join();
+                    Err(anyhow!("Failed to open the progress window"))
+                }
+            }
+        }
+
+        pub fn set_text(&self, text: &str) {
+            let text = to_wide(text);
+            unsafe { SetWindowTextW(self.label as HWND, text.as_ptr()) };
+        }
+
+        /// Moves the progress bar to `percent` (from 0 to 100).
+        pub fn set_progress(&self, percent: f32) {
+            let position = (percent.clamp(0.0, 100.0) / 100.0 * PROGRESS_RANGE) as WPARAM;
+            unsafe { SendMessageW(self.progress_bar as HWND, PBM_SETPOS, position, 0) };
+        }
+    }
+
+    impl Drop for ProgressWindow {
+        fn drop(&mut self) {
+            unsafe { PostMessageW(self.window as HWND, WM_CLOSE_PROGRESS_WINDOW, 0, 0) };
+            if let Some(ui_thread) = self.ui_thread.take() {
+                let _ = ui_thread.join();
+            }
+        }
+    }
+
+    unsafe extern "system" fn window_procedure(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            // Alt+F4, the update keeps going
+            WM_CLOSE => 0,
+            WM_COMMAND if LOWORD(wparam as u32) == CANCEL_BUTTON_ID => {
+                CANCEL_TOKEN.with(|token| {
+                    if let Some(token) = token.borrow().as_ref() {
+                        token.cancel();
+                    }
+                });
+                // The window is closed once the update has stopped
+                EnableWindow(lparam as HWND, FALSE);
+                0
+            }
+            WM_CLOSE_PROGRESS_WINDOW => {
+                DestroyWindow(hwnd);
+                0
+            }
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                0
+            }
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+
+    fn to_wide(value: &str) -> Vec<u16> {
+        OsStr::new(value).encode_wide().chain(Some(0)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_status() {
+        assert_eq!(
+            describe_status(&PatchingStatus::DownloadInProgress(1, 3, 2048)),
+            "Downloading patches: 1/3 (2 KiB/s)"
+        );
+        assert_eq!(
+            describe_status(&PatchingStatus::InstallationInProgress(2, 3)),
+            "Installing patches: 2/3"
+        );
+        assert_eq!(
+            describe_status(&PatchingStatus::PatchesSkipped(vec![
+                "a.thor".to_string(),
+                "b.thor".to_string()
+            ])),
+            "Skipped patches: a.thor, b.thor"
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::reader::GrfFileEncryption;
+use gruf::grf::GrfArchive;
+use gruf::thor::ThorArchive;
+use serde::Serialize;
+
+/// Header and entries of a THOR patch or of a GRF archive.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveInfo {
+    Thor {
+        use_grf_merging: bool,      // false -> client directory, true -> GRF
+        target_grf: Option<String>, // None -> default GRF
+        entries: Vec<ArchiveEntryInfo>,
+    },
+    Grf {
+        version: String,
+        entries: Vec<ArchiveEntryInfo>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveEntryInfo {
+    path: String,
+    size: usize,
+    size_compressed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_removed: Option<bool>, // THOR entries only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_encrypted: Option<bool>, // GRF entries only
+}
+
+/// Prints the header and the entries of the archive located at
+/// `archive_path`, as JSON if `json_output` is set.
+pub fn print_archive_info(archive_path: &Path, json_output: bool) -> Result<()> {
+    let archive_info = inspect_archive(archive_path)?;
+    if json_output {
+        println!("{}", serde_json::to_string_pretty(&archive_info)?);
+    } else {
+        print!("{}", archive_info.describe(archive_path));
+    }
+    Ok(())
+}
+
+/// Parses the archive located at `archive_path`. Archives are identified by
+/// their extension.
+pub fn inspect_archive(archive_path: &Path) -> Result<ArchiveInfo> {
+    let extension = archive_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("thor") => {
+            let thor_archive = ThorArchive::open(archive_path)
+                .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+            Ok(thor_archive_info(&thor_archive))
+        }
+        Some("grf") | Some("gpf") => {
+            let grf_archive = GrfArchive::open_shared(archive_path)
+                .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+            Ok(grf_archive_info(&grf_archive))
+        }
+        _ => Err(anyhow!(
+            "Unsupported archive '{}', expected a THOR or GRF file",
+            archive_path.display()
+        )),
+    }
+}
+
+fn thor_archive_info<R: Read + Seek>(thor_archive: &ThorArchive<R>) -> ArchiveInfo {
+    let target_grf_name = thor_archive.target_grf_name();
+    let mut entries: Vec<ArchiveEntryInfo> = thor_archive
+        .get_entries()
+        .map(|entry| ArchiveEntryInfo {
+            path: entry.relative_path.clone(),
+            size: entry.size,
+            size_compressed: entry.size_compressed,
+            is_removed: Some(entry.is_removed),
+            is_encrypted: None,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    ArchiveInfo::Thor {
+        use_grf_merging: thor_archive.use_grf_merging(),
+        target_grf: Some(target_grf_name).filter(|name| !name.is_empty()),
+        entries,
+    }
+}
+
+fn grf_archive_info(grf_archive: &GrfArchive) -> ArchiveInfo {
+    let mut entries: Vec<ArchiveEntryInfo> = grf_archive
+        .get_entries()
+        .map(|entry| ArchiveEntryInfo {
+            path: entry.relative_path.clone(),
+            size: entry.size,
+            size_compressed: entry.size_compressed,
+            is_removed: None,
+            is_encrypted: Some(entry.encryption != GrfFileEncryption::Unencrypted),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    ArchiveInfo::Grf {
+        version: format!(
+            "{}.{}",
+            grf_archive.version_major(),
+            grf_archive.version_minor()
+        ),
+        entries,
+    }
+}
+
+impl ArchiveInfo {
+    /// Returns a human-readable description of the archive, with one line per
+    /// entry.
+    fn describe(&self, archive_path: &Path) -> String {
+        let (header, entries) = match self {
+            ArchiveInfo::Thor {
+                use_grf_merging,
+                target_grf,
+                entries,
+            } => {
+                let target = match (use_grf_merging, target_grf) {
+                    (false, _) => "client directory".to_string(),
+                    (true, None) => "default GRF".to_string(),
+                    (true, Some(target_grf)) => format!("'{}'", target_grf),
+                };
+                (
+                    format!(
+                        "THOR patch '{}'\nTarget: {}\n",
+                        archive_path.display(),
+                        target
+                    ),
+                    entries,
+                )
+            }
+            ArchiveInfo::Grf { version, entries } => (
+                format!(
+                    "GRF archive '{}'\nVersion: {}\n",
+                    archive_path.display(),
+                    version
+                ),
+                entries,
+            ),
+        };
+        let mut description = format!("{}Entries: {}\n", header, entries.len());
+        description.push_str(&format!("{:>12} {:>12}  Path\n", "Size", "Compressed"));
+        for entry in entries {
+            let flag = if entry.is_removed == Some(true) {
+                " (removed)"
+            } else if entry.is_encrypted == Some(true) {
+                " (encrypted)"
+            } else {
+                ""
+            };
+            if entry.is_removed == Some(true) {
+                description.push_str(&format!("{:>12} {:>12}  ", "-", "-"));
+            } else {
+                description.push_str(&format!(
+                    "{:>12} {:>12}  ",
+                    entry.size, entry.size_compressed
+                ));
+            }
+            description.push_str(&format!("{}{}\n", entry.path, flag));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::ThorArchiveBuilder;
+    use std::fs::File;
+
+    #[test]
+    fn test_inspect_thor_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let archive_path = temp_dir.path().join("hotfix.thor");
+        {
+            let output_file = File::create(&archive_path).unwrap();
+            let mut builder =
+                ThorArchiveBuilder::new(output_file, true, Some("custom.grf".to_string()), false)
+                    .unwrap();
+            builder
+                .append_file_update("data\\b.txt".to_string(), &b"content"[..])
+                .unwrap();
+            builder.append_file_removal("data\\a.txt".to_string());
+            builder.finish().unwrap();
+        }
+        let archive_info = inspect_archive(&archive_path).unwrap();
+        let json_info = serde_json::to_value(&archive_info).unwrap();
+        assert_eq!(json_info["type"], "thor");
+        assert_eq!(json_info["target_grf"], "custom.grf");
+        assert_eq!(json_info["entries"][0]["path"], "data\\a.txt");
+        assert_eq!(json_info["entries"][0]["is_removed"], true);
+        assert_eq!(json_info["entries"][1]["size"], 7);
+        assert!(json_info["entries"][1].get("is_encrypted").is_none());
+
+        let description = archive_info.describe(&archive_path);
+        assert!(description.contains("Target: 'custom.grf'\nEntries: 2\n"));
+        assert!(description.contains("data\\a.txt (removed)\n"));
+
+        assert!(inspect_archive(&temp_dir.path().join("hotfix.zip")).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rpatchur_core::{InstallDetectionConfiguration, PatcherConfiguration};
+use tinyfiledialogs as tfd;
+
+use crate::settings::{load_settings_in, save_settings_in};
+
+/// Moves to the game's installation directory, if the patcher hasn't been
+/// dropped into it.
+///
+/// The directory chosen by the user on first run (including the patcher's own
+/// directory, if all the existing installations have been declined) is
+/// remembered in the settings store. Existing installations are only looked
+/// for when `interactive` is true, since the user has to confirm which one to
+/// patch.
+pub fn enter_install_directory(config: &PatcherConfiguration, interactive: bool) -> Result<()> {
+    let patcher_directory = env::current_dir()?;
+    let mut settings = load_settings_in(&patcher_directory);
+    if let Some(install_path) = &settings.install_directory {
+        log::info!("Using installation directory '{}'", install_path.display());
+        return env::set_current_dir(install_path).with_context(|| {
+            format!(
+                "Failed to access installation directory '{}'",
+                install_path.display()
+            )
+        });
+    }
+    // Is the game installed here?
+    if Path::new(&config.play.path).exists() {
+        return Ok(());
+    }
+    let detection_config = match &config.install_detection {
+        Some(v) if interactive => v,
+        _ => return Ok(()),
+    };
+
+    let install_paths = detect_install_paths(detection_config, &config.play.path);
+    if install_paths.is_empty() {
+        return Ok(());
+    }
+    let chosen_path = install_paths
+        .into_iter()
+        .find(|install_path| {
+            let answer = tfd::message_box_yes_no(
+                "Existing installation found",
+                format!(
+                    "The game seems to be installed in '{}'. Do you want to patch this installation?",
+                    install_path.display()
+                )
+                .as_str(),
+                tfd::MessageBoxIcon::Question,
+                tfd::YesNo::Yes,
+            );
+            answer == tfd::YesNo::Yes
+        })
+        .unwrap_or_else(|| patcher_directory.clone());
+    settings.install_directory = Some(chosen_path.clone());
+    save_settings_in(&patcher_directory, &settings)
+        .with_context(|| "Failed to save installation directory")?;
+    log::info!("Using installation directory '{}'", chosen_path.display());
+    env::set_current_dir(&chosen_path).with_context(|| {
+        format!(
+            "Failed to access installation directory '{}'",
+            chosen_path.display()
+        )
+    })
+}
+
+/// Returns the directories of existing installations that contain the game
+/// client's executable.
+fn detect_install_paths(
+    detection_config: &InstallDetectionConfiguration,
+    client_exe: &str,
+) -> Vec<PathBuf> {
+    let mut candidates = vec![];
+    for registry_value in detection_config.registry_values.iter().flatten() {
+        if let Some(path) = read_registry_path(&registry_value.key, &registry_value.value) {
+            candidates.push(path);
+        }
+    }
+    if let Some(steam_folders) = &detection_config.steam_folders {
+        for library_path in find_steam_libraries() {
+            let common_path = library_path.join("steamapps").join("common");
+            candidates.extend(steam_folders.iter().map(|folder| common_path.join(folder)));
+        }
+    }
+
+    let mut install_paths: Vec<PathBuf> = vec![];
+    for candidate in candidates {
+        if candidate.join(client_exe).is_file() && !install_paths.contains(&candidate) {
+            install_paths.push(candidate);
+        }
+    }
+    install_paths
+}
+
+/// Returns the paths of the Steam libraries configured on this machine.
+fn find_steam_libraries() -> Vec<PathBuf> {
+    let steam_path = match find_steam_path() {
+        Some(v) => v,
+        None => return vec![],
+    };
+    let library_folders_path = steam_path.join("steamapps").join("libraryfolders.vdf");
+    let mut libraries = vec![steam_path];
+    if let Ok(content) = fs::read_to_string(library_folders_path) {
+        for library_path in parse_steam_library_folders(&content) {
+            if !libraries.contains(&library_path) {
+                libraries.push(library_path);
+            }
+        }
+    }
+    libraries
+}
+
+/// Extracts the paths listed in Steam's `libraryfolders.vdf` file.
+///
+/// Both the current format (`"path"` keys in library objects) and the legacy
+/// one (paths directly associated with numeric keys) are supported.
+fn parse_steam_library_folders(content: &str) -> Vec<PathBuf> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut tokens = line.trim().split('"').filter(|s| !s.trim().is_empty());
+            let key = tokens.next()?;
+            let value = tokens.next()?;
+            if key == "path" || key.parse::<u32>().is_ok() {
+                Some(PathBuf::from(value.replace("\\\\", "\\")))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns Steam's installation directory.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn find_steam_path() -> Option<PathBuf> {
+    read_registry_path("HKEY_CURRENT_USER\\Software\\Valve\\Steam", "SteamPath")
+}
+
+/// Returns Steam's installation directory.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn find_steam_path() -> Option<PathBuf> {
+    let home = PathBuf::from(env::var_os("HOME")?);
+    [home.join(".steam/steam"), home.join(".local/share/Steam")]
+        .iter()
+        .find(|path| path.is_dir())
+        .cloned()
+}
+
+/// Reads a path from a registry value (e.g., `HKEY_LOCAL_MACHINE\SOFTWARE\...`).
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn read_registry_path(key: &str, value: &str) -> Option<PathBuf> {
+    use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+    use winreg::RegKey;
+
+    let (hive, sub_key) = key.split_once('\\')?;
+    let hive = match hive.to_ascii_uppercase().as_str() {
+        "HKEY_LOCAL_MACHINE" | "HKLM" => RegKey::predef(HKEY_LOCAL_MACHINE),
+        "HKEY_CURRENT_USER" | "HKCU" => RegKey::predef(HKEY_CURRENT_USER),
+        _ => {
+            log::warn!("Unsupported registry hive '{}'", hive);
+            return None;
+        }
+    };
+    let path: String = hive.open_subkey(sub_key).ok()?.get_value(value).ok()?;
+    Some(PathBuf::from(path))
+}
+
+/// There's no registry on other platforms.
+#[cfg(not(windows))]
+fn read_registry_path(_key: &str, _value: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Forgets the installation directory chosen by the user on first run.
+///
+/// `patcher_directory` is the directory the patcher has been started from.
+pub fn forget_install_directory(patcher_directory: &Path) -> Result<()> {
+    let mut settings = load_settings_in(patcher_directory);
+    if settings.install_directory.take().is_none() {
+        return Ok(());
+    }
+    save_settings_in(patcher_directory, &settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_steam_library_folders() {
+        let content = r#"
+"libraryfolders"
+{
+	"0"
+	{
+		"path"		"C:\\Program Files (x86)\\Steam"
+		"label"		""
+		"contentid"		"4155307183158742816"
+	}
+	"1"
+	{
+		"path"		"D:\\SteamLibrary"
+	}
+}"#;
+        let libraries = parse_steam_library_folders(content);
+        assert_eq!(
+            libraries,
+            vec![
+                PathBuf::from("C:\\Program Files (x86)\\Steam"),
+                PathBuf::from("D:\\SteamLibrary")
+            ]
+        );
+
+        let legacy_content = r#"
+"LibraryFolders"
+{
+	"TimeNextStatsReport"		"1561832478"
+	"ContentStatsID"		"-158337411110787451"
+	"1"		"E:\\Games\\Steam"
+}"#;
+        let libraries = parse_steam_library_folders(legacy_content);
+        assert_eq!(libraries, vec![PathBuf::from("E:\\Games\\Steam")]);
+    }
+}
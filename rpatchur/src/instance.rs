@@ -0,0 +1,162 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
+use anyhow::{Context, Result};
+use rpatchur_core::{get_patcher_name, share_with_unelevated_user};
+use serde::{Deserialize, Serialize};
+
+/// Message sent by a secondary instance of the patcher to the primary one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceMessage {
+    Focus,               // Bring the primary instance's window to the front
+    OpenUrl(String),     // Focus and execute the action requested through a custom URL
+    ApplyPatch(PathBuf), // Focus and apply a patch file opened by the user
+}
+
+/// Lock held by the primary instance of the patcher for a given installation.
+///
+/// The lock is released when this is dropped.
+pub struct InstanceLock {
+    lock_file: File,
+    listener: TcpListener,
+}
+
+impl InstanceLock {
+    /// Starts a thread that receives messages sent by secondary instances and
+    /// passes them to `on_message`.
+    pub fn listen<F>(&self, on_message: F) -> Result<()>
+    where
+        F: Fn(InstanceMessage) + Send + 'static,
+    {
+        let listener = self.listener.try_clone()?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let message = stream
+                    .map_err(anyhow::Error::from)
+                    .and_then(read_instance_message);
+                match message {
+                    Err(e) => log::warn!("Failed to receive instance message: {:#}", e),
+                    Ok(message) => on_message(message),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = AdvisoryFileLock::unlock(&self.lock_file);
+        if let Ok(port_file_path) = get_instance_port_file_path() {
+            let _ = fs::remove_file(port_file_path);
+        }
+    }
+}
+
+/// Tries to become the primary instance of the patcher for the current
+/// installation (i.e., working directory).
+///
+/// Returns `None` if another instance already holds the lock.
+pub fn take_instance_lock() -> Result<Option<InstanceLock>> {
+    let lock_file_path = get_instance_lock_file_path()?;
+    let lock_file = File::create(&lock_file_path)?;
+    share_with_unelevated_user(lock_file_path);
+    if AdvisoryFileLock::try_lock(&lock_file, FileLockMode::Exclusive).is_err() {
+        return Ok(None);
+    }
+    // Let secondary instances know where to reach us. The port is written in
+    // a separate file because locks are mandatory on Windows.
+    // The file is replaced atomically, so that secondary instances never
+    // read a partially written port
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let port_file_path = get_instance_port_file_path()?;
+    let temp_port_file_path = port_file_path.with_extension("port.tmp");
+    fs::write(
+        &temp_port_file_path,
+        listener.local_addr()?.port().to_string(),
+    )
+    .and_then(|_| fs::rename(&temp_port_file_path, &port_file_path))
+    .with_context(|| "Failed to write instance port file")?;
+    share_with_unelevated_user(port_file_path);
+
+    Ok(Some(InstanceLock {
+        lock_file,
+        listener,
+    }))
+}
+
+/// Sends a message to the primary instance of the patcher.
+///
+/// The primary instance might have just taken the lock and not written its
+/// port yet (or a stale port might be left by a crashed instance), so this
+/// tries again for a little while.
+pub fn notify_primary_instance(message: InstanceMessage) -> Result<()> {
+    const MAX_ATTEMPTS: u32 = 5;
+    const RETRY_DELAY: Duration = Duration::from_millis(200);
+    // Allow the primary instance to take the focus (Windows prevents it by default)
+    #[cfg(windows)]
+    unsafe {
+        winapi::um::winuser::AllowSetForegroundWindow(winapi::um::winuser::ASFW_ANY);
+    }
+    let mut attempt = 1;
+    let mut stream = loop {
+        match connect_to_primary_instance() {
+            Ok(v) => break v,
+            Err(e) if attempt == MAX_ATTEMPTS => return Err(e),
+            Err(e) => log::debug!("Failed to reach the primary instance: {:#}", e),
+        }
+        thread::sleep(RETRY_DELAY * attempt);
+        attempt += 1;
+    };
+    serde_json::to_writer(&mut stream, &message)?;
+    stream.write_all(b"\n")?;
+    Ok(())
+}
+
+fn connect_to_primary_instance() -> Result<TcpStream> {
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+    let port: u16 = fs::read_to_string(get_instance_port_file_path()?)
+        .with_context(|| "Failed to read instance port file")?
+        .trim()
+        .parse()
+        .with_context(|| "Invalid instance port file")?;
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    Ok(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?)
+}
+
+fn read_instance_message(stream: TcpStream) -> Result<InstanceMessage> {
+    const READ_TIMEOUT: Duration = Duration::from_secs(2);
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).with_context(|| "Invalid instance message")
+}
+
+/// Removes the files used to find the primary instance. Must be called once
+/// the instance lock has been released.
+pub fn remove_instance_files() -> Result<()> {
+    for file_path in &[
+        get_instance_lock_file_path()?,
+        get_instance_port_file_path()?,
+    ] {
+        match fs::remove_file(file_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn get_instance_lock_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("instance"))
+}
+
+fn get_instance_port_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("instance.port"))
+}
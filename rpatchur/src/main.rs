@@ -1,22 +1,55 @@
 #![windows_subsystem = "windows"]
 
-mod patcher;
+mod bandwidth;
+mod browser;
+mod clients;
+mod connectivity;
+mod control;
+mod deep_link;
+mod defender;
+mod diagnostics;
+mod diff;
+mod dpi;
+mod events;
+mod fallback;
+mod inspect;
+mod install_path;
+mod instance;
+mod navigation;
+mod news;
 mod process;
+mod rate_limit;
+mod recording;
+mod server_status;
+mod session;
+mod settings;
+mod shortcuts;
+mod sound;
+mod terminal;
+mod tray;
 mod ui;
+mod uninstall;
+mod version;
+mod watchdog;
+mod window;
 
 use log::LevelFilter;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
+use rpatchur_core::{
+    retrieve_patcher_configuration, set_active_profile, CancellationToken, Patcher, PatcherCommand,
+    PatcherConfiguration, PatchingStatus, PendingDownloads, ProgressSink, UpdateError,
+    UpdateOutcome,
+};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
 use tinyfiledialogs as tfd;
 use tokio::runtime;
 
-use patcher::{
-    patcher_thread_routine, retrieve_patcher_configuration, PatcherCommand, PatcherConfiguration,
-};
+use instance::InstanceMessage;
 use ui::{UiController, WebViewUserData};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
@@ -24,62 +57,583 @@ const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 const PKG_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const PKG_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
+// Exit codes used in headless mode
+const EXIT_CODE_UP_TO_DATE: i32 = 0;
+const EXIT_CODE_FAILURE: i32 = 1;
+const EXIT_CODE_PATCHED: i32 = 2;
+const EXIT_CODE_NETWORK_FAILURE: i32 = 3;
+const EXIT_CODE_INSTALL_FAILURE: i32 = 4;
+const EXIT_CODE_DOWNLOADED: i32 = 5; // Patches downloaded but not installed yet
+const EXIT_CODE_INVALID_SETUP: i32 = 6; // Invalid command-line arguments or configuration
+const EXIT_CODE_PENDING_INSTALL: i32 = 7; // Patches downloaded by an earlier run, not installed yet
+
+// Connectivity checks of the UI's host
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECTIVITY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// Time given to the patching thread to finish installing a patch on exit
+const PATCHER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+// How long patching seems to take with `--mock-patching`
+const MOCK_PATCHING_DURATION: Duration = Duration::from_secs(2);
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = PKG_NAME, version = PKG_VERSION, author = PKG_AUTHORS, about = PKG_DESCRIPTION)]
 struct Opt {
     /// Sets a custom working directory
     #[structopt(short, long, parse(from_os_str))]
     working_directory: Option<PathBuf>,
+    /// Uses the configuration, cache and settings of a profile (e.g., for another server
+    /// sharing the same directory)
+    #[structopt(long)]
+    profile: Option<String>,
+    /// Updates the game without opening a window, reporting progress on stdout (as JSON if it
+    /// isn't a terminal) and logs on stderr
+    #[structopt(long)]
+    headless: bool,
+    /// Downloads pending patches without opening a window (e.g., from a scheduled task), installs
+    /// them unless the game is running, and exits. Patches left to install are installed from
+    /// the download cache by the next update
+    #[structopt(long)]
+    background_check: bool,
+    /// Closes the running game clients listed in `patching.close_client_processes` before
+    /// installing patches with `--headless` or `--background-check`, instead of leaving them
+    /// running
+    #[structopt(long)]
+    close_clients: bool,
+    /// Applies a patch file, as if it had been submitted manually
+    #[structopt(long, parse(from_os_str))]
+    patch: Option<PathBuf>,
+    /// Removes the patcher's data (cache, settings, registered URL protocol, etc.) and exits
+    #[structopt(long)]
+    uninstall_data: bool,
+    /// Writes a zip archive containing the sanitized configuration and the patcher's state, for
+    /// support, and exits
+    #[structopt(long, parse(from_os_str))]
+    export_diagnostics: Option<PathBuf>,
+    /// Restores the patcher's state from an archive written with `--export-diagnostics`, and exits
+    #[structopt(long, parse(from_os_str))]
+    import_diagnostics: Option<PathBuf>,
+    /// Writes the events sent to the UI to a file, so that they can be replayed with
+    /// `--replay-events`
+    #[structopt(long, parse(from_os_str), conflicts_with = "replay-events")]
+    record_events: Option<PathBuf>,
+    /// Sends the events recorded with `--record-events` to the UI again, with their original
+    /// timing
+    #[structopt(long, parse(from_os_str), requires = "mock-patching")]
+    replay_events: Option<PathBuf>,
+    /// Pretends to patch the game when the UI asks for it (reporting progress, then that the
+    /// game is ready) instead of updating it, so that UIs can be developed without a patch
+    /// server
+    #[structopt(long)]
+    mock_patching: bool,
+    /// Serves the patch list (`plist.txt`, or the directory's THOR files ordered by name) and
+    /// the patches of a local directory on 127.0.0.1, for testing, until interrupted
+    #[structopt(long, parse(from_os_str))]
+    serve_fixtures: Option<PathBuf>,
+    /// Port the fixture server listens on
+    #[structopt(long, default_value = "8080")]
+    fixtures_port: u16,
+    /// Delay the fixture server waits before answering each request, in milliseconds
+    #[structopt(long, default_value = "0")]
+    fixtures_latency_ms: u64,
+    /// Makes the fixture server answer every Nth request with an error
+    #[structopt(long)]
+    fixtures_fail_every: Option<u64>,
+    /// Patch file to apply, or link to open (e.g., rpatchur://play) received through the custom
+    /// URL scheme
+    #[structopt(conflicts_with = "patch")]
+    target: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
-    SimpleLogger::new()
-        .with_level(LevelFilter::Off)
-        .with_module_level(PKG_NAME, LevelFilter::Info)
-        .init()
-        .with_context(|| "Failed to initalize the logger")?;
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Prints the header and the entries of a THOR patch or of a GRF archive, and exits
+    Inspect {
+        /// Prints the information as JSON
+        #[structopt(long)]
+        json: bool,
+        /// Path to a THOR or GRF file
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+    },
+    /// Prints the entries that have been added, removed or changed between two archives (or
+    /// that a THOR patch would change in a GRF archive), and exits
+    Diff {
+        /// Prints the differences as JSON
+        #[structopt(long)]
+        json: bool,
+        /// Path to a GRF file (or to a THOR file if the other one is a GRF)
+        #[structopt(parse(from_os_str))]
+        old_archive: PathBuf,
+        /// Path to a GRF file (or to a THOR file if the other one is a GRF)
+        #[structopt(parse(from_os_str))]
+        new_archive: PathBuf,
+    },
+}
 
+fn main() -> Result<()> {
+    // Must be done before any window (including message boxes) is created
+    dpi::enable_per_monitor_dpi_awareness();
     // Parse CLI arguments
-    let cli_args = Opt::from_args();
+    let mut cli_args = match Opt::from_iter_safe(env::args_os()) {
+        Ok(v) => v,
+        Err(e) if e.use_stderr() => {
+            #[cfg(windows)]
+            attach_parent_console();
+            eprintln!("{}", e.message);
+            std::process::exit(EXIT_CODE_INVALID_SETUP);
+        }
+        // --help or --version
+        Err(e) => e.exit(),
+    };
+    match &cli_args.command {
+        Some(Command::Inspect { json, archive }) => {
+            #[cfg(windows)]
+            attach_parent_console();
+            return inspect::print_archive_info(archive, *json);
+        }
+        Some(Command::Diff {
+            json,
+            old_archive,
+            new_archive,
+        }) => {
+            #[cfg(windows)]
+            attach_parent_console();
+            return diff::print_archive_diff(old_archive, new_archive, *json);
+        }
+        None => {}
+    }
+    // The positional argument is either a patch file (e.g., dropped onto the
+    // executable) or a link, it cannot be combined with `--patch`
+    let (patch_file_path, url) = match cli_args.target.take() {
+        Some(target) if target.to_ascii_lowercase().ends_with(".thor") => {
+            (Some(PathBuf::from(target)), None)
+        }
+        target => (cli_args.patch.take(), target),
+    };
+    // Relative paths must not depend on the working directory we'll use
+    let patch_file_path = match patch_file_path {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None,
+    };
+    let export_diagnostics_path = match cli_args.export_diagnostics.take() {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None,
+    };
+    let import_diagnostics_path = match cli_args.import_diagnostics.take() {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None,
+    };
+    let record_events_path = match cli_args.record_events.take() {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None,
+    };
+    let replayed_events = match &cli_args.replay_events {
+        Some(path) => Some(recording::load_recorded_events(path)?),
+        None => None,
+    };
+
+    // Logs would get mixed with the JSON output in headless mode, they're
+    // written to stderr instead
+    let headless = cli_args.headless || cli_args.background_check;
+    if headless {
+        #[cfg(windows)]
+        attach_parent_console();
+        // Progress bars would be broken by every log line
+        let log_level = if atty::is(atty::Stream::Stdout) {
+            LevelFilter::Warn
+        } else {
+            LevelFilter::Info
+        };
+        StderrLogger::init(log_level).with_context(|| "Failed to initalize the logger")?;
+    } else {
+        SimpleLogger::new()
+            .with_level(LevelFilter::Off)
+            .with_module_level(PKG_NAME, LevelFilter::Info)
+            .with_module_level("rpatchur_core", LevelFilter::Info)
+            .init()
+            .with_context(|| "Failed to initalize the logger")?;
+    }
+
+    if let Some(fixtures_directory) = &cli_args.serve_fixtures {
+        #[cfg(windows)]
+        attach_parent_console();
+        let options = rpatchur_core::FixtureServerOptions {
+            latency: Duration::from_millis(cli_args.fixtures_latency_ms),
+            fail_every: cli_args.fixtures_fail_every,
+        };
+        return rpatchur_core::serve_fixtures(fixtures_directory, cli_args.fixtures_port, options);
+    }
+    if let Some(profile_name) = &cli_args.profile {
+        exit_on_invalid_setup(set_active_profile(profile_name), headless)?;
+    }
+    let working_directory_overridden = cli_args.working_directory.is_some();
     if let Some(working_directory) = cli_args.working_directory {
-        env::set_current_dir(working_directory)
-            .with_context(|| "Specified working directory is invalid or inaccessible")?;
+        exit_on_invalid_setup(
+            env::set_current_dir(working_directory)
+                .with_context(|| "Specified working directory is invalid or inaccessible"),
+            headless,
+        )?;
     };
 
     let config = match retrieve_patcher_configuration(None) {
         Err(e) => {
             let err_msg = "Failed to retrieve the patcher's configuration";
-            tfd::message_box_ok(
-                "Error",
-                format!("Error: {}: {:#}.", err_msg, e).as_str(),
-                tfd::MessageBoxIcon::Error,
-            );
-            return Err(e);
+            if !headless {
+                tfd::message_box_ok(
+                    "Error",
+                    format!("Error: {}: {:#}.", err_msg, e).as_str(),
+                    tfd::MessageBoxIcon::Error,
+                );
+            }
+            return exit_on_invalid_setup(Err(e.context(err_msg)), headless);
         }
         Ok(v) => v,
     };
 
+    // Patch an existing installation if the patcher isn't in the game's directory
+    let patcher_directory = env::current_dir()?;
+    if !working_directory_overridden {
+        if let Err(e) = install_path::enter_install_directory(&config, !headless) {
+            log::warn!("{:#}", e);
+        }
+    }
+
+    if let Some(destination) = export_diagnostics_path {
+        #[cfg(windows)]
+        attach_parent_console();
+        diagnostics::export_diagnostics(&config, &patcher_directory, &destination)?;
+        log::info!("Diagnostics written to '{}'", destination.display());
+        return Ok(());
+    }
+    if let Some(archive_path) = import_diagnostics_path {
+        return run_import_diagnostics(&config, &archive_path);
+    }
+    if cli_args.headless {
+        let exit_code = run_headless(Patcher::new(config), cli_args.close_clients)?;
+        std::process::exit(exit_code);
+    }
+    if cli_args.background_check {
+        let exit_code = run_background_check(config, cli_args.close_clients)?;
+        std::process::exit(exit_code);
+    }
+    if cli_args.uninstall_data {
+        return run_uninstall_data(&config, &patcher_directory);
+    }
+
+    // Only one instance of the patcher can run for a given installation,
+    // forward the request to the running one if there's already one
+    let instance_lock =
+        match instance::take_instance_lock().with_context(|| "Failed to take the instance lock")? {
+            Some(instance_lock) => instance_lock,
+            None => {
+                log::info!("The patcher is already running");
+                let message = match (patch_file_path, url) {
+                    (Some(patch_file_path), _) => InstanceMessage::ApplyPatch(patch_file_path),
+                    (None, Some(url)) => InstanceMessage::OpenUrl(url),
+                    (None, None) => InstanceMessage::Focus,
+                };
+                return instance::notify_primary_instance(message)
+                    .with_context(|| "Failed to notify the running instance");
+            }
+        };
+
+    // Register the custom URL scheme if needed
+    let url_scheme = config.url_protocol.as_ref().map(|c| c.scheme.clone());
+    if let Some(scheme) = &url_scheme {
+        if let Err(e) = deep_link::register_url_protocol(scheme) {
+            log::warn!("Failed to register URL protocol: {:#}", e);
+        }
+    }
+
+    if let Err(e) = shortcuts::create_shortcuts_on_first_run(&config) {
+        log::warn!("{:#}", e);
+    }
+
     // Create a channel to allow the webview's thread to communicate with the patching thread
     let (tx, rx) = flume::bounded(32);
+    let control_tx = tx.clone();
     let window_title = config.window.title.clone();
-    let webview = ui::build_webview(
-        window_title.as_str(),
-        WebViewUserData::new(config.clone(), tx),
-    )
-    .with_context(|| "Failed to build a web view")?;
+    // Display a fallback page if the UI cannot be reached
+    let index_url = connectivity::resolve_page_url(&config.web.index_url)
+        .with_context(|| "Invalid 'index_url'")?;
+    let offline = !connectivity::is_url_reachable(&index_url, CONNECTIVITY_TIMEOUT);
+    let page_url = if offline {
+        log::warn!("'{}' is unreachable, starting in offline mode", index_url);
+        config
+            .web
+            .fallback_index_url
+            .as_ref()
+            .and_then(|location| connectivity::resolve_page_url(location).ok())
+            .filter(|url| connectivity::is_url_reachable(url, CONNECTIVITY_TIMEOUT))
+    } else {
+        Some(index_url.clone())
+    };
+    // Only the UI's own pages can call more than harmless functions
+    let page_urls: Vec<&str> = std::iter::once(index_url.as_str())
+        .chain(page_url.as_deref())
+        .collect();
+    let trusted_url_prefixes = navigation::allowed_url_prefixes(&page_urls, &[]);
+    let mut user_data =
+        WebViewUserData::new(config.clone(), tx, patcher_directory, trusted_url_prefixes);
+    if let Some(path) = record_events_path {
+        user_data.set_event_recorder(recording::EventRecorder::create(&path)?);
+    }
+    let webview = match ui::build_webview(window_title.as_str(), user_data, page_url.clone())
+        .with_context(|| "Failed to build a web view")
+    {
+        Ok(v) => v,
+        // Let the user patch and play anyway
+        Err(e) => return fallback::run_fallback_ui(config, &e),
+    };
+
+    let ui_controller = UiController::new(&webview);
+    // Keep the UI (and its access to the patcher) away from other sites
+    let allowed_url_prefixes = navigation::allowed_url_prefixes(
+        &page_urls,
+        config.web.allowed_origins.as_deref().unwrap_or_default(),
+    );
+    let on_page_loaded = webview.user_data().page_load_listener();
+    browser::guard_navigation(&webview, allowed_url_prefixes, Box::new(on_page_loaded))
+        .with_context(|| "Failed to restrict the UI's navigation")?;
+    if let Some(patch_file_path) = patch_file_path {
+        ui_controller.apply_patch_file(patch_file_path);
+    }
+    if let Some(url) = url {
+        open_deep_link(&ui_controller, &url, url_scheme.as_deref());
+    }
+    let instance_ui_controller = UiController::new(&webview);
+    instance_lock
+        .listen(move |message| match message {
+            InstanceMessage::Focus => instance_ui_controller.focus_window(),
+            InstanceMessage::OpenUrl(url) => {
+                instance_ui_controller.focus_window();
+                open_deep_link(&instance_ui_controller, &url, url_scheme.as_deref());
+            }
+            InstanceMessage::ApplyPatch(patch_file_path) => {
+                instance_ui_controller.focus_window();
+                instance_ui_controller.apply_patch_file(patch_file_path);
+            }
+        })
+        .with_context(|| "Failed to listen to other instances")?;
+    if offline {
+        // Load the actual UI as soon as it's back online
+        let offline_ui_controller = UiController::new(&webview);
+        std::thread::spawn(move || {
+            connectivity::wait_until_reachable(
+                &index_url,
+                CONNECTIVITY_TIMEOUT,
+                CONNECTIVITY_RETRY_INTERVAL,
+            );
+            log::info!("'{}' is reachable again", index_url);
+            offline_ui_controller.load_url(&index_url);
+        });
+    }
+    // Expose the local control endpoint if needed
+    if let Some(control_config) = &config.control {
+        control::spawn_control_server(
+            control_config.port,
+            control_tx,
+            ui_controller.status_snapshot(),
+        )
+        .with_context(|| "Failed to start the control endpoint")?;
+    }
 
     // Spawn a patching thread
-    let patching_thread = new_patching_thread(rx, UiController::new(&webview), config);
+    let (stopped_tx, stopped_rx) = flume::bounded(0);
+    let patching_thread = if cli_args.mock_patching {
+        if let Some(events) = replayed_events {
+            ui_controller.spawn_event_replay(events);
+        }
+        new_mock_patching_thread(rx, ui_controller, stopped_tx)
+    } else {
+        new_patching_thread(rx, ui_controller, config, stopped_tx)
+    };
+    // The patching thread is asked to stop once the web view's user data is
+    // dropped
     webview
         .run()
         .with_context(|| "Failed to run the web view")?;
-    // Join the patching thread
+    join_patching_thread(patching_thread, stopped_rx)
+}
+
+/// Waits for the patching thread to stop, for a limited time.
+///
+/// The patching thread stops once it's done with the patch it's installing,
+/// exiting before that could corrupt the GRF being patched.
+fn join_patching_thread(
+    patching_thread: std::thread::JoinHandle<Result<()>>,
+    stopped_rx: flume::Receiver<()>,
+) -> Result<()> {
+    log::trace!("Waiting for the patching thread to stop");
+    if let Err(flume::RecvTimeoutError::Timeout) = stopped_rx.recv_timeout(PATCHER_SHUTDOWN_TIMEOUT)
+    {
+        return Err(anyhow!("Patching thread didn't stop in time"));
+    }
     patching_thread
         .join()
         .map_err(|_| anyhow!("Failed to join patching thread"))?
-        .with_context(|| "Patching thread ran into an error")?;
+        .with_context(|| "Patching thread ran into an error")
+}
+
+/// Executes the action requested through a link, if the custom URL scheme is
+/// enabled.
+fn open_deep_link(ui_controller: &UiController, url: &str, url_scheme: Option<&str>) {
+    let scheme = match url_scheme {
+        Some(v) => v,
+        None => {
+            log::warn!("Ignoring link '{}', URL protocol is disabled", url);
+            return;
+        }
+    };
+    match deep_link::parse_deep_link(url, scheme) {
+        Err(e) => log::warn!("{:#}", e),
+        Ok(link) => ui_controller.open_deep_link(link),
+    }
+}
+
+/// Exits with `EXIT_CODE_INVALID_SETUP` if `result` is an error in headless
+/// mode, so that scripts can tell setup errors from failed updates. Errors are
+/// returned as is otherwise.
+fn exit_on_invalid_setup<T>(result: Result<T>, headless: bool) -> Result<T> {
+    match result {
+        Err(e) if headless => {
+            log::error!("{:#}", e);
+            std::process::exit(EXIT_CODE_INVALID_SETUP);
+        }
+        res => res,
+    }
+}
+
+/// Writes the logs of the patcher to stderr, in headless mode, since stdout is
+/// used for the patching status.
+struct StderrLogger {
+    level: LevelFilter,
+}
+
+impl StderrLogger {
+    fn init(level: LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(StderrLogger { level }))
+    }
+}
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let target = metadata.target();
+        metadata.level() <= self.level
+            && (target.starts_with(PKG_NAME) || target.starts_with("rpatchur_core"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{:<5} [{}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Updates the game without UI and returns the exit code the process should
+/// exit with.
+fn run_headless(patcher: Patcher, close_clients: bool) -> Result<i32> {
+    #[cfg(windows)]
+    attach_parent_console();
+
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let ui_controller = UiController::headless(patcher.config(), close_clients);
+    let result = tokio_rt.block_on(patcher.update(&ui_controller, &CancellationToken::new()));
+    Ok(match result {
+        Ok(UpdateOutcome::UpToDate) => EXIT_CODE_UP_TO_DATE,
+        Ok(UpdateOutcome::Patched) => EXIT_CODE_PATCHED,
+        Err(UpdateError::Network(_)) => EXIT_CODE_NETWORK_FAILURE,
+        Err(UpdateError::Installation(_)) => EXIT_CODE_INSTALL_FAILURE,
+        Err(UpdateError::Canceled) | Err(UpdateError::Other(_)) => EXIT_CODE_FAILURE,
+    })
+}
+
+/// Updates the game in headless mode, or only downloads pending patches if
+/// the game is running, since GRFs can't be patched while the client has them
+/// open.
+fn run_background_check(config: PatcherConfiguration, close_clients: bool) -> Result<i32> {
+    let patcher = Patcher::new(config);
+    let game_client_running = match patcher.is_game_client_running() {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("{:#}", e);
+            // Downloading is always safe
+            true
+        }
+    };
+    if !game_client_running {
+        return run_headless(patcher, close_clients);
+    }
+    // The next update couldn't install the downloaded patches
+    if !patcher.config().patching.resumable_updates.unwrap_or(true) {
+        log::error!("Patches can only be downloaded ahead of time with 'resumable_updates'");
+        return Ok(EXIT_CODE_INVALID_SETUP);
+    }
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let progress_sink = UiController::headless(patcher.config(), close_clients);
+    let result = tokio_rt.block_on(patcher.download_pending_patches(&progress_sink));
+    Ok(match result {
+        Ok(PendingDownloads {
+            downloaded: 0,
+            previously_downloaded: 0,
+        }) => EXIT_CODE_UP_TO_DATE,
+        Ok(PendingDownloads { downloaded: 0, .. }) => EXIT_CODE_PENDING_INSTALL,
+        Ok(_) => EXIT_CODE_DOWNLOADED,
+        Err(e) => {
+            log::error!("Failed to download patches: {:#}", e);
+            EXIT_CODE_NETWORK_FAILURE
+        }
+    })
+}
+
+/// Removes the patcher's data, unless the patcher is running.
+fn run_uninstall_data(config: &PatcherConfiguration, patcher_directory: &Path) -> Result<()> {
+    #[cfg(windows)]
+    attach_parent_console();
+
+    let instance_lock = instance::take_instance_lock()
+        .with_context(|| "Failed to take the instance lock")?
+        .ok_or_else(|| anyhow!("The patcher is running, close it first"))?;
+    let res = uninstall::uninstall_patcher_data(config, patcher_directory);
+    drop(instance_lock);
+    instance::remove_instance_files().with_context(|| "Failed to remove instance files")?;
+    res
+}
+
+/// Restores the patcher's state from a diagnostics archive, unless the
+/// patcher is running.
+fn run_import_diagnostics(config: &PatcherConfiguration, archive_path: &Path) -> Result<()> {
+    #[cfg(windows)]
+    attach_parent_console();
+
+    let _instance_lock = instance::take_instance_lock()
+        .with_context(|| "Failed to take the instance lock")?
+        .ok_or_else(|| anyhow!("The patcher is running, close it first"))?;
+    diagnostics::import_diagnostics(config, archive_path)
+}
 
-    Ok(())
+/// Attaches the process to its parent's console (if any), so that headless
+/// output is visible even though the executable uses the "windows" subsystem.
+#[cfg(windows)]
+fn attach_parent_console() {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+    let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
 }
 
 /// Spawns a new thread that runs a single threaded tokio runtime to execute the patcher routine
@@ -87,16 +641,66 @@ fn new_patching_thread(
     rx: flume::Receiver<PatcherCommand>,
     ui_ctrl: UiController,
     config: PatcherConfiguration,
+    stopped_tx: flume::Sender<()>,
 ) -> std::thread::JoinHandle<Result<()>> {
     std::thread::spawn(move || {
+        // Dropping the sender notifies the main thread, even on panic
+        let _stopped_tx = stopped_tx;
         // Build a tokio runtime that runs a scheduler on the current thread and a reactor
         let tokio_rt = runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .with_context(|| "Failed to build a tokio runtime")?;
         // Block on the patching task from our synchronous function
-        tokio_rt.block_on(patcher_thread_routine(ui_ctrl, config, rx));
+        tokio_rt.block_on(Patcher::new(config).run(&ui_ctrl, rx));
 
         Ok(())
     })
 }
+
+/// Stands in for the patching thread with `--mock-patching`, so that neither
+/// the network nor the game's files are touched: commands that start patching
+/// pretend to install nothing for `MOCK_PATCHING_DURATION` before reporting
+/// that the game is ready, and other commands are ignored.
+fn new_mock_patching_thread(
+    rx: flume::Receiver<PatcherCommand>,
+    ui_controller: UiController,
+    stopped_tx: flume::Sender<()>,
+) -> std::thread::JoinHandle<Result<()>> {
+    std::thread::spawn(move || {
+        let _stopped_tx = stopped_tx;
+        // Stops once the web view's user data is dropped
+        for command in rx.iter() {
+            if !starts_patching(&command) {
+                log::info!("Ignoring patcher command (mock patching)");
+                // Commands sent by the control endpoint mark the patcher as busy
+                ui_controller.set_patch_in_progress(false);
+                continue;
+            }
+            log::info!("Pretending to patch the game (mock patching)");
+            ui_controller.set_patch_in_progress(true);
+            let _ = ui_controller
+                .dispatch_patching_status(PatchingStatus::InstallationInProgress(0, 0));
+            std::thread::sleep(MOCK_PATCHING_DURATION);
+            let _ = ui_controller.dispatch_patching_status(PatchingStatus::Ready);
+            ui_controller.set_patch_in_progress(false);
+        }
+        Ok(())
+    })
+}
+
+/// Returns true for the commands after which the patching thread reports
+/// progress, then either `Ready` or an error.
+fn starts_patching(command: &PatcherCommand) -> bool {
+    matches!(
+        command,
+        PatcherCommand::StartUpdate
+            | PatcherCommand::ForceUpdate
+            | PatcherCommand::ApplyPatch(..)
+            | PatcherCommand::ApplyRemotePatch(..)
+            | PatcherCommand::RestoreFromPoint
+            | PatcherCommand::RestoreBackups
+            | PatcherCommand::DefragmentGrfs
+            | PatcherCommand::RepairGameFiles
+    )
+}
@@ -0,0 +1,164 @@
+use url::{Origin, Url};
+
+/// Page the offline UI is displayed in.
+pub const BLANK_PAGE_URL: &str = "about:blank";
+
+/// Returns the URL prefixes of the pages the UI can be navigated to: the
+/// origins of `page_urls` (the directory of the page for local files) and
+/// `allowed_origins` (e.g., `https://myserver.com`).
+pub fn allowed_url_prefixes(page_urls: &[&str], allowed_origins: &[String]) -> Vec<String> {
+    let mut prefixes = vec![BLANK_PAGE_URL.to_string()];
+    let urls = page_urls
+        .iter()
+        .copied()
+        .chain(allowed_origins.iter().map(String::as_str));
+    for url in urls {
+        match url_prefix(url) {
+            Some(prefix) => prefixes.push(prefix),
+            None => log::warn!("Ignoring invalid origin '{}'", url),
+        }
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+/// Indicates whether `url` starts with one of `allowed_url_prefixes`.
+pub fn is_url_allowed(url: &str, allowed_url_prefixes: &[String]) -> bool {
+    allowed_url_prefixes
+        .iter()
+        .any(|prefix| url.starts_with(prefix.as_str()))
+}
+
+fn url_prefix(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if url.scheme() == "file" {
+        return url.join(".").ok().map(|url| url.to_string());
+    }
+    match url.origin() {
+        origin @ Origin::Tuple(..) => Some(format!("{}/", origin.ascii_serialization())),
+        Origin::Opaque(_) => None,
+    }
+}
+
+/// What to do with a navigation of the UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavigationDecision {
+    Allow,
+    OpenInBrowser,
+    Block,
+}
+
+/// Decides what to do with a navigation to `url`, in a new window if
+/// `new_window`.
+///
+/// The UI never opens new windows. Pages it can't be navigated to are opened
+/// in the default browser instead, but only for web links the user clicked
+/// (`user_initiated`). Other navigations (e.g., started by scripts or
+/// redirections) are blocked.
+pub fn decide_navigation(
+    url: &str,
+    new_window: bool,
+    user_initiated: bool,
+    allowed_url_prefixes: &[String],
+) -> NavigationDecision {
+    if !new_window && is_url_allowed(url, allowed_url_prefixes) {
+        return NavigationDecision::Allow;
+    }
+    let is_web_link = Url::parse(url)
+        .map(|url| matches!(url.scheme(), "http" | "https" | "mailto"))
+        .unwrap_or(false);
+    if user_initiated && is_web_link {
+        NavigationDecision::OpenInBrowser
+    } else {
+        NavigationDecision::Block
+    }
+}
+
+/// Opens `url` with the native URL handler (e.g., the default browser).
+pub fn open_in_browser(url: &str) {
+    match open::that(url) {
+        Ok(exit_status) => {
+            if !exit_status.success() {
+                if let Some(code) = exit_status.code() {
+                    log::error!("Command returned non-zero exit status {}!", code);
+                }
+            }
+        }
+        Err(why) => {
+            log::error!("Failed to open '{}': '{}'", url, why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_url_prefixes() {
+        let prefixes = allowed_url_prefixes(
+            &[
+                "https://MyServer.com/ui/index.html",
+                "file:///C:/Games/RO/ui/index.html",
+            ],
+            &[
+                "https://news.myserver.com".to_string(),
+                "https://myserver.com/".to_string(),
+                "not an origin".to_string(),
+            ],
+        );
+        assert_eq!(
+            prefixes,
+            vec![
+                "about:blank",
+                "file:///C:/Games/RO/ui/",
+                "https://myserver.com/",
+                "https://news.myserver.com/",
+            ]
+        );
+
+        assert!(is_url_allowed("https://myserver.com/news.html", &prefixes));
+        assert!(is_url_allowed("about:blank", &prefixes));
+        assert!(!is_url_allowed("https://myserver.com.evil.com/", &prefixes));
+        assert!(!is_url_allowed("file:///C:/Games/RO/index.html", &prefixes));
+    }
+
+    #[test]
+    fn test_decide_navigation() {
+        let prefixes = allowed_url_prefixes(&["https://myserver.com/index.html"], &[]);
+        let decide = |url, new_window, user_initiated| {
+            decide_navigation(url, new_window, user_initiated, &prefixes)
+        };
+        assert_eq!(
+            decide("https://myserver.com/news.html", false, false),
+            NavigationDecision::Allow
+        );
+        assert_eq!(
+            decide("https://myserver.com/news.html", true, true),
+            NavigationDecision::OpenInBrowser
+        );
+        assert_eq!(
+            decide("https://forum.com/", false, true),
+            NavigationDecision::OpenInBrowser
+        );
+        // Not clicked by the user
+        assert_eq!(
+            decide("https://forum.com/", false, false),
+            NavigationDecision::Block
+        );
+        assert_eq!(
+            decide("https://forum.com/", true, false),
+            NavigationDecision::Block
+        );
+        // Not a web link
+        assert_eq!(
+            decide("file:///C:/Windows/System32/cmd.exe", false, true),
+            NavigationDecision::Block
+        );
+        assert_eq!(
+            decide("javascript:alert(1)", true, true),
+            NavigationDecision::Block
+        );
+    }
+}
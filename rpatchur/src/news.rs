@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::{get_patcher_name, share_with_unelevated_user, NewsConfiguration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::runtime;
+
+/// News item, as passed to the UI.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct NewsItem {
+    pub title: String,
+    pub date: Option<String>,
+    pub url: Option<String>,
+    pub body: Option<String>,
+}
+
+/// Fetches and parses the configured news feed (RSS, Atom or JSON).
+///
+/// The last successfully fetched news are cached and returned when the feed
+/// can't be reached. This is blocking, it shouldn't be called from the UI
+/// thread.
+pub fn fetch_news(news_config: &NewsConfiguration) -> Result<Vec<NewsItem>> {
+    const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+    let cache_file_path = get_news_cache_file_path()?;
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let fetch_result = tokio_rt
+        .block_on(fetch_feed(&news_config.url, FETCH_TIMEOUT))
+        .and_then(|content| parse_feed(&content));
+    match fetch_result {
+        Ok(mut news) => {
+            if let Some(max_items) = news_config.max_items {
+                news.truncate(max_items);
+            }
+            let cache_file = File::create(&cache_file_path)?;
+            serde_json::to_writer(cache_file, &news).context("Failed to cache news")?;
+            share_with_unelevated_user(&cache_file_path);
+            Ok(news)
+        }
+        Err(err) => {
+            log::warn!("Failed to fetch news: {:#}", err);
+            let cache_file = File::open(&cache_file_path).context("No cached news available")?;
+            serde_json::from_reader(cache_file).context("Failed to deserialize cached news")
+        }
+    }
+}
+
+async fn fetch_feed(url: &str, timeout: Duration) -> Result<String> {
+    let resp = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await
+        .with_context(|| "Failed to GET URL")?
+        .error_for_status()?;
+    resp.text().await.with_context(|| "Invalid response body")
+}
+
+/// Parses a news feed, its format is detected from its content.
+fn parse_feed(content: &str) -> Result<Vec<NewsItem>> {
+    let content = content.trim_start();
+    if content.starts_with('{') || content.starts_with('[') {
+        parse_json_feed(content)
+    } else {
+        parse_xml_feed(content)
+    }
+}
+
+/// Parses either a JSON Feed document or an array of items with the fields of
+/// `NewsItem`.
+fn parse_json_feed(content: &str) -> Result<Vec<NewsItem>> {
+    let document: Value = serde_json::from_str(content).context("Invalid JSON feed")?;
+    let items = match &document {
+        Value::Array(items) => items,
+        Value::Object(feed) => feed
+            .get("items")
+            .and_then(Value::as_array)
+            .context("Missing 'items' in JSON feed")?,
+        _ => return Err(anyhow!("Invalid JSON feed")),
+    };
+    let get_string = |item: &Value, keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| item.get(key).and_then(Value::as_str))
+            .map(str::to_string)
+    };
+    Ok(items
+        .iter()
+        .filter_map(|item| {
+            Some(NewsItem {
+                title: get_string(item, &["title"])?,
+                date: get_string(item, &["date", "date_published"]),
+                url: get_string(item, &["url", "external_url"]),
+                body: get_string(item, &["body", "content_html", "content_text", "summary"]),
+            })
+        })
+        .collect())
+}
+
+/// Parses an RSS 2.0 or an Atom feed.
+fn parse_xml_feed(content: &str) -> Result<Vec<NewsItem>> {
+    let document = roxmltree::Document::parse(content).context("Invalid XML feed")?;
+    let child_text = |node: roxmltree::Node, names: &[&str]| {
+        node.children()
+            .find(|child| names.contains(&child.tag_name().name()))
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_string())
+    };
+    Ok(document
+        .descendants()
+        .filter(|node| matches!(node.tag_name().name(), "item" | "entry"))
+        .filter_map(|node| {
+            // Atom links are stored in attributes
+            let url = child_text(node, &["link"])
+                .filter(|s| !s.is_empty())
+                .or_else(|| {
+                    node.children()
+                        .find(|child| child.tag_name().name() == "link")
+                        .and_then(|link| link.attribute("href"))
+                        .map(str::to_string)
+                });
+            Some(NewsItem {
+                title: child_text(node, &["title"])?,
+                date: child_text(node, &["pubDate", "published", "updated"]),
+                url,
+                body: child_text(node, &["description", "content", "summary"]),
+            })
+        })
+        .collect())
+}
+
+pub fn get_news_cache_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("news"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_feed() {
+        let rss = r#"<?xml version="1.0"?>
+<rss version="2.0"><channel><title>News</title>
+  <item>
+    <title>Maintenance</title>
+    <link>https://myserver.com/news/1</link>
+    <pubDate>Mon, 06 Sep 2021 10:00:00 GMT</pubDate>
+    <description><![CDATA[<b>Servers</b> will be down]]></description>
+  </item>
+</channel></rss>"#;
+        let expected = vec![NewsItem {
+            title: "Maintenance".to_string(),
+            date: Some("Mon, 06 Sep 2021 10:00:00 GMT".to_string()),
+            url: Some("https://myserver.com/news/1".to_string()),
+            body: Some("<b>Servers</b> will be down".to_string()),
+        }];
+        assert_eq!(parse_feed(rss).unwrap(), expected);
+
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+  <entry>
+    <title>Maintenance</title>
+    <link href="https://myserver.com/news/1"/>
+    <updated>2021-09-06T10:00:00Z</updated>
+  </entry>
+</feed>"#;
+        let news = parse_feed(atom).unwrap();
+        assert_eq!(news[0].url.as_deref(), Some("https://myserver.com/news/1"));
+        assert_eq!(news[0].date.as_deref(), Some("2021-09-06T10:00:00Z"));
+
+        let json_feed = r#"{"version": "https://jsonfeed.org/version/1.1", "items": [
+            {"id": "1", "title": "Maintenance", "url": "https://myserver.com/news/1",
+             "date_published": "2021-09-06T10:00:00Z", "content_text": "Servers will be down"}
+        ]}"#;
+        let news = parse_feed(json_feed).unwrap();
+        assert_eq!(news[0].body.as_deref(), Some("Servers will be down"));
+
+        let json_array = r#"[{"title": "Maintenance", "body": "Servers will be down"}]"#;
+        let news = parse_feed(json_array).unwrap();
+        assert_eq!(news[0].title, "Maintenance");
+        assert_eq!(news[0].date, None);
+    }
+}
@@ -1,26 +1,267 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use anyhow::Result;
 
+/// Process started by `start_executable`.
+pub enum ChildProcess {
+    Std(std::process::Child),
+    #[cfg(windows)]
+    Elevated(windows::ProcessHandle), // Started through `ShellExecuteExW`
+    #[cfg(windows)]
+    Untracked, // Started, but its handle couldn't be retrieved
+}
+
+impl ChildProcess {
+    /// Waits for the process to exit and returns its exit code, if known.
+    pub fn wait(self) -> Result<Option<i32>> {
+        match self {
+            ChildProcess::Std(mut child) => Ok(child.wait()?.code()),
+            #[cfg(windows)]
+            ChildProcess::Elevated(process_handle) => process_handle.wait().map(Some),
+            #[cfg(windows)]
+            ChildProcess::Untracked => Ok(None),
+        }
+    }
+}
+
+/// Processes started by the patcher, along with the processes they started.
+///
+/// This is a job object on Windows and a set of process groups on other
+/// platforms, so that processes that aren't children of the patcher (e.g.,
+/// clients started by a launcher) can be waited for and terminated too.
+/// Processes aren't terminated when the patcher exits.
+pub struct ProcessGroup {
+    #[cfg(windows)]
+    job: windows::JobHandle,
+    #[cfg(not(windows))]
+    process_group_ids: std::sync::Mutex<Vec<libc::pid_t>>,
+}
+
+impl ProcessGroup {
+    /// This is the Windows version.
+    #[cfg(windows)]
+    pub fn new() -> Result<ProcessGroup> {
+        Ok(ProcessGroup {
+            job: windows::JobHandle::new()?,
+        })
+    }
+
+    /// This is the non-Windows version.
+    #[cfg(not(windows))]
+    pub fn new() -> Result<ProcessGroup> {
+        Ok(ProcessGroup {
+            process_group_ids: std::sync::Mutex::new(vec![]),
+        })
+    }
+
+    /// Returns `true` if processes of the group are still running.
+    ///
+    /// This is the Windows version.
+    #[cfg(windows)]
+    pub fn has_running_processes(&self) -> Result<bool> {
+        Ok(self.job.active_process_count()? > 0)
+    }
+
+    /// Returns `true` if processes of the group are still running.
+    ///
+    /// Process groups that are gone aren't tracked anymore, since their ID can
+    /// be reused. Processes that have exited but haven't been waited for yet
+    /// are considered running.
+    ///
+    /// This is the non-Windows version.
+    #[cfg(not(windows))]
+    pub fn has_running_processes(&self) -> Result<bool> {
+        let mut process_group_ids = self
+            .process_group_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Signal 0 only checks that the process group exists
+        process_group_ids.retain(|&pgid| unsafe { libc::killpg(pgid, 0) } == 0 || !is_esrch());
+        Ok(!process_group_ids.is_empty())
+    }
+
+    /// Forcefully terminates all the processes of the group.
+    ///
+    /// This is the Windows version.
+    #[cfg(windows)]
+    pub fn terminate(&self) -> Result<()> {
+        self.job.terminate()
+    }
+
+    /// Forcefully terminates all the processes of the group.
+    ///
+    /// This is the non-Windows version.
+    #[cfg(not(windows))]
+    pub fn terminate(&self) -> Result<()> {
+        let process_group_ids = self
+            .process_group_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for &pgid in process_group_ids.iter() {
+            // Process groups can be gone already
+            if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 && !is_esrch() {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until all the processes of the group have exited.
+    pub fn wait_until_empty(&self) -> Result<()> {
+        const POLL_INTERVAL: Duration = Duration::from_secs(1);
+        while self.has_running_processes()? {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// This is the Windows version.
+    #[cfg(windows)]
+    fn add(&self, process: &ChildProcess) -> Result<()> {
+        use std::os::windows::io::AsRawHandle;
+
+        match process {
+            ChildProcess::Std(child) => self.job.assign_process(child.as_raw_handle() as _),
+            ChildProcess::Elevated(process_handle) => {
+                self.job.assign_process(process_handle.raw_handle())
+            }
+            ChildProcess::Untracked => Ok(()),
+        }
+    }
+
+    /// This is the non-Windows version.
+    #[cfg(not(windows))]
+    fn add(&self, process: &ChildProcess) {
+        let ChildProcess::Std(child) = process;
+        // Processes are the leader of their own process group
+        self.process_group_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(child.id() as libc::pid_t);
+    }
+}
+
+/// Returns `true` if the last system call failed because the process (group)
+/// doesn't exist.
+#[cfg(not(windows))]
+fn is_esrch() -> bool {
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
+
 /// Starts an executable file in a cross-platform way.
 ///
+/// `working_directory` is relative to the patcher's working directory.
+/// Returns `None` if the executable hasn't been started (e.g., elevation has
+/// been refused). Started processes are added to `process_group`, if any.
+///
 /// This is the Windows version.
 #[cfg(windows)]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    exe_env: &HashMap<String, String>,
+    working_directory: Option<&str>,
+    process_group: Option<&ProcessGroup>,
+) -> Result<Option<ChildProcess>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut process = spawn_executable(
+        exe_path,
+        exe_arguments,
+        exe_env,
+        working_directory,
+        process_group.is_some(),
+    )?;
+    if let (Some(process_group), Some(process)) = (process_group, &mut process) {
+        // Processes can still be waited for
+        if let Err(e) = process_group.add(process) {
+            log::warn!("Failed to track '{}': {:#}", exe_path, e);
+        }
+        if let ChildProcess::Std(child) = process {
+            if let Err(e) = windows::resume_process(child.id()) {
+                let _ = child.kill();
+                return Err(e);
+            }
+        }
+    }
+    Ok(process)
+}
+
+/// Processes started with `std` are suspended if `suspended` is set, so that
+/// they can be added to a job object before starting other processes.
+/// Elevated processes can't be started suspended, the processes they start
+/// before being added to the job object aren't tracked.
+
+#[cfg(windows)]
+fn spawn_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    exe_env: &HashMap<String, String>,
+    working_directory: Option<&str>,
+    suspended: bool,
+) -> Result<Option<ChildProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
 {
+    use std::os::windows::process::CommandExt;
+    use std::process::Command;
+    use winapi::um::winbase::CREATE_SUSPENDED;
+    const ERROR_ELEVATION_REQUIRED: i32 = 740;
+
+    let exe_arguments: Vec<String> = exe_arguments
+        .into_iter()
+        .map(|e| e.as_ref().into())
+        .collect();
+    let working_directory = resolve_working_directory(working_directory)?;
+    // Elevated processes do not inherit their parent's environment, so only
+    // go through `runas` if there's no other choice
+    if !exe_env.is_empty() {
+        let mut command = Command::new(std::env::current_dir()?.join(exe_path));
+        command.args(&exe_arguments).envs(exe_env);
+        if let Some(working_directory) = &working_directory {
+            command.current_dir(working_directory);
+        }
+        if suspended {
+            command.creation_flags(CREATE_SUSPENDED);
+        }
+        match command.spawn() {
+            Ok(child) => return Ok(Some(ChildProcess::Std(child))),
+            Err(e) if e.raw_os_error() == Some(ERROR_ELEVATION_REQUIRED) => {
+                log::warn!(
+                    "'{}' requires elevation, environment variables will be ignored",
+                    exe_path
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
     // Fold parameter list into a String
     let exe_parameter = exe_arguments
-        .into_iter()
-        .fold(String::new(), |a: String, b| a + " " + b.as_ref() + "");
-    windows::win32_spawn_process_runas(exe_path, &exe_parameter)
+        .iter()
+        .fold(String::new(), |a: String, b| a + " " + b + "");
+    windows::win32_spawn_process_runas(exe_path, &exe_parameter, working_directory)
 }
 
 /// Starts an executable file in a cross-platform way.
 ///
+/// `working_directory` is relative to the patcher's working directory.
+/// Returns `None` if the executable hasn't been started. Started processes
+/// are added to `process_group`, if any.
+///
 /// This is the non-Windows version.
 #[cfg(not(windows))]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    exe_env: &HashMap<String, String>,
+    working_directory: Option<&str>,
+    process_group: Option<&ProcessGroup>,
+) -> Result<Option<ChildProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -31,10 +272,39 @@ where
         .into_iter()
         .map(|e| e.as_ref().into())
         .collect();
-    Command::new(exe_path)
-        .args(exe_arguments)
-        .spawn()
-        .map(|_| Ok(true))?
+    let mut command = match resolve_working_directory(working_directory)? {
+        None => Command::new(exe_path),
+        Some(working_directory) => {
+            // Relative paths would be resolved from the new working directory
+            let mut command = Command::new(std::env::current_dir()?.join(exe_path));
+            command.current_dir(working_directory);
+            command
+        }
+    };
+    if process_group.is_some() {
+        use std::os::unix::process::CommandExt;
+        // Start a new process group, which the process' children will inherit
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let process = ChildProcess::Std(command.args(exe_arguments).envs(exe_env).spawn()?);
+    if let Some(process_group) = process_group {
+        process_group.add(&process);
+    }
+    Ok(Some(process))
+}
+
+fn resolve_working_directory(working_directory: Option<&str>) -> Result<Option<PathBuf>> {
+    match working_directory {
+        None => Ok(None),
+        Some(working_directory) => Ok(Some(std::env::current_dir()?.join(working_directory))),
+    }
 }
 
 // Note: Taken from the rustup project
@@ -43,6 +313,157 @@ mod windows {
     use anyhow::{anyhow, Result};
     use std::ffi::OsStr;
     use std::os::windows::ffi::OsStrExt;
+    use winapi::um::winnt::HANDLE;
+
+    use super::ChildProcess;
+
+    /// Handle of a process started with `ShellExecuteExW`.
+    pub struct ProcessHandle(HANDLE);
+
+    // Process handles can be used from any thread
+    unsafe impl Send for ProcessHandle {}
+
+    impl ProcessHandle {
+        pub fn raw_handle(&self) -> HANDLE {
+            self.0
+        }
+
+        pub fn wait(self) -> Result<i32> {
+            use winapi::shared::minwindef::DWORD;
+            use winapi::um::processthreadsapi::GetExitCodeProcess;
+            use winapi::um::synchapi::WaitForSingleObject;
+            use winapi::um::winbase::{INFINITE, WAIT_FAILED};
+
+            let mut exit_code: DWORD = 0;
+            unsafe {
+                if WaitForSingleObject(self.0, INFINITE) == WAIT_FAILED {
+                    return Err(anyhow!("Failed to wait for process"));
+                }
+                if GetExitCodeProcess(self.0, &mut exit_code) == 0 {
+                    return Err(anyhow!("Failed to get the process' exit code"));
+                }
+            }
+            Ok(exit_code as i32)
+        }
+    }
+
+    impl Drop for ProcessHandle {
+        fn drop(&mut self) {
+            unsafe {
+                winapi::um::handleapi::CloseHandle(self.0);
+            }
+        }
+    }
+
+    /// Handle of a job object, processes are left running when it's closed.
+    pub struct JobHandle(HANDLE);
+
+    // Job objects can be used from any thread
+    unsafe impl Send for JobHandle {}
+    unsafe impl Sync for JobHandle {}
+
+    impl JobHandle {
+        pub fn new() -> Result<JobHandle> {
+            use std::ptr;
+            use winapi::um::winbase::CreateJobObjectW;
+
+            let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+            if job.is_null() {
+                return Err(anyhow!("Failed to create job object"));
+            }
+            Ok(JobHandle(job))
+        }
+
+        pub fn assign_process(&self, process: HANDLE) -> Result<()> {
+            use winapi::um::jobapi2::AssignProcessToJobObject;
+
+            if unsafe { AssignProcessToJobObject(self.0, process) } == 0 {
+                return Err(anyhow!("Failed to assign process to job object"));
+            }
+            Ok(())
+        }
+
+        pub fn active_process_count(&self) -> Result<u32> {
+            use std::ptr;
+            use winapi::shared::minwindef::DWORD;
+            use winapi::um::jobapi2::QueryInformationJobObject;
+            use winapi::um::winnt::{
+                JobObjectBasicAccountingInformation, JOBOBJECT_BASIC_ACCOUNTING_INFORMATION,
+            };
+
+            let mut accounting_info: JOBOBJECT_BASIC_ACCOUNTING_INFORMATION =
+                unsafe { std::mem::zeroed() };
+            let result = unsafe {
+                QueryInformationJobObject(
+                    self.0,
+                    JobObjectBasicAccountingInformation,
+                    &mut accounting_info as *mut JOBOBJECT_BASIC_ACCOUNTING_INFORMATION as _,
+                    std::mem::size_of::<JOBOBJECT_BASIC_ACCOUNTING_INFORMATION>() as DWORD,
+                    ptr::null_mut(),
+                )
+            };
+            if result == 0 {
+                return Err(anyhow!("Failed to query the job object's processes"));
+            }
+            Ok(accounting_info.ActiveProcesses)
+        }
+
+        pub fn terminate(&self) -> Result<()> {
+            use winapi::um::jobapi2::TerminateJobObject;
+
+            if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+                return Err(anyhow!("Failed to terminate the job object's processes"));
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for JobHandle {
+        fn drop(&mut self) {
+            unsafe {
+                winapi::um::handleapi::CloseHandle(self.0);
+            }
+        }
+    }
+
+    /// Resumes the main thread of a process started with `CREATE_SUSPENDED`.
+    pub fn resume_process(process_id: u32) -> Result<()> {
+        use winapi::shared::minwindef::{DWORD, FALSE};
+        use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+        use winapi::um::processthreadsapi::{OpenThread, ResumeThread};
+        use winapi::um::tlhelp32::{
+            CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+        };
+        use winapi::um::winnt::THREAD_SUSPEND_RESUME;
+
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) };
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Err(anyhow!("Failed to list the process' threads"));
+        }
+        let mut resumed = false;
+        let mut thread_entry: THREADENTRY32 = unsafe { std::mem::zeroed() };
+        thread_entry.dwSize = std::mem::size_of::<THREADENTRY32>() as DWORD;
+        let mut has_entry = unsafe { Thread32First(snapshot, &mut thread_entry) } != 0;
+        while has_entry {
+            // Suspended processes only have their main thread
+            if thread_entry.th32OwnerProcessID == process_id {
+                unsafe {
+                    let thread =
+                        OpenThread(THREAD_SUSPEND_RESUME, FALSE, thread_entry.th32ThreadID);
+                    if !thread.is_null() {
+                        resumed = ResumeThread(thread) != DWORD::MAX;
+                        CloseHandle(thread);
+                    }
+                }
+            }
+            has_entry = unsafe { Thread32Next(snapshot, &mut thread_entry) } != 0;
+        }
+        unsafe { CloseHandle(snapshot) };
+        if !resumed {
+            return Err(anyhow!("Failed to resume the process"));
+        }
+        Ok(())
+    }
 
     fn to_u16s<S: AsRef<OsStr>>(s: S) -> Result<Vec<u16>> {
         fn inner(s: &OsStr) -> Result<Vec<u16>> {
@@ -58,7 +479,11 @@ mod windows {
 
     /// This function is required to start processes that require elevation, from
     /// a non-elevated process.
-    pub fn win32_spawn_process_runas<S>(path: S, parameter: S) -> Result<bool>
+    pub fn win32_spawn_process_runas<S>(
+        path: S,
+        parameter: S,
+        directory: Option<std::path::PathBuf>,
+    ) -> Result<Option<ChildProcess>>
     where
         S: AsRef<OsStr>,
     {
@@ -70,6 +495,7 @@ mod windows {
             pub fn ShellExecuteExW(pExecInfo: *mut SHELLEXECUTEINFOW) -> BOOL;
         }
         const SEE_MASK_CLASSNAME: ULONG = 1;
+        const SEE_MASK_NOCLOSEPROCESS: ULONG = 0x40;
         const SW_SHOW: c_int = 5;
 
         // Note: It seems `path` has to be absolute for the class overwrite to work
@@ -78,14 +504,15 @@ mod windows {
         let parameter = to_u16s(parameter)?;
         let operation = to_u16s("runas")?;
         let class = to_u16s("exefile")?;
+        let directory = directory.map(to_u16s).transpose()?;
         let mut execute_info = SHELLEXECUTEINFOW {
             cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
-            fMask: SEE_MASK_CLASSNAME,
+            fMask: SEE_MASK_CLASSNAME | SEE_MASK_NOCLOSEPROCESS,
             hwnd: ptr::null_mut(),
             lpVerb: operation.as_ptr(),
             lpFile: exe_path.as_ptr(),
             lpParameters: parameter.as_ptr(),
-            lpDirectory: ptr::null_mut(),
+            lpDirectory: directory.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
             nShow: SW_SHOW,
             hInstApp: ptr::null_mut(),
             lpIDList: ptr::null_mut(),
@@ -97,6 +524,42 @@ mod windows {
         };
 
         let result = unsafe { ShellExecuteExW(&mut execute_info) };
-        Ok(result != 0)
+        if result == 0 {
+            return Ok(None);
+        }
+        if execute_info.hProcess.is_null() {
+            return Ok(Some(ChildProcess::Untracked));
+        }
+        Ok(Some(ChildProcess::Elevated(ProcessHandle(
+            execute_info.hProcess,
+        ))))
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_group() {
+        let process_group = ProcessGroup::new().unwrap();
+        let client = start_executable(
+            "sh",
+            vec!["-c", "sleep 30 & wait"],
+            &HashMap::new(),
+            None,
+            Some(&process_group),
+        )
+        .unwrap()
+        .unwrap();
+        // Wait for the child process to start
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(process_group.has_running_processes().unwrap());
+        process_group.terminate().unwrap();
+        assert!(client.wait().unwrap().is_none());
+        process_group.wait_until_empty().unwrap();
+        assert!(!process_group.has_running_processes().unwrap());
+        // Process groups that are gone can still be terminated
+        process_group.terminate().unwrap();
     }
 }
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many times a function can be called within `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateLimit {
+    max_calls: usize,
+    period: Duration,
+}
+
+/// Limits how often the UI's functions can be called, so that a buggy UI
+/// can't flood the patching thread or start dozens of game clients.
+#[derive(Default)]
+pub struct RateLimiter {
+    recent_calls: HashMap<&'static str, VecDeque<Instant>>, // Indexed by `rate_limit` bucket
+}
+
+impl RateLimiter {
+    /// Records a call to `function_name` made at `now`. Returns false (and
+    /// doesn't record the call) if the function's limit has been reached.
+    pub fn try_call(&mut self, function_name: &str, now: Instant) -> bool {
+        if UNLIMITED_FUNCTIONS.contains(&function_name) {
+            return true;
+        }
+        let (bucket, limit) = rate_limit(function_name);
+        let recent_calls = self.recent_calls.entry(bucket).or_default();
+        while let Some(&call_time) = recent_calls.front() {
+            if now.saturating_duration_since(call_time) < limit.period {
+                break;
+            }
+            recent_calls.pop_front();
+        }
+        if recent_calls.len() >= limit.max_calls {
+            return false;
+        }
+        recent_calls.push_back(now);
+        true
+    }
+}
+
+/// Functions with a limit of their own: name, maximum number of calls and
+/// period (in seconds).
+const RATE_LIMITS: &[(&str, usize, u64)] = &[
+    // Start game clients
+    ("play", 1, 3),
+    ("login", 1, 3),
+    ("launch_client", 1, 3),
+    // Send commands to the patching thread or start long operations
+    ("start_update", 2, 1),
+    ("force_update", 2, 1),
+    ("cancel_update", 2, 1),
+    ("manual_patch", 2, 1),
+    ("reset_cache", 2, 1),
+    ("create_restore_point", 2, 1),
+    ("restore_from_point", 2, 1),
+    ("restore_backups", 2, 1),
+    ("defragment_grfs", 2, 1),
+    ("preview_patch", 2, 1),
+    ("export_diagnostics", 2, 1),
+    // Start other programs
+    ("open_url", 2, 1),
+    ("setup", 2, 1),
+];
+/// Answers to the patcher's own prompts, which the patching thread waits for:
+/// rejecting them would leave it waiting.
+const UNLIMITED_FUNCTIONS: &[&str] = &["patch_failed_reply", "close_processes_reply"];
+/// Limit shared by the other functions
+const DEFAULT_RATE_LIMIT: (&str, usize, u64) = ("default", 20, 1);
+
+/// Returns the bucket `function_name`'s calls are counted in, and the limit
+/// of that bucket.
+fn rate_limit(function_name: &str) -> (&'static str, RateLimit) {
+    let &(bucket, max_calls, period_secs) = RATE_LIMITS
+        .iter()
+        .find(|(name, _, _)| *name == function_name)
+        .unwrap_or(&DEFAULT_RATE_LIMIT);
+    (
+        bucket,
+        RateLimit {
+            max_calls,
+            period: Duration::from_secs(period_secs),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut rate_limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert!(rate_limiter.try_call("play", start));
+        assert!(!rate_limiter.try_call("play", start + Duration::from_secs(1)));
+        // Other functions have their own limit
+        assert!(rate_limiter.try_call("launch_client", start + Duration::from_secs(1)));
+        assert!(rate_limiter.try_call("play", start + Duration::from_secs(3)));
+
+        // Functions without a limit of their own share one
+        for i in 0..20 {
+            let function_name = if i % 2 == 0 { "get_news" } else { "unknown" };
+            assert!(rate_limiter.try_call(function_name, start));
+        }
+        assert!(!rate_limiter.try_call("get_server_status", start));
+        assert!(rate_limiter.try_call("get_server_status", start + Duration::from_secs(1)));
+
+        // Replies are never rejected, nor counted
+        for _ in 0..50 {
+            assert!(rate_limiter.try_call("close_processes_reply", start));
+        }
+        assert!(rate_limiter.try_call("get_news", start + Duration::from_secs(1)));
+    }
+}
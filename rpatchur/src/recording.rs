@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Event sent to the UI, as written by `--record-events`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub elapsed_ms: u64, // Since the recording started
+    pub event: Value,    // For readability, not used when replaying
+    pub js_code: String, // Code that dispatched the event
+}
+
+/// Writes the events sent to the UI to a file, as newline-delimited JSON
+/// objects, so that they can be replayed with `--replay-events`.
+pub struct EventRecorder {
+    file: File,
+    started_at: Instant,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<EventRecorder> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create '{}'", path.display()))?;
+        Ok(EventRecorder {
+            file,
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Appends an event to the recording. Events are written right away, so
+    /// that the recording is usable even if the patcher crashes.
+    pub fn record(&mut self, event: Value, js_code: &str) -> Result<()> {
+        let recorded_event = RecordedEvent {
+            elapsed_ms: self.started_at.elapsed().as_millis() as u64,
+            event,
+            js_code: js_code.to_string(),
+        };
+        let mut line = serde_json::to_vec(&recorded_event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Reads events written by an `EventRecorder`, in the order they were sent.
+pub fn load_recorded_events(path: &Path) -> Result<Vec<RecordedEvent>> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut events = vec![];
+    for (line_index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid event on line {}", line_index + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_record_and_load_events() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let recording_path = temp_dir.path().join("events.jsonl");
+        {
+            let mut recorder = EventRecorder::create(&recording_path).unwrap();
+            recorder
+                .record(
+                    json!({"type": "ready"}),
+                    "rpatchurEvent({\"type\":\"ready\"})",
+                )
+                .unwrap();
+            recorder
+                .record(
+                    json!({"type": "updates_available", "patch_count": 2}),
+                    "updatesAvailable(2)",
+                )
+                .unwrap();
+        }
+        let events = load_recorded_events(&recording_path).unwrap();
+        let js_code: Vec<&str> = events.iter().map(|e| e.js_code.as_str()).collect();
+        assert_eq!(
+            js_code,
+            vec!["rpatchurEvent({\"type\":\"ready\"})", "updatesAvailable(2)"]
+        );
+        assert!(events[0].elapsed_ms <= events[1].elapsed_ms);
+
+        std::fs::write(&recording_path, "{\"elapsed_ms\": 0}\n").unwrap();
+        assert!(load_recorded_events(&recording_path).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::ServiceInfo;
+use serde_json::{json, Value};
+use tokio::runtime::{self, Runtime};
+
+/// Probes the given services and returns their status as JSON objects of the
+/// form `{"name": ..., "up": ..., "latency_ms": ...}`.
+///
+/// HTTP services also return the JSON document they serve (if any) in
+/// `data`. Probes are blocking, this shouldn't be called from the UI thread.
+pub fn probe_services(services: &[ServiceInfo], timeout: Duration) -> Result<Vec<Value>> {
+    let tokio_rt = probe_runtime()?;
+    Ok(services
+        .iter()
+        .map(|service| {
+            let start = Instant::now();
+            let result = match (&service.tcp, &service.http) {
+                (Some(address), _) => probe_tcp_service(address, timeout).map(|_| None),
+                (None, Some(url)) => tokio_rt.block_on(probe_http_service(url, timeout)),
+                (None, None) => Err(anyhow!("No endpoint configured")),
+            };
+            let latency_ms = start.elapsed().as_millis() as u64;
+            match result {
+                Err(e) => {
+                    log::debug!("Service '{}' is down: {:#}", service.name, e);
+                    json!({ "name": service.name, "up": false, "latency_ms": null })
+                }
+                Ok(data) => json!({
+                    "name": service.name,
+                    "up": true,
+                    "latency_ms": latency_ms,
+                    "data": data,
+                }),
+            }
+        })
+        .collect())
+}
+
+/// Returns the runtime HTTP probes run on. It's shared by all the calls, UIs
+/// usually poll the status of the services.
+fn probe_runtime() -> Result<&'static Runtime> {
+    static PROBE_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(tokio_rt) = PROBE_RUNTIME.get() {
+        return Ok(tokio_rt);
+    }
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    // Another thread might have been faster, in which case ours is dropped
+    Ok(PROBE_RUNTIME.get_or_init(|| tokio_rt))
+}
+
+fn probe_tcp_service(address: &str, timeout: Duration) -> Result<()> {
+    let socket_address = address
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve '{}'", address))?
+        .next()
+        .with_context(|| format!("Failed to resolve '{}'", address))?;
+    TcpStream::connect_timeout(&socket_address, timeout)?;
+    Ok(())
+}
+
+async fn probe_http_service(url: &str, timeout: Duration) -> Result<Option<Value>> {
+    let resp = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    // The status document is optional
+    let body = resp.text().await?;
+    Ok(serde_json::from_str(&body).ok())
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::CONTENT_TYPE;
+use rpatchur_core::SessionTokenConfiguration;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::runtime;
+
+/// Arguments used when `play.session_token.arguments` isn't set.
+const DEFAULT_ARGUMENTS: &[&str] = &["-token:{token}", "{login}", "server"];
+
+#[derive(Deserialize)]
+struct SessionTokenResponse {
+    token: String,
+}
+
+/// Exchanges the player's credentials for a short-lived session token, so
+/// that the password doesn't appear in the game client's command line.
+///
+/// Credentials are POSTed to `play.session_token.endpoint` as a JSON object
+/// (`{"login": "...", "password": "..."}`), which responds with
+/// `{"token": "..."}`. This is blocking, it shouldn't be called from the UI
+/// thread.
+pub fn request_session_token(
+    session_config: &SessionTokenConfiguration,
+    login: &str,
+    password: &str,
+) -> Result<String> {
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    let resp_content = tokio_rt.block_on(async {
+        let resp = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?
+            .post(&session_config.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "login": login, "password": password }).to_string())
+            .send()
+            .await
+            .with_context(|| "Failed to reach the session endpoint")?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "Session endpoint refused the login: {}",
+                resp.status()
+            ));
+        }
+        resp.text().await.with_context(|| "Invalid response body")
+    })?;
+    parse_session_token_response(&resp_content)
+}
+
+/// Returns the game client's arguments for `login`, with `token` as the
+/// session token.
+pub fn session_arguments(
+    session_config: &SessionTokenConfiguration,
+    login: &str,
+    token: &str,
+) -> Vec<String> {
+    let substitute = |argument: &str| argument.replace("{login}", login).replace("{token}", token);
+    match &session_config.arguments {
+        Some(arguments) => arguments.iter().map(|x| substitute(x)).collect(),
+        None => DEFAULT_ARGUMENTS.iter().map(|x| substitute(x)).collect(),
+    }
+}
+
+fn parse_session_token_response(resp_content: &str) -> Result<String> {
+    let resp: SessionTokenResponse =
+        serde_json::from_str(resp_content).context("Invalid session endpoint response")?;
+    if resp.token.is_empty() {
+        return Err(anyhow!("Session endpoint returned an empty token"));
+    }
+    Ok(resp.token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_arguments() {
+        let mut session_config = SessionTokenConfiguration {
+            endpoint: "https://myserver.com/api/session".to_string(),
+            arguments: None,
+        };
+        assert_eq!(
+            session_arguments(&session_config, "player", "abc"),
+            vec!["-token:abc", "player", "server"]
+        );
+        session_config.arguments = Some(vec!["-t:{token}".to_string(), "{login}".to_string()]);
+        assert_eq!(
+            session_arguments(&session_config, "player", "abc"),
+            vec!["-t:abc", "player"]
+        );
+    }
+
+    #[test]
+    fn test_parse_session_token_response() {
+        assert_eq!(
+            parse_session_token_response(r#"{"token": "abc", "expires_in": 60}"#).unwrap(),
+            "abc"
+        );
+        assert!(parse_session_token_response(r#"{"token": ""}"#).is_err());
+        assert!(parse_session_token_response("Forbidden").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use rpatchur_core::{get_patcher_name, share_with_unelevated_user};
+use serde::{Deserialize, Serialize};
+
+use crate::bandwidth::BandwidthUsage;
+
+/// Held while the settings store is read, changed and written back, since
+/// both the UI and the patching threads change it.
+static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Choices made by the user through the UI, persisted across runs.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub active_client: Option<String>, // Name of the selected `play.clients` entry
+    #[serde(default)]
+    pub bandwidth: BandwidthUsage, // Bytes downloaded by the patcher
+    #[serde(default)]
+    pub install_directory: Option<PathBuf>, // Installation chosen on first run (see `install_detection`)
+    #[serde(default)]
+    pub monthly_bandwidth_cap_mb: Option<u64>, // Players are warned when it's about to be reached
+    #[serde(default)]
+    pub zoom_factor: Option<f64>, // Set with `set_zoom`, overrides `window.ui_scale`
+}
+
+/// Reads the settings store. Default settings are returned if it cannot be
+/// read.
+pub fn load_settings() -> Settings {
+    load_settings_in(Path::new(""))
+}
+
+/// Reads the settings store located in `directory`, instead of the current
+/// working directory.
+pub fn load_settings_in(directory: &Path) -> Settings {
+    let res = get_settings_file_path().and_then(|settings_file_path| {
+        let settings_file = File::open(directory.join(settings_file_path))?;
+        serde_json::from_reader(settings_file).context("Failed to deserialize settings")
+    });
+    match res {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::debug!("Using default settings: {:#}", e);
+            Settings::default()
+        }
+    }
+}
+
+pub fn save_settings(settings: &Settings) -> Result<()> {
+    save_settings_in(Path::new(""), settings)
+}
+
+/// Changes the settings store with `change`, without losing the changes made
+/// concurrently by other threads through this function.
+pub fn update_settings<T>(change: impl FnOnce(&mut Settings) -> T) -> Result<T> {
+    let _lock = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut settings = load_settings();
+    let result = change(&mut settings);
+    save_settings(&settings)?;
+    Ok(result)
+}
+
+/// Writes the settings store located in `directory`, instead of the current
+/// working directory.
+pub fn save_settings_in(directory: &Path, settings: &Settings) -> Result<()> {
+    let settings_file_path = directory.join(get_settings_file_path()?);
+    let settings_file = File::create(&settings_file_path)?;
+    serde_json::to_writer(settings_file, settings).context("Failed to serialize settings")?;
+    share_with_unelevated_user(settings_file_path);
+    Ok(())
+}
+
+/// Removes the settings store, so that default settings are used.
+pub fn reset_settings() -> Result<()> {
+    match fs::remove_file(get_settings_file_path()?) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn get_settings_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("settings"))
+}
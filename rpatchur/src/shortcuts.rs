@@ -0,0 +1,233 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use rpatchur_core::{
+    get_active_profile, get_patcher_name, PatcherConfiguration, ShortcutsConfiguration,
+};
+
+/// Creates the shortcuts described in the `shortcuts` section of the
+/// configuration, the first time the patcher is started.
+pub fn create_shortcuts_on_first_run(config: &PatcherConfiguration) -> Result<()> {
+    let shortcuts_config = match &config.shortcuts {
+        Some(v) if v.on_first_run.unwrap_or(false) => v,
+        _ => return Ok(()),
+    };
+    let marker_file_path = get_shortcuts_marker_file_path()?;
+    if marker_file_path.exists() {
+        return Ok(());
+    }
+    create_shortcuts(shortcuts_config, &config.window.title)?;
+    fs::write(marker_file_path, "").with_context(|| "Failed to write shortcuts marker file")
+}
+
+/// Creates desktop and Start Menu shortcuts to the patcher and associates
+/// `.thor` files with it, depending on the configuration.
+pub fn create_shortcuts(
+    shortcuts_config: &ShortcutsConfiguration,
+    default_name: &str,
+) -> Result<()> {
+    let name = shortcuts_config.name.as_deref().unwrap_or(default_name);
+    let exe_path = env::current_exe()?;
+    let working_dir = env::current_dir()?;
+    if shortcuts_config.desktop.unwrap_or(true) {
+        log::info!("Creating desktop shortcut");
+        create_shortcut(ShortcutLocation::Desktop, name, &exe_path, &working_dir)
+            .with_context(|| "Failed to create desktop shortcut")?;
+    }
+    if shortcuts_config.start_menu.unwrap_or(true) {
+        log::info!("Creating Start Menu shortcut");
+        create_shortcut(ShortcutLocation::StartMenu, name, &exe_path, &working_dir)
+            .with_context(|| "Failed to create Start Menu shortcut")?;
+    }
+    if shortcuts_config.associate_thor_files.unwrap_or(false) {
+        log::info!("Associating .thor files with the patcher");
+        associate_thor_files(&exe_path, &working_dir)
+            .with_context(|| "Failed to associate .thor files")?;
+    }
+    Ok(())
+}
+
+/// Returns the arguments that start the patcher with the active profile, if
+/// any (e.g., " --profile myserver").
+fn profile_arguments() -> String {
+    match get_active_profile() {
+        Some(profile_name) => format!(" --profile {}", profile_name),
+        None => String::new(),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ShortcutLocation {
+    Desktop,
+    StartMenu,
+}
+
+/// Creates a `.lnk` shortcut through the `WScript.Shell` COM object.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn create_shortcut(
+    location: ShortcutLocation,
+    name: &str,
+    exe_path: &std::path::Path,
+    working_dir: &std::path::Path,
+) -> Result<()> {
+    use std::os::windows::process::CommandExt;
+    use std::process::Command;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    // Strings are quoted with single quotes in the script
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+    let folder = match location {
+        ShortcutLocation::Desktop => "Desktop",
+        ShortcutLocation::StartMenu => "Programs",
+    };
+    let script = format!(
+        "$path = Join-Path ([Environment]::GetFolderPath('{}')) {}; \
+         $shortcut = (New-Object -ComObject WScript.Shell).CreateShortcut($path); \
+         $shortcut.TargetPath = {}; \
+         $shortcut.Arguments = {}; \
+         $shortcut.WorkingDirectory = {}; \
+         $shortcut.Save()",
+        folder,
+        quote(&format!("{}.lnk", name)),
+        quote(&exe_path.to_string_lossy()),
+        quote(profile_arguments().trim_start()),
+        quote(&working_dir.to_string_lossy()),
+    );
+    let status = Command::new("powershell")
+        .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("PowerShell exited with {}", status));
+    }
+    Ok(())
+}
+
+/// Creates a `.desktop` entry.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn create_shortcut(
+    location: ShortcutLocation,
+    name: &str,
+    exe_path: &std::path::Path,
+    working_dir: &std::path::Path,
+) -> Result<()> {
+    let home = PathBuf::from(env::var_os("HOME").context("HOME is not set")?);
+    let directory = match location {
+        ShortcutLocation::Desktop => env::var_os("XDG_DESKTOP_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join("Desktop")),
+        ShortcutLocation::StartMenu => home.join(".local/share/applications"),
+    };
+    fs::create_dir_all(&directory)?;
+    let desktop_entry = format!(
+        "[Desktop Entry]\nType=Application\nName={}\nExec=\"{}\"{}\nPath={}\nTerminal=false\n",
+        name,
+        exe_path.display(),
+        profile_arguments(),
+        working_dir.display()
+    );
+    fs::write(
+        directory.join(format!("{}.desktop", get_patcher_name()?.to_string_lossy())),
+        desktop_entry,
+    )?;
+    Ok(())
+}
+
+/// Associates `.thor` files with the patcher for the current user. Opening
+/// one applies it as a manual patch.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn associate_thor_files(exe_path: &std::path::Path, working_dir: &std::path::Path) -> Result<()> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+    const PROG_ID: &str = "rpatchur.thor";
+
+    let command = format!(
+        "\"{}\" --working-directory \"{}\" --patch \"%1\"",
+        exe_path.display(),
+        working_dir.display()
+    );
+    let classes = RegKey::predef(HKEY_CURRENT_USER).open_subkey("Software\\Classes")?;
+    let (extension_key, _) = classes.create_subkey(".thor")?;
+    extension_key.set_value("", &PROG_ID)?;
+    let (prog_id_key, _) = classes.create_subkey(PROG_ID)?;
+    prog_id_key.set_value("", &"THOR Patch File")?;
+    let (command_key, _) = prog_id_key.create_subkey("shell\\open\\command")?;
+    command_key.set_value("", &command)?;
+
+    // Let the shell know that associations have changed
+    unsafe {
+        use winapi::um::shlobj::{SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNF_IDLIST};
+        SHChangeNotify(
+            SHCNE_ASSOCCHANGED,
+            SHCNF_IDLIST,
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+    }
+    Ok(())
+}
+
+/// Associates `.thor` files with the patcher.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+fn associate_thor_files(_exe_path: &std::path::Path, _working_dir: &std::path::Path) -> Result<()> {
+    Err(anyhow!("File associations are only supported on Windows"))
+}
+
+/// Removes the association of `.thor` files with the patcher, if any.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn dissociate_thor_files() -> Result<()> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+    const PROG_ID: &str = "rpatchur.thor";
+
+    let classes = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey_with_flags("Software\\Classes", winreg::enums::KEY_ALL_ACCESS)?;
+    // `.thor` files might have been associated with another program since
+    let associated_prog_id: Option<String> = classes
+        .open_subkey(".thor")
+        .and_then(|extension_key| extension_key.get_value(""))
+        .ok();
+    if associated_prog_id.as_deref() == Some(PROG_ID) {
+        classes.delete_subkey_all(".thor")?;
+    }
+    match classes.delete_subkey_all(PROG_ID) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    // Let the shell know that associations have changed
+    unsafe {
+        use winapi::um::shlobj::{SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNF_IDLIST};
+        SHChangeNotify(
+            SHCNE_ASSOCCHANGED,
+            SHCNF_IDLIST,
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+    }
+    Ok(())
+}
+
+/// There are no file associations on other platforms.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn dissociate_thor_files() -> Result<()> {
+    Ok(())
+}
+
+pub fn get_shortcuts_marker_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("shortcuts"))
+}
@@ -0,0 +1,30 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context, Result};
+
+/// Plays a sound file (WAV or OGG) from a background thread.
+pub fn play_sound_file(sound_file_path: impl Into<PathBuf>) {
+    let sound_file_path = sound_file_path.into();
+    thread::spawn(move || {
+        if let Err(e) = play_sound_file_blocking(&sound_file_path) {
+            log::warn!(
+                "Failed to play sound '{}': {:#}",
+                sound_file_path.display(),
+                e
+            );
+        }
+    });
+}
+
+fn play_sound_file_blocking(sound_file_path: &Path) -> Result<()> {
+    let sound_file = File::open(sound_file_path)?;
+    let (_stream, stream_handle) =
+        rodio::OutputStream::try_default().context("No audio output device available")?;
+    let sink = rodio::Sink::try_new(&stream_handle)?;
+    sink.append(rodio::Decoder::new(BufReader::new(sound_file))?);
+    sink.sleep_until_end();
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rpatchur_core::PatchingStatus;
+
+const BAR_WIDTH: usize = 30;
+
+/// Renders the patching status as a progress bar, for interactive terminals.
+///
+/// The bar is redrawn in place on each update, other statuses are printed on
+/// their own line.
+#[derive(Default)]
+pub struct ProgressBars {
+    state: Mutex<ProgressBarsState>,
+}
+
+#[derive(Default)]
+struct ProgressBarsState {
+    stage: Option<(Stage, Instant)>, // Current stage and when it started
+    line_len: usize,                 // Length of the line being redrawn, if any
+    installed_patches: (usize, usize), // Installed patches, Total number
+    entries: Option<(usize, usize)>, // Processed entries of the current patch, Total number
+    overall: Option<(f32, Option<u64>)>, // Percentage of the whole update, Estimated seconds left
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Download,
+    Installation,
+}
+
+impl ProgressBars {
+    pub fn render(&self, status: &PatchingStatus) {
+        let mut state = match self.state.lock() {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let mut stdout = io::stdout();
+        let (stage, label, done, total, bytes_per_sec) = match status {
+            PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => (
+                Stage::Download,
+                "Downloading",
+                *nb_downloaded,
+                *nb_total,
+                Some(*bytes_per_sec),
+            ),
+            PatchingStatus::InstallationInProgress(nb_installed, nb_total) => {
+                state.installed_patches = (*nb_installed, *nb_total);
+                state.entries = None;
+                (
+                    Stage::Installation,
+                    "Installing",
+                    *nb_installed,
+                    *nb_total,
+                    None,
+                )
+            }
+            PatchingStatus::EntryInstallationInProgress(nb_processed, nb_total, _) => {
+                state.entries = Some((*nb_processed, *nb_total));
+                let (nb_installed, nb_total) = state.installed_patches;
+                (
+                    Stage::Installation,
+                    "Installing",
+                    nb_installed,
+                    nb_total,
+                    None,
+                )
+            }
+            PatchingStatus::OverallProgress(percent, eta_secs) => {
+                // Shown along with the next bar update
+                state.overall = Some((*percent, *eta_secs));
+                return;
+            }
+            _ => {
+                state.print_line(&describe_final_status(status));
+                return;
+            }
+        };
+        let started_at = match state.stage {
+            Some((current_stage, started_at)) if current_stage == stage => started_at,
+            _ => {
+                if state.line_len > 0 {
+                    let _ = writeln!(stdout);
+                    state.line_len = 0;
+                }
+                let now = Instant::now();
+                state.stage = Some((stage, now));
+                now
+            }
+        };
+        // The overall estimate accounts for both stages and for file sizes
+        let eta = match state.overall {
+            Some((_, Some(eta_secs))) => Some(Duration::from_secs(eta_secs)),
+            _ => estimate_remaining_time(started_at.elapsed(), done, total),
+        };
+        let mut line = format_progress_line(label, done, total, bytes_per_sec, eta);
+        if let Some((percent, _)) = state.overall {
+            line += &format!("  {:.0}% overall", percent);
+        }
+        if let (Stage::Installation, Some((nb_processed, nb_total))) = (stage, state.entries) {
+            line += &format!("  ({}/{} files)", nb_processed, nb_total);
+        }
+        // Pad the line to erase what's left of the previous one
+        let line_len = line.chars().count();
+        let padding = state.line_len.saturating_sub(line_len);
+        let _ = write!(stdout, "\r{}{}", line, " ".repeat(padding));
+        let _ = stdout.flush();
+        state.line_len = line_len;
+    }
+
+    /// Prints a message on its own line, below the current bar.
+    pub fn print_line(&self, line: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.print_line(line);
+        }
+    }
+}
+
+impl ProgressBarsState {
+    fn print_line(&mut self, line: &str) {
+        let mut stdout = io::stdout();
+        // End the bar's line first
+        if self.line_len > 0 {
+            let _ = writeln!(stdout);
+        }
+        let _ = writeln!(stdout, "{}", line);
+        self.line_len = 0;
+        self.stage = None;
+    }
+}
+
+fn describe_final_status(status: &PatchingStatus) -> String {
+    match status {
+        PatchingStatus::Ready => "Ready".to_string(),
+        PatchingStatus::Error(msg) => format!("Error: {}", msg),
+        PatchingStatus::ManualPatchApplied(name) => format!("Patch '{}' applied", name),
+        PatchingStatus::PatchesSkipped(names) => format!("Skipped patches: {}", names.join(", ")),
+        PatchingStatus::DownloadInProgress(..)
+        | PatchingStatus::InstallationInProgress(..)
+        | PatchingStatus::EntryInstallationInProgress(..)
+        | PatchingStatus::OverallProgress(..) => String::new(),
+    }
+}
+
+/// Extrapolates the remaining time from the time spent on the items that are
+/// done.
+fn estimate_remaining_time(elapsed: Duration, done: usize, total: usize) -> Option<Duration> {
+    if done == 0 || done > total {
+        return None;
+    }
+    Some(elapsed.mul_f64((total - done) as f64 / done as f64))
+}
+
+fn format_progress_line(
+    label: &str,
+    done: usize,
+    total: usize,
+    bytes_per_sec: Option<u64>,
+    eta: Option<Duration>,
+) -> String {
+    // Empty lists are complete right away
+    let filled = (BAR_WIDTH * done.min(total))
+        .checked_div(total)
+        .unwrap_or(BAR_WIDTH);
+    let mut line = format!(
+        "{:<11} [{}{}] {}/{}",
+        label,
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        done,
+        total
+    );
+    if let Some(bytes_per_sec) = bytes_per_sec {
+        line += &format!("  {}", format_speed(bytes_per_sec));
+    }
+    if let Some(eta) = eta {
+        let secs = eta.as_secs();
+        line += &format!("  ETA {:02}:{:02}", secs / 60, secs % 60);
+    }
+    line
+}
+
+fn format_speed(bytes_per_sec: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    if bytes_per_sec >= MIB {
+        format!("{:.1} MiB/s", bytes_per_sec as f64 / MIB as f64)
+    } else if bytes_per_sec >= KIB {
+        format!("{:.1} KiB/s", bytes_per_sec as f64 / KIB as f64)
+    } else {
+        format!("{} B/s", bytes_per_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress_line() {
+        assert_eq!(
+            format_progress_line(
+                "Downloading",
+                1,
+                3,
+                Some(1536 * 1024),
+                Some(Duration::from_secs(75))
+            ),
+            "Downloading [##########--------------------] 1/3  1.5 MiB/s  ETA 01:15"
+        );
+        assert_eq!(
+            format_progress_line("Installing", 0, 0, None, None),
+            "Installing  [##############################] 0/0"
+        );
+    }
+
+    #[test]
+    fn test_estimate_remaining_time() {
+        assert_eq!(estimate_remaining_time(Duration::from_secs(10), 0, 4), None);
+        assert_eq!(
+            estimate_remaining_time(Duration::from_secs(10), 1, 4),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            estimate_remaining_time(Duration::from_secs(10), 4, 4),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn test_format_speed() {
+        assert_eq!(format_speed(512), "512 B/s");
+        assert_eq!(format_speed(2048), "2.0 KiB/s");
+        assert_eq!(format_speed(3 * 1024 * 1024), "3.0 MiB/s");
+    }
+}
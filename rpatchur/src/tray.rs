@@ -0,0 +1,437 @@
+use anyhow::Result;
+
+/// Actions offered by the tray icon, while the patcher is minimized in
+/// `play.stay_open_while_running` mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayAction {
+    Restore,  // Show the patcher's window again
+    Relaunch, // Start another game client
+    Repair,   // Verify the game's files and install the damaged ones again
+}
+
+impl TrayAction {
+    const MENU_ACTIONS: [TrayAction; 3] = [
+        TrayAction::Restore,
+        TrayAction::Relaunch,
+        TrayAction::Repair,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            TrayAction::Restore => "Show the patcher",
+            TrayAction::Relaunch => "Play again",
+            TrayAction::Repair => "Repair the game",
+        }
+    }
+}
+
+/// Icon in the notification area. Clicking it restores the patcher, and its
+/// context menu offers the other `TrayAction`s. The icon is removed once
+/// dropped.
+pub struct TrayIcon {
+    // Only held to be dropped along with `TrayIcon`
+    #[cfg(windows)]
+    _inner: windows_tray::WindowsTrayIcon,
+    #[cfg(not(windows))]
+    _inner: gtk_tray::GtkTrayIcon,
+}
+
+impl TrayIcon {
+    /// Shows the icon, with `title` as its tooltip. `on_action` is called
+    /// with the actions picked by the user.
+    ///
+    /// On Windows, the icon runs its message loop on its own thread (from
+    /// which `on_action` is called). Otherwise, this must be called from the
+    /// GTK thread.
+    pub fn show<F>(title: &str, on_action: F) -> Result<TrayIcon>
+    where
+        F: Fn(TrayAction) + Send + 'static,
+    {
+        #[cfg(windows)]
+        let inner = windows_tray::WindowsTrayIcon::show(title, Box::new(on_action))?;
+        #[cfg(not(windows))]
+        let inner = gtk_tray::GtkTrayIcon::show(title, Box::new(on_action))?;
+        Ok(TrayIcon { _inner: inner })
+    }
+}
+
+#[cfg(windows)]
+mod windows_tray {
+    use std::cell::RefCell;
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::thread::JoinHandle;
+
+    use anyhow::{anyhow, Result};
+    use winapi::shared::minwindef::{LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::{HWND, POINT};
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::shellapi::{
+        Shell_NotifyIconW, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NOTIFYICONDATAW,
+    };
+    use winapi::um::winuser::{
+        AppendMenuW, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DestroyMenu, DestroyWindow,
+        DispatchMessageW, GetCursorPos, GetMessageW, LoadIconW, PostMessageW, PostQuitMessage,
+        RegisterClassW, SetForegroundWindow, TrackPopupMenu, TranslateMessage, HWND_MESSAGE,
+        IDI_APPLICATION, MAKEINTRESOURCEW, MF_STRING, MSG, TPM_RIGHTBUTTON, WM_APP, WM_COMMAND,
+        WM_CONTEXTMENU, WM_DESTROY, WM_LBUTTONUP, WM_NULL, WM_RBUTTONUP, WNDCLASSW,
+    };
+
+    use super::TrayAction;
+
+    // Sent by the shell when the icon is clicked
+    const WM_TRAY_ICON: UINT = WM_APP + 1;
+    // Sent by the owner of the icon to remove it
+    const WM_CLOSE_TRAY_ICON: UINT = WM_APP + 2;
+    const TRAY_ICON_ID: UINT = 1;
+    // Resource of the patcher's icon, as embedded by `winres`
+    const ICON_RESOURCE_ID: u16 = 1;
+
+    thread_local! {
+        // The window procedure runs on the icon's thread
+        static ON_ACTION: RefCell<Option<Box<dyn Fn(TrayAction) + Send>>> = RefCell::new(None);
+    }
+
+    pub struct WindowsTrayIcon {
+        // Handles are passed around as integers, since pointers aren't `Send`
+        window: usize,
+        ui_thread: Option<JoinHandle<()>>,
+    }
+
+    impl WindowsTrayIcon {
+        pub fn show(
+            title: &str,
+            on_action: Box<dyn Fn(TrayAction) + Send>,
+        ) -> Result<WindowsTrayIcon> {
+            let title: Vec<u16> = OsStr::new(title).encode_wide().collect();
+            let (window_tx, window_rx) = flume::bounded(1);
+            let ui_thread = std::thread::spawn(move || unsafe {
+                ON_ACTION.with(|callback| *callback.borrow_mut() = Some(on_action));
+                let instance = GetModuleHandleW(ptr::null());
+                let class_name = to_wide("RPatchurTrayIcon");
+                let mut window_class: WNDCLASSW = std::mem::zeroed();
+                window_class.lpfnWndProc = Some(window_procedure);
+                window_class.hInstance = instance;
+                window_class.lpszClassName = class_name.as_ptr();
+                RegisterClassW(&window_class);
+                // Message-only window, which receives the icon's notifications
+                let window = CreateWindowExW(
+                    0,
+                    class_name.as_ptr(),
+                    ptr::null(),
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    HWND_MESSAGE,
+                    ptr::null_mut(),
+                    instance,
+                    ptr::null_mut(),
+                );
+                if window.is_null() {
+                    let _ = window_tx.send(None);
+                    return;
+                }
+                let mut icon_data = notify_icon_data(window);
+                icon_data.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
+                icon_data.uCallbackMessage = WM_TRAY_ICON;
+                icon_data.hIcon = LoadIconW(instance, MAKEINTRESOURCEW(ICON_RESOURCE_ID));
+                if icon_data.hIcon.is_null() {
+                    icon_data.hIcon = LoadIconW(ptr::null_mut(), IDI_APPLICATION);
+                }
+                // The tooltip is truncated to fit, the last character is the
+                // terminating null
+                let tip_len = title.len().min(icon_data.szTip.len() - 1);
+                icon_data.szTip[..tip_len].copy_from_slice(&title[..tip_len]);
+                if Shell_NotifyIconW(NIM_ADD, &mut icon_data) == 0 {
+                    DestroyWindow(window);
+                    let _ = window_tx.send(None);
+                    return;
+                }
+                let _ = window_tx.send(Some(window as usize));
+
+                let mut msg: MSG = std::mem::zeroed();
+                while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                }
+            });
+            match window_rx.recv() {
+                Ok(Some(window)) => Ok(WindowsTrayIcon {
+                    window,
+                    ui_thread: Some(ui_thread),
+                }),
+                _ => {
+                    let _ = ui_thread.join();
+                    Err(anyhow!("Failed to add the tray icon"))
+                }
+            }
+        }
+    }
+
+    impl Drop for WindowsTrayIcon {
+        fn drop(&mut self) {
+            unsafe { PostMessageW(self.window as HWND, WM_CLOSE_TRAY_ICON, 0, 0) };
+            if let Some(ui_thread) = self.ui_thread.take() {
+                let _ = ui_thread.join();
+            }
+        }
+    }
+
+    fn notify_icon_data(window: HWND) -> NOTIFYICONDATAW {
+        let mut icon_data: NOTIFYICONDATAW = unsafe { std::mem::zeroed() };
+        icon_data.cbSize = std::mem::size_of::<NOTIFYICONDATAW>() as u32;
+        icon_data.hWnd = window;
+        icon_data.uID = TRAY_ICON_ID;
+        icon_data
+    }
+
+    fn run_action(action: TrayAction) {
+        ON_ACTION.with(|callback| {
+            if let Some(callback) = callback.borrow().as_ref() {
+                callback(action);
+            }
+        });
+    }
+
+    /// Shows the icon's context menu at the cursor's position.
+    unsafe fn show_context_menu(hwnd: HWND) {
+        let menu = CreatePopupMenu();
+        if menu.is_null() {
+            return;
+        }
+        for (i, action) in TrayAction::MENU_ACTIONS.iter().enumerate() {
+            AppendMenuW(menu, MF_STRING, 1 + i, to_wide(action.label()).as_ptr());
+        }
+        let mut cursor = POINT { x: 0, y: 0 };
+        GetCursorPos(&mut cursor);
+        // Otherwise the menu isn't closed when clicking elsewhere
+        SetForegroundWindow(hwnd);
+        TrackPopupMenu(
+            menu,
+            TPM_RIGHTBUTTON,
+            cursor.x,
+            cursor.y,
+            0,
+            hwnd,
+            ptr::null(),
+        );
+        PostMessageW(hwnd, WM_NULL, 0, 0);
+        DestroyMenu(menu);
+    }
+
+    unsafe extern "system" fn window_procedure(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_TRAY_ICON => {
+                match lparam as UINT {
+                    WM_LBUTTONUP => run_action(TrayAction::Restore),
+                    WM_RBUTTONUP | WM_CONTEXTMENU => show_context_menu(hwnd),
+                    _ => {}
+                }
+                0
+            }
+            WM_COMMAND => {
+                let item_id = LOWORD(wparam as u32) as usize;
+                if let Some(&action) = item_id
+                    .checked_sub(1)
+                    .and_then(|i| TrayAction::MENU_ACTIONS.get(i))
+                {
+                    run_action(action);
+                }
+                0
+            }
+            WM_CLOSE_TRAY_ICON => {
+                let mut icon_data = notify_icon_data(hwnd);
+                Shell_NotifyIconW(NIM_DELETE, &mut icon_data);
+                DestroyWindow(hwnd);
+                0
+            }
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                0
+            }
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+
+    fn to_wide(value: &str) -> Vec<u16> {
+        OsStr::new(value).encode_wide().chain(Some(0)).collect()
+    }
+}
+
+#[cfg(not(windows))]
+mod gtk_tray {
+    use std::ffi::CString;
+    use std::os::raw::{c_int, c_uint};
+    use std::ptr;
+
+    use anyhow::{anyhow, Result};
+    use glib_sys::gpointer;
+    use gobject_sys::{g_object_unref, g_signal_connect_data, GCallback, GObject};
+    use gtk_sys::{
+        gtk_menu_item_new_with_label, gtk_menu_new, gtk_menu_popup, gtk_menu_shell_append,
+        gtk_status_icon_new_from_icon_name, gtk_status_icon_position_menu,
+        gtk_status_icon_set_title, gtk_status_icon_set_tooltip_text, gtk_status_icon_set_visible,
+        gtk_widget_destroy, gtk_widget_show_all, GtkMenu, GtkMenuItem, GtkMenuPositionFunc,
+        GtkMenuShell, GtkStatusIcon, GtkWidget,
+    };
+
+    use super::TrayAction;
+
+    const ICON_NAME: &str = "applications-games";
+
+    type ActionCallback = Box<dyn Fn(TrayAction) + Send>;
+
+    /// What signal handlers are given, freed along with the icon.
+    struct HandlerData {
+        on_action: *const ActionCallback,
+        action: TrayAction,
+    }
+
+    pub struct GtkTrayIcon {
+        status_icon: *mut GtkStatusIcon,
+        menu: *mut GtkWidget,
+        _on_action: Box<ActionCallback>, // Referenced by `handler_data`
+        handler_data: Vec<*mut HandlerData>,
+    }
+
+    impl GtkTrayIcon {
+        pub fn show(title: &str, on_action: ActionCallback) -> Result<GtkTrayIcon> {
+            let title = CString::new(title)?;
+            let icon_name = CString::new(ICON_NAME)?;
+            let on_action = Box::new(on_action);
+            let mut handler_data = vec![];
+            let mut handler = |action| {
+                let data = Box::into_raw(Box::new(HandlerData {
+                    on_action: &*on_action,
+                    action,
+                }));
+                handler_data.push(data);
+                data as gpointer
+            };
+            unsafe {
+                let status_icon = gtk_status_icon_new_from_icon_name(icon_name.as_ptr());
+                if status_icon.is_null() {
+                    return Err(anyhow!("Failed to add the tray icon"));
+                }
+                gtk_status_icon_set_title(status_icon, title.as_ptr());
+                gtk_status_icon_set_tooltip_text(status_icon, title.as_ptr());
+                let menu = gtk_menu_new();
+                for &action in TrayAction::MENU_ACTIONS.iter() {
+                    let label = CString::new(action.label())?;
+                    let item = gtk_menu_item_new_with_label(label.as_ptr());
+                    gtk_menu_shell_append(menu as *mut GtkMenuShell, item as *mut GtkMenuItem);
+                    connect(
+                        item as *mut GObject,
+                        "activate",
+                        std::mem::transmute::<
+                            unsafe extern "C" fn(*mut GtkMenuItem, gpointer),
+                            unsafe extern "C" fn(),
+                        >(on_menu_item_activated),
+                        handler(action),
+                    );
+                }
+                gtk_widget_show_all(menu);
+                // Left clicks restore the patcher, right clicks show the menu
+                connect(
+                    status_icon as *mut GObject,
+                    "activate",
+                    std::mem::transmute::<
+                        unsafe extern "C" fn(*mut GtkStatusIcon, gpointer),
+                        unsafe extern "C" fn(),
+                    >(on_icon_activated),
+                    handler(TrayAction::Restore),
+                );
+                connect(
+                    status_icon as *mut GObject,
+                    "popup-menu",
+                    std::mem::transmute::<
+                        unsafe extern "C" fn(*mut GtkStatusIcon, c_uint, u32, gpointer),
+                        unsafe extern "C" fn(),
+                    >(on_popup_menu),
+                    menu as gpointer,
+                );
+                gtk_status_icon_set_visible(status_icon, 1);
+                Ok(GtkTrayIcon {
+                    status_icon,
+                    menu,
+                    _on_action: on_action,
+                    handler_data,
+                })
+            }
+        }
+    }
+
+    impl Drop for GtkTrayIcon {
+        fn drop(&mut self) {
+            // Signals aren't emitted anymore once the widgets are gone, the
+            // handlers' data can then be freed
+            unsafe {
+                gtk_status_icon_set_visible(self.status_icon, 0);
+                g_object_unref(self.status_icon as *mut GObject);
+                gtk_widget_destroy(self.menu);
+                for data in self.handler_data.drain(..) {
+                    drop(Box::from_raw(data));
+                }
+            }
+        }
+    }
+
+    unsafe fn connect(
+        instance: *mut GObject,
+        signal: &str,
+        handler: unsafe extern "C" fn(),
+        data: gpointer,
+    ) {
+        let signal = CString::new(signal).unwrap();
+        let handler: GCallback = Some(handler);
+        g_signal_connect_data(instance, signal.as_ptr(), handler, data, None, 0);
+    }
+
+    unsafe fn run_action(data: gpointer) {
+        let data = &*(data as *const HandlerData);
+        (*data.on_action)(data.action);
+    }
+
+    unsafe extern "C" fn on_menu_item_activated(_item: *mut GtkMenuItem, data: gpointer) {
+        run_action(data);
+    }
+
+    unsafe extern "C" fn on_icon_activated(_status_icon: *mut GtkStatusIcon, data: gpointer) {
+        run_action(data);
+    }
+
+    unsafe extern "C" fn on_popup_menu(
+        status_icon: *mut GtkStatusIcon,
+        button: c_uint,
+        activate_time: u32,
+        menu: gpointer,
+    ) {
+        let position_menu: GtkMenuPositionFunc = Some(std::mem::transmute::<
+            unsafe extern "C" fn(
+                *mut GtkMenu,
+                *mut c_int,
+                *mut c_int,
+                *mut i32,
+                *mut GtkStatusIcon,
+            ),
+            unsafe extern "C" fn(*mut GtkMenu, *mut c_int, *mut c_int, *mut i32, gpointer),
+        >(gtk_status_icon_position_menu));
+        gtk_menu_popup(
+            menu as *mut GtkMenu,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            position_menu,
+            status_icon as gpointer,
+            button,
+            activate_time,
+        );
+    }
+}
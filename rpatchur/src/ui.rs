@@ -1,173 +1,801 @@
-use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::patcher::{get_patcher_name, PatcherCommand, PatcherConfiguration};
-use crate::process::start_executable;
-use serde::Deserialize;
-use serde_json::Value;
+use crate::bandwidth::{get_bandwidth_stats, record_downloaded_bytes, set_monthly_bandwidth_cap};
+use crate::browser::set_context_menu_enabled;
+use crate::clients::{active_play_target, has_client, list_clients};
+use crate::control::SharedStatusSnapshot;
+use crate::deep_link::{is_patch_url_allowed, DeepLink};
+use crate::defender::add_defender_exclusion;
+use crate::diagnostics::export_diagnostics;
+use crate::events::{format_js_call, response_js_code, UiEvent};
+use crate::navigation::{is_url_allowed, open_in_browser};
+use crate::news::fetch_news;
+use crate::process::{start_executable, ChildProcess, ProcessGroup};
+use crate::rate_limit::RateLimiter;
+use crate::recording::{EventRecorder, RecordedEvent};
+use crate::server_status::probe_services;
+use crate::session::{request_session_token, session_arguments};
+use crate::settings::{load_settings, reset_settings, update_settings};
+use crate::shortcuts::create_shortcuts;
+use crate::sound::play_sound_file;
+use crate::terminal::ProgressBars;
+use crate::tray::{TrayAction, TrayIcon};
+use crate::uninstall::uninstall_patcher_data;
+use crate::version::version_info;
+use crate::watchdog::wait_for_exit;
+use crate::window::{
+    set_always_on_top, set_click_through_regions, set_window_opacity, WindowRegion,
+};
+use rpatchur_core::{
+    dispatch_plugin_event, is_elevated, reset_patcher_cache, CrashWatchdogConfiguration,
+    PatchFailureAction, PatcherCommand, PatcherConfiguration, PatchingStatus, PatchingSummary,
+    PluginEvent, ProgressSink, SessionTokenConfiguration, SoundsConfiguration, UpdateError,
+    UpdateOutcome, WindowConfiguration,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tinyfiledialogs as tfd;
+use url::Url;
 use web_view::{Content, Handle, WebView};
 
 /// 'Opaque" struct that can be used to update the UI.
 pub struct UiController {
-    web_view_handle: Handle<WebViewUserData>,
+    backend: UiBackend,
+    status: SharedStatusSnapshot,
+    sounds: Option<SoundsConfiguration>,
+    close_clients: bool, // Close running game clients without asking, in headless mode
+}
+
+/// Indicates where updates of the UI are sent to.
+enum UiBackend {
+    WebView(Handle<WebViewUserData>),
+    Headless(HeadlessOutput),
+}
+
+/// Format of the patching status, in headless mode.
+enum HeadlessOutput {
+    Json,                       // Newline-delimited JSON on stdout
+    ProgressBars(ProgressBars), // When stdout is a terminal
 }
+
 impl UiController {
     pub fn new(web_view: &WebView<'_, WebViewUserData>) -> UiController {
         UiController {
-            web_view_handle: web_view.handle(),
+            backend: UiBackend::WebView(web_view.handle()),
+            status: SharedStatusSnapshot::default(),
+            sounds: web_view.user_data().patcher_config.sounds.clone(),
+            close_clients: false,
         }
     }
 
-    /// Allows another thread to indicate the current status of the patching process.
+    /// Creates a controller that reports the patching status on stdout, as
+    /// newline-delimited JSON objects, or as progress bars if stdout is a
+    /// terminal.
     ///
-    /// This updates the UI with useful information.
-    pub fn dispatch_patching_status(&self, status: PatchingStatus) -> Result<(), web_view::Error> {
-        self.web_view_handle.dispatch(move |webview| {
-            let result = match status {
-                PatchingStatus::Ready => {
-                    let js_code = r#"
-                        const progressBar = document.getElementById('download-progress-bar');
-                        const progressText = document.getElementById('download-progress-text');
-                        const playButton = document.getElementById('button-play');
-                        
-                        progressBar.style.width = '100%';
-                        progressBar.setAttribute('aria-valuenow', '100');
-                        progressBar.classList.remove('bg-warning', 'bg-danger');
-                        progressBar.classList.add('bg-primary');
-                        progressText.textContent = 'Ready';
-                        playButton.disabled = false;
-                    "#;
-                    if let Err(e) = webview.eval(js_code) {
-                        log::warn!("Failed to set ready status: {}.", e);
+    /// Running game clients are closed before installing patches only if
+    /// `close_clients` is true, since there's nobody to ask.
+    pub fn headless(patcher_config: &PatcherConfiguration, close_clients: bool) -> UiController {
+        let output = if atty::is(atty::Stream::Stdout) {
+            HeadlessOutput::ProgressBars(ProgressBars::default())
+        } else {
+            HeadlessOutput::Json
+        };
+        UiController {
+            backend: UiBackend::Headless(output),
+            status: SharedStatusSnapshot::default(),
+            sounds: patcher_config.sounds.clone(),
+            close_clients,
+        }
+    }
+
+    /// Returns a handle to the last known status of the patcher, which is
+    /// kept up to date by this controller.
+    pub fn status_snapshot(&self) -> SharedStatusSnapshot {
+        self.status.clone()
+    }
+
+    /// Navigates to the given URL.
+    pub fn load_url(&self, url: &str) {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        let url = url.to_string();
+        if let Err(e) = web_view_handle.dispatch(move |webview| {
+            if let Err(e) = call_js_function(webview, "window.location.replace", &[json!(url)]) {
+                log::warn!("Failed to load URL: {}.", e);
+            }
+            Ok(())
+        }) {
+            log::warn!("Failed to load URL: {}.", e);
+        }
+    }
+
+    /// Sends recorded events to the UI in a separate thread, with their
+    /// original timing.
+    pub fn spawn_event_replay(&self, events: Vec<RecordedEvent>) {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle.clone(),
+            UiBackend::Headless(_) => return,
+        };
+        std::thread::spawn(move || {
+            let started_at = Instant::now();
+            for recorded_event in events {
+                let due_time = Duration::from_millis(recorded_event.elapsed_ms);
+                if let Some(delay) = due_time.checked_sub(started_at.elapsed()) {
+                    std::thread::sleep(delay);
+                }
+                let res = web_view_handle.dispatch(move |webview| {
+                    if let Err(e) = eval_js(webview, recorded_event.event, &recorded_event.js_code)
+                    {
+                        log::warn!("Failed to replay event: {}.", e);
                     }
                     Ok(())
-                },
-                PatchingStatus::Error(msg) => {
-                    webview.eval(&format!("patchingStatusError(\"{}\")", msg))
-                }
-                PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => {
-                    webview.eval(&format!(
-                        "patchingStatusDownloading({}, {}, {})",
-                        nb_downloaded, nb_total, bytes_per_sec
-                    ))
-                }
-                PatchingStatus::InstallationInProgress(nb_installed, nb_total) => webview.eval(
-                    &format!("patchingStatusInstalling({}, {})", nb_installed, nb_total),
-                ),
-                PatchingStatus::ManualPatchApplied(name) => {
-                    webview.eval(&format!("patchingStatusPatchApplied(\"{}\")", name))
+                });
+                if res.is_err() {
+                    return;
                 }
-            };
-            if let Err(e) = result {
+            }
+            log::info!("All recorded events have been replayed");
+        });
+    }
+
+    /// Restores the patcher's window and brings it to the front.
+    pub fn focus_window(&self) {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        if let Err(e) = web_view_handle.dispatch(|webview| {
+            restore_window(webview);
+            Ok(())
+        }) {
+            log::warn!("Failed to focus window: {}.", e);
+        }
+    }
+
+    /// Applies a patch file opened by the user, unless patching is in
+    /// progress, in which case the UI is notified instead.
+    pub fn apply_patch_file(&self, patch_file_path: PathBuf) {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        if let Err(e) = web_view_handle.dispatch(move |webview| {
+            log::info!("Requesting patch '{}'", patch_file_path.display());
+            send_patcher_command_when_idle(webview, PatcherCommand::ApplyPatch(patch_file_path));
+            Ok(())
+        }) {
+            log::warn!("Failed to apply patch file: {}.", e);
+        }
+    }
+
+    /// Executes the action requested through a custom URL.
+    pub fn open_deep_link(&self, link: DeepLink) {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        if let Err(e) = web_view_handle.dispatch(move |webview| {
+            match link {
+                DeepLink::Play => handle_play(webview),
+                DeepLink::ApplyPatch(patch_url) => handle_remote_patch(webview, patch_url),
+            }
+            Ok(())
+        }) {
+            log::warn!("Failed to open link: {}.", e);
+        }
+    }
+}
+
+impl ProgressSink for UiController {
+    /// Allows another thread to indicate the current status of the patching process.
+    ///
+    /// This updates the UI with useful information.
+    fn dispatch_patching_status(&self, status: PatchingStatus) -> anyhow::Result<()> {
+        if let Ok(mut snapshot) = self.status.lock() {
+            snapshot.last_status = Some(status.to_json());
+        }
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(HeadlessOutput::Json) => {
+                println!("{}", status.to_json());
+                return Ok(());
+            }
+            UiBackend::Headless(HeadlessOutput::ProgressBars(progress_bars)) => {
+                progress_bars.render(&status);
+                return Ok(());
+            }
+        };
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, UiEvent::from(&status)) {
                 log::warn!("Failed to dispatch patching status: {}.", e);
             }
             Ok(())
-        })
+        })?)
+    }
+
+    /// Asks the user whether the given running processes can be closed. The
+    /// answer is sent back through a `CloseProcessesReply` command.
+    ///
+    /// Returns false if the user cannot be asked (i.e., in headless mode).
+    fn prompt_close_processes(&self, process_names: &[String]) -> bool {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return false,
+        };
+        let event = UiEvent::CloseProcesses {
+            process_names: process_names.to_vec(),
+        };
+        let res = web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, event) {
+                log::warn!("Failed to dispatch close processes prompt: {}.", e);
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch close processes prompt: {}.", e);
+            return false;
+        }
+        true
+    }
+
+    /// Indicates whether running game clients can be closed in headless
+    /// mode, as requested with `--close-clients`.
+    fn confirm_close_processes(&self, _process_names: &[String]) -> bool {
+        self.close_clients
+    }
+
+    /// Lets the UI know how many patches are available, after a background
+    /// check.
+    fn dispatch_updates_available(&self, patch_count: usize) -> anyhow::Result<()> {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return Ok(()),
+        };
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, UiEvent::UpdatesAvailable { patch_count }) {
+                log::warn!("Failed to dispatch available updates: {}.", e);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Lets the UI know that patching has made no progress for a while.
+    ///
+    /// In headless mode, this is only printed as JSON.
+    fn dispatch_patching_stalled(&self, stage: &str, seconds: u64) -> anyhow::Result<()> {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(HeadlessOutput::Json) => {
+                println!(
+                    "{}",
+                    json!({ "status": "stalled", "stage": stage, "seconds": seconds })
+                );
+                return Ok(());
+            }
+            UiBackend::Headless(HeadlessOutput::ProgressBars(_)) => return Ok(()),
+        };
+        let stage = stage.to_string();
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, UiEvent::PatchingStalled { stage, seconds }) {
+                log::warn!("Failed to dispatch stalled status: {}.", e);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Suggests that the player checks their antivirus, which kept a GRF
+    /// locked.
+    fn dispatch_antivirus_interference_suspected(&self) -> anyhow::Result<()> {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(HeadlessOutput::Json) => {
+                println!(
+                    "{}",
+                    json!({ "status": "antivirus_interference_suspected" })
+                );
+                return Ok(());
+            }
+            UiBackend::Headless(HeadlessOutput::ProgressBars(_)) => return Ok(()),
+        };
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, UiEvent::AntivirusInterferenceSuspected) {
+                log::warn!("Failed to dispatch antivirus hint: {}.", e);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Gives the statistics of the update that just ended to the UI.
+    ///
+    /// In headless mode, the summary is printed alongside the patching status.
+    fn dispatch_patching_summary(&self, summary: &PatchingSummary) -> anyhow::Result<()> {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(HeadlessOutput::Json) => {
+                println!("{}", summary.to_json());
+                return Ok(());
+            }
+            UiBackend::Headless(HeadlessOutput::ProgressBars(progress_bars)) => {
+                progress_bars.print_line(&summary.describe());
+                return Ok(());
+            }
+        };
+        let event = UiEvent::PatchingSummary {
+            summary: summary.clone(),
+        };
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, event) {
+                log::warn!("Failed to dispatch patching summary: {}.", e);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Adds the downloaded bytes to the bandwidth usage, and warns the UI if
+    /// the player's monthly cap is about to be reached.
+    fn record_downloaded_bytes(&self, byte_count: u64) {
+        let cap_approached = match record_downloaded_bytes(byte_count) {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Failed to record bandwidth usage: {:#}", e);
+                false
+            }
+        };
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        let res = web_view_handle.dispatch(move |webview| {
+            webview.user_data_mut().session_downloaded_bytes += byte_count;
+            if cap_approached {
+                let stats = get_bandwidth_stats(webview.user_data().session_downloaded_bytes);
+                if let Err(e) = emit_event(webview, UiEvent::BandwidthCapApproached { stats }) {
+                    log::warn!("Failed to dispatch bandwidth warning: {}.", e);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            log::warn!("Failed to record bandwidth usage: {}.", e);
+        }
+    }
+
+    /// Lets the UI know that the update will only start at the end of quiet
+    /// hours.
+    fn dispatch_update_deferred(&self, resume_time: &str) -> anyhow::Result<()> {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return Ok(()),
+        };
+        let event = UiEvent::UpdateDeferred {
+            resume_time: resume_time.to_string(),
+        };
+        Ok(web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, event) {
+                log::warn!("Failed to dispatch update deferral: {}.", e);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Lets the user decide what to do with a patch that failed to apply. The
+    /// answer is sent back through a `PatchFailureReply` command.
+    ///
+    /// Returns false if the user cannot be asked (i.e., in headless mode).
+    fn prompt_patch_failure(&self, patch_name: &str, error: &str) -> bool {
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return false,
+        };
+        let event = UiEvent::PatchFailed {
+            patch_name: patch_name.to_string(),
+            error: error.to_string(),
+        };
+        let res = web_view_handle.dispatch(move |webview| {
+            if let Err(e) = emit_event(webview, event) {
+                log::warn!("Failed to dispatch patch failure prompt: {}.", e);
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch patch failure prompt: {}.", e);
+            return false;
+        }
+        true
     }
 
-    pub fn set_patch_in_progress(&self, value: bool) {
-        if let Err(e) = self.web_view_handle.dispatch(move |webview| {
-            webview.user_data_mut().patching_in_progress = value;
+    fn set_patch_in_progress(&self, value: bool) {
+        if let Ok(mut snapshot) = self.status.lock() {
+            snapshot.patching_in_progress = value;
+        }
+        let web_view_handle = match &self.backend {
+            UiBackend::WebView(handle) => handle,
+            UiBackend::Headless(_) => return,
+        };
+        if let Err(e) = web_view_handle.dispatch(move |webview| {
+            let user_data = webview.user_data_mut();
+            user_data.patching_in_progress = value;
+            // Exit now that the patching thread has stopped
+            if !value && user_data.exit_requested {
+                webview.exit();
+            }
             Ok(())
         }) {
             log::warn!("Failed to dispatch patching status: {}.", e);
         }
     }
-}
 
-/// Used to indicate the current status of the patching process.
-pub enum PatchingStatus {
-    Ready,
-    Error(String),                         // Error message
-    DownloadInProgress(usize, usize, u64), // Downloaded files, Total number, Bytes per second
-    InstallationInProgress(usize, usize),  // Installed patches, Total number
-    ManualPatchApplied(String),            // Patch file name
+    /// Plays the sound associated with the result, if any.
+    fn patching_finished(&self, result: &Result<UpdateOutcome, UpdateError>) {
+        let sounds = match &self.sounds {
+            Some(v) => v,
+            None => return,
+        };
+        let sound_file_path = match result {
+            Ok(UpdateOutcome::UpToDate) | Err(UpdateError::Canceled) => return,
+            Ok(UpdateOutcome::Patched) => &sounds.on_complete,
+            Err(_) => &sounds.on_error,
+        };
+        if let Some(sound_file_path) = sound_file_path {
+            play_sound_file(sound_file_path);
+        }
+    }
 }
 
 pub struct WebViewUserData {
     patcher_config: PatcherConfiguration,
     patching_thread_tx: flume::Sender<PatcherCommand>,
     patching_in_progress: bool,
+    exit_requested: bool,                 // Exit once patching has been canceled
+    login_arguments: Option<Vec<String>>, // Credentials of the last login, for `play.require_login`
+    running_clients: usize, // Clients the patcher waits for, in `play.stay_open_while_running` mode
+    client_processes: Option<Arc<ProcessGroup>>, // Game clients and the processes they started
+    patcher_directory: PathBuf, // Directory the patcher has been started from
+    session_downloaded_bytes: u64,
+    trusted_url_prefixes: Vec<String>, // Pages that can call more than `HARMLESS_FUNCTIONS`
+    current_page_url: Arc<Mutex<String>>, // Of the main frame, as loaded by the web view
+    rate_limiter: RateLimiter,
+    current_request_id: Option<u64>, // Of the JSON request being handled, until it's answered
+    event_recorder: Option<EventRecorder>, // Set with `--record-events`
+    tray_icon: Option<TrayIcon>,     // While minimized in `play.stay_open_while_running` mode
 }
 impl WebViewUserData {
     pub fn new(
         patcher_config: PatcherConfiguration,
         patching_thread_tx: flume::Sender<PatcherCommand>,
+        patcher_directory: PathBuf,
+        trusted_url_prefixes: Vec<String>,
     ) -> WebViewUserData {
         WebViewUserData {
             patcher_config,
             patching_thread_tx,
             patching_in_progress: false,
+            exit_requested: false,
+            login_arguments: None,
+            running_clients: 0,
+            client_processes: match ProcessGroup::new() {
+                Ok(v) => Some(Arc::new(v)),
+                Err(e) => {
+                    log::warn!("Game clients won't be tracked: {:#}", e);
+                    None
+                }
+            },
+            patcher_directory,
+            session_downloaded_bytes: 0,
+            trusted_url_prefixes,
+            current_page_url: Arc::new(Mutex::new(String::new())), // Untrusted until loaded
+            rate_limiter: RateLimiter::default(),
+            current_request_id: None,
+            event_recorder: None,
+            tray_icon: None,
         }
     }
+
+    /// Returns the function to call with the URL of each page loaded in the
+    /// web view's main frame, which decides the functions the page can call.
+    pub fn page_load_listener(&self) -> impl Fn(&str) + 'static {
+        let current_page_url = self.current_page_url.clone();
+        move |url| {
+            log::debug!("Page loaded: '{}'", url);
+            *current_page_url.lock().unwrap() = url.to_string();
+        }
+    }
+
+    /// Records the events sent to the UI from now on.
+    pub fn set_event_recorder(&mut self, event_recorder: EventRecorder) {
+        self.event_recorder = Some(event_recorder);
+    }
 }
 impl Drop for WebViewUserData {
     fn drop(&mut self) {
-        // Ask the patching thread to stop whenever WebViewUserData is dropped
-        let _res = self.patching_thread_tx.try_send(PatcherCommand::Quit);
+        const QUIT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+        // Ask the patching thread to stop whenever WebViewUserData is dropped.
+        // The command mustn't be dropped if the channel happens to be full.
+        if let Err(e) = self
+            .patching_thread_tx
+            .send_timeout(PatcherCommand::Quit, QUIT_SEND_TIMEOUT)
+        {
+            log::warn!("Failed to ask the patching thread to stop: {}", e);
+        }
     }
 }
 
 /// Creates a `WebView` object with the appropriate settings for our needs.
+///
+/// The bundled offline page is displayed if there's no `page_url` to load.
 pub fn build_webview<'a>(
     title: &'a str,
     user_data: WebViewUserData,
+    page_url: Option<String>,
 ) -> web_view::WVResult<WebView<'a, WebViewUserData>> {
-    web_view::builder()
+    let content = match page_url {
+        Some(page_url) => Content::Url(page_url),
+        None => Content::Html(offline_page(&user_data.patcher_config)),
+    };
+    let enable_devtools = webview_devtools_policy(&user_data.patcher_config.window);
+    let mut webview = web_view::builder()
         .title(title)
-        .content(Content::Url(user_data.patcher_config.web.index_url.clone()))
+        .content(content)
         .size(
             user_data.patcher_config.window.width,
             user_data.patcher_config.window.height,
         )
         .resizable(user_data.patcher_config.window.resizable)
+        .debug(enable_devtools)
         .user_data(user_data)
         .invoke_handler(|webview, arg| {
+            if arg.trim_start().starts_with('{') {
+                handle_json_request(webview, arg);
+                return Ok(());
+            }
+            if !accept_request(webview, arg) {
+                return Ok(());
+            }
             match arg {
                 "play" => handle_play(webview),
                 "setup" => handle_setup(webview),
                 "exit" => handle_exit(webview),
+                "confirm_exit" => handle_confirm_exit(webview),
                 "start_update" => handle_start_update(webview),
+                "force_update" => handle_force_update(webview),
                 "cancel_update" => handle_cancel_update(webview),
-                "reset_cache" => handle_reset_cache(webview),
+                "reset_cache" => handle_reset_cache(webview, json!({})),
+                "create_restore_point" => handle_create_restore_point(webview),
+                "restore_from_point" => handle_restore_from_point(webview),
+                "restore_backups" => handle_restore_backups(webview),
+                "defragment_grfs" => handle_defragment_grfs(webview),
                 "manual_patch" => handle_manual_patch(webview),
-                request => handle_json_request(webview, request),
+                "create_shortcuts" => handle_create_shortcuts(webview),
+                "kill_clients" => handle_kill_clients(webview),
+                "exclude_from_defender" => handle_exclude_from_defender(webview),
+                "uninstall_patcher_data" => handle_uninstall_patcher_data(webview),
+                "export_diagnostics" => handle_export_diagnostics(webview),
+                function_name => {
+                    reject_request(webview, Some(function_name), "Unknown function".to_string())
+                }
             }
             Ok(())
         })
-        .build()
+        .build()?;
+    let enable_context_menu = webview
+        .user_data()
+        .patcher_config
+        .window
+        .enable_context_menu;
+    if let Err(e) = set_context_menu_enabled(&webview, enable_context_menu.unwrap_or(false)) {
+        log::warn!("Failed to configure the context menu: {:#}", e);
+    }
+    #[cfg(windows)]
+    {
+        subclass_window(&webview);
+        crate::dpi::scale_window_to_dpi(webview.window_handle() as _);
+        crate::dpi::fit_window_to_monitor(webview.window_handle() as _);
+    }
+    let zoom_factor =
+        load_settings()
+            .zoom_factor
+            .or(webview.user_data().patcher_config.window.ui_scale);
+    if let Some(zoom_factor) = zoom_factor {
+        set_zoom_factor(&mut webview, zoom_factor);
+    }
+    Ok(webview)
+}
+
+/// Indicates whether the webview's developer tools should be enabled.
+fn webview_devtools_policy(window_config: &WindowConfiguration) -> bool {
+    window_config
+        .enable_devtools
+        .unwrap_or(cfg!(debug_assertions))
+}
+
+/// Sets the webview's native zoom level, 1.0 being 100%.
+fn set_zoom_factor(webview: &mut WebView<WebViewUserData>, zoom_factor: f64) {
+    const MIN_ZOOM_FACTOR: f64 = 0.25;
+    const MAX_ZOOM_FACTOR: f64 = 5.0;
+    let zoom_factor = zoom_factor.clamp(MIN_ZOOM_FACTOR, MAX_ZOOM_FACTOR);
+    // MSHTML expects a percentage, WebKitGTK a factor
+    #[cfg(windows)]
+    webview.set_zoom_level(zoom_factor * 100.0);
+    #[cfg(not(windows))]
+    webview.set_zoom_level(zoom_factor);
+}
+
+/// Makes closing the window go through `handle_exit`, so that patching isn't
+/// interrupted without confirmation, and keeps the window on screen when it's
+/// moved to a monitor with another DPI or when monitors change.
+#[cfg(windows)]
+fn subclass_window(webview: &WebView<'_, WebViewUserData>) {
+    use crate::dpi::{fit_window_to_monitor, move_window, Rect};
+    use winapi::shared::basetsd::{DWORD_PTR, UINT_PTR};
+    use winapi::shared::minwindef::{LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::{HWND, RECT};
+    use winapi::um::commctrl::{DefSubclassProc, SetWindowSubclass};
+    use winapi::um::winuser::{WM_CLOSE, WM_DISPLAYCHANGE, WM_DPICHANGED};
+
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+        _id: UINT_PTR,
+        ref_data: DWORD_PTR,
+    ) -> LRESULT {
+        match msg {
+            WM_CLOSE => {
+                let web_view_handle = &*(ref_data as *const Handle<WebViewUserData>);
+                let _ = web_view_handle.dispatch(|webview| {
+                    handle_exit(webview);
+                    Ok(())
+                });
+                return 0;
+            }
+            // Windows suggests a size and position for the new DPI, the current
+            // size scaled by the ratio between the new and the previous DPI
+            WM_DPICHANGED => {
+                let suggested_rect = &*(lparam as *const RECT);
+                move_window(
+                    hwnd,
+                    Rect {
+                        left: suggested_rect.left,
+                        top: suggested_rect.top,
+                        right: suggested_rect.right,
+                        bottom: suggested_rect.bottom,
+                    },
+                );
+                fit_window_to_monitor(hwnd);
+                return 0;
+            }
+            WM_DISPLAYCHANGE => fit_window_to_monitor(hwnd),
+            _ => {}
+        }
+        DefSubclassProc(hwnd, msg, wparam, lparam)
+    }
+
+    // The handle is leaked on purpose, it must outlive the window
+    let web_view_handle = Box::into_raw(Box::new(webview.handle()));
+    let res = unsafe {
+        SetWindowSubclass(
+            webview.window_handle() as HWND,
+            Some(window_proc),
+            0,
+            web_view_handle as DWORD_PTR,
+        )
+    };
+    if res == 0 {
+        log::warn!("Failed to intercept window closing");
+    }
+}
+
+/// Generates the page displayed when the configured UI cannot be reached.
+///
+/// The Play button stays enabled if the game client is present.
+fn offline_page(patcher_config: &PatcherConfiguration) -> String {
+    const OFFLINE_PAGE_TEMPLATE: &str = include_str!("../resources/offline.html");
+    let play_button_state = if Path::new(&patcher_config.play.path).exists() {
+        ""
+    } else {
+        "disabled"
+    };
+    OFFLINE_PAGE_TEMPLATE
+        .replace("PATCHER_TITLE", &html_escape(&patcher_config.window.title))
+        .replace("PLAY_BUTTON_STATE", play_button_state)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 /// Opens the configured game client with the configured arguments.
 ///
+/// With `play.require_login`, the credentials of the last login are passed to
+/// the client, and the UI's `loginRequired` function is called instead if no
+/// login has been performed yet.
+///
 /// This function can create elevated processes on Windows with UAC activated.
 fn handle_play(webview: &mut WebView<WebViewUserData>) {
-    let client_arguments = webview.user_data().patcher_config.play.arguments.clone();
-    start_game_client(webview, &client_arguments);
+    let target_arguments = active_play_target(&webview.user_data().patcher_config.play)
+        .arguments
+        .to_vec();
+    if let Some(client_arguments) = allowed_client_arguments(webview, target_arguments) {
+        start_game_client(webview, &client_arguments);
+    }
+}
+
+/// Returns the arguments to start the game client with: the credentials of
+/// the last login with `play.require_login`, followed by `target_arguments`.
+///
+/// Returns `None` and notifies the UI if the client cannot be started:
+/// while patching is in progress (the client would load GRFs that are being
+/// rewritten), or until a login has been performed with `play.require_login`.
+fn allowed_client_arguments(
+    webview: &mut WebView<WebViewUserData>,
+    target_arguments: Vec<String>,
+) -> Option<Vec<String>> {
+    if webview.user_data().patching_in_progress {
+        if let Err(e) = emit_event(webview, UiEvent::PatchingInProgress) {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return None;
+    }
+    let require_login = webview
+        .user_data()
+        .patcher_config
+        .play
+        .require_login
+        .unwrap_or(false);
+    let mut client_arguments = vec![];
+    if require_login {
+        match &webview.user_data().login_arguments {
+            Some(login_arguments) => client_arguments.extend(login_arguments.iter().cloned()),
+            None => {
+                log::info!("A login is required to start the client");
+                if let Err(e) = emit_event(webview, UiEvent::LoginRequired) {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return None;
+            }
+        }
+    }
+    client_arguments.extend(target_arguments);
+    Some(client_arguments)
 }
 
 /// Opens the configured 'Setup' software with the configured arguments.
 ///
 /// This function can create elevated processes on Windows with UAC activated.
 fn handle_setup(webview: &mut WebView<WebViewUserData>) {
-    let setup_exe: &String = &webview.user_data().patcher_config.setup.path;
-    let setup_arguments = &webview.user_data().patcher_config.setup.arguments;
+    let setup_config = &webview.user_data().patcher_config.setup;
+    let setup_exe: &String = &setup_config.path;
+    let setup_arguments = &setup_config.arguments;
+    let setup_env = setup_config.env.clone().unwrap_or_default();
+    let setup_working_directory = setup_config.working_directory.as_deref();
     let exit_on_success = webview
         .user_data()
         .patcher_config
         .setup
         .exit_on_success
         .unwrap_or(false);
-    match start_executable(setup_exe, setup_arguments) {
-        Ok(success) => {
-            if success {
-                log::trace!("Setup software started");
-                if exit_on_success {
-                    webview.exit();
-                }
+    match start_executable(
+        setup_exe,
+        setup_arguments,
+        &setup_env,
+        setup_working_directory,
+        None,
+    ) {
+        Ok(Some(_)) => {
+            log::trace!("Setup software started");
+            if exit_on_success {
+                webview.exit();
             }
         }
+        Ok(None) => {}
         Err(e) => {
             log::warn!("Failed to start setup software: {}", e);
         }
@@ -175,15 +803,53 @@ fn handle_setup(webview: &mut WebView<WebViewUserData>) {
 }
 
 /// Exits the patcher cleanly.
+///
+/// Exiting while patching has to be confirmed, either through a dialog or by
+/// the UI (with `confirmExitWhilePatching()`).
 fn handle_exit(webview: &mut WebView<WebViewUserData>) {
-    webview.exit();
+    if !webview.user_data().patching_in_progress {
+        webview.exit();
+        return;
+    }
+    let confirm_exit_in_ui = webview
+        .user_data()
+        .patcher_config
+        .window
+        .confirm_exit_in_ui
+        .unwrap_or(false);
+    if confirm_exit_in_ui {
+        if let Err(e) = emit_event(webview, UiEvent::ConfirmExitWhilePatching) {
+            log::warn!("Failed to dispatch exit confirmation: {}.", e);
+        }
+        return;
+    }
+    let answer = tfd::message_box_yes_no(
+        "Patching in progress",
+        "Patching is in progress. Do you want to cancel it and exit?",
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::No,
+    );
+    if answer == tfd::YesNo::Yes {
+        handle_confirm_exit(webview);
+    }
+}
+
+/// Cancels patching and exits once the patching thread has stopped (and
+/// cleaned up after itself).
+fn handle_confirm_exit(webview: &mut WebView<WebViewUserData>) {
+    if !webview.user_data().patching_in_progress {
+        webview.exit();
+        return;
+    }
+    webview.user_data_mut().exit_requested = true;
+    handle_cancel_update(webview);
 }
 
 /// Starts the patching task/thread.
 fn handle_start_update(webview: &mut WebView<WebViewUserData>) {
     // Patching is already in progress, abort.
     if webview.user_data().patching_in_progress {
-        let res = webview.eval("notificationInProgress()");
+        let res = emit_event(webview, UiEvent::PatchingInProgress);
         if let Err(e) = res {
             log::warn!("Failed to dispatch notification: {}.", e);
         }
@@ -199,6 +865,11 @@ fn handle_start_update(webview: &mut WebView<WebViewUserData>) {
     }
 }
 
+/// Starts the patching task/thread, even during quiet hours.
+fn handle_force_update(webview: &mut WebView<WebViewUserData>) {
+    send_patcher_command_when_idle(webview, PatcherCommand::ForceUpdate);
+}
+
 /// Cancels the patching task/thread.
 fn handle_cancel_update(webview: &mut WebView<WebViewUserData>) {
     if webview
@@ -211,14 +882,172 @@ fn handle_cancel_update(webview: &mut WebView<WebViewUserData>) {
     }
 }
 
-/// Resets the patcher cache (which is used to keep track of already applied
-/// patches).
-fn handle_reset_cache(_webview: &mut WebView<WebViewUserData>) {
-    if let Ok(patcher_name) = get_patcher_name() {
-        let cache_file_path = PathBuf::from(patcher_name).with_extension("dat");
-        if let Err(e) = fs::remove_file(cache_file_path) {
-            log::warn!("Failed to remove the cache file: {}", e);
+/// What `reset_cache` resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetScope {
+    Patches,  // The patcher cache (which is used to keep track of already applied patches)
+    Settings, // Choices made through the UI (e.g., the active client)
+    All,
+}
+
+/// Parameters expected for the reset_cache function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResetCacheParameters {
+    scope: Option<ResetScope>, // Defaults to `patches`
+}
+
+/// Resets the patcher cache and/or the settings store, and notifies the UI
+/// with `cacheResetDone(scope)`. Files that don't exist are considered reset.
+fn handle_reset_cache(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<ResetCacheParameters> = serde_json::from_value(parameters);
+    let scope = match result {
+        Err(e) => {
+            reject_request(
+                webview,
+                Some("reset_cache"),
+                format!("Invalid arguments: {}", e),
+            );
+            return;
+        }
+        Ok(params) => params.scope.unwrap_or(ResetScope::Patches),
+    };
+    if scope != ResetScope::Settings {
+        if let Err(e) = reset_patcher_cache(&webview.user_data().patcher_config) {
+            log::error!("Failed to remove the cache file: {:#}", e);
+            return;
+        }
+    }
+    if scope != ResetScope::Patches {
+        if let Err(e) = reset_settings() {
+            log::error!("Failed to remove the settings: {:#}", e);
+            return;
+        }
+    }
+    log::info!("Reset {:?}", scope);
+    if let Err(e) = emit_event(webview, UiEvent::CacheResetDone { scope }) {
+        log::warn!("Failed to dispatch cache reset: {}.", e);
+    }
+}
+
+/// Snapshots the game's GRFs, so that updates applied from now on can be
+/// undone.
+fn handle_create_restore_point(webview: &mut WebView<WebViewUserData>) {
+    send_patcher_command_when_idle(webview, PatcherCommand::CreateRestorePoint);
+}
+
+/// Undoes the updates applied since the restore point was created, after
+/// asking the user for confirmation.
+fn handle_restore_from_point(webview: &mut WebView<WebViewUserData>) {
+    let answer = tfd::message_box_yes_no(
+        "Restore game files",
+        "Game files will be restored to the state they were in when the restore point was created. Continue?",
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::No,
+    );
+    if answer == tfd::YesNo::Yes {
+        send_patcher_command_when_idle(webview, PatcherCommand::RestoreFromPoint);
+    }
+}
+
+/// Puts back the files that patches replaced on disk, after asking the user for
+/// confirmation.
+fn handle_restore_backups(webview: &mut WebView<WebViewUserData>) {
+    let answer = tfd::message_box_yes_no(
+        "Restore backups",
+        "Files replaced by patches (outside of GRFs) will be restored from their backups. Continue?",
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::No,
+    );
+    if answer == tfd::YesNo::Yes {
+        send_patcher_command_when_idle(webview, PatcherCommand::RestoreBackups);
+    }
+}
+
+/// Rebuilds the GRFs that removed entries left unused space in, after asking
+/// the user for confirmation.
+fn handle_defragment_grfs(webview: &mut WebView<WebViewUserData>) {
+    let answer = tfd::message_box_yes_no(
+        "Defragment GRFs",
+        "GRFs with unused space will be rebuilt, which can take a while and requires as much free disk space as they use. Continue?",
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::No,
+    );
+    if answer == tfd::YesNo::Yes {
+        send_patcher_command_when_idle(webview, PatcherCommand::DefragmentGrfs);
+    }
+}
+
+/// Adds the game's directory to Windows Defender's exclusions, after asking
+/// the user for confirmation. Real-time scanning can make patching multi-GB
+/// GRFs a lot slower.
+///
+/// This is only possible when `windows.defender_exclusion` is enabled and the
+/// patcher runs as administrator. Failures are reported to the UI.
+fn handle_exclude_from_defender(webview: &mut WebView<WebViewUserData>) {
+    const FUNCTION_NAME: &str = "exclude_from_defender";
+    let enabled = webview
+        .user_data()
+        .patcher_config
+        .windows
+        .as_ref()
+        .and_then(|windows| windows.defender_exclusion)
+        .unwrap_or(false);
+    if !enabled {
+        let error = "Excluding the game from Windows Defender is disabled".to_string();
+        reject_request(webview, Some(FUNCTION_NAME), error);
+        return;
+    }
+    if !is_elevated() {
+        let error = "Excluding the game from Windows Defender requires administrator privileges"
+            .to_string();
+        reject_request(webview, Some(FUNCTION_NAME), error);
+        return;
+    }
+    let game_directory = match std::env::current_dir() {
+        Ok(v) => v,
+        Err(e) => {
+            let error = format!("Failed to resolve the game's directory: {}", e);
+            reject_request(webview, Some(FUNCTION_NAME), error);
+            return;
+        }
+    };
+    let answer = tfd::message_box_yes_no(
+        "Exclude from Windows Defender",
+        format!(
+            "Windows Defender will stop scanning '{}', which makes updates faster. Continue?",
+            game_directory.display()
+        )
+        .as_str(),
+        tfd::MessageBoxIcon::Warning,
+        tfd::YesNo::No,
+    );
+    if answer != tfd::YesNo::Yes {
+        return;
+    }
+    match add_defender_exclusion(&game_directory) {
+        Ok(()) => log::info!(
+            "Excluded '{}' from Windows Defender",
+            game_directory.display()
+        ),
+        Err(e) => {
+            let error = format!("Failed to exclude the game from Windows Defender: {:#}", e);
+            reject_request(webview, Some(FUNCTION_NAME), error);
+        }
+    }
+}
+
+/// Sends a command to the patching thread, unless patching is in progress.
+fn send_patcher_command_when_idle(webview: &mut WebView<WebViewUserData>, command: PatcherCommand) {
+    if webview.user_data().patching_in_progress {
+        if let Err(e) = emit_event(webview, UiEvent::PatchingInProgress) {
+            log::warn!("Failed to dispatch notification: {}.", e);
         }
+        return;
+    }
+    if let Err(e) = webview.user_data().patching_thread_tx.send(command) {
+        log::warn!("Failed to send command to patching thread: {}.", e);
     }
 }
 
@@ -226,7 +1055,7 @@ fn handle_reset_cache(_webview: &mut WebView<WebViewUserData>) {
 fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
     // Patching is already in progress, abort.
     if webview.user_data().patching_in_progress {
-        let res = webview.eval("notificationInProgress()");
+        let res = emit_event(webview, UiEvent::PatchingInProgress);
         if let Err(e) = res {
             log::warn!("Failed to dispatch notification: {}.", e);
         }
@@ -251,108 +1080,1102 @@ fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
     }
 }
 
-/// Parses JSON requests (for invoking functions with parameters) and dispatches
-/// them to the invoked function.
-fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
-    let result: serde_json::Result<Value> = serde_json::from_str(request);
-    match result {
-        Err(e) => {
-            log::error!("Invalid JSON request: {}", e);
-        }
-        Ok(json_req) => {
-            let function_name = json_req["function"].as_str();
-            if let Some(function_name) = function_name {
-                let function_params = json_req["parameters"].clone();
-                match function_name {
-                    "login" => handle_login(webview, function_params),
-                    "open_url" => handle_open_url(function_params),
-                    _ => {
-                        log::error!("Unknown function '{}'", function_name);
-                    }
-                }
-            }
-        }
+/// Creates shortcuts to the patcher, as described in the configuration.
+fn handle_create_shortcuts(webview: &mut WebView<WebViewUserData>) {
+    let patcher_config = &webview.user_data().patcher_config;
+    let shortcuts_config = patcher_config.shortcuts.clone().unwrap_or_default();
+    if let Err(e) = create_shortcuts(&shortcuts_config, &patcher_config.window.title) {
+        log::error!("Failed to create shortcuts: {:#}", e);
     }
 }
 
-/// Parameters expected for the login function
-#[derive(Deserialize)]
-struct LoginParameters {
-    login: String,
-    password: String,
+/// Forcefully terminates the game clients started by the patcher, along with
+/// the processes they started.
+fn handle_kill_clients(webview: &mut WebView<WebViewUserData>) {
+    if let Some(client_processes) = &webview.user_data().client_processes {
+        log::info!("Terminating game clients");
+        if let Err(e) = client_processes.terminate() {
+            log::error!("Failed to terminate game clients: {:#}", e);
+        }
+    }
 }
 
-/// Launches the game client with the given credentials
-fn handle_login(webview: &mut WebView<WebViewUserData>, parameters: Value) {
-    let result: serde_json::Result<LoginParameters> = serde_json::from_value(parameters);
-    match result {
-        Err(e) => log::error!("Invalid arguments given for 'login': {}", e),
-        Ok(login_params) => {
-            // Push credentials to the list of arguments first
-            let mut play_arguments: Vec<String> = vec![
-                format!("-t:{}", login_params.password),
+/// Removes the patcher's data (see `uninstall_patcher_data`) and exits, so
+/// that the next run starts afresh.
+fn handle_uninstall_patcher_data(webview: &mut WebView<WebViewUserData>) {
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = emit_event(webview, UiEvent::PatchingInProgress);
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+    let user_data = webview.user_data();
+    if let Err(e) = uninstall_patcher_data(&user_data.patcher_config, &user_data.patcher_directory)
+    {
+        log::error!("{:#}", e);
+    }
+    webview.exit();
+}
+
+/// Writes a diagnostics archive (see `export_diagnostics`) to a location
+/// chosen by the user. Failures are shown in a message box, since logs may
+/// be all the user has otherwise.
+fn handle_export_diagnostics(webview: &mut WebView<WebViewUserData>) {
+    let opt_path = tfd::save_file_dialog_with_filter(
+        "Export diagnostics",
+        "diagnostics.zip",
+        &["*.zip"],
+        "Zip Archives (*.zip)",
+    );
+    if let Some(path) = opt_path {
+        let user_data = webview.user_data();
+        let destination = PathBuf::from(path);
+        match export_diagnostics(
+            &user_data.patcher_config,
+            &user_data.patcher_directory,
+            &destination,
+        ) {
+            Ok(()) => log::info!("Diagnostics written to '{}'", destination.display()),
+            Err(e) => {
+                log::error!("Failed to export diagnostics: {:#}", e);
+                tfd::message_box_ok(
+                    "Export diagnostics",
+                    format!("Failed to export diagnostics: {:#}.", e).as_str(),
+                    tfd::MessageBoxIcon::Error,
+                );
+            }
+        }
+    }
+}
+
+/// Downloads and applies a patch requested through a custom URL.
+fn handle_remote_patch(webview: &mut WebView<WebViewUserData>, patch_url: Url) {
+    let patch_servers = &webview.user_data().patcher_config.web.patch_servers;
+    if !is_patch_url_allowed(&patch_url, patch_servers) {
+        log::warn!("Refusing to apply patch from '{}'", patch_url);
+        return;
+    }
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = emit_event(webview, UiEvent::PatchingInProgress);
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+
+    log::info!("Requesting remote patch '{}'", patch_url);
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::ApplyRemotePatch(patch_url))
+        .is_ok()
+    {
+        log::trace!("Sent ApplyRemotePatch command to patching thread");
+    }
+}
+
+/// Request made with a JSON object, e.g.
+/// `{"id": 1, "function": "set_zoom", "parameters": {"factor": 1.5}}`
+///
+/// Requests that have an `id` are answered through the UI's
+/// `rpatchurResponse` function (see `send_outcome`), once they've been
+/// handled.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRequest {
+    id: Option<u64>,
+    function: String,
+    #[serde(default)]
+    parameters: Value, // Null for functions that don't expect any
+}
+
+/// Parses JSON requests (for invoking functions with parameters) and dispatches
+/// them to the invoked function.
+fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
+    let result: serde_json::Result<JsonRequest> = serde_json::from_str(request);
+    let json_req = match result {
+        Err(e) => {
+            reject_request(webview, None, format!("Invalid JSON request: {}", e));
+            return;
+        }
+        Ok(v) => v,
+    };
+    let function_name = json_req.function.as_str();
+    let function_params = json_req.parameters;
+    webview.user_data_mut().current_request_id = json_req.id;
+    if accept_request(webview, function_name) {
+        dispatch_json_request(webview, function_name, function_params);
+    }
+    // Handlers answer requests that have a result (or take their ID to answer
+    // them later), the others are acknowledged once handled
+    if let Some(request_id) = webview.user_data_mut().current_request_id.take() {
+        send_outcome(webview, Some(request_id), Ok(None));
+    }
+}
+
+fn dispatch_json_request(
+    webview: &mut WebView<WebViewUserData>,
+    function_name: &str,
+    function_params: Value,
+) {
+    match function_name {
+        "login" => handle_login(webview, function_params),
+        "open_url" => handle_open_url(webview, function_params),
+        "launch_client" => handle_launch_client(webview, function_params),
+        "get_server_status" => handle_get_server_status(webview),
+        "get_news" => handle_get_news(webview),
+        "get_version_info" => handle_get_version_info(webview),
+        "list_clients" => handle_list_clients(webview),
+        "set_active_client" => handle_set_active_client(webview, function_params),
+        "get_bandwidth_stats" => handle_get_bandwidth_stats(webview),
+        "set_zoom" => handle_set_zoom(webview, function_params),
+        "set_always_on_top" => handle_set_always_on_top(webview, function_params),
+        "set_window_opacity" => handle_set_window_opacity(webview, function_params),
+        "set_click_through_regions" => handle_set_click_through_regions(webview, function_params),
+        "set_monthly_bandwidth_cap" => handle_set_monthly_bandwidth_cap(webview, function_params),
+        "patch_failed_reply" => handle_patch_failed_reply(webview, function_params),
+        "close_processes_reply" => handle_close_processes_reply(webview, function_params),
+        "reset_cache" => handle_reset_cache(webview, function_params),
+        #[cfg(feature = "staff")]
+        "skip_patch" => handle_skip_patch(webview, function_params),
+        #[cfg(feature = "staff")]
+        "preview_patch" => handle_preview_patch(webview, function_params),
+        _ => reject_request(webview, Some(function_name), "Unknown function".to_string()),
+    }
+}
+
+/// The only functions third-party pages (e.g., a news page allowed with
+/// `web.allowed_origins`) can call. The others are only honored for the UI's
+/// own pages.
+///
+/// The current page is the one the web view last loaded in its main frame,
+/// which frames' requests are attributed to, since the web view doesn't tell
+/// which frame a request comes from.
+const HARMLESS_FUNCTIONS: &[&str] = &[
+    "play",
+    "exit",
+    "get_server_status",
+    "get_news",
+    "get_version_info",
+    "list_clients",
+    "get_bandwidth_stats",
+];
+
+/// Indicates whether a call to `function_name` can be handled: the current
+/// page must be allowed to call it, and the function's rate limit mustn't
+/// have been reached. Refused calls are reported to the UI.
+fn accept_request(webview: &mut WebView<WebViewUserData>, function_name: &str) -> bool {
+    let user_data = webview.user_data_mut();
+    let current_page_url = user_data.current_page_url.lock().unwrap().clone();
+    let error = if !HARMLESS_FUNCTIONS.contains(&function_name)
+        && !is_url_allowed(&current_page_url, &user_data.trusted_url_prefixes)
+    {
+        format!("Not allowed from '{}'", current_page_url)
+    } else if !user_data
+        .rate_limiter
+        .try_call(function_name, Instant::now())
+    {
+        "Rate limit exceeded".to_string()
+    } else {
+        return true;
+    };
+    reject_request(webview, Some(function_name), error);
+    false
+}
+
+/// Reports a request that couldn't be handled (invalid, refused, ...) to the
+/// UI: as an error response if the request has an ID, with a
+/// `request_rejected` event otherwise.
+fn reject_request(
+    webview: &mut WebView<WebViewUserData>,
+    function_name: Option<&str>,
+    error: String,
+) {
+    log::warn!(
+        "Rejected call to '{}': {}",
+        function_name.unwrap_or("?"),
+        error
+    );
+    if let Some(request_id) = webview.user_data_mut().current_request_id.take() {
+        send_outcome(webview, Some(request_id), Err(error));
+        return;
+    }
+    let event = UiEvent::RequestRejected {
+        function: function_name.map(str::to_string),
+        error,
+    };
+    if let Err(e) = emit_event(webview, event) {
+        log::warn!("Failed to dispatch rejected request: {}.", e);
+    }
+}
+
+/// Sends the outcome of a request to the UI: through its `rpatchurResponse`
+/// function if the request has an ID, as an event otherwise (in which case
+/// failures are only logged).
+fn send_outcome(
+    webview: &mut WebView<WebViewUserData>,
+    request_id: Option<u64>,
+    outcome: Result<Option<UiEvent>, String>,
+) {
+    let res = match (request_id, outcome) {
+        (Some(request_id), outcome) => {
+            let outcome = outcome.as_ref().map(Option::as_ref).map_err(String::as_str);
+            let description = match outcome {
+                Ok(result) => json!({ "response": request_id, "result": result }),
+                Err(e) => json!({ "response": request_id, "error": e }),
+            };
+            eval_js(webview, description, &response_js_code(request_id, outcome))
+        }
+        (None, Ok(Some(event))) => emit_event(webview, event),
+        (None, Ok(None)) => Ok(()),
+        (None, Err(e)) => {
+            log::error!("{}", e);
+            Ok(())
+        }
+    };
+    if let Err(e) = res {
+        log::warn!("Failed to dispatch response: {}.", e);
+    }
+}
+
+/// Same as `send_outcome`, from another thread.
+fn dispatch_outcome(
+    web_view_handle: Handle<WebViewUserData>,
+    request_id: Option<u64>,
+    outcome: Result<Option<UiEvent>, String>,
+) {
+    let res = web_view_handle.dispatch(move |webview| {
+        send_outcome(webview, request_id, outcome);
+        Ok(())
+    });
+    if let Err(e) = res {
+        log::warn!("Failed to dispatch response: {}.", e);
+    }
+}
+
+/// Parameters expected for the login function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoginParameters {
+    login: String,
+    password: String,
+}
+
+/// Launches the game client with the given credentials
+///
+/// If `play.session_token` is set, credentials are exchanged for a session
+/// token in the background first, and only the token is passed to the client.
+fn handle_login(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<LoginParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(webview, Some("login"), format!("Invalid arguments: {}", e)),
+        Ok(login_params) => {
+            if let Some(session_config) = &webview.user_data().patcher_config.play.session_token {
+                start_game_client_with_session_token(webview, session_config.clone(), login_params);
+                return;
+            }
+            let login_arguments = vec![
+                format!("-t:{}", login_params.password),
                 login_params.login,
                 "server".to_string(),
             ];
-            play_arguments.extend(
-                webview
-                    .user_data()
-                    .patcher_config
-                    .play
-                    .arguments
-                    .iter()
-                    .cloned(),
+            start_game_client_with_login(webview, login_arguments);
+        }
+    }
+}
+
+/// Starts the game client with the arguments of a login, which are
+/// remembered for `play.require_login`.
+fn start_game_client_with_login(
+    webview: &mut WebView<WebViewUserData>,
+    login_arguments: Vec<String>,
+) {
+    // Push credentials to the list of arguments first
+    let mut play_arguments = login_arguments.clone();
+    play_arguments.extend(
+        active_play_target(&webview.user_data().patcher_config.play)
+            .arguments
+            .iter()
+            .cloned(),
+    );
+    webview.user_data_mut().login_arguments = Some(login_arguments);
+    start_game_client(webview, &play_arguments);
+}
+
+fn start_game_client_with_session_token(
+    webview: &mut WebView<WebViewUserData>,
+    session_config: SessionTokenConfiguration,
+    login_params: LoginParameters,
+) {
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let res =
+            request_session_token(&session_config, &login_params.login, &login_params.password);
+        let dispatch_res = web_view_handle.dispatch(move |webview| {
+            match res {
+                Err(e) => {
+                    log::error!("Failed to get a session token: {:#}", e);
+                    let event = UiEvent::LoginFailed {
+                        error: format!("{:#}", e),
+                    };
+                    if let Err(e) = emit_event(webview, event) {
+                        log::warn!("Failed to dispatch login failure: {}.", e);
+                    }
+                }
+                Ok(token) => {
+                    let login_arguments =
+                        session_arguments(&session_config, &login_params.login, &token);
+                    start_game_client_with_login(webview, login_arguments);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = dispatch_res {
+            log::warn!("Failed to dispatch session token: {}.", e);
+        }
+    });
+}
+
+/// Parameters expected for the launch_client function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LaunchClientParameters {
+    count: Option<usize>,    // Number of clients to start, defaults to 1
+    profile: Option<String>, // Name of the launch profile to use
+}
+
+/// Launches one or more game clients, optionally with the arguments of a
+/// launch profile, under the same conditions as `handle_play`
+fn handle_launch_client(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    const MAX_CLIENT_COUNT: usize = 16;
+    let result: serde_json::Result<LaunchClientParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("launch_client"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            let count = params.count.unwrap_or(1);
+            if count == 0 || count > MAX_CLIENT_COUNT {
+                log::error!("Invalid client count {} given for 'launch_client'", count);
+                return;
+            }
+            let play_config = &webview.user_data().patcher_config.play;
+            let target_arguments = match &params.profile {
+                None => active_play_target(play_config).arguments.to_vec(),
+                Some(profile_name) => match play_config
+                    .profiles
+                    .as_ref()
+                    .and_then(|profiles| profiles.get(profile_name))
+                {
+                    Some(profile_arguments) => profile_arguments.clone(),
+                    None => {
+                        log::error!("Unknown launch profile '{}'", profile_name);
+                        return;
+                    }
+                },
+            };
+            if let Some(client_arguments) = allowed_client_arguments(webview, target_arguments) {
+                start_game_clients(webview, &client_arguments, count);
+            }
+        }
+    }
+}
+
+/// Passes the game clients configured in `play.clients` to the UI's
+/// `clientList` function
+fn handle_list_clients(webview: &mut WebView<WebViewUserData>) {
+    let clients = list_clients(
+        &webview.user_data().patcher_config.play,
+        load_settings().active_client.as_deref(),
+    );
+    let request_id = webview.user_data_mut().current_request_id.take();
+    send_outcome(
+        webview,
+        request_id,
+        Ok(Some(UiEvent::ClientList { clients })),
+    );
+}
+
+/// Parameters expected for the set_active_client function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetActiveClientParameters {
+    name: String,
+}
+
+/// Selects the game client that `play` and `login` launch, and remembers it
+fn handle_set_active_client(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetActiveClientParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_active_client"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if !has_client(&webview.user_data().patcher_config.play, &params.name) {
+                log::error!("Unknown client '{}'", params.name);
+                return;
+            }
+            if let Err(e) = update_settings(|settings| settings.active_client = Some(params.name)) {
+                log::error!("Failed to save settings: {:#}", e);
+                return;
+            }
+            handle_list_clients(webview);
+        }
+    }
+}
+
+/// Passes the bandwidth used by the patcher (this session, this month and
+/// overall) to the UI's `bandwidthStats` function
+fn handle_get_bandwidth_stats(webview: &mut WebView<WebViewUserData>) {
+    let stats = get_bandwidth_stats(webview.user_data().session_downloaded_bytes);
+    let request_id = webview.user_data_mut().current_request_id.take();
+    send_outcome(
+        webview,
+        request_id,
+        Ok(Some(UiEvent::BandwidthStats { stats })),
+    );
+}
+
+/// Parameters expected for the set_monthly_bandwidth_cap function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetMonthlyBandwidthCapParameters {
+    cap_mb: Option<u64>, // None disables the warning
+}
+
+/// Sets the monthly bandwidth cap the player gets warned about, and remembers
+/// it
+fn handle_set_monthly_bandwidth_cap(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetMonthlyBandwidthCapParameters> =
+        serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_monthly_bandwidth_cap"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if let Err(e) = set_monthly_bandwidth_cap(params.cap_mb) {
+                log::error!("Failed to save settings: {:#}", e);
+                return;
+            }
+            handle_get_bandwidth_stats(webview);
+        }
+    }
+}
+
+/// Parameters expected for the set_zoom function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetZoomParameters {
+    factor: f64, // 1.0 being 100%
+}
+
+/// Zooms the UI in or out, and remembers the zoom factor
+fn handle_set_zoom(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetZoomParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_zoom"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if !params.factor.is_finite() || params.factor <= 0.0 {
+                reject_request(
+                    webview,
+                    Some("set_zoom"),
+                    format!("Invalid zoom factor: {}", params.factor),
+                );
+                return;
+            }
+            set_zoom_factor(webview, params.factor);
+            let factor = params.factor;
+            if let Err(e) = update_settings(|settings| settings.zoom_factor = Some(factor)) {
+                log::error!("Failed to save settings: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Parameters expected for the set_always_on_top function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetAlwaysOnTopParameters {
+    enabled: bool,
+}
+
+/// Keeps the window above other windows, or stops doing so
+fn handle_set_always_on_top(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetAlwaysOnTopParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_always_on_top"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if let Err(e) = set_always_on_top(webview, params.enabled) {
+                log::error!("Failed to change the window's z-order: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Parameters expected for the set_window_opacity function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetWindowOpacityParameters {
+    opacity: f64, // From 0.2 (translucent) to 1.0 (opaque)
+}
+
+/// Makes the whole window translucent
+fn handle_set_window_opacity(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetWindowOpacityParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_window_opacity"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if let Err(e) = set_window_opacity(webview, params.opacity) {
+                reject_request(webview, Some("set_window_opacity"), format!("{:#}", e));
+            }
+        }
+    }
+}
+
+/// Parameters expected for the set_click_through_regions function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetClickThroughRegionsParameters {
+    regions: Vec<WindowRegion>, // Empty to restore the whole window
+}
+
+/// Makes parts of the window click-through, for skins that aren't
+/// rectangular
+fn handle_set_click_through_regions(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SetClickThroughRegionsParameters> =
+        serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("set_click_through_regions"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if let Err(e) = set_click_through_regions(webview, &params.regions) {
+                reject_request(
+                    webview,
+                    Some("set_click_through_regions"),
+                    format!("{:#}", e),
+                );
+            }
+        }
+    }
+}
+
+/// Probes the configured services in the background and passes their status
+/// to the UI's `serverStatus` function
+fn handle_get_server_status(webview: &mut WebView<WebViewUserData>) {
+    const DEFAULT_TIMEOUT_MS: u64 = 2000;
+    let server_status_config = match &webview.user_data().patcher_config.server_status {
+        Some(v) => v.clone(),
+        None => {
+            reject_request(
+                webview,
+                Some("get_server_status"),
+                "Requires a 'server_status' configuration section".to_string(),
             );
-            start_game_client(webview, &play_arguments);
+            return;
+        }
+    };
+    let request_id = webview.user_data_mut().current_request_id.take();
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let timeout = Duration::from_millis(
+            server_status_config
+                .timeout_ms
+                .unwrap_or(DEFAULT_TIMEOUT_MS),
+        );
+        let outcome = match probe_services(&server_status_config.services, timeout) {
+            Err(e) => Err(format!("Failed to probe services: {:#}", e)),
+            Ok(services_status) => Ok(Some(UiEvent::ServerStatus {
+                services: services_status,
+            })),
+        };
+        dispatch_outcome(web_view_handle, request_id, outcome);
+    });
+}
+
+/// Fetches the configured news feed in the background and passes the news to
+/// the UI's `newsFeed` function
+fn handle_get_news(webview: &mut WebView<WebViewUserData>) {
+    let news_config = match &webview.user_data().patcher_config.news {
+        Some(v) => v.clone(),
+        None => {
+            reject_request(
+                webview,
+                Some("get_news"),
+                "Requires a 'news' configuration section".to_string(),
+            );
+            return;
+        }
+    };
+    let request_id = webview.user_data_mut().current_request_id.take();
+    let web_view_handle = webview.handle();
+    std::thread::spawn(move || {
+        let outcome = match fetch_news(&news_config) {
+            Err(e) => Err(format!("Failed to retrieve news: {:#}", e)),
+            Ok(news) => Ok(Some(UiEvent::NewsFeed { news })),
+        };
+        dispatch_outcome(web_view_handle, request_id, outcome);
+    });
+}
+
+/// Passes information about the patcher's build to the UI's `versionInfo`
+/// function
+fn handle_get_version_info(webview: &mut WebView<WebViewUserData>) {
+    let event = UiEvent::VersionInfo {
+        info: version_info(),
+    };
+    let request_id = webview.user_data_mut().current_request_id.take();
+    send_outcome(webview, request_id, Ok(Some(event)));
+}
+
+/// Parameters expected for the patch_failed_reply function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchFailedReplyParameters {
+    action: PatchFailureAction,
+}
+
+/// Forwards the answer to a `patchFailedPrompt` event to the patching thread
+fn handle_patch_failed_reply(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<PatchFailedReplyParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("patch_failed_reply"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::PatchFailureReply(params.action))
+                .is_ok()
+            {
+                log::trace!("Sent PatchFailureReply command to patching thread");
+            }
+        }
+    }
+}
+
+/// Parameters expected for the close_processes_reply function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CloseProcessesReplyParameters {
+    close: bool,
+}
+
+/// Forwards the answer to a `closeProcessesPrompt` event to the patching
+/// thread
+fn handle_close_processes_reply(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<CloseProcessesReplyParameters> =
+        serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("close_processes_reply"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::CloseProcessesReply(params.close))
+                .is_ok()
+            {
+                log::trace!("Sent CloseProcessesReply command to patching thread");
+            }
+        }
+    }
+}
+
+/// Parameters expected for the skip_patch function
+#[cfg(feature = "staff")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkipPatchParameters {
+    index: usize,
+}
+
+/// Makes the next updates skip a known-bad patch (staff builds only)
+#[cfg(feature = "staff")]
+fn handle_skip_patch(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    let result: serde_json::Result<SkipPatchParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => reject_request(
+            webview,
+            Some("skip_patch"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => {
+            send_patcher_command_when_idle(webview, PatcherCommand::SkipPatch(params.index))
         }
     }
 }
 
 /// Parameters expected for the open_url function
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct OpenUrlParameters {
     url: String,
 }
 
 /// Opens an URL with the native URL Handler
-fn handle_open_url(parameters: Value) {
+fn handle_open_url(webview: &mut WebView<WebViewUserData>, parameters: Value) {
     let result: serde_json::Result<OpenUrlParameters> = serde_json::from_value(parameters);
     match result {
-        Err(e) => log::error!("Invalid arguments given for 'open_url': {}", e),
-        Ok(params) => match open::that(params.url) {
-            Ok(exit_status) => {
-                if !exit_status.success() {
-                    if let Some(code) = exit_status.code() {
-                        log::error!("Command returned non-zero exit status {}!", code);
-                    }
-                }
-            }
-            Err(why) => {
-                log::error!("Error open_url function: '{}'", why);
-            }
-        },
+        Err(e) => reject_request(
+            webview,
+            Some("open_url"),
+            format!("Invalid arguments: {}", e),
+        ),
+        Ok(params) => open_in_browser(&params.url),
     }
 }
 
 fn start_game_client(webview: &mut WebView<WebViewUserData>, client_arguments: &[String]) {
-    let client_exe: &String = &webview.user_data().patcher_config.play.path;
-    let exit_on_success = webview
-        .user_data()
-        .patcher_config
-        .play
-        .exit_on_success
-        .unwrap_or(true);
-    match start_executable(client_exe, client_arguments) {
-        Ok(success) => {
-            if success {
+    start_game_clients(webview, client_arguments, 1);
+}
+
+/// Starts `count` instances of the game client.
+///
+/// `play.exit_on_success` is only taken into account once the last client
+/// has been started. In `play.stay_open_while_running` mode, the patcher
+/// minimizes itself instead and waits for the clients to exit. Clients are
+/// watched for crashes if the patcher stays open and `play.crash_watchdog` is
+/// set.
+fn start_game_clients(
+    webview: &mut WebView<WebViewUserData>,
+    client_arguments: &[String],
+    count: usize,
+) {
+    // Plugins can add arguments
+    let actions = dispatch_plugin_event(
+        &webview.user_data().patcher_config,
+        PluginEvent::PrePlay,
+        json!({ "arguments": client_arguments }),
+    );
+    let client_arguments: Vec<String> = client_arguments
+        .iter()
+        .cloned()
+        .chain(actions.extra_arguments)
+        .collect();
+    let play_config = &webview.user_data().patcher_config.play;
+    let client_exe = active_play_target(play_config).path;
+    let client_env = play_config.env.clone().unwrap_or_default();
+    let client_working_directory = play_config.working_directory.as_deref();
+    let stay_open_while_running = play_config.stay_open_while_running.unwrap_or(false);
+    let exit_on_success = !stay_open_while_running && play_config.exit_on_success.unwrap_or(true);
+    let watchdog_config = if exit_on_success {
+        None
+    } else {
+        play_config.crash_watchdog.clone()
+    };
+    let monitor_clients = stay_open_while_running || watchdog_config.is_some();
+    let mut last_client_started = false;
+    let mut started_clients = vec![];
+    for _ in 0..count {
+        last_client_started = match start_executable(
+            client_exe,
+            &client_arguments,
+            &client_env,
+            client_working_directory,
+            webview.user_data().client_processes.as_deref(),
+        ) {
+            Ok(Some(client)) => {
                 log::trace!("Client started");
-                if exit_on_success {
-                    webview.exit();
+                started_clients.push(client);
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                log::warn!("Failed to start client: {}", e);
+                false
+            }
+        };
+    }
+    if last_client_started && exit_on_success {
+        webview.exit();
+        return;
+    }
+    if last_client_started {
+        prefetch_patches(webview);
+    }
+    if !monitor_clients {
+        return;
+    }
+    if stay_open_while_running {
+        webview.user_data_mut().running_clients += started_clients.len();
+    }
+    let client_processes = webview.user_data().client_processes.clone();
+    for client in started_clients {
+        spawn_client_monitor(
+            webview.handle(),
+            client,
+            client_processes.clone(),
+            watchdog_config.clone(),
+            stay_open_while_running,
+        );
+    }
+    if last_client_started && stay_open_while_running {
+        webview.set_minimized(true);
+        show_tray_icon(webview);
+    }
+}
+
+/// Shows the tray icon with which the user can restore the patcher, start
+/// another client or repair the game while the patcher is minimized.
+fn show_tray_icon(webview: &mut WebView<WebViewUserData>) {
+    if webview.user_data().tray_icon.is_some() {
+        return;
+    }
+    let web_view_handle = webview.handle();
+    let title = webview.user_data().patcher_config.window.title.clone();
+    let tray_icon = TrayIcon::show(&title, move |action| {
+        let dispatch_res = web_view_handle.dispatch(move |webview| {
+            handle_tray_action(webview, action);
+            Ok(())
+        });
+        if let Err(e) = dispatch_res {
+            log::warn!("Failed to dispatch tray action: {}.", e);
+        }
+    });
+    match tray_icon {
+        Ok(tray_icon) => webview.user_data_mut().tray_icon = Some(tray_icon),
+        Err(e) => log::warn!("Failed to show the tray icon: {:#}", e),
+    }
+}
+
+/// Handles an action picked from the tray icon. The window is restored,
+/// except when starting another client.
+fn handle_tray_action(webview: &mut WebView<WebViewUserData>, action: TrayAction) {
+    log::trace!("Tray action: {:?}", action);
+    match action {
+        TrayAction::Restore => restore_window(webview),
+        TrayAction::Relaunch => handle_play(webview),
+        TrayAction::Repair => {
+            restore_window(webview);
+            if webview.user_data().patching_in_progress {
+                if let Err(e) = emit_event(webview, UiEvent::PatchingInProgress) {
+                    log::warn!("Failed to dispatch notification: {}.", e);
                 }
+                return;
             }
+            send_patcher_command_when_idle(webview, PatcherCommand::RepairGameFiles);
         }
+    }
+}
+
+/// Asks the patching thread to download small pending patches while the game
+/// is being played, if `patching.prefetch_max_patch_size_mb` is set.
+fn prefetch_patches(webview: &mut WebView<WebViewUserData>) {
+    let user_data = webview.user_data();
+    if user_data.patching_in_progress
+        || user_data
+            .patcher_config
+            .patching
+            .prefetch_max_patch_size_mb
+            .is_none()
+    {
+        return;
+    }
+    if let Err(e) = user_data
+        .patching_thread_tx
+        .try_send(PatcherCommand::PrefetchPatches)
+    {
+        log::warn!("Failed to send command to patching thread: {}.", e);
+    }
+}
+
+/// Parameters expected for the preview_patch function, either a local THOR
+/// file or the index of a published patch
+#[cfg(feature = "staff")]
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum PreviewPatchParameters {
+    Path { path: PathBuf },
+    Index { index: usize },
+}
+
+/// Lists the entries of a patch without applying it, and passes them to the
+/// UI's `patchPreview` function (staff builds only)
+///
+/// Published patches are downloaded to a temporary directory first.
+#[cfg(feature = "staff")]
+fn handle_preview_patch(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+    use anyhow::Context;
+    use rpatchur_core::{preview_patch_file, preview_published_patch};
+
+    let result: serde_json::Result<PreviewPatchParameters> = serde_json::from_value(parameters);
+    let params = match result {
         Err(e) => {
-            log::warn!("Failed to start client: {}", e);
+            reject_request(
+                webview,
+                Some("preview_patch"),
+                format!("Invalid arguments: {}", e),
+            );
+            return;
+        }
+        Ok(v) => v,
+    };
+    let config = webview.user_data().patcher_config.clone();
+    let request_id = webview.user_data_mut().current_request_id.take();
+    let web_view_handle = webview.handle();
+    let progress_sink = UiController::new(webview);
+    std::thread::spawn(move || {
+        let preview_res = match params {
+            PreviewPatchParameters::Path { path } => {
+                preview_patch_file(path, &config.client.default_grf_name)
+            }
+            PreviewPatchParameters::Index { index } => {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .with_context(|| "Failed to build a tokio runtime")
+                    .and_then(|tokio_rt| {
+                        tokio_rt.block_on(preview_published_patch(index, &config, &progress_sink))
+                    })
+            }
+        };
+        let outcome = match preview_res {
+            // UIs that don't use responses are notified of failures too
+            Err(e) if request_id.is_none() => {
+                log::error!("Failed to preview patch: {:#}", e);
+                Ok(Some(UiEvent::PatchPreviewFailed {
+                    error: format!("{:#}", e),
+                }))
+            }
+            Err(e) => Err(format!("Failed to preview patch: {:#}", e)),
+            Ok(preview) => Ok(Some(UiEvent::PatchPreview { preview })),
+        };
+        dispatch_outcome(web_view_handle, request_id, outcome);
+    });
+}
+
+/// Waits for `client` to exit, in a separate thread.
+///
+/// The UI is notified with `clientCrashed` if the client crashes. Once the last
+/// monitored client exits, along with the processes it started, in
+/// `play.stay_open_while_running` mode, the window is restored and the UI is
+/// notified with `clientExited`.
+fn spawn_client_monitor(
+    web_view_handle: Handle<WebViewUserData>,
+    client: ChildProcess,
+    client_processes: Option<Arc<ProcessGroup>>,
+    watchdog_config: Option<CrashWatchdogConfiguration>,
+    stay_open_while_running: bool,
+) {
+    std::thread::spawn(move || {
+        let client_exit = wait_for_exit(client);
+        if let Some(watchdog_config) = watchdog_config {
+            if let Some(exit_code) = client_exit.crash_exit_code(&watchdog_config) {
+                log::warn!("Client crashed (exit code {})", exit_code);
+                let event = UiEvent::ClientCrashed {
+                    exit_code,
+                    troubleshooting_url: watchdog_config.troubleshooting_url,
+                };
+                let dispatch_res =
+                    web_view_handle.dispatch(move |webview| emit_event(webview, event));
+                if let Err(e) = dispatch_res {
+                    log::warn!("Failed to dispatch client crash: {}.", e);
+                }
+            }
+        }
+        if !stay_open_while_running {
+            return;
+        }
+        // Launchers can exit once they've started the actual client
+        if let Some(client_processes) = client_processes {
+            if let Err(e) = client_processes.wait_until_empty() {
+                log::warn!("Failed to wait for game clients: {:#}", e);
+            }
+        }
+        let dispatch_res = web_view_handle.dispatch(move |webview| {
+            let user_data = webview.user_data_mut();
+            user_data.running_clients = user_data.running_clients.saturating_sub(1);
+            if user_data.running_clients == 0 {
+                restore_window(webview);
+                emit_event(
+                    webview,
+                    UiEvent::ClientExited {
+                        exit_code: client_exit.exit_code,
+                    },
+                )?;
+            }
+            Ok(())
+        });
+        if let Err(e) = dispatch_res {
+            log::warn!("Failed to dispatch client exit: {}.", e);
+        }
+    });
+}
+
+/// Restores the patcher's window and brings it to the front, removing the
+/// tray icon.
+fn restore_window(webview: &mut WebView<WebViewUserData>) {
+    webview.user_data_mut().tray_icon = None;
+    webview.set_minimized(false);
+    webview.set_visible(true);
+    #[cfg(windows)]
+    unsafe {
+        use winapi::shared::windef::HWND;
+        winapi::um::winuser::SetForegroundWindow(webview.window_handle() as HWND);
+    }
+}
+
+/// Sends an event to the UI.
+fn emit_event(webview: &mut WebView<WebViewUserData>, event: UiEvent) -> web_view::WVResult {
+    let js_code = event.to_js_code();
+    eval_js(webview, json!(event), &js_code)
+}
+
+/// Runs JavaScript code in the UI, recording it with `--record-events`.
+///
+/// Every call into the UI must go through here, so that replays reproduce
+/// what the UI was sent. `description` is only written to the recording.
+fn eval_js(
+    webview: &mut WebView<WebViewUserData>,
+    description: Value,
+    js_code: &str,
+) -> web_view::WVResult {
+    if let Some(event_recorder) = &mut webview.user_data_mut().event_recorder {
+        if let Err(e) = event_recorder.record(description, js_code) {
+            log::warn!("Failed to record event: {:#}", e);
         }
     }
+    webview.eval(js_code)
+}
+
+/// Calls one of the UI's JavaScript functions.
+///
+/// Arguments are serialized as JSON, which makes any string safe to pass.
+fn call_js_function(
+    webview: &mut WebView<WebViewUserData>,
+    function_name: &str,
+    args: &[Value],
+) -> web_view::WVResult {
+    let description = json!({ "call": function_name, "args": args });
+    eval_js(webview, description, &format_js_call(function_name, args))
 }
@@ -0,0 +1,66 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rpatchur_core::{remove_patcher_data, PatcherConfiguration};
+
+use crate::deep_link::unregister_url_protocol;
+use crate::install_path::forget_install_directory;
+use crate::news::get_news_cache_file_path;
+use crate::settings::reset_settings;
+use crate::shortcuts::{dissociate_thor_files, get_shortcuts_marker_file_path};
+
+/// Removes the data the patcher stores on the system: the patcher cache, the
+/// restore point, the settings store, the news cache, the remembered
+/// installation directory, along with the registered URL protocol and file
+/// associations. Game files and shortcuts are left untouched.
+///
+/// `patcher_directory` is the directory the patcher has been started from,
+/// the current directory being the game's. Everything is removed even if
+/// some steps fail, failed steps are then reported in the returned error.
+pub fn uninstall_patcher_data(
+    config: &PatcherConfiguration,
+    patcher_directory: &Path,
+) -> Result<()> {
+    let mut errors = vec![];
+    let mut run_step = |description: &str, result: Result<()>| {
+        if let Err(e) = result {
+            log::warn!("Failed to remove {}: {:#}", description, e);
+            errors.push(description.to_string());
+        }
+    };
+    run_step("the patcher cache", remove_patcher_data(config));
+    run_step("the settings", reset_settings());
+    run_step(
+        "the news cache",
+        get_news_cache_file_path().and_then(remove_file_if_exists),
+    );
+    run_step(
+        "the shortcuts marker",
+        get_shortcuts_marker_file_path().and_then(remove_file_if_exists),
+    );
+    run_step(
+        "the installation directory",
+        forget_install_directory(patcher_directory),
+    );
+    if let Some(url_protocol_config) = &config.url_protocol {
+        run_step(
+            "the URL protocol",
+            unregister_url_protocol(&url_protocol_config.scheme),
+        );
+    }
+    run_step("the file associations", dissociate_thor_files());
+    if errors.is_empty() {
+        log::info!("Patcher data removed");
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to remove {}", errors.join(", ")))
+    }
+}
+
+pub fn remove_file_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
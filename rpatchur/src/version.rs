@@ -0,0 +1,37 @@
+use serde::Serialize;
+
+/// Information about the patcher's build, which UIs can display (e.g., in an
+/// About panel).
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,      // Commit the patcher was built from
+    pub build_date: &'static str,    // "YYYY-MM-DD" (UTC)
+    pub features: Vec<&'static str>, // Enabled cargo features
+}
+
+pub fn version_info() -> VersionInfo {
+    let mut features = vec![];
+    if cfg!(feature = "staff") {
+        features.push("staff");
+    }
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("RPATCHUR_GIT_HASH"),
+        build_date: env!("RPATCHUR_BUILD_DATE"),
+        features,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_info() {
+        let version_info = version_info();
+        assert_eq!(version_info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(version_info.build_date.len(), "YYYY-MM-DD".len());
+        assert!(!version_info.git_hash.is_empty());
+    }
+}
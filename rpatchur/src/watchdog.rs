@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use rpatchur_core::CrashWatchdogConfiguration;
+
+use crate::process::ChildProcess;
+
+const DEFAULT_GRACE_PERIOD_SECS: u64 = 60;
+
+/// How a game client exited.
+pub struct ClientExit {
+    pub exit_code: Option<i32>, // Unknown if the process couldn't be waited for
+    uptime: Duration,
+}
+
+/// Waits for `client` to exit.
+///
+/// Blocks until the client exits.
+pub fn wait_for_exit(client: ChildProcess) -> ClientExit {
+    let started_at = Instant::now();
+    let exit_code = match client.wait() {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Failed to wait for client: {:#}", e);
+            None
+        }
+    };
+    log::debug!("Client exited with code {:?}", exit_code);
+    ClientExit {
+        exit_code,
+        uptime: started_at.elapsed(),
+    }
+}
+
+impl ClientExit {
+    /// Returns the client's exit code if it crashed, that is if it exited with
+    /// an error within `crash_watchdog.grace_period_secs`.
+    pub fn crash_exit_code(&self, watchdog_config: &CrashWatchdogConfiguration) -> Option<i32> {
+        let grace_period = Duration::from_secs(
+            watchdog_config
+                .grace_period_secs
+                .unwrap_or(DEFAULT_GRACE_PERIOD_SECS),
+        );
+        match self.exit_code {
+            Some(exit_code) if is_crash(exit_code, self.uptime, grace_period) => Some(exit_code),
+            _ => None,
+        }
+    }
+}
+
+fn is_crash(exit_code: i32, uptime: Duration, grace_period: Duration) -> bool {
+    exit_code != 0 && uptime < grace_period
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_crash() {
+        let grace_period = Duration::from_secs(60);
+        assert!(is_crash(1, Duration::from_secs(5), grace_period));
+        // Clients closed by the player
+        assert!(!is_crash(0, Duration::from_secs(5), grace_period));
+        // Clients that ran for a while
+        assert!(!is_crash(-1, Duration::from_secs(3600), grace_period));
+    }
+
+    #[test]
+    fn test_crash_exit_code() {
+        let watchdog_config = CrashWatchdogConfiguration {
+            grace_period_secs: Some(10),
+            troubleshooting_url: None,
+        };
+        let client_exit = ClientExit {
+            exit_code: Some(3),
+            uptime: Duration::from_secs(2),
+        };
+        assert_eq!(client_exit.crash_exit_code(&watchdog_config), Some(3));
+        let client_exit = ClientExit {
+            exit_code: None,
+            uptime: Duration::from_secs(2),
+        };
+        assert_eq!(client_exit.crash_exit_code(&watchdog_config), None);
+    }
+}
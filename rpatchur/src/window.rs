@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use web_view::WebView;
+
+// Keeps the window visible whatever the UI asks for
+const MIN_OPACITY: f64 = 0.2;
+
+/// Rectangle of the window's client area, in device pixels.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct WindowRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Keeps the window above the other (non-topmost) windows, or stops doing
+/// so.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn set_always_on_top<T>(webview: &WebView<'_, T>, enabled: bool) -> Result<()> {
+    use winapi::shared::windef::HWND;
+    use winapi::um::winuser::{
+        SetWindowPos, HWND_NOTOPMOST, HWND_TOPMOST, SWP_NOACTIVATE, SWP_NOMOVE, SWP_NOSIZE,
+    };
+
+    let insert_after = if enabled {
+        HWND_TOPMOST
+    } else {
+        HWND_NOTOPMOST
+    };
+    let res = unsafe {
+        SetWindowPos(
+            webview.window_handle() as HWND,
+            insert_after,
+            0,
+            0,
+            0,
+            0,
+            SWP_NOMOVE | SWP_NOSIZE | SWP_NOACTIVATE,
+        )
+    };
+    if res == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Sets the opacity of the whole window, from 0.0 (invisible) to 1.0
+/// (opaque). Opacities below `MIN_OPACITY` are raised to it.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn set_window_opacity<T>(webview: &WebView<'_, T>, opacity: f64) -> Result<()> {
+    use winapi::shared::windef::HWND;
+    use winapi::um::winuser::{
+        GetWindowLongPtrW, SetLayeredWindowAttributes, SetWindowLongPtrW, GWL_EXSTYLE, LWA_ALPHA,
+        WS_EX_LAYERED,
+    };
+
+    let hwnd = webview.window_handle() as HWND;
+    let alpha = (opacity_or_err(opacity)? * 255.0).round() as u8;
+    let res = unsafe {
+        // Only layered windows can be translucent
+        let ex_style = GetWindowLongPtrW(hwnd, GWL_EXSTYLE);
+        if ex_style & WS_EX_LAYERED as isize == 0 {
+            SetWindowLongPtrW(hwnd, GWL_EXSTYLE, ex_style | WS_EX_LAYERED as isize);
+        }
+        SetLayeredWindowAttributes(hwnd, 0, alpha, LWA_ALPHA)
+    };
+    if res == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Makes `regions` of the window click-through: they aren't drawn, and clicks
+/// go to the windows below. An empty list restores the whole window, and
+/// regions covering the whole client area are refused.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn set_click_through_regions<T>(
+    webview: &WebView<'_, T>,
+    regions: &[WindowRegion],
+) -> Result<()> {
+    use winapi::shared::windef::{HWND, POINT, RECT};
+    use winapi::um::wingdi::{CombineRgn, CreateRectRgn, DeleteObject, RGN_DIFF};
+    use winapi::um::winuser::{ClientToScreen, GetClientRect, GetWindowRect, SetWindowRgn};
+
+    let hwnd = webview.window_handle() as HWND;
+    if regions.is_empty() {
+        unsafe { SetWindowRgn(hwnd, std::ptr::null_mut(), 1) };
+        return Ok(());
+    }
+    unsafe {
+        // Window regions are relative to the window's top-left corner,
+        // including its frame
+        let mut window_rect: RECT = std::mem::zeroed();
+        let mut client_rect: RECT = std::mem::zeroed();
+        let mut client_origin = POINT { x: 0, y: 0 };
+        if GetWindowRect(hwnd, &mut window_rect) == 0
+            || GetClientRect(hwnd, &mut client_rect) == 0
+            || ClientToScreen(hwnd, &mut client_origin) == 0
+        {
+            return Err(anyhow!("Failed to retrieve the window's position"));
+        }
+        // Clicks couldn't reach the window anymore
+        if covers_client_area(regions, client_rect.right, client_rect.bottom) {
+            return Err(anyhow!("The regions can't cover the whole window"));
+        }
+        let offset_x = client_origin.x - window_rect.left;
+        let offset_y = client_origin.y - window_rect.top;
+        let window_region = CreateRectRgn(
+            0,
+            0,
+            window_rect.right - window_rect.left,
+            window_rect.bottom - window_rect.top,
+        );
+        for region in regions {
+            let left = offset_x + region.x;
+            let top = offset_y + region.y;
+            let hole = CreateRectRgn(left, top, left + region.width, top + region.height);
+            CombineRgn(window_region, window_region, hole, RGN_DIFF);
+            DeleteObject(hole as _);
+        }
+        // The system owns the region from now on
+        if SetWindowRgn(hwnd, window_region, 1) == 0 {
+            DeleteObject(window_region as _);
+            return Err(anyhow!("Failed to set the window's region"));
+        }
+    }
+    Ok(())
+}
+
+/// Window styles are only supported on Windows.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn set_always_on_top<T>(_webview: &WebView<'_, T>, _enabled: bool) -> Result<()> {
+    Err(unsupported())
+}
+
+/// Window styles are only supported on Windows.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn set_window_opacity<T>(_webview: &WebView<'_, T>, opacity: f64) -> Result<()> {
+    opacity_or_err(opacity)?;
+    Err(unsupported())
+}
+
+/// Window styles are only supported on Windows.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn set_click_through_regions<T>(
+    _webview: &WebView<'_, T>,
+    _regions: &[WindowRegion],
+) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(windows))]
+fn unsupported() -> anyhow::Error {
+    anyhow!("Window styles aren't supported on this platform")
+}
+
+fn opacity_or_err(opacity: f64) -> Result<f64> {
+    if (0.0..=1.0).contains(&opacity) {
+        Ok(opacity.max(MIN_OPACITY))
+    } else {
+        Err(anyhow!("Invalid opacity {}, expected 0.0 to 1.0", opacity))
+    }
+}
+
+/// Indicates whether `regions` cover every pixel of a `width` x `height`
+/// client area, together.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn covers_client_area(regions: &[WindowRegion], width: i32, height: i32) -> bool {
+    // The regions' edges split the area into cells that are either fully
+    // covered by a region or not at all
+    let mut xs = vec![0, width];
+    let mut ys = vec![0, height];
+    for region in regions {
+        xs.push(region.x);
+        xs.push(region.x.saturating_add(region.width));
+        ys.push(region.y);
+        ys.push(region.y.saturating_add(region.height));
+    }
+    for edges in [&mut xs, &mut ys] {
+        edges.sort_unstable();
+        edges.dedup();
+    }
+    let contains = |region: &WindowRegion, x: i32, y: i32| {
+        x >= region.x
+            && y >= region.y
+            && x < region.x.saturating_add(region.width)
+            && y < region.y.saturating_add(region.height)
+    };
+    let cells = |edges: &[i32], size: i32| -> Vec<i32> {
+        edges
+            .windows(2)
+            .filter(|w| w[0] >= 0 && w[1] <= size)
+            .map(|w| w[0])
+            .collect()
+    };
+    let (columns, rows) = (cells(&xs, width), cells(&ys, height));
+    columns.iter().all(|&x| {
+        rows.iter()
+            .all(|&y| regions.iter().any(|region| contains(region, x, y)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: i32, y: i32, width: i32, height: i32) -> WindowRegion {
+        WindowRegion {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_opacity_or_err() {
+        assert_eq!(opacity_or_err(0.5).unwrap(), 0.5);
+        assert_eq!(opacity_or_err(0.0).unwrap(), MIN_OPACITY);
+        assert!(opacity_or_err(1.5).is_err());
+        assert!(opacity_or_err(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_covers_client_area() {
+        assert!(!covers_client_area(&[], 800, 600));
+        assert!(!covers_client_area(&[region(0, 0, 100, 100)], 800, 600));
+        assert!(covers_client_area(&[region(-10, -10, 900, 700)], 800, 600));
+        // Together, but not separately
+        let halves = [region(0, 0, 400, 600), region(400, 0, 400, 600)];
+        assert!(covers_client_area(&halves, 800, 600));
+        assert!(!covers_client_area(&halves[..1], 800, 600));
+        let gap = [region(0, 0, 400, 600), region(401, 0, 399, 600)];
+        assert!(!covers_client_area(&gap, 800, 600));
+    }
+}